@@ -0,0 +1,115 @@
+//! Address ranges for `[server.access]`, which limits who can reach the
+//! webmail at all, and for `server.trusted_proxies`, whose forwarding
+//! headers say who the client really is.
+
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address range such as `192.168.1.0/24` or `fd00::/8`. A bare address
+/// stands for just itself.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                same_prefix(u32::from(network).into(), u32::from(addr).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => same_prefix(network.into(), addr.into(), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// The client a request came from. A trusted proxy's X-Forwarded-For lists
+/// the addresses it was forwarded for, each proxy appending its peer, so
+/// the client is the last entry not itself a trusted proxy; anything left
+/// of that could have been made up by the client.
+pub fn forwarded_client(peer: IpAddr, forwarded_for: Option<&str>, trusted: &[Cidr]) -> IpAddr {
+    if !is_trusted(peer, trusted) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in forwarded_for.unwrap_or("").rsplit(',') {
+        let Ok(addr) = hop.trim().parse::<IpAddr>() else { break };
+        client = addr;
+        if !is_trusted(addr, trusted) {
+            break;
+        }
+    }
+    client
+}
+
+/// The client of a request that came in on the Unix socket. Only a proxy
+/// on this host can connect there, so the last X-Forwarded-For entry, the
+/// address it appended, is believed like a trusted proxy's peer.
+pub fn socket_client(forwarded_for: Option<&str>, trusted: &[Cidr]) -> Option<IpAddr> {
+    let forwarded_for = forwarded_for?;
+    let (rest, last) = match forwarded_for.rsplit_once(',') {
+        Some((rest, last)) => (Some(rest), last),
+        None => (None, forwarded_for),
+    };
+    let peer = last.trim().parse().ok()?;
+    Some(forwarded_client(peer, rest, trusted))
+}
+
+pub fn is_trusted(addr: IpAddr, trusted: &[Cidr]) -> bool {
+    trusted.iter().any(|range| range.contains(addr))
+}
+
+/// Whether the top `prefix` of `bits` bits of the two addresses agree
+fn same_prefix(network: u128, addr: u128, bits: u32, prefix: u8) -> bool {
+    let shift = bits - u32::from(prefix);
+    shift == 128 || (network ^ addr) >> shift == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network = addr
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address in {:?}", s))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", s))?,
+            None => max,
+        };
+        // Clients' mapped addresses are compared as the IPv4 ones they are,
+        // so a mapped range is too
+        match network.to_canonical() {
+            IpAddr::V4(v4) if network.is_ipv6() && prefix >= 96 => Ok(Cidr {
+                network: IpAddr::V4(v4),
+                prefix: prefix - 96,
+            }),
+            _ => Ok(Cidr { network, prefix }),
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests;
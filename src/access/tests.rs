@@ -0,0 +1,83 @@
+use super::*;
+
+fn cidr(s: &str) -> Cidr {
+    s.parse().unwrap()
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn prefixes_cover_their_edges() {
+    let everyone = cidr("0.0.0.0/0");
+    assert!(everyone.contains(ip("0.0.0.0")));
+    assert!(everyone.contains(ip("255.255.255.255")));
+    assert!(!everyone.contains(ip("::1")));
+    assert!(cidr("::/0").contains(ip("ffff::1")));
+
+    let one = cidr("192.0.2.7/32");
+    assert!(one.contains(ip("192.0.2.7")));
+    assert!(!one.contains(ip("192.0.2.6")));
+    assert!(!one.contains(ip("192.0.2.8")));
+    assert!(cidr("192.0.2.7").contains(ip("192.0.2.7")));
+
+    let net = cidr("10.1.0.0/16");
+    assert!(net.contains(ip("10.1.255.255")));
+    assert!(!net.contains(ip("10.2.0.0")));
+    // Host bits in the network don't matter
+    assert!(cidr("10.1.2.3/16").contains(ip("10.1.9.9")));
+
+    let host = cidr("2001:db8::1/128");
+    assert!(host.contains(ip("2001:db8::1")));
+    assert!(!host.contains(ip("2001:db8::2")));
+    assert!(cidr("fd00::/8").contains(ip("fdff:ffff::1")));
+    assert!(!cidr("fd00::/8").contains(ip("fe00::1")));
+
+    for bad in ["10.0.0.0/33", "::/129", "10.0.0.0/-1", "10.0.0/8", "example.com", "10.0.0.0/"] {
+        assert!(bad.parse::<Cidr>().is_err(), "{} parsed", bad);
+    }
+}
+
+#[test]
+fn mapped_addresses_are_their_ipv4_ones() {
+    // How a dual-stack socket reports an IPv4 client
+    assert!(cidr("192.0.2.0/24").contains(ip("::ffff:192.0.2.9")));
+    assert!(!cidr("192.0.2.0/24").contains(ip("::ffff:198.51.100.1")));
+    assert!(cidr("::ffff:192.0.2.0/120").contains(ip("192.0.2.9")));
+    assert!(cidr("::ffff:192.0.2.0/120").contains(ip("::ffff:192.0.2.9")));
+    assert!(!cidr("::ffff:192.0.2.0/120").contains(ip("192.0.3.9")));
+    assert!(!cidr("::/0").contains(ip("::ffff:192.0.2.9")));
+    let trusted = [cidr("127.0.0.1")];
+    assert_eq!(forwarded_client(ip("::ffff:127.0.0.1"), Some("203.0.113.5"), &trusted), ip("203.0.113.5"));
+}
+
+#[test]
+fn only_trusted_proxies_are_believed() {
+    let trusted = [cidr("10.0.0.0/8"), cidr("fd00::/8")];
+    let client = |peer: &str, forwarded_for: Option<&str>| forwarded_client(ip(peer), forwarded_for, &trusted);
+
+    // Straight from the internet, whatever it says
+    assert_eq!(client("203.0.113.5", Some("10.0.0.1")), ip("203.0.113.5"));
+    assert_eq!(client("203.0.113.5", Some("198.51.100.1, 10.0.0.2")), ip("203.0.113.5"));
+    // Through a trusted proxy, which appended its peer
+    assert_eq!(client("10.0.0.2", Some("203.0.113.5")), ip("203.0.113.5"));
+    assert_eq!(client("10.0.0.2", None), ip("10.0.0.2"));
+    assert_eq!(client("10.0.0.2", Some("")), ip("10.0.0.2"));
+    // The client's own entries to the left of it are ignored
+    assert_eq!(client("10.0.0.2", Some("127.0.0.1, 203.0.113.5")), ip("203.0.113.5"));
+    assert_eq!(client("10.0.0.2", Some("10.9.9.9, 203.0.113.5")), ip("203.0.113.5"));
+    // Across several trusted hops
+    assert_eq!(client("10.0.0.2", Some("198.51.100.1, 203.0.113.5, fd00::7, 10.0.0.3")), ip("203.0.113.5"));
+    // All trusted: the first is the best there is
+    assert_eq!(client("10.0.0.2", Some("10.0.0.4, 10.0.0.3")), ip("10.0.0.4"));
+    // Garbage stops the walk at what was known so far
+    assert_eq!(client("10.0.0.2", Some("203.0.113.5, unknown")), ip("10.0.0.2"));
+    assert_eq!(client("10.0.0.2", Some("198.51.100.1, garbage, 10.0.0.3")), ip("10.0.0.3"));
+    assert_eq!(client("10.0.0.2", Some("203.0.113.5:4711")), ip("10.0.0.2"));
+
+    assert_eq!(socket_client(Some("198.51.100.1, 203.0.113.5"), &trusted), Some(ip("203.0.113.5")));
+    assert_eq!(socket_client(Some("198.51.100.1, 10.0.0.3"), &trusted), Some(ip("198.51.100.1")));
+    assert_eq!(socket_client(Some("nonsense"), &trusted), None);
+    assert_eq!(socket_client(None, &trusted), None);
+}
@@ -0,0 +1,149 @@
+//! The access log: one line per request in Common or Combined Log Format,
+//! kept apart from the diagnostic log so standard analyzers can read it.
+//!
+//! Requests are routed without their handlers returning the response, so
+//! `respond` notes what it sent with [`note_response`] and the line is
+//! written from that once the handler is done.
+
+use serde::Deserialize;
+use std::cell::Cell;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tiny_http::Request;
+
+use crate::config::AccessLogConfig;
+use crate::log;
+
+/// Which of the standard line formats to write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+    /// Common plus the quoted Referer and User-Agent
+    #[default]
+    Combined,
+}
+
+thread_local! {
+    /// Status and body length of the response sent for this thread's request
+    static RESPONSE: Cell<Option<(u16, Option<usize>)>> = const { Cell::new(None) };
+}
+
+/// Record the response going out for the current request
+pub fn note_response(status: u16, length: Option<usize>) {
+    RESPONSE.with(|response| response.set(Some((status, length))));
+}
+
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+    format: AccessFormat,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> Result<Self, String> {
+        let out: Box<dyn Write + Send> = if config.path == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.path)
+                .map_err(|e| format!("failed to open access log {}: {}", config.path, e))?;
+            Box::new(file)
+        };
+        Ok(AccessLog {
+            out: Mutex::new(out),
+            format: config.format,
+        })
+    }
+
+    /// What to log about `request`, taken before the handler consumes it
+    pub fn start(&self, request: &Request, client: Option<IpAddr>, user: Option<String>) -> AccessEntry {
+        RESPONSE.with(|response| response.set(None));
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.as_str().to_string())
+        };
+        AccessEntry {
+            client,
+            user,
+            time: SystemTime::now(),
+            request_line: format!("{} {} HTTP/{}", request.method(), request.url(), request.http_version()),
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+        }
+    }
+
+    /// Write the line for a request that took `elapsed`, followed by that
+    /// time in seconds as nginx's `$request_time` is
+    pub fn finish(&self, entry: AccessEntry, elapsed: Duration) {
+        // A handler that failed before answering leaves nothing behind
+        let (status, length) = RESPONSE.with(Cell::take).unwrap_or((500, None));
+        let secs = entry
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut line = format!(
+            "{} - {} [{}] \"{}\" {} {}",
+            entry.client.map_or_else(|| "-".to_string(), |c| c.to_string()),
+            entry.user.as_deref().map_or_else(|| "-".to_string(), quote_free),
+            log::clf_date(secs),
+            escape(&entry.request_line),
+            status,
+            length.filter(|l| *l > 0).map_or_else(|| "-".to_string(), |l| l.to_string())
+        );
+        if self.format == AccessFormat::Combined {
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                entry.referer.as_deref().map_or_else(|| "-".to_string(), escape),
+                entry.user_agent.as_deref().map_or_else(|| "-".to_string(), escape)
+            ));
+        }
+        line.push_str(&format!(" {:.3}\n", elapsed.as_secs_f64()));
+
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            crate::log_error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// A request on its way through, for its access log line
+pub struct AccessEntry {
+    client: Option<IpAddr>,
+    user: Option<String>,
+    time: SystemTime,
+    request_line: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// `s` fit to go between quotes, with quotes, backslashes and control
+/// characters escaped the way nginx does
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push_str(&format!("\\x{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A bare field such as the user, which can't hold spaces either
+fn quote_free(s: &str) -> String {
+    escape(s).replace(' ', "_")
+}
@@ -0,0 +1,151 @@
+//! The scripts and stylesheet under static/, built into the binary.
+//!
+//! Pages link to each at a URL with a hash of its contents in it, such as
+//! `/static/app.3f2a9c41d07e8b65.js`, which browsers may keep for good: a
+//! build that changes the file changes the URL. The plain
+//! `/static/app.js` still works, but has to be checked with the server on
+//! each use. Both carry an ETag so such a check costs a 304.
+//!
+//! With `server.static_dir`, other paths under `/static/` are files from
+//! that directory, such as logos. `/static/custom.css` is the operator's
+//! stylesheet, from `server.custom_css` or that directory, which every page
+//! loads after style.css.
+
+use ring::digest::{digest, SHA256};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Name of the operator's stylesheet under `/static/` and in `static_dir`
+pub const CUSTOM_CSS: &str = "custom.css";
+
+pub struct Asset {
+    /// File name under static/
+    pub name: &'static str,
+    pub body: &'static str,
+    pub content_type: &'static str,
+    /// Start of the SHA-256 of `body` in hex
+    pub hash: String,
+}
+
+impl Asset {
+    /// Where pages link to it, with its hash before the extension
+    pub fn url(&self) -> String {
+        match self.name.rsplit_once('.') {
+            Some((stem, ext)) => format!("/static/{}.{}.{}", stem, self.hash, ext),
+            None => format!("/static/{}.{}", self.name, self.hash),
+        }
+    }
+
+    /// Weak, since the same script may go out gzipped or not
+    pub fn etag(&self) -> String {
+        format!("W/\"{}\"", self.hash)
+    }
+}
+
+fn assets() -> &'static [Asset] {
+    static ASSETS: OnceLock<Vec<Asset>> = OnceLock::new();
+    ASSETS.get_or_init(|| {
+        [
+            ("htmx.min.js", include_str!("../static/htmx.min.js"), "application/javascript"),
+            ("sse.js", include_str!("../static/sse.js"), "application/javascript"),
+            ("app.js", include_str!("../static/app.js"), "application/javascript"),
+            ("keys.js", include_str!("../static/keys.js"), "application/javascript"),
+            ("style.css", include_str!("../static/style.css"), "text/css; charset=utf-8"),
+            ("icon.svg", include_str!("../static/icon.svg"), "image/svg+xml"),
+        ]
+        .into_iter()
+        .map(|(name, body, content_type)| Asset {
+            name,
+            body,
+            content_type,
+            hash: hash(body),
+        })
+        .collect()
+    })
+}
+
+/// Start of the SHA-256 of `text` in hex
+fn hash(text: &str) -> String {
+    digest(&SHA256, text.as_bytes()).as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Every asset's hashed URL
+pub fn urls() -> Vec<String> {
+    assets().iter().map(Asset::url).collect()
+}
+
+/// A hash that changes with any of the assets, naming this build's
+pub fn version() -> String {
+    hash(&urls().concat())
+}
+
+/// The hashed URL of the static file `name`, for linking to it
+pub fn url(name: &str) -> String {
+    assets()
+        .iter()
+        .find(|asset| asset.name == name)
+        .map(Asset::url)
+        .unwrap_or_else(|| format!("/static/{}", name))
+}
+
+/// The asset at `path`, and whether it was asked for by its hashed URL
+pub fn find(path: &str) -> Option<(&'static Asset, bool)> {
+    let name = path.strip_prefix("/static/")?;
+    assets().iter().find_map(|asset| {
+        if asset.name == name {
+            Some((asset, false))
+        } else if asset.url() == path {
+            Some((asset, true))
+        } else {
+            None
+        }
+    })
+}
+
+/// The file `name` stands for in `dir`, if there is one. Names are kept to
+/// plain characters, may not start a component with a dot, and must lead
+/// to a file still inside `dir` once symlinks are followed.
+pub fn resolve(dir: &Path, name: &str) -> Option<PathBuf> {
+    let plain = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/'));
+    if !plain || name.split('/').any(|part| part.is_empty() || part.starts_with('.')) {
+        return None;
+    }
+    let relative = Path::new(name);
+    if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    let root = fs::canonicalize(dir).ok()?;
+    let path = fs::canonicalize(root.join(relative)).ok()?;
+    (path.starts_with(&root) && path.is_file()).then_some(path)
+}
+
+/// The Content-Type for a file in `static_dir`, by its extension
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "css" => "text/css; charset=utf-8",
+        "js" => "application/javascript",
+        "json" | "map" => "application/json",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
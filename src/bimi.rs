@@ -0,0 +1,191 @@
+//! BIMI sender logos for DKIM-verified mail.
+//!
+//! A logo is only shown when an Authentication-Results header reports a DKIM
+//! pass aligned with the From domain and the domain publishes a BIMI record.
+//! TXT lookups go through a DNS-over-HTTPS JSON resolver; logos are fetched
+//! once and cached in memory, and the browser only ever loads them from us.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::config::BimiConfig;
+use crate::{log_debug, log_info, log_warn};
+
+const POSITIVE_TTL: Duration = Duration::from_secs(6 * 3600);
+const NEGATIVE_TTL: Duration = Duration::from_secs(3600);
+const MAX_LOGO_BYTES: u64 = 64 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct CacheEntry {
+    fetched: Instant,
+    logo: Option<Vec<u8>>,
+}
+
+pub struct BimiCache {
+    entries: RwLock<HashMap<String, CacheEntry>>,
+}
+
+impl BimiCache {
+    pub fn new() -> Self {
+        BimiCache {
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Cached logo for a domain, without triggering any lookup
+    pub fn logo(&self, domain: &str) -> Option<Vec<u8>> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(&domain.to_ascii_lowercase())
+            .and_then(|e| e.logo.clone())
+    }
+
+    /// Make sure the domain's logo is cached (or known to be absent) and
+    /// report whether one is available
+    pub fn ensure(&self, domain: &str, config: &BimiConfig) -> bool {
+        let domain = domain.to_ascii_lowercase();
+        if let Some(entry) = self.entries.read().unwrap().get(&domain) {
+            let ttl = if entry.logo.is_some() { POSITIVE_TTL } else { NEGATIVE_TTL };
+            if entry.fetched.elapsed() < ttl {
+                return entry.logo.is_some();
+            }
+        }
+
+        let logo = fetch_logo(&domain, config);
+        let found = logo.is_some();
+        self.entries.write().unwrap().insert(
+            domain,
+            CacheEntry {
+                fetched: Instant::now(),
+                logo,
+            },
+        );
+        found
+    }
+}
+
+/// Domain part of an address, lowercased
+pub fn address_domain(address: &str) -> Option<String> {
+    address
+        .rsplit_once('@')
+        .map(|(_, d)| d.trim_end_matches('>').to_ascii_lowercase())
+        .filter(|d| d.contains('.'))
+}
+
+/// True if any Authentication-Results header (from the trusted authserv-id,
+/// when configured) reports dkim=pass for the From domain or a parent of it
+pub fn dkim_aligned_pass(
+    auth_results: &[String],
+    from_domain: &str,
+    trusted_authserv_id: Option<&str>,
+) -> bool {
+    auth_results.iter().any(|header| {
+        let mut parts = header.split(';');
+        let authserv_id = parts.next().unwrap_or("").trim();
+        if let Some(trusted) = trusted_authserv_id {
+            let id = authserv_id.split_whitespace().next().unwrap_or("");
+            if !id.eq_ignore_ascii_case(trusted) {
+                return false;
+            }
+        }
+
+        parts.any(|result| {
+            let result = result.trim().to_ascii_lowercase();
+            if !result.starts_with("dkim=pass") {
+                return false;
+            }
+            result
+                .split_whitespace()
+                .filter_map(|token| token.strip_prefix("header.d="))
+                .any(|d| {
+                    let d = d.trim_matches(|c| c == '"' || c == ')');
+                    from_domain == d || from_domain.ends_with(&format!(".{}", d))
+                })
+        })
+    })
+}
+
+/// Look up default._bimi at the domain (then its parent, as an approximation of
+/// the organizational domain) and download the SVG it points to
+fn fetch_logo(domain: &str, config: &BimiConfig) -> Option<Vec<u8>> {
+    let mut candidates = vec![domain.to_string()];
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() > 2 {
+        candidates.push(labels[labels.len() - 2..].join("."));
+    }
+
+    let location = candidates
+        .iter()
+        .find_map(|d| lookup_bimi_location(d, &config.doh_url))?;
+
+    if !location.starts_with("https://") {
+        log_warn!("[BIMI] Ignoring non-HTTPS logo location for {}: {}", domain, location);
+        return None;
+    }
+
+    log_debug!("[BIMI] Fetching logo for {} from {}", domain, location);
+    let response = ureq::AgentBuilder::new()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .get(&location)
+        .call()
+        .map_err(|e| log_warn!("[BIMI] Logo fetch for {} failed: {}", domain, e))
+        .ok()?;
+
+    let mut body = Vec::new();
+    std::io::Read::read_to_end(
+        &mut std::io::Read::take(response.into_reader(), MAX_LOGO_BYTES + 1),
+        &mut body,
+    )
+    .ok()?;
+    if body.len() as u64 > MAX_LOGO_BYTES {
+        log_warn!("[BIMI] Logo for {} exceeds {} bytes", domain, MAX_LOGO_BYTES);
+        return None;
+    }
+
+    let text = String::from_utf8_lossy(&body).to_ascii_lowercase();
+    if !text.contains("<svg") || text.contains("<script") {
+        log_warn!("[BIMI] Logo for {} is not an acceptable SVG", domain);
+        return None;
+    }
+
+    log_info!("[BIMI] Cached logo for {} ({} bytes)", domain, body.len());
+    Some(body)
+}
+
+/// Resolve the l= tag of the domain's BIMI TXT record via DNS-over-HTTPS
+fn lookup_bimi_location(domain: &str, doh_url: &str) -> Option<String> {
+    let name = format!("default._bimi.{}", domain);
+    let response: serde_json::Value = ureq::AgentBuilder::new()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .get(doh_url)
+        .query("name", &name)
+        .query("type", "TXT")
+        .set("Accept", "application/dns-json")
+        .call()
+        .map_err(|e| log_warn!("[BIMI] DNS lookup for {} failed: {}", name, e))
+        .ok()?
+        .into_json()
+        .ok()?;
+
+    response["Answer"].as_array()?.iter().find_map(|answer| {
+        // TXT data arrives as one or more quoted strings to concatenate
+        let data: String = answer["data"]
+            .as_str()?
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .collect();
+        let data = if data.is_empty() { answer["data"].as_str()?.to_string() } else { data };
+        if !data.trim_start().starts_with("v=BIMI1") {
+            return None;
+        }
+        data.split(';')
+            .filter_map(|tag| tag.trim().strip_prefix("l="))
+            .map(|l| l.trim().to_string())
+            .find(|l| !l.is_empty())
+    })
+}
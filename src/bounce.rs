@@ -0,0 +1,138 @@
+//! Bounces: the delivery status notifications (RFC 3464) a mail server
+//! sends back about a message it couldn't deliver, so the viewer can say
+//! who it didn't reach and why rather than list the report's parts.
+//!
+//! A notification is a multipart/report whose machine-readable part,
+//! message/delivery-status (message/global-delivery-status when the
+//! addresses aren't ASCII), has a block of fields per recipient. Most also
+//! return the message itself, or its headers, from which the copy in Sent
+//! is found again to resend.
+
+use crate::jmap::{BodyPart, Email};
+
+/// A recipient the message didn't reach
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub recipient: String,
+    /// The receiving server's reply, or the status code when it gave none
+    pub reason: String,
+}
+
+/// What a notification reports
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bounce {
+    pub failures: Vec<Failure>,
+    /// The failed message's own id, read from the copy returned with the
+    /// report, and the sent message with it, if it is still in the account
+    pub message_id: Option<String>,
+    pub original_id: Option<String>,
+}
+
+fn is_type(part: &BodyPart, wanted: &[&str]) -> bool {
+    part.r#type.as_deref().is_some_and(|t| wanted.iter().any(|w| t.eq_ignore_ascii_case(w)))
+}
+
+/// The report's status part, and the part returning the message or its
+/// headers, if `email` is a delivery status notification
+pub fn report_parts(email: &Email) -> Option<(&BodyPart, Option<&BodyPart>)> {
+    let mut queue: Vec<&BodyPart> = email.body_structure.iter().collect();
+    while !queue.is_empty() {
+        let part = queue.remove(0);
+        let children = part.sub_parts.as_deref().unwrap_or_default();
+        if is_type(part, &["multipart/report"]) {
+            let status = children
+                .iter()
+                .find(|c| c.blob_id.is_some() && is_type(c, &["message/delivery-status", "message/global-delivery-status"]));
+            if let Some(status) = status {
+                let returned = children.iter().find(|c| {
+                    c.blob_id.is_some()
+                        && is_type(c, &["message/rfc822", "text/rfc822-headers", "message/global", "message/global-headers"])
+                });
+                return Some((status, returned));
+            }
+        }
+        queue.extend(children);
+    }
+    None
+}
+
+/// Whether `part` is one of the report's own parts, which say nothing to a
+/// reader once the report is shown
+pub fn is_report_part(part: &BodyPart) -> bool {
+    is_type(
+        part,
+        &["message/delivery-status", "message/global-delivery-status", "text/rfc822-headers", "message/global-headers"],
+    )
+}
+
+impl Bounce {
+    /// The failures in a message/delivery-status part's text and the
+    /// Message-ID in `returned`, the headers of the message sent back.
+    /// `None` when no recipient failed, as for a report of delivery or of
+    /// a delay.
+    pub fn parse(status: &str, returned: Option<&str>) -> Option<Bounce> {
+        let status = status.replace("\r\n", "\n");
+        // The first block is about the message, every other one a recipient
+        let failures: Vec<Failure> = status
+            .split("\n\n")
+            .skip(1)
+            .filter_map(|block| {
+                let fields = fields(block);
+                let field = |name: &str| fields.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+                if !field("action")?.eq_ignore_ascii_case("failed") {
+                    return None;
+                }
+                let recipient = field("original-recipient").or(field("final-recipient"))?;
+                let reason = match field("diagnostic-code") {
+                    Some(code) => without_type(code),
+                    None => field("status").unwrap_or(""),
+                };
+                Some(Failure {
+                    recipient: without_type(recipient).to_string(),
+                    reason: reason.to_string(),
+                })
+            })
+            .collect();
+        if failures.is_empty() {
+            return None;
+        }
+        let message_id = returned.and_then(|headers| {
+            let headers = headers.replace("\r\n", "\n");
+            let head = headers.split("\n\n").next().unwrap_or("");
+            let fields = fields(head);
+            let id = fields.iter().find(|(n, _)| n == "message-id")?.1.trim();
+            let id = id.trim_start_matches('<').trim_end_matches('>');
+            (!id.is_empty()).then(|| id.to_string())
+        });
+        Some(Bounce {
+            failures,
+            message_id,
+            original_id: None,
+        })
+    }
+}
+
+/// A block's fields, names lowercased and folded lines joined
+fn fields(block: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in block.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+/// `rfc822; ann@example.com` without its `rfc822;`, and the same for
+/// `smtp; 550 ...`
+fn without_type(value: &str) -> &str {
+    value.split_once(';').map_or(value, |(_, rest)| rest).trim()
+}
+
+#[cfg(test)]
+mod tests;
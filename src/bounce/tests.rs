@@ -0,0 +1,44 @@
+use super::*;
+
+const STATUS: &str = "Reporting-MTA: dns; mx.example.com\r\n\
+Arrival-Date: Tue, 3 Mar 2020 10:00:00 +0000\r\n\
+\r\n\
+Final-Recipient: rfc822; ann@example.org\r\n\
+Action: failed\r\n\
+Status: 5.1.1\r\n\
+Diagnostic-Code: smtp; 550 5.1.1 <ann@example.org>: Recipient address\r\n\
+\x20rejected; user unknown\r\n\
+\r\n\
+Original-Recipient: rfc822;Bo@Example.org\r\n\
+Final-Recipient: rfc822;bo@mail.example.org\r\n\
+Action: failed\r\n\
+Status: 5.2.2\r\n\
+\r\n\
+Final-Recipient: rfc822; cy@example.org\r\n\
+Action: delayed\r\n\
+Status: 4.4.1\r\n";
+
+#[test]
+fn failed_recipients_and_why() {
+    let returned = "Received: from client\r\nMessage-Id:\r\n <plan-1@example.com>\r\nSubject: Plans\r\n\r\nThe body";
+    let bounce = Bounce::parse(STATUS, Some(returned)).unwrap();
+    assert_eq!(
+        bounce.failures,
+        [
+            Failure {
+                recipient: "ann@example.org".to_string(),
+                reason: "550 5.1.1 <ann@example.org>: Recipient address rejected; user unknown".to_string(),
+            },
+            Failure {
+                recipient: "Bo@Example.org".to_string(),
+                reason: "5.2.2".to_string(),
+            },
+        ]
+    );
+    assert_eq!(bounce.message_id.as_deref(), Some("plan-1@example.com"));
+
+    assert_eq!(Bounce::parse(STATUS, None).unwrap().message_id, None);
+    // Only delayed, so nothing bounced yet
+    let delayed = "Reporting-MTA: dns; mx.example.com\n\nFinal-Recipient: rfc822; cy@example.org\nAction: delayed\n";
+    assert_eq!(Bounce::parse(delayed, None), None);
+}
@@ -0,0 +1,130 @@
+//! The week ahead in the account's calendars (JMAP Calendars), for the
+//! agenda the sidebar links to.
+//!
+//! Events are JSCalendar objects: a local start in a time zone (none for a
+//! floating time, taken in the reader's) and a nominal duration. Times are
+//! worked out here, so the server's own UTC conversion isn't needed.
+
+use crate::dates;
+use crate::ical::EventTime;
+use crate::jmap::CalendarEvent;
+
+/// Days the agenda covers, today included
+pub const DAYS: i64 = 7;
+
+/// One event, or one occurrence of a recurring one, as the agenda shows it
+pub struct Occurrence {
+    pub title: String,
+    pub location: Option<String>,
+    pub start: EventTime,
+    pub end: Option<EventTime>,
+}
+
+impl Occurrence {
+    /// The day it is listed under, as days since the epoch in the reader's
+    /// zone
+    pub fn day(&self) -> i64 {
+        match self.start {
+            EventTime::At(time) => dates::day_of(time),
+            EventTime::Date { year, month, day } => dates::days_from_civil(year, month, day),
+        }
+    }
+
+    fn sort_key(&self) -> i64 {
+        match self.start {
+            EventTime::At(time) => time,
+            _ => dates::from_local(None, self.day(), 0),
+        }
+    }
+}
+
+/// The agenda's span, from the start of today for [`DAYS`] days, in
+/// seconds since the epoch
+pub fn span() -> (i64, i64) {
+    let today = dates::today();
+    (dates::from_local(None, today, 0), dates::from_local(None, today + DAYS, 0))
+}
+
+/// The events in start order, those without a start one can make sense of
+/// left out
+pub fn occurrences(events: &[CalendarEvent]) -> Vec<Occurrence> {
+    let mut occurrences: Vec<Occurrence> = events.iter().filter_map(occurrence).collect();
+    occurrences.sort_by_key(Occurrence::sort_key);
+    occurrences
+}
+
+fn occurrence(event: &CalendarEvent) -> Option<Occurrence> {
+    let (days, seconds) = local_date_time(&event.start)?;
+    let (length_days, length_seconds) = event.duration.as_deref().and_then(duration).unwrap_or((0, 0));
+    let (start, end) = if event.show_without_time {
+        let (year, month, day) = dates::civil_from_days(days);
+        // Like an iCalendar DTEND, the end is the day after the last one
+        let end = (length_days > 0).then(|| {
+            let (year, month, day) = dates::civil_from_days(days + length_days);
+            EventTime::Date { year, month, day }
+        });
+        (EventTime::Date { year, month, day }, end)
+    } else {
+        let start = dates::from_local(event.time_zone.as_deref(), days, seconds);
+        let length = length_days * 86400 + length_seconds;
+        (EventTime::At(start), (length > 0).then_some(EventTime::At(start + length)))
+    };
+    // Locations are keyed by made-up ids; the lowest keeps the pick stable
+    let location = event
+        .locations
+        .iter()
+        .flatten()
+        .filter(|(_, location)| location.name.as_deref().is_some_and(|name| !name.trim().is_empty()))
+        .min_by_key(|(id, _)| *id)
+        .and_then(|(_, location)| location.name.clone());
+    Some(Occurrence {
+        title: event.title.clone(),
+        location,
+        start,
+        end,
+    })
+}
+
+/// A LocalDateTime, `2026-10-20T09:30:00`, as days since the epoch and
+/// seconds into the day
+fn local_date_time(value: &str) -> Option<(i64, i64)> {
+    let field = |range: std::ops::Range<usize>| value.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let seconds = match value.get(10..11) {
+        Some("T") => field(11..13)? * 3600 + field(14..16)? * 60 + field(17..19).unwrap_or(0),
+        _ => 0,
+    };
+    Some((dates::days_from_civil(year, month, day), seconds))
+}
+
+/// An ISO 8601 duration, `P1W`, `P2DT1H30M` or `PT45M`, as whole days and
+/// seconds
+fn duration(value: &str) -> Option<(i64, i64)> {
+    let mut days = 0;
+    let mut seconds = 0;
+    let mut in_time = false;
+    let mut number = String::new();
+    for c in value.strip_prefix('P')?.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if c == 'T' && number.is_empty() {
+            in_time = true;
+            continue;
+        }
+        let n: i64 = std::mem::take(&mut number).parse().ok()?;
+        match (in_time, c) {
+            (false, 'W') => days += n * 7,
+            (false, 'D') => days += n,
+            (true, 'H') => seconds += n * 3600,
+            (true, 'M') => seconds += n * 60,
+            (true, 'S') => seconds += n,
+            _ => return None,
+        }
+    }
+    number.is_empty().then_some((days, seconds))
+}
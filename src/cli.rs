@@ -0,0 +1,368 @@
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::discovery;
+use crate::jmap::{EmailAddress, JmapClient, OutgoingEmail, Transport};
+use crate::log::Level;
+use crate::log_info;
+use crate::secret::Key;
+use crate::tls;
+
+const USAGE: &str = "usage: rust-jmap-webmail [options] [send-test ... | check [--offline]]\n\
+     \n  --config <path>        read this instead of ./config.toml\
+     \n  --listen <addr:port>   listen here instead of server.listen_addr/listen_port;\
+     \n                         unix:<path> for a Unix socket\
+     \n  --log-level <level>    debug, info, warn or error\
+     \n  --demo                 use a built-in fake JMAP backend\
+     \n  --version              print the version and exit";
+
+/// Options for the server itself, given before any command
+pub struct Options {
+    pub config_path: String,
+    pub listen: Option<String>,
+    pub log_level: Option<Level>,
+    pub demo: bool,
+    /// What followed `send-test`, when that is the command
+    pub send_test: Option<Vec<String>>,
+    /// What followed `check`, when that is the command
+    pub check: Option<Vec<String>>,
+}
+
+/// Read the command line. `Ok(None)` means it only asked for the version
+/// or help, which has been printed.
+pub fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        config_path: "config.toml".to_string(),
+        listen: None,
+        log_level: None,
+        demo: false,
+        send_test: None,
+        check: None,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("{} requires a value\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--config" => options.config_path = value()?,
+            "--listen" => options.listen = Some(value()?),
+            "--log-level" => {
+                let level = value()?;
+                options.log_level = Some(level.parse().map_err(|e| format!("{}\n{}", e, USAGE))?);
+            }
+            "--demo" => options.demo = true,
+            "--version" | "-V" => {
+                println!("rust-jmap-webmail {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return Ok(None);
+            }
+            "send-test" => {
+                options.send_test = Some(iter.cloned().collect());
+                break;
+            }
+            "check" => {
+                options.check = Some(iter.cloned().collect());
+                break;
+            }
+            other => return Err(format!("unknown argument: {}\n{}", other, USAGE)),
+        }
+    }
+    Ok(Some(options))
+}
+
+impl Options {
+    /// Put the command line's overrides into `config`, over both the file
+    /// and the environment
+    pub fn apply(&self, config: &mut Config) -> Result<(), String> {
+        if let Some(listen) = &self.listen {
+            let server = &mut config.server;
+            if let Some(path) = listen.strip_prefix("unix:") {
+                server.listen_socket = Some(path.to_string());
+            } else {
+                let (addr, port) = listen
+                    .rsplit_once(':')
+                    .and_then(|(addr, port)| Some((addr, port.parse().ok()?)))
+                    .ok_or_else(|| format!("--listen {} is not <addr:port> or unix:<path>", listen))?;
+                server.listen_addr = addr.to_string();
+                server.listen_port = port;
+                server.listen_socket = None;
+            }
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        Ok(())
+    }
+}
+
+const SEND_TEST_USAGE: &str =
+    "usage: rust-jmap-webmail send-test --to <address> [--username <user>] [--identity <email>] [--list-identities]\n\
+     \x20      [--server <name>]\n\
+     The password is read from WEBMAIL_PASSWORD; the username may also come from WEBMAIL_USERNAME.";
+
+/// `send-test`: discover the JMAP session, log in and send a test message
+/// through the same JmapClient code path the web UI uses.
+pub fn send_test(config: &Config, args: &[String]) -> Result<(), String> {
+    let mut to = None;
+    let mut username = std::env::var("WEBMAIL_USERNAME").ok();
+    let mut identity_email = None;
+    let mut list_identities = false;
+    let mut server_name = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("{} requires a value\n{}", arg, SEND_TEST_USAGE))
+        };
+        match arg.as_str() {
+            "--to" => to = Some(value()?),
+            "--username" => username = Some(value()?),
+            "--identity" => identity_email = Some(value()?),
+            "--list-identities" => list_identities = true,
+            "--server" => server_name = Some(value()?),
+            "--help" | "-h" => {
+                println!("{}", SEND_TEST_USAGE);
+                return Ok(());
+            }
+            other => return Err(format!("unknown argument: {}\n{}", other, SEND_TEST_USAGE)),
+        }
+    }
+
+    if to.is_none() && !list_identities {
+        return Err(format!("--to is required\n{}", SEND_TEST_USAGE));
+    }
+    let username = username.ok_or_else(|| format!("--username is required\n{}", SEND_TEST_USAGE))?;
+    let password = std::env::var("WEBMAIL_PASSWORD")
+        .map_err(|_| format!("WEBMAIL_PASSWORD is not set\n{}", SEND_TEST_USAGE))?;
+
+    let jmap = &config.jmap;
+    let server = match &server_name {
+        Some(name) => Some(
+            jmap.servers
+                .iter()
+                .find(|s| s.name == *name)
+                .ok_or_else(|| format!("no [[jmap.servers]] named {}", name))?,
+        ),
+        None => jmap.default_server(),
+    };
+
+    // Without a server configured, autodiscovery is on
+    let discovered = match server {
+        Some(server) => JmapClient::discover(
+            &server.well_known_url,
+            &username,
+            &password,
+            jmap.auth.schemes(),
+            jmap.http_options(),
+        ),
+        None => discovery::discover(&username, &password, jmap.auth.schemes(), jmap)
+            .map(|(_url, session, client)| (session, client)),
+    };
+    let (_session, client) = discovered.map_err(|e| format!("discovery failed: {}", e))?;
+
+    let identities = client
+        .get_identities()
+        .map_err(|e| format!("failed to fetch identities: {}", e))?;
+    if list_identities {
+        for identity in &identities {
+            println!("{}\t{} <{}>", identity.id, identity.name, identity.email);
+        }
+        return Ok(());
+    }
+
+    let Some(to) = to else {
+        return Err(format!("--to is required\n{}", SEND_TEST_USAGE));
+    };
+    let identity = match &identity_email {
+        Some(email) => identities.iter().find(|i| i.email.eq_ignore_ascii_case(email)),
+        None => identities.first(),
+    }
+    .ok_or_else(|| match &identity_email {
+        Some(email) => format!("no identity with address {}", email),
+        None => "account has no sending identities".to_string(),
+    })?;
+    log_info!("Sending test message as {} <{}>", identity.name, identity.email);
+
+    let email = OutgoingEmail {
+        to: vec![EmailAddress {
+            name: None,
+            email: Some(to.clone()),
+        }],
+        subject: "rust-jmap-webmail test message".to_string(),
+        text_body: format!(
+            "This is a test message sent by `rust-jmap-webmail send-test` at {}.\n\n\
+             If you received it, JMAP discovery, authentication and submission work.\n",
+            crate::log::now()
+        ),
+        ..Default::default()
+    };
+
+    let email_id = client
+        .send_email(identity, &email)
+        .map_err(|e| format!("send failed: {}", e))?;
+
+    println!("Test message sent to {} (email id {})", to, email_id);
+    Ok(())
+}
+
+const CHECK_USAGE: &str = "usage: rust-jmap-webmail check [--offline]\n\
+     Validates the configuration and, unless --offline, that each JMAP server answers at\n\
+     its well-known URL. Exits with status 1 if anything is wrong.";
+
+/// `check`: look over the loaded config for mistakes that would only show
+/// up once the server is running, and see whether the JMAP server answers,
+/// printing a line per check
+pub fn check(config: &Config, args: &[String]) -> Result<(), String> {
+    let mut offline = false;
+    for arg in args {
+        match arg.as_str() {
+            "--offline" => offline = true,
+            "--help" | "-h" => {
+                println!("{}", CHECK_USAGE);
+                return Ok(());
+            }
+            other => return Err(format!("unknown argument: {}\n{}", other, CHECK_USAGE)),
+        }
+    }
+
+    let mut results = vec![("listen address".to_string(), check_listen(config))];
+    if let Some(tls) = &config.server.tls {
+        results.push(("TLS certificate and key".to_string(), tls::server_config(tls).map(|_| ())));
+    }
+    for server in &config.jmap.servers {
+        results.push((format!("JMAP server {} URL", server.name), check_url(&server.well_known_url)));
+    }
+    for (id, provider) in &config.oauth {
+        let urls = [
+            ("authorize_url", &provider.authorize_url),
+            ("token_url", &provider.token_url),
+            ("redirect_url", &provider.redirect_url),
+        ];
+        let result = urls
+            .iter()
+            .try_for_each(|(name, url)| check_url(url).map_err(|e| format!("{}: {}", name, e)));
+        results.push((format!("[oauth.{}]", id), result));
+    }
+    if config.bimi.enabled {
+        results.push(("bimi.doh_url".to_string(), check_url(&config.bimi.doh_url)));
+    }
+    results.push(("[sessions]".to_string(), check_sessions(config)));
+    if let Some(path) = &config.prefs.path {
+        results.push(("prefs.path".to_string(), check_parent(path)));
+    }
+    if let Some(access) = &config.log.access {
+        if access.path != "-" {
+            results.push(("log.access.path".to_string(), check_parent(&access.path)));
+        }
+    }
+    if !offline {
+        // A URL that can't be right has already been reported
+        for server in config.jmap.servers.iter().filter(|s| check_url(&s.well_known_url).is_ok()) {
+            results.push((format!("JMAP server {}", server.name), check_jmap(&server.well_known_url, config)));
+        }
+    }
+
+    let mut failed = 0;
+    for (what, result) in &results {
+        match result {
+            Ok(()) => println!("ok    {}", what),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL  {}: {}", what, e);
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        1 => Err("1 problem found".to_string()),
+        n => Err(format!("{} problems found", n)),
+    }
+}
+
+fn check_listen(config: &Config) -> Result<(), String> {
+    let server = &config.server;
+    match &server.listen_socket {
+        Some(_) if server.tls.is_some() => Err("[server.tls] can't be used with listen_socket".to_string()),
+        Some(path) => check_parent(path),
+        None => {
+            let addr = config.listen_address();
+            let mut addrs = addr
+                .to_socket_addrs()
+                .map_err(|e| format!("{} does not resolve: {}", addr, e))?;
+            addrs
+                .next()
+                .map(|_| ())
+                .ok_or_else(|| format!("{} does not resolve", addr))
+        }
+    }
+}
+
+/// Whether the server could make requests to `url` at all
+fn check_url(url: &str) -> Result<(), String> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .ok_or_else(|| format!("{} is not an http:// or https:// URL", url))?;
+    if rest.split(['/', '?', '#']).next().unwrap_or("").is_empty() {
+        return Err(format!("{} has no host", url));
+    }
+    Ok(())
+}
+
+/// Whether a file can be created at `path`
+fn check_parent(path: &str) -> Result<(), String> {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+            Err(format!("directory {} does not exist", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_sessions(config: &Config) -> Result<(), String> {
+    let sessions = &config.sessions;
+    if sessions.idle_minutes == 0 || sessions.max_hours == 0 {
+        return Err("idle_minutes and max_hours must be more than 0".to_string());
+    }
+    match (&sessions.path, &sessions.key) {
+        (Some(_), None) => Err("path is set but key is not".to_string()),
+        (Some(path), Some(key)) => {
+            Key::from_base64(key).map_err(|e| format!("invalid key: {}", e))?;
+            check_parent(path)
+        }
+        (None, _) => Ok(()),
+    }
+}
+
+/// Ask for the session without credentials, which a JMAP server should
+/// refuse with 401 or answer with a redirect to the real endpoint
+fn check_jmap(url: &str, config: &Config) -> Result<(), String> {
+    let transport = Transport::new(config.jmap.http_options());
+    match transport.send("Session check", true, |agent| agent.get(url), None) {
+        Ok(response) => match response.status() {
+            300..=399 => {
+                log_info!("{} redirects to {}", url, response.header("Location").unwrap_or("nowhere"));
+                Ok(())
+            }
+            status => {
+                log_info!("{} answered {} without credentials", url, status);
+                Ok(())
+            }
+        },
+        Err(e) => match *e {
+            ureq::Error::Status(401, _) => Ok(()),
+            ureq::Error::Status(code, _) => Err(format!("{} answered with status {}", url, code)),
+            ureq::Error::Transport(e) => Err(format!("could not reach {}: {}", url, e)),
+        },
+    }
+}
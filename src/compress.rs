@@ -0,0 +1,92 @@
+//! gzip and deflate for the responses built in memory: pages, fragments
+//! and the scripts. Blobs are streamed through as they are, since mail
+//! attachments are mostly compressed already.
+//!
+//! Those responses are made by helpers that don't see the request, so
+//! [`negotiate`] notes what the client accepts when a request comes in and
+//! [`encode`] picks it up from there.
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use std::cell::Cell;
+use std::io::Write;
+
+use crate::config::CompressionConfig;
+use crate::log_warn;
+
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+thread_local! {
+    /// The encoding the client of this thread's request takes best
+    static ACCEPTED: Cell<Option<Encoding>> = const { Cell::new(None) };
+}
+
+/// Note which encoding the current request's Accept-Encoding allows,
+/// preferring gzip
+pub fn negotiate(accept_encoding: Option<&str>) {
+    let mut gzip = false;
+    let mut deflate = false;
+    for item in accept_encoding.unwrap_or("").split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        // "gzip;q=0" is a refusal
+        let refused = parts.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = !refused,
+            "deflate" => deflate = !refused,
+            _ => {}
+        }
+    }
+    let accepted = if gzip {
+        Some(Encoding::Gzip)
+    } else if deflate {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    };
+    ACCEPTED.with(|cell| cell.set(accepted));
+}
+
+/// `body` compressed for the current request if it is big enough to be
+/// worth it, along with the Content-Encoding to send it with
+pub fn encode(config: &CompressionConfig, body: Vec<u8>) -> (Vec<u8>, Option<&'static str>) {
+    let Some(encoding) = ACCEPTED.with(Cell::get).filter(|_| config.enabled && body.len() >= config.min_bytes) else {
+        return (body, None);
+    };
+    let compressed = match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).and_then(|_| encoder.finish())
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).and_then(|_| encoder.finish())
+        }
+    };
+    match compressed {
+        Ok(compressed) => (compressed, Some(encoding.name())),
+        Err(e) => {
+            log_warn!("Sending response uncompressed: {}", e);
+            (body, None)
+        }
+    }
+}
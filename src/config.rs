@@ -1,28 +1,981 @@
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
+use std::net::IpAddr;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::access::Cidr;
+use crate::access_log::AccessFormat;
+use crate::dates;
+use crate::i18n;
+use crate::jmap::{client_tls, AuthScheme, HttpOptions};
+use crate::log::{Format, Level};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub jmap: JmapConfig,
+    #[serde(default)]
+    pub compose: ComposeConfig,
+    #[serde(default)]
+    pub pdf: PdfConfig,
+    #[serde(default)]
+    pub bimi: BimiConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub prefs: PrefsConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub poll: PollConfig,
+    #[serde(default)]
+    pub sessions: SessionConfig,
+    #[serde(default)]
+    pub totp: TotpConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub branding: BrandingConfig,
+    #[serde(default)]
+    pub i18n: I18nConfig,
+    #[serde(default)]
+    pub users: UserLimitsConfig,
+    /// Providers offered on the login page, under `[oauth.<id>]`
+    #[serde(default)]
+    pub oauth: BTreeMap<String, OAuthProvider>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
     pub listen_addr: String,
     pub listen_port: u16,
+    /// Listen on this Unix socket instead of `listen_addr`/`listen_port`,
+    /// for a reverse proxy on the same host
+    #[serde(default)]
+    pub listen_socket: Option<String>,
+    /// Permissions for `listen_socket`, such as `0o660`
+    #[serde(default)]
+    pub socket_mode: Option<u32>,
+    /// Serve HTTPS directly on the listen address
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Path the webmail is mounted under behind a reverse proxy, such as
+    /// `/webmail`; empty when it has the whole host
+    #[serde(default, deserialize_with = "base_path")]
+    pub base_path: String,
+    #[serde(default)]
+    pub cookie: CookieConfig,
+    #[serde(default)]
+    pub headers: SecurityHeaders,
+    #[serde(default)]
+    pub access: AccessConfig,
+    /// Reverse proxies whose X-Forwarded-For and X-Forwarded-Proto are
+    /// believed
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub passthrough: PassthroughConfig,
+    /// Directory of further files to serve under `/static/`, such as logos,
+    /// a favicon.ico or a custom.css. Anything in it is public.
+    #[serde(default)]
+    pub static_dir: Option<String>,
+    /// Stylesheet loaded by every page after the built-in one, to restyle
+    /// it; by default a custom.css in `static_dir`
+    #[serde(default)]
+    pub custom_css: Option<String>,
+}
+
+/// `base_path` with a leading slash and without a trailing one, so that
+/// it can go straight in front of a root-relative URL. Only characters
+/// that need no escaping in a URL or in markup are taken.
+fn base_path<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let path = String::deserialize(deserializer)?;
+    let path = path.trim().trim_end_matches('/');
+    if !path.is_empty() && !path.starts_with('/') {
+        return Err(serde::de::Error::custom(format!("base_path {:?} must start with /", path)));
+    }
+    if !path.chars().all(|c| c.is_ascii_alphanumeric() || "/-._~".contains(c)) {
+        return Err(serde::de::Error::custom(format!(
+            "base_path {:?} may only have letters, digits and / - . _ ~",
+            path
+        )));
+    }
+    Ok(path.to_string())
+}
+
+/// Sign in with credentials every request brings, from the browser's
+/// Basic auth or an authenticating proxy in front, instead of the login page
+#[derive(Debug, Default, Deserialize)]
+pub struct PassthroughConfig {
+    /// Take `Authorization: Basic`, asking the browser for it when it is
+    /// missing or the JMAP server turns it down
+    #[serde(default)]
+    pub basic: bool,
+    /// The headers a trusted proxy puts the username and the password (or
+    /// API token) in; set both or neither
+    #[serde(default)]
+    pub user_header: Option<String>,
+    #[serde(default)]
+    pub password_header: Option<String>,
+}
+
+impl PassthroughConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.basic || self.user_header.is_some()
+    }
+}
+
+/// Which client addresses may connect. A denied address is refused even if
+/// it is also allowed; with no allow list, anything not denied is allowed.
+/// Behind a reverse proxy this needs `trusted_proxies` to see past it.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AccessConfig {
+    #[serde(default)]
+    pub allow: Vec<Cidr>,
+    #[serde(default)]
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessConfig {
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(addr))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(addr)))
+    }
+}
+
+/// Compressing pages and scripts for clients that accept it. Worth turning
+/// off when a reverse proxy in front compresses anyway.
+#[derive(Debug, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default = "default_compression_enabled")]
+    pub enabled: bool,
+    /// Smaller responses go out as they are
+    #[serde(default = "default_compression_min_bytes")]
+    pub min_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: default_compression_enabled(),
+            min_bytes: default_compression_min_bytes(),
+        }
+    }
+}
+
+fn default_compression_enabled() -> bool {
+    true
+}
+
+fn default_compression_min_bytes() -> usize {
+    1024
+}
+
+/// How much a client may send, and how long it may leave the connection
+/// idle, so that one can't run the server out of memory or hold it up
+#[derive(Debug, Deserialize)]
+pub struct LimitsConfig {
+    /// The body of a sign-in
+    #[serde(default = "default_login_kb")]
+    pub login_kb: u64,
+    /// The body of any other form
+    #[serde(default = "default_form_kb")]
+    pub form_kb: u64,
+    /// A message sent or saved with its attachments, or mail imported
+    #[serde(default = "default_upload_mb")]
+    pub upload_mb: u64,
+    /// How long a client may go without sending anything, part way through
+    /// a request or between requests on a kept-alive connection
+    #[serde(default = "default_client_read_timeout")]
+    pub read_timeout_seconds: u64,
+    /// How long a client may go without taking any of a response
+    #[serde(default = "default_client_write_timeout")]
+    pub write_timeout_seconds: u64,
+    /// Connections relayed at once, each taking threads of its own; more
+    /// are turned away until some close. 0 takes any number
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+}
+
+impl LimitsConfig {
+    pub fn login_bytes(&self) -> u64 {
+        self.login_kb * 1024
+    }
+
+    pub fn form_bytes(&self) -> u64 {
+        self.form_kb * 1024
+    }
+
+    pub fn upload_bytes(&self) -> u64 {
+        self.upload_mb * 1024 * 1024
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            login_kb: default_login_kb(),
+            form_kb: default_form_kb(),
+            upload_mb: default_upload_mb(),
+            read_timeout_seconds: default_client_read_timeout(),
+            write_timeout_seconds: default_client_write_timeout(),
+            max_connections: default_max_connections(),
+        }
+    }
+}
+
+fn default_login_kb() -> u64 {
+    16
+}
+
+fn default_form_kb() -> u64 {
+    1024
+}
+
+fn default_upload_mb() -> u64 {
+    25
+}
+
+fn default_client_read_timeout() -> u64 {
+    30
+}
+
+fn default_client_write_timeout() -> u64 {
+    60
+}
+
+fn default_max_connections() -> usize {
+    512
+}
+
+/// Headers sent with every response. An empty value leaves that header out.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityHeaders {
+    #[serde(default = "default_content_security_policy")]
+    pub content_security_policy: String,
+    #[serde(default = "default_frame_options")]
+    pub frame_options: String,
+    #[serde(default = "default_referrer_policy")]
+    pub referrer_policy: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            content_security_policy: default_content_security_policy(),
+            frame_options: default_frame_options(),
+            referrer_policy: default_referrer_policy(),
+        }
+    }
+}
+
+/// Scripts only from our own files, so script in a message can't run even
+/// if it got past the sanitizer. Inline styles stay allowed for the
+/// templates and for mail; images may come from anywhere because remote
+/// images are shown once the user asks. Objects are PDF attachments shown
+/// in the viewer.
+fn default_content_security_policy() -> String {
+    "default-src 'self'; script-src 'self'; style-src 'self' 'unsafe-inline'; img-src * data:; \
+     object-src 'self'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'"
+        .to_string()
+}
+
+fn default_frame_options() -> String {
+    "DENY".to_string()
+}
+
+/// Opening a link in a message mustn't tell the sender which message
+fn default_referrer_policy() -> String {
+    "no-referrer".to_string()
+}
+
+/// A certificate chain and its private key, both PEM files
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Attributes of the session cookie. Behind HTTPS, set `secure` so the
+/// browser never sends it over plain HTTP.
+#[derive(Debug, Deserialize)]
+pub struct CookieConfig {
+    #[serde(default = "default_cookie_name")]
+    pub name: String,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default)]
+    pub same_site: SameSite,
+    /// Send the cookie to subdomains of this domain too; otherwise only to
+    /// the host that set it
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            name: default_cookie_name(),
+            secure: false,
+            same_site: SameSite::default(),
+            domain: None,
+        }
+    }
+}
+
+fn default_cookie_name() -> String {
+    "session".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub enum SameSite {
+    #[default]
+    Strict,
+    Lax,
+    /// Browsers only accept this with Secure, which it implies
+    None,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct JmapConfig {
+    /// The server to use when there is only one. Once loaded it has been
+    /// moved to the front of `servers`.
+    #[serde(default)]
+    pub well_known_url: String,
+    /// Servers to choose between at login, under `[[jmap.servers]]`; the
+    /// first is the default
+    #[serde(default)]
+    pub servers: Vec<JmapServer>,
+    /// Find the server from the domain of the address typed at login,
+    /// instead of or as well as the servers configured
+    #[serde(default)]
+    pub autodiscover: bool,
+    /// DNS-over-HTTPS JSON resolver for autodiscovery's SRV lookups
+    #[serde(default = "default_doh_url")]
+    pub doh_url: String,
+    #[serde(default)]
+    pub auth: JmapAuth,
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout_seconds: u64,
+    /// Longest the server may take to send each part of a response
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout_seconds: u64,
+    /// How many times to retry a request that failed with a network error
+    /// or a 5xx status
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Wait before the first retry; it doubles for each one after
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff_ms: u64,
+    /// Make method calls over JMAP's WebSocket (RFC 8887) when the server
+    /// offers it
+    #[serde(default = "default_websocket")]
+    pub websocket: bool,
+    /// How long mailboxes and list rows fetched are used before the server
+    /// is asked whether they changed
+    #[serde(default = "default_cache_seconds")]
+    pub cache_seconds: u64,
+    #[serde(default)]
+    pub tls: JmapTlsConfig,
+    /// Sign every browser in as this user, without the login page
+    #[serde(default)]
+    pub credentials: Option<JmapCredentials>,
+    /// `tls` ready to use, once loaded
+    #[serde(skip)]
+    client_tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl JmapConfig {
+    /// The server used when none was chosen, such as by OAuth sign-ins.
+    /// Only with `autodiscover` can there be none.
+    pub fn default_server(&self) -> Option<&JmapServer> {
+        self.servers.first()
+    }
+
+    /// Put `well_known_url` in with the other servers, so that unless
+    /// autodiscovering there is at least one there
+    fn collect_servers(&mut self) -> Result<(), String> {
+        let url = std::mem::take(&mut self.well_known_url);
+        if !url.is_empty() && !self.servers.iter().any(|s| s.well_known_url == url) {
+            // Named after its host, though the name is only shown when
+            // there are others to choose from
+            let host = url.split_once("://").map_or(url.as_str(), |(_, rest)| rest);
+            let name = host.split('/').next().unwrap_or(host).to_string();
+            self.servers.insert(0, JmapServer { name, well_known_url: url });
+        }
+        if self.servers.is_empty() && !self.autodiscover {
+            return Err("jmap.well_known_url, [[jmap.servers]] or jmap.autodiscover is required".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            connect_timeout: Duration::from_secs(self.connect_timeout_seconds),
+            read_timeout: Duration::from_secs(self.read_timeout_seconds),
+            retries: self.retries,
+            retry_backoff: Duration::from_millis(self.retry_backoff_ms),
+            tls: self.client_tls.clone(),
+        }
+    }
+}
+
+/// What to trust the JMAP server's certificate on, beyond the public CAs
+#[derive(Debug, Default, Deserialize)]
+pub struct JmapTlsConfig {
+    /// PEM file of further CA certificates, such as a homelab's own CA
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// Accept any certificate at all, which lets anyone in between read the
+    /// mail and the passwords sent to it. Only for trying things out.
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+}
+
+/// A JMAP server offered on the login page
+#[derive(Debug, Clone, Deserialize)]
+pub struct JmapServer {
+    pub name: String,
     pub well_known_url: String,
 }
 
+/// How the password typed at login is sent to the JMAP server. `auto` tries
+/// it as a Basic password first and then as a Bearer API token.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JmapAuth {
+    #[default]
+    Auto,
+    Basic,
+    Bearer,
+}
+
+impl JmapAuth {
+    pub fn schemes(self) -> &'static [AuthScheme] {
+        match self {
+            JmapAuth::Auto => &[AuthScheme::Basic, AuthScheme::Bearer],
+            JmapAuth::Basic => &[AuthScheme::Basic],
+            JmapAuth::Bearer => &[AuthScheme::Bearer],
+        }
+    }
+}
+
+/// An OAuth 2.0 authorization server that issues tokens the JMAP server
+/// accepts as Bearer credentials
+#[derive(Debug, Deserialize)]
+pub struct OAuthProvider {
+    /// Shown on the login button, as in "Sign in with Fastmail"
+    pub name: String,
+    pub client_id: String,
+    /// Only for clients registered as confidential
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    pub authorize_url: String,
+    pub token_url: String,
+    /// This server's /oauth/callback, exactly as registered with the
+    /// provider
+    pub redirect_url: String,
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+/// The one user of a private deployment, signed in without being asked
+#[derive(Debug, Deserialize)]
+pub struct JmapCredentials {
+    pub username: String,
+    /// The password or API token, unless it is in `password_file`
+    #[serde(default)]
+    password: Option<String>,
+    /// A file holding the password, such as a secret a service manager
+    /// provides; read at load, without a trailing newline
+    #[serde(default)]
+    password_file: Option<String>,
+    /// As the login form's `server`: an index into `[[jmap.servers]]`, or
+    /// `auto`; the default server without one
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+impl JmapCredentials {
+    pub fn password(&self) -> &str {
+        self.password.as_deref().unwrap_or_default()
+    }
+
+    /// Take the password from `password_file` if it is there, and make sure
+    /// there is exactly one
+    fn read_password(&mut self) -> Result<(), String> {
+        match (&self.password, &self.password_file) {
+            (Some(_), Some(_)) => Err("jmap.credentials: password and password_file are both set".to_string()),
+            (None, None) => Err("jmap.credentials: password or password_file is required".to_string()),
+            (Some(_), None) => Ok(()),
+            (None, Some(path)) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("jmap.credentials: failed to read {}: {}", path, e))?;
+                self.password = Some(contents.trim_end_matches(['\r', '\n']).to_string());
+                Ok(())
+            }
+        }
+    }
+}
+
+fn default_connect_timeout() -> u64 {
+    HttpOptions::default().connect_timeout.as_secs()
+}
+
+fn default_read_timeout() -> u64 {
+    HttpOptions::default().read_timeout.as_secs()
+}
+
+fn default_retries() -> u32 {
+    HttpOptions::default().retries
+}
+
+fn default_retry_backoff() -> u64 {
+    HttpOptions::default().retry_backoff.as_millis() as u64
+}
+
+fn default_websocket() -> bool {
+    true
+}
+
+fn default_cache_seconds() -> u64 {
+    30
+}
+
+/// What the compose form takes in
+#[derive(Debug, Deserialize)]
+pub struct ComposeConfig {
+    /// Largest file attached on its own, streamed to the JMAP server as it
+    /// arrives; the server's maxSizeUpload applies as well
+    #[serde(default = "default_max_attachment_mb")]
+    pub max_attachment_mb: u64,
+}
+
+impl ComposeConfig {
+    pub fn max_attachment_bytes(&self) -> u64 {
+        self.max_attachment_mb * 1024 * 1024
+    }
+}
+
+impl Default for ComposeConfig {
+    fn default() -> Self {
+        ComposeConfig {
+            max_attachment_mb: default_max_attachment_mb(),
+        }
+    }
+}
+
+fn default_max_attachment_mb() -> u64 {
+    25
+}
+
+/// Settings for the /email/{id}/pdf export
+#[derive(Debug, Deserialize)]
+pub struct PdfConfig {
+    #[serde(default)]
+    pub font: PdfFont,
+    #[serde(default)]
+    pub page_size: PageSize,
+    #[serde(default = "default_pdf_font_size")]
+    pub font_size: f32,
+}
+
+impl Default for PdfConfig {
+    fn default() -> Self {
+        PdfConfig {
+            font: PdfFont::default(),
+            page_size: PageSize::default(),
+            font_size: default_pdf_font_size(),
+        }
+    }
+}
+
+fn default_pdf_font_size() -> f32 {
+    10.0
+}
+
+/// One of the PDF standard fonts, so nothing needs embedding
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PdfFont {
+    #[default]
+    Helvetica,
+    TimesRoman,
+    Courier,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PageSize {
+    #[default]
+    A4,
+    Letter,
+    Legal,
+}
+
+/// Verified sender logos (BIMI). Off by default: lookups reveal sender
+/// domains to the DNS-over-HTTPS resolver and to the logo hosts.
+#[derive(Debug, Deserialize)]
+pub struct BimiConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_doh_url")]
+    pub doh_url: String,
+    /// Only trust Authentication-Results added by this authserv-id
+    #[serde(default)]
+    pub trusted_authserv_id: Option<String>,
+}
+
+impl Default for BimiConfig {
+    fn default() -> Self {
+        BimiConfig {
+            enabled: false,
+            doh_url: default_doh_url(),
+            trusted_authserv_id: None,
+        }
+    }
+}
+
+fn default_doh_url() -> String {
+    "https://cloudflare-dns.com/dns-query".to_string()
+}
+
+/// Automatic purge of old Trash/Junk messages. Days are set by the operator;
+/// each user can switch the policy on or off in settings.
+#[derive(Debug, Deserialize)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub trash_days: Option<u32>,
+    #[serde(default)]
+    pub junk_days: Option<u32>,
+    #[serde(default = "default_retention_interval")]
+    pub interval_minutes: u64,
+    #[serde(default)]
+    pub enabled_by_default: bool,
+}
+
+impl RetentionConfig {
+    pub fn is_configured(&self) -> bool {
+        self.trash_days.is_some() || self.junk_days.is_some()
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            trash_days: None,
+            junk_days: None,
+            interval_minutes: default_retention_interval(),
+            enabled_by_default: false,
+        }
+    }
+}
+
+fn default_retention_interval() -> u64 {
+    60
+}
+
+/// Where per-user preferences such as saved searches are stored. With no
+/// path they are kept in memory only and lost on restart.
+#[derive(Debug, Default, Deserialize)]
+pub struct PrefsConfig {
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub backend: PrefsBackend,
+}
+
+/// What the file at `[prefs] path` is: one JSON document rewritten after
+/// every change, or a SQLite database with a row per user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefsBackend {
+    #[default]
+    File,
+    Sqlite,
+}
+
+/// Caps on what each user may take of the instance, across all of their
+/// sessions, so that one heavy user can't slow it down for the others.
+/// Nothing is capped unless set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserLimitsConfig {
+    /// For every user
+    #[serde(flatten)]
+    pub everyone: UserLimits,
+    /// For particular usernames, each cap set replacing everyone's; 0
+    /// lifts it
+    #[serde(default)]
+    pub overrides: BTreeMap<String, UserLimits>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct UserLimits {
+    /// Mail kept in memory for the user's lists
+    #[serde(default)]
+    pub cache_mb: Option<u64>,
+    /// Requests to the JMAP server waiting at once; more queue behind them
+    #[serde(default)]
+    pub upstream_calls: Option<usize>,
+    /// A message sent or saved with its attachments, an attachment, or mail
+    /// imported, where lower than the server's own limits
+    #[serde(default)]
+    pub upload_mb: Option<u64>,
+}
+
+impl UserLimitsConfig {
+    /// The caps that apply to `username`, with none left at 0
+    pub fn of(&self, username: &str) -> UserLimits {
+        let own = self.overrides.get(username).copied().unwrap_or_default();
+        UserLimits {
+            cache_mb: own.cache_mb.or(self.everyone.cache_mb).filter(|&mb| mb > 0),
+            upstream_calls: own.upstream_calls.or(self.everyone.upstream_calls).filter(|&calls| calls > 0),
+            upload_mb: own.upload_mb.or(self.everyone.upload_mb).filter(|&mb| mb > 0),
+        }
+    }
+}
+
+/// Where the mailboxes and list rows sessions cache are also kept on disk,
+/// in SQLite. Without a path the cache lasts only as long as the session.
+#[derive(Debug, Default, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+/// How the deployment names itself on its pages
+#[derive(Debug, Deserialize)]
+pub struct BrandingConfig {
+    /// Shown in page titles and on the login form
+    #[serde(default = "default_brand_name")]
+    pub name: String,
+    /// Image above the login form; root-relative URLs such as
+    /// `/static/logo.png` are under `base_path`
+    #[serde(default)]
+    pub logo_url: Option<String>,
+    /// Text shown under the login form, such as a maintenance notice
+    #[serde(default)]
+    pub login_message: Option<String>,
+}
+
+impl Default for BrandingConfig {
+    fn default() -> Self {
+        BrandingConfig {
+            name: default_brand_name(),
+            logo_url: None,
+            login_message: None,
+        }
+    }
+}
+
+fn default_brand_name() -> String {
+    "Webmail".to_string()
+}
+
+/// The language and time zone pages use for users who haven't picked
+/// their own
+#[derive(Debug, Deserialize)]
+pub struct I18nConfig {
+    /// Used when the browser asks for no language there is a catalog for
+    #[serde(default = "default_language")]
+    pub default_language: String,
+    /// A tz database name such as `Europe/Berlin`, `UTC`, or an offset
+    /// such as `+05:30`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+impl Default for I18nConfig {
+    fn default() -> Self {
+        I18nConfig {
+            default_language: default_language(),
+            timezone: default_timezone(),
+        }
+    }
+}
+
+fn default_language() -> String {
+    i18n::FALLBACK.to_string()
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+/// Periodic refresh of the sidebar counts and open list, for JMAP servers
+/// that offer no push. Off unless an interval is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PollConfig {
+    #[serde(default)]
+    pub interval_seconds: Option<u64>,
+}
+
+/// Which log lines are written, and how
+#[derive(Debug, Default, Deserialize)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub level: Level,
+    #[serde(default)]
+    pub format: Format,
+    /// Off unless `[log.access]` is given
+    #[serde(default)]
+    pub access: Option<AccessLogConfig>,
+}
+
+/// Where the access log goes, one line per request
+#[derive(Debug, Deserialize)]
+pub struct AccessLogConfig {
+    /// File to append to, or `-` for standard output
+    pub path: String,
+    #[serde(default)]
+    pub format: AccessFormat,
+}
+
+/// How long a login lasts. A session ends after `idle_minutes` without
+/// the user doing anything, and after `max_hours` in any case.
+#[derive(Debug, Deserialize)]
+pub struct SessionConfig {
+    #[serde(default = "default_idle_minutes")]
+    pub idle_minutes: u64,
+    #[serde(default = "default_max_hours")]
+    pub max_hours: u64,
+    /// Offer "Remember me" at login, for a session that lasts this many
+    /// days regardless of activity and whose cookie outlives the browser
+    #[serde(default)]
+    pub remember_days: Option<u64>,
+    /// File to keep sessions in across restarts; in memory only without one
+    #[serde(default)]
+    pub path: Option<String>,
+    /// 32 bytes of base64 to encrypt `path` with
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+impl SessionConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_minutes * 60)
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_hours * 3600)
+    }
+
+    pub fn remember_age(&self) -> Option<Duration> {
+        self.remember_days.map(|days| Duration::from_secs(days * 86400))
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            idle_minutes: default_idle_minutes(),
+            max_hours: default_max_hours(),
+            remember_days: None,
+            path: None,
+            key: None,
+        }
+    }
+}
+
+/// A one-time code from an authenticator app at sign-in, for the users
+/// who set one up
+#[derive(Debug, Default, Deserialize)]
+pub struct TotpConfig {
+    /// Offer the setup in settings and ask set-up users for a code
+    #[serde(default)]
+    pub enabled: bool,
+    /// The name authenticator apps list the account under; the branding
+    /// name without one
+    #[serde(default)]
+    pub issuer: Option<String>,
+}
+
+fn default_idle_minutes() -> u64 {
+    240
+}
+
+fn default_max_hours() -> u64 {
+    168
+}
+
+/// The settings a SIGHUP can change while running. Everything else in
+/// [`Config`] keeps what the process started with.
+#[derive(Debug, Clone)]
+pub struct LiveSettings {
+    pub access: AccessConfig,
+    pub headers: SecurityHeaders,
+    pub poll: PollConfig,
+}
+
+impl LiveSettings {
+    pub fn from_config(config: &Config) -> Self {
+        LiveSettings {
+            access: config.server.access.clone(),
+            headers: config.server.headers.clone(),
+            poll: config.poll.clone(),
+        }
+    }
+}
+
+/// Prefix of the environment variables that override config.toml. Every
+/// setting is in a table, so an override always has a `__` in it too,
+/// which leaves others such as send-test's `WEBMAIL_PASSWORD` alone.
+const ENV_PREFIX: &str = "WEBMAIL_";
+
 impl Config {
+    /// The config file with any `WEBMAIL_*` environment overrides laid over
+    /// it. With overrides the file may be left out altogether.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
-        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
-        toml::from_str(&contents).map_err(ConfigError::Parse)
+        let overrides: Vec<(String, String)> = std::env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.contains("__"))
+            .collect();
+        let mut table = match fs::read_to_string(path) {
+            Ok(contents) => contents.parse::<toml::Table>().map_err(ConfigError::Parse)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !overrides.is_empty() => toml::Table::new(),
+            Err(e) => return Err(ConfigError::Io(e)),
+        };
+        for (name, value) in &overrides {
+            apply_override(&mut table, name, value).map_err(ConfigError::Env)?;
+        }
+        Self::from_table(table)
+    }
+
+    /// A config from TOML text, as a config file would have it, without
+    /// any environment overrides: for a server embedding the webmail
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        Self::from_table(text.parse::<toml::Table>().map_err(ConfigError::Parse)?)
+    }
+
+    fn from_table(table: toml::Table) -> Result<Self, ConfigError> {
+        let mut config: Config = toml::Value::Table(table).try_into().map_err(ConfigError::Parse)?;
+        config.jmap.collect_servers().map_err(ConfigError::Invalid)?;
+        config.jmap.client_tls = client_tls(&config.jmap.tls).map_err(ConfigError::Invalid)?;
+        let passthrough = &config.server.passthrough;
+        if passthrough.user_header.is_some() != passthrough.password_header.is_some() {
+            return Err(ConfigError::Invalid(
+                "server.passthrough: user_header and password_header go together".to_string(),
+            ));
+        }
+        if let Some(credentials) = &mut config.jmap.credentials {
+            credentials.read_password().map_err(ConfigError::Invalid)?;
+        }
+        if !i18n::is_known(&config.i18n.default_language) {
+            return Err(ConfigError::Invalid(format!(
+                "i18n.default_language: no catalog for {:?}",
+                config.i18n.default_language
+            )));
+        }
+        if dates::zone(&config.i18n.timezone).is_none() {
+            return Err(ConfigError::Invalid(format!("i18n.timezone: unknown time zone {:?}", config.i18n.timezone)));
+        }
+        Ok(config)
     }
 
     pub fn listen_address(&self) -> String {
@@ -30,10 +983,47 @@ impl Config {
     }
 }
 
+/// Set the key an environment variable names, such as `server.listen_port`
+/// for `WEBMAIL_SERVER__LISTEN_PORT`. The value is read as TOML, so numbers,
+/// booleans and arrays work, falling back to a plain string; a key the file
+/// already has as a string stays one, so a numeric password isn't turned
+/// into a number.
+fn apply_override(table: &mut toml::Table, name: &str, value: &str) -> Result<(), String> {
+    let path: Vec<String> = name[ENV_PREFIX.len()..]
+        .split("__")
+        .map(str::to_ascii_lowercase)
+        .collect();
+    let (key, parents) = path
+        .split_last()
+        .filter(|(key, _)| !key.is_empty())
+        .ok_or_else(|| format!("{} names no key", name))?;
+
+    let mut table = table;
+    for parent in parents {
+        table = table
+            .entry(parent.as_str())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("{}: {} is not a table", name, parent))?;
+    }
+    let value = match table.get(key.as_str()) {
+        Some(toml::Value::String(_)) => toml::Value::String(value.to_string()),
+        _ => format!("v = {}", value)
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut parsed| parsed.remove("v"))
+            .unwrap_or_else(|| toml::Value::String(value.to_string())),
+    };
+    table.insert(key.clone(), value);
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum ConfigError {
     Io(std::io::Error),
     Parse(toml::de::Error),
+    Env(String),
+    Invalid(String),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -41,6 +1031,8 @@ impl std::fmt::Display for ConfigError {
         match self {
             ConfigError::Io(e) => write!(f, "failed to read config file: {}", e),
             ConfigError::Parse(e) => write!(f, "failed to parse config file: {}", e),
+            ConfigError::Env(e) => write!(f, "invalid environment override: {}", e),
+            ConfigError::Invalid(e) => write!(f, "invalid config: {}", e),
         }
     }
 }
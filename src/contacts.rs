@@ -0,0 +1,234 @@
+//! Recipient suggestions for the compose form, from the people in
+//! recently listed messages, and the checks behind its recipient chips.
+//!
+//! Each session keeps its own address book in memory: every list page
+//! shown adds its messages' From, To and Cc, and the first lookup seeds it
+//! from the most recent mail, so suggestions work before any list has
+//! been opened. Nothing is written to the server or to disk.
+//!
+//! Each entry of a recipient field shows as a chip, flagged when it won't
+//! parse, repeats an address already given, or has a domain one letter
+//! off a well-known one or one the address book has seen.
+
+use std::collections::HashMap;
+
+use crate::dates;
+use crate::jmap::{Email, EmailAddress};
+
+/// Addresses kept per session; the least recently seen go first
+const MAX_ENTRIES: usize = 2000;
+/// Messages the first lookup learns from
+pub const SEED_SIZE: u32 = 200;
+/// Domains a near miss of is taken for a typo, besides those in the
+/// address book
+const KNOWN_DOMAINS: &[&str] = &[
+    "gmail.com",
+    "googlemail.com",
+    "yahoo.com",
+    "hotmail.com",
+    "outlook.com",
+    "live.com",
+    "icloud.com",
+    "aol.com",
+    "protonmail.com",
+    "proton.me",
+    "fastmail.com",
+    "gmx.net",
+    "gmx.de",
+    "web.de",
+];
+
+struct Entry {
+    address: EmailAddress,
+    /// How many times the address came up
+    count: u32,
+    /// The newest message it came up in, in seconds since the epoch
+    last_seen: i64,
+}
+
+#[derive(Default)]
+pub struct AddressBook {
+    /// By lowercased address
+    entries: HashMap<String, Entry>,
+    seeded: bool,
+}
+
+impl AddressBook {
+    /// Whether the book still needs filling from recent mail
+    pub fn needs_seed(&self) -> bool {
+        !self.seeded
+    }
+
+    /// Take in the correspondents of the recent mail fetched for seeding
+    pub fn seed(&mut self, emails: &[Email]) {
+        self.seeded = true;
+        self.learn(emails);
+    }
+
+    /// Take in the senders and recipients of `emails`
+    pub fn learn(&mut self, emails: &[Email]) {
+        for email in emails {
+            let last_seen = email.received_at.as_deref().and_then(dates::parse).unwrap_or(0);
+            let addresses = [&email.from, &email.to, &email.cc];
+            for address in addresses.into_iter().flatten().flatten() {
+                let Some(key) = address.email.as_deref().map(str::to_lowercase) else { continue };
+                if !key.contains('@') {
+                    continue;
+                }
+                let entry = self.entries.entry(key).or_insert_with(|| Entry {
+                    address: EmailAddress { name: None, email: address.email.clone() },
+                    count: 0,
+                    last_seen,
+                });
+                entry.count += 1;
+                // The name from the newest message wins, since names change
+                if last_seen >= entry.last_seen {
+                    entry.last_seen = last_seen;
+                    if address.name.as_deref().is_some_and(|n| !n.trim().is_empty()) {
+                        entry.address.name = address.name.clone();
+                    }
+                }
+            }
+        }
+        if self.entries.len() > MAX_ENTRIES {
+            let mut ages: Vec<i64> = self.entries.values().map(|e| e.last_seen).collect();
+            ages.sort_unstable_by(|a, b| b.cmp(a));
+            let cutoff = ages[MAX_ENTRIES - 1];
+            self.entries.retain(|_, e| e.last_seen >= cutoff);
+        }
+    }
+
+    /// Whether any address in the book is at `domain`, lowercased
+    fn knows_domain(&self, domain: &str) -> bool {
+        self.entries.keys().any(|key| key.rsplit_once('@').is_some_and(|(_, d)| d == domain))
+    }
+
+    /// The domains of the addresses in the book, each once
+    fn domains(&self) -> Vec<String> {
+        let mut domains: Vec<String> =
+            self.entries.keys().filter_map(|key| Some(key.rsplit_once('@')?.1.to_string())).collect();
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+
+    /// Up to `limit` addresses for what has been typed so far: those with a
+    /// name word or the address starting with it first, then those merely
+    /// containing it, each by how often and how lately they came up
+    pub fn suggest(&self, typed: &str, limit: usize) -> Vec<&EmailAddress> {
+        let typed = typed.trim().trim_matches(['"', '<']).to_lowercase();
+        if typed.is_empty() {
+            return Vec::new();
+        }
+        let mut matches: Vec<(u8, &Entry)> = self
+            .entries
+            .iter()
+            .filter_map(|(key, entry)| {
+                let name = entry.address.name.as_deref().unwrap_or("").to_lowercase();
+                if key.starts_with(&typed) || name.split_whitespace().any(|w| w.starts_with(&typed)) {
+                    Some((0, entry))
+                } else if key.contains(&typed) || name.contains(&typed) {
+                    Some((1, entry))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        matches.sort_by(|(rank_a, a), (rank_b, b)| {
+            rank_a
+                .cmp(rank_b)
+                .then(b.count.cmp(&a.count))
+                .then(b.last_seen.cmp(&a.last_seen))
+                .then(a.address.email.cmp(&b.address.email))
+        });
+        matches.into_iter().take(limit).map(|(_, entry)| &entry.address).collect()
+    }
+}
+
+/// What looks wrong with an entry of a recipient field
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// It isn't an address
+    Invalid,
+    /// The same address is in this field or one before it already
+    Duplicate,
+    /// The domain is a letter off a known one; holds the entry corrected
+    Typo(String),
+}
+
+/// An entry of a recipient field as typed, and what looks wrong with it
+#[derive(Debug, PartialEq)]
+pub struct Chip {
+    pub entry: String,
+    pub problem: Option<Problem>,
+}
+
+/// The chips for the recipient field `field`, with `earlier` the fields
+/// before it (To, then Cc, then Bcc), whose addresses it shouldn't repeat
+pub fn check(earlier: &[&str], field: &str, book: Option<&AddressBook>) -> Vec<Chip> {
+    let mut seen: Vec<String> = earlier
+        .iter()
+        .flat_map(|list| EmailAddress::split_list(list))
+        .filter_map(|entry| EmailAddress::parse_entry(&entry).ok()?.email)
+        .map(|email| email.to_lowercase())
+        .collect();
+    EmailAddress::split_list(field)
+        .into_iter()
+        .map(|entry| {
+            let problem = match EmailAddress::parse_entry(&entry).ok().and_then(|a| a.email) {
+                None => Some(Problem::Invalid),
+                Some(email) if seen.contains(&email.to_lowercase()) => Some(Problem::Duplicate),
+                Some(email) => {
+                    seen.push(email.to_lowercase());
+                    typo_fix(&entry, &email, book).map(Problem::Typo)
+                }
+            };
+            Chip { entry, problem }
+        })
+        .collect()
+}
+
+/// `entry` with its domain corrected, when `email`'s is unknown but a
+/// letter off one that is known
+fn typo_fix(entry: &str, email: &str, book: Option<&AddressBook>) -> Option<String> {
+    let (_, typed) = email.rsplit_once('@')?;
+    let domain = typed.to_lowercase();
+    let known = |d: &str| KNOWN_DOMAINS.contains(&d) || book.is_some_and(|b| b.knows_domain(d));
+    if known(&domain) {
+        return None;
+    }
+    let book_domains = book.map(AddressBook::domains).unwrap_or_default();
+    let fix = KNOWN_DOMAINS
+        .iter()
+        .copied()
+        .chain(book_domains.iter().map(String::as_str))
+        .find(|known| one_edit_apart(&domain, known))?;
+    // The address's domain is the text after the entry's last @
+    let at = entry.rfind('@')?;
+    let end = at + 1 + typed.len();
+    Some(format!("{}{}{}", &entry[..at + 1], fix, entry.get(end..)?))
+}
+
+/// Whether one letter added, dropped, changed or swapped with the next
+/// turns `a` into `b`
+fn one_edit_apart(a: &str, b: &str) -> bool {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let start = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[start..], &b[start..]);
+    match (a.len(), b.len()) {
+        (0, 0) => false,
+        (x, y) if x == y => a[1..] == b[1..] || (x >= 2 && a[0] == b[1] && a[1] == b[0] && a[2..] == b[2..]),
+        (x, y) if x + 1 == y => a == &b[1..],
+        (x, y) if x == y + 1 => &a[1..] == b,
+        _ => false,
+    }
+}
+
+/// The part of a recipient field still being typed: what follows the last
+/// separator
+pub fn last_entry(field: &str) -> &str {
+    field.rsplit([',', ';']).next().unwrap_or(field).trim_start()
+}
+
+#[cfg(test)]
+mod tests;
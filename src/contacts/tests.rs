@@ -0,0 +1,34 @@
+use super::*;
+
+fn problems(earlier: &[&str], field: &str) -> Vec<Option<Problem>> {
+    check(earlier, field, None).into_iter().map(|chip| chip.problem).collect()
+}
+
+#[test]
+fn chips_split_pasted_lists() {
+    let chips = check(&[], "ann@example.com; \"Bo, B\" <bo@example.com>,, cy@example.com", None);
+    let entries: Vec<&str> = chips.iter().map(|c| c.entry.as_str()).collect();
+    assert_eq!(entries, ["ann@example.com", "\"Bo, B\" <bo@example.com>", "cy@example.com"]);
+    assert!(chips.iter().all(|c| c.problem.is_none()));
+}
+
+#[test]
+fn chips_flag_invalid_and_repeated_addresses() {
+    assert_eq!(problems(&[], "ann@example.com, ann, ANN@example.com"), [None, Some(Problem::Invalid), Some(Problem::Duplicate)]);
+    assert_eq!(problems(&["Ann <ann@example.com>", ""], "ann@example.com"), [Some(Problem::Duplicate)]);
+}
+
+#[test]
+fn chips_offer_a_fix_for_domain_typos() {
+    assert_eq!(
+        problems(&[], "Ann <ann@gmial.com>, bo@gmail.con, cy@gmail.com, dee@example.com"),
+        [
+            Some(Problem::Typo("Ann <ann@gmail.com>".to_string())),
+            Some(Problem::Typo("bo@gmail.com".to_string())),
+            None,
+            None,
+        ]
+    );
+    assert!(one_edit_apart("outlok.com", "outlook.com"));
+    assert!(!one_edit_apart("example.com", "gmail.com"));
+}
@@ -0,0 +1,575 @@
+//! Dates as the reader sees them: JMAP's UTCDates moved into their time
+//! zone and written the way their language writes them.
+//!
+//! A zone is either `UTC`, a fixed offset such as `+05:30`, or a name from
+//! the system's tz database (`/usr/share/zoneinfo`, or `$TZDIR`), whose
+//! TZif file is read on first use. Beyond the file's last transition its
+//! footer rule, such as `CET-1CEST,M3.5.0,M10.5.0/3`, says when daylight
+//! saving time starts and ends.
+//!
+//! Like the language, the zone belongs to the request: [`select`] sets it
+//! when a request comes in and [`short`] and [`full`] write dates in it.
+//! [`relative`] ages are counted on render, and so move on whenever the
+//! list is redrawn.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use crate::i18n::{t, tf};
+use crate::log_warn;
+
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+struct LocalType {
+    /// Seconds east of UTC
+    offset: i64,
+    abbreviation: String,
+}
+
+pub struct Zone {
+    /// UTC times at which the zone changed to `types[index]`, in order
+    transitions: Vec<(i64, usize)>,
+    types: Vec<LocalType>,
+    /// What applies after the last transition
+    rule: Option<Rule>,
+}
+
+impl Zone {
+    fn fixed(offset: i64, abbreviation: String) -> Zone {
+        Zone {
+            transitions: Vec::new(),
+            types: vec![LocalType { offset, abbreviation }],
+            rule: None,
+        }
+    }
+
+    /// The offset east of UTC and the abbreviation in use at `time`
+    fn local_type(&self, time: i64) -> (i64, &str) {
+        let last = self.transitions.last().map(|(at, _)| *at);
+        if let Some(rule) = self.rule.as_ref().filter(|_| last.is_none_or(|last| time >= last)) {
+            return rule.at(time);
+        }
+        let index = match self.transitions.partition_point(|(at, _)| *at <= time) {
+            0 => 0,
+            n => self.transitions[n - 1].1,
+        };
+        self.types
+            .get(index)
+            .map_or((0, "UTC"), |t| (t.offset, t.abbreviation.as_str()))
+    }
+}
+
+/// A POSIX TZ string's standard time and, if it has one, daylight time
+struct Rule {
+    std: LocalType,
+    dst: Option<(LocalType, Transition, Transition)>,
+}
+
+impl Rule {
+    fn at(&self, time: i64) -> (i64, &str) {
+        let std = (self.std.offset, self.std.abbreviation.as_str());
+        let Some((dst, start, end)) = &self.dst else { return std };
+        let (year, _, _) = civil_from_days((time + self.std.offset).div_euclid(86400));
+        // Both are written in the local time then in force
+        let starts = start.local_time(year) - self.std.offset;
+        let ends = end.local_time(year) - dst.offset;
+        let in_dst = if starts < ends {
+            starts <= time && time < ends
+        } else {
+            // Southern hemisphere: daylight time spans the new year
+            !(ends <= time && time < starts)
+        };
+        if in_dst {
+            (dst.offset, dst.abbreviation.as_str())
+        } else {
+            std
+        }
+    }
+}
+
+/// When in the year a POSIX rule switches, and at what local time of day
+struct Transition {
+    day: RuleDay,
+    seconds: i64,
+}
+
+enum RuleDay {
+    /// `Jn`: day 1 to 365, never counting February 29
+    Julian(i64),
+    /// `n`: day 0 to 365, counting February 29
+    Ordinal(i64),
+    /// `Mm.w.d`: weekday `d` (0 is Sunday) of week `w` of month `m`, week 5
+    /// being the last
+    Weekday { month: i64, week: i64, weekday: i64 },
+}
+
+impl Transition {
+    /// Seconds since the epoch, in local time, of the switch in `year`
+    fn local_time(&self, year: i64) -> i64 {
+        let days = match self.day {
+            RuleDay::Julian(n) => {
+                let leap_day = if is_leap(year) && n > 59 { 1 } else { 0 };
+                days_from_civil(year, 1, 1) + n - 1 + leap_day
+            }
+            RuleDay::Ordinal(n) => days_from_civil(year, 1, 1) + n,
+            RuleDay::Weekday { month, week, weekday } => {
+                let first = days_from_civil(year, month, 1);
+                let mut day = first + (weekday - weekday_of(first)).rem_euclid(7) + (week - 1) * 7;
+                while day >= first + days_in_month(year, month) {
+                    day -= 7;
+                }
+                day
+            }
+        };
+        days * 86400 + self.seconds
+    }
+}
+
+thread_local! {
+    /// The zone dates are written in for this thread's request
+    static CURRENT: RefCell<Option<Arc<Zone>>> = const { RefCell::new(None) };
+}
+
+/// The zone called `name`, loaded once and kept
+pub fn zone(name: &str) -> Option<Arc<Zone>> {
+    static ZONES: OnceLock<Mutex<HashMap<String, Option<Arc<Zone>>>>> = OnceLock::new();
+    let zones = ZONES.get_or_init(Default::default);
+    if let Some(zone) = zones.lock().unwrap().get(name) {
+        return zone.clone();
+    }
+    let zone = load(name).map(Arc::new);
+    zones.lock().unwrap().insert(name.to_string(), zone.clone());
+    zone
+}
+
+/// Write dates in the zone called `name` for the rest of the current
+/// request, returning whether there is such a zone
+pub fn select(name: &str) -> bool {
+    let Some(zone) = zone(name) else { return false };
+    CURRENT.with(|current| *current.borrow_mut() = Some(zone));
+    true
+}
+
+fn load(name: &str) -> Option<Zone> {
+    if name.eq_ignore_ascii_case("UTC") || name.eq_ignore_ascii_case("GMT") {
+        return Some(Zone::fixed(0, "UTC".to_string()));
+    }
+    if let Some(offset) = parse_fixed_offset(name) {
+        return Some(Zone::fixed(offset, name.to_string()));
+    }
+    // Names only ever lead somewhere inside the database
+    let plain = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '/'));
+    if !plain || name.split('/').any(|part| part.is_empty() || part.starts_with('.')) {
+        return None;
+    }
+    let dir = std::env::var_os("TZDIR").map_or_else(|| PathBuf::from(ZONEINFO), PathBuf::from);
+    let data = fs::read(dir.join(name)).ok()?;
+    let zone = parse_tzif(&data);
+    if zone.is_none() {
+        log_warn!("Can't read time zone {}: not a TZif file", name);
+    }
+    zone
+}
+
+/// `+05:30` or `-08:00` as seconds east of UTC
+fn parse_fixed_offset(s: &str) -> Option<i64> {
+    let sign = match s.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let (hours, minutes) = s[1..].split_once(':')?;
+    let (hours, minutes): (i64, i64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours <= 14 && minutes < 60).then_some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// A TZif file (RFC 8536), using the 64-bit data and footer of version 2
+/// and later when present
+fn parse_tzif(data: &[u8]) -> Option<Zone> {
+    let header = |at: usize| -> Option<[usize; 6]> {
+        if data.get(at..at + 4)? != b"TZif" {
+            return None;
+        }
+        let mut counts = [0; 6];
+        for (i, count) in counts.iter_mut().enumerate() {
+            let start = at + 20 + i * 4;
+            *count = u32::from_be_bytes(data.get(start..start + 4)?.try_into().ok()?) as usize;
+        }
+        Some(counts)
+    };
+    // isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt
+    let counts = header(0)?;
+    let block_len = |c: [usize; 6], time_size: usize| {
+        c[3] * time_size + c[3] + c[4] * 6 + c[5] + c[2] * (time_size + 4) + c[1] + c[0]
+    };
+    let (at, counts, time_size) = if data[4] >= b'2' {
+        let second = 44 + block_len(counts, 4);
+        (second + 44, header(second)?, 8)
+    } else {
+        (44, counts, 4)
+    };
+    let [_, _, _, timecnt, typecnt, charcnt] = counts;
+
+    let read_time = |at: usize| -> Option<i64> {
+        let bytes = data.get(at..at + time_size)?;
+        Some(if time_size == 8 {
+            i64::from_be_bytes(bytes.try_into().ok()?)
+        } else {
+            i32::from_be_bytes(bytes.try_into().ok()?) as i64
+        })
+    };
+    let indices_at = at + timecnt * time_size;
+    let types_at = indices_at + timecnt;
+    let chars_at = types_at + typecnt * 6;
+    let chars = data.get(chars_at..chars_at + charcnt)?;
+
+    let mut transitions = Vec::with_capacity(timecnt);
+    for i in 0..timecnt {
+        let index = *data.get(indices_at + i)? as usize;
+        transitions.push((read_time(at + i * time_size)?, index));
+    }
+    let mut types = Vec::with_capacity(typecnt);
+    for i in 0..typecnt {
+        let entry = data.get(types_at + i * 6..types_at + i * 6 + 6)?;
+        let offset = i32::from_be_bytes(entry[..4].try_into().ok()?) as i64;
+        let name = chars.get(entry[5] as usize..)?;
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        types.push(LocalType {
+            offset,
+            abbreviation: String::from_utf8_lossy(&name[..end]).into_owned(),
+        });
+    }
+    if types.is_empty() || transitions.iter().any(|(_, index)| *index >= types.len()) {
+        return None;
+    }
+
+    let rule = (time_size == 8)
+        .then(|| {
+            let footer = &data[at + block_len(counts, 8)..];
+            let footer = std::str::from_utf8(footer).ok()?.trim_matches('\n');
+            parse_rule(footer)
+        })
+        .flatten();
+    Some(Zone { transitions, types, rule })
+}
+
+/// A POSIX TZ string such as `CET-1CEST,M3.5.0,M10.5.0/3` or `<+0530>-5:30`
+fn parse_rule(s: &str) -> Option<Rule> {
+    let mut rest = s;
+    let std_name = take_name(&mut rest)?;
+    // POSIX counts hours west of UTC
+    let std_offset = -take_time(&mut rest)?;
+    let std = LocalType { offset: std_offset, abbreviation: std_name };
+    if rest.is_empty() {
+        return Some(Rule { std, dst: None });
+    }
+
+    let dst_name = take_name(&mut rest)?;
+    let dst_offset = if rest.starts_with(',') { std_offset + 3600 } else { -take_time(&mut rest)? };
+    let (start, end) = rest.strip_prefix(',')?.split_once(',')?;
+    let dst = LocalType { offset: dst_offset, abbreviation: dst_name };
+    Some(Rule {
+        std,
+        dst: Some((dst, parse_transition(start)?, parse_transition(end)?)),
+    })
+}
+
+fn take_name(rest: &mut &str) -> Option<String> {
+    let (name, after) = if let Some(quoted) = rest.strip_prefix('<') {
+        let (name, after) = quoted.split_once('>')?;
+        (name, after)
+    } else {
+        let end = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        rest.split_at(end)
+    };
+    *rest = after;
+    (name.len() >= 3).then(|| name.to_string())
+}
+
+/// `[+-]hh[:mm[:ss]]` as seconds, hours running past 24 as later
+/// versions allow
+fn take_time(rest: &mut &str) -> Option<i64> {
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, ':' | '+' | '-')))
+        .unwrap_or(rest.len());
+    let (time, after) = rest.split_at(end);
+    *rest = after;
+    let (sign, time) = match time.as_bytes().first()? {
+        b'-' => (-1, &time[1..]),
+        b'+' => (1, &time[1..]),
+        _ => (1, time),
+    };
+    let mut seconds = 0;
+    for (i, part) in time.split(':').enumerate() {
+        let value: i64 = part.parse().ok()?;
+        seconds += value * [3600, 60, 1].get(i)?;
+    }
+    Some(sign * seconds)
+}
+
+fn parse_transition(s: &str) -> Option<Transition> {
+    let (day, time) = s.split_once('/').unwrap_or((s, "2"));
+    let day = if let Some(n) = day.strip_prefix('J') {
+        RuleDay::Julian(n.parse().ok()?)
+    } else if let Some(m) = day.strip_prefix('M') {
+        let mut parts = m.split('.').map(|p| p.parse::<i64>().ok());
+        let (month, week, weekday) = (parts.next()??, parts.next()??, parts.next()??);
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || !(0..=6).contains(&weekday) {
+            return None;
+        }
+        RuleDay::Weekday { month, week, weekday }
+    } else {
+        RuleDay::Ordinal(day.parse().ok()?)
+    };
+    let mut time = time;
+    Some(Transition { day, seconds: take_time(&mut time)? })
+}
+
+/// Seconds since the epoch of an RFC 3339 date-time such as JMAP's
+/// `2024-03-03T14:32:00Z`, with or without fractional seconds or an offset
+pub fn parse(s: &str) -> Option<i64> {
+    let field = |range: std::ops::Range<usize>| s.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if !matches!(s.as_bytes().get(10), Some(b'T' | b't' | b' ')) || !(1..=12).contains(&month) {
+        return None;
+    }
+    let zone = s[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" | "z" => 0,
+        other => parse_fixed_offset(other)?,
+    };
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// The local date and time of `time` in the current request's zone, with
+/// the zone's abbreviation then
+struct Local<'a> {
+    days: i64,
+    year: i64,
+    month: i64,
+    day: i64,
+    weekday: i64,
+    time: String,
+    zone: &'a str,
+}
+
+fn with_local<R>(time: i64, f: impl FnOnce(Local, &dyn Fn(i64) -> i64) -> R) -> R {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let utc;
+        let zone = match current.as_deref() {
+            Some(zone) => zone,
+            None => {
+                utc = Zone::fixed(0, "UTC".to_string());
+                &utc
+            }
+        };
+        let (offset, abbreviation) = zone.local_type(time);
+        let local = time + offset;
+        let days = local.div_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        let seconds = local.rem_euclid(86400);
+        let local_days = |other: i64| (other + zone.local_type(other).0).div_euclid(86400);
+        f(
+            Local {
+                days,
+                year,
+                month,
+                day,
+                weekday: weekday_of(days),
+                time: format!("{:02}:{:02}", seconds / 3600, seconds % 3600 / 60),
+                zone: abbreviation,
+            },
+            &local_days,
+        )
+    })
+}
+
+fn month_name(month: i64) -> &'static str {
+    t("dates.months").split_whitespace().nth(month as usize - 1).unwrap_or("")
+}
+
+/// How a message list shows `date`: the time for today and yesterday, the
+/// day for earlier this year, and the day and year before that. Anything
+/// that isn't a date-time is shown as it is.
+pub fn short(date: &str) -> String {
+    let Some(time) = parse(date) else { return date.to_string() };
+    with_local(time, |local, local_days| {
+        let today = local_days(now());
+        let day = local.day.to_string();
+        let year = local.year.to_string();
+        if local.days == today {
+            tf("dates.today", &[("time", &local.time)])
+        } else if local.days == today - 1 {
+            tf("dates.yesterday", &[("time", &local.time)])
+        } else if civil_from_days(today).0 == local.year {
+            tf("dates.this_year", &[("month", month_name(local.month)), ("day", &day)])
+        } else {
+            tf(
+                "dates.other_year",
+                &[("month", month_name(local.month)), ("day", &day), ("year", &year)],
+            )
+        }
+    })
+}
+
+/// How long ago `date` was, such as "5m", "2h" or "3d", for a message
+/// list. After a week that says less than the date, so [`short`] is used.
+pub fn relative(date: &str) -> String {
+    let Some(time) = parse(date) else { return date.to_string() };
+    let age = now() - time;
+    let n = |unit: i64| (age / unit).to_string();
+    match age {
+        // A little ahead is clock skew
+        -59..=59 => t("dates.just_now").to_string(),
+        60..=3599 => tf("dates.minutes_ago", &[("n", &n(60))]),
+        3600..=86399 => tf("dates.hours_ago", &[("n", &n(3600))]),
+        86400..=604799 => tf("dates.days_ago", &[("n", &n(86400))]),
+        _ => short(date),
+    }
+}
+
+/// `date` in full, with the weekday, time and zone, for the message view
+pub fn full(date: &str) -> String {
+    match parse(date) {
+        Some(time) => full_at(time),
+        None => date.to_string(),
+    }
+}
+
+/// [`full`] for seconds since the epoch
+pub fn full_at(time: i64) -> String {
+    with_local(time, |local, _| {
+        let weekday = weekday_name(local.weekday);
+        tf(
+            "dates.full",
+            &[
+                ("weekday", weekday),
+                ("month", month_name(local.month)),
+                ("day", &local.day.to_string()),
+                ("year", &local.year.to_string()),
+                ("time", &local.time),
+                ("zone", local.zone),
+            ],
+        )
+    })
+}
+
+/// A calendar date on its own, with its weekday, such as an all-day event's
+pub fn day(year: i64, month: i64, day: i64) -> String {
+    let weekday = weekday_name(weekday_of(days_from_civil(year, month, day)));
+    tf(
+        "dates.day",
+        &[
+            ("weekday", weekday),
+            ("month", month_name(month)),
+            ("day", &day.to_string()),
+            ("year", &year.to_string()),
+        ],
+    )
+}
+
+/// Seconds since the epoch of a wall-clock time, `seconds` into the day
+/// `days` after the epoch, in the zone called `zone`. Without one, or for
+/// a zone there's no such name for, the current request's zone is used.
+pub fn from_local(zone_name: Option<&str>, days: i64, seconds: i64) -> i64 {
+    let local = days * 86400 + seconds;
+    // The offset in force an offset's width before is right but for the
+    // hour a clock change skips or repeats
+    let resolve = |zone: &Zone| local - zone.local_type(local - zone.local_type(local).0).0;
+    match zone_name.and_then(zone) {
+        Some(zone) => resolve(&zone),
+        None => CURRENT.with(|current| current.borrow().as_deref().map_or(local, resolve)),
+    }
+}
+
+fn weekday_name(weekday: i64) -> &'static str {
+    t("dates.weekdays").split_whitespace().nth(weekday as usize).unwrap_or("")
+}
+
+/// Today's date in the current request's zone, as days since the epoch
+pub fn today() -> i64 {
+    day_of(now())
+}
+
+/// The date `time` falls on in the current request's zone, as days since
+/// the epoch
+pub fn day_of(time: i64) -> i64 {
+    with_local(time, |local, _| local.days)
+}
+
+/// The wall-clock time of `time` in the current request's zone, "14:32"
+pub fn clock(time: i64) -> String {
+    with_local(time, |local, _| local.time)
+}
+
+/// `time` as a UTC date the way JMAP writes them, `2026-10-14T09:30:00Z`
+pub fn utc(time: i64) -> String {
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    let seconds = time.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 0 for Sunday; the epoch was a Thursday
+pub fn weekday_of(days: i64) -> i64 {
+    (days + 4).rem_euclid(7)
+}
+
+/// Days since the epoch of a proleptic Gregorian date (after Howard
+/// Hinnant's days_from_civil)
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The (year, month, day) of a count of days since the epoch
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,1009 @@
+//! Deterministic in-process JMAP backend for `--demo` mode and the tests.
+//!
+//! Serves a canned session object, mailboxes and emails on a loopback port so
+//! the UI can be evaluated without a real mail server. Any credentials are
+//! accepted and nothing leaves the machine. Email/set really changes the
+//! messages, for as long as the backend runs, so reading, flagging, moving
+//! and deleting behave as they would against a server; sends are filed in
+//! Sent but go nowhere.
+
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::thread;
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::dates;
+use crate::{log_debug, log_error, log_info};
+
+const ACCOUNT_ID: &str = "demo";
+
+struct DemoMailbox {
+    id: &'static str,
+    name: &'static str,
+    role: Option<&'static str>,
+    parent_id: Option<&'static str>,
+}
+
+struct DemoEmail {
+    id: &'static str,
+    mailbox_id: &'static str,
+    from_name: &'static str,
+    from_email: &'static str,
+    subject: &'static str,
+    received_at: &'static str,
+    seen: bool,
+    body: &'static str,
+}
+
+const MAILBOXES: &[DemoMailbox] = &[
+    DemoMailbox {
+        id: "inbox",
+        name: "Inbox",
+        role: Some("inbox"),
+        parent_id: None,
+    },
+    DemoMailbox {
+        id: "drafts",
+        name: "Drafts",
+        role: Some("drafts"),
+        parent_id: None,
+    },
+    DemoMailbox {
+        id: "sent",
+        name: "Sent",
+        role: Some("sent"),
+        parent_id: None,
+    },
+    DemoMailbox {
+        id: "trash",
+        name: "Trash",
+        role: Some("trash"),
+        parent_id: None,
+    },
+    DemoMailbox {
+        id: "junk",
+        name: "Junk",
+        role: Some("junk"),
+        parent_id: None,
+    },
+    DemoMailbox {
+        id: "archive",
+        name: "Archive",
+        role: Some("archive"),
+        parent_id: None,
+    },
+    DemoMailbox {
+        id: "projects",
+        name: "Projects",
+        role: None,
+        parent_id: None,
+    },
+    DemoMailbox {
+        id: "website",
+        name: "Website",
+        role: None,
+        parent_id: Some("projects"),
+    },
+];
+
+const EMAILS: &[DemoEmail] = &[
+    DemoEmail {
+        id: "m01",
+        mailbox_id: "inbox",
+        from_name: "Alice Example",
+        from_email: "alice@example.com",
+        subject: "Welcome to the demo",
+        received_at: "2024-03-08T09:15:00Z",
+        seen: false,
+        body: "Hi!\n\nThis message comes from the built-in demo backend. Nothing here\ntouches a real mail server, so feel free to click around.\n\n-- \nAlice",
+    },
+    DemoEmail {
+        id: "m02",
+        mailbox_id: "inbox",
+        from_name: "Bob Builder",
+        from_email: "bob@example.org",
+        subject: "Re: Lunch on Friday?",
+        received_at: "2024-03-07T12:40:00Z",
+        seen: false,
+        body: "Friday works for me.\n\n> Are you free for lunch on Friday?\n> The new place on Main St. opens this week.",
+    },
+    DemoEmail {
+        id: "m03",
+        mailbox_id: "inbox",
+        from_name: "Carol Ops",
+        from_email: "carol@ops.example.net",
+        subject: "Maintenance window tonight",
+        received_at: "2024-03-06T17:05:00Z",
+        seen: true,
+        body: "Heads up: the database cluster will be upgraded tonight between\n22:00 and 23:00 UTC. Expect a few minutes of read-only mode.",
+    },
+    DemoEmail {
+        id: "m04",
+        mailbox_id: "inbox",
+        from_name: "Newsletter",
+        from_email: "news@letters.example.com",
+        subject: "This week in Rust",
+        received_at: "2024-03-05T06:00:00Z",
+        seen: true,
+        body: "Hello Rustaceans!\n\nHere is what happened in the Rust community this week...",
+    },
+    DemoEmail {
+        id: "m05",
+        mailbox_id: "projects",
+        from_name: "Dave Planner",
+        from_email: "dave@example.com",
+        subject: "Project kickoff notes",
+        received_at: "2024-03-04T14:30:00Z",
+        seen: true,
+        body: "Notes from today's kickoff:\n\n1. Scope agreed\n2. First milestone in two weeks\n3. Weekly sync on Tuesdays",
+    },
+    DemoEmail {
+        id: "m06",
+        mailbox_id: "archive",
+        from_name: "Erin Finance",
+        from_email: "erin@example.com",
+        subject: "Invoice #1042",
+        received_at: "2024-02-28T10:00:00Z",
+        seen: true,
+        body: "Please find the details for invoice #1042 below.\n\nAmount due: 120.00 EUR",
+    },
+    DemoEmail {
+        id: "m07",
+        mailbox_id: "sent",
+        from_name: "Demo User",
+        from_email: "demo@example.com",
+        subject: "Are you free for lunch on Friday?",
+        received_at: "2024-03-07T11:02:00Z",
+        seen: true,
+        body: "Are you free for lunch on Friday?\nThe new place on Main St. opens this week.",
+    },
+    DemoEmail {
+        id: "m08",
+        mailbox_id: "drafts",
+        from_name: "Demo User",
+        from_email: "demo@example.com",
+        subject: "Half-written thoughts",
+        received_at: "2024-03-03T20:11:00Z",
+        seen: true,
+        body: "TODO: finish this",
+    },
+    DemoEmail {
+        id: "m09",
+        mailbox_id: "trash",
+        from_name: "Old Friend",
+        from_email: "friend@example.net",
+        subject: "Old conversation",
+        received_at: "2024-01-15T08:45:00Z",
+        seen: true,
+        body: "This one was deleted.",
+    },
+    DemoEmail {
+        id: "m10",
+        mailbox_id: "junk",
+        from_name: "Prize Department",
+        from_email: "winner@spam.example",
+        subject: "You have WON!!!",
+        received_at: "2024-03-02T03:33:00Z",
+        seen: false,
+        body: "Click here to claim your prize.",
+    },
+];
+
+/// A message as one backend holds it now: one of `EMAILS` as changed since,
+/// or one the client created
+struct StoredEmail {
+    id: String,
+    mailbox_ids: BTreeSet<String>,
+    keywords: BTreeSet<String>,
+    from_name: String,
+    from_email: String,
+    to: Value,
+    subject: String,
+    received_at: String,
+    body: String,
+}
+
+impl From<&DemoEmail> for StoredEmail {
+    fn from(email: &DemoEmail) -> Self {
+        StoredEmail {
+            id: email.id.to_string(),
+            mailbox_ids: BTreeSet::from([email.mailbox_id.to_string()]),
+            keywords: email.seen.then(|| "$seen".to_string()).into_iter().collect(),
+            from_name: email.from_name.to_string(),
+            from_email: email.from_email.to_string(),
+            to: json!([{ "name": "Demo User", "email": "demo@example.com" }]),
+            subject: email.subject.to_string(),
+            received_at: email.received_at.to_string(),
+            body: email.body.to_string(),
+        }
+    }
+}
+
+enum ChangeKind {
+    Created,
+    Updated,
+    Destroyed,
+}
+
+/// The messages of one backend, starting as `EMAILS`, and what each
+/// Email/set did to them so /changes calls can say. Every state is a
+/// `demo-{n}` of the one counter, Email and Mailbox alike, since moving or
+/// reading a message changes its mailboxes' counts.
+struct Mailstore {
+    emails: Vec<StoredEmail>,
+    state: u64,
+    changes: Vec<(u64, String, ChangeKind)>,
+    created: u64,
+}
+
+impl Mailstore {
+    fn new() -> Self {
+        Mailstore {
+            emails: EMAILS.iter().map(StoredEmail::from).collect(),
+            state: 0,
+            changes: Vec::new(),
+            created: 0,
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("demo-{}", self.state)
+    }
+
+    fn find(&self, id: &str) -> Option<&StoredEmail> {
+        self.emails.iter().find(|e| e.id == id)
+    }
+}
+
+/// Start the demo backend on a loopback port and return its well-known URL.
+/// Each one holds its own messages, so tests can change them freely.
+pub fn start() -> Result<String, String> {
+    let server = Server::http("127.0.0.1:0").map_err(|e| e.to_string())?;
+    let addr = server
+        .server_addr()
+        .to_ip()
+        .ok_or_else(|| "demo backend has no IP address".to_string())?;
+    let base_url = format!("http://{}", addr);
+    log_info!("[DEMO] Fake JMAP backend listening on {}", base_url);
+
+    let thread_base_url = base_url.clone();
+    let store = Mutex::new(Mailstore::new());
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            handle(&thread_base_url, &store, request);
+        }
+    });
+
+    Ok(format!("{}/.well-known/jmap", base_url))
+}
+
+fn handle(base_url: &str, store: &Mutex<Mailstore>, mut request: Request) {
+    let path = request.url().to_string();
+    log_debug!("[DEMO] {} {}", request.method(), path);
+
+    let result = if path == "/.well-known/jmap" {
+        request.respond(json_response(&session_object(base_url)))
+    } else if path == "/api" {
+        let mut body = String::new();
+        let _ = request.as_reader().read_to_string(&mut body);
+        let response = match serde_json::from_str::<Value>(&body) {
+            Ok(req) => api_response(&mut store.lock().unwrap(), &req),
+            Err(e) => {
+                json!({ "type": "urn:ietf:params:jmap:error:notJSON", "detail": e.to_string() })
+            }
+        };
+        request.respond(json_response(&response))
+    } else if path.starts_with("/upload/") {
+        // Like sends, uploads are accepted and thrown away
+        let content_type = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Content-Type"))
+            .map(|h| h.value.to_string())
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        let size = std::io::copy(request.as_reader(), &mut std::io::sink()).unwrap_or(0);
+        let response = json!({
+            "accountId": ACCOUNT_ID,
+            "blobId": format!("demo-upload-{}", size),
+            "type": content_type,
+            "size": size
+        });
+        request.respond(json_response(&response).with_status_code(201))
+    } else if let Some(blob_id) = path
+        .strip_prefix("/download/")
+        .and_then(|rest| rest.split('/').nth(1))
+    {
+        match raw_message(&store.lock().unwrap(), blob_id) {
+            Some(raw) => request.respond(Response::from_string(raw)),
+            None => request.respond(Response::from_string("Not Found").with_status_code(404)),
+        }
+    } else {
+        request.respond(Response::from_string("Not Found").with_status_code(404))
+    };
+
+    if let Err(e) = result {
+        log_error!("[DEMO] Failed to respond: {}", e);
+    }
+}
+
+fn json_response(value: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn session_object(base_url: &str) -> Value {
+    json!({
+        "username": "demo@example.com",
+        "apiUrl": format!("{}/api", base_url),
+        "downloadUrl": format!("{}/download/{{accountId}}/{{blobId}}/{{name}}?accept={{type}}", base_url),
+        "uploadUrl": format!("{}/upload/{{accountId}}/", base_url),
+        // No eventSourceUrl: only the client itself changes anything, so
+        // there is nothing to push
+        "state": "demo-session",
+        "capabilities": {
+            "urn:ietf:params:jmap:core": {
+                "maxSizeUpload": 50_000_000,
+                "maxConcurrentUpload": 4,
+                "maxSizeRequest": 10_000_000,
+                "maxConcurrentRequests": 4,
+                "maxCallsInRequest": 16,
+                "maxObjectsInGet": 500,
+                "maxObjectsInSet": 500,
+                "collationAlgorithms": []
+            },
+            "urn:ietf:params:jmap:mail": {},
+            "urn:ietf:params:jmap:submission": {},
+            "urn:ietf:params:jmap:vacationresponse": {},
+            "urn:ietf:params:jmap:quota": {}
+        },
+        "accounts": {
+            ACCOUNT_ID: {
+                "name": "demo@example.com",
+                "isPersonal": true,
+                "isReadOnly": false,
+                "accountCapabilities": {
+                    "urn:ietf:params:jmap:mail": {},
+                    "urn:ietf:params:jmap:submission": {},
+                    "urn:ietf:params:jmap:vacationresponse": {},
+                    "urn:ietf:params:jmap:quota": {}
+                }
+            }
+        },
+        "primaryAccounts": { "urn:ietf:params:jmap:mail": ACCOUNT_ID }
+    })
+}
+
+fn api_response(store: &mut Mailstore, request: &Value) -> Value {
+    let calls = request["methodCalls"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let mut responses: Vec<Value> = Vec::new();
+    // Creation ids such as "draft" are the request's, for later calls'
+    // "#draft" to find
+    let mut created_ids: HashMap<String, String> = HashMap::new();
+    for call in &calls {
+        let name = call[0].as_str().unwrap_or("");
+        let args = &resolve_references(&call[1], &responses);
+        let call_id = call[2].clone();
+        let response = match name {
+            "Mailbox/get" => json!(["Mailbox/get", mailbox_get(store), call_id]),
+            "Mailbox/changes" => match mailbox_changes(store, args) {
+                Ok(result) => json!([name, result, call_id]),
+                Err(error) => json!(["error", { "type": error }, call_id]),
+            },
+            "Email/query" => match email_query(store, args) {
+                Ok(result) => json!([name, result, call_id]),
+                Err(error) => json!(["error", { "type": error }, call_id]),
+            },
+            "Email/changes" => match email_changes(store, args) {
+                Ok(result) => json!([name, result, call_id]),
+                Err(error) => json!(["error", { "type": error }, call_id]),
+            },
+            "Email/get" => json!(["Email/get", email_get(store, args), call_id]),
+            "Email/set" => json!([name, email_set(store, args, &mut created_ids), call_id]),
+            "Thread/get" => json!(["Thread/get", thread_get(store, args), call_id]),
+            "Identity/get" => json!(["Identity/get", identity_get(), call_id]),
+            "VacationResponse/get" => json!(["VacationResponse/get", vacation_response_get(), call_id]),
+            "Quota/get" => json!(["Quota/get", quota_get(store), call_id]),
+            // Nothing is really sent, so the Outbox stays empty
+            "EmailSubmission/query" => json!([
+                name,
+                { "accountId": ACCOUNT_ID, "queryState": "demo", "ids": [], "position": 0 },
+                call_id
+            ]),
+            "EmailSubmission/get" => json!([name, { "accountId": ACCOUNT_ID, "state": "demo", "list": [] }, call_id]),
+            // Sends are accepted and discarded, though the sent message is
+            // filed as asked
+            "EmailSubmission/set" => {
+                let result = accept_creates(args);
+                responses.push(json!([name, result, call_id.clone()]));
+                submitted(store, args, &created_ids, call_id)
+            }
+            // Settings changes are accepted and discarded so they can be tried out
+            "VacationResponse/set" => json!([name, accept_creates(args), call_id]),
+            // Like uploads, imported messages are accepted and thrown away
+            "Email/import" => json!([name, accept_imports(args), call_id]),
+            _ => json!(["error", { "type": "unknownMethod" }, call_id]),
+        };
+        responses.push(response);
+    }
+
+    json!({ "methodResponses": responses, "sessionState": "demo-session" })
+}
+
+/// Replace `#name` back-reference arguments with the value they point to
+/// in an earlier response. Only plain JSON pointer paths are supported,
+/// which is all the client sends.
+fn resolve_references(args: &Value, responses: &[Value]) -> Value {
+    let Some(map) = args.as_object() else {
+        return args.clone();
+    };
+    let mut resolved = serde_json::Map::new();
+    for (key, value) in map {
+        match key.strip_prefix('#') {
+            Some(name) => {
+                let referenced = responses
+                    .iter()
+                    .find(|r| r[0] == value["name"] && r[2] == value["resultOf"])
+                    .and_then(|r| r[1].pointer(value["path"].as_str().unwrap_or("")))
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                resolved.insert(name.to_string(), referenced);
+            }
+            None => {
+                resolved.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Value::Object(resolved)
+}
+
+fn identity_get() -> Value {
+    json!({
+        "accountId": ACCOUNT_ID,
+        "state": "demo",
+        "list": [{
+            "id": "demo-identity",
+            "name": "Demo User",
+            "email": "demo@example.com",
+            "textSignature": "Demo User\nSent from the rust-jmap-webmail demo",
+            "mayDelete": false
+        }]
+    })
+}
+
+fn vacation_response_get() -> Value {
+    json!({
+        "accountId": ACCOUNT_ID,
+        "state": "demo",
+        "list": [{ "id": "singleton", "isEnabled": false }],
+        "notFound": []
+    })
+}
+
+fn quota_get(store: &Mailstore) -> Value {
+    let used: usize = store.emails.iter().map(demo_size).sum();
+    json!({
+        "accountId": ACCOUNT_ID,
+        "state": "demo",
+        "list": [{
+            "id": "demo-storage",
+            "name": "Mail storage",
+            "resourceType": "octets",
+            "scope": "account",
+            "types": ["Mail"],
+            "used": used,
+            "hardLimit": 1_073_741_824
+        }],
+        "notFound": []
+    })
+}
+
+fn accept_imports(args: &Value) -> Value {
+    let created: serde_json::Map<String, Value> = args["emails"]
+        .as_object()
+        .map(|emails| {
+            emails
+                .keys()
+                .map(|k| (k.clone(), json!({ "id": format!("demo-{}", k) })))
+                .collect()
+        })
+        .unwrap_or_default();
+    log_info!("[DEMO] Accepted and discarded {} imported message(s)", created.len());
+    json!({ "accountId": ACCOUNT_ID, "newState": "demo", "created": created })
+}
+
+fn accept_creates(args: &Value) -> Value {
+    let created: serde_json::Map<String, Value> = args["create"]
+        .as_object()
+        .map(|create| {
+            create
+                .keys()
+                .map(|k| (k.clone(), json!({ "id": format!("demo-{}", k) })))
+                .collect()
+        })
+        .unwrap_or_default();
+    let updated: serde_json::Map<String, Value> = args["update"]
+        .as_object()
+        .map(|update| update.keys().map(|k| (k.clone(), Value::Null)).collect())
+        .unwrap_or_default();
+    log_info!(
+        "[DEMO] Accepted and discarded {} created, {} updated object(s)",
+        created.len(),
+        updated.len()
+    );
+    json!({ "accountId": ACCOUNT_ID, "newState": "demo", "created": created, "updated": updated })
+}
+
+/// Email/set for real: messages created, patched and destroyed, each a
+/// change the next /changes call reports
+fn email_set(store: &mut Mailstore, args: &Value, created_ids: &mut HashMap<String, String>) -> Value {
+    let old_state = store.state();
+    store.state += 1;
+    let state = store.state;
+
+    let mut created = serde_json::Map::new();
+    for (key, email) in args["create"].as_object().into_iter().flatten() {
+        store.created += 1;
+        let id = format!("new{}", store.created);
+        let stored = created_email(&id, email);
+        let ids = json!({
+            "id": id,
+            "blobId": format!("blob-{}", id),
+            "threadId": format!("thread-{}", id),
+            "size": demo_size(&stored)
+        });
+        created.insert(key.clone(), ids);
+        created_ids.insert(key.clone(), id.clone());
+        store.emails.push(stored);
+        store.changes.push((state, id, ChangeKind::Created));
+    }
+
+    let mut updated = serde_json::Map::new();
+    let mut not_updated = serde_json::Map::new();
+    for (id, patch) in args["update"].as_object().into_iter().flatten() {
+        let id = creation_reference(id, created_ids);
+        match store.emails.iter_mut().find(|e| e.id == id) {
+            Some(email) => {
+                apply_patch(email, patch);
+                updated.insert(id.clone(), Value::Null);
+                store.changes.push((state, id, ChangeKind::Updated));
+            }
+            None => {
+                not_updated.insert(id, json!({ "type": "notFound" }));
+            }
+        }
+    }
+
+    let mut destroyed = Vec::new();
+    let mut not_destroyed = serde_json::Map::new();
+    for id in args["destroy"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+        let id = creation_reference(id, created_ids);
+        match store.emails.iter().position(|e| e.id == id) {
+            Some(at) => {
+                store.emails.remove(at);
+                destroyed.push(id.clone());
+                store.changes.push((state, id, ChangeKind::Destroyed));
+            }
+            None => {
+                not_destroyed.insert(id, json!({ "type": "notFound" }));
+            }
+        }
+    }
+
+    log_info!(
+        "[DEMO] Email/set created {}, updated {}, destroyed {} message(s)",
+        created.len(),
+        updated.len(),
+        destroyed.len()
+    );
+    json!({
+        "accountId": ACCOUNT_ID,
+        "oldState": old_state,
+        "newState": store.state(),
+        "created": created,
+        "updated": updated,
+        "notUpdated": not_updated,
+        "destroyed": destroyed,
+        "notDestroyed": not_destroyed
+    })
+}
+
+/// The id `id` stands for: an earlier call's creation if it is `#key`
+fn creation_reference(id: &str, created_ids: &HashMap<String, String>) -> String {
+    match id.strip_prefix('#') {
+        Some(key) => created_ids.get(key).cloned().unwrap_or_else(|| id.to_string()),
+        None => id.to_string(),
+    }
+}
+
+/// A message from an Email/set create's properties, with the text of its
+/// first body value
+fn created_email(id: &str, email: &Value) -> StoredEmail {
+    let set_keys = |value: &Value| -> BTreeSet<String> {
+        value
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(_, set)| set.as_bool() == Some(true))
+            .map(|(key, _)| key.clone())
+            .collect()
+    };
+    let body = email["bodyValues"]
+        .as_object()
+        .and_then(|values| values.values().next())
+        .and_then(|value| value["value"].as_str())
+        .unwrap_or("");
+    StoredEmail {
+        id: id.to_string(),
+        mailbox_ids: set_keys(&email["mailboxIds"]),
+        keywords: set_keys(&email["keywords"]),
+        from_name: email["from"][0]["name"].as_str().unwrap_or("").to_string(),
+        from_email: email["from"][0]["email"].as_str().unwrap_or("").to_string(),
+        to: if email["to"].is_array() { email["to"].clone() } else { json!([]) },
+        subject: email["subject"].as_str().unwrap_or("").to_string(),
+        received_at: email["receivedAt"].as_str().map_or_else(now, str::to_string),
+        body: body.to_string(),
+    }
+}
+
+/// Apply an Email/set patch's mailboxIds and keywords, whole or one
+/// `mailboxIds/{id}` or `keywords/{keyword}` at a time. Nothing else about
+/// a message may change.
+fn apply_patch(email: &mut StoredEmail, patch: &Value) {
+    for (path, value) in patch.as_object().into_iter().flatten() {
+        let (property, key) = path.split_once('/').map_or((path.as_str(), None), |(p, k)| (p, Some(k)));
+        let set = match property {
+            "mailboxIds" => &mut email.mailbox_ids,
+            "keywords" => &mut email.keywords,
+            _ => continue,
+        };
+        match key {
+            Some(key) if value.as_bool() == Some(true) => {
+                set.insert(key.to_string());
+            }
+            Some(key) => {
+                set.remove(key);
+            }
+            None => {
+                *set = value
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .filter(|(_, set)| set.as_bool() == Some(true))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+            }
+        }
+    }
+}
+
+/// The implicit Email/set of an EmailSubmission/set's onSuccessUpdateEmail
+/// and onSuccessDestroyEmail, on the messages its submissions sent
+fn submitted(store: &mut Mailstore, args: &Value, created_ids: &HashMap<String, String>, call_id: Value) -> Value {
+    let email_of = |key: &str| -> Option<String> {
+        let submission = key.strip_prefix('#').map_or(&Value::Null, |key| &args["create"][key]);
+        submission["emailId"].as_str().map(|id| creation_reference(id, created_ids))
+    };
+    let update: serde_json::Map<String, Value> = args["onSuccessUpdateEmail"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, patch)| Some((email_of(key)?, patch.clone())))
+        .collect();
+    let destroy: Vec<String> = args["onSuccessDestroyEmail"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|key| email_of(key.as_str()?))
+        .collect();
+    let args = json!({ "update": update, "destroy": destroy });
+    json!(["Email/set", email_set(store, &args, &mut created_ids.clone()), call_id])
+}
+
+/// What changed in the messages since `sinceState`, from the log
+/// Email/set keeps
+fn email_changes(store: &Mailstore, args: &Value) -> Result<Value, &'static str> {
+    let since = since_state(store, args)?;
+    let (mut created, mut updated, mut destroyed) = (Vec::new(), Vec::new(), Vec::new());
+    for (_, id, kind) in store.changes.iter().filter(|(state, _, _)| *state > since) {
+        let list = match kind {
+            ChangeKind::Created => &mut created,
+            ChangeKind::Updated => &mut updated,
+            ChangeKind::Destroyed => &mut destroyed,
+        };
+        if !list.contains(id) {
+            list.push(id.clone());
+        }
+    }
+    // Only the latest word on each message counts, and one created since
+    // and gone again was never there
+    let created_since = |id: &String| {
+        store.changes.iter().any(|(state, c, kind)| *state > since && c == id && matches!(kind, ChangeKind::Created))
+    };
+    created.retain(|id| store.find(id).is_some());
+    updated.retain(|id| store.find(id).is_some() && !created.contains(id));
+    destroyed.retain(|id| store.find(id).is_none() && !created_since(id));
+    Ok(json!({
+        "accountId": ACCOUNT_ID,
+        "oldState": args["sinceState"],
+        "newState": store.state(),
+        "hasMoreChanges": false,
+        "created": created,
+        "updated": updated,
+        "destroyed": destroyed
+    }))
+}
+
+/// Any message changed may have changed every mailbox's counts, so all of
+/// them are updated since any earlier state
+fn mailbox_changes(store: &Mailstore, args: &Value) -> Result<Value, &'static str> {
+    let since = since_state(store, args)?;
+    let updated: Vec<&str> = if since < store.state { MAILBOXES.iter().map(|m| m.id).collect() } else { Vec::new() };
+    Ok(json!({
+        "accountId": ACCOUNT_ID,
+        "oldState": args["sinceState"],
+        "newState": store.state(),
+        "hasMoreChanges": false,
+        "created": [],
+        "updated": updated,
+        "destroyed": []
+    }))
+}
+
+/// The counter `sinceState` was, if this backend handed it out
+fn since_state(store: &Mailstore, args: &Value) -> Result<u64, &'static str> {
+    args["sinceState"]
+        .as_str()
+        .and_then(|state| state.strip_prefix("demo-"))
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n <= store.state)
+        .ok_or("cannotCalculateChanges")
+}
+
+fn mailbox_get(store: &Mailstore) -> Value {
+    let list: Vec<Value> = MAILBOXES
+        .iter()
+        .enumerate()
+        .map(|(i, m)| {
+            let emails: Vec<_> = store.emails.iter().filter(|e| e.mailbox_ids.contains(m.id)).collect();
+            let unread = emails.iter().filter(|e| !e.keywords.contains("$seen")).count();
+            json!({
+                "id": m.id,
+                "name": m.name,
+                "parentId": m.parent_id,
+                "role": m.role,
+                "sortOrder": i,
+                "totalEmails": emails.len(),
+                "unreadEmails": unread
+            })
+        })
+        .collect();
+
+    json!({ "accountId": ACCOUNT_ID, "state": store.state(), "list": list, "notFound": [] })
+}
+
+fn matches_filter(email: &StoredEmail, filter: &Value) -> bool {
+    if let Some(op) = filter["operator"].as_str() {
+        let conditions = filter["conditions"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+        let mut results = conditions.iter().map(|c| matches_filter(email, c));
+        return match op {
+            "AND" => results.all(|m| m),
+            "OR" => results.any(|m| m),
+            _ => !results.any(|m| m),
+        };
+    }
+
+    // Case-insensitive substring matching is close enough for the demo
+    let contains = |haystack: &str, needle: &str| {
+        haystack.to_lowercase().contains(&needle.to_lowercase())
+    };
+    let from = format!("{} {}", email.from_name, email.from_email);
+    let to = email.to.to_string();
+    let text = format!("{} {} {} {}", from, to, email.subject, email.body);
+    let string_tests: [(&str, &str); 4] = [("text", &text), ("from", &from), ("to", &to), ("subject", &email.subject)];
+    for (key, haystack) in string_tests {
+        if let Some(needle) = filter[key].as_str() {
+            if !contains(haystack, needle) {
+                return false;
+            }
+        }
+    }
+    // No demo message has attachments
+    if filter["hasAttachment"] == Value::Bool(true) {
+        return false;
+    }
+    if let Some(keyword) = filter["hasKeyword"].as_str() {
+        if !email.keywords.contains(keyword) {
+            return false;
+        }
+    }
+    if let Some(keyword) = filter["notKeyword"].as_str() {
+        if email.keywords.contains(keyword) {
+            return false;
+        }
+    }
+    // Every demo message is a thread of its own
+    if let Some(keyword) = filter["someInThreadHaveKeyword"].as_str() {
+        if !email.keywords.contains(keyword) {
+            return false;
+        }
+    }
+    if let Some(before) = filter["before"].as_str() {
+        if email.received_at.as_str() >= before {
+            return false;
+        }
+    }
+    if let Some(after) = filter["after"].as_str() {
+        if email.received_at.as_str() < after {
+            return false;
+        }
+    }
+    if let Some(mailbox) = filter["inMailbox"].as_str() {
+        if !email.mailbox_ids.contains(mailbox) {
+            return false;
+        }
+    }
+    if let Some(excluded) = filter["inMailboxOtherThan"].as_array() {
+        let excluded: Vec<&str> = excluded.iter().filter_map(Value::as_str).collect();
+        if email.mailbox_ids.iter().all(|m| excluded.contains(&m.as_str())) {
+            return false;
+        }
+    }
+    true
+}
+
+fn email_query(store: &Mailstore, args: &Value) -> Result<Value, &'static str> {
+    let mut matching: Vec<&StoredEmail> = store
+        .emails
+        .iter()
+        .filter(|e| matches_filter(e, &args["filter"]))
+        .collect();
+    let comparator = &args["sort"][0];
+    let ascending = comparator["isAscending"].as_bool().unwrap_or(false);
+    matching.sort_by(|a, b| {
+        let order = match comparator["property"].as_str() {
+            Some("from") => a.from_name.cmp(&b.from_name),
+            Some("subject") => a.subject.cmp(&b.subject),
+            Some("size") => demo_size(a).cmp(&demo_size(b)),
+            _ => a.received_at.cmp(&b.received_at),
+        };
+        if ascending {
+            order
+        } else {
+            order.reverse()
+        }
+    });
+
+    let position = match args["anchor"].as_str() {
+        Some(anchor) => {
+            let at = matching.iter().position(|e| e.id == anchor).ok_or("anchorNotFound")?;
+            (at as i64 + args["anchorOffset"].as_i64().unwrap_or(0)).max(0) as usize
+        }
+        None => args["position"].as_u64().unwrap_or(0) as usize,
+    };
+    let limit = args["limit"].as_u64().unwrap_or(50) as usize;
+    let ids: Vec<&str> = matching
+        .iter()
+        .skip(position)
+        .take(limit)
+        .map(|e| e.id.as_str())
+        .collect();
+
+    Ok(json!({
+        "accountId": ACCOUNT_ID,
+        "queryState": store.state(),
+        "canCalculateChanges": false,
+        "position": position,
+        "total": matching.len(),
+        "ids": ids
+    }))
+}
+
+fn email_get(store: &Mailstore, args: &Value) -> Value {
+    let requested: Vec<&str> = args["ids"]
+        .as_array()
+        .map(|ids| ids.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+
+    let mut list = Vec::new();
+    let mut not_found = Vec::new();
+    for id in requested {
+        match store.find(id) {
+            Some(email) => list.push(email_object(email)),
+            None => not_found.push(id),
+        }
+    }
+
+    json!({ "accountId": ACCOUNT_ID, "state": store.state(), "list": list, "notFound": not_found })
+}
+
+/// Every demo message is its own thread, `thread-{id}`
+fn thread_get(store: &Mailstore, args: &Value) -> Value {
+    let mut list = Vec::new();
+    let mut not_found = Vec::new();
+    for id in args["ids"].as_array().into_iter().flatten().filter_map(|v| v.as_str()) {
+        match id.strip_prefix("thread-").filter(|e| store.find(e).is_some()) {
+            Some(email_id) => list.push(json!({ "id": id, "emailIds": [email_id] })),
+            None => not_found.push(id),
+        }
+    }
+
+    json!({ "accountId": ACCOUNT_ID, "state": store.state(), "list": list, "notFound": not_found })
+}
+
+/// Roughly what the message would weigh as RFC 5322 text
+fn demo_size(email: &StoredEmail) -> usize {
+    email.body.len() + email.subject.len() + 200
+}
+
+fn email_object(email: &StoredEmail) -> Value {
+    let keywords: serde_json::Map<String, Value> = email.keywords.iter().map(|k| (k.clone(), json!(true))).collect();
+    let mailbox_ids: serde_json::Map<String, Value> = email.mailbox_ids.iter().map(|m| (m.clone(), json!(true))).collect();
+    let preview: String = email.body.chars().take(80).collect();
+
+    json!({
+        "id": email.id,
+        "blobId": format!("blob-{}", email.id),
+        "threadId": format!("thread-{}", email.id),
+        "mailboxIds": mailbox_ids,
+        "from": [{ "name": email.from_name, "email": email.from_email }],
+        "to": email.to,
+        "subject": email.subject,
+        "receivedAt": email.received_at,
+        "size": demo_size(email),
+        "preview": preview.replace('\n', " "),
+        "textBody": [{ "partId": "1", "type": "text/plain" }],
+        "bodyValues": { "1": { "value": email.body, "isEncodingProblem": false, "isTruncated": false } },
+        "keywords": keywords,
+        "headers": raw_headers(email)
+    })
+}
+
+/// The header fields of the message `message_text` makes, as the
+/// `headers` property has them
+fn raw_headers(email: &StoredEmail) -> Value {
+    let raw = message_text(email);
+    let head = raw.split("\r\n\r\n").next().unwrap_or("");
+    let fields: Vec<Value> = head
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect();
+    json!(fields)
+}
+
+fn raw_message(store: &Mailstore, blob_id: &str) -> Option<String> {
+    let id = blob_id.strip_prefix("blob-")?;
+    store.find(id).map(message_text)
+}
+
+fn message_text(email: &StoredEmail) -> String {
+    let to: Vec<String> = email
+        .to
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|a| format!("{} <{}>", a["name"].as_str().unwrap_or(""), a["email"].as_str().unwrap_or("")))
+        .collect();
+    format!(
+        "From: {} <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@demo.invalid>\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+        email.from_name,
+        email.from_email,
+        to.join(", "),
+        email.subject,
+        email.received_at,
+        email.id,
+        email.body.replace('\n', "\r\n")
+    )
+}
+
+/// The time now as a UTC date the way JMAP writes them
+fn now() -> String {
+    dates::utc(dates::now())
+}
+
+#[cfg(test)]
+mod tests;
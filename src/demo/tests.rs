@@ -0,0 +1,378 @@
+//! The app against the demo backend: the JMAP client's calls, and the
+//! handlers signing in, opening a message and following its links over
+//! real HTTP.
+
+use std::sync::Arc;
+use std::thread;
+
+use super::*;
+use crate::config::Config;
+use crate::handlers::{self, AppState};
+use crate::jmap::{AuthScheme, EmailAddress, HttpOptions, JmapClient, OutgoingEmail};
+use crate::prefs::PrefsStore;
+use crate::session::SessionStore;
+
+fn client(well_known_url: &str) -> JmapClient {
+    let (_, client) = JmapClient::discover(
+        well_known_url,
+        "demo@example.com",
+        "anything",
+        &[AuthScheme::Basic],
+        HttpOptions::default(),
+    )
+    .expect("the demo backend takes any password");
+    client
+}
+
+fn unread(client: &JmapClient, mailbox_id: &str) -> u32 {
+    let mailboxes = client.get_mailboxes().unwrap();
+    mailboxes.iter().find(|m| m.id == mailbox_id).unwrap().unread_emails
+}
+
+fn in_mailbox(client: &JmapClient, mailbox_id: &str) -> Vec<String> {
+    client
+        .query_emails_filtered(json!({ "inMailbox": mailbox_id }), 50, 0)
+        .unwrap()
+        .ids
+}
+
+#[test]
+fn email_set_changes_the_messages() {
+    let client = client(&start().unwrap());
+    let (_, state) = client.get_emails_with_state(&["m02".to_string()]).unwrap();
+    let unread_before = unread(&client, "inbox");
+
+    client.set_keywords(&["m02".to_string()], &[("$seen", true)]).unwrap();
+    assert_eq!(unread(&client, "inbox"), unread_before - 1);
+    client.move_emails(&["m02".to_string()], "archive").unwrap();
+    assert!(in_mailbox(&client, "archive").contains(&"m02".to_string()));
+    assert!(!in_mailbox(&client, "inbox").contains(&"m02".to_string()));
+    assert_eq!(client.email_changes(&state).unwrap().updated, ["m02"]);
+
+    assert_eq!(client.destroy_emails(&["m02".to_string()]).unwrap(), 1);
+    assert!(client.get_email("m02").unwrap().is_none());
+    let changes = client.email_changes(&state).unwrap();
+    assert!(changes.updated.is_empty());
+    assert_eq!(changes.destroyed, ["m02"]);
+    assert!(client.email_changes("somewhere-else").unwrap_err().is_method_error("cannotCalculateChanges"));
+}
+
+#[test]
+fn backends_keep_their_own_messages() {
+    let first = client(&start().unwrap());
+    let second = client(&start().unwrap());
+    first.destroy_emails(&["m01".to_string()]).unwrap();
+    assert!(first.get_email("m01").unwrap().is_none());
+    assert!(second.get_email("m01").unwrap().is_some());
+}
+
+#[test]
+fn sent_messages_are_filed_in_sent() {
+    let client = client(&start().unwrap());
+    let identity = client.get_identities().unwrap().remove(0);
+    let email = OutgoingEmail {
+        to: vec![EmailAddress {
+            name: None,
+            email: Some("alice@example.com".to_string()),
+        }],
+        subject: "Demo send".to_string(),
+        text_body: "Hello".to_string(),
+        html_body: None,
+        ..Default::default()
+    };
+
+    let id = client.send_email(&identity, &email).unwrap();
+    assert!(in_mailbox(&client, "sent").contains(&id));
+    assert!(!in_mailbox(&client, "drafts").contains(&id));
+    let sent = client.get_email(&id).unwrap().unwrap();
+    assert_eq!(sent.subject.as_deref(), Some("Demo send"));
+    assert!(!sent.keywords.contains_key("$draft"));
+}
+
+/// The app itself on a loopback port, using `well_known_url`'s backend
+fn serve(well_known_url: &str) -> String {
+    serve_under(well_known_url, "")
+}
+
+/// The app mounted at `base_path`, as a reverse proxy would forward it
+fn serve_under(well_known_url: &str, base_path: &str) -> String {
+    let toml = format!(
+        "[server]\nlisten_addr = \"127.0.0.1\"\nlisten_port = 0\nbase_path = \"{}\"\n[jmap]\nwell_known_url = \"{}\"\n",
+        base_path, well_known_url
+    );
+    let config = Config::from_toml(&toml).unwrap();
+    let prefs = PrefsStore::open(&config.prefs).unwrap();
+    let sessions = SessionStore::open(&config.sessions, &config.jmap, &config.users, None).unwrap();
+    let state = Arc::new(AppState::new(config, prefs, sessions, None, None));
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            handlers::handle_request(&state, request);
+        }
+    });
+    url
+}
+
+#[test]
+fn signing_in_and_opening_a_message() {
+    let backend = start().unwrap();
+    let app = serve(&backend);
+    let agent = ureq::agent();
+
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let fetch = |path: &str| {
+        let url = format!("{}{}", app, path);
+        agent.get(&url).set("Cookie", &cookie).set("HX-Request", "true").call().unwrap().into_string().unwrap()
+    };
+
+    assert!(fetch("/mailboxes").contains("Projects"));
+    assert!(fetch("/email/m01").contains("Welcome to the demo"));
+    // Opening it marked it read on the server
+    let opened = client(&backend).get_email("m01").unwrap().unwrap();
+    assert!(opened.keywords.contains_key("$seen"));
+}
+
+#[test]
+fn links_stay_under_the_base_path() {
+    let app = serve_under(&start().unwrap(), "/webmail");
+    let agent = ureq::agent();
+    let login = agent
+        .post(&format!("{}/webmail/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    assert!(cookie.starts_with("session="));
+    let fetch = |path: &str| {
+        let url = format!("{}/webmail{}", app, path);
+        agent.get(&url).set("Cookie", &cookie).call().unwrap().into_string().unwrap()
+    };
+
+    for path in ["/", "/mailboxes", "/mailbox/inbox/emails", "/email/m01", "/compose", "/settings"] {
+        let html = fetch(path);
+        let links: Vec<&str> = html.split("=\"/").skip(1).filter(|rest| !rest.starts_with('/')).collect();
+        assert!(!links.is_empty(), "{} has no links", path);
+        for link in links {
+            assert!(link.starts_with("webmail/"), "{} links to /{}", path, &link[..link.find('"').unwrap_or(link.len())]);
+        }
+    }
+}
+
+#[test]
+fn pages_under_an_account_path_stay_on_that_account() {
+    let app = serve(&start().unwrap());
+    let agent = ureq::builder().redirects(0).build();
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let get = |path: &str| agent.get(&format!("{}{}", app, path)).set("Cookie", &cookie);
+
+    let html = get("/account/demo/mailbox/inbox/emails").call().unwrap().into_string().unwrap();
+    let links: Vec<&str> = html.split("=\"/").skip(1).filter(|rest| !rest.starts_with('/')).collect();
+    assert!(!links.is_empty());
+    for link in links {
+        assert!(link.starts_with("account/demo/"), "links to /{}", &link[..link.find('"').unwrap_or(link.len())]);
+    }
+
+    let bare = get("/account/demo").call().unwrap();
+    assert_eq!(bare.status(), 303);
+    assert_eq!(bare.header("Location"), Some("/account/demo/"));
+    assert!(matches!(get("/account/someone-else/mailboxes").call(), Err(ureq::Error::Status(404, _))));
+}
+
+#[test]
+fn the_wrong_method_is_refused_with_those_allowed() {
+    let app = serve(&start().unwrap());
+    let refused = ureq::request("DELETE", &format!("{}/login", app)).call().unwrap_err();
+    let ureq::Error::Status(405, response) = refused else {
+        panic!("expected 405, got {:?}", refused);
+    };
+    assert_eq!(response.header("Allow"), Some("GET, POST"));
+    assert!(matches!(ureq::get(&format!("{}/login/", app)).call(), Err(ureq::Error::Status(404, _))));
+}
+
+#[test]
+fn following_a_link_from_a_message() {
+    let app = serve(&start().unwrap());
+    let link = crate::links::redirect_path("https://shop.example/sale?id=7&utm_source=news");
+
+    // Nobody else gets a click-through page on our origin
+    let anonymous = ureq::builder().redirects(0).build();
+    let to_login = anonymous.get(&format!("{}{}", app, link)).call().unwrap();
+    assert_eq!(to_login.status(), 303);
+    assert_eq!(to_login.header("Location"), Some("/login"));
+
+    let agent = ureq::agent();
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let page = agent.get(&format!("{}{}", app, link)).set("Cookie", &cookie).call().unwrap();
+    assert_eq!(page.header("Referrer-Policy"), Some("no-referrer"));
+    let html = page.into_string().unwrap();
+    assert!(html.contains(r#"href="https://shop.example/sale?id=7""#));
+    assert!(html.contains("utm_source"));
+
+    let script = crate::links::redirect_path("javascript:alert(1)");
+    let refused = agent.get(&format!("{}{}", app, script)).set("Cookie", &cookie).call().unwrap_err();
+    assert!(matches!(refused, ureq::Error::Status(400, _)));
+}
+
+#[test]
+fn composing_from_a_mailto_link() {
+    let app = serve(&start().unwrap());
+    let agent = ureq::agent();
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let url = format!("{}/compose?to=mailto%3Aann%40example.com%3Fsubject%3DHello%2520there", app);
+
+    // The browser following it gets the app, which then loads the form
+    let page = agent.get(&url).set("Cookie", &cookie).call().unwrap().into_string().unwrap();
+    assert!(page.contains(r#"hx-get="/compose?to=mailto%3Aann%40example.com%3Fsubject%3DHello%2520there""#));
+    let form = agent.get(&url).set("Cookie", &cookie).set("HX-Request", "true").call().unwrap().into_string().unwrap();
+    assert!(form.contains(r#"value="ann@example.com""#));
+    assert!(form.contains(r#"value="Hello there""#));
+}
+
+#[test]
+fn muting_a_conversation() {
+    let backend = start().unwrap();
+    let app = serve(&backend);
+    let agent = ureq::agent();
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let csrf = login.into_string().unwrap();
+    let csrf = csrf.split(r#""X-CSRF-Token": ""#).nth(1).and_then(|rest| rest.split('"').next()).unwrap().to_string();
+    let post = |path: &str| {
+        let url = format!("{}{}", app, path);
+        agent.post(&url).set("Cookie", &cookie).set("HX-Request", "true").set("X-CSRF-Token", &csrf).send_string("").unwrap().into_string().unwrap()
+    };
+
+    assert!(post("/thread/thread-m02/mute").contains(r#"hx-post="/thread/thread-m02/unmute""#));
+    let muted = client(&backend).get_email("m02").unwrap().unwrap();
+    assert!(muted.keywords.contains_key("$muted") && muted.keywords.contains_key("$seen"));
+    // Nothing else in the thread, so the Inbox keeps it
+    assert!(in_mailbox(&client(&backend), "inbox").contains(&"m02".to_string()));
+
+    assert!(post("/thread/thread-m02/unmute").contains(r#"hx-post="/thread/thread-m02/mute""#));
+    assert!(!client(&backend).get_email("m02").unwrap().unwrap().keywords.contains_key("$muted"));
+}
+
+#[test]
+fn pinning_a_message() {
+    let backend = start().unwrap();
+    let app = serve(&backend);
+    let agent = ureq::agent();
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let csrf = login.into_string().unwrap();
+    let csrf = csrf.split(r#""X-CSRF-Token": ""#).nth(1).and_then(|rest| rest.split('"').next()).unwrap().to_string();
+    let post = |path: &str| {
+        let url = format!("{}{}", app, path);
+        agent.post(&url).set("Cookie", &cookie).set("HX-Request", "true").set("X-CSRF-Token", &csrf).send_string("").unwrap().into_string().unwrap()
+    };
+    let list = || {
+        let url = format!("{}/mailbox/inbox/emails", app);
+        agent.get(&url).set("Cookie", &cookie).set("HX-Request", "true").call().unwrap().into_string().unwrap()
+    };
+
+    assert!(!list().contains(r#"class="pinned""#));
+    assert!(post("/email/m03/pin").contains(r#"hx-post="/email/m03/unpin""#));
+    let inbox = list();
+    let pinned = inbox.find(r#"<tbody class="pinned">"#).unwrap();
+    let row = inbox.find(r#"id="row-m03""#).unwrap();
+    assert!(pinned < row && inbox.find(r#"id="row-m01""#).unwrap() > row);
+    // Listed once, in the pinned section only
+    assert_eq!(inbox.matches(r#"id="row-m03""#).count(), 1);
+
+    assert!(post("/email/m03/unpin").contains(r#"hx-post="/email/m03/pin""#));
+    assert!(!list().contains(r#"class="pinned""#));
+}
+
+#[test]
+fn a_refused_action_is_rolled_back() {
+    let backend = start().unwrap();
+    let app = serve(&backend);
+    let agent = ureq::agent();
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let csrf = login.into_string().unwrap();
+    let csrf = csrf.split(r#""X-CSRF-Token": ""#).nth(1).and_then(|rest| rest.split('"').next()).unwrap().to_string();
+    let post = |path: &str| {
+        let url = format!("{}{}", app, path);
+        agent.post(&url).set("Cookie", &cookie).set("HX-Request", "true").set("X-CSRF-Token", &csrf)
+    };
+
+    let flagged = post("/email/m01/flag").send_string("").unwrap();
+    assert_eq!(flagged.header("HX-Reswap"), None);
+    assert!(flagged.into_string().unwrap().contains(r#"id="star-row-m01" hx-swap-oob="true""#));
+
+    // The server has no such message, so the page's star and row are put
+    // back, with a toast saying why
+    let refused = post("/email/gone/flag").send_string("").unwrap();
+    assert_eq!(refused.status(), 200);
+    assert_eq!(refused.header("HX-Reswap"), Some("none"));
+    let body = refused.into_string().unwrap();
+    assert!(body.contains(r#"<div id="toasts" hx-swap-oob="beforeend"><div class="toast error" role="alert">Failed to update message"#));
+}
+
+#[test]
+fn a_chunked_upload_carries_on_where_it_got_to() {
+    let backend = start().unwrap();
+    let app = serve(&backend);
+    let agent = ureq::agent();
+    let login = agent
+        .post(&format!("{}/login", app))
+        .send_form(&[("username", "demo@example.com"), ("password", "anything")])
+        .unwrap();
+    let cookie = login.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+    let csrf = login.into_string().unwrap();
+    let csrf = csrf.split(r#""X-CSRF-Token": ""#).nth(1).and_then(|rest| rest.split('"').next()).unwrap().to_string();
+    let request = |method: &str, path: &str| {
+        let url = format!("{}{}", app, path);
+        agent.request(method, &url).set("Cookie", &cookie).set("HX-Request", "true").set("X-CSRF-Token", &csrf)
+    };
+    let received = |response: ureq::Response| response.into_json::<serde_json::Value>().unwrap()["received"].clone();
+
+    let started: serde_json::Value = request("POST", "/compose/uploads")
+        .send_form(&[("name", "notes.txt"), ("type", "text/plain"), ("size", "11")])
+        .unwrap()
+        .into_json()
+        .unwrap();
+    let url = format!("/compose/uploads/{}", started["id"].as_str().unwrap());
+
+    let first = request("POST", &format!("{}?offset=0", url)).send_bytes(b"hello ").unwrap();
+    assert_eq!(first.status(), 202);
+    assert_eq!(received(first), 6);
+    // Its answer was lost, so the chunk is sent again and turned down with
+    // where to carry on from, as asking says too
+    let Err(ureq::Error::Status(409, again)) = request("POST", &format!("{}?offset=0", url)).send_bytes(b"hello ") else {
+        panic!("a chunk sent twice is turned down");
+    };
+    assert_eq!(received(again), 6);
+    assert_eq!(received(request("GET", &url).call().unwrap()), 6);
+
+    let last = request("POST", &format!("{}?offset=6", url)).send_bytes(b"world").unwrap();
+    let line = last.into_string().unwrap();
+    assert!(line.contains("notes.txt") && line.contains("demo-upload-11"), "{}", line);
+    assert!(matches!(request("GET", &url).call(), Err(ureq::Error::Status(404, _))));
+}
@@ -0,0 +1,85 @@
+//! Finding a user's JMAP server from the domain of their address, for
+//! `jmap.autodiscover` (RFC 8620 section 2.2).
+//!
+//! `https://{domain}/.well-known/jmap` is tried first. If nothing useful
+//! answers there, the domain's `_jmap._tcp` SRV record, looked up through
+//! a DNS-over-HTTPS JSON resolver, names the host and port to try instead.
+
+use std::time::Duration;
+
+use crate::bimi::address_domain;
+use crate::config::JmapConfig;
+use crate::jmap::{AuthScheme, JmapClient, JmapError, JmapSession};
+use crate::{log_debug, log_info, log_warn};
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Log in at the server found for `username`, returning the well-known URL
+/// that worked along with the session
+pub fn discover(
+    username: &str,
+    password: &str,
+    schemes: &[AuthScheme],
+    jmap: &JmapConfig,
+) -> Result<(String, JmapSession, JmapClient), JmapError> {
+    let domain = address_domain(username)
+        .ok_or_else(|| JmapError::Api(format!("can't tell which mail server {} belongs to", username)))?;
+
+    let url = format!("https://{}/.well-known/jmap", domain);
+    log_info!("[JMAP] Autodiscovering server for {} at {}", domain, url);
+    let error = match JmapClient::discover(&url, username, password, schemes, jmap.http_options()) {
+        Ok((session, client)) => return Ok((url, session, client)),
+        // The right server, and the password was wrong
+        Err(JmapError::Unauthorized) => return Err(JmapError::Unauthorized),
+        Err(e) => e,
+    };
+
+    let Some((host, port)) = lookup_srv(&domain, &jmap.doh_url) else {
+        return Err(error);
+    };
+    let url = format!("https://{}:{}/.well-known/jmap", host, port);
+    log_info!("[JMAP] Trying {} from the SRV record of {}", url, domain);
+    let (session, client) = JmapClient::discover(&url, username, password, schemes, jmap.http_options())?;
+    Ok((url, session, client))
+}
+
+/// The target of the domain's `_jmap._tcp` SRV record with the best
+/// priority, if it has one
+fn lookup_srv(domain: &str, doh_url: &str) -> Option<(String, u16)> {
+    let name = format!("_jmap._tcp.{}", domain);
+    let response: serde_json::Value = ureq::AgentBuilder::new()
+        .timeout(LOOKUP_TIMEOUT)
+        .build()
+        .get(doh_url)
+        .query("name", &name)
+        .query("type", "SRV")
+        .set("Accept", "application/dns-json")
+        .call()
+        .map_err(|e| log_warn!("[JMAP] DNS lookup for {} failed: {}", name, e))
+        .ok()?
+        .into_json()
+        .ok()?;
+
+    // Each answer's data is "priority weight port target."
+    let best = response["Answer"]
+        .as_array()?
+        .iter()
+        .filter_map(|answer| {
+            let mut fields = answer["data"].as_str()?.split_whitespace();
+            let priority: u16 = fields.next()?.parse().ok()?;
+            let _weight = fields.next()?;
+            let port: u16 = fields.next()?.parse().ok()?;
+            let target = fields.next()?.trim_end_matches('.').to_string();
+            // A target of "." says the service is deliberately not offered
+            (!target.is_empty()).then_some((priority, target, port))
+        })
+        .min_by_key(|(priority, _, _)| *priority);
+
+    match best {
+        Some((_, target, port)) => Some((target, port)),
+        None => {
+            log_debug!("[JMAP] No SRV record for {}", name);
+            None
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! A mailbox's messages as one download, for backing it up: an mbox file,
+//! or a zip of .eml files. Messages go oldest first, found a page of
+//! Email/query at a time, and each original is downloaded only when the
+//! export reaches it, so a large mailbox never has to fit in memory.
+//!
+//! The mbox is the mboxrd kind: a line starting with "From ", after any
+//! number of ">", gets one more ">", which readers take off again. Line
+//! endings become LF, as mbox readers expect.
+//!
+//! Once the download has started there is no error page to show. An mbox
+//! that can't be finished is cut off with an error, so the browser reports
+//! the download as failed rather than leaving a backup that looks whole;
+//! a zip lists what it couldn't get in MISSING.txt.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::Arc;
+
+use crate::jmap::{Email, JmapClient, JmapError};
+use crate::zip;
+use crate::{dates, log, log_error, log_info};
+
+/// Emails asked for per Email/query page
+const PAGE_SIZE: u32 = 100;
+
+/// The emails matching a filter, a page at a time. An error fetching a
+/// page ends the list with it.
+pub struct Messages {
+    client: Arc<JmapClient>,
+    filter: serde_json::Value,
+    position: u32,
+    page: VecDeque<Email>,
+    done: bool,
+}
+
+impl Messages {
+    /// The first page is fetched straight away, so that a server that
+    /// can't be reached is reported before the download starts
+    pub fn start(client: Arc<JmapClient>, filter: serde_json::Value) -> Result<Messages, JmapError> {
+        let mut messages = Messages {
+            client,
+            filter,
+            position: 0,
+            page: VecDeque::new(),
+            done: false,
+        };
+        messages.fetch()?;
+        Ok(messages)
+    }
+
+    fn fetch(&mut self) -> Result<(), JmapError> {
+        let (result, emails) = self.client.query_export_page(self.filter.clone(), PAGE_SIZE, self.position)?;
+        // The server may clamp the position asked for, so go on from its
+        self.position = result.position + result.ids.len() as u32;
+        self.done = result.ids.is_empty() || result.total.is_some_and(|total| self.position >= total);
+        self.page.extend(emails);
+        Ok(())
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Result<Email, JmapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Emails destroyed since their page was found leave it short
+        while self.page.is_empty() && !self.done {
+            if let Err(e) = self.fetch() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
+
+/// The message as it arrived (RFC 5322)
+fn open(client: &JmapClient, email: &Email) -> io::Result<Box<dyn Read + Send>> {
+    let blob_id = email
+        .blob_id
+        .as_deref()
+        .ok_or_else(|| io::Error::other("the server has no original of it"))?;
+    match client.download_blob(blob_id, "email.eml", "message/rfc822") {
+        Ok(download) => Ok(download.reader as Box<dyn Read + Send>),
+        Err(e) => {
+            log_error!("Failed to download {} for export: {}", email.id, e);
+            Err(io::Error::other(e.to_string()))
+        }
+    }
+}
+
+/// The messages as an mbox file, produced as it is read
+pub struct Mbox {
+    client: Arc<JmapClient>,
+    messages: Messages,
+    current: Option<BufReader<Box<dyn Read + Send>>>,
+    line: Vec<u8>,
+    /// Bytes ready to go out ahead of anything else
+    pending: Vec<u8>,
+    pending_at: usize,
+    exported: usize,
+}
+
+impl Mbox {
+    pub fn new(client: Arc<JmapClient>, messages: Messages) -> Mbox {
+        Mbox {
+            client,
+            messages,
+            current: None,
+            line: Vec::new(),
+            pending: Vec::new(),
+            pending_at: 0,
+            exported: 0,
+        }
+    }
+
+    fn start_message(&mut self, email: &Email) -> io::Result<()> {
+        let reader = open(&self.client, email)
+            .map_err(|e| io::Error::other(format!("message {} could not be exported: {}", email.id, e)))?;
+        // The envelope sender isn't known, so the From header's stands in
+        let sender = email
+            .from
+            .as_ref()
+            .and_then(|from| from.first())
+            .and_then(|address| address.email.as_deref())
+            .filter(|email| !email.is_empty() && !email.contains(char::is_whitespace))
+            .unwrap_or("MAILER-DAEMON");
+        let received = email.received_at.as_deref().and_then(dates::parse).unwrap_or(0);
+        let from_line = format!("From {} {}\n", sender, log::asctime_date(received.max(0) as u64));
+        self.pending.extend_from_slice(from_line.as_bytes());
+        self.current = Some(BufReader::new(reader));
+        self.exported += 1;
+        Ok(())
+    }
+}
+
+/// `line` as it goes in an mbox: quoted if it would read as a "From "
+/// line, ending in LF
+fn push_line(line: &[u8], out: &mut Vec<u8>) {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let unquoted = &line[line.iter().take_while(|&&b| b == b'>').count()..];
+    if unquoted.starts_with(b"From ") {
+        out.push(b'>');
+    }
+    out.extend_from_slice(line);
+    out.push(b'\n');
+}
+
+impl Read for Mbox {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pending_at < self.pending.len() {
+                let n = out.len().min(self.pending.len() - self.pending_at);
+                out[..n].copy_from_slice(&self.pending[self.pending_at..self.pending_at + n]);
+                self.pending_at += n;
+                return Ok(n);
+            }
+            self.pending.clear();
+            self.pending_at = 0;
+            if let Some(reader) = self.current.as_mut() {
+                self.line.clear();
+                if reader.read_until(b'\n', &mut self.line)? == 0 {
+                    // A blank line ends each message
+                    self.current = None;
+                    self.pending.push(b'\n');
+                } else {
+                    push_line(&self.line, &mut self.pending);
+                }
+                continue;
+            }
+            match self.messages.next() {
+                Some(Ok(email)) => self.start_message(&email)?,
+                Some(Err(e)) => {
+                    log_error!("Mailbox export stopped after {} messages: {}", self.exported, e);
+                    return Err(io::Error::other(e.to_string()));
+                }
+                None => {
+                    log_info!("Exported {} messages as mbox", self.exported);
+                    return Ok(0);
+                }
+            }
+        }
+    }
+}
+
+/// The messages as zip entries named by date and subject, each downloaded
+/// when the archive reaches it. A page that can't be fetched ends them with
+/// an entry that fails to open, so MISSING.txt says where they stop.
+pub fn eml_entries(client: Arc<JmapClient>, messages: Messages) -> impl Iterator<Item = zip::Entry> + Send {
+    let mut exported = 0;
+    messages.map(move |message| match message {
+        Ok(email) => {
+            exported += 1;
+            let client = Arc::clone(&client);
+            zip::Entry {
+                name: eml_name(&email),
+                open: Box::new(move || open(&client, &email)),
+            }
+        }
+        Err(e) => {
+            log_error!("Mailbox export stopped after {} messages: {}", exported, e);
+            zip::Entry {
+                name: format!("messages after the first {}", exported),
+                open: Box::new(move || Err(io::Error::other(e.to_string()))),
+            }
+        }
+    })
+}
+
+/// "2024-03-02 Subject.eml", the subject cut short; the archive makes
+/// names safe and unique
+fn eml_name(email: &Email) -> String {
+    let date = email.received_at.as_deref().and_then(|at| at.get(..10)).unwrap_or("undated");
+    let subject: String = email.subject.as_deref().unwrap_or_default().chars().take(60).collect();
+    match subject.trim() {
+        "" => format!("{}.eml", date),
+        subject => format!("{} {}.eml", date, subject),
+    }
+}
@@ -0,0 +1,393 @@
+//! The JSON API under `/api/v1`, for scripts and other frontends: the
+//! webmail's own mail handling without its pages, as a simpler gateway to
+//! the JMAP server.
+//!
+//! `POST /api/v1/token` signs in with a username and password, as the
+//! login form does, and answers with a token to send back as
+//! `Authorization: Bearer <token>`. The token names a session like the
+//! cookie does, and expires with it; `DELETE /api/v1/token` ends it
+//! sooner. Errors come back as `{"error": "..."}` with the status saying
+//! what kind: 400 for a request we can't act on, 401 without a live token
+//! or once the JMAP server stops accepting the session's credentials,
+//! 404 for a message that isn't there, 409 for one that isn't in a state
+//! the action fits, and 502 when the JMAP server failed us.
+//!
+//! `openapi.json` beside this file describes it all, and is served at
+//! `/api/openapi.json`. The tests hold it to the routes, so a route added
+//! or changed here needs its description there too. Its `info.version` is
+//! the API's: additions bump the minor version, and a change that would
+//! break existing clients belongs under a new `/api/v2` instead.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tiny_http::{Header, Request, Response};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use super::params::Params;
+use super::{
+    check_code, device, json_response, mailbox_filter, needs_code, parse_search, respond, sign_in, AppState, Page,
+    SignInError,
+};
+use crate::jmap::{BodyPart, Email, EmailAddress, JmapClient, JmapError, Mailbox, Position};
+use crate::session::Device;
+use crate::{log_error, log_info};
+
+pub const OPENAPI: &str = include_str!("openapi.json");
+
+/// The OpenAPI description, its server the path we're mounted at
+pub fn openapi(state: &AppState, request: Request) -> Result<(), ()> {
+    let mut document: Value = serde_json::from_str(OPENAPI).expect("openapi.json is valid JSON");
+    let base_path = &state.config.server.base_path;
+    if !base_path.is_empty() {
+        document["servers"] = json!([{ "url": base_path }]);
+    }
+    reply(state, request, 200, &document)
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    username: String,
+    password: Zeroizing<String>,
+    /// As the login form's `server` field: an index into
+    /// `[[jmap.servers]]`, or `auto`
+    #[serde(default)]
+    server: Option<String>,
+    /// From the authenticator app, if the user set two-factor up
+    #[serde(default)]
+    code: Option<String>,
+}
+
+/// Sign in, answering with the token for the new session
+pub fn create_token(state: &Arc<AppState>, mut request: Request) -> Result<(), ()> {
+    // The body holds the password, so wipe it afterwards
+    let mut body = Zeroizing::new(String::new());
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        return error(state, request, 400, "Failed to read request");
+    }
+    let login = match serde_json::from_str::<TokenRequest>(&body) {
+        Ok(login) if !login.username.is_empty() && !login.password.is_empty() => login,
+        _ => return error(state, request, 400, "Expected a JSON object with a username and password"),
+    };
+
+    match sign_in(state, login.username, login.password.to_string(), login.server.as_deref()) {
+        Ok(mut session) => {
+            if needs_code(state, &session.username)
+                && !login.code.is_some_and(|code| check_code(state, &session.username, &code))
+            {
+                return error(state, request, 401, "Two-factor code required");
+            }
+            session.device = Device {
+                api: true,
+                ..device(state, &request)
+            };
+            let mut body = json!({
+                "username": session.username,
+                "account_id": session.account_id,
+            });
+            let session_id = state.sessions.create(session);
+            log_info!("Issued an API token for session {}", session_id);
+            body["token"] = json!(session_id.to_string());
+            reply(state, request, 201, &body)
+        }
+        Err(SignInError::UnknownServer) => error(state, request, 400, "Unknown mail server"),
+        Err(SignInError::Failed(JmapError::Unauthorized)) => error(state, request, 401, "Login failed"),
+        Err(SignInError::Failed(e)) => error(state, request, 502, &format!("Login failed: {}", e)),
+    }
+}
+
+/// End the token's session
+pub fn revoke_token(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
+    log_info!("Revoking API token for session {}", session_id);
+    state.sessions.remove(session_id);
+    respond(state, request, Response::empty(204)).map_err(|_| ())
+}
+
+pub fn mailboxes(state: &Arc<AppState>, client: JmapClient, request: Request) -> Result<(), ()> {
+    match client.get_mailboxes() {
+        Ok(mailboxes) => {
+            let list: Vec<Value> = mailboxes.iter().map(mailbox_json).collect();
+            reply(state, request, 200, &json!({ "mailboxes": list }))
+        }
+        Err(e) => failed(state, request, "Failed to load mailboxes", &e),
+    }
+}
+
+/// A page of a mailbox's messages, with the HTML list's `offset`, `limit`,
+/// `sort`, `dir` and `unread` parameters
+pub fn mailbox_emails(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    mailbox_id: &str,
+    query: &Params,
+    request: Request,
+) -> Result<(), ()> {
+    let filter = mailbox_filter(&client, mailbox_id);
+    list(state, &client, filter, Page::from_params(query), request)
+}
+
+/// A page of the messages matching the search form's fields, which are
+/// taken as the same parameters
+pub fn search(state: &Arc<AppState>, client: JmapClient, query: &Params, request: Request) -> Result<(), ()> {
+    let filter = match parse_search(query).filter() {
+        Ok(Some(filter)) => filter,
+        Ok(None) => return error(state, request, 400, "Enter at least one search criterion"),
+        Err(e) => return error(state, request, 400, &e),
+    };
+    list(state, &client, Ok(filter.to_json()), Page::from_params(query), request)
+}
+
+fn list(
+    state: &AppState,
+    client: &JmapClient,
+    filter: Result<Value, JmapError>,
+    page: Page,
+    request: Request,
+) -> Result<(), ()> {
+    let query = filter.and_then(|filter| {
+        client.query_and_get_emails(page.filter(filter), &page.sort, page.limit, &Position::Index(page.offset))
+    });
+    match query {
+        Ok((result, mut emails, _)) => {
+            // Email/get needn't answer in the order asked
+            emails.sort_by_key(|e| result.ids.iter().position(|id| *id == e.id));
+            let list: Vec<Value> = emails.iter().map(summary_json).collect();
+            let body = json!({
+                "total": result.total,
+                "position": result.position,
+                "emails": list,
+            });
+            reply(state, request, 200, &body)
+        }
+        Err(e) => failed(state, request, "Failed to query emails", &e),
+    }
+}
+
+/// One message in full. Unlike opening it in the webmail, this doesn't
+/// mark it read.
+pub fn email(state: &Arc<AppState>, client: JmapClient, email_id: &str, request: Request) -> Result<(), ()> {
+    match client.get_email(email_id) {
+        Ok(Some(email)) => reply(state, request, 200, &email_json(&email)),
+        Ok(None) => error(state, request, 404, "Email not found"),
+        Err(e) => failed(state, request, "Failed to load email", &e),
+    }
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    mailbox: String,
+    /// Add the mailbox, keeping the ones it's in, rather than move there
+    #[serde(default)]
+    copy: bool,
+}
+
+/// Why an action wasn't carried out
+enum Refusal {
+    Status(u16, String),
+    Failed(JmapError),
+}
+
+impl From<JmapError> for Refusal {
+    fn from(e: JmapError) -> Self {
+        Refusal::Failed(e)
+    }
+}
+
+/// Do `action` to a message, as the buttons in its view do: `seen`,
+/// `unseen`, `flag`, `unflag`, `archive`, `spam`, `not-spam`, `delete`, or
+/// `move` with `{"mailbox": id}` (and `"copy": true` to keep it where it
+/// is too). `delete` moves it to Trash, and destroys one already there
+/// only with `?confirm=yes`. Answers 204 once done.
+pub fn act(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    action: &str,
+    query: &Params,
+    mut request: Request,
+) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        return error(state, request, 400, "Failed to read request");
+    }
+
+    match apply(&client, email_id, action, query, &body) {
+        Ok(0) => error(state, request, 404, "Email not found"),
+        Ok(_) => {
+            log_info!("API: {} email {}", action, email_id);
+            respond(state, request, Response::empty(204)).map_err(|_| ())
+        }
+        Err(Refusal::Status(status, message)) => error(state, request, status, &message),
+        Err(Refusal::Failed(e)) => failed(state, request, &format!("Failed to {} message", action), &e),
+    }
+}
+
+/// Carry out `act`'s action, giving how many messages it changed
+fn apply(client: &JmapClient, email_id: &str, action: &str, query: &Params, body: &str) -> Result<usize, Refusal> {
+    let ids = &[email_id.to_string()];
+    let changed = match action {
+        "seen" | "unseen" => client.set_keywords(ids, &[("$seen", action == "seen")])?,
+        "flag" | "unflag" => client.set_keywords(ids, &[("$flagged", action == "flag")])?,
+        "archive" => {
+            let Some(archive) = with_role(client, "archive")? else {
+                return Err(Refusal::Status(409, "This account has no Archive mailbox".to_string()));
+            };
+            client.move_emails(ids, &archive.id)?
+        }
+        "spam" | "not-spam" => {
+            let junk = action == "spam";
+            let target = with_role(client, if junk { "junk" } else { "inbox" })?;
+            client.report_junk(ids, junk, target.as_ref().map(|m| m.id.as_str()))?
+        }
+        "move" => {
+            let Ok(target) = serde_json::from_str::<MoveRequest>(body) else {
+                return Err(Refusal::Status(400, "Expected a JSON object with a mailbox".to_string()));
+            };
+            if target.copy {
+                client.copy_emails(ids, &target.mailbox)?
+            } else {
+                client.move_emails(ids, &target.mailbox)?
+            }
+        }
+        "delete" => {
+            let Some(email) = client.get_emails(ids)?.into_iter().next() else {
+                return Ok(0);
+            };
+            let trash = with_role(client, "trash")?;
+            let in_trash = trash.as_ref().is_some_and(|t| email.mailbox_ids.get(&t.id).copied().unwrap_or(false));
+            match trash {
+                Some(trash) if !in_trash => client.move_emails(ids, &trash.id)?,
+                // Already in Trash, or the account has none
+                _ if query.get("confirm") != Some("yes") => {
+                    let message = "Deleting this message can't be undone; repeat with ?confirm=yes";
+                    return Err(Refusal::Status(409, message.to_string()));
+                }
+                _ => client.destroy_emails(ids)?,
+            }
+        }
+        _ => return Err(Refusal::Status(404, format!("Unknown action: {}", action))),
+    };
+    Ok(changed)
+}
+
+/// The account's mailbox with `role`, if it has one
+fn with_role(client: &JmapClient, role: &str) -> Result<Option<Mailbox>, JmapError> {
+    Ok(client.get_mailboxes()?.into_iter().find(|m| m.role.as_deref() == Some(role)))
+}
+
+/// The session id in an `Authorization: Bearer` header
+pub fn bearer_token(authorization: &str) -> Option<Uuid> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Uuid::parse_str(token.trim()).ok()
+}
+
+/// For a request without a live token
+pub fn unauthorized(state: &AppState, request: Request) -> Result<(), ()> {
+    let response = json_response(state, json!({ "error": "Sign in for a token first" }).to_string())
+        .with_status_code(401)
+        .with_header(Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..]).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+fn mailbox_json(mailbox: &Mailbox) -> Value {
+    json!({
+        "id": mailbox.id,
+        "name": mailbox.name,
+        "parent_id": mailbox.parent_id,
+        "role": mailbox.role,
+        "total": mailbox.total_emails,
+        "unread": mailbox.unread_emails,
+        "sort_order": mailbox.sort_order,
+    })
+}
+
+/// A message as an entry in a list
+fn summary_json(email: &Email) -> Value {
+    json!({
+        "id": email.id,
+        "thread_id": email.thread_id,
+        "mailbox_ids": set_keys(&email.mailbox_ids),
+        "keywords": set_keys(&email.keywords),
+        "from": addresses(&email.from),
+        "to": addresses(&email.to),
+        "subject": email.subject,
+        "received_at": email.received_at,
+        "size": email.size,
+        "preview": email.preview,
+    })
+}
+
+/// A message with its headers and body. The HTML is as the sender wrote
+/// it, not sanitized as the webmail shows it, so a frontend showing it
+/// has to do that itself.
+fn email_json(email: &Email) -> Value {
+    let body = |parts: &Option<Vec<BodyPart>>, content_type: &str| {
+        let text: String = parts
+            .iter()
+            .flatten()
+            .filter(|part| part.r#type.as_deref() == Some(content_type))
+            .filter_map(|part| email.body_values.get(&part.part_id))
+            .map(|value| value.value.as_str())
+            .collect();
+        (!text.is_empty()).then_some(text)
+    };
+    let attachments: Vec<Value> = email
+        .attachments
+        .iter()
+        .flatten()
+        .map(|part| {
+            json!({
+                "part_id": part.part_id,
+                "blob_id": part.blob_id,
+                "name": part.name,
+                "type": part.r#type,
+                "size": part.size,
+            })
+        })
+        .collect();
+
+    let mut json = summary_json(email);
+    json["cc"] = json!(addresses(&email.cc));
+    json["bcc"] = json!(addresses(&email.bcc));
+    json["reply_to"] = json!(addresses(&email.reply_to));
+    json["message_id"] = json!(email.message_id);
+    json["in_reply_to"] = json!(email.in_reply_to);
+    json["references"] = json!(email.references);
+    json["text"] = json!(body(&email.text_body, "text/plain"));
+    json["html"] = json!(body(&email.html_body, "text/html"));
+    json["attachments"] = json!(attachments);
+    json
+}
+
+fn addresses(list: &Option<Vec<EmailAddress>>) -> &[EmailAddress] {
+    list.as_deref().unwrap_or_default()
+}
+
+/// The keys of a JMAP set such as `mailboxIds`, in order
+fn set_keys(set: &HashMap<String, bool>) -> Vec<&str> {
+    let mut keys: Vec<&str> = set.iter().filter(|(_, on)| **on).map(|(key, _)| key.as_str()).collect();
+    keys.sort_unstable();
+    keys
+}
+
+fn reply(state: &AppState, request: Request, status: u16, body: &Value) -> Result<(), ()> {
+    let response = json_response(state, body.to_string()).with_status_code(status);
+    respond(state, request, response).map_err(|_| ())
+}
+
+pub(super) fn error(state: &AppState, request: Request, status: u16, message: &str) -> Result<(), ()> {
+    reply(state, request, status, &json!({ "error": message }))
+}
+
+/// For a request the JMAP server failed. One that no longer accepts the
+/// credentials gets a 401, and the session ends after it.
+fn failed(state: &AppState, request: Request, what: &str, e: &JmapError) -> Result<(), ()> {
+    log_error!("{}: {}", what, e);
+    let status = if matches!(e, JmapError::Unauthorized) { 401 } else { 502 };
+    error(state, request, status, &format!("{}: {}", what, e))
+}
@@ -0,0 +1,97 @@
+//! What can go wrong handling a page's request, and how each kind is
+//! answered: with a status code saying what kind it is, as a fragment in
+//! the pane htmx asked for, or as a page of its own when the browser
+//! loaded the URL itself. `app.js` has htmx swap the fragment in despite
+//! the status.
+//!
+//! When the JMAP server stops accepting the session's credentials part way
+//! through (a changed password, a revoked app password), the browser is
+//! sent back to the login page; `middleware::end_rejected_session` ends
+//! the session.
+
+use tiny_http::{Header, Request};
+
+use super::{header_value, html_response, is_https, respond, AppState};
+use crate::jmap::JmapError;
+use crate::session::clear_session_cookie;
+use crate::templates;
+
+#[derive(Debug)]
+pub enum AppError {
+    /// The JMAP server no longer accepts the session's credentials
+    AuthExpired,
+    /// The JMAP server couldn't be reached, or answered with something
+    /// that isn't JMAP
+    Upstream(String),
+    /// The JMAP server turned a method call down
+    Method(String),
+    /// A request that can't be acted on as it was sent
+    BadRequest(String),
+    /// A request body larger than the route takes
+    TooLarge(String),
+}
+
+impl AppError {
+    /// `e`, from the JMAP server while doing `what`, such as "Failed to
+    /// load email"
+    pub fn jmap(what: &str, e: JmapError) -> AppError {
+        let message = format!("{}: {}", what, e);
+        match e {
+            JmapError::Unauthorized => AppError::AuthExpired,
+            JmapError::Http(_) | JmapError::Parse(_) => AppError::Upstream(message),
+            JmapError::Api(_) => AppError::Method(message),
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            AppError::AuthExpired => 401,
+            AppError::Upstream(_) => 502,
+            AppError::Method(_) => 422,
+            AppError::BadRequest(_) => 400,
+            AppError::TooLarge(_) => 413,
+        }
+    }
+
+    pub fn respond(self, state: &AppState, request: Request) -> Result<(), ()> {
+        let status = self.status();
+        if let AppError::AuthExpired = self {
+            return sign_in_again(state, request);
+        }
+        let message = self.to_string();
+        let html = match header_value(&request, "HX-Request") {
+            Some(_) => templates::error_fragment(&message),
+            None => templates::error_page(&state.config.branding, &message),
+        };
+        respond(state, request, html_response(state, html).with_status_code(status)).map_err(|_| ())
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::AuthExpired => write!(f, "The mail server no longer accepts your sign-in"),
+            AppError::Upstream(message)
+            | AppError::Method(message)
+            | AppError::BadRequest(message)
+            | AppError::TooLarge(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Send the browser to the login page, saying why, with the cookie of the
+/// session that ended cleared. htmx is told to go there with HX-Redirect,
+/// since a redirect would only load the page into the pane.
+pub fn sign_in_again(state: &AppState, request: Request) -> Result<(), ()> {
+    let login = format!("{}/login?expired=1", state.config.server.base_path);
+    let cookie = clear_session_cookie(&state.config.server, is_https(state, &request));
+    let response = html_response(state, String::new())
+        .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
+    let response = match header_value(&request, "HX-Request") {
+        Some(_) => response.with_header(Header::from_bytes(&b"HX-Redirect"[..], login.as_bytes()).unwrap()),
+        None => response
+            .with_status_code(303)
+            .with_header(Header::from_bytes(&b"Location"[..], login.as_bytes()).unwrap()),
+    };
+    respond(state, request, response).map_err(|_| ())
+}
@@ -0,0 +1,67 @@
+//! `mailto:` links (RFC 6068). Once the webmail is the browser's handler
+//! for them, following one opens `/compose?to=mailto:...` with the whole
+//! link as `to`; a link of the app's own can give the fields as parameters
+//! of their own instead.
+
+use super::params::{self, Params};
+
+/// What a link fills the compose form in with
+#[derive(Debug, Default, PartialEq)]
+pub struct Mailto {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+/// The fields of `/compose`'s query, from `to` when that is a `mailto:`
+/// link, or else from `to`, `cc`, `bcc`, `subject` and `body`
+pub fn from_query(query: &Params) -> Mailto {
+    if let Some(link) = query.get("to").and_then(parse) {
+        return link;
+    }
+    let all = |key| query.get_all(key).map(str::to_string).collect();
+    Mailto {
+        to: all("to"),
+        cc: all("cc"),
+        bcc: all("bcc"),
+        subject: query.value("subject").to_string(),
+        body: query.value("body").to_string(),
+    }
+}
+
+/// `link`'s addresses and header fields, if it is a `mailto:` link. Fields
+/// other than these, such as `In-Reply-To`, are left out.
+pub fn parse(link: &str) -> Option<Mailto> {
+    let scheme = link.get(..7)?;
+    if !scheme.eq_ignore_ascii_case("mailto:") {
+        return None;
+    }
+    let (path, query) = link[7..].split_once('?').unwrap_or((&link[7..], ""));
+    let mut mailto = Mailto {
+        to: addresses(path),
+        ..Mailto::default()
+    };
+    for field in query.split('&').filter(|f| !f.is_empty()) {
+        let (name, value) = field.split_once('=').unwrap_or((field, ""));
+        match name.to_ascii_lowercase().as_str() {
+            "to" => mailto.to.extend(addresses(value)),
+            "cc" => mailto.cc.extend(addresses(value)),
+            "bcc" => mailto.bcc.extend(addresses(value)),
+            "subject" => mailto.subject = params::decode_segment(value),
+            // Line breaks are given as %0D%0A
+            "body" => mailto.body = params::decode_segment(value).replace("\r\n", "\n"),
+            _ => {}
+        }
+    }
+    Some(mailto)
+}
+
+fn addresses(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|a| params::decode_segment(a).trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
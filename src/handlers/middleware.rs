@@ -0,0 +1,331 @@
+//! The steps every request goes through on its way to a handler.
+//!
+//! Each layer does its part and then passes the request on to the rest of
+//! the chain with `next`, or answers it itself and stops there: the access
+//! list refuses, a missing session redirects to the login page. `REQUEST`
+//! runs for every request and `SESSION` after it for the routes that need
+//! someone signed in (`API` for the JSON API's), so something new every
+//! request needs is one more layer in a list rather than a change to each
+//! handler. The security headers aren't a layer: `respond` adds them to
+//! whatever goes out.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tiny_http::Request;
+use uuid::Uuid;
+
+use super::{
+    account_path, api, client_addr, error, forbidden, has_csrf_token, header_value, is_background, is_other_user, redirect, redirect_to_login,
+    refresh_stale_session, serve_404, user_language, AppState,
+};
+use crate::compress;
+use crate::dates;
+use crate::i18n;
+use crate::jmap::JmapClient;
+use crate::log;
+use crate::oauth;
+use crate::session::parse_session_cookie;
+use crate::templates;
+use crate::theme;
+use crate::{log_debug, log_error, log_info, log_warn};
+
+/// A request on its way through, with what the layers so far have made of it
+pub struct Exchange {
+    pub method: String,
+    /// The URL as requested, for the logs
+    pub url: String,
+    /// What routes are matched against: the URL after the base path and
+    /// any account
+    pub path: String,
+    /// The account a URL under `/account/{id}` names, rather than the
+    /// session's default
+    pub account: Option<String>,
+    /// The client, looking past any trusted proxies
+    pub remote: Option<IpAddr>,
+    /// From the cookie, and from `require_session` on one still valid
+    pub session_id: Option<Uuid>,
+    /// For the session's account, once `open_client` has run
+    pub client: Option<JmapClient>,
+    /// The session came from an API token rather than the cookie, so what
+    /// refuses the request answers in JSON
+    pub by_token: bool,
+}
+
+impl Exchange {
+    pub fn new(state: &AppState, request: &Request) -> Self {
+        let url = request.url().to_string();
+        Exchange {
+            method: request.method().to_string(),
+            path: url.clone(),
+            url,
+            account: None,
+            remote: client_addr(state, request),
+            session_id: header_value(request, "Cookie")
+                .and_then(|cookie| parse_session_cookie(&state.config.server.cookie, &cookie)),
+            client: None,
+            by_token: false,
+        }
+    }
+}
+
+/// The rest of the chain, down to the handler
+pub type Next<'n> = &'n dyn Fn(&mut Exchange, Request) -> Result<(), ()>;
+pub type Layer = fn(&Arc<AppState>, &mut Exchange, Request, Next<'_>) -> Result<(), ()>;
+
+/// For every request, from the first thing done with it
+pub const REQUEST: &[Layer] = &[negotiate, log_exchange, check_access, strip_base_path, pick_account];
+/// For routes that need someone signed in, after `REQUEST`
+pub const SESSION: &[Layer] = &[require_session, check_csrf, refresh_session, end_rejected_session, open_client];
+/// For the JSON API's routes, after `REQUEST`. A token is never sent
+/// along by the browser on its own, so there is no CSRF check.
+pub const API: &[Layer] = &[require_token, refresh_session, end_rejected_session, open_client];
+
+/// Pass `request` through `layers` to `handler`
+pub fn run(
+    state: &Arc<AppState>,
+    layers: &[Layer],
+    exchange: &mut Exchange,
+    request: Request,
+    handler: Next<'_>,
+) -> Result<(), ()> {
+    match layers.split_first() {
+        Some((layer, rest)) => layer(state, exchange, request, &|exchange, request| {
+            run(state, rest, exchange, request, handler)
+        }),
+        None => handler(exchange, request),
+    }
+}
+
+/// Name the request for the logs, and settle what the response is written
+/// in: the encoding, the language, the zone dates are shown in and the theme
+fn negotiate(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    // A proxy in front may already have named the request
+    let request_id = header_value(&request, "X-Request-Id")
+        .filter(|id| id.len() <= 64 && id.chars().all(|c| c.is_ascii_graphic()))
+        .unwrap_or_else(|| Uuid::now_v7().to_string());
+    log::set_request_id(Some(request_id));
+    compress::negotiate(header_value(&request, "Accept-Encoding").as_deref());
+    i18n::negotiate(
+        header_value(&request, "Accept-Language").as_deref(),
+        &state.config.i18n.default_language,
+    );
+    dates::select(&state.config.i18n.timezone);
+    templates::mount(&state.config.server.base_path);
+    theme::note(header_value(&request, "Cookie").as_deref());
+    let result = next(exchange, request);
+    log::set_request_id(None);
+    result
+}
+
+/// Log the request and how long it took, and write the access log entry
+fn log_exchange(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    let start = Instant::now();
+    let (method, url) = (exchange.method.clone(), exchange.url.clone());
+    let remote_addr = exchange
+        .remote
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    log_info!(
+        "REQUEST {} {} from {}",
+        method,
+        url,
+        remote_addr
+    );
+    if let Some(ref sid) = exchange.session_id {
+        log_debug!("Session ID: {}", sid);
+    }
+
+    let access_entry = state.access_log.as_ref().map(|access_log| {
+        let user = exchange.session_id.and_then(|id| state.sessions.get(&id, |s| s.username.clone()));
+        access_log.start(&request, exchange.remote, user)
+    });
+
+    let response = next(exchange, request);
+
+    let elapsed = start.elapsed();
+    if let (Some(access_log), Some(entry)) = (&state.access_log, access_entry) {
+        access_log.finish(entry, elapsed);
+    }
+    match response {
+        Ok(()) => log_info!(
+            "RESPONSE {} {} completed in {:?}",
+            method,
+            url,
+            elapsed
+        ),
+        Err(()) => log_error!(
+            "RESPONSE {} {} failed after {:?}",
+            method,
+            url,
+            elapsed
+        ),
+    }
+    response
+}
+
+/// Refuse clients `[server.access]` doesn't let in
+fn check_access(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    if exchange.remote.is_some_and(|addr| !state.live().access.permits(addr)) {
+        let remote_addr = exchange.remote.map_or_else(|| "unknown".to_string(), |a| a.to_string());
+        log_warn!("Refused {} {} from {}: not permitted by [server.access]", exchange.method, exchange.url, remote_addr);
+        return forbidden(state, request);
+    }
+    next(exchange, request)
+}
+
+/// Behind a proxy mounting us under base_path, route on what follows it
+fn strip_base_path(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    let base_path = state.config.server.base_path.as_str();
+    match exchange.url.strip_prefix(base_path) {
+        Some(rest) if rest.starts_with('/') => {
+            exchange.path = rest.to_string();
+            next(exchange, request)
+        }
+        Some("") => redirect(state, request, &format!("{}/", base_path)),
+        _ => serve_404(state, request),
+    }
+}
+
+/// A URL under `/account/{id}` is for that account, whatever the session
+/// shows by default, so that each tab can show its own. Links on its pages
+/// stay under it.
+fn pick_account(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    let Some(rest) = exchange.path.strip_prefix("/account/") else {
+        return next(exchange, request);
+    };
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (id, rest) = rest.split_at(end);
+    // Ids are URL-safe (RFC 8620 section 1.2), so never need decoding
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return serve_404(state, request);
+    }
+    if !rest.starts_with('/') {
+        let url = format!("{}{}/{}", state.config.server.base_path, account_path(id), rest);
+        return redirect(state, request, &url);
+    }
+    templates::mount(&format!("{}{}", state.config.server.base_path, account_path(id)));
+    exchange.account = Some(id.to_string());
+    exchange.path = rest.to_string();
+    next(exchange, request)
+}
+
+/// Send anyone without a live session to the login page
+fn require_session(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    // Passthrough credentials for another user sign this session out
+    if let Some(id) = exchange.session_id.filter(|id| is_other_user(state, id, &request)) {
+        log_info!("Passed-through credentials changed user, ending session {}", id);
+        state.sessions.remove(&id);
+        exchange.session_id = None;
+    }
+    match exchange.session_id {
+        Some(id) if state.sessions.check(&id, !is_background(&exchange.path), exchange.remote) => {
+            next(exchange, request)
+        }
+        _ => {
+            exchange.session_id = None;
+            redirect_to_login(state, request)
+        }
+    }
+}
+
+/// Take the session from an `Authorization: Bearer` token, never the
+/// cookie, and refuse the request without a live one
+fn require_token(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    exchange.by_token = true;
+    exchange.session_id = header_value(&request, "Authorization")
+        .and_then(|value| api::bearer_token(&value))
+        .filter(|id| state.sessions.check(id, true, exchange.remote));
+    if exchange.session_id.is_none() {
+        return api::unauthorized(state, request);
+    }
+    next(exchange, request)
+}
+
+/// Anything that changes state must carry the session's token, which only
+/// pages rendered for this session know
+fn check_csrf(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    let Some(session_id) = exchange.session_id else {
+        return redirect_to_login(state, request);
+    };
+    if exchange.method != "GET" && !has_csrf_token(state, &session_id, &request) {
+        log_error!("Rejected {} {} without a valid CSRF token", exchange.method, exchange.path);
+        return forbidden(state, request);
+    }
+    next(exchange, request)
+}
+
+/// Bring the session up to date: an OAuth token about to expire, account
+/// details the server may have changed, and the language the user chose
+fn refresh_session(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    let Some(session_id) = exchange.session_id else {
+        return signed_out(state, exchange, request);
+    };
+    oauth::refresh_expiring_token(state, &session_id);
+    refresh_stale_session(state, &session_id);
+    if let Some(username) = state.sessions.get(&session_id, |s| s.username.clone()) {
+        user_language(state, &username);
+    }
+    next(exchange, request)
+}
+
+/// End a session once the JMAP server has turned its credentials down:
+/// before the request if an earlier one found out, or after it if this
+/// one did, so the next goes to the login page
+fn end_rejected_session(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    let Some(session_id) = exchange.session_id else {
+        return signed_out(state, exchange, request);
+    };
+    let rejected = || state.sessions.get(&session_id, |s| s.watch.is_rejected()).unwrap_or(false);
+    if rejected() {
+        end_session(state, &session_id);
+        exchange.session_id = None;
+        return if exchange.by_token {
+            api::unauthorized(state, request)
+        } else {
+            error::sign_in_again(state, request)
+        };
+    }
+    let result = next(exchange, request);
+    if rejected() {
+        end_session(state, &session_id);
+    }
+    result
+}
+
+fn end_session(state: &AppState, session_id: &Uuid) {
+    log_warn!("Ending session {}: the mail server no longer accepts its credentials", session_id);
+    state.sessions.remove(session_id);
+}
+
+/// A client for the account the URL names or else the session's, with
+/// whatever token `refresh_session` left it. The session can still have
+/// ended since it was checked, by a logout in another tab.
+fn open_client(state: &Arc<AppState>, exchange: &mut Exchange, request: Request, next: Next<'_>) -> Result<(), ()> {
+    let Some(client) = exchange.session_id.and_then(|id| {
+        state.sessions.get(&id, |s| match &exchange.account {
+            Some(account_id) => s.client_for(account_id).ok_or(()),
+            None => Ok(s.client()),
+        })
+    }) else {
+        log_error!("No client found for session: {:?}", exchange.session_id);
+        return signed_out(state, exchange, request);
+    };
+    let Ok(client) = client else {
+        log_warn!("Session {:?} has no account {:?}", exchange.session_id, exchange.account);
+        return serve_404(state, request);
+    };
+    exchange.client = Some(client);
+    next(exchange, request)
+}
+
+/// Answer a request whose session has gone: the login page for the
+/// browser, a 401 for the API
+pub fn signed_out(state: &AppState, exchange: &Exchange, request: Request) -> Result<(), ()> {
+    if exchange.by_token {
+        api::unauthorized(state, request)
+    } else {
+        redirect_to_login(state, request)
+    }
+}
@@ -1,191 +1,840 @@
-use std::sync::Arc;
-use std::time::Instant;
+mod api;
+mod error;
+mod mailto;
+mod middleware;
+mod multipart;
+mod params;
+mod range;
+mod router;
+#[cfg(test)]
+mod tests;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use tiny_http::{Header, Request, Response};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
-use crate::config::Config;
-use crate::jmap::JmapClient;
+use crate::access;
+use crate::access_log::{self, AccessLog};
+use crate::assets::{self, Asset};
+use crate::compress;
+use crate::bimi::{self, BimiCache};
+use crate::bounce::{self, Bounce};
+use crate::calendar;
+use crate::config::{Config, LiveSettings};
+use crate::contacts::{self, AddressBook};
+use crate::dates;
+use crate::discovery;
+use crate::export;
+use crate::i18n;
+use crate::ical;
+use crate::jmap::{
+    AuthScheme, BodyPart, CacheStore, ChangesResponse, Condition, Email, EmailAddress, EmailQueryResult, Filter, Identity, JmapClient, JmapError, JmapSession, MailAccount, Mailbox,
+    OutgoingAttachment, OutgoingEmail, Position, Search, Sort, SortProperty, VacationResponse, ALL_MAIL_ID,
+    FLAGGED_ID, PINNED_KEYWORD,
+};
+use crate::links;
+use crate::mute;
+use crate::log;
 use crate::session::{
-    clear_session_cookie, make_session_cookie, parse_session_cookie, Session, SessionStore,
+    clear_session_cookie, mail_cache, make_session_cookie, to_unix, Device, Session, SessionStore, ShownList,
 };
+use crate::oauth::{self, OAuthFlows, OAuthGrant};
+use crate::pdf;
+use crate::plugins::Plugins;
+use crate::prefs::{
+    Density, LastViewed, Posting, PrefsStore, ReplyPrefs, SavedSearch, TotpSecret, DEFAULT_LIST_HEIGHT, DEFAULT_QUOTE_PREFIX,
+    LIST_HEIGHTS, MAX_QUOTE_PREFIX,
+};
+use crate::push::{self, PushHub};
+use crate::pwa;
+use crate::retention;
+use crate::sanitize;
+use crate::secret::{random_token, tokens_match, Secret};
+use crate::snooze;
 use crate::templates;
-use crate::{log_debug, log_error, log_info};
+use crate::theme::{self, Theme};
+use crate::totp::{self, Attempt, PendingLogins};
+use crate::uploads::{self, Added, ChunkError, ChunkedUploads};
+use crate::zip;
+use crate::relay::RelayedPeers;
+use crate::{log_debug, log_error, log_info, log_warn};
+use error::AppError;
+use middleware::Exchange;
+use params::Params;
+use range::ByteRange;
+use router::{delete, get, post, Args, Body, Match, Route};
 
 pub struct AppState {
     pub config: Config,
     pub sessions: SessionStore,
+    pub bimi: BimiCache,
+    pub prefs: PrefsStore,
+    pub push: PushHub,
+    pub oauth: OAuthFlows,
+    /// Sign-ins waiting for their two-factor code
+    pub pending_logins: PendingLogins,
+    /// Extensions from a server embedding the webmail, to register before
+    /// the state is shared
+    pub plugins: Plugins,
+    pub relayed_peers: RelayedPeers,
+    /// Attachments coming in from the compose form in chunks
+    pub uploads: ChunkedUploads,
+    pub access_log: Option<AccessLog>,
+    /// Where sessions' mail caches are kept on disk, with `[cache] path`
+    pub cache_store: Option<Arc<dyn CacheStore>>,
+    /// What of `config` a SIGHUP has re-read (`src/reload.rs`)
+    pub live: RwLock<Arc<LiveSettings>>,
 }
 
 impl AppState {
-    pub fn new(config: Config) -> Self {
+    pub fn new(
+        config: Config,
+        prefs: PrefsStore,
+        sessions: SessionStore,
+        access_log: Option<AccessLog>,
+        cache_store: Option<Arc<dyn CacheStore>>,
+    ) -> Self {
         AppState {
+            live: RwLock::new(Arc::new(LiveSettings::from_config(&config))),
             config,
-            sessions: SessionStore::new(),
+            sessions,
+            bimi: BimiCache::new(),
+            prefs,
+            push: PushHub::new(),
+            oauth: OAuthFlows::new(),
+            pending_logins: PendingLogins::new(),
+            plugins: Plugins::new(),
+            relayed_peers: RelayedPeers::new(),
+            uploads: ChunkedUploads::new(),
+            access_log,
+            cache_store,
         }
     }
+
+    /// The reloadable settings as they are now
+    pub fn live(&self) -> Arc<LiveSettings> {
+        Arc::clone(&self.live.read().unwrap())
+    }
 }
 
 type BoxResponse = Response<std::io::Cursor<Vec<u8>>>;
 
 pub fn handle_request(state: &Arc<AppState>, request: Request) {
-    let start = Instant::now();
-    let path = request.url().to_string();
-    let method = request.method().to_string();
-    let remote_addr = request
-        .remote_addr()
-        .map(|a| a.to_string())
-        .unwrap_or_else(|| "unknown".to_string());
+    let mut exchange = Exchange::new(state, &request);
+    let _ = middleware::run(state, middleware::REQUEST, &mut exchange, request, &|exchange, request| {
+        route(state, exchange, request)
+    });
+}
 
-    log_info!(
-        "REQUEST {} {} from {}",
-        method,
-        path,
-        remote_addr
-    );
+fn route(state: &Arc<AppState>, exchange: &mut Exchange, request: Request) -> Result<(), ()> {
+    let path = exchange.path.clone();
+    // Static files
+    if let Some((asset, hashed)) = assets::find(&path) {
+        return serve_asset(state, asset, hashed, request);
+    }
+    let name = path.split_once('?').map_or(path.as_str(), |(p, _)| p);
+    if name == format!("/static/{}", assets::CUSTOM_CSS) {
+        if let Some(file) = custom_css(state) {
+            return serve_static_file(state, &file, request);
+        }
+    }
+    if let Some(dir) = &state.config.server.static_dir {
+        // Browsers look for the icon at the root by themselves
+        let name = if name == "/favicon.ico" { Some("favicon.ico") } else { name.strip_prefix("/static/") };
+        if let Some(file) = name.and_then(|name| assets::resolve(Path::new(dir), name)) {
+            return serve_static_file(state, &file, request);
+        }
+    }
 
-    // Extract session if present
-    let session_id = request
-        .headers()
-        .iter()
-        .find(|h| h.field.as_str().to_ascii_lowercase() == "cookie")
-        .and_then(|h| parse_session_cookie(h.value.as_str()));
+    let (handler, body, args) = match router::find(ROUTES, &exchange.method, &path) {
+        Match::Found(handler, body, args) => (handler, body, args),
+        Match::WrongMethod(allowed) => return method_not_allowed(state, &allowed, request),
+        Match::NotFound => return serve_404(state, request),
+    };
+    if let Some(refused) = refuse_body(state, body, &request) {
+        log_warn!("Refusing {} {}: {}", exchange.method, exchange.url, refused);
+        if path.starts_with("/api/") {
+            return api::error(state, request, refused.status(), &refused.to_string());
+        }
+        return refused.respond(state, request);
+    }
+    let (layers, handler) = match *handler {
+        Handler::Public(handler) => return handler(state, &args, request),
+        Handler::SignedIn(handler) => (middleware::SESSION, handler),
+        Handler::Api(handler) => (middleware::API, handler),
+    };
+    middleware::run(state, layers, exchange, request, &|exchange, request| {
+        match (exchange.session_id, exchange.client.take()) {
+            (Some(session_id), Some(client)) => handler(state, &session_id, client, &args, request),
+            _ => middleware::signed_out(state, exchange, request),
+        }
+    })
+}
 
-    if let Some(ref sid) = session_id {
-        log_debug!("Session ID: {}", sid);
+/// Why a request's body won't be read, if it is larger than the route
+/// takes. tiny_http reads no further than the Content-Length, so a body
+/// small enough by it can be read whole; a chunked body has none, and could
+/// go on for ever.
+fn refuse_body(state: &AppState, body: Body, request: &Request) -> Option<AppError> {
+    let limits = &state.config.server.limits;
+    let (limit, what) = match body {
+        Body::Login => (limits.login_bytes(), "The form"),
+        Body::Form => (limits.form_bytes(), "The form"),
+        Body::Upload => (limits.upload_bytes(), "An upload, attachments and all,"),
+        Body::Streamed => return None,
+    };
+    match request.body_length() {
+        Some(length) if length as u64 > limit => Some(AppError::TooLarge(format!(
+            "{} can't be larger than {}",
+            what,
+            templates::format_size(limit)
+        ))),
+        Some(_) => None,
+        None if header_value(request, "Transfer-Encoding").is_some() => {
+            Some(AppError::BadRequest("A request body needs a Content-Length".to_string()))
+        }
+        None => None,
     }
+}
 
-    let response = route(state, &method, &path, session_id, request);
+type PublicHandler = fn(&Arc<AppState>, &Args, Request) -> Result<(), ()>;
+type SessionHandler = fn(&Arc<AppState>, &Uuid, JmapClient, &Args, Request) -> Result<(), ()>;
 
-    let elapsed = start.elapsed();
-    match response {
-        Ok(()) => log_info!(
-            "RESPONSE {} {} completed in {:?}",
-            method,
-            path,
-            elapsed
-        ),
-        Err(()) => log_error!(
-            "RESPONSE {} {} failed after {:?}",
-            method,
-            path,
-            elapsed
-        ),
+#[derive(Clone, Copy)]
+enum Handler {
+    /// Served without signing in
+    Public(PublicHandler),
+    /// Served only to a signed-in session, through `middleware::SESSION`,
+    /// with a client for its account
+    SignedIn(SessionHandler),
+    /// The JSON API's: like `SignedIn`, but for a session signed in with a
+    /// token from `POST /api/v1/token`, through `middleware::API`
+    Api(SessionHandler),
+}
+
+use Handler::{Api, Public, SignedIn};
+
+/// Every endpoint but the static files, which `route` looks up first
+static ROUTES: &[Route<Handler>] = &[
+    get("/login", Public(|state, args, request| {
+        // Nobody is asked with [jmap.credentials] or passthrough, so go
+        // where they sign in
+        if skips_login_page(state) {
+            return redirect(state, request, &format!("{}/", state.config.server.base_path));
+        }
+        // Where `error::sign_in_again` sends a session the server ended
+        let expired = args.query.get("expired").map(|_| i18n::t("login.expired"));
+        serve_login_page(state, request, expired)
+    })),
+    post("/login", Public(|state, _, request| {
+        if is_same_origin(&request) {
+            handle_login(state, request)
+        } else {
+            forbidden(state, request)
+        }
+    }))
+    .taking(Body::Login),
+    post("/login/code", Public(|state, _, request| {
+        if is_same_origin(&request) {
+            handle_code_login(state, request)
+        } else {
+            forbidden(state, request)
+        }
+    }))
+    .taking(Body::Login),
+    // For installing it as an app; the worker must be at the top to cover it all
+    get("/manifest.webmanifest", Public(|state, _, request| {
+        let manifest = pwa::manifest(&state.config.branding, &state.config.server.base_path);
+        serve_generated(state, manifest, "application/manifest+json", request)
+    })),
+    get("/sw.js", Public(|state, _, request| {
+        let worker = pwa::service_worker(&state.config.server.base_path);
+        serve_generated(state, worker, "application/javascript", request)
+    })),
+    // Sign-in through an OAuth provider
+    get("/oauth/callback", Public(|state, args, request| handle_oauth_callback(state, &args.query, request))),
+    get("/oauth/{provider}/start", Public(|state, args, request| {
+        handle_oauth_start(state, args.param("provider"), request)
+    })),
+    get("/", SignedIn(|state, session_id, client, _, request| serve_main_page(state, session_id, client, request))),
+    post("/logout", SignedIn(|state, session_id, _, _, request| handle_logout(state, session_id, request))),
+    post("/account", SignedIn(|state, session_id, _, _, request| handle_switch_account(state, session_id, request))),
+    post("/theme", SignedIn(|state, _, _, _, request| handle_theme(state, request))),
+    post("/language", SignedIn(|state, session_id, client, _, request| handle_language(state, session_id, client, request))),
+    // Where links in messages go first; only signed-in users see those, so
+    // nobody else gets a click-through page on our origin
+    get("/redirect", SignedIn(|state, _, _, args, request| serve_link_page(state, args.query.get("url"), request))),
+    get("/mailboxes", SignedIn(|state, session_id, client, args, request| {
+        handle_mailboxes(state, session_id, client, args.query.get("selected"), request)
+    })),
+    get("/mailboxes/unread", SignedIn(|state, session_id, client, _, request| {
+        handle_unread_counts(state, session_id, client, request)
+    })),
+    get("/unread-count", SignedIn(|state, _, client, args, request| {
+        handle_unread_total(state, client, args.query.get("scope") == Some("all"), request)
+    })),
+    get("/mailboxes/poll", SignedIn(|state, session_id, client, args, request| {
+        handle_mailbox_poll(state, session_id, client, args.query.value("since"), request)
+    })),
+    get("/events", SignedIn(|state, session_id, client, _, request| serve_events(state, session_id, client, request))),
+    // Where a click on a mailbox leaves the address bar
+    get("/mailbox/{id}", SignedIn(|state, session_id, client, args, request| {
+        let opened = templates::Opened {
+            list: Some(format!("/mailbox/{}/emails", templates::url_encode(args.param("id")))),
+            view: None,
+            mailbox: Some(args.param("id").to_string()),
+        };
+        serve_opened_page(state, session_id, &client, &opened, request)
+    })),
+    get("/mailbox/{id}/emails", SignedIn(|state, session_id, client, args, request| {
+        let page = Page::from_params(&args.query);
+        handle_emails(state, session_id, client, args.param("id"), page, request)
+    })),
+    post("/mailbox/{id}/empty", SignedIn(|state, _, client, args, request| {
+        handle_empty_mailbox(state, client, args.param("id"), request)
+    })),
+    get("/mailbox/{id}/export", SignedIn(|state, _, client, args, request| {
+        handle_mailbox_export(state, client, args.param("id"), &args.query, request)
+    })),
+    get("/search", SignedIn(|state, _, client, args, request| serve_search_form(state, client, &args.query, request))),
+    get("/search/results", SignedIn(|state, session_id, client, args, request| {
+        handle_search(state, session_id, client, &args.query, request)
+    })),
+    get("/saved-searches", SignedIn(|state, session_id, _, _, request| {
+        serve_saved_searches(state, session_id, request)
+    })),
+    post("/saved-searches", SignedIn(|state, session_id, _, _, request| {
+        handle_save_search(state, session_id, request)
+    })),
+    get("/saved-search/{id}/emails", SignedIn(|state, session_id, client, args, request| {
+        let page = Page::from_params(&args.query);
+        handle_saved_search_emails(state, session_id, client, args.param("id"), page, request)
+    })),
+    post("/saved-search/{id}/delete", SignedIn(|state, session_id, _, args, request| {
+        handle_delete_saved_search(state, session_id, args.param("id"), request)
+    })),
+    get("/settings", SignedIn(|state, session_id, client, _, request| {
+        serve_settings_index(state, session_id, client, request)
+    })),
+    get("/settings/mailboxes", SignedIn(|state, _, client, _, request| serve_mailbox_settings(state, client, request))),
+    post("/mailboxes/create", SignedIn(|state, session_id, client, _, request| {
+        handle_mailbox_change(state, session_id, client, MailboxChange::Create, request)
+    })),
+    post("/mailboxes/import", SignedIn(|state, session_id, client, _, request| {
+        handle_import(state, session_id, client, request)
+    }))
+    .taking(Body::Upload),
+    post("/mailboxes/{id}/rename", SignedIn(|state, session_id, client, args, request| {
+        let change = MailboxChange::Rename(args.param("id").to_string());
+        handle_mailbox_change(state, session_id, client, change, request)
+    })),
+    post("/mailboxes/{id}/delete", SignedIn(|state, session_id, client, args, request| {
+        let change = MailboxChange::Delete(args.param("id").to_string());
+        handle_mailbox_change(state, session_id, client, change, request)
+    })),
+    post("/mailboxes/{id}/collapse", SignedIn(|state, session_id, client, args, request| {
+        handle_mailbox_collapse(state, session_id, client, args.param("id"), true, request)
+    })),
+    post("/mailboxes/{id}/expand", SignedIn(|state, session_id, client, args, request| {
+        handle_mailbox_collapse(state, session_id, client, args.param("id"), false, request)
+    })),
+    get("/compose", SignedIn(|state, session_id, client, args, request| {
+        // A mailto: link followed in the browser opens the app around it
+        if is_page_load(&request) {
+            let opened = templates::Opened {
+                view: Some(args.url.to_string()),
+                ..templates::Opened::default()
+            };
+            return serve_opened_page(state, session_id, &client, &opened, request);
+        }
+        serve_compose(state, session_id, client, &mailto::from_query(&args.query), request)
+    })),
+    get("/contacts/suggest", SignedIn(|state, session_id, client, args, request| {
+        serve_contact_suggestions(state, session_id, client, &args.query, request)
+    })),
+    get("/compose/recipients", SignedIn(|state, session_id, client, args, request| {
+        serve_recipient_chips(state, session_id, client, &args.query, request)
+    })),
+    post("/compose", SignedIn(|state, session_id, client, _, request| {
+        handle_compose(state, session_id, client, false, request)
+    }))
+    .taking(Body::Upload),
+    post("/compose/draft", SignedIn(|state, session_id, client, _, request| {
+        handle_compose(state, session_id, client, true, request)
+    }))
+    .taking(Body::Upload),
+    post("/compose/attachments", SignedIn(|state, session_id, client, _, request| {
+        handle_attachment_upload(state, session_id, client, request)
+    }))
+    .taking(Body::Streamed),
+    post("/compose/uploads", SignedIn(|state, session_id, client, _, request| {
+        handle_upload_start(state, session_id, client, request)
+    })),
+    get("/compose/uploads/{id}", SignedIn(|state, session_id, _, args, request| {
+        serve_upload_progress(state, session_id, args.param("id"), request)
+    })),
+    post("/compose/uploads/{id}", SignedIn(|state, session_id, client, args, request| {
+        handle_upload_chunk(state, session_id, client, args.param("id").to_string(), args.query.get_as("offset"), request)
+    }))
+    .taking(Body::Streamed),
+    get("/settings/retention", SignedIn(|state, session_id, _, _, request| {
+        serve_retention_settings(state, session_id, request)
+    })),
+    post("/settings/retention", SignedIn(|state, session_id, _, _, request| {
+        handle_retention_toggle(state, session_id, request)
+    })),
+    post("/settings/retention/run", SignedIn(|state, session_id, client, _, request| {
+        handle_retention_run(state, session_id, client, request)
+    })),
+    get("/outbox", SignedIn(|state, session_id, client, _, request| serve_outbox(state, session_id, client, request))),
+    get("/calendar", SignedIn(|state, session_id, client, _, request| {
+        serve_calendar(state, session_id, client, request)
+    })),
+    get("/settings/vacation", SignedIn(|state, session_id, client, _, request| {
+        serve_vacation_settings(state, session_id, client, request)
+    })),
+    post("/settings/vacation", SignedIn(|state, session_id, client, _, request| {
+        handle_vacation_update(state, session_id, client, request)
+    })),
+    get("/settings/timezone", SignedIn(|state, session_id, _, _, request| {
+        serve_timezone_settings(state, session_id, request)
+    })),
+    post("/settings/timezone", SignedIn(|state, session_id, _, _, request| {
+        handle_timezone_update(state, session_id, request)
+    })),
+    get("/settings/replies", SignedIn(|state, session_id, _, _, request| {
+        serve_reply_settings(state, session_id, request)
+    })),
+    post("/settings/replies", SignedIn(|state, session_id, _, _, request| {
+        handle_reply_settings_update(state, session_id, request)
+    })),
+    get("/settings/display", SignedIn(|state, session_id, _, _, request| {
+        serve_display_settings(state, session_id, request)
+    })),
+    post("/settings/display", SignedIn(|state, session_id, client, _, request| {
+        handle_display_update(state, session_id, client, request)
+    })),
+    get("/settings/two-factor", SignedIn(|state, session_id, _, _, request| {
+        serve_totp_settings(state, session_id, None, None, request)
+    })),
+    post("/settings/two-factor/enable", SignedIn(|state, session_id, _, _, request| {
+        handle_totp_change(state, session_id, true, request)
+    })),
+    post("/settings/two-factor/disable", SignedIn(|state, session_id, _, _, request| {
+        handle_totp_change(state, session_id, false, request)
+    })),
+    get("/settings/sessions", SignedIn(|state, session_id, _, _, request| {
+        serve_session_settings(state, session_id, None, request)
+    })),
+    post("/settings/sessions/revoke-others", SignedIn(|state, session_id, _, _, request| {
+        handle_revoke_other_sessions(state, session_id, request)
+    })),
+    post("/settings/sessions/{id}/revoke", SignedIn(|state, session_id, _, args, request| {
+        handle_session_revoke(state, session_id, args.param("id"), request)
+    })),
+    get("/settings/muted", SignedIn(|state, _, client, _, request| {
+        serve_muted_settings(state, client, request)
+    })),
+    get("/settings/signatures", SignedIn(|state, session_id, client, _, request| {
+        serve_signature_settings(state, session_id, client, request)
+    })),
+    post("/settings/signatures", SignedIn(|state, session_id, client, _, request| {
+        handle_signatures_update(state, session_id, client, request)
+    })),
+    get("/settings/filters", SignedIn(|state, session_id, client, _, request| {
+        handle_sieve(state, session_id, client, SieveAction::List, request)
+    })),
+    get("/settings/filters/new", SignedIn(|state, session_id, client, _, request| {
+        handle_sieve(state, session_id, client, SieveAction::Edit(None), request)
+    })),
+    post("/settings/filters/save", SignedIn(|state, session_id, client, _, request| {
+        handle_sieve(state, session_id, client, SieveAction::Save, request)
+    })),
+    post("/settings/filters/deactivate", SignedIn(|state, session_id, client, _, request| {
+        handle_sieve(state, session_id, client, SieveAction::Activate(None), request)
+    })),
+    post("/settings/filters/{id}/activate", SignedIn(|state, session_id, client, args, request| {
+        let action = SieveAction::Activate(Some(args.param("id").to_string()));
+        handle_sieve(state, session_id, client, action, request)
+    })),
+    post("/settings/filters/{id}/delete", SignedIn(|state, session_id, client, args, request| {
+        let action = SieveAction::Delete(args.param("id").to_string());
+        handle_sieve(state, session_id, client, action, request)
+    })),
+    get("/settings/filters/{id}", SignedIn(|state, session_id, client, args, request| {
+        let action = SieveAction::Edit(Some(args.param("id").to_string()));
+        handle_sieve(state, session_id, client, action, request)
+    })),
+    get("/blob/{id}", SignedIn(|state, _, client, args, request| {
+        let preview = args.query.get("preview") == Some("1");
+        handle_blob_download(state, client, args.param("id"), args.query.get("email"), preview, request)
+    })),
+    get("/bimi/{domain}", SignedIn(|state, _, _, args, request| serve_bimi_logo(state, args.param("domain"), request))),
+    get("/email/{id}/raw", SignedIn(|state, _, client, args, request| {
+        handle_email_raw(state, client, args.param("id"), request)
+    })),
+    get("/email/{id}/reply", SignedIn(|state, session_id, client, args, request| {
+        serve_reply(state, session_id, client, args.param("id"), ReplyAction::Reply, request)
+    })),
+    get("/email/{id}/reply-all", SignedIn(|state, session_id, client, args, request| {
+        serve_reply(state, session_id, client, args.param("id"), ReplyAction::ReplyAll, request)
+    })),
+    get("/email/{id}/forward", SignedIn(|state, session_id, client, args, request| {
+        serve_reply(state, session_id, client, args.param("id"), ReplyAction::Forward, request)
+    })),
+    get("/email/{id}/resend", SignedIn(|state, session_id, client, args, request| {
+        serve_reply(state, session_id, client, args.param("id"), ReplyAction::Resend, request)
+    })),
+    get("/email/{id}/headers", SignedIn(|state, _, client, args, request| {
+        handle_email_headers(state, client, args.param("id"), request)
+    })),
+    get("/email/{id}/eml", SignedIn(|state, _, client, args, request| {
+        handle_email_eml(state, client, args.param("id"), request)
+    })),
+    get("/email/{id}/attachments.zip", SignedIn(|state, _, client, args, request| {
+        handle_attachments_zip(state, client, args.param("id"), request)
+    })),
+    get("/email/{id}/pdf", SignedIn(|state, _, client, args, request| {
+        handle_email_pdf(state, client, args.param("id"), request)
+    })),
+    post("/email/{id}/rsvp", SignedIn(|state, session_id, client, args, request| {
+        handle_rsvp(state, session_id, client, args.param("id"), request)
+    })),
+    post("/email/{id}/pin", SignedIn(|state, _, client, args, request| {
+        handle_pin(state, client, args.param("id"), true, request)
+    })),
+    post("/email/{id}/unpin", SignedIn(|state, _, client, args, request| {
+        handle_pin(state, client, args.param("id"), false, request)
+    })),
+    post("/email/{id}/seen", SignedIn(|state, _, client, args, request| {
+        handle_set_seen(state, client, args.param("id"), true, request)
+    })),
+    post("/email/{id}/unseen", SignedIn(|state, _, client, args, request| {
+        handle_set_seen(state, client, args.param("id"), false, request)
+    })),
+    post("/email/{id}/flag", SignedIn(|state, _, client, args, request| {
+        handle_set_flagged(state, client, args.param("id"), true, request)
+    })),
+    post("/email/{id}/unflag", SignedIn(|state, _, client, args, request| {
+        handle_set_flagged(state, client, args.param("id"), false, request)
+    })),
+    post("/email/{id}/delete", SignedIn(|state, _, client, args, request| {
+        let confirmed = args.query.get("confirm") == Some("yes");
+        handle_delete(state, client, args.param("id"), confirmed, request)
+    })),
+    post("/emails/bulk", SignedIn(|state, _, client, _, request| handle_bulk(state, client, request))),
+    post("/email/{id}/archive", SignedIn(|state, _, client, args, request| {
+        handle_archive(state, client, args.param("id"), request)
+    })),
+    post("/email/{id}/snooze", SignedIn(|state, _, client, args, request| {
+        handle_snooze(state, client, args.param("id"), request)
+    })),
+    post("/email/{id}/spam", SignedIn(|state, _, client, args, request| {
+        handle_junk(state, client, args.param("id"), true, request)
+    })),
+    post("/email/{id}/not-spam", SignedIn(|state, _, client, args, request| {
+        handle_junk(state, client, args.param("id"), false, request)
+    })),
+    post("/email/{id}/plugin/{plugin}/{action}", SignedIn(|state, _, client, args, request| {
+        handle_plugin_action(state, client, args.param("id"), args.param("plugin"), args.param("action"), request)
+    })),
+    post("/email/{id}/move", SignedIn(|state, _, client, args, request| {
+        handle_move(state, client, args.param("id"), request)
+    })),
+    get("/thread/{id}", SignedIn(|state, session_id, client, args, request| {
+        let focus = args.query.value("focus");
+        if is_page_load(&request) {
+            return serve_message_page(state, session_id, client, focus, args.url, request);
+        }
+        if !focus.is_empty() {
+            remember_viewed(state, session_id, &client, Viewed::Email(focus));
+        }
+        let options = templates::ViewOptions {
+            no_sending: !account_has(state, session_id, &client, |a| a.has_submission),
+            reply_all_first: reply_prefs(state, session_id).reply_all,
+            plugins: Some(&state.plugins),
+            ..templates::ViewOptions::default()
+        };
+        handle_thread(state, client, args.param("id"), focus, &options, request)
+    })),
+    get("/thread/{id}/search", SignedIn(|state, _, client, args, request| {
+        handle_thread_search(state, client, args.param("id"), args.query.value("q"), request)
+    })),
+    post("/thread/{id}/mute", SignedIn(|state, _, client, args, request| {
+        handle_mute(state, client, args.param("id"), true, request)
+    })),
+    post("/thread/{id}/unmute", SignedIn(|state, _, client, args, request| {
+        handle_mute(state, client, args.param("id"), false, request)
+    })),
+    get("/email/{id}/part/{part}", SignedIn(|state, _, client, args, request| {
+        handle_email_part(state, client, args.param("id"), args.param("part"), request)
+    })),
+    get("/email/{id}", SignedIn(|state, session_id, client, args, request| {
+        let email_id = args.param("id");
+        if is_page_load(&request) {
+            return serve_message_page(state, session_id, client, email_id, args.url, request);
+        }
+        let options = templates::ViewOptions {
+            prefer_text: args.query.get("view") == Some("text"),
+            remote_images: args.query.get("images") == Some("remote"),
+            no_sending: !account_has(state, session_id, &client, |a| a.has_submission),
+            reply_all_first: reply_prefs(state, session_id).reply_all,
+            plugins: Some(&state.plugins),
+        };
+        handle_email(state, session_id, client, email_id, &options, request)
+    })),
+    // The JSON API, in api.rs
+    get("/api/openapi.json", Public(|state, _, request| api::openapi(state, request))),
+    post("/api/v1/token", Public(|state, _, request| api::create_token(state, request))).taking(Body::Login),
+    delete("/api/v1/token", Api(|state, session_id, _, _, request| api::revoke_token(state, session_id, request))),
+    get("/api/v1/mailboxes", Api(|state, _, client, _, request| api::mailboxes(state, client, request))),
+    get("/api/v1/mailboxes/{id}/emails", Api(|state, _, client, args, request| {
+        api::mailbox_emails(state, client, args.param("id"), &args.query, request)
+    })),
+    get("/api/v1/search", Api(|state, _, client, args, request| api::search(state, client, &args.query, request))),
+    get("/api/v1/emails/{id}", Api(|state, _, client, args, request| {
+        api::email(state, client, args.param("id"), request)
+    })),
+    post("/api/v1/emails/{id}/{action}", Api(|state, _, client, args, request| {
+        api::act(state, client, args.param("id"), args.param("action"), &args.query, request)
+    })),
+];
+
+/// Requests the page makes by itself to stay up to date, which shouldn't
+/// keep an idle session alive
+fn is_background(path: &str) -> bool {
+    path == "/events"
+        || path == "/mailboxes/unread"
+        || path.starts_with("/mailboxes/poll?")
+        || Params::from_url(path).get("refresh") == Some("1")
+}
+
+/// Send `response` along with the security headers every response carries
+fn respond<R: Read>(state: &AppState, request: Request, response: Response<R>) -> std::io::Result<()> {
+    access_log::note_response(response.status_code().0, response.data_length());
+    request.respond(with_security_headers(state, response))
+}
+
+/// Send a response whose body is read as it goes out, such as a blob
+/// streamed from the JMAP server, on a thread of its own so a large file
+/// or a slow client doesn't hold up the requests behind it
+fn respond_streaming<R: Read + Send + 'static>(state: &AppState, request: Request, response: Response<R>) -> Result<(), ()> {
+    access_log::note_response(response.status_code().0, response.data_length());
+    let response = with_security_headers(state, response);
+    let request_id = log::request_id();
+    thread::spawn(move || {
+        log::set_request_id(request_id);
+        if let Err(e) = request.respond(response) {
+            log_error!("Failed to stream response: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// `response` with the security headers, and the request id so a problem
+/// seen in the browser can be found in the logs. A header the response
+/// already sets for itself, such as a blob's sandbox, is left as it is.
+fn with_security_headers<R: Read>(state: &AppState, mut response: Response<R>) -> Response<R> {
+    if let Some(id) = log::request_id() {
+        response.add_header(Header::from_bytes(&b"X-Request-Id"[..], id.as_bytes()).unwrap());
     }
+    let live = state.live();
+    let headers = &live.headers;
+    for (name, value) in [
+        ("X-Content-Type-Options", "nosniff"),
+        ("Content-Security-Policy", headers.content_security_policy.as_str()),
+        ("X-Frame-Options", headers.frame_options.as_str()),
+        ("Referrer-Policy", headers.referrer_policy.as_str()),
+    ] {
+        let own = response.headers().iter().any(|h| h.field.equiv(name));
+        if !value.is_empty() && !own {
+            response.add_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+        }
+    }
+    response
 }
 
-fn route(
-    state: &Arc<AppState>,
-    method: &str,
-    path: &str,
-    session_id: Option<Uuid>,
-    request: Request,
-) -> Result<(), ()> {
-    // Static files
-    if path == "/static/htmx.min.js" {
-        return serve_htmx(request);
+fn html_response(state: &AppState, mut html: String) -> BoxResponse {
+    // Only whole pages have a head to load the operator's styles in
+    if html.contains("</head>") && custom_css(state).is_some() {
+        html = templates::with_stylesheet(&html, &format!("/static/{}", assets::CUSTOM_CSS));
     }
+    body_response(state, html.into_bytes(), "text/html; charset=utf-8")
+}
 
-    // Login page and submission (no auth required)
-    if path == "/login" {
-        return match method {
-            "GET" => serve_login_page(request, None),
-            "POST" => handle_login(state, request),
-            _ => serve_404(request),
-        };
+/// The operator's stylesheet: `server.custom_css`, or else a custom.css in
+/// `server.static_dir`. Looked for on each use, so one can be added or
+/// removed without a restart.
+fn custom_css(state: &AppState) -> Option<PathBuf> {
+    let server = &state.config.server;
+    match (&server.custom_css, &server.static_dir) {
+        (Some(path), _) => Some(PathBuf::from(path)).filter(|p| p.is_file()),
+        (None, Some(dir)) => assets::resolve(Path::new(dir), assets::CUSTOM_CSS),
+        (None, None) => None,
+    }
+}
+
+fn json_response(state: &AppState, body: String) -> BoxResponse {
+    body_response(state, body.into_bytes(), "application/json; charset=utf-8")
+}
+
+/// A response with `body`, compressed when the client accepts that
+fn body_response(state: &AppState, body: Vec<u8>, content_type: &str) -> BoxResponse {
+    let compression = &state.config.server.compression;
+    let (body, encoding) = compress::encode(compression, body);
+    let len = body.len();
+    let mut response = Response::from_data(body)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap())
+        .with_header(Header::from_bytes(&b"Content-Length"[..], len.to_string()).unwrap());
+    if let Some(encoding) = encoding {
+        response.add_header(Header::from_bytes(&b"Content-Encoding"[..], encoding.as_bytes()).unwrap());
     }
+    // Caches mustn't hand a compressed copy to a client that can't read it
+    if compression.enabled {
+        response.add_header(Header::from_bytes(&b"Vary"[..], &b"Accept-Encoding"[..]).unwrap());
+    }
+    response
+}
 
-    // Check auth for all other routes
-    let session_id = match session_id {
-        Some(id) if state.sessions.exists(&id) => id,
-        _ => return redirect_to_login(request),
+/// A response read from `reader` as it is sent instead of held in memory,
+/// chunked when the `length` isn't known up front
+fn stream_response<R: Read>(reader: R, length: Option<usize>, content_type: &str) -> Response<R> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .unwrap_or_else(|_| Header::from_bytes(&b"Content-Type"[..], &b"application/octet-stream"[..]).unwrap());
+    Response::new(200.into(), vec![content_type], reader, length, None)
+}
+
+/// One of the built-in scripts. At its hashed URL it can never change, so
+/// the browser keeps it; at the plain one it asks again each time, and gets
+/// a 304 if it already has this build's.
+fn serve_asset(state: &AppState, asset: &Asset, hashed: bool, request: Request) -> Result<(), ()> {
+    let etag = asset.etag();
+    let response = if has_etag(&request, &etag) {
+        Response::from_data(Vec::new()).with_status_code(304)
+    } else {
+        body_response(state, asset.body.as_bytes().to_vec(), asset.content_type)
     };
+    let cache_control = if hashed { "public, max-age=31536000, immutable" } else { "no-cache" };
+    let response = response
+        .with_header(Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap())
+        .with_header(Header::from_bytes(&b"Cache-Control"[..], cache_control.as_bytes()).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
 
-    // Authenticated routes
-    match (method, path) {
-        ("GET", "/") => serve_main_page(state, &session_id, request),
-        ("POST", "/logout") => handle_logout(state, &session_id, request),
-        ("GET", "/mailboxes") => handle_mailboxes(state, &session_id, request),
-        ("GET", p) if p.starts_with("/mailbox/") && (p.contains("/emails?") || p.ends_with("/emails")) => {
-            // Parse path and query string
-            let (path_part, query_string) = if let Some(idx) = p.find('?') {
-                (&p[..idx], Some(&p[idx + 1..]))
-            } else {
-                (p, None)
-            };
-            let mailbox_id = path_part
-                .strip_prefix("/mailbox/")
-                .and_then(|s| s.strip_suffix("/emails"))
-                .unwrap_or("");
-            let offset = query_string
-                .and_then(|qs| parse_query_param(qs, "offset"))
-                .and_then(|v| v.parse::<u32>().ok())
-                .unwrap_or(0);
-            handle_emails(state, &session_id, mailbox_id, offset, request)
-        }
-        ("GET", p) if p.starts_with("/email/") && p.ends_with("/raw") => {
-            let email_id = p
-                .strip_prefix("/email/")
-                .and_then(|s| s.strip_suffix("/raw"))
-                .unwrap_or("");
-            handle_email_raw(state, &session_id, email_id, request)
-        }
-        ("GET", p) if p.starts_with("/email/") => {
-            let email_id = p.strip_prefix("/email/").unwrap_or("");
-            handle_email(state, &session_id, email_id, request)
-        }
-        _ => serve_404(request),
-    }
-}
-
-fn html_response(body: String) -> BoxResponse {
-    let bytes = body.into_bytes();
-    let len = bytes.len();
-    Response::from_data(bytes)
-        .with_header(
-            Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap(),
-        )
-        .with_header(Header::from_bytes(&b"Content-Length"[..], len.to_string()).unwrap())
+/// A file made from the configuration and the build, checked with the
+/// server on each use like an asset asked for by its plain URL
+fn serve_generated(state: &AppState, body: String, content_type: &str, request: Request) -> Result<(), ()> {
+    let response = body_response(state, body.into_bytes(), content_type)
+        .with_header(Header::from_bytes(&b"Cache-Control"[..], &b"no-cache"[..]).unwrap());
+    respond(state, request, response).map_err(|_| ())
 }
 
-fn json_response(body: String) -> BoxResponse {
-    let bytes = body.into_bytes();
-    let len = bytes.len();
-    Response::from_data(bytes)
-        .with_header(
-            Header::from_bytes(&b"Content-Type"[..], &b"application/json; charset=utf-8"[..])
-                .unwrap(),
-        )
-        .with_header(Header::from_bytes(&b"Content-Length"[..], len.to_string()).unwrap())
+/// Whether the browser's If-None-Match says it already has `etag`
+fn has_etag(request: &Request, etag: &str) -> bool {
+    header_value(request, "If-None-Match").is_some_and(|tags| {
+        tags.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag.trim_start_matches("W/"))
+    })
 }
 
-fn text_response(body: String) -> BoxResponse {
-    let bytes = body.into_bytes();
-    let len = bytes.len();
-    Response::from_data(bytes)
-        .with_header(
-            Header::from_bytes(&b"Content-Type"[..], &b"text/plain; charset=utf-8"[..]).unwrap(),
-        )
-        .with_header(Header::from_bytes(&b"Content-Length"[..], len.to_string()).unwrap())
+/// A file from `server.static_dir`, streamed from disk. It may be replaced
+/// at any time, so the browser checks back with an ETag from its size and
+/// modification time.
+fn serve_static_file(state: &AppState, path: &Path, request: Request) -> Result<(), ()> {
+    let opened = fs::File::open(path).and_then(|file| Ok((file.metadata()?, file)));
+    let (metadata, file) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            log_error!("Failed to open {}: {}", path.display(), e);
+            return serve_404(state, request);
+        }
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    let etag = format!("W/\"{:x}-{:x}\"", metadata.len(), modified);
+    let etag_header = Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap();
+    let cache_control = Header::from_bytes(&b"Cache-Control"[..], &b"no-cache"[..]).unwrap();
+    if has_etag(&request, &etag) {
+        let response = Response::from_data(Vec::new())
+            .with_status_code(304)
+            .with_header(etag_header)
+            .with_header(cache_control);
+        return respond(state, request, response).map_err(|_| ());
+    }
+    let response = stream_response(file, Some(metadata.len() as usize), assets::content_type(path))
+        .with_header(etag_header)
+        .with_header(cache_control);
+    respond_streaming(state, request, response)
 }
 
-fn serve_htmx(request: Request) -> Result<(), ()> {
-    let htmx_js = include_str!("../../static/htmx.min.js");
-    let bytes = htmx_js.as_bytes().to_vec();
-    let len = bytes.len();
-    let response = Response::from_data(bytes)
-        .with_header(
-            Header::from_bytes(&b"Content-Type"[..], &b"application/javascript"[..]).unwrap(),
-        )
-        .with_header(Header::from_bytes(&b"Content-Length"[..], len.to_string()).unwrap());
-    request.respond(response).map_err(|_| ())
+fn login_html(state: &AppState, error: Option<&str>) -> String {
+    let providers: Vec<(&str, &str)> = state
+        .config
+        .oauth
+        .iter()
+        .map(|(id, provider)| (id.as_str(), provider.name.as_str()))
+        .collect();
+    let jmap = &state.config.jmap;
+    let servers: Vec<&str> = jmap.servers.iter().map(|s| s.name.as_str()).collect();
+    templates::login_page(
+        &state.config.branding,
+        error,
+        &servers,
+        jmap.autodiscover,
+        &providers,
+        state.config.sessions.remember_days.is_some(),
+    )
+}
+
+/// The interstitial for a link in a message: where it really goes, with
+/// its trackers taken off, and no Referer for that site to learn ours from
+fn serve_link_page(state: &AppState, url: Option<&str>, request: Request) -> Result<(), ()> {
+    let cleaned = links::strip_trackers(url.unwrap_or(""));
+    let Some(host) = links::destination_host(&cleaned.url) else {
+        return AppError::BadRequest("Only web links can be followed from here".to_string()).respond(state, request);
+    };
+    let html = templates::link_page(&state.config.branding, &cleaned.url, host, &cleaned.removed);
+    let response = html_response(state, html)
+        .with_header(Header::from_bytes(&b"Referrer-Policy"[..], &b"no-referrer"[..]).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+fn serve_login_page(state: &AppState, request: Request, error: Option<&str>) -> Result<(), ()> {
+    let html = login_html(state, error);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
 }
 
-fn serve_login_page(request: Request, error: Option<&str>) -> Result<(), ()> {
-    let html = templates::login_page(error);
-    request.respond(html_response(html)).map_err(|_| ())
+fn redirect(state: &AppState, request: Request, url: &str) -> Result<(), ()> {
+    let response = Response::empty(303).with_header(Header::from_bytes(&b"Location"[..], url.as_bytes()).unwrap());
+    respond(state, request, response).map_err(|_| ())
 }
 
-fn redirect_to_login(request: Request) -> Result<(), ()> {
+fn redirect_to_login(state: &AppState, request: Request) -> Result<(), ()> {
+    if let Some(credentials) = &state.config.jmap.credentials {
+        let password = credentials.password().to_string();
+        return sign_in_directly(state, credentials.username.clone(), password, credentials.server.as_deref(), request);
+    }
+    if state.config.server.passthrough.is_enabled() {
+        return match passed_credentials(state, &request) {
+            Some((username, password)) => sign_in_directly(state, username, password.to_string(), None, request),
+            None => ask_for_credentials(state, request),
+        };
+    }
     // For htmx requests, return the login page directly
     // For regular requests, do a redirect
     let is_htmx = request
@@ -194,402 +843,4498 @@ fn redirect_to_login(request: Request) -> Result<(), ()> {
         .any(|h| h.field.as_str().to_ascii_lowercase() == "hx-request");
 
     if is_htmx {
-        let html = templates::login_page(None);
-        request.respond(html_response(html)).map_err(|_| ())
+        let html = login_html(state, None);
+        respond(state, request, html_response(state, html)).map_err(|_| ())
     } else {
-        let response = Response::empty(303)
-            .with_header(Header::from_bytes(&b"Location"[..], &b"/login"[..]).unwrap());
-        request.respond(response).map_err(|_| ())
+        redirect(state, request, &format!("{}/login", state.config.server.base_path))
     }
 }
 
-fn serve_404(request: Request) -> Result<(), ()> {
-    let response = Response::from_string("Not Found").with_status_code(404);
-    request.respond(response).map_err(|_| ())
+/// Whether nobody is shown the login page: signed in from
+/// `[jmap.credentials]` or whatever `[server.passthrough]` brings
+fn skips_login_page(state: &AppState) -> bool {
+    state.config.jmap.credentials.is_some() || state.config.server.passthrough.is_enabled()
 }
 
-fn handle_login(state: &Arc<AppState>, mut request: Request) -> Result<(), ()> {
-    log_debug!("Processing login request");
-
-    // Parse form body
-    let mut body = String::new();
-    if request.as_reader().read_to_string(&mut body).is_err() {
-        log_error!("Failed to read login request body");
-        return serve_login_page(request, Some("Failed to read request"));
+/// Sign the browser in with credentials it didn't type into the login
+/// page, and send it back with the new session's cookie: htmx to reload the
+/// page, any other GET to the same address and the rest to the main page.
+/// If the server turns them down, the login page says why, or with
+/// passthrough the browser is asked again.
+fn sign_in_directly(
+    state: &AppState,
+    username: String,
+    password: String,
+    server: Option<&str>,
+    request: Request,
+) -> Result<(), ()> {
+    let passthrough = state.config.jmap.credentials.is_none();
+    let mut session = match sign_in(state, username, password, server) {
+        Ok(session) => session,
+        Err(_) if passthrough => return ask_for_credentials(state, request),
+        Err(SignInError::UnknownServer) => {
+            return serve_login_page(state, request, Some("Unknown mail server in [jmap.credentials]"));
+        }
+        Err(SignInError::Failed(e)) => return serve_login_page(state, request, Some(&format!("Login failed: {}", e))),
+    };
+    session.device = device(state, &request);
+    let session_id = state.sessions.create(session);
+    if passthrough {
+        log_info!("Signed session {} in with passed-through credentials", session_id);
+    } else {
+        log_info!("Signed session {} in with the configured credentials", session_id);
     }
+    let cookie = make_session_cookie(&state.config.server, &session_id, None, is_https(state, &request));
+    let response = html_response(state, String::new())
+        .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
+    let response = if header_value(&request, "HX-Request").is_some() {
+        response.with_header(Header::from_bytes(&b"HX-Refresh"[..], &b"true"[..]).unwrap())
+    } else {
+        let back_to = match request.method() {
+            tiny_http::Method::Get => request.url().to_string(),
+            _ => format!("{}/", state.config.server.base_path),
+        };
+        response
+            .with_status_code(303)
+            .with_header(Header::from_bytes(&b"Location"[..], back_to.as_bytes()).unwrap())
+    };
+    respond(state, request, response).map_err(|_| ())
+}
 
-    let mut username = None;
-    let mut password = None;
+/// The username and password `[server.passthrough]` takes from the
+/// request: a trusted proxy's headers, then the browser's Basic auth
+fn passed_credentials(state: &AppState, request: &Request) -> Option<(String, Zeroizing<String>)> {
+    let passthrough = &state.config.server.passthrough;
+    if let (Some(user_header), Some(password_header)) = (&passthrough.user_header, &passthrough.password_header) {
+        // Anyone could send these headers, so only a proxy's count
+        if from_trusted_proxy(state, request) {
+            if let (Some(username), Some(password)) =
+                (header_value(request, user_header), header_value(request, password_header))
+            {
+                if !username.is_empty() {
+                    return Some((username, Zeroizing::new(password)));
+                }
+            }
+        }
+    }
+    if passthrough.basic {
+        return header_value(request, "Authorization").and_then(|value| basic_credentials(&value));
+    }
+    None
+}
 
-    for pair in body.split('&') {
-        let mut parts = pair.splitn(2, '=');
-        let key = parts.next().unwrap_or("");
-        let value = parts
-            .next()
-            .map(|v| urlencoding_decode(v))
-            .unwrap_or_default();
+/// The username and password of an `Authorization: Basic` value
+fn basic_credentials(value: &str) -> Option<(String, Zeroizing<String>)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = Zeroizing::new(STANDARD.decode(encoded.trim()).ok()?);
+    let decoded = Zeroizing::new(String::from_utf8(decoded.to_vec()).ok()?);
+    let (username, password) = decoded.split_once(':')?;
+    if username.is_empty() {
+        return None;
+    }
+    Some((username.to_string(), Zeroizing::new(password.to_string())))
+}
 
-        match key {
-            "username" => username = Some(value),
-            "password" => password = Some(value),
-            _ => {}
-        }
+/// Answer 401 for passthrough without usable credentials, asking the
+/// browser for Basic auth if that's what it takes
+fn ask_for_credentials(state: &AppState, request: Request) -> Result<(), ()> {
+    let mut response = Response::from_string(i18n::t("login.credentials_required")).with_status_code(401);
+    if state.config.server.passthrough.basic {
+        let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", state.config.branding.name.replace('"', ""));
+        response = response.with_header(Header::from_bytes(&b"WWW-Authenticate"[..], challenge.as_bytes()).unwrap());
     }
+    respond(state, request, response).map_err(|_| ())
+}
 
-    let (username, password) = match (username, password) {
-        (Some(u), Some(p)) if !u.is_empty() && !p.is_empty() => (u, p),
-        _ => {
-            log_error!("Login attempt with missing username or password");
-            let html = templates::login_page(Some("Username and password required"));
-            return request.respond(html_response(html)).map_err(|_| ());
-        }
+/// Whether the request's passed-through credentials are for someone other
+/// than `session_id`'s user, whose session they then replace
+fn is_other_user(state: &AppState, session_id: &Uuid, request: &Request) -> bool {
+    if state.config.jmap.credentials.is_some() || !state.config.server.passthrough.is_enabled() {
+        return false;
+    }
+    let Some((username, _)) = passed_credentials(state, request) else {
+        return false;
     };
+    state
+        .sessions
+        .get(session_id, |s| s.username != username)
+        .unwrap_or(false)
+}
 
-    log_info!("Login attempt for user: {}", username);
+/// The client's address, looking past any trusted proxies
+fn client_addr(state: &AppState, request: &Request) -> Option<IpAddr> {
+    let forwarded_for = header_value(request, "X-Forwarded-For");
+    let trusted = &state.config.server.trusted_proxies;
+    match peer_addr(state, request) {
+        Some(peer) => Some(access::forwarded_client(peer.ip(), forwarded_for.as_deref(), trusted)),
+        None => access::socket_client(forwarded_for.as_deref(), trusted),
+    }
+}
 
-    // Try to authenticate with JMAP server
-    match JmapClient::discover(&state.config.jmap.well_known_url, &username, &password) {
-        Ok((_session, client)) => {
-            log_info!(
-                "Login successful for user: {}, account_id: {}",
-                username,
-                client.account_id()
-            );
+/// The other end of the connection, looking through our own TLS relay.
+/// On the Unix socket there is no address to give.
+fn peer_addr(state: &AppState, request: &Request) -> Option<SocketAddr> {
+    let peer = *request.remote_addr()?;
+    Some(state.relayed_peers.client(peer).unwrap_or(peer))
+}
 
-            let session = Session {
-                username: username.clone(),
-                password,
-                api_url: client.api_url().to_string(),
-                account_id: client.account_id().to_string(),
-                download_url: client.download_url().map(|s| s.to_string()),
-            };
+/// Whether the browser reached us over HTTPS: always with `[server.tls]`,
+/// and otherwise only if a trusted proxy in front says so. Whatever is on
+/// the other end of the Unix socket counts as one.
+fn is_https(state: &AppState, request: &Request) -> bool {
+    if state.config.server.tls.is_some() {
+        return true;
+    }
+    from_trusted_proxy(state, request) && header_value(request, "X-Forwarded-Proto").is_some_and(|proto| proto.eq_ignore_ascii_case("https"))
+}
 
-            let session_id = state.sessions.create(session);
-            log_debug!("Created session: {}", session_id);
-            let cookie = make_session_cookie(&session_id);
+/// Whether the request comes from one of `trusted_proxies`, or the Unix
+/// socket, where whatever is on the other end counts as one
+fn from_trusted_proxy(state: &AppState, request: &Request) -> bool {
+    match peer_addr(state, request) {
+        Some(peer) => access::is_trusted(peer.ip(), &state.config.server.trusted_proxies),
+        None => true,
+    }
+}
 
-            let html = templates::main_page(&username);
-            let response = html_response(html)
-                .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
+fn has_csrf_token(state: &AppState, session_id: &Uuid, request: &Request) -> bool {
+    let Some(given) = header_value(request, "X-CSRF-Token") else {
+        return false;
+    };
+    state
+        .sessions
+        .get(session_id, |s| tokens_match(&given, &s.csrf_token))
+        .unwrap_or(false)
+}
 
-            request.respond(response).map_err(|_| ())
-        }
-        Err(e) => {
-            log_error!("Login failed for user {}: {}", username, e);
-            let error_msg = format!("Login failed: {}", e);
-            let html = templates::login_page(Some(&error_msg));
-            request.respond(html_response(html)).map_err(|_| ())
-        }
+/// Whether a request without a session yet came from one of our own pages,
+/// going by the headers browsers add. Requests without them, which no
+/// browser sends today, aren't from a page another site could have made.
+fn is_same_origin(request: &Request) -> bool {
+    if let Some(site) = header_value(request, "Sec-Fetch-Site") {
+        return site == "same-origin" || site == "none";
+    }
+    match (header_value(request, "Origin"), header_value(request, "Host")) {
+        (Some(origin), Some(host)) => origin.split_once("://").map(|(_, rest)| rest) == Some(host.as_str()),
+        _ => true,
     }
 }
 
-fn handle_logout(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
-    log_info!("User logging out, session: {}", session_id);
-    state.sessions.remove(session_id);
-    let cookie = clear_session_cookie();
-    let html = templates::login_page(None);
-    let response = html_response(html)
-        .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
-    request.respond(response).map_err(|_| ())
+fn forbidden(state: &AppState, request: Request) -> Result<(), ()> {
+    let response = Response::from_string("Forbidden").with_status_code(403);
+    respond(state, request, response).map_err(|_| ())
 }
 
-fn serve_main_page(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
-    let username = state
-        .sessions
-        .get(session_id, |s| s.username.clone())
-        .unwrap_or_default();
+/// A path we serve, asked for with a method it doesn't take
+fn method_not_allowed(state: &AppState, allowed: &[&str], request: Request) -> Result<(), ()> {
+    let response = Response::from_string("Method Not Allowed")
+        .with_status_code(405)
+        .with_header(Header::from_bytes(&b"Allow"[..], allowed.join(", ").as_bytes()).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
 
-    let html = templates::main_page(&username);
-    request.respond(html_response(html)).map_err(|_| ())
+fn serve_404(state: &AppState, request: Request) -> Result<(), ()> {
+    let response = Response::from_string("Not Found").with_status_code(404);
+    respond(state, request, response).map_err(|_| ())
 }
 
-fn handle_mailboxes(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
-    log_debug!("Fetching mailboxes for session: {}", session_id);
+fn handle_login(state: &Arc<AppState>, mut request: Request) -> Result<(), ()> {
+    log_debug!("Processing login request");
 
-    let client = match get_client(state, session_id) {
-        Some(c) => c,
-        None => {
-            log_error!("No client found for session: {}", session_id);
-            return redirect_to_login(request);
+    // Parse form body, which holds the password, so wipe it afterwards
+    let mut body = Zeroizing::new(String::new());
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read login request body");
+        return serve_login_page(state, request, Some("Failed to read request"));
+    }
+
+    let form = Params::parse(&body);
+    let (username, password) = match (form.get("username"), form.get("password")) {
+        (Some(u), Some(p)) if !u.is_empty() && !p.is_empty() => (u.to_string(), p.to_string()),
+        _ => {
+            log_error!("Login attempt with missing username or password");
+            let html = login_html(state, Some("Username and password required"));
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
         }
     };
 
-    match client.get_mailboxes() {
-        Ok(mailboxes) => {
-            log_info!(
-                "Fetched {} mailboxes for session {}",
-                mailboxes.len(),
-                session_id
-            );
-            for mb in &mailboxes {
-                log_debug!(
-                    "  Mailbox: {} (id={}, total={}, unread={})",
-                    mb.name,
-                    mb.id,
-                    mb.total_emails,
-                    mb.unread_emails
-                );
-            }
-            let html = templates::mailbox_list(&mailboxes);
-            request.respond(html_response(html)).map_err(|_| ())
-        }
-        Err(e) => {
-            log_error!("Failed to fetch mailboxes: {}", e);
-            let html = templates::error_fragment(&format!("Failed to load mailboxes: {}", e));
-            request.respond(html_response(html)).map_err(|_| ())
+    let mut session = match sign_in(state, username, password, form.get("server")) {
+        Ok(session) => session,
+        Err(SignInError::UnknownServer) => return serve_login_page(state, request, Some("Unknown mail server")),
+        Err(SignInError::Failed(e)) => {
+            let error_msg = format!("Login failed: {}", e);
+            let html = login_html(state, Some(&error_msg));
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
         }
+    };
+    let remember_age = state.config.sessions.remember_age().filter(|_| form.get("remember") == Some("1"));
+    session.remember = remember_age.is_some();
+    session.device = device(state, &request);
+
+    if needs_code(state, &session.username) {
+        log_info!("Asking user {} for a two-factor code", session.username);
+        let token = state.pending_logins.start(session, remember_age);
+        return serve_code_page(state, &token, None, request);
     }
+    finish_login(state, session, remember_age, request)
 }
 
-const EMAILS_PER_PAGE: u32 = 50;
-
-fn handle_emails(
+/// Make `session` a session the browser holds, and show the main page
+fn finish_login(
     state: &Arc<AppState>,
-    session_id: &Uuid,
-    mailbox_id: &str,
-    offset: u32,
+    session: Session,
+    remember_age: Option<Duration>,
     request: Request,
 ) -> Result<(), ()> {
-    let mailbox_id_decoded = urlencoding_decode(mailbox_id);
-    log_info!(
-        "Fetching emails for mailbox: {} (decoded: {}, offset: {})",
-        mailbox_id,
-        mailbox_id_decoded,
-        offset
+    // Switches to the user's language and time zone for the page
+    user_language(state, &session.username);
+    let opened = last_viewed(state, &session.client(), &session.username, &session.account_id);
+    let html = templates::main_page(
+        &state.config.branding,
+        &session,
+        &session.account_id,
+        &state.prefs.get(&session.username),
+        &opened,
+        &state.plugins.sidebar_links(&session.username),
     );
+    let session_id = state.sessions.create(session);
+    log_debug!("Created session: {}", session_id);
+    let cookie = make_session_cookie(&state.config.server, &session_id, remember_age, is_https(state, &request));
 
-    let client = match get_client(state, session_id) {
-        Some(c) => c,
-        None => {
-            log_error!("No client found for session: {}", session_id);
-            return redirect_to_login(request);
-        }
-    };
+    let response = html_response(state, html)
+        .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
 
-    log_debug!("Querying email IDs for mailbox: {}", mailbox_id_decoded);
+    respond(state, request, response).map_err(|_| ())
+}
 
-    match client.query_emails(&mailbox_id_decoded, EMAILS_PER_PAGE, offset) {
-        Ok(query_result) => {
-            log_info!(
-                "Email/query returned {} email IDs for mailbox {} (total: {:?})",
-                query_result.ids.len(),
-                mailbox_id_decoded,
-                query_result.total
-            );
+/// Whether `username` has to give a code from their authenticator app
+/// before a sign-in becomes a session
+fn needs_code(state: &AppState, username: &str) -> bool {
+    state.config.totp.enabled && state.prefs.get(username).totp.is_some()
+}
 
-            if query_result.ids.is_empty() {
-                log_debug!("No emails in mailbox, returning empty list");
-                let html = templates::email_list(&[], mailbox_id, None);
-                return request.respond(html_response(html)).map_err(|_| ());
-            }
+/// Take `code` as `username`'s second factor, using up its time step.
+/// Wrong codes count against the user however they were given.
+fn check_code(state: &AppState, username: &str, code: &str) -> bool {
+    state.pending_logins.check_limited(username, || take_code(state, username, code))
+}
 
-            log_debug!("Email IDs returned: {:?}", query_result.ids);
-            log_debug!("Fetching email details for {} emails...", query_result.ids.len());
+fn take_code(state: &AppState, username: &str, code: &str) -> bool {
+    let Some(secret) = state.prefs.get(username).totp else {
+        return false;
+    };
+    let Some(step) = totp::verify(&secret.secret, code, secret.last_step) else {
+        log_warn!("Wrong two-factor code for user {}", username);
+        return false;
+    };
+    let saved = state.prefs.update(username, |prefs| {
+        if let Some(secret) = &mut prefs.totp {
+            secret.last_step = step;
+        }
+    });
+    if let Err(e) = saved {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+    true
+}
 
-            match client.get_emails(&query_result.ids) {
-                Ok(emails) => {
-                    log_info!(
-                        "Email/get returned {} emails (requested {})",
-                        emails.len(),
-                        query_result.ids.len()
-                    );
-
-                    if emails.len() != query_result.ids.len() {
-                        log_error!(
-                            "MISMATCH: Requested {} email IDs but got {} emails back!",
-                            query_result.ids.len(),
-                            emails.len()
-                        );
-                        log_debug!("Requested IDs: {:?}", query_result.ids);
-                        let returned_ids: Vec<_> = emails.iter().map(|e| &e.id).collect();
-                        log_debug!("Returned IDs: {:?}", returned_ids);
-
-                        // Find missing IDs
-                        let returned_set: std::collections::HashSet<_> =
-                            emails.iter().map(|e| e.id.as_str()).collect();
-                        let missing: Vec<_> = query_result.ids
-                            .iter()
-                            .filter(|id| !returned_set.contains(id.as_str()))
-                            .collect();
-                        log_error!("Missing email IDs: {:?}", missing);
-                    }
+fn serve_code_page(state: &AppState, token: &str, error: Option<&str>, request: Request) -> Result<(), ()> {
+    let html = templates::code_page(&state.config.branding, token, error);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
 
-                    // Sort emails by receivedAt descending (newest first)
-                    let mut emails = emails;
-                    emails.sort_by(|a, b| {
-                        let a_date = a.received_at.as_deref().unwrap_or("");
-                        let b_date = b.received_at.as_deref().unwrap_or("");
-                        b_date.cmp(a_date) // Reverse order for newest first
-                    });
-
-                    // Calculate pagination info
-                    let next_offset = if let Some(total) = query_result.total {
-                        let next = offset + emails.len() as u32;
-                        if next < total {
-                            Some(next)
-                        } else {
-                            None
-                        }
-                    } else {
-                        // If total is unknown but we got a full page, assume there might be more
-                        if emails.len() as u32 == EMAILS_PER_PAGE {
-                            Some(offset + EMAILS_PER_PAGE)
-                        } else {
-                            None
-                        }
-                    };
-
-                    // Use rows-only template for pagination (offset > 0)
-                    let html = if offset > 0 {
-                        templates::email_list_rows(&emails, mailbox_id, next_offset)
-                    } else {
-                        templates::email_list(&emails, mailbox_id, next_offset)
-                    };
-                    request.respond(html_response(html)).map_err(|_| ())
-                }
-                Err(e) => {
-                    log_error!("Failed to fetch email details: {}", e);
-                    let html = templates::error_fragment(&format!("Failed to load emails: {}", e));
-                    request.respond(html_response(html)).map_err(|_| ())
-                }
-            }
-        }
-        Err(e) => {
-            log_error!("Failed to query emails for mailbox {}: {}", mailbox_id_decoded, e);
-            let html = templates::error_fragment(&format!("Failed to query emails: {}", e));
-            request.respond(html_response(html)).map_err(|_| ())
-        }
+/// The code for a sign-in held back by `handle_login`, which goes ahead if
+/// it is right
+fn handle_code_login(state: &Arc<AppState>, mut request: Request) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read two-factor code body");
+        return serve_login_page(state, request, Some("Failed to read request"));
+    }
+    let form = Params::parse(&body);
+    let token = form.value("token");
+    let attempt = state
+        .pending_logins
+        .attempt(token, |session| check_code(state, &session.username, form.value("code")));
+    match attempt {
+        Attempt::Passed(session, remember_age) => finish_login(state, *session, remember_age, request),
+        Attempt::Failed => serve_code_page(state, token, Some(i18n::t("totp.wrong")), request),
+        Attempt::Gone => serve_login_page(state, request, Some(i18n::t("totp.expired"))),
     }
 }
 
-fn handle_email(
-    state: &Arc<AppState>,
-    session_id: &Uuid,
-    email_id: &str,
-    request: Request,
-) -> Result<(), ()> {
-    let email_id_decoded = urlencoding_decode(email_id);
-    log_info!(
-        "Fetching single email: {} (decoded: {})",
-        email_id,
-        email_id_decoded
-    );
+/// Why `sign_in` gave no session
+enum SignInError {
+    /// The form named a server that isn't configured
+    UnknownServer,
+    Failed(JmapError),
+}
+
+/// Sign in with a password at the server the login form picked: an index
+/// into `[[jmap.servers]]`, or `auto` to find it from the address. The
+/// login page only offers a choice when there is more than one, and no
+/// choice means the default.
+fn sign_in(state: &AppState, username: String, password: String, server: Option<&str>) -> Result<Session, SignInError> {
+    let jmap = &state.config.jmap;
+    let server = match server {
+        Some("auto") if jmap.autodiscover => None,
+        Some(index) => match index.parse::<usize>().ok().and_then(|i| jmap.servers.get(i)) {
+            Some(server) => Some(server),
+            None => {
+                log_error!("Login attempt for unknown server {}", index);
+                return Err(SignInError::UnknownServer);
+            }
+        },
+        None => jmap.default_server(),
+    };
 
-    let client = match get_client(state, session_id) {
-        Some(c) => c,
+    // Try to authenticate with JMAP server
+    let discovered = match server {
+        Some(server) => {
+            log_info!("Login attempt for user: {} on {}", username, server.name);
+            JmapClient::discover(&server.well_known_url, &username, &password, jmap.auth.schemes(), jmap.http_options())
+                .map(|(jmap_session, client)| (server.well_known_url.clone(), jmap_session, client))
+        }
         None => {
-            log_error!("No client found for session: {}", session_id);
-            return redirect_to_login(request);
+            log_info!("Login attempt for user: {} on an autodiscovered server", username);
+            discovery::discover(&username, &password, jmap.auth.schemes(), jmap)
         }
     };
-
-    match client.get_email(&email_id_decoded) {
-        Ok(Some(email)) => {
+    match discovered {
+        Ok((well_known_url, jmap_session, client)) => {
             log_info!(
-                "Fetched email {} - \"{}\"",
-                email.id,
-                email.subject.as_deref().unwrap_or("(no subject)")
+                "Login successful for user: {}, account_id: {}",
+                username,
+                client.account_id()
             );
-            let html = templates::email_view(&email);
-            request.respond(html_response(html)).map_err(|_| ())
+            Ok(new_session(state, username, password, &well_known_url, &jmap_session, &client))
+        }
+        Err(e) => {
+            log_error!("Login failed for user {}: {}", username, e);
+            Err(SignInError::Failed(e))
+        }
+    }
+}
+
+/// A session for a user the JMAP server has just accepted
+fn new_session(
+    state: &AppState,
+    username: String,
+    password: String,
+    well_known_url: &str,
+    jmap_session: &JmapSession,
+    client: &JmapClient,
+) -> Session {
+    let allowance = state.sessions.allowance(&username);
+    Session {
+        accounts: mail_accounts(jmap_session, client, &username),
+        cache: mail_cache(&state.config.jmap, &state.cache_store, well_known_url, &username, &allowance),
+        username,
+        credential: Secret::new(password),
+        auth_scheme: client.auth_scheme(),
+        oauth: None,
+        well_known_url: well_known_url.to_string(),
+        api_url: client.api_url(),
+        account_id: client.account_id().to_string(),
+        download_url: client.download_url().map(|s| s.to_string()),
+        upload_url: client.upload_url().map(|s| s.to_string()),
+        event_source_url: jmap_session.event_source_url.clone(),
+        websocket: jmap_session.websocket().filter(|_| state.config.jmap.websocket),
+        retention_enabled: state.config.retention.enabled_by_default,
+        purge_log: Vec::new(),
+        shown_list: None,
+        mailbox_state: HashMap::new(),
+        contacts: HashMap::new(),
+        transport: client.transport().clone().with_call_limit(allowance.calls.clone()),
+        limits: client.limits(),
+        allowance,
+        watch: client.watch().clone(),
+        created: Instant::now(),
+        csrf_token: random_token(),
+        remember: false,
+        last_active: Instant::now(),
+        device: Device::default(),
+    }
+}
+
+/// As much of a User-Agent header as is kept with a session
+const MAX_USER_AGENT: usize = 256;
+
+/// What a session is being signed in from, by the request doing it
+fn device(state: &AppState, request: &Request) -> Device {
+    Device {
+        user_agent: header_value(request, "User-Agent").map(|agent| agent.chars().take(MAX_USER_AGENT).collect()),
+        address: client_addr(state, request),
+        api: false,
+    }
+}
+
+/// Send the browser to the provider's sign-in page
+fn handle_oauth_start(state: &AppState, id: &str, request: Request) -> Result<(), ()> {
+    let Some(provider) = state.config.oauth.get(id) else {
+        return serve_404(state, request);
+    };
+    log_info!("Starting OAuth sign-in with {}", id);
+    let url = state.oauth.start(id, provider);
+    redirect(state, request, &url)
+}
+
+/// Where the provider sends the browser back to, with a code to exchange
+/// for an access token that the JMAP server accepts in place of a password
+fn handle_oauth_callback(state: &AppState, params: &Params, request: Request) -> Result<(), ()> {
+    if let Some(error) = params.get("error") {
+        log_error!("OAuth sign-in was refused: {}", error);
+        let message = format!("Sign-in was refused: {}", params.get("error_description").unwrap_or(error));
+        return serve_login_page(state, request, Some(&message));
+    }
+    let Some((id, verifier)) = state.oauth.finish(params.value("state")) else {
+        log_error!("OAuth callback with an unknown or expired state");
+        return serve_login_page(state, request, Some("Sign-in expired, please try again"));
+    };
+    let Some(provider) = state.config.oauth.get(&id) else {
+        return serve_login_page(state, request, Some("Sign-in provider is no longer configured"));
+    };
+
+    let jmap = &state.config.jmap;
+    let tokens = match oauth::exchange_code(provider, params.value("code"), &verifier, jmap.http_options()) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            log_error!("OAuth code exchange with {} failed: {}", id, e);
+            return serve_login_page(state, request, Some(&format!("Login failed: {}", e)));
+        }
+    };
+
+    // The token says who the user is, so the server's session object names
+    // them. Providers issue tokens for the default server.
+    let Some(server) = jmap.default_server() else {
+        log_error!("OAuth sign-in with {} but no JMAP server is configured", id);
+        return serve_login_page(state, request, Some("Sign-in needs a configured mail server"));
+    };
+    let well_known_url = &server.well_known_url;
+    let discovered = JmapClient::discover(well_known_url, "", &tokens.access_token, &[AuthScheme::Bearer], jmap.http_options());
+    let (jmap_session, client) = match discovered {
+        Ok(result) => result,
+        Err(e) => {
+            log_error!("Login with an access token from {} failed: {}", id, e);
+            return serve_login_page(state, request, Some(&format!("Login failed: {}", e)));
+        }
+    };
+    let username = jmap_session.username.clone();
+    log_info!("Login successful for user: {} via {}, account_id: {}", username, id, client.account_id());
+
+    let mut session = new_session(state, username, tokens.access_token.clone(), well_known_url, &jmap_session, &client);
+    session.oauth = Some(OAuthGrant {
+        provider: id,
+        expires_at: tokens.expires_at(),
+        refresh_token: tokens.refresh_token.map(Secret::new),
+    });
+    session.device = device(state, &request);
+    if needs_code(state, &session.username) {
+        log_info!("Asking user {} for a two-factor code", session.username);
+        let token = state.pending_logins.start(session, None);
+        return serve_code_page(state, &token, None, request);
+    }
+    let session_id = state.sessions.create(session);
+    log_debug!("Created session: {}", session_id);
+    let cookie = make_session_cookie(&state.config.server, &session_id, None, is_https(state, &request));
+
+    // Browsers leave SameSite=Strict cookies off a redirect that started on
+    // the provider's site, so the cookie would be missing from a redirect
+    // to "/". Loading it from this page counts as same-site.
+    let response = html_response(state, templates::redirect_page("/"))
+        .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+fn mail_accounts(jmap_session: &JmapSession, client: &JmapClient, username: &str) -> Vec<MailAccount> {
+    let mut accounts = jmap_session.mail_accounts();
+    if accounts.is_empty() {
+        // Servers that omit accountCapabilities still gave us one, and
+        // their session-wide capabilities say what it can do
+        let has = |capability: &str| jmap_session.capabilities.contains_key(capability);
+        accounts.push(MailAccount {
+            id: client.account_id().to_string(),
+            name: username.to_string(),
+            is_read_only: false,
+            has_sieve: has("urn:ietf:params:jmap:sieve"),
+            has_smime_verify: has("urn:ietf:params:jmap:smimeverify"),
+            has_calendars: has("urn:ietf:params:jmap:calendars"),
+            has_submission: has("urn:ietf:params:jmap:submission"),
+            has_vacation: has("urn:ietf:params:jmap:vacationresponse"),
+            has_quota: has("urn:ietf:params:jmap:quota"),
+        });
+    }
+    accounts
+}
+
+/// Fetch the JMAP session object again once the server has signalled that
+/// it changed, picking up a moved apiUrl or a different set of accounts
+/// without a new login. The account being shown is kept if it still
+/// exists. If the server can't be reached the session is left as it was,
+/// to try again on the next request.
+fn refresh_stale_session(state: &AppState, session_id: &Uuid) {
+    let credentials = state
+        .sessions
+        .get(session_id, |s| {
+            s.watch
+                .is_stale()
+                .then(|| (s.username.clone(), s.credential.reveal(), s.auth_scheme, s.well_known_url.clone()))
+        })
+        .flatten();
+    let Some((username, password, auth_scheme, well_known_url)) = credentials else {
+        return;
+    };
+
+    log_info!("Refreshing JMAP session for session {}", session_id);
+    let jmap = &state.config.jmap;
+    let discovered = JmapClient::discover(&well_known_url, &username, &password, &[auth_scheme], jmap.http_options());
+    let (jmap_session, client) = match discovered {
+        Ok(result) => result,
+        Err(e) => {
+            log_error!("Failed to refresh JMAP session for {}: {}", username, e);
+            if matches!(e, JmapError::Unauthorized) {
+                state.sessions.get(session_id, |s| s.watch.credentials_rejected());
+            }
+            return;
+        }
+    };
+
+    let accounts = mail_accounts(&jmap_session, &client, &username);
+    state.sessions.update(session_id, |s| {
+        if s.api_url != client.api_url() {
+            log_info!("API URL for {} moved from {} to {}", username, s.api_url, client.api_url());
+        }
+        if !accounts.iter().any(|a| a.id == s.account_id) {
+            s.account_id = client.account_id().to_string();
+        }
+        // What is kept for accounts no longer there goes with them
+        s.shown_list = s.shown_list.take().filter(|shown| accounts.iter().any(|a| a.id == shown.account_id));
+        s.mailbox_state.retain(|id, _| accounts.iter().any(|a| &a.id == id));
+        s.contacts.retain(|id, _| accounts.iter().any(|a| &a.id == id));
+        s.api_url = client.api_url();
+        s.accounts = accounts;
+        s.download_url = client.download_url().map(|s| s.to_string());
+        s.upload_url = client.upload_url().map(|s| s.to_string());
+        s.event_source_url = jmap_session.event_source_url.clone();
+        s.websocket = jmap_session.websocket().filter(|_| state.config.jmap.websocket);
+        s.limits = client.limits();
+        s.watch = client.watch().clone();
+    });
+}
+
+fn handle_logout(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
+    log_info!("User logging out, session: {}", session_id);
+    state.sessions.remove(session_id);
+    let cookie = clear_session_cookie(&state.config.server, is_https(state, &request));
+    // With [jmap.credentials] or passthrough there is no login page, only
+    // a fresh session
+    if skips_login_page(state) {
+        let response = html_response(state, String::new())
+            .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap())
+            .with_header(Header::from_bytes(&b"HX-Redirect"[..], format!("{}/", state.config.server.base_path).as_bytes()).unwrap());
+        return respond(state, request, response).map_err(|_| ());
+    }
+    let html = login_html(state, None);
+    let response = html_response(state, html)
+        .with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+/// The language `username` picked in the sidebar, if any, which also
+/// becomes the current request's along with their time zone
+fn user_language(state: &AppState, username: &str) -> Option<String> {
+    let prefs = state.prefs.get(username);
+    if let Some(timezone) = &prefs.timezone {
+        dates::select(timezone);
+    }
+    let language = prefs.language.filter(|l| i18n::is_known(l));
+    if let Some(language) = &language {
+        i18n::select(language);
+    }
+    language
+}
+
+/// Whether the account `client` is on has what `has` looks for among the
+/// capabilities its server advertised
+fn account_has(state: &AppState, session_id: &Uuid, client: &JmapClient, has: impl Fn(&MailAccount) -> bool) -> bool {
+    state
+        .sessions
+        .get(session_id, |s| s.accounts.iter().any(|a| a.id == client.account_id() && has(a)))
+        .unwrap_or(false)
+}
+
+/// Say that the server doesn't do a feature, with the message `key` naming
+/// it, rather than passing on the error asking it would bring
+fn unsupported(state: &AppState, request: Request, key: &'static str) -> Result<(), ()> {
+    log_info!("{} requested, which the server doesn't support", key);
+    let html = templates::error_fragment(i18n::t(key));
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// The settings pages, linked from the sidebar, and the account's storage.
+/// What the server supports is per account, so these follow the account
+/// switcher.
+fn serve_settings_index(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    let Some(account) = state
+        .sessions
+        .get(session_id, |s| s.accounts.iter().find(|a| a.id == client.account_id()).cloned())
+        .flatten()
+    else {
+        return redirect_to_login(state, request);
+    };
+    let quotas = if account.has_quota {
+        client.get_quotas().unwrap_or_else(|e| {
+            log_error!("Failed to fetch quotas: {}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let html = templates::settings_index(&account, &quotas, state.config.totp.enabled);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn serve_reply_settings(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let html = templates::reply_settings(&state.prefs.get(&username).replies, None);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Keep how replies are started. An empty quote prefix goes back to the
+/// default, and a long one is cut short.
+fn handle_reply_settings_update(state: &Arc<AppState>, session_id: &Uuid, mut request: Request) -> Result<(), ()> {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read reply settings body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let prefix: String = form.value("quote_prefix").chars().filter(|c| !c.is_control()).take(MAX_QUOTE_PREFIX).collect();
+    let replies = ReplyPrefs {
+        posting: Posting::parse(form.value("posting")).unwrap_or_default(),
+        include_original: form.get("include_original").is_some(),
+        quote_prefix: if prefix.trim().is_empty() { DEFAULT_QUOTE_PREFIX.to_string() } else { prefix },
+        reply_all: form.get("reply_all").is_some(),
+    };
+    if let Err(e) = state.prefs.update(&username, |prefs| prefs.replies = replies.clone()) {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+    let html = templates::reply_settings(&replies, Some(templates::Outcome::Notice(i18n::t("replies.saved"))));
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// How the user likes replies started
+fn reply_prefs(state: &AppState, session_id: &Uuid) -> ReplyPrefs {
+    state.sessions.get(session_id, |s| state.prefs.get(&s.username).replies).unwrap_or_default()
+}
+
+fn serve_timezone_settings(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let current = state.prefs.get(&username).timezone;
+    let html = templates::timezone_settings(current.as_deref(), &state.config.i18n.timezone, None);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Keep the time zone typed into the settings form, or go back to the
+/// server's with an empty one
+fn handle_timezone_update(state: &Arc<AppState>, session_id: &Uuid, mut request: Request) -> Result<(), ()> {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read time zone body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let timezone = Some(form.value("timezone").trim()).filter(|tz| !tz.is_empty());
+    let default = state.config.i18n.timezone.as_str();
+
+    let html = match timezone {
+        Some(tz) if dates::zone(tz).is_none() => {
+            let message = format!("Unknown time zone {}", tz);
+            templates::timezone_settings(Some(tz), default, Some(templates::Outcome::Error(&message)))
+        }
+        _ => {
+            if let Err(e) = state.prefs.update(&username, |prefs| prefs.timezone = timezone.map(str::to_string)) {
+                log_error!("Failed to persist preferences: {}", e);
+            }
+            dates::select(timezone.unwrap_or(default));
+            let notice = format!("Dates are now shown in {}", timezone.unwrap_or(default));
+            templates::timezone_settings(timezone, default, Some(templates::Outcome::Notice(&notice)))
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn serve_display_settings(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let html = templates::display_settings(&state.prefs.get(&username));
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Keep the list's density and height. They shape the whole page, so it
+/// is rendered again with the settings open in it.
+fn handle_display_update(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, mut request: Request) -> Result<(), ()> {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read display settings body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let density = Density::parse(form.value("density")).unwrap_or_default();
+    // The default is left unset, so that it follows if it ever changes
+    let list_height = form
+        .value("list_height")
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .map(|height| height.clamp(*LIST_HEIGHTS.start() as u32, *LIST_HEIGHTS.end() as u32) as u8)
+        .filter(|&height| height != DEFAULT_LIST_HEIGHT);
+    let saved = state.prefs.update(&username, |prefs| {
+        prefs.density = density;
+        prefs.list_height = list_height;
+    });
+    if let Err(e) = saved {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+    let opened = templates::Opened {
+        view: Some("/settings/display".to_string()),
+        ..templates::Opened::default()
+    };
+    serve_opened_page(state, session_id, &client, &opened, request)
+}
+
+/// Turning the second factor on, with `secret` (a fresh one without it)
+/// offered, or off if it is on
+fn serve_totp_settings(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    secret: Option<&str>,
+    outcome: Option<templates::Outcome>,
+    request: Request,
+) -> Result<(), ()> {
+    if !state.config.totp.enabled {
+        return serve_404(state, request);
+    }
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let html = if state.prefs.get(&username).totp.is_some() {
+        templates::totp_settings(None, outcome)
+    } else {
+        let secret = secret.map(str::to_string).unwrap_or_else(totp::new_secret);
+        let issuer = state.config.totp.issuer.as_deref().unwrap_or(&state.config.branding.name);
+        let uri = totp::provisioning_uri(issuer, &username, &secret);
+        templates::totp_settings(Some((&secret, &uri)), outcome)
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Turn the second factor on with the secret the setup offered, or off,
+/// either way only with a code that is right for it
+fn handle_totp_change(state: &Arc<AppState>, session_id: &Uuid, enable: bool, mut request: Request) -> Result<(), ()> {
+    if !state.config.totp.enabled {
+        return serve_404(state, request);
+    }
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read two-factor settings body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let code = form.value("code");
+    let wrong = Some(templates::Outcome::Error(i18n::t("totp.wrong")));
+
+    if !enable {
+        if !check_code(state, &username, code) {
+            return serve_totp_settings(state, session_id, None, wrong, request);
+        }
+        if let Err(e) = state.prefs.update(&username, |prefs| prefs.totp = None) {
+            log_error!("Failed to persist preferences: {}", e);
+        }
+        log_info!("User {} turned two-factor authentication off", username);
+        let notice = Some(templates::Outcome::Notice(i18n::t("totp.turned_off")));
+        return serve_totp_settings(state, session_id, None, notice, request);
+    }
+
+    // Replacing the secret would get around needing a code to turn it off
+    if state.prefs.get(&username).totp.is_some() {
+        return serve_totp_settings(state, session_id, None, None, request);
+    }
+    let secret = form.value("secret").trim();
+    let Some(step) = totp::verify(secret, code, 0) else {
+        return serve_totp_settings(state, session_id, Some(secret), wrong, request);
+    };
+    let totp = TotpSecret {
+        secret: secret.to_string(),
+        last_step: step,
+    };
+    if let Err(e) = state.prefs.update(&username, |prefs| prefs.totp = Some(totp)) {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+    log_info!("User {} turned two-factor authentication on", username);
+    let notice = Some(templates::Outcome::Notice(i18n::t("totp.turned_on")));
+    serve_totp_settings(state, session_id, None, notice, request)
+}
+
+/// The user's sessions on every device, with how a change to them went
+fn serve_session_settings(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    outcome: Option<templates::Outcome>,
+    request: Request,
+) -> Result<(), ()> {
+    let sessions: Vec<templates::SignedInSession> = state
+        .sessions
+        .of_same_user(session_id, |s| (s.device.clone(), to_unix(s.created), to_unix(s.last_active)))
+        .into_iter()
+        .map(|(id, (device, created, last_active))| templates::SignedInSession {
+            id: id.to_string(),
+            current: id == *session_id,
+            device,
+            created,
+            last_active,
+        })
+        .collect();
+    if sessions.is_empty() {
+        return redirect_to_login(state, request);
+    }
+    let html = templates::session_settings(&sessions, outcome);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Sign out another of the user's sessions, which its device finds out on
+/// its next request
+fn handle_session_revoke(state: &Arc<AppState>, session_id: &Uuid, other: &str, request: Request) -> Result<(), ()> {
+    let revoked = Uuid::parse_str(other).is_ok_and(|other| state.sessions.revoke(session_id, &other));
+    let outcome = if revoked {
+        log_info!("Session {} signed out session {}", session_id, other);
+        templates::Outcome::Notice(i18n::t("sessions.revoked"))
+    } else {
+        templates::Outcome::Error(i18n::t("sessions.not_found"))
+    };
+    serve_session_settings(state, session_id, Some(outcome), request)
+}
+
+fn handle_revoke_other_sessions(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
+    let count = state.sessions.revoke_others(session_id);
+    log_info!("Session {} signed out {} other sessions", session_id, count);
+    let notice = i18n::tf("sessions.revoked_others", &[("n", &count.to_string())]);
+    serve_session_settings(state, session_id, Some(templates::Outcome::Notice(&notice)), request)
+}
+
+fn serve_signature_settings(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_submission) {
+        return unsupported(state, request, "unsupported.signatures");
+    }
+    let Some(username) = session_username(state, session_id) else {
+        return redirect_to_login(state, request);
+    };
+    let html = match client.get_identities() {
+        Ok(identities) => templates::signature_settings(&identities, &state.prefs.get(&username), None),
+        Err(e) => {
+            log_error!("Failed to fetch identities: {}", e);
+            return AppError::jmap("Failed to load identities", e).respond(state, request);
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// The muted threads, to unmute them
+fn serve_muted_settings(state: &Arc<AppState>, client: JmapClient, request: Request) -> Result<(), ()> {
+    let html = match mute::threads(&client) {
+        Ok(emails) => templates::muted_settings(&emails),
+        Err(e) => {
+            log_error!("Failed to fetch muted threads: {}", e);
+            return AppError::jmap("Failed to load muted conversations", e).respond(state, request);
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Keep the signatures typed into the settings form, HTML ones sanitized.
+/// One left as the server has it isn't kept, so changes made there still
+/// come through.
+fn handle_signatures_update(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    mut request: Request,
+) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_submission) {
+        return unsupported(state, request, "unsupported.signatures");
+    }
+    let Some(username) = session_username(state, session_id) else {
+        return redirect_to_login(state, request);
+    };
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read signatures body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let identities = match client.get_identities() {
+        Ok(identities) => identities,
+        Err(e) => {
+            log_error!("Failed to fetch identities: {}", e);
+            return AppError::jmap("Failed to load identities", e).respond(state, request);
+        }
+    };
+
+    let form = Params::parse(&body);
+    let typed: Vec<(&str, String, String)> = form
+        .get_all("identity")
+        .zip(form.get_all("signature"))
+        .zip(form.get_all("html_signature"))
+        .map(|((id, signature), html)| {
+            let signature = signature.replace("\r\n", "\n").trim_end().to_string();
+            (id, signature, sanitize::signature(html.trim()))
+        })
+        .collect();
+    let result = state.prefs.update(&username, |prefs| {
+        for (id, signature, html) in typed {
+            let Some(identity) = identities.iter().find(|i| i.id == id) else { continue };
+            if signature == templates::identity_signature(identity) {
+                prefs.signatures.remove(id);
+            } else {
+                prefs.signatures.insert(id.to_string(), signature);
+            }
+            if html == sanitize::signature(identity.html_signature.as_deref().unwrap_or("").trim()) {
+                prefs.html_signatures.remove(id);
+            } else {
+                prefs.html_signatures.insert(id.to_string(), html);
+            }
+        }
+        // Forget identities deleted on the server since
+        prefs.signatures.retain(|id, _| identities.iter().any(|i| &i.id == id));
+        prefs.html_signatures.retain(|id, _| identities.iter().any(|i| &i.id == id));
+    });
+    if let Err(e) = result {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+
+    let outcome = templates::Outcome::Notice(i18n::t("signatures.saved"));
+    let html = templates::signature_settings(&identities, &state.prefs.get(&username), Some(outcome));
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Keep the language picked in the sidebar, or go back to the browser's
+/// with an empty one, and redraw the page in it
+fn handle_language(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, mut request: Request) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read language body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let params = Params::parse(&body);
+    let language = Some(params.value("language")).filter(|l| i18n::is_known(l));
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    if let Err(e) = state.prefs.update(&username, |prefs| prefs.language = language.map(str::to_string)) {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+    match language {
+        Some(language) => i18n::select(language),
+        None => i18n::negotiate(
+            header_value(&request, "Accept-Language").as_deref(),
+            &state.config.i18n.default_language,
+        ),
+    }
+    serve_main_page(state, session_id, client, request)
+}
+
+/// Keep the theme the sidebar toggle switched to. The page has already
+/// switched itself, so there's nothing to send back but the cookie.
+fn handle_theme(state: &Arc<AppState>, mut request: Request) -> Result<(), ()> {
+    let mut body = String::new();
+    let _ = request.as_reader().read_to_string(&mut body);
+    let Some(theme) = Theme::parse(Params::parse(&body).value("theme")) else {
+        return respond(state, request, Response::empty(400)).map_err(|_| ());
+    };
+    let cookie = theme::cookie(&state.config.server, theme, is_https(state, &request));
+    let response = Response::empty(204).with_header(Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes()).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+/// The app shell, opening where the user left off in `client`'s account
+fn serve_main_page(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return redirect_to_login(state, request);
+    };
+    let opened = last_viewed(state, &client, &username, client.account_id());
+    serve_opened_page(state, session_id, &client, &opened, request)
+}
+
+/// What the user last had open in `account_id`, as far as it is still
+/// there: the mailbox unless it was deleted, and the message unless it was.
+/// Without a mailbox the message is listed with one it is in.
+fn last_viewed(state: &AppState, client: &JmapClient, username: &str, account_id: &str) -> templates::Opened {
+    let Some(last) = state.prefs.get(username).last_viewed.remove(account_id) else {
+        return templates::Opened::default();
+    };
+    let mailbox = last.mailbox.filter(|id| {
+        id == ALL_MAIL_ID
+            || id == FLAGGED_ID
+            || client.get_mailboxes().is_ok_and(|mailboxes| mailboxes.iter().any(|m| &m.id == id))
+    });
+    let email = last.email.and_then(|id| match client.get_emails(&[id]) {
+        Ok(emails) => emails.into_iter().next(),
+        Err(e) => {
+            log_error!("Failed to look up the last message viewed: {}", e);
+            None
+        }
+    });
+    let mailbox = mailbox.or_else(|| email.as_ref().and_then(first_mailbox));
+    templates::Opened {
+        list: mailbox.as_ref().map(|id| format!("/mailbox/{}/emails", templates::url_encode(id))),
+        view: email.map(|email| format!("/email/{}", templates::url_encode(&email.id))),
+        mailbox,
+    }
+}
+
+/// What the user opened, for `last_viewed` to go back to
+enum Viewed<'a> {
+    Mailbox(&'a str),
+    Email(&'a str),
+}
+
+/// Remember `viewed` as where the user is in `client`'s account. A message
+/// is taken to be in the mailbox listed, so listing another one forgets it.
+fn remember_viewed(state: &AppState, session_id: &Uuid, client: &JmapClient, viewed: Viewed) {
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return;
+    };
+    let account_id = client.account_id().to_string();
+    let last = state.prefs.get(&username).last_viewed.remove(&account_id).unwrap_or_default();
+    let next = match viewed {
+        Viewed::Mailbox(id) if last.mailbox.as_deref() == Some(id) => return,
+        Viewed::Mailbox(id) => LastViewed {
+            mailbox: Some(id.to_string()),
+            email: None,
+        },
+        Viewed::Email(id) => LastViewed {
+            email: Some(id.to_string()),
+            ..last.clone()
+        },
+    };
+    // Most views are of what is already remembered; only changes are written
+    if next == last {
+        return;
+    }
+    if let Err(e) = state.prefs.update(&username, |prefs| {
+        prefs.last_viewed.insert(account_id, next);
+    }) {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+}
+
+/// The first of the mailboxes `email` is in, by id
+fn first_mailbox(email: &Email) -> Option<String> {
+    email.mailbox_ids.iter().filter(|(_, set)| **set).map(|(id, _)| id.clone()).min()
+}
+
+/// The app shell with `opened` loading into it, for a link to a mailbox or
+/// message loaded directly
+fn serve_opened_page(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: &JmapClient,
+    opened: &templates::Opened,
+    request: Request,
+) -> Result<(), ()> {
+    let html = state
+        .sessions
+        .get(session_id, |s| {
+            templates::main_page(
+                &state.config.branding,
+                s,
+                client.account_id(),
+                &state.prefs.get(&s.username),
+                opened,
+                &state.plugins.sidebar_links(&s.username),
+            )
+        })
+        .unwrap_or_default();
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// The page for a link to a message or thread at `view`, with the list of
+/// one of the mailboxes `email_id` is in beside it
+fn serve_message_page(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    email_id: &str,
+    view: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let mailbox = match client.get_emails(&[email_id.to_string()]) {
+        _ if email_id.is_empty() => None,
+        Ok(emails) => emails.into_iter().next().as_ref().and_then(first_mailbox),
+        Err(e) => {
+            log_error!("Failed to look up the mailbox of {}: {}", email_id, e);
+            None
+        }
+    };
+    let opened = templates::Opened {
+        list: mailbox.as_ref().map(|id| format!("/mailbox/{}/emails", id)),
+        view: Some(view.to_string()),
+        mailbox,
+    };
+    serve_opened_page(state, session_id, &client, &opened, request)
+}
+
+/// Whether a GET is the browser loading a page, from a bookmark, a reload or
+/// a typed address, rather than htmx asking for a fragment of one
+fn is_page_load(request: &Request) -> bool {
+    header_value(request, "HX-Request").is_none() || header_value(request, "HX-History-Restore-Request").is_some()
+}
+
+/// Open another of the session's accounts in this tab, at its own
+/// `/account/{id}/` so that other tabs stay on theirs. It is also what
+/// URLs without an account show from now on, such as a new tab's.
+fn handle_switch_account(state: &Arc<AppState>, session_id: &Uuid, mut request: Request) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read account switch body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let account_id = Params::parse(&body).value("account").to_string();
+
+    let mut switched = false;
+    state.sessions.update(session_id, |s| {
+        if s.accounts.iter().any(|a| a.id == account_id) {
+            s.account_id = account_id.clone();
+            switched = true;
+        }
+    });
+    if !switched {
+        log_error!("Session {} asked for unknown account {}", session_id, account_id);
+        return serve_404(state, request);
+    }
+    log_info!("Session {} switched to account {}", session_id, account_id);
+    let url = format!("{}{}/", state.config.server.base_path, account_path(&account_id));
+    let response = html_response(state, String::new())
+        .with_header(Header::from_bytes(&b"HX-Redirect"[..], url.as_bytes()).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+/// Where the pages of `account_id` are, under the base path
+fn account_path(account_id: &str) -> String {
+    format!("/account/{}", templates::url_encode(account_id))
+}
+
+/// The sidebar's folders. The path down to `selected`, the mailbox the page
+/// opened at, is shown expanded even where the user collapsed it.
+fn handle_mailboxes(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    selected: Option<&str>,
+    request: Request,
+) -> Result<(), ()> {
+    log_debug!("Fetching mailboxes for session: {}", session_id);
+
+
+    match client.get_mailboxes_with_state() {
+        Ok((mailboxes, mailbox_state)) => {
+            log_info!(
+                "Fetched {} mailboxes for session {}",
+                mailboxes.len(),
+                session_id
+            );
+            for mb in &mailboxes {
+                log_debug!(
+                    "  Mailbox: {} (id={}, total={}, unread={})",
+                    mb.name,
+                    mb.id,
+                    mb.total_emails,
+                    mb.unread_emails
+                );
+            }
+            let collapsed = collapsed_mailboxes(state, session_id);
+            let mut html = templates::mailbox_list(&mailboxes, &collapsed, selected);
+            record_mailbox_state(state, session_id, &client, &mailbox_state);
+            if let Some(interval) = poll_interval(state, session_id) {
+                html += &templates::mailbox_poller(interval, &mailbox_state);
+            }
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            AppError::jmap("Failed to load mailboxes", e).respond(state, request)
+        }
+    }
+}
+
+/// The folders the session's user collapsed in the sidebar
+fn collapsed_mailboxes(state: &AppState, session_id: &Uuid) -> HashSet<String> {
+    state
+        .sessions
+        .get(session_id, |s| s.username.clone())
+        .map(|username| state.prefs.get(&username).collapsed_mailboxes)
+        .unwrap_or_default()
+}
+
+/// Collapse or expand a folder in the sidebar, remembering it for the user,
+/// and render the sidebar again
+fn handle_mailbox_collapse(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    mailbox_id: &str,
+    collapse: bool,
+    request: Request,
+) -> Result<(), ()> {
+    let Some(username) = session_username(state, session_id) else {
+        return redirect_to_login(state, request);
+    };
+    let result = state.prefs.update(&username, |prefs| {
+        if collapse {
+            prefs.collapsed_mailboxes.insert(mailbox_id.to_string());
+        } else {
+            prefs.collapsed_mailboxes.remove(mailbox_id);
+        }
+    });
+    if let Err(e) = result {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+    let html = match client.get_mailboxes() {
+        Ok(mailboxes) => templates::mailbox_list(&mailboxes, &collapsed_mailboxes(state, session_id), None),
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            return AppError::jmap("Failed to load mailboxes", e).respond(state, request);
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Seconds between polls for changes, if the operator turned polling on and
+/// the server can't push them instead
+fn poll_interval(state: &AppState, session_id: &Uuid) -> Option<u64> {
+    let interval = state.live().poll.interval_seconds.filter(|&s| s > 0)?;
+    let has_push = state.sessions.get(session_id, Session::has_push).unwrap_or(false);
+    (!has_push).then_some(interval)
+}
+
+/// One poll from the sidebar. Nothing is re-rendered (204) while
+/// Mailbox/changes reports nothing new; otherwise the badges, or the whole
+/// sidebar if folders came or went, are swapped in along with a poller for
+/// the new state, and `mailboxesChanged` tells the open list to refresh.
+fn handle_mailbox_poll(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, since: &str, request: Request) -> Result<(), ()> {
+    let Some(interval) = poll_interval(state, session_id) else {
+        return respond(state, request, Response::empty(204)).map_err(|_| ());
+    };
+
+    // Without a usable state (e.g. cannotCalculateChanges) start over from
+    // a full refresh
+    let folders_changed = match client.mailbox_changes(since) {
+        Ok(changes) if changes.is_empty() && changes.new_state == since => {
+            return respond(state, request, Response::empty(204)).map_err(|_| ());
+        }
+        Ok(changes) => !changes.created.is_empty() || !changes.destroyed.is_empty(),
+        Err(e) => {
+            log_error!("Mailbox/changes since {} failed: {}", since, e);
+            true
+        }
+    };
+
+    let (mailboxes, mailbox_state) = match client.get_mailboxes_with_state() {
+        Ok(result) => result,
+        Err(e) => {
+            // Try again on the next tick
+            log_error!("Failed to fetch mailboxes: {}", e);
+            return respond(state, request, Response::empty(204)).map_err(|_| ());
+        }
+    };
+    let sidebar = if folders_changed {
+        templates::mailbox_list_oob(&mailboxes, &collapsed_mailboxes(state, session_id))
+    } else {
+        templates::unread_counts_oob(&mailboxes)
+    };
+    let html = sidebar + &templates::mailbox_poller(interval, &mailbox_state);
+    let response = html_response(state, html)
+        .with_header(Header::from_bytes(&b"HX-Trigger"[..], &b"mailboxesChanged"[..]).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+/// Just the unread badges, swapped out-of-band, for live updates that
+/// shouldn't disturb the selected mailbox. Only the mailboxes that
+/// Mailbox/changes reports since the sidebar was rendered are fetched, and
+/// nothing is sent (204) if none changed.
+fn handle_unread_counts(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    let since = state.sessions.get(session_id, |s| s.mailbox_state.get(client.account_id()).cloned()).flatten();
+    let changed = since.and_then(|since| match client.mailbox_changes(&since) {
+        Ok(changes) if changes.is_empty() && changes.new_state == since => Some(Vec::new()),
+        // New or deleted folders have no badge to update here, so refetch
+        // everything for them as for an unknown state
+        Ok(changes) if changes.created.is_empty() && changes.destroyed.is_empty() && !changes.has_more_changes => {
+            Some(changes.updated)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            log_error!("Mailbox/changes since {} failed: {}", since, e);
+            None
+        }
+    });
+
+    let mailboxes = match changed {
+        Some(ids) if ids.is_empty() => return respond(state, request, Response::empty(204)).map_err(|_| ()),
+        Some(ids) => client.get_mailboxes_by_id(&ids),
+        None => client.get_mailboxes_with_state(),
+    };
+    match mailboxes {
+        Ok((mailboxes, mailbox_state)) => {
+            record_mailbox_state(state, session_id, &client, &mailbox_state);
+            respond(state, request, html_response(state, templates::unread_counts_oob(&mailboxes))).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            respond(state, request, Response::empty(502)).map_err(|_| ())
+        }
+    }
+}
+
+/// How many messages are unread in the Inbox, or with `all` in every
+/// mailbox, as `{"unread": n}`, for the count in the tab's title
+fn handle_unread_total(state: &Arc<AppState>, client: JmapClient, all: bool, request: Request) -> Result<(), ()> {
+    match client.get_mailboxes() {
+        Ok(mailboxes) => {
+            let unread: u64 = mailboxes
+                .iter()
+                .filter(|m| all || m.role.as_deref() == Some("inbox"))
+                .map(|m| u64::from(m.unread_emails))
+                .sum();
+            let body = serde_json::json!({ "unread": unread }).to_string();
+            respond(state, request, json_response(state, body)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            respond(state, request, Response::empty(502)).map_err(|_| ())
+        }
+    }
+}
+
+fn record_mailbox_state(state: &AppState, session_id: &Uuid, client: &JmapClient, mailbox_state: &str) {
+    state.sessions.update(session_id, |s| {
+        s.mailbox_state.insert(client.account_id().to_string(), mailbox_state.to_string());
+    });
+}
+
+/// The page's Server-Sent Events stream of changes, fed by [`push`]. The
+/// response is written on its own thread since it stays open for as long
+/// as the tab does; a 204 tells the browser not to reconnect when the
+/// server has no EventSource.
+fn serve_events(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    let Some(changes) = push::subscribe(state, *session_id, client.account_id()) else {
+        return respond(state, request, Response::empty(204)).map_err(|_| ());
+    };
+
+    access_log::note_response(200, None);
+    let request_id = log::request_id();
+    thread::spawn(move || {
+        log::set_request_id(request_id);
+        let mut writer = request.into_writer();
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+        let mut send = |text: &str| writer.write_all(text.as_bytes()).and_then(|_| writer.flush());
+        if send(head).is_err() {
+            return;
+        }
+        loop {
+            // Comments keep the connection alive and find closed tabs even
+            // while the upstream connection is down
+            let message = match changes.recv_timeout(Duration::from_secs(push::PING_INTERVAL * 2)) {
+                Ok(change) => match change.event_name() {
+                    Some(name) => format!("event: {}\ndata: changed\n\n", name),
+                    None => ": ping\n\n".to_string(),
+                },
+                Err(RecvTimeoutError::Timeout) => ": ping\n\n".to_string(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if send(&message).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve_mailbox_settings(state: &Arc<AppState>, client: JmapClient, request: Request) -> Result<(), ()> {
+    match client.get_mailboxes() {
+        Ok(mailboxes) => {
+            let html = templates::mailbox_settings(&mailboxes, None);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            AppError::jmap("Failed to load mailboxes", e).respond(state, request)
+        }
+    }
+}
+
+enum MailboxChange {
+    Create,
+    Rename(String),
+    Delete(String),
+}
+
+/// Apply a Mailbox/set change and re-render the folder settings, with the
+/// server's refusal (if any) shown inline, plus a refreshed sidebar
+fn handle_mailbox_change(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    change: MailboxChange,
+    mut request: Request,
+) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read mailbox change body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let field = |key: &str| form.value(key).trim();
+    let name = field("name");
+
+    let result = match &change {
+        MailboxChange::Create | MailboxChange::Rename(_) if name.is_empty() => {
+            Err(JmapError::Api("Folder name is required".to_string()))
+        }
+        MailboxChange::Create => {
+            let parent = Some(field("parent_id")).filter(|p| !p.is_empty());
+            client.create_mailbox(name, parent).map(|_| format!("Created {}", name))
+        }
+        MailboxChange::Rename(id) => client.rename_mailbox(id, name).map(|_| format!("Renamed to {}", name)),
+        MailboxChange::Delete(id) => client.destroy_mailbox(id).map(|_| "Folder deleted".to_string()),
+    };
+
+    let mailboxes = match client.get_mailboxes() {
+        Ok(mailboxes) => mailboxes,
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            return AppError::jmap("Failed to load mailboxes", e).respond(state, request);
+        }
+    };
+    let html = match &result {
+        Ok(message) => {
+            log_info!("Mailbox change applied: {}", message);
+            templates::mailbox_settings(&mailboxes, Some(templates::Outcome::Notice(message)))
+                + &templates::mailbox_list_oob(&mailboxes, &collapsed_mailboxes(state, session_id))
+        }
+        Err(e) => {
+            log_error!("Mailbox change refused: {}", e);
+            templates::mailbox_settings(&mailboxes, Some(templates::Outcome::Error(&refusal(e))))
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Email/import the .eml files of the folder settings' import form into the
+/// chosen folder, and re-render the settings with how it went
+fn handle_import(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, mut request: Request) -> Result<(), ()> {
+    let content_type = header_value(&request, "content-type").unwrap_or_default();
+    let limit = upload_limit(state, session_id, state.config.server.limits.upload_bytes());
+    let mut body = Vec::new();
+    let read = request.as_reader().take(limit + 1).read_to_end(&mut body);
+    let parts = if read.is_err() {
+        Err("Failed to read request".to_string())
+    } else if body.len() as u64 > limit {
+        Err(format!("Imports are limited to {} at a time", templates::format_size(limit)))
+    } else {
+        multipart::boundary(&content_type)
+            .ok_or_else(|| "Failed to read request".to_string())
+            .and_then(|boundary| multipart::parse(&body, &boundary))
+    };
+    let parts = match parts {
+        Ok(parts) => parts,
+        Err(e) => {
+            log_error!("Failed to read import upload: {}", e);
+            let html = templates::error_fragment(&e);
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+    };
+
+    let field = |name: &str| {
+        parts
+            .iter()
+            .find(|p| p.name == name && p.filename.is_none())
+            .map(|p| String::from_utf8_lossy(&p.data).into_owned())
+    };
+    let mailbox_id = field("mailbox_id").unwrap_or_default();
+    let mut keywords = Vec::new();
+    if field("seen").is_some() {
+        keywords.push("$seen");
+    }
+    if field("flagged").is_some() {
+        keywords.push("$flagged");
+    }
+    // Browsers send an empty file part when nothing was picked
+    let files: Vec<&multipart::Part> = parts
+        .iter()
+        .filter(|p| p.name == "file" && p.filename.is_some() && !p.data.is_empty())
+        .collect();
+
+    let mut failures = Vec::new();
+    let mut uploaded = Vec::new();
+    for file in &files {
+        let name = file.filename.as_deref().unwrap_or_default();
+        match client.upload_blob(&file.data, "message/rfc822") {
+            Ok(blob) => uploaded.push((name, blob.blob_id)),
+            Err(e) => failures.push(format!("{}: {}", name, refusal(&e))),
+        }
+    }
+    let blob_ids: Vec<String> = uploaded.iter().map(|(_, blob_id)| blob_id.clone()).collect();
+    let result = if files.is_empty() {
+        Err(JmapError::Api("Choose at least one .eml file".to_string()))
+    } else if mailbox_id.is_empty() {
+        Err(JmapError::Api("Choose a folder to import into".to_string()))
+    } else {
+        client.import_emails(&blob_ids, &mailbox_id, &keywords)
+    };
+    let outcome = result.map(|imported| {
+        for ((name, _), result) in uploaded.iter().zip(imported) {
+            if let Err(e) = result {
+                failures.push(format!("{}: {}", name, refusal(&e)));
+            }
+        }
+        let count = files.len() - failures.len();
+        log_info!("Imported {} of {} messages into {}", count, files.len(), mailbox_id);
+        if failures.is_empty() {
+            format!("Imported {} message(s)", count)
+        } else {
+            format!("Imported {} of {} message(s). {}", count, files.len(), failures.join("; "))
+        }
+    });
+
+    let mailboxes = match client.get_mailboxes() {
+        Ok(mailboxes) => mailboxes,
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            return AppError::jmap("Failed to load mailboxes", e).respond(state, request);
+        }
+    };
+    let html = match &outcome {
+        Ok(message) if failures.is_empty() => {
+            templates::mailbox_settings(&mailboxes, Some(templates::Outcome::Notice(message)))
+                + &templates::mailbox_list_oob(&mailboxes, &collapsed_mailboxes(state, session_id))
+        }
+        Ok(message) => {
+            templates::mailbox_settings(&mailboxes, Some(templates::Outcome::Error(message)))
+                + &templates::mailbox_list_oob(&mailboxes, &collapsed_mailboxes(state, session_id))
+        }
+        Err(e) => {
+            log_error!("Import refused: {}", e);
+            templates::mailbox_settings(&mailboxes, Some(templates::Outcome::Error(&refusal(e))))
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// How many submissions the Outbox lists
+const OUTBOX_SIZE: u32 = 50;
+
+fn serve_outbox(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_submission) {
+        return unsupported(state, request, "unsupported.sending");
+    }
+    let html = match client.get_recent_submissions(OUTBOX_SIZE) {
+        Ok((submissions, emails)) => templates::outbox(&submissions, &emails),
+        Err(e) => {
+            log_error!("Failed to fetch email submissions: {}", e);
+            return AppError::jmap("Failed to load the outbox", e).respond(state, request);
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Events asked for at most, occurrences of recurring ones included
+const AGENDA_SIZE: u32 = 200;
+
+/// The week ahead, for accounts advertising urn:ietf:params:jmap:calendars
+fn serve_calendar(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_calendars) {
+        let html = templates::error_fragment(i18n::t("calendar.none"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    }
+
+    let (from, until) = calendar::span();
+    let (after, before) = (log::utc_date(from as u64), log::utc_date(until as u64));
+    let html = match client.get_calendar_events(&after, &before, AGENDA_SIZE) {
+        Ok(events) => templates::calendar_agenda(&calendar::occurrences(&events)),
+        Err(e) => {
+            log_error!("Failed to fetch calendar events: {}", e);
+            return AppError::jmap("Failed to load the calendar", e).respond(state, request);
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn serve_vacation_settings(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_vacation) {
+        return unsupported(state, request, "unsupported.vacation");
+    }
+    let html = match client.get_vacation_response() {
+        Ok(vacation) => templates::vacation_settings(&vacation, None),
+        Err(e) => {
+            log_error!("Failed to fetch vacation response: {}", e);
+            return AppError::jmap("Failed to load vacation responder", e).respond(state, request);
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Save the vacation form with VacationResponse/set and re-render it. On
+/// failure the form keeps what was typed so nothing has to be re-entered.
+fn handle_vacation_update(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    mut request: Request,
+) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_vacation) {
+        return unsupported(state, request, "unsupported.vacation");
+    }
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read vacation settings body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let optional = |key: &str| Some(form.value(key).trim().to_string()).filter(|v| !v.is_empty());
+
+    let mut vacation = VacationResponse {
+        is_enabled: form.get("enabled") == Some("1"),
+        subject: optional("subject"),
+        text_body: Some(form.value("text_body").to_string()).filter(|v| !v.trim().is_empty()),
+        ..Default::default()
+    };
+    let dates = utc_date_time(form.value("from_date"))
+        .and_then(|from| Ok((from, utc_date_time(form.value("to_date"))?)));
+    let result = match dates {
+        Err(e) => Err(JmapError::Api(e)),
+        Ok((from, to)) => {
+            vacation.from_date = from;
+            vacation.to_date = to;
+            match (&vacation.from_date, &vacation.to_date) {
+                // UTCDates in the same format compare correctly as strings
+                (Some(from), Some(to)) if to <= from => {
+                    Err(JmapError::Api("The end date must be after the start date".to_string()))
+                }
+                _ => client.set_vacation_response(&vacation),
+            }
+        }
+    };
+
+    let html = match result {
+        Ok(()) => {
+            log_info!("Vacation response updated (enabled={})", vacation.is_enabled);
+            let notice = if vacation.is_enabled {
+                "Vacation responder turned on"
+            } else {
+                "Vacation responder turned off"
+            };
+            templates::vacation_settings(&vacation, Some(templates::Outcome::Notice(notice)))
+        }
+        Err(e) => {
+            log_error!("Vacation response update refused: {}", e);
+            templates::vacation_settings(&vacation, Some(templates::Outcome::Error(&refusal(&e))))
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+enum SieveAction {
+    List,
+    Edit(Option<String>),
+    Save,
+    Activate(Option<String>),
+    Delete(String),
+}
+
+/// The Sieve filter pages, for accounts advertising urn:ietf:params:jmap:sieve.
+/// Changes re-render the script list or the editor with the outcome inline.
+fn handle_sieve(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, action: SieveAction, mut request: Request) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_sieve) {
+        return unsupported(state, request, "unsupported.filters");
+    }
+
+    let html = match action {
+        SieveAction::List => sieve_script_list(&client, None),
+        SieveAction::Edit(None) => templates::sieve_script_editor(None, "", "", None),
+        SieveAction::Edit(Some(id)) => {
+            let script = client.get_sieve_scripts().and_then(|scripts| {
+                let script = scripts
+                    .into_iter()
+                    .find(|s| s.id == id)
+                    .ok_or_else(|| JmapError::Api("Script not found".to_string()))?;
+                let text = client.get_sieve_script_text(&script)?;
+                Ok((script, text))
+            });
+            match script {
+                Ok((script, text)) => {
+                    templates::sieve_script_editor(Some(&id), script.name.as_deref().unwrap_or(""), &text, None)
+                }
+                Err(e) => {
+                    log_error!("Failed to load Sieve script {}: {}", id, e);
+                    return AppError::jmap("Failed to load script", e).respond(state, request);
+                }
+            }
+        }
+        SieveAction::Save => {
+            let mut body = String::new();
+            if request.as_reader().read_to_string(&mut body).is_err() {
+                log_error!("Failed to read Sieve script body");
+                return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+            }
+            let form = Params::parse(&body);
+            let id = Some(form.value("id")).filter(|id| !id.is_empty());
+            let name = form.value("name").trim();
+            let text = form.value("script");
+
+            if form.get("check") == Some("1") {
+                let outcome = client.validate_sieve_script(text);
+                let message = match &outcome {
+                    Ok(None) => templates::Outcome::Notice("The script is valid"),
+                    Ok(Some(problem)) => templates::Outcome::Error(problem),
+                    Err(e) => {
+                        log_error!("SieveScript/validate failed: {}", e);
+                        templates::Outcome::Error("The script could not be checked")
+                    }
+                };
+                templates::sieve_script_editor(id, name, text, Some(message))
+            } else if name.is_empty() {
+                templates::sieve_script_editor(id, name, text, Some(templates::Outcome::Error("A name is required")))
+            } else {
+                match client.save_sieve_script(id, name, text) {
+                    Ok(saved_id) => {
+                        log_info!("Sieve script {} saved", saved_id);
+                        templates::sieve_script_editor(Some(&saved_id), name, text, Some(templates::Outcome::Notice("Saved")))
+                    }
+                    Err(e) => {
+                        log_error!("Sieve script save refused: {}", e);
+                        templates::sieve_script_editor(id, name, text, Some(templates::Outcome::Error(&refusal(&e))))
+                    }
+                }
+            }
+        }
+        SieveAction::Activate(id) => {
+            let notice = if id.is_some() { "Script activated" } else { "Filtering turned off" };
+            match client.activate_sieve_script(id.as_deref()) {
+                Ok(()) => sieve_script_list(&client, Some(templates::Outcome::Notice(notice))),
+                Err(e) => {
+                    log_error!("Sieve activation refused: {}", e);
+                    sieve_script_list(&client, Some(templates::Outcome::Error(&refusal(&e))))
+                }
+            }
+        }
+        SieveAction::Delete(id) => match client.destroy_sieve_script(&id) {
+            Ok(()) => sieve_script_list(&client, Some(templates::Outcome::Notice("Script deleted"))),
+            Err(e) => {
+                log_error!("Sieve script delete refused: {}", e);
+                sieve_script_list(&client, Some(templates::Outcome::Error(&refusal(&e))))
+            }
+        },
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn sieve_script_list(client: &JmapClient, outcome: Option<templates::Outcome>) -> String {
+    match client.get_sieve_scripts() {
+        Ok(scripts) => templates::sieve_script_list(&scripts, outcome),
+        Err(e) => {
+            log_error!("Failed to fetch Sieve scripts: {}", e);
+            templates::error_fragment(&format!("Failed to load filters: {}", e))
+        }
+    }
+}
+
+/// What to show for a refused change: a SetError's description on its own,
+/// anything else in full
+fn refusal(e: &JmapError) -> String {
+    match e {
+        JmapError::Api(description) => description.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// A `<input type="datetime-local">` value (`YYYY-MM-DDTHH:MM`, optionally
+/// with seconds) to a UTCDate, or `None` when left empty
+fn utc_date_time(value: &str) -> Result<Option<String>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let valid = matches!(value.len(), 16 | 19)
+        && value.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            10 => c == 'T',
+            13 | 16 => c == ':',
+            _ => c.is_ascii_digit(),
+        });
+    if !valid {
+        return Err(format!("Invalid date: {}", value));
+    }
+    let seconds = if value.len() == 16 { ":00" } else { "" };
+    Ok(Some(format!("{}{}Z", value, seconds)))
+}
+
+const EMAILS_PER_PAGE: u32 = 50;
+const MAX_EMAILS_PER_PAGE: u32 = 200;
+
+/// Which slice of an Email/query result to show, in what order and whether
+/// only unread messages, from `?offset=&anchor=&limit=&sort=&dir=&unread=`.
+/// A page further down a list starts just past its `anchor`, the last email
+/// the page before it listed, so mail arriving meanwhile doesn't shift it;
+/// `offset` is where that was, for when the anchor has gone. `refresh=1`
+/// marks a live refresh of a list that is already on screen.
+#[derive(Debug, Clone)]
+struct Page {
+    offset: u32,
+    anchor: Option<String>,
+    limit: u32,
+    sort: Sort,
+    unread_only: bool,
+    refresh: bool,
+}
+
+impl Page {
+    fn from_params(params: &Params) -> Page {
+        Page {
+            offset: params.get_as("offset").unwrap_or(0),
+            anchor: params.get("anchor").filter(|id| !id.is_empty()).map(str::to_string),
+            limit: params
+                .get_as("limit")
+                .unwrap_or(EMAILS_PER_PAGE)
+                .clamp(1, MAX_EMAILS_PER_PAGE),
+            sort: match params.get("sort").and_then(SortProperty::parse) {
+                Some(property) => Sort {
+                    property,
+                    ascending: params.get("dir") == Some("asc"),
+                },
+                None => Sort::default(),
+            },
+            unread_only: params.get("unread") == Some("1"),
+            refresh: params.get("refresh") == Some("1"),
+        }
+    }
+
+    /// The same-sized page following on from `anchor`, which is at
+    /// `offset - 1`
+    fn after(&self, anchor: &str, offset: u32) -> Page {
+        Page {
+            offset,
+            anchor: Some(anchor.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// The first page, in a different order or with the unread filter
+    /// switched
+    fn first(&self, sort: Sort, unread_only: bool) -> Page {
+        Page {
+            offset: 0,
+            anchor: None,
+            sort,
+            unread_only,
+            ..self.clone()
+        }
+    }
+
+    /// Where the page starts in Email/query terms
+    fn position(&self) -> Position {
+        match &self.anchor {
+            Some(id) => Position::After(id.clone()),
+            None => Position::Index(self.offset),
+        }
+    }
+
+    /// Narrow a query's filter to this page's unread setting
+    fn filter(&self, filter: serde_json::Value) -> serde_json::Value {
+        if self.unread_only {
+            Filter::from(Condition::NotKeyword("$seen".to_string())).narrow(filter)
+        } else {
+            filter
+        }
+    }
+
+    /// `offset=..`, plus `anchor=..` past the first page and `limit=..` and
+    /// `sort=..&dir=..` when they aren't the defaults
+    fn query_string(&self) -> String {
+        let mut query = format!("offset={}", self.offset);
+        if let Some(anchor) = &self.anchor {
+            query.push_str(&format!("&anchor={}", templates::url_encode(anchor)));
+        }
+        if self.limit != EMAILS_PER_PAGE {
+            query.push_str(&format!("&limit={}", self.limit));
+        }
+        if self.sort != Sort::default() {
+            let dir = if self.sort.ascending { "asc" } else { "desc" };
+            query.push_str(&format!("&sort={}&dir={}", self.sort.property.as_str(), dir));
+        }
+        if self.unread_only {
+            query.push_str("&unread=1");
+        }
+        query
+    }
+}
+
+fn handle_emails(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    mailbox_id: &str,
+    page: Page,
+    request: Request,
+) -> Result<(), ()> {
+    log_info!(
+        "Fetching emails for mailbox: {} (offset: {}, limit: {})",
+        mailbox_id,
+        page.offset,
+        page.limit
+    );
+
+
+    file_muted(&client, mailbox_id);
+    log_debug!("Querying email IDs for mailbox: {}", mailbox_id);
+
+    let filter = mailbox_filter(&client, mailbox_id);
+    let page_url = |page: &Page| format!("/mailbox/{}/emails?{}", templates::url_encode(mailbox_id), page.query_string());
+    let sync = ListSync::new(state, session_id, &client, &page, page_url);
+    if sync.unchanged() {
+        return respond(state, request, Response::empty(204)).map_err(|_| ());
+    }
+
+    // Pinned messages go above the rest of the first page, and not among them
+    let pinned = match &filter {
+        Ok(filter) if page.offset == 0 => {
+            let pinned = Filter::from(Condition::HasKeyword(PINNED_KEYWORD.to_string())).narrow(filter.clone());
+            pinned_emails(&client, page.filter(pinned))
+        }
+        _ => Vec::new(),
+    };
+    let query = filter.and_then(|filter| {
+        let unpinned = Filter::from(Condition::NotKeyword(PINNED_KEYWORD.to_string())).narrow(filter);
+        query_list(&client, &sync, page.filter(unpinned), &page)
+    });
+
+    let html = match query {
+        Ok(list) => {
+            log_info!(
+                "Email/query returned {} email IDs for mailbox {} (total: {:?})",
+                list.result.ids.len(),
+                mailbox_id,
+                list.result.total
+            );
+            remember_viewed(state, session_id, &client, Viewed::Mailbox(mailbox_id));
+            email_list_page(&client, &sync, list, &pinned, mailbox_id, &page, page_url)
+        }
+        Err(e) => {
+            log_error!("Failed to query emails for mailbox {}: {}", mailbox_id, e);
+            ListHtml::Page(templates::error_fragment(&format!("Failed to query emails: {}", e)))
+        }
+    };
+    respond(state, request, html.into_response(state)).map_err(|_| ())
+}
+
+/// Most pinned messages listed above a mailbox's others; any more are
+/// left out of its list
+const PINNED_LIMIT: u32 = 50;
+
+/// The messages pinned in a list matching `filter`, newest first
+fn pinned_emails(client: &JmapClient, filter: serde_json::Value) -> Vec<Email> {
+    match client.query_and_get_emails(filter, &Sort::default(), PINNED_LIMIT, &Position::Index(0)) {
+        Ok((result, mut emails, _)) => {
+            fill_thread_sizes(client, &mut emails);
+            emails.sort_by_key(|e| result.ids.iter().position(|id| *id == e.id));
+            emails
+        }
+        Err(e) => {
+            log_error!("Failed to query pinned emails: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Before the Inbox is listed, file away what arrived in muted threads
+fn file_muted(client: &JmapClient, mailbox_id: &str) {
+    let mailboxes = match client.get_mailboxes() {
+        Ok(mailboxes) => mailboxes,
+        Err(e) => return log_error!("Failed to fetch mailboxes: {}", e),
+    };
+    if !mailboxes.iter().any(|m| m.id == mailbox_id && m.role.as_deref() == Some("inbox")) {
+        return;
+    }
+    if let Err(e) = mute::file_new(client, &mailboxes) {
+        log_error!("Failed to file away messages in muted threads: {}", e);
+    }
+}
+
+/// What lists a mailbox's messages, the virtual All Mail and Flagged
+/// mailboxes included
+fn mailbox_filter(client: &JmapClient, mailbox_id: &str) -> Result<serde_json::Value, JmapError> {
+    if mailbox_id == ALL_MAIL_ID {
+        all_mail_filter(client)
+    } else if mailbox_id == FLAGGED_ID {
+        Ok(Filter::from(Condition::HasKeyword("$flagged".to_string())).to_json())
+    } else {
+        Ok(Filter::from(Condition::InMailbox(mailbox_id.to_string())).to_json())
+    }
+}
+
+/// Delta sync for the email list. The session remembers the first page it
+/// last rendered; a live refresh of that same list asks Email/changes what
+/// happened since instead of fetching every row again.
+struct ListSync<'a> {
+    state: &'a AppState,
+    session_id: &'a Uuid,
+    /// The list's account and first page URL, which identify it
+    account_id: String,
+    url: String,
+    /// The changes since the list was shown, with the ids it showed then
+    delta: Option<(ChangesResponse, Vec<String>)>,
+}
+
+impl<'a> ListSync<'a> {
+    /// Changes are only asked for on a refresh of the list last shown.
+    /// Without them, or if the server can't list them all, the list is
+    /// rendered in full as before.
+    fn new(
+        state: &'a AppState,
+        session_id: &'a Uuid,
+        client: &JmapClient,
+        page: &Page,
+        page_url: impl Fn(&Page) -> String,
+    ) -> Self {
+        let url = page_url(&page.first(page.sort, page.unread_only));
+        let shown = page
+            .refresh
+            .then(|| {
+                state.sessions.get(session_id, |s| {
+                    s.shown_list
+                        .as_ref()
+                        .filter(|shown| shown.account_id == client.account_id() && shown.url == url)
+                        .map(|shown| (shown.ids.clone(), shown.email_state.clone()))
+                })
+            })
+            .flatten()
+            .flatten();
+        let delta = shown.and_then(|(ids, since)| match client.email_changes(&since) {
+            Ok(changes) if !changes.has_more_changes => Some((changes, ids)),
+            Ok(_) => None,
+            Err(e) => {
+                log_error!("Email/changes since {} failed: {}", since, e);
+                None
+            }
+        });
+        ListSync { state, session_id, account_id: client.account_id().to_string(), url, delta }
+    }
+
+    /// No email at all changed since the list was shown
+    fn unchanged(&self) -> bool {
+        self.delta.as_ref().is_some_and(|(changes, _)| changes.is_empty())
+    }
+
+    /// Remember a first page as rendered, at the given Email state
+    fn record(&self, ids: &[String], email_state: String) {
+        let shown = ShownList {
+            account_id: self.account_id.clone(),
+            url: self.url.clone(),
+            ids: ids.to_vec(),
+            email_state,
+        };
+        self.state.sessions.update(self.session_id, |s| s.shown_list = Some(shown));
+    }
+}
+
+/// One page of a list's Email/query, and its emails with their Email state
+/// when they were fetched in the same request
+struct ListQuery {
+    result: EmailQueryResult,
+    emails: Option<(Vec<Email>, String)>,
+}
+
+/// Run a list's query. The page's emails come back in the same request by
+/// back-reference, except on a refresh `sync` has changes for, which may
+/// only need the rows that changed. A page whose anchor has since been
+/// deleted or moved away starts at its offset instead.
+fn query_list(
+    client: &JmapClient,
+    sync: &ListSync,
+    filter: serde_json::Value,
+    page: &Page,
+) -> Result<ListQuery, JmapError> {
+    let query = |position: &Position| -> Result<ListQuery, JmapError> {
+        if sync.delta.is_some() {
+            let result = client.query_emails_sorted(filter.clone(), &page.sort, page.limit, position)?;
+            return Ok(ListQuery { result, emails: None });
+        }
+        let (result, emails, email_state) =
+            client.query_and_get_emails(filter.clone(), &page.sort, page.limit, position)?;
+        Ok(ListQuery {
+            result,
+            emails: Some((emails, email_state)),
+        })
+    };
+    match query(&page.position()) {
+        Err(e) if page.anchor.is_some() && e.is_method_error("anchorNotFound") => {
+            log_info!("Anchor of the page at {} is gone, listing from there instead", page.offset);
+            query(&Position::Index(page.offset))
+        }
+        result => result,
+    }
+}
+
+/// A rendered list page. `Changed` holds only out-of-band rows, for a
+/// refresh whose list kept the same messages in the same order.
+enum ListHtml {
+    Page(String),
+    Changed(String),
+}
+
+impl ListHtml {
+    fn into_response(self, state: &AppState) -> BoxResponse {
+        match self {
+            ListHtml::Page(html) => html_response(state, html),
+            // Leave the list in place; only the rows swap
+            ListHtml::Changed(html) => html_response(state, html)
+                .with_header(Header::from_bytes(&b"HX-Reswap"[..], &b"none"[..]).unwrap()),
+        }
+    }
+}
+
+/// Fetch and render one page of Email/query results. `page_url` builds the
+/// URL of another page of the same query, for the next page and the column
+/// headers that re-sort. Past the first page only rows are returned, to
+/// replace the row that fetched them at the end of the list. A refresh that `sync` has changes
+/// for, and whose page still holds the same messages, only re-renders the
+/// rows of those that changed. `pinned` go above the first page's rows.
+fn email_list_page(
+    client: &JmapClient,
+    sync: &ListSync,
+    list: ListQuery,
+    pinned: &[Email],
+    mailbox_id: &str,
+    page: &Page,
+    page_url: impl Fn(&Page) -> String,
+) -> ListHtml {
+    let query_result = list.result;
+    let listed: Vec<String> = pinned.iter().map(|e| e.id.clone()).chain(query_result.ids.iter().cloned()).collect();
+    if let Some((changes, shown_ids)) = &sync.delta {
+        if *shown_ids == listed {
+            return changed_rows(client, sync, changes, &listed);
+        }
+    }
+
+    let requery = |sort, unread_only| page_url(&page.first(sort, unread_only));
+    let view = |next_page| templates::ListView {
+        mailbox_id,
+        sort: page.sort,
+        unread_only: page.unread_only,
+        requery: &requery,
+        next_page,
+        pinned,
+    };
+
+    log_debug!("Email IDs returned: {:?}", query_result.ids);
+
+    let fetched = match list.emails {
+        Some(fetched) => Ok(fetched),
+        None => {
+            log_debug!("Fetching email details for {} emails...", query_result.ids.len());
+            client.get_emails_with_state(&query_result.ids)
+        }
+    };
+    let emails = match fetched {
+        Ok((emails, email_state)) => {
+            if page.offset == 0 {
+                sync.record(&listed, email_state);
+            }
+            sync.state.sessions.update(sync.session_id, |s| {
+                s.contacts.entry(sync.account_id.clone()).or_default().learn(&emails)
+            });
+            emails
+        }
+        Err(e) => {
+            log_error!("Failed to fetch email details: {}", e);
+            return ListHtml::Page(templates::error_fragment(&format!("Failed to load emails: {}", e)));
+        }
+    };
+    if emails.is_empty() && query_result.ids.is_empty() && pinned.is_empty() {
+        log_debug!("No emails in mailbox, returning empty list");
+        return ListHtml::Page(templates::email_list(&[], &[], &view(None)));
+    }
+    log_info!(
+        "Email/get returned {} emails (requested {})",
+        emails.len(),
+        query_result.ids.len()
+    );
+
+    if emails.len() != query_result.ids.len() {
+        log_error!(
+            "MISMATCH: Requested {} email IDs but got {} emails back!",
+            query_result.ids.len(),
+            emails.len()
+        );
+        log_debug!("Requested IDs: {:?}", query_result.ids);
+        let returned_ids: Vec<_> = emails.iter().map(|e| &e.id).collect();
+        log_debug!("Returned IDs: {:?}", returned_ids);
+
+        // Find missing IDs
+        let returned_set: std::collections::HashSet<_> =
+            emails.iter().map(|e| e.id.as_str()).collect();
+        let missing: Vec<_> = query_result.ids
+            .iter()
+            .filter(|id| !returned_set.contains(id.as_str()))
+            .collect();
+        log_error!("Missing email IDs: {:?}", missing);
+    }
+
+    // Email/get needn't answer in the order asked, so restore the query's
+    let mut emails = emails;
+    fill_thread_sizes(client, &mut emails);
+    emails.sort_by_key(|e| query_result.ids.iter().position(|id| *id == e.id));
+
+    // Calculate pagination info. The server may clamp the position we asked
+    // for, so continue from the one it reports.
+    let next = query_result.position + query_result.ids.len() as u32;
+    let has_more = match query_result.total {
+        Some(total) => next < total,
+        // If total is unknown but we got a full page, assume there might be more
+        None => query_result.ids.len() as u32 >= page.limit,
+    };
+    let next_page = has_more
+        .then(|| query_result.ids.last())
+        .flatten()
+        .map(|last| page_url(&page.after(last, next)));
+
+    // For the per-row "Move to" menus; the list still renders without them
+    let mailboxes = client.get_mailboxes().unwrap_or_else(|e| {
+        log_error!("Failed to fetch mailboxes for move menus: {}", e);
+        Vec::new()
+    });
+
+    // Use rows-only template for pagination (offset > 0)
+    ListHtml::Page(if page.offset > 0 {
+        templates::email_list_rows(&emails, &mailboxes, next_page.as_deref())
+    } else {
+        templates::email_list(&emails, &mailboxes, &view(next_page))
+    })
+}
+
+/// The rows on the page that were created or updated since it was shown,
+/// swapped out-of-band
+fn changed_rows(client: &JmapClient, sync: &ListSync, changes: &ChangesResponse, ids: &[String]) -> ListHtml {
+    let changed: Vec<String> = ids
+        .iter()
+        .filter(|id| changes.updated.contains(id) || changes.created.contains(id))
+        .cloned()
+        .collect();
+    log_debug!("Refreshing {} changed rows of {}", changed.len(), ids.len());
+
+    let mut emails = match client.get_emails(&changed) {
+        Ok(emails) => emails,
+        Err(e) => {
+            log_error!("Failed to fetch changed emails: {}", e);
+            return ListHtml::Page(templates::error_fragment(&format!("Failed to load emails: {}", e)));
+        }
+    };
+    if emails.is_empty() {
+        sync.record(ids, changes.new_state.clone());
+        return ListHtml::Changed(String::new());
+    }
+    fill_thread_sizes(client, &mut emails);
+    let mailboxes = client.get_mailboxes().unwrap_or_else(|e| {
+        log_error!("Failed to fetch mailboxes for move menus: {}", e);
+        Vec::new()
+    });
+    sync.record(ids, changes.new_state.clone());
+    ListHtml::Changed(templates::email_rows_oob(&emails, &mailboxes))
+}
+
+/// The advanced search form, pre-filled when coming back from results
+fn serve_search_form(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    query: &Params,
+    request: Request,
+) -> Result<(), ()> {
+    let mailboxes = client.get_mailboxes().unwrap_or_else(|e| {
+        log_error!("Failed to fetch mailboxes for search form: {}", e);
+        Vec::new()
+    });
+    let html = templates::search_form(&parse_search(query), &mailboxes);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn handle_search(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    query: &Params,
+    request: Request,
+) -> Result<(), ()> {
+    let search = parse_search(query);
+    let page = Page::from_params(query);
+
+    let filter = match search.filter() {
+        Ok(Some(filter)) => filter,
+        Ok(None) => {
+            let html = templates::error_fragment("Enter at least one search criterion");
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+        Err(e) => {
+            let html = templates::error_fragment(&e);
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+    };
+    log_info!("Searching (offset: {}, limit: {})", page.offset, page.limit);
+
+    let canonical = search_query_string(&search);
+    let page_url = |page: &Page| format!("/search/results?{}&{}", canonical, page.query_string());
+
+    // What the cache finds shows straight away, and asks for the server's
+    // results to replace it
+    let from_server = query.get("source") == Some("server");
+    if page.offset == 0 && !page.refresh && !from_server {
+        let results_url = format!("{}&source=server", page_url(&page));
+        let summary = |count| templates::local_search_summary(count, &results_url);
+        if let Some(html) = local_search_page(&client, &filter, &search.mailbox, &page, page_url, summary) {
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+    }
+
+    let sync = ListSync::new(state, session_id, &client, &page, page_url);
+    if sync.unchanged() {
+        return respond(state, request, Response::empty(204)).map_err(|_| ());
+    }
+
+    let html = match query_list(&client, &sync, page.filter(filter.to_json()), &page) {
+        Ok(list) => {
+            let summary = templates::search_summary(list.result.total, &canonical);
+            match email_list_page(&client, &sync, list, &[], &search.mailbox, &page, page_url) {
+                ListHtml::Page(list) if page.offset == 0 => ListHtml::Page(format!("{}{}", summary, list)),
+                list => list,
+            }
+        }
+        Err(e) => {
+            log_error!("Search failed: {}", e);
+            // Keep showing what the cache found rather than take it away
+            let summary = |count| templates::local_search_failed(count, &e.to_string());
+            let local = from_server
+                .then(|| local_search_page(&client, &filter, &search.mailbox, &page, page_url, summary));
+            ListHtml::Page(
+                local
+                    .flatten()
+                    .unwrap_or_else(|| templates::error_fragment(&format!("Search failed: {}", e))),
+            )
+        }
+    };
+    respond(state, request, html.into_response(state)).map_err(|_| ())
+}
+
+/// The first page of a search from the emails the cache keeps, under
+/// `summary` of how many match, or `None` if none of them do
+fn local_search_page(
+    client: &JmapClient,
+    filter: &Filter,
+    mailbox_id: &str,
+    page: &Page,
+    page_url: impl Fn(&Page) -> String,
+    summary: impl FnOnce(usize) -> String,
+) -> Option<String> {
+    let mut conditions = vec![filter.clone()];
+    if page.unread_only {
+        conditions.push(Condition::NotKeyword("$seen".to_string()).into());
+    }
+    let filter = Filter::all(conditions)?;
+    let (count, emails) = client.search_cached(&filter, page.sort, page.limit as usize)?;
+    if count == 0 {
+        return None;
+    }
+    log_info!("Search found {} cached emails", count);
+
+    let requery = |sort, unread_only| page_url(&page.first(sort, unread_only));
+    let view = templates::ListView {
+        mailbox_id,
+        sort: page.sort,
+        unread_only: page.unread_only,
+        requery: &requery,
+        next_page: None,
+        pinned: &[],
+    };
+    let mailboxes = client.get_mailboxes().unwrap_or_else(|e| {
+        log_error!("Failed to fetch mailboxes for move menus: {}", e);
+        Vec::new()
+    });
+    Some(format!("{}{}", summary(count), templates::email_list(&emails, &mailboxes, &view)))
+}
+
+fn parse_search(params: &Params) -> Search {
+    let field = |key| params.value(key).to_string();
+    Search {
+        text: field("text"),
+        from: field("from"),
+        to: field("to"),
+        subject: field("subject"),
+        after: field("after"),
+        before: field("before"),
+        has_attachment: params.get("has_attachment") == Some("true"),
+        mailbox: field("mailbox"),
+    }
+}
+
+/// The non-empty fields of a search as a query string, for further pages
+/// and "Edit search" links
+fn search_query_string(search: &Search) -> String {
+    let has_attachment = if search.has_attachment { "true" } else { "" };
+    [
+        ("text", search.text.as_str()),
+        ("from", &search.from),
+        ("to", &search.to),
+        ("subject", &search.subject),
+        ("after", &search.after),
+        ("before", &search.before),
+        ("has_attachment", has_attachment),
+        ("mailbox", &search.mailbox),
+    ]
+    .iter()
+    .filter(|(_, value)| !value.is_empty())
+    .map(|(key, value)| format!("{}={}", key, templates::url_encode(value)))
+    .collect::<Vec<_>>()
+    .join("&")
+}
+
+fn session_username(state: &Arc<AppState>, session_id: &Uuid) -> Option<String> {
+    state.sessions.get(session_id, |s| s.username.clone())
+}
+
+/// `limit` on an upload, or the user's own cap where that is lower
+fn upload_limit(state: &Arc<AppState>, session_id: &Uuid, limit: u64) -> u64 {
+    state.sessions.get(session_id, |s| s.allowance.upload_limit(limit)).unwrap_or(limit)
+}
+
+fn serve_saved_searches(state: &Arc<AppState>, session_id: &Uuid, request: Request) -> Result<(), ()> {
+    let Some(username) = session_username(state, session_id) else {
+        return redirect_to_login(state, request);
+    };
+    let html = templates::saved_search_list(&state.prefs.get(&username).saved_searches);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Store the search a results page was produced from under a name
+fn handle_save_search(state: &Arc<AppState>, session_id: &Uuid, mut request: Request) -> Result<(), ()> {
+    let Some(username) = session_username(state, session_id) else {
+        return redirect_to_login(state, request);
+    };
+
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        return respond(state, request, html_response(state, templates::error_fragment("Failed to read request"))).map_err(|_| ());
+    }
+    let form = Params::parse(&body);
+    let field = |key: &str| form.value(key).trim();
+
+    let name = field("name");
+    let filter = match parse_search(&Params::parse(field("query"))).filter() {
+        Ok(Some(filter)) if !name.is_empty() => filter,
+        Ok(Some(_)) => {
+            let html = templates::error_fragment(i18n::t("search.needs_name"));
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+        Ok(None) => {
+            let html = templates::error_fragment(i18n::t("search.needs_criteria"));
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+        Err(e) => {
+            let html = templates::error_fragment(&e);
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+    };
+
+    let saved = SavedSearch {
+        id: Uuid::now_v7().to_string(),
+        name: name.to_string(),
+        filter: filter.to_json(),
+    };
+    let result = state
+        .prefs
+        .update(&username, |prefs| prefs.saved_searches.push(saved));
+    if let Err(e) = &result {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+
+    let notice = match result {
+        Ok(()) => format!(r#"<span class="notice">Saved as {}</span>"#, templates::html_escape(name)),
+        Err(_) => r#"<span class="error">Saved for now, but it will be lost on restart</span>"#.to_string(),
+    };
+    let html = notice + &templates::saved_searches_oob(&state.prefs.get(&username).saved_searches);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Run a saved search's stored filter
+fn handle_saved_search_emails(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    search_id: &str,
+    page: Page,
+    request: Request,
+) -> Result<(), ()> {
+    let Some(username) = session_username(state, session_id) else {
+        return redirect_to_login(state, request);
+    };
+
+    let saved = state
+        .prefs
+        .get(&username)
+        .saved_searches
+        .into_iter()
+        .find(|s| s.id == search_id);
+    let Some(saved) = saved else {
+        let html = templates::error_fragment(i18n::t("search.saved_gone"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    };
+    log_info!("Running saved search {} (offset: {})", saved.name, page.offset);
+
+    let page_url = |page: &Page| {
+        format!("/saved-search/{}/emails?{}", templates::url_encode(search_id), page.query_string())
+    };
+    let sync = ListSync::new(state, session_id, &client, &page, page_url);
+    if sync.unchanged() {
+        return respond(state, request, Response::empty(204)).map_err(|_| ());
+    }
+
+    let html = match query_list(&client, &sync, page.filter(saved.filter), &page) {
+        Ok(list) => email_list_page(&client, &sync, list, &[], "", &page, page_url),
+        Err(e) => {
+            log_error!("Saved search {} failed: {}", saved.name, e);
+            ListHtml::Page(templates::error_fragment(&format!("Search failed: {}", e)))
+        }
+    };
+    respond(state, request, html.into_response(state)).map_err(|_| ())
+}
+
+fn handle_delete_saved_search(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    search_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let Some(username) = session_username(state, session_id) else {
+        return redirect_to_login(state, request);
+    };
+    if let Err(e) = state
+        .prefs
+        .update(&username, |prefs| prefs.saved_searches.retain(|s| s.id != search_id))
+    {
+        log_error!("Failed to persist preferences: {}", e);
+    }
+    let html = templates::saved_search_list(&state.prefs.get(&username).saved_searches);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Destroy everything in a Trash or Junk mailbox
+fn handle_empty_mailbox(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    mailbox_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let mailbox = match client.get_mailboxes() {
+        Ok(mailboxes) => mailboxes.into_iter().find(|m| m.id == mailbox_id),
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            return AppError::jmap("Failed to empty mailbox", e).respond(state, request);
+        }
+    };
+    // Only the disposable mailboxes can be emptied wholesale
+    let Some(mailbox) = mailbox.filter(|m| matches!(m.role.as_deref(), Some("trash") | Some("junk") | Some("spam"))) else {
+        let html = templates::error_fragment(i18n::t("actions.only_disposable"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    };
+
+    let filter = serde_json::json!({ "inMailbox": mailbox.id });
+    match retention::destroy_matching(&client, filter).and_then(|count| Ok((count, client.get_mailboxes()?))) {
+        Ok((count, mailboxes)) => {
+            log_info!("Emptied {}: {} messages destroyed", mailbox.name, count);
+            let html = templates::notice_fragment(&i18n::tf("actions.emptied", &[("name", &mailbox.name), ("n", &count.to_string())]))
+                + &templates::unread_counts_oob(&mailboxes);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to empty {}: {}", mailbox.name, e);
+            AppError::jmap(&format!("Failed to empty {}", mailbox.name), e).respond(state, request)
+        }
+    }
+}
+
+/// Query every email except those filed in Trash or Junk
+/// Everything except Trash and Junk
+fn all_mail_filter(client: &JmapClient) -> Result<serde_json::Value, JmapError> {
+    let excluded: Vec<String> = client
+        .get_mailboxes()?
+        .into_iter()
+        .filter(|m| matches!(m.role.as_deref(), Some("trash") | Some("junk") | Some("spam")))
+        .map(|m| m.id)
+        .collect();
+    log_debug!("All Mail query excluding mailboxes: {:?}", excluded);
+
+    Ok(if excluded.is_empty() {
+        serde_json::Value::Null
+    } else {
+        Filter::from(Condition::InMailboxOtherThan(excluded)).to_json()
+    })
+}
+
+fn handle_email(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    email_id: &str,
+    options: &templates::ViewOptions,
+    request: Request,
+) -> Result<(), ()> {
+    log_info!("Fetching single email: {}", email_id);
+
+
+    match client.get_email(email_id) {
+        Ok(Some(mut email)) => {
+            log_info!(
+                "Fetched email {} - \"{}\"",
+                email.id,
+                email.subject.as_deref().unwrap_or("(no subject)")
+            );
+            // Without sending there are no identities to edit it with
+            let draft = email.keywords.get("$draft").copied().unwrap_or(false);
+            if draft && account_has(state, session_id, &client, |a| a.has_submission) {
+                return serve_draft(state, session_id, &client, &email, request);
+            }
+
+            // Opening a message marks it read
+            let mut marked_seen = false;
+            if !email.keywords.get("$seen").copied().unwrap_or(false) {
+                match client.set_keywords(std::slice::from_ref(&email.id), &[("$seen", true)]) {
+                    Ok(_) => {
+                        email.keywords.insert("$seen".to_string(), true);
+                        marked_seen = true;
+                    }
+                    Err(e) => log_error!("Failed to mark email {} as read: {}", email.id, e),
+                }
+            }
+            let mailboxes = client.get_mailboxes().unwrap_or_else(|e| {
+                log_error!("Failed to fetch mailboxes: {}", e);
+                Vec::new()
+            });
+
+            let verified_domain = verified_sender_domain(state, &email);
+            email.invite = read_invite(&client, &email);
+            email.bounce = read_bounce(&client, &email);
+            remember_viewed(state, session_id, &client, Viewed::Email(&email.id));
+            let mut html = templates::email_view(&email, &mailboxes, verified_domain.as_deref(), options);
+            if marked_seen {
+                fill_thread_sizes(&client, std::slice::from_mut(&mut email));
+                html.push_str(&seen_changed_oob(&email, &mailboxes));
+            }
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Ok(None) => {
+            log_error!("Email not found: {}", email_id);
+            let html = templates::error_fragment("Email not found");
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch email {}: {}", email_id, e);
+            AppError::jmap("Failed to load email", e).respond(state, request)
+        }
+    }
+}
+
+fn handle_set_seen(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    seen: bool,
+    request: Request,
+) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    let ids = std::slice::from_ref(&email_id);
+    if let Err(e) = client.set_keywords(ids, &[("$seen", seen)]) {
+        log_error!("Failed to update $seen on {}: {}", email_id, e);
+        let correction = email_as_it_is(&client, &email_id);
+        return roll_back(state, AppError::jmap("Failed to update message", e), correction, request);
+    }
+
+    let mut html = templates::seen_toggle(&email_id, seen);
+    match client.get_emails(ids).and_then(|emails| Ok((emails, client.get_mailboxes()?))) {
+        Ok((mut emails, mailboxes)) => {
+            fill_thread_sizes(&client, &mut emails);
+            if let Some(email) = emails.first() {
+                html.push_str(&seen_changed_oob(email, &mailboxes));
+            }
+        }
+        Err(e) => log_error!("Failed to refresh email {}: {}", email_id, e),
+    }
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Pin a message above the others in its mailbox, or unpin it, answering
+/// with the opposite control and telling the open list to refresh
+fn handle_pin(state: &Arc<AppState>, client: JmapClient, email_id: &str, pinned: bool, request: Request) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    if let Err(e) = client.set_keywords(std::slice::from_ref(&email_id), &[(PINNED_KEYWORD, pinned)]) {
+        log_error!("Failed to update {} on {}: {}", PINNED_KEYWORD, email_id, e);
+        return AppError::jmap("Failed to update message", e).respond(state, request);
+    }
+    let response = html_response(state, templates::pin_toggle(&email_id, pinned))
+        .with_header(Header::from_bytes(&b"HX-Trigger"[..], &b"mailboxesChanged"[..]).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+/// Mute or unmute a whole conversation, answering with the opposite
+/// control
+fn handle_mute(state: &Arc<AppState>, client: JmapClient, thread_id: &str, muted: bool, request: Request) -> Result<(), ()> {
+    let thread_id = thread_id.to_string();
+    let done = client.get_threads(std::slice::from_ref(&thread_id)).and_then(|threads| {
+        let ids = threads.into_iter().next().map(|t| t.email_ids).unwrap_or_default();
+        mute::set(&client, &ids, muted)
+    });
+    if let Err(e) = done {
+        log_error!("Failed to update {} on thread {}: {}", mute::KEYWORD, thread_id, e);
+        return AppError::jmap("Failed to update conversation", e).respond(state, request);
+    }
+    let html = templates::mute_toggle(&thread_id, muted);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn handle_set_flagged(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    flagged: bool,
+    request: Request,
+) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    match client.set_keywords(std::slice::from_ref(&email_id), &[("$flagged", flagged)]) {
+        Ok(_) => {
+            let html = templates::flag_stars_oob(&email_id, flagged);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to update $flagged on {}: {}", email_id, e);
+            let correction = email_as_it_is(&client, &email_id);
+            roll_back(state, AppError::jmap("Failed to update message", e), correction, request)
+        }
+    }
+}
+
+/// Move a message to Trash, or destroy it once confirmed if it's already there
+fn handle_delete(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    confirmed: bool,
+    request: Request,
+) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    let lookup = client.get_emails(std::slice::from_ref(&email_id)).and_then(|emails| {
+        let mailboxes = client.get_mailboxes()?;
+        Ok((emails.into_iter().next(), mailboxes))
+    });
+    let (email, mailboxes) = match lookup {
+        Ok((Some(email), mailboxes)) => (email, mailboxes),
+        Ok((None, _)) => {
+            let html = templates::error_fragment("Email not found");
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+        Err(e) => {
+            log_error!("Failed to look up email {} for deletion: {}", email_id, e);
+            return AppError::jmap("Failed to delete message", e).respond(state, request);
+        }
+    };
+
+    let trash = mailboxes.iter().find(|m| m.role.as_deref() == Some("trash"));
+    let in_trash = trash.is_some_and(|t| email.mailbox_ids.get(&t.id).copied().unwrap_or(false));
+    let ids = std::slice::from_ref(&email_id);
+
+    let result = match trash {
+        Some(trash) if !in_trash => client.move_emails(ids, &trash.id).map(|_| i18n::t("actions.moved_to_trash")),
+        // Already in Trash, or the account has none: destroying needs a confirmation
+        _ if !confirmed => {
+            let html = templates::delete_confirmation(&email_id);
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+        _ => client.destroy_emails(ids).map(|_| i18n::t("actions.destroyed")),
+    };
+
+    match result {
+        Ok(message) => {
+            log_info!("Deleted email {}: {}", email_id, message);
+            let mut html = templates::notice_fragment(message);
+            html.push_str(&templates::email_row_remove_oob(&email_id));
+            match client.get_mailboxes() {
+                Ok(mailboxes) => html.push_str(&templates::unread_counts_oob(&mailboxes)),
+                Err(e) => log_error!("Failed to refresh unread counts: {}", e),
+            }
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to delete email {}: {}", email_id, e);
+            AppError::jmap("Failed to delete message", e).respond(state, request)
+        }
+    }
+}
+
+/// Set `thread_size` on emails about to be rendered as list rows, so rows in
+/// a conversation get their badge and open the thread view
+/// Largest calendar part read for an invitation
+const MAX_CALENDAR_BYTES: u64 = 256 * 1024;
+
+/// Largest part of a bounce read, of its report and of the message returned
+const MAX_REPORT_BYTES: u64 = 64 * 1024;
+
+/// The text of `part` of `email`, up to `limit` bytes of it
+fn read_part(client: &JmapClient, email: &Email, part: &BodyPart, name: &str, limit: u64) -> Option<String> {
+    let blob_id = part.blob_id.as_deref()?;
+    let content_type = part.r#type.as_deref().unwrap_or("application/octet-stream");
+    let download = client
+        .download_blob(blob_id, name, content_type)
+        .map_err(|e| log_error!("Failed to download {} {} of {}: {}", name, blob_id, email.id, e))
+        .ok()?;
+    let mut data = Vec::new();
+    if let Err(e) = download.reader.take(limit).read_to_end(&mut data) {
+        log_error!("Failed to read {} {} of {}: {}", name, blob_id, email.id, e);
+        return None;
+    }
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// The meeting invitation in `email`'s calendar part, if it has one
+fn read_invite(client: &JmapClient, email: &Email) -> Option<ical::Invite> {
+    let text = read_part(client, email, email.calendar_part()?, "invite.ics", MAX_CALENDAR_BYTES)?;
+    ical::Invite::parse(&text)
+}
+
+/// Who `email` says a message didn't reach, if it is a bounce, with the
+/// sent message it is about when that is still in the account
+fn read_bounce(client: &JmapClient, email: &Email) -> Option<Bounce> {
+    let (status, returned) = bounce::report_parts(email)?;
+    let status = read_part(client, email, status, "report", MAX_REPORT_BYTES)?;
+    let returned = returned.and_then(|part| read_part(client, email, part, "returned", MAX_REPORT_BYTES));
+    let mut bounce = Bounce::parse(&status, returned.as_deref())?;
+    if let Some(message_id) = &bounce.message_id {
+        let filter = serde_json::json!({ "header": ["Message-ID", message_id] });
+        // The bounce itself may carry the id too, in its References
+        match client.query_emails_filtered(filter, 2, 0) {
+            Ok(found) => bounce.original_id = found.ids.into_iter().find(|id| *id != email.id),
+            Err(e) => log_error!("Failed to look up the message {} bounced: {}", email.id, e),
+        }
+    }
+    Some(bounce)
+}
+
+/// Answer a meeting invitation: an iTIP REPLY to its organizer from the
+/// identity it was addressed to, then the invitation again with a note
+fn handle_rsvp(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    email_id: &str,
+    mut request: Request,
+) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_submission) {
+        return unsupported(state, request, "unsupported.invitations");
+    }
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read RSVP body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let Some(partstat) = ical::PartStat::parse(Params::parse(&body).value("partstat")) else {
+        let html = templates::error_fragment(i18n::t("calendar.unknown_answer"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    };
+
+    let lookup = client.get_email(email_id).and_then(|email| Ok((email, client.get_identities()?)));
+    let (email, identities) = match lookup {
+        Ok((Some(email), identities)) => (email, identities),
+        Ok((None, _)) => return serve_404(state, request),
+        Err(e) => {
+            log_error!("Failed to load invitation {}: {}", email_id, e);
+            return AppError::jmap("Failed to load invitation", e).respond(state, request);
+        }
+    };
+    let Some((invite, organizer)) = read_invite(&client, &email).and_then(|i| {
+        let organizer = i.organizer.clone()?;
+        Some((i, organizer))
+    }) else {
+        let html = templates::error_fragment(i18n::t("calendar.no_invitation"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    };
+    // Invitations to a list have none of ours among the attendees
+    let Some(identity) = identities
+        .iter()
+        .find(|i| invite.attendee(&i.email).is_some())
+        .or_else(|| identities.first())
+    else {
+        let html = templates::error_fragment(i18n::t("calendar.no_identity"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    };
+
+    let summary = invite.summary.as_deref().unwrap_or("");
+    let name = if identity.name.is_empty() { identity.email.as_str() } else { identity.name.as_str() };
+    let (subject, text) = match partstat {
+        ical::PartStat::Accepted => ("calendar.subject_accepted", "calendar.body_accepted"),
+        ical::PartStat::Tentative => ("calendar.subject_tentative", "calendar.body_tentative"),
+        ical::PartStat::Declined => ("calendar.subject_declined", "calendar.body_declined"),
+    };
+    let message_id = email.message_id.clone().unwrap_or_default();
+    let reply = OutgoingEmail {
+        to: vec![EmailAddress {
+            name: organizer.name.clone(),
+            email: Some(organizer.email.clone()),
+        }],
+        subject: i18n::tf(subject, &[("summary", summary)]),
+        text_body: format!("{}\n", i18n::tf(text, &[("name", name)])),
+        html_body: None,
+        in_reply_to: message_id.clone(),
+        references: email.references.clone().unwrap_or_default().into_iter().chain(message_id).collect(),
+        calendar: Some(invite.reply(&identity.email, Some(&identity.name), partstat, dates::now())),
+        ..Default::default()
+    };
+    let html = match client.send_email(identity, &reply) {
+        Ok(sent_id) => {
+            log_info!("Answered invitation {} with {} as {}", invite.uid, partstat.name(), sent_id);
+            templates::calendar_invite(&invite, &email.id, Some(&organizer.email))
+        }
+        Err(e) => {
+            log_error!("Failed to answer invitation {}: {}", invite.uid, e);
+            format!(
+                "{}{}",
+                templates::error_fragment(&i18n::tf("calendar.send_failed", &[("error", &e.to_string())])),
+                templates::calendar_invite(&invite, &email.id, None)
+            )
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn fill_thread_sizes(client: &JmapClient, emails: &mut [Email]) {
+    let mut thread_ids: Vec<String> = emails.iter().filter_map(|e| e.thread_id.clone()).collect();
+    thread_ids.sort();
+    thread_ids.dedup();
+    match client.get_threads(&thread_ids) {
+        Ok(threads) => {
+            for email in emails.iter_mut() {
+                email.thread_size = threads
+                    .iter()
+                    .find(|t| Some(&t.id) == email.thread_id.as_ref())
+                    .map_or(0, |t| t.email_ids.len());
+            }
+        }
+        Err(e) => log_error!("Failed to fetch threads: {}", e),
+    }
+}
+
+/// Most messages a search within a conversation looks through
+const THREAD_SEARCH_LIMIT: u32 = 256;
+
+/// The messages of a conversation that match `query`. Email/query has no
+/// filter for a thread, so the text search is kept to the time between its
+/// first and last message, and what it finds to those in the thread.
+fn handle_thread_search(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    thread_id: &str,
+    query: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let thread_id = thread_id.to_string();
+    let query = query.trim();
+    let search = client.get_threads(std::slice::from_ref(&thread_id)).and_then(|threads| {
+        let ids = threads.into_iter().next().map(|t| t.email_ids).unwrap_or_default();
+        if query.is_empty() {
+            return Ok((ids.clone(), ids.len()));
+        }
+        let times: Vec<i64> = client
+            .get_emails(&ids)?
+            .iter()
+            .filter_map(|e| e.received_at.as_deref().and_then(dates::parse))
+            .collect();
+        let mut conditions = vec![serde_json::json!({ "text": query })];
+        if let (Some(first), Some(last)) = (times.iter().min(), times.iter().max()) {
+            conditions.push(serde_json::json!({ "after": dates::utc(*first) }));
+            conditions.push(serde_json::json!({ "before": dates::utc(last + 1) }));
+        }
+        let filter = serde_json::json!({ "operator": "AND", "conditions": conditions });
+        let found = client.query_emails_filtered(filter, THREAD_SEARCH_LIMIT, 0)?.ids;
+        let matches = ids.iter().filter(|id| found.contains(id)).cloned().collect();
+        Ok((matches, ids.len()))
+    });
+    match search {
+        Ok((matches, total)) => {
+            let html = templates::thread_matches(query, &matches, total);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to search thread {}: {}", thread_id, e);
+            AppError::jmap("Failed to search conversation", e).respond(state, request)
+        }
+    }
+}
+
+/// A whole conversation, opened from a list row. Unread messages in it are
+/// marked read.
+fn handle_thread(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    thread_id: &str,
+    focus: &str,
+    options: &templates::ViewOptions,
+    request: Request,
+) -> Result<(), ()> {
+    let thread_id = thread_id.to_string();
+    let lookup = client.get_threads(std::slice::from_ref(&thread_id)).and_then(|threads| {
+        let ids = threads.into_iter().next().map(|t| t.email_ids).unwrap_or_default();
+        client.get_emails_full(&ids)
+    });
+    let mut emails = match lookup {
+        Ok(emails) if !emails.is_empty() => emails,
+        Ok(_) => {
+            let html = templates::error_fragment("Conversation not found");
+            return respond(state, request, html_response(state, html)).map_err(|_| ());
+        }
+        Err(e) => {
+            log_error!("Failed to fetch thread {}: {}", thread_id, e);
+            return AppError::jmap("Failed to load conversation", e).respond(state, request);
+        }
+    };
+    emails.sort_by(|a, b| a.received_at.cmp(&b.received_at));
+    log_info!("Fetched thread {} with {} messages", thread_id, emails.len());
+
+    let unread: Vec<String> = emails
+        .iter()
+        .filter(|e| !e.keywords.get("$seen").copied().unwrap_or(false))
+        .map(|e| e.id.clone())
+        .collect();
+    let mut marked_seen = false;
+    if !unread.is_empty() {
+        match client.set_keywords(&unread, &[("$seen", true)]) {
+            Ok(_) => {
+                for email in emails.iter_mut().filter(|e| unread.contains(&e.id)) {
+                    email.keywords.insert("$seen".to_string(), true);
+                }
+                marked_seen = true;
+            }
+            Err(e) => log_error!("Failed to mark thread {} as read: {}", thread_id, e),
+        }
+    }
+    let mailboxes = client.get_mailboxes().unwrap_or_else(|e| {
+        log_error!("Failed to fetch mailboxes: {}", e);
+        Vec::new()
+    });
+
+    let thread_size = emails.len();
+    for email in &mut emails {
+        email.thread_size = thread_size;
+        email.invite = read_invite(&client, email);
+        email.bounce = read_bounce(&client, email);
+    }
+    let messages: Vec<(Email, Option<String>)> = emails
+        .into_iter()
+        .map(|email| {
+            let verified_domain = verified_sender_domain(state, &email);
+            (email, verified_domain)
+        })
+        .collect();
+    let mut html = templates::thread_view(&messages, focus, &mailboxes, options);
+    if marked_seen {
+        for (email, _) in messages.iter().filter(|(e, _)| unread.contains(&e.id)) {
+            if email.id == focus {
+                html.push_str(&templates::email_row_oob(email, &mailboxes));
+            } else {
+                html.push_str(&templates::email_rows_oob(std::slice::from_ref(email), &mailboxes));
+            }
+        }
+        html.push_str(&templates::unread_counts_oob(&mailboxes));
+    }
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Out-of-band swaps that bring the list row and mailbox unread counts in
+/// line after a message's $seen keyword changed
+fn seen_changed_oob(email: &Email, mailboxes: &[Mailbox]) -> String {
+    templates::email_row_oob(email, mailboxes) + &templates::unread_counts_oob(mailboxes)
+}
+
+/// A message's list row, stars and unread badges as the server has them,
+/// out of band, to put back what the page showed before an action failed
+fn email_as_it_is(client: &JmapClient, email_id: &str) -> String {
+    let ids = [email_id.to_string()];
+    match client.get_emails(&ids).and_then(|emails| Ok((emails, client.get_mailboxes()?))) {
+        Ok((mut emails, mailboxes)) => {
+            fill_thread_sizes(client, &mut emails);
+            emails
+                .first()
+                .map(|email| {
+                    let flagged = email.keywords.get("$flagged").copied().unwrap_or(false);
+                    seen_changed_oob(email, &mailboxes) + &templates::flag_stars_oob(email_id, flagged)
+                })
+                .unwrap_or_default()
+        }
+        Err(e) => {
+            log_error!("Failed to refresh email {}: {}", email_id, e);
+            String::new()
+        }
+    }
+}
+
+/// Answer an action the page showed as done before asking (app.js) but
+/// that failed: `correction` undoes it, and a toast says why, leaving the
+/// rest of the page as it is. A lapsed sign-in still goes to the login
+/// page.
+fn roll_back(state: &Arc<AppState>, error: AppError, correction: String, request: Request) -> Result<(), ()> {
+    if let AppError::AuthExpired = error {
+        return error.respond(state, request);
+    }
+    let html = correction + &templates::error_toast_oob(&error.to_string());
+    let response = html_response(state, html).with_header(Header::from_bytes(&b"HX-Reswap"[..], &b"none"[..]).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+/// Apply one action to every checked row of the list, batched into a single
+/// Email/set
+fn handle_bulk(state: &Arc<AppState>, client: JmapClient, mut request: Request) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read bulk action body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let ids: Vec<String> = form.get_all("email").map(str::to_string).collect();
+    let action = form.value("action");
+    if ids.is_empty() {
+        return respond(state, request, html_response(state, templates::html_escape(i18n::t("actions.none_selected")))).map_err(|_| ());
+    }
+
+    let lookup = client
+        .get_emails(&ids)
+        .and_then(|emails| Ok((emails, client.get_mailboxes()?)));
+    let (mut emails, mailboxes) = match lookup {
+        Ok(found) => found,
+        Err(e) => {
+            log_error!("Failed to look up emails for bulk {}: {}", action, e);
+            return AppError::jmap("Failed", e).respond(state, request);
+        }
+    };
+    let role = |role: &str| mailboxes.iter().find(|m| m.role.as_deref() == Some(role));
+    let filed_in = |e: &Email, id: &str| e.mailbox_ids.get(id).copied().unwrap_or(false);
+
+    let mut update = serde_json::Map::new();
+    let mut destroy = Vec::new();
+    let (summary, target, removes_rows) = match action {
+        "read" | "unread" => {
+            let seen = action == "read";
+            for e in &mut emails {
+                let value = if seen { serde_json::json!(true) } else { serde_json::Value::Null };
+                update.insert(e.id.clone(), serde_json::json!({ "keywords/$seen": value }));
+                e.keywords.insert("$seen".to_string(), seen);
+            }
+            (if seen { "actions.bulk_read" } else { "actions.bulk_unread" }, None, false)
+        }
+        "delete" => {
+            let trash = role("trash");
+            for e in &emails {
+                match trash {
+                    Some(trash) if !filed_in(e, &trash.id) => {
+                        update.insert(e.id.clone(), serde_json::json!({ "mailboxIds": { trash.id.clone(): true } }));
+                    }
+                    _ => destroy.push(e.id.clone()),
+                }
+            }
+            ("actions.bulk_deleted", None, true)
+        }
+        "move" | "archive" => {
+            let target = if action == "archive" {
+                role("archive")
+            } else {
+                form.get("mailbox").and_then(|id| mailboxes.iter().find(|m| m.id == id))
+            };
+            let Some(target) = target else {
+                let message = if action == "archive" { "actions.no_archive" } else { "actions.choose_mailbox" };
+                return respond(state, request, html_response(state, templates::html_escape(i18n::t(message)))).map_err(|_| ());
+            };
+            for e in &emails {
+                update.insert(e.id.clone(), serde_json::json!({ "mailboxIds": { target.id.clone(): true } }));
+            }
+            ("actions.bulk_moved", Some(target.name.clone()), true)
+        }
+        _ => return serve_404(state, request),
+    };
+    if !removes_rows {
+        fill_thread_sizes(&client, &mut emails);
+    }
+
+    let result = client
+        .set_emails(update, &destroy)
+        .and_then(|changed| Ok((changed, client.get_mailboxes()?)));
+    match result {
+        Ok((changed, mailboxes)) => {
+            log_info!("Bulk {} applied to {} of {} emails", action, changed, emails.len());
+            let n = changed.to_string();
+            let mut args = vec![("n", n.as_str())];
+            if let Some(name) = &target {
+                args.push(("name", name));
+            }
+            let mut html = templates::html_escape(&i18n::tf(summary, &args));
+            if removes_rows {
+                for e in &emails {
+                    html.push_str(&templates::email_row_remove_oob(&e.id));
+                }
+            } else {
+                html.push_str(&templates::email_rows_oob(&emails, &mailboxes));
+            }
+            html.push_str(&templates::unread_counts_oob(&mailboxes));
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Bulk {} failed: {}", action, e);
+            AppError::jmap("Failed", e).respond(state, request)
+        }
+    }
+}
+
+/// Move a message out of its current mailbox into the role=archive mailbox
+/// Archive a message. Its row is already hidden (app.js), and comes back
+/// if it can't be.
+fn handle_archive(state: &Arc<AppState>, client: JmapClient, email_id: &str, request: Request) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    let moved = client.get_mailboxes().and_then(|mailboxes| {
+        match mailboxes.iter().find(|m| m.role.as_deref() == Some("archive")) {
+            Some(archive) => client.move_emails(std::slice::from_ref(&email_id), &archive.id).map(Some),
+            None => Ok(None),
+        }
+    });
+    let error = match moved {
+        Ok(Some(_)) => None,
+        Ok(None) => Some(AppError::BadRequest(i18n::t("actions.no_archive").to_string())),
+        Err(e) => {
+            log_error!("Failed to archive email {}: {}", email_id, e);
+            Some(AppError::jmap("Failed to archive message", e))
+        }
+    };
+    if let Some(error) = error {
+        let correction = email_as_it_is(&client, &email_id);
+        return roll_back(state, error, correction, request);
+    }
+
+    log_info!("Archived email {}", email_id);
+    let mut html = templates::notice_fragment(i18n::t("actions.archived")) + &templates::email_row_remove_oob(&email_id);
+    match client.get_mailboxes() {
+        Ok(mailboxes) => html.push_str(&templates::unread_counts_oob(&mailboxes)),
+        Err(e) => log_error!("Failed to fetch mailboxes: {}", e),
+    }
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Run a plugin's button on a message, showing the notice or error it
+/// gives back
+fn handle_plugin_action(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    plugin: &str,
+    action: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let Some(plugin) = state.plugins.get(plugin) else {
+        return serve_404(state, request);
+    };
+    let email = match client.get_email(email_id) {
+        Ok(Some(email)) => email,
+        Ok(None) => return serve_404(state, request),
+        Err(e) => {
+            log_error!("Failed to fetch email {}: {}", email_id, e);
+            return AppError::jmap("Failed to fetch message", e).respond(state, request);
+        }
+    };
+    let html = match plugin.run_action(action, &email, &client) {
+        Ok(notice) => {
+            log_info!("Plugin {} ran {} on email {}", plugin.name(), action, email_id);
+            templates::notice_fragment(&notice)
+        }
+        Err(e) => {
+            log_error!("Plugin {} failed to run {} on email {}: {}", plugin.name(), action, email_id, e);
+            templates::error_fragment(&e)
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// Snooze a message until the time picked from the view's snooze menu
+fn handle_snooze(state: &Arc<AppState>, client: JmapClient, email_id: &str, mut request: Request) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read snooze request body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let Some(time) = snooze::resolve(form.value("until"), form.get("at")) else {
+        let html = templates::error_fragment(i18n::t("actions.snooze_past"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    };
+
+    let lookup = client
+        .get_emails(std::slice::from_ref(&email_id))
+        .and_then(|emails| Ok((emails, client.get_mailboxes()?)));
+    let (email, mailboxes) = match lookup {
+        Ok((emails, mailboxes)) => match emails.into_iter().next() {
+            Some(email) => (email, mailboxes),
+            None => return serve_404(state, request),
+        },
+        Err(e) => {
+            log_error!("Failed to look up email {} to snooze: {}", email_id, e);
+            return AppError::jmap("Failed to snooze message", e).respond(state, request);
+        }
+    };
+
+    let result = snooze::snooze(&client, &email, &mailboxes, time).and_then(|_| client.get_mailboxes());
+    match result {
+        Ok(mailboxes) => {
+            let html = templates::notice_fragment(&i18n::tf("actions.snoozed", &[("when", &dates::full_at(time))]))
+                + &templates::email_row_remove_oob(&email_id)
+                + &templates::unread_counts_oob(&mailboxes);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to snooze email {}: {}", email_id, e);
+            AppError::jmap("Failed to snooze message", e).respond(state, request)
+        }
+    }
+}
+
+/// Report a message as spam, moving it to the role=junk mailbox, or as not
+/// spam, moving it back to the Inbox. Without such a mailbox only the
+/// keywords change and the message stays where it is.
+fn handle_junk(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    junk: bool,
+    request: Request,
+) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    let mailboxes = match client.get_mailboxes() {
+        Ok(mailboxes) => mailboxes,
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes: {}", e);
+            return AppError::jmap("Failed to report message", e).respond(state, request);
+        }
+    };
+    let role = if junk { "junk" } else { "inbox" };
+    let target = mailboxes.iter().find(|m| m.role.as_deref() == Some(role));
+
+    let result = client
+        .report_junk(std::slice::from_ref(&email_id), junk, target.map(|m| m.id.as_str()))
+        .and_then(|_| client.get_mailboxes());
+    match result {
+        Ok(mailboxes) => {
+            log_info!("Reported email {} as {}", email_id, if junk { "spam" } else { "not spam" });
+            let mut html = templates::notice_fragment(i18n::t(if junk { "actions.spam" } else { "actions.not_spam" }));
+            if target.is_some() {
+                html.push_str(&templates::email_row_remove_oob(&email_id));
+            }
+            html.push_str(&templates::unread_counts_oob(&mailboxes));
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to report email {}: {}", email_id, e);
+            AppError::jmap("Failed to report message", e).respond(state, request)
+        }
+    }
+}
+
+/// File a message in another mailbox. `mode=copy` adds the mailbox and keeps
+/// the existing ones; anything else moves it there.
+fn handle_move(state: &Arc<AppState>, client: JmapClient, email_id: &str, mut request: Request) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read move request body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let target = form.value("mailbox");
+    let copy = form.get("mode") == Some("copy");
+    if target.is_empty() {
+        let html = templates::error_fragment(i18n::t("actions.choose_mailbox"));
+        return respond(state, request, html_response(state, html)).map_err(|_| ());
+    }
+
+    let ids = std::slice::from_ref(&email_id);
+    let result = if copy {
+        client.copy_emails(ids, target)
+    } else {
+        client.move_emails(ids, target)
+    };
+    let mailboxes = result.and_then(|_| client.get_mailboxes());
+
+    match mailboxes {
+        Ok(mailboxes) => {
+            let name = mailboxes
+                .iter()
+                .find(|m| m.id == target)
+                .map(|m| m.name.as_str())
+                .unwrap_or(target);
+            log_info!("{} email {} to {}", if copy { "Copied" } else { "Moved" }, email_id, name);
+            let notice = if copy { "actions.copied_to" } else { "actions.moved_to" };
+            let mut html = templates::notice_fragment(&i18n::tf(notice, &[("name", name)]));
+            if !copy {
+                html.push_str(&templates::email_row_remove_oob(&email_id));
+            }
+            html.push_str(&templates::unread_counts_oob(&mailboxes));
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to move email {} to {}: {}", email_id, target, e);
+            AppError::jmap("Failed to move message", e).respond(state, request)
+        }
+    }
+}
+
+/// Drafts open in the compose form rather than the read-only view
+fn serve_draft(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: &JmapClient,
+    email: &Email,
+    request: Request,
+) -> Result<(), ()> {
+    log_info!("Opening draft {} for editing", email.id);
+    match identities(state, session_id, client) {
+        Ok(identities) => {
+            let form = templates::ComposeForm::from_draft(email, &identities);
+            let html = templates::compose_page(&identities, &form, None);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch identities: {}", e);
+            AppError::jmap("Failed to load identities", e).respond(state, request)
+        }
+    }
+}
+
+/// The sender's domain if it passed aligned DKIM and has a cached BIMI logo
+fn verified_sender_domain(state: &Arc<AppState>, email: &Email) -> Option<String> {
+    let config = &state.config.bimi;
+    if !config.enabled {
+        return None;
+    }
+
+    let from = email.from.as_ref()?.first()?.email.as_deref()?;
+    let domain = bimi::address_domain(from)?;
+    let auth_results = email.authentication_results.as_deref().unwrap_or_default();
+    if !bimi::dkim_aligned_pass(auth_results, &domain, config.trusted_authserv_id.as_deref()) {
+        log_debug!("No aligned DKIM pass for {}, skipping BIMI", domain);
+        return None;
+    }
+
+    state.bimi.ensure(&domain, config).then_some(domain)
+}
+
+fn serve_bimi_logo(state: &Arc<AppState>, domain: &str, request: Request) -> Result<(), ()> {
+    // Only ever serve what the email view already cached; never fetch on demand
+    let Some(svg) = state.bimi.logo(domain) else {
+        return serve_404(state, request);
+    };
+
+    let len = svg.len();
+    let response = Response::from_data(svg)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"image/svg+xml"[..]).unwrap())
+        .with_header(Header::from_bytes(&b"Content-Length"[..], len.to_string()).unwrap())
+        .with_header(
+            Header::from_bytes(
+                &b"Content-Security-Policy"[..],
+                &b"default-src 'none'; style-src 'unsafe-inline'; sandbox"[..],
+            )
+            .unwrap(),
+        )
+        .with_header(Header::from_bytes(&b"X-Content-Type-Options"[..], &b"nosniff"[..]).unwrap())
+        .with_header(Header::from_bytes(&b"Cache-Control"[..], &b"private, max-age=3600"[..]).unwrap());
+    respond(state, request, response).map_err(|_| ())
+}
+
+/// A new message, filled in with what `link` gives, the body above the
+/// signature
+fn serve_compose(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    link: &mailto::Mailto,
+    request: Request,
+) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_submission) {
+        return unsupported(state, request, "unsupported.sending");
+    }
+    match identities(state, session_id, &client) {
+        Ok(identities) => {
+            let mut form = templates::ComposeForm::new(&identities);
+            form.to = link.to.join(", ");
+            form.cc = link.cc.join(", ");
+            form.bcc = link.bcc.join(", ");
+            form.subject = link.subject.clone();
+            form.body.insert_str(0, &link.body);
+            let html = templates::compose_page(&identities, &form, None);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch identities: {}", e);
+            AppError::jmap("Failed to load identities", e).respond(state, request)
+        }
+    }
+}
+
+/// Suggested recipients
+const SUGGESTIONS: usize = 8;
+
+/// Addresses for the recipient field named by `field`, matching what is
+/// being typed after its last separator. The session's address book is
+/// filled from recent mail the first time it's asked.
+fn serve_contact_suggestions(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    params: &Params,
+    request: Request,
+) -> Result<(), ()> {
+    let account_id = client.account_id().to_string();
+    let Some(needs_seed) = state.sessions.get(session_id, |s| s.contacts.get(&account_id).is_none_or(AddressBook::needs_seed))
+    else {
+        return redirect_to_login(state, request);
+    };
+    let field = params.value("field");
+    if !matches!(field, "to" | "cc" | "bcc") {
+        return serve_404(state, request);
+    }
+
+    if needs_seed {
+        match client.get_recent_correspondents(contacts::SEED_SIZE) {
+            Ok(emails) => {
+                state.sessions.update(session_id, |s| s.contacts.entry(account_id.clone()).or_default().seed(&emails));
+            }
+            // Suggest from what the lists have shown; try again next time
+            Err(e) => log_error!("Failed to fetch recent correspondents: {}", e),
+        }
+    }
+    let typed = contacts::last_entry(params.value(field));
+    let suggestions: Vec<EmailAddress> = state
+        .sessions
+        .get(session_id, |s| {
+            let contacts = s.contacts.get(&account_id);
+            contacts.map(|c| c.suggest(typed, SUGGESTIONS).into_iter().cloned().collect()).unwrap_or_default()
+        })
+        .unwrap_or_default();
+    let html = templates::contact_suggestions(&suggestions);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// The chips for the recipient field named by `field`, checked against
+/// the fields before it and the session's address book
+fn serve_recipient_chips(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    params: &Params,
+    request: Request,
+) -> Result<(), ()> {
+    let fields = ["to", "cc", "bcc"];
+    let Some(position) = fields.iter().position(|f| *f == params.value("field")) else {
+        return serve_404(state, request);
+    };
+    let earlier: Vec<&str> = fields[..position].iter().map(|f| params.value(f)).collect();
+    let field = params.value(fields[position]);
+    let Some(chips) =
+        state.sessions.get(session_id, |s| contacts::check(&earlier, field, s.contacts.get(client.account_id())))
+    else {
+        return redirect_to_login(state, request);
+    };
+    let html = templates::recipient_chips(&chips);
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+#[derive(Debug)]
+enum ReplyAction {
+    Reply,
+    ReplyAll,
+    Forward,
+    /// The sent message again, after it bounced
+    Resend,
+}
+
+fn serve_reply(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    email_id: &str,
+    action: ReplyAction,
+    request: Request,
+) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_submission) {
+        return unsupported(state, request, "unsupported.sending");
+    }
+    log_info!("Preparing {:?} for email {}", action, email_id);
+
+
+    let loaded = client
+        .get_email(email_id)
+        .and_then(|email| Ok((email, identities(state, session_id, &client)?)));
+    match loaded {
+        Ok((Some(email), identities)) => {
+            let form = match action {
+                ReplyAction::Reply => templates::ComposeForm::reply(&email, &identities, false, &reply_prefs(state, session_id)),
+                ReplyAction::ReplyAll => templates::ComposeForm::reply(&email, &identities, true, &reply_prefs(state, session_id)),
+                ReplyAction::Forward => templates::ComposeForm::forward(&email, &identities),
+                ReplyAction::Resend => templates::ComposeForm::resend(&email, &identities),
+            };
+            let html = templates::compose_page(&identities, &form, None);
+            respond(state, request, html_response(state, html)).map_err(|_| ())
         }
-        Ok(None) => {
-            log_error!("Email not found: {}", email_id_decoded);
+        Ok((None, _)) => {
+            log_error!("Email not found: {}", email_id);
             let html = templates::error_fragment("Email not found");
-            request.respond(html_response(html)).map_err(|_| ())
+            respond(state, request, html_response(state, html)).map_err(|_| ())
         }
         Err(e) => {
-            log_error!("Failed to fetch email {}: {}", email_id_decoded, e);
-            let html = templates::error_fragment(&format!("Failed to load email: {}", e));
-            request.respond(html_response(html)).map_err(|_| ())
+            log_error!("Failed to prepare reply to {}: {}", email_id, e);
+            AppError::jmap("Failed to load email", e).respond(state, request)
         }
     }
 }
 
-fn handle_email_raw(
+/// Send the composed message, or with `save_only` just store it in Drafts
+fn handle_compose(
     state: &Arc<AppState>,
     session_id: &Uuid,
-    email_id: &str,
+    client: JmapClient,
+    save_only: bool,
+    mut request: Request,
+) -> Result<(), ()> {
+    if !account_has(state, session_id, &client, |a| a.has_submission) {
+        return unsupported(state, request, "unsupported.sending");
+    }
+    let content_type = header_value(&request, "content-type").unwrap_or_default();
+    let limit = upload_limit(state, session_id, state.config.server.limits.upload_bytes());
+    let mut body = Vec::new();
+    let read = request.as_reader().take(limit + 1).read_to_end(&mut body);
+    if read.is_err() {
+        log_error!("Failed to read compose request body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    if body.len() as u64 > limit {
+        log_error!("Compose request body exceeds {} bytes", limit);
+        let message = format!("Attachments are limited to {} in total", templates::format_size(limit));
+        return AppError::TooLarge(message).respond(state, request);
+    }
+
+    // The form is multipart when files are attached, urlencoded otherwise
+    let (fields, files) = match multipart::boundary(&content_type) {
+        Some(boundary) => match multipart::parse(&body, &boundary) {
+            Ok(parts) => {
+                let (files, fields): (Vec<_>, Vec<_>) =
+                    parts.into_iter().partition(|p| p.filename.is_some());
+                let fields = fields
+                    .into_iter()
+                    .map(|p| (p.name, String::from_utf8_lossy(&p.data).into_owned()))
+                    .collect();
+                (fields, files)
+            }
+            Err(e) => {
+                log_error!("Failed to parse multipart compose body: {}", e);
+                return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+            }
+        },
+        None => (Params::parse(&String::from_utf8_lossy(&body)), Vec::new()),
+    };
+
+    let mut form = templates::ComposeForm::default();
+    for (key, value) in fields {
+        match key.as_str() {
+            "identity" => form.identity_id = value,
+            "to" => form.to = value,
+            "cc" => form.cc = value,
+            "bcc" => form.bcc = value,
+            "subject" => form.subject = value,
+            "body" => form.body = value,
+            "in_reply_to" => form.in_reply_to = value,
+            "references" => form.references = value,
+            "draft_id" => form.draft_id = value,
+            "attachment" => match serde_json::from_str(&value) {
+                Ok(attachment) => form.attachments.push(attachment),
+                Err(e) => log_error!("Ignoring malformed attachment field: {}", e),
+            },
+            _ => {}
+        }
+    }
+
+    let identities = match identities(state, session_id, &client) {
+        Ok(identities) => identities,
+        Err(e) => {
+            log_error!("Failed to fetch identities: {}", e);
+            return AppError::jmap("Failed to load identities", e).respond(state, request);
+        }
+    };
+
+    // Browsers send an empty file part when nothing was picked
+    for file in files.into_iter().filter(|f| !f.data.is_empty()) {
+        let content_type = file
+            .content_type
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        match client.upload_blob(&file.data, &content_type) {
+            Ok(uploaded) => form.attachments.push(OutgoingAttachment {
+                blob_id: uploaded.blob_id,
+                r#type: uploaded.r#type,
+                name: file.filename,
+                size: uploaded.size,
+            }),
+            Err(e) => {
+                log_error!("Failed to upload attachment: {}", e);
+                let message = format!("Failed to upload attachment: {}", e);
+                let html = templates::compose_page(&identities, &form, Some(&message));
+                return respond(state, request, html_response(state, html)).map_err(|_| ());
+            }
+        }
+    }
+
+    let respond_with_error = |request: Request, message: &str| {
+        let html = templates::compose_page(&identities, &form, Some(message));
+        respond(state, request, html_response(state, html)).map_err(|_| ())
+    };
+
+    let Some(identity) = identities.iter().find(|i| i.id == form.identity_id) else {
+        return respond_with_error(request, "Choose an identity to send from");
+    };
+
+    let text_body = form.body.replace("\r\n", "\n");
+    let parsed = EmailAddress::parse_list(&form.to).and_then(|to| {
+        Ok(OutgoingEmail {
+            to,
+            cc: EmailAddress::parse_list(&form.cc)?,
+            bcc: EmailAddress::parse_list(&form.bcc)?,
+            subject: form.subject.clone(),
+            html_body: templates::html_body(&text_body, identity),
+            text_body: text_body.clone(),
+            in_reply_to: form.in_reply_to.split_whitespace().map(String::from).collect(),
+            references: form.references.split_whitespace().map(String::from).collect(),
+            attachments: form.attachments.clone(),
+            calendar: None,
+            draft_id: (!form.draft_id.is_empty()).then(|| form.draft_id.clone()),
+        })
+    });
+
+    if save_only {
+        // Drafts may be incomplete, so only malformed addresses are an error
+        let email = match parsed {
+            Ok(email) => email,
+            Err(e) => return respond_with_error(request, &e),
+        };
+        return match client.save_draft(identity, &email) {
+            Ok(draft_id) => {
+                log_info!("Saved draft {} for session {}", draft_id, session_id);
+                form.draft_id = draft_id;
+                let html = format!(
+                    "{}{}",
+                    templates::notice_fragment("Draft saved"),
+                    templates::compose_page(&identities, &form, None)
+                );
+                respond(state, request, html_response(state, html)).map_err(|_| ())
+            }
+            Err(e) => {
+                log_error!("Failed to save draft: {}", e);
+                respond_with_error(request, &format!("Failed to save draft: {}", e))
+            }
+        };
+    }
+
+    let email = match parsed {
+        Ok(email) if email.to.is_empty() && email.cc.is_empty() && email.bcc.is_empty() => {
+            return respond_with_error(request, "Add at least one recipient");
+        }
+        Ok(email) => email,
+        Err(e) => return respond_with_error(request, &e),
+    };
+
+    match client.send_email(identity, &email) {
+        Ok(email_id) => {
+            log_info!("Sent email {} for session {}", email_id, session_id);
+            let recipients: Vec<_> = email.to.iter().chain(&email.cc).chain(&email.bcc).cloned().collect();
+            let html = templates::notice_fragment(&format!(
+                "Message sent to {}",
+                templates::format_addresses(&recipients)
+            ));
+            respond(state, request, html_response(state, html)).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to send email: {}", e);
+            respond_with_error(request, &format!("Failed to send: {}", e))
+        }
+    }
+}
+
+/// Upload the files picked in the compose form as they arrive, each
+/// streamed to the JMAP server without being held here, and answer with
+/// their lines for the form's attachment list. Runs on a thread of its own,
+/// since a large file takes a while to come in.
+fn handle_attachment_upload(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, request: Request) -> Result<(), ()> {
+    let content_type = header_value(&request, "content-type").unwrap_or_default();
+    let Some(boundary) = multipart::boundary(&content_type) else {
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    };
+    let limit = upload_limit(state, session_id, state.config.compose.max_attachment_bytes().min(client.max_upload_size()));
+
+    answer_on_thread(state, request, move |state, request| {
+        let mut files = multipart::Files::new(request.as_reader(), &boundary, limit);
+        let mut html = String::new();
+        loop {
+            let file = match files.next_file() {
+                Ok(Some(file)) => file,
+                Ok(None) => break,
+                Err(e) => {
+                    log_error!("Failed to read attachment upload: {}", e);
+                    html.push_str(&templates::error_fragment("Failed to read request"));
+                    break;
+                }
+            };
+            // Browsers send an empty file part when nothing was picked
+            if file.filename.is_empty() {
+                continue;
+            }
+            let content_type = file.content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+            match client.upload_blob_stream(&mut files, &content_type) {
+                Ok(uploaded) => html.push_str(&templates::compose_attachment(&OutgoingAttachment {
+                    blob_id: uploaded.blob_id,
+                    r#type: uploaded.r#type,
+                    name: Some(file.filename),
+                    size: uploaded.size,
+                })),
+                Err(_) if files.over_limit() => {
+                    log_error!("Attachment {} exceeds {} bytes", file.filename, limit);
+                    html.push_str(&templates::error_fragment(&format!(
+                        "Attachments are limited to {} each",
+                        templates::format_size(limit)
+                    )));
+                    break;
+                }
+                Err(e) => {
+                    log_error!("Failed to upload attachment: {}", e);
+                    html.push_str(&templates::error_fragment(&format!("Failed to upload attachment: {}", e)));
+                    break;
+                }
+            }
+        }
+        drop(files);
+        html_response(state, html)
+    })
+}
+
+/// Answer `request` from a thread of its own with what `answer` makes of
+/// it, for a body that takes a while to come in or go on to the JMAP
+/// server
+fn answer_on_thread(
+    state: &Arc<AppState>,
     request: Request,
+    answer: impl FnOnce(&Arc<AppState>, &mut Request) -> BoxResponse + Send + 'static,
 ) -> Result<(), ()> {
-    let email_id_decoded = urlencoding_decode(email_id);
-    log_info!(
-        "Fetching raw email: {} (decoded: {})",
-        email_id,
-        email_id_decoded
-    );
+    access_log::note_response(200, None);
+    let state = Arc::clone(state);
+    let request_id = log::request_id();
+    let language = i18n::current();
+    thread::spawn(move || {
+        log::set_request_id(request_id);
+        i18n::select(language);
+        templates::mount(&state.config.server.base_path);
+        let mut request = request;
+        let response = with_security_headers(&state, answer(&state, &mut request));
+        let url = request.url().to_string();
+        if let Err(e) = request.respond(response) {
+            log_error!("Failed to answer {}: {}", url, e);
+        }
+    });
+    Ok(())
+}
 
-    let client = match get_client(state, session_id) {
-        Some(c) => c,
-        None => {
-            log_error!("No client found for session: {}", session_id);
-            return redirect_to_login(request);
+/// Make room for a file from the compose form too large to send in one
+/// go (`src/uploads.rs`), answering with the token its chunks go to
+fn handle_upload_start(state: &Arc<AppState>, session_id: &Uuid, client: JmapClient, mut request: Request) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+    let form = Params::parse(&body);
+    let (Some(name), Some(size)) = (form.get("name").filter(|n| !n.is_empty()), form.get_as::<u64>("size")) else {
+        return AppError::BadRequest("Missing file name or size".to_string()).respond(state, request);
+    };
+    let limit = upload_limit(state, session_id, state.config.compose.max_attachment_bytes().min(client.max_upload_size()));
+    if size > limit {
+        log_error!("Attachment {} exceeds {} bytes", name, limit);
+        let message = format!("Attachments are limited to {} each", templates::format_size(limit));
+        return AppError::TooLarge(message).respond(state, request);
+    }
+    let content_type = form.get("type").filter(|t| !t.is_empty()).unwrap_or("application/octet-stream");
+    match state.uploads.start(session_id, name, content_type, size) {
+        Ok(Some(token)) => {
+            let body = serde_json::json!({ "id": token, "chunk": uploads::CHUNK_BYTES }).to_string();
+            respond(state, request, json_response(state, body)).map_err(|_| ())
+        }
+        Ok(None) => AppError::BadRequest("Wait for the other attachments to finish uploading".to_string()).respond(state, request),
+        Err(e) => {
+            log_error!("Failed to make room for attachment {}: {}", name, e);
+            let html = templates::error_fragment("Failed to upload attachment");
+            respond(state, request, html_response(state, html).with_status_code(500)).map_err(|_| ())
+        }
+    }
+}
+
+/// How many bytes of an upload have arrived, to carry on from there after
+/// a dropped connection
+fn serve_upload_progress(state: &Arc<AppState>, session_id: &Uuid, token: &str, request: Request) -> Result<(), ()> {
+    match state.uploads.received(session_id, token) {
+        Some(received) => {
+            let body = serde_json::json!({ "received": received }).to_string();
+            respond(state, request, json_response(state, body)).map_err(|_| ())
         }
+        None => serve_404(state, request),
+    }
+}
+
+/// Take a chunk of an upload, sent as starting `offset` bytes into the
+/// file. The answer is how far the upload has got (202), or where to send
+/// from (409) for a chunk that doesn't start there; the last chunk's is
+/// the file's line for the attachment list once it is on the JMAP server.
+fn handle_upload_chunk(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    token: String,
+    offset: Option<u64>,
+    request: Request,
+) -> Result<(), ()> {
+    let Some(offset) = offset else {
+        return AppError::BadRequest("Missing offset".to_string()).respond(state, request);
     };
+    let session_id = *session_id;
+    answer_on_thread(state, request, move |state, request| {
+        let failed = |status: u16, message: &str| html_response(state, templates::error_fragment(message)).with_status_code(status);
+        let progress = |status: u16, received: u64| {
+            let body = serde_json::json!({ "received": received }).to_string();
+            json_response(state, body).with_status_code(status)
+        };
+        let mut chunk = Vec::new();
+        if let Err(e) = request.as_reader().take(uploads::CHUNK_BYTES + 1).read_to_end(&mut chunk) {
+            log_warn!("Failed to read a chunk of upload {}: {}", token, e);
+            return failed(400, "Failed to read request");
+        }
+        let mut upload = match state.uploads.add(&session_id, &token, offset, &chunk) {
+            Ok(Added::Partial(received)) => return progress(202, received),
+            Ok(Added::Complete(upload)) => upload,
+            Err(ChunkError::Offset(received)) => return progress(409, received),
+            Err(ChunkError::Gone) => return failed(404, "The upload is gone; attach the file again"),
+            Err(ChunkError::TooLong) => return failed(413, "The chunk runs past the end of the file"),
+            Err(ChunkError::Io(e)) => {
+                log_error!("Failed to write a chunk of upload {}: {}", token, e);
+                return failed(500, "Failed to upload attachment");
+            }
+        };
+        let content_type = upload.content_type.clone();
+        let uploaded = match upload.reader() {
+            Ok(reader) => client.upload_blob_stream(reader, &content_type).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match uploaded {
+            Ok(uploaded) => html_response(
+                state,
+                templates::compose_attachment(&OutgoingAttachment {
+                    blob_id: uploaded.blob_id,
+                    r#type: uploaded.r#type,
+                    name: Some(upload.name.clone()),
+                    size: uploaded.size,
+                }),
+            ),
+            Err(e) => {
+                log_error!("Failed to upload attachment: {}", e);
+                state.uploads.put_back(&token, upload);
+                failed(502, &format!("Failed to upload attachment: {}", e))
+            }
+        }
+    })
+}
+
+fn serve_retention_settings(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    request: Request,
+) -> Result<(), ()> {
+    let html = state
+        .sessions
+        .get(session_id, |s| {
+            templates::retention_settings(&state.config.retention, s.retention_enabled, &s.purge_log)
+        })
+        .unwrap_or_default();
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+fn handle_retention_toggle(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    mut request: Request,
+) -> Result<(), ()> {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        log_error!("Failed to read retention settings body");
+        return AppError::BadRequest("Failed to read request".to_string()).respond(state, request);
+    }
+
+    let enabled = Params::parse(&body).get("enabled") == Some("true");
+    log_info!("Retention policy {} for session {}", if enabled { "enabled" } else { "disabled" }, session_id);
+    state.sessions.update(session_id, |s| s.retention_enabled = enabled);
 
-    match client.get_email_raw(&email_id_decoded) {
-        Ok(Some(text)) => {
-            log_info!("Returning raw email {} ({} bytes)", email_id_decoded, text.len());
-            let response = text_response(text);
-            request.respond(response).map_err(|_| ())
+    serve_retention_settings(state, session_id, request)
+}
+
+fn handle_retention_run(
+    state: &Arc<AppState>,
+    session_id: &Uuid,
+    client: JmapClient,
+    request: Request,
+) -> Result<(), ()> {
+    log_info!("Running retention purge on demand for session {}", session_id);
+    retention::run_for_session(state, session_id, &client);
+    serve_retention_settings(state, session_id, request)
+}
+
+fn handle_email_raw(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    log_info!("Fetching raw email: {}", email_id);
+
+
+    match client.download_email_raw(email_id) {
+        Ok(Some(download)) => {
+            log_info!("Streaming raw email {} ({:?} bytes)", email_id, download.length);
+            let response = stream_response(download.reader, download.length, "text/plain; charset=utf-8");
+            respond_streaming(state, request, response)
         }
         Ok(None) => {
-            log_error!("Raw email not found: {}", email_id_decoded);
+            log_error!("Raw email not found: {}", email_id);
             let response = Response::from_string("Email not found").with_status_code(404);
-            request.respond(response).map_err(|_| ())
+            respond(state, request, response).map_err(|_| ())
         }
         Err(e) => {
-            log_error!("Failed to fetch raw email {}: {}", email_id_decoded, e);
+            log_error!("Failed to fetch raw email {}: {}", email_id, e);
             let response =
                 Response::from_string(format!("Failed to load email: {}", e)).with_status_code(500);
-            request.respond(response).map_err(|_| ())
+            respond(state, request, response).map_err(|_| ())
         }
     }
 }
 
-fn get_client(state: &Arc<AppState>, session_id: &Uuid) -> Option<JmapClient> {
-    state.sessions.get(session_id, |s| {
-        JmapClient::from_session(
-            s.username.clone(),
-            s.password.clone(),
-            s.api_url.clone(),
-            s.account_id.clone(),
-            s.download_url.clone(),
-        )
-    })
+fn handle_email_pdf(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    log_info!("Exporting email as PDF: {}", email_id);
+
+
+    match client.get_email(email_id) {
+        Ok(Some(email)) => {
+            let bytes = pdf::email_pdf(&email, &state.config.pdf);
+            log_info!("Returning PDF for email {} ({} bytes)", email.id, bytes.len());
+            let filename = export_filename(email.subject.as_deref().unwrap_or(""), "pdf");
+            let len = bytes.len();
+            let response = Response::from_data(bytes)
+                .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/pdf"[..]).unwrap())
+                .with_header(Header::from_bytes(&b"Content-Length"[..], len.to_string()).unwrap())
+                .with_header(
+                    Header::from_bytes(
+                        &b"Content-Disposition"[..],
+                        format!("inline; filename=\"{}\"", filename),
+                    )
+                    .unwrap(),
+                );
+            respond(state, request, response).map_err(|_| ())
+        }
+        Ok(None) => {
+            log_error!("Email not found for PDF export: {}", email_id);
+            let response = Response::from_string("Email not found").with_status_code(404);
+            respond(state, request, response).map_err(|_| ())
+        }
+        Err(e) => {
+            log_error!("Failed to fetch email {} for PDF: {}", email_id, e);
+            let response =
+                Response::from_string(format!("Failed to load email: {}", e)).with_status_code(500);
+            respond(state, request, response).map_err(|_| ())
+        }
+    }
+}
+
+/// Every header field of a message, for the view's "Show all headers"
+fn handle_email_headers(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let html = match client.get_email_headers(email_id) {
+        Ok(Some(headers)) => templates::all_headers(&headers),
+        Ok(None) => return serve_404(state, request),
+        Err(e) => {
+            log_error!("Failed to fetch headers of {}: {}", email_id, e);
+            return AppError::jmap("Failed to load headers", e).respond(state, request);
+        }
+    };
+    respond(state, request, html_response(state, html)).map_err(|_| ())
+}
+
+/// The original message as a .eml file to save, named after its subject
+fn handle_email_eml(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let email_id = email_id.to_string();
+    let email = match client.get_emails(std::slice::from_ref(&email_id)) {
+        Ok(emails) => emails.into_iter().next(),
+        Err(e) => {
+            log_error!("Failed to fetch email {} for download: {}", email_id, e);
+            let response = Response::from_string(format!("Failed to fetch email: {}", e)).with_status_code(502);
+            return respond(state, request, response).map_err(|_| ());
+        }
+    };
+    let Some((blob_id, subject)) = email.and_then(|e| Some((e.blob_id?, e.subject.unwrap_or_default()))) else {
+        return serve_404(state, request);
+    };
+    let filename = export_filename(&subject, "eml");
+    stream_blob(state, &client, &blob_id, &filename, "message/rfc822", "attachment", request)
+}
+
+/// A mailbox's messages in one download to keep as a backup: an mbox file,
+/// or with `format=zip` a zip of .eml files
+fn handle_mailbox_export(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    mailbox_id: &str,
+    query: &Params,
+    request: Request,
+) -> Result<(), ()> {
+    let as_zip = query.get("format") == Some("zip");
+
+    let mailbox = match client.get_mailboxes() {
+        Ok(mailboxes) => mailboxes.into_iter().find(|m| m.id == mailbox_id),
+        Err(e) => {
+            log_error!("Failed to fetch mailboxes for export: {}", e);
+            let response = Response::from_string(format!("Failed to fetch mailboxes: {}", e)).with_status_code(502);
+            return respond(state, request, response).map_err(|_| ());
+        }
+    };
+    let Some(mailbox) = mailbox else {
+        return serve_404(state, request);
+    };
+    let client = Arc::new(client);
+    let messages = match export::Messages::start(Arc::clone(&client), serde_json::json!({ "inMailbox": mailbox.id })) {
+        Ok(messages) => messages,
+        Err(e) => {
+            log_error!("Failed to list {} for export: {}", mailbox.name, e);
+            let response = Response::from_string(format!("Failed to list messages: {}", e)).with_status_code(502);
+            return respond(state, request, response).map_err(|_| ());
+        }
+    };
+
+    log_info!("Exporting {} as {}", mailbox.name, if as_zip { "zip" } else { "mbox" });
+    let (body, content_type, extension): (Box<dyn Read + Send>, _, _) = if as_zip {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        let entries = export::eml_entries(client, messages);
+        (Box::new(zip::ZipStream::new(entries, now)), "application/zip", "zip")
+    } else {
+        (Box::new(export::Mbox::new(client, messages)), "application/mbox", "mbox")
+    };
+    let filename = export_filename(&mailbox.name, extension);
+    let response = stream_response(body, None, content_type)
+        .with_header(Header::from_bytes(&b"Content-Disposition"[..], content_disposition("attachment", &filename)).unwrap());
+    respond_streaming(state, request, response)
+}
+
+/// Every attachment of a message in one zip, each blob fetched from the
+/// JMAP server only as the archive reaches it
+fn handle_attachments_zip(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let client = Arc::new(client);
+
+    let email = match client.get_email(email_id) {
+        Ok(Some(email)) => email,
+        Ok(None) => return serve_404(state, request),
+        Err(e) => {
+            log_error!("Failed to fetch email {} for its attachments: {}", email_id, e);
+            let response = Response::from_string(format!("Failed to fetch email: {}", e)).with_status_code(502);
+            return respond(state, request, response).map_err(|_| ());
+        }
+    };
+    let entries: Vec<zip::Entry> = email
+        .attachments
+        .iter()
+        .flatten()
+        .filter_map(|part| {
+            let blob_id = part.blob_id.clone()?;
+            let name = part.name.clone().unwrap_or_default();
+            let content_type = part
+                .r#type
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            let client = Arc::clone(&client);
+            let entry_name = name.clone();
+            let open: zip::Opener = Box::new(move || match client.download_blob(&blob_id, &name, &content_type) {
+                Ok(download) => Ok(download.reader as Box<dyn Read + Send>),
+                Err(e) => {
+                    log_error!("Failed to download blob {} into zip: {}", blob_id, e);
+                    Err(std::io::Error::other(e.to_string()))
+                }
+            });
+            Some(zip::Entry { name: entry_name, open })
+        })
+        .collect();
+    if entries.is_empty() {
+        return serve_404(state, request);
+    }
+
+    log_info!("Streaming {} attachments of {} as a zip", entries.len(), email.id);
+    let modified = email.received_at.as_deref().and_then(dates::parse).unwrap_or(0);
+    let filename = export_filename(email.subject.as_deref().unwrap_or(""), "zip");
+    let response = stream_response(zip::ZipStream::new(entries.into_iter(), modified), None, "application/zip")
+        .with_header(Header::from_bytes(&b"Content-Disposition"[..], content_disposition("attachment", &filename)).unwrap());
+    respond_streaming(state, request, response)
+}
+
+/// Proxy an attachment from the JMAP download endpoint. The name and type
+/// come from the owning email's attachment list when `email_id` is given.
+/// A `preview` of a raster image or PDF is shown in the page rather than
+/// downloaded, going by that listed type only.
+fn handle_blob_download(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    blob_id: &str,
+    email_id: Option<&str>,
+    preview: bool,
+    request: Request,
+) -> Result<(), ()> {
+    let part = match email_id.map(str::to_string) {
+        Some(email_id) => match client.get_emails(std::slice::from_ref(&email_id)) {
+            Ok(emails) => emails
+                .into_iter()
+                .flat_map(|e| e.attachments.unwrap_or_default())
+                .find(|a| a.blob_id.as_deref() == Some(blob_id)),
+            Err(e) => {
+                log_error!("Failed to look up attachment metadata in {}: {}", email_id, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let name = part
+        .as_ref()
+        .and_then(|p| p.name.clone())
+        .unwrap_or_else(|| "download".to_string());
+    let content_type = part
+        .as_ref()
+        .and_then(|p| p.r#type.clone())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let listed_type = part.as_ref().and_then(|p| p.r#type.as_deref()).map(str::to_ascii_lowercase);
+    let previewable = listed_type
+        .as_deref()
+        .is_some_and(|t| templates::INLINE_IMAGE_TYPES.contains(&t) || t == "application/pdf");
+    let disposition = if preview && previewable { "inline" } else { "attachment" };
+    stream_blob(state, &client, blob_id, &name, &content_type, disposition, request)
 }
 
-fn urlencoding_decode(s: &str) -> String {
-    let mut result = String::with_capacity(s.len());
-    let mut chars = s.chars().peekable();
+/// Serve an inline body part (the target of a cid: reference) by partId
+fn handle_email_part(
+    state: &Arc<AppState>,
+    client: JmapClient,
+    email_id: &str,
+    part_id: &str,
+    request: Request,
+) -> Result<(), ()> {
+    let email = match client.get_email(email_id) {
+        Ok(Some(email)) => email,
+        Ok(None) => return serve_404(state, request),
+        Err(e) => {
+            log_error!("Failed to fetch email {}: {}", email_id, e);
+            let response = Response::from_string(format!("Failed to fetch email: {}", e)).with_status_code(502);
+            return respond(state, request, response).map_err(|_| ());
+        }
+    };
+    let Some(part) = email.find_part(part_id) else {
+        return serve_404(state, request);
+    };
+    let Some(blob_id) = part.blob_id.as_deref() else {
+        return serve_404(state, request);
+    };
+    let name = part.name.clone().unwrap_or_else(|| format!("part-{}", part_id));
+    let content_type = part
+        .r#type
+        .clone()
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    // Only raster images may render inline; anything else is a download
+    let disposition = if templates::INLINE_IMAGE_TYPES.contains(&content_type.to_ascii_lowercase().as_str()) {
+        "inline"
+    } else {
+        "attachment"
+    };
+    stream_blob(state, &client, blob_id, &name, &content_type, disposition, request)
+}
 
-    while let Some(c) = chars.next() {
-        if c == '%' {
-            let hex: String = chars.by_ref().take(2).collect();
-            if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                result.push(byte as char);
+fn stream_blob(
+    state: &AppState,
+    client: &JmapClient,
+    blob_id: &str,
+    name: &str,
+    content_type: &str,
+    disposition: &str,
+    request: Request,
+) -> Result<(), ()> {
+    // A blob's bytes never change, so its id serves as the validator an
+    // If-Range resuming a download checks
+    let etag = format!("\"{}\"", blob_id);
+    let range = header_value(&request, "Range")
+        .filter(|_| header_value(&request, "If-Range").is_none_or(|validator| validator.trim() == etag))
+        .and_then(|range| ByteRange::parse(&range));
+    let download = client
+        .download_blob_range(blob_id, name, content_type, range.map(|r| r.header()).as_deref())
+        .map_err(|e| e.to_string())
+        .and_then(|download| range::serve(download, range));
+    match download {
+        Ok(download) if download.status == 416 => {
+            log_info!("Range {:?} of blob {} is past its end", range, blob_id);
+            let mut response = Response::empty(416);
+            if let Some(content_range) = &download.content_range {
+                response.add_header(Header::from_bytes(&b"Content-Range"[..], content_range.as_bytes()).unwrap());
+            }
+            respond(state, request, response).map_err(|_| ())
+        }
+        Ok(download) => {
+            log_info!("Streaming blob {} as {} ({})", blob_id, name, content_type);
+            // A PDF shown in the viewer has to be framed by our own pages, and
+            // browsers won't render one in a sandbox; its viewer runs no
+            // script of the document's on our origin anyway
+            let framed_pdf = disposition == "inline" && content_type.eq_ignore_ascii_case("application/pdf");
+            let (csp, frame_options) = if framed_pdf {
+                ("frame-ancestors 'self'", "SAMEORIGIN")
             } else {
-                result.push('%');
-                result.push_str(&hex);
+                ("sandbox", "DENY")
+            };
+            let mut response = stream_response(download.reader, download.length, content_type)
+                .with_status_code(download.status)
+                .with_header(Header::from_bytes(&b"Content-Disposition"[..], content_disposition(disposition, name)).unwrap())
+                .with_header(Header::from_bytes(&b"Content-Security-Policy"[..], csp).unwrap())
+                .with_header(Header::from_bytes(&b"X-Frame-Options"[..], frame_options).unwrap())
+                .with_header(Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap())
+                .with_header(Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap());
+            if let Some(content_range) = &download.content_range {
+                response.add_header(Header::from_bytes(&b"Content-Range"[..], content_range.as_bytes()).unwrap());
             }
-        } else if c == '+' {
-            result.push(' ');
-        } else {
-            result.push(c);
+            respond_streaming(state, request, response)
+        }
+        Err(e) => {
+            log_error!("Failed to download blob {}: {}", blob_id, e);
+            let response =
+                Response::from_string(format!("Failed to download attachment: {}", e)).with_status_code(502);
+            respond(state, request, response).map_err(|_| ())
         }
     }
+}
 
-    result
+/// Content-Disposition value with an ASCII fallback filename and the
+/// original name in RFC 5987 encoding
+fn content_disposition(kind: &str, name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' { c } else { '_' })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        kind,
+        fallback,
+        templates::url_encode(name)
+    )
 }
 
-fn parse_query_param<'a>(query_string: &'a str, key: &str) -> Option<&'a str> {
-    for pair in query_string.split('&') {
-        let mut parts = pair.splitn(2, '=');
-        if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
-            if k == key {
-                return Some(v);
-            }
+/// Build a header-safe filename from the subject, ending in `extension`
+fn export_filename(subject: &str, extension: &str) -> String {
+    let name: String = subject
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .take(60)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() {
+        format!("message.{}", extension)
+    } else {
+        format!("{}.{}", name, extension)
+    }
+}
+
+fn header_value(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+/// The account's identities, with the signatures typed in settings in
+/// place of their own, for filling in the compose form
+fn identities(state: &Arc<AppState>, session_id: &Uuid, client: &JmapClient) -> Result<Vec<Identity>, JmapError> {
+    let mut identities = client.get_identities()?;
+    let Some(username) = state.sessions.get(session_id, |s| s.username.clone()) else {
+        return Ok(identities);
+    };
+    let prefs = state.prefs.get(&username);
+    for identity in &mut identities {
+        if let Some(signature) = prefs.signatures.get(&identity.id) {
+            identity.text_signature = Some(signature.clone());
+            identity.html_signature = None;
+        }
+        if let Some(signature) = prefs.html_signatures.get(&identity.id) {
+            identity.html_signature = Some(signature.clone());
         }
     }
-    None
+    Ok(identities)
 }
+
@@ -0,0 +1,309 @@
+//! multipart/form-data request bodies (RFC 7578), as sent by the compose
+//! form when files are attached: parsed whole, or with `Files` a file at a
+//! time as the body arrives, for uploads too large to hold.
+
+use std::io::{self, Read};
+
+/// The most a part's headers may take up
+const MAX_HEADERS: usize = 16 * 1024;
+/// How much of the body is read at a time
+const CHUNK: usize = 64 * 1024;
+
+pub struct Part {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// The boundary parameter if the Content-Type is multipart/form-data
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    params.find_map(|p| {
+        let (key, value) = p.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+pub fn parse(body: &[u8], boundary: &str) -> Result<Vec<Part>, String> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut separator = b"\r\n".to_vec();
+    separator.extend_from_slice(&delimiter);
+
+    let start = find(body, &delimiter).ok_or("multipart body has no opening boundary")?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or("malformed multipart boundary line")?;
+
+        let header_end = find(rest, b"\r\n\r\n").ok_or("multipart part has no header terminator")?;
+        let headers = String::from_utf8_lossy(&rest[..header_end]);
+        rest = &rest[header_end + 4..];
+
+        let data_end = find(rest, &separator).ok_or("multipart body has no closing boundary")?;
+        let data = rest[..data_end].to_vec();
+        rest = &rest[data_end + separator.len()..];
+
+        let (name, filename, content_type) = part_headers(&headers);
+        parts.push(Part {
+            name: name.ok_or("multipart part has no name")?,
+            filename,
+            content_type,
+            data,
+        });
+    }
+}
+
+/// The name, filename and content type a part's headers give it
+fn part_headers(headers: &str) -> (Option<String>, Option<String>, Option<String>) {
+    let mut name = None;
+    let mut filename = None;
+    let mut content_type = None;
+    for line in headers.split("\r\n") {
+        let Some((header, value)) = line.split_once(':') else { continue };
+        if header.trim().eq_ignore_ascii_case("content-disposition") {
+            for (key, value) in disposition_params(value) {
+                match key.to_ascii_lowercase().as_str() {
+                    "name" => name = Some(value),
+                    "filename" => filename = Some(value),
+                    _ => {}
+                }
+            }
+        } else if header.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+    (name, filename, content_type)
+}
+
+/// A file part as `Files` reaches it
+pub struct FileHeader {
+    pub filename: String,
+    pub content_type: Option<String>,
+}
+
+/// The file parts of a multipart body read from `inner`, one after another.
+/// `next_file` moves to the next one, skipping the fields between, and the
+/// file's contents are then read from the `Files` itself, failing once
+/// there is more of one than `limit`. Only as much of the body as a chunk
+/// and a boundary line is held at a time.
+pub struct Files<R> {
+    inner: R,
+    limit: u64,
+    /// Of the current file so far
+    read: u64,
+    over_limit: bool,
+    /// "\r\n--boundary", which ends each part
+    separator: Vec<u8>,
+    /// Read from `inner` and not yet used
+    buffer: Vec<u8>,
+    eof: bool,
+    /// Where the body stands: in the preamble, in a part's contents, or
+    /// just past a part, at its separator
+    state: State,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    InPart,
+    AfterPart,
+    Done,
+}
+
+impl<R: Read> Files<R> {
+    pub fn new(inner: R, boundary: &str, limit: u64) -> Files<R> {
+        Files {
+            inner,
+            limit,
+            read: 0,
+            over_limit: false,
+            separator: format!("\r\n--{}", boundary).into_bytes(),
+            buffer: Vec::new(),
+            eof: false,
+            state: State::Start,
+        }
+    }
+
+    /// Read more of the body into the buffer; false at its end
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let start = self.buffer.len();
+        self.buffer.resize(start + CHUNK, 0);
+        let read = self.inner.read(&mut self.buffer[start..]);
+        self.buffer.truncate(start + *read.as_ref().unwrap_or(&0));
+        let n = read?;
+        self.eof = n == 0;
+        Ok(n > 0)
+    }
+
+    /// Where `needle` starts in the buffer, reading until it is there or
+    /// `limit` bytes have been looked through without it
+    fn find_buffered(&mut self, needle: &[u8], limit: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(at) = find(&self.buffer, needle) {
+                return Ok(Some(at));
+            }
+            if self.buffer.len() > limit || !self.fill().map_err(|e| e.to_string())? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// The next file in the body, or `None` after the last. What is left
+    /// unread of the one before is skipped.
+    pub fn next_file(&mut self) -> Result<Option<FileHeader>, String> {
+        match self.state {
+            State::Done => return Ok(None),
+            State::Start => {
+                // The opening boundary has no CRLF before it unless there is
+                // a preamble
+                let delimiter = self.separator[2..].to_vec();
+                let at = self
+                    .find_buffered(&delimiter, MAX_HEADERS)?
+                    .ok_or("multipart body has no opening boundary")?;
+                self.buffer.drain(..at + delimiter.len());
+            }
+            State::InPart => self.skip_part()?,
+            State::AfterPart => {
+                self.buffer.drain(..self.separator.len());
+            }
+        }
+        loop {
+            while self.buffer.len() < 2 && self.fill().map_err(|e| e.to_string())? {}
+            if self.buffer.starts_with(b"--") {
+                self.state = State::Done;
+                return Ok(None);
+            }
+            if !self.buffer.starts_with(b"\r\n") {
+                return Err("malformed multipart boundary line".to_string());
+            }
+            self.buffer.drain(..2);
+            let end = self
+                .find_buffered(b"\r\n\r\n", MAX_HEADERS)?
+                .ok_or("multipart part has no header terminator")?;
+            let headers = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+            self.buffer.drain(..end + 4);
+            self.state = State::InPart;
+            self.read = 0;
+            let (_, filename, content_type) = part_headers(&headers);
+            if let Some(filename) = filename {
+                return Ok(Some(FileHeader { filename, content_type }));
+            }
+            // A field: read past it to the next part
+            self.skip_part()?;
+        }
+    }
+
+    /// Read past the rest of the current part, whatever its size
+    fn skip_part(&mut self) -> Result<(), String> {
+        let mut discard = [0; 8192];
+        while self.state == State::InPart {
+            self.read_part(&mut discard).map_err(|e| e.to_string())?;
+        }
+        self.buffer.drain(..self.separator.len());
+        Ok(())
+    }
+
+    /// Whether the file being read turned out larger than the limit
+    pub fn over_limit(&self) -> bool {
+        self.over_limit
+    }
+
+    fn read_part(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::InPart || out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if let Some(at) = find(&self.buffer, &self.separator) {
+                if at == 0 {
+                    self.state = State::AfterPart;
+                    return Ok(0);
+                }
+                return Ok(self.take_buffered(at.min(out.len()), out));
+            }
+            // All but what could be the start of the separator is contents
+            let safe = self.buffer.len().saturating_sub(self.separator.len() - 1);
+            if safe > 0 {
+                return Ok(self.take_buffered(safe.min(out.len()), out));
+            }
+            if !self.fill()? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "multipart body has no closing boundary"));
+            }
+        }
+    }
+}
+
+/// The contents of the current file, ending at its separator
+impl<R: Read> Read for Files<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_part(out)?;
+        self.read += n as u64;
+        if self.read > self.limit {
+            self.over_limit = true;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the file is over the size limit"));
+        }
+        Ok(n)
+    }
+}
+
+impl<R> Files<R> {
+    fn take_buffered(&mut self, n: usize, out: &mut [u8]) -> usize {
+        out[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+        n
+    }
+}
+
+/// key=value parameters of a Content-Disposition header, honouring quotes
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in value.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                segments.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    segments.push(current);
+
+    for segment in segments {
+        if let Some((key, value)) = segment.split_once('=') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            params.push((key.trim().to_string(), value.to_string()));
+        }
+    }
+    params
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -0,0 +1,111 @@
+//! application/x-www-form-urlencoded parameters, as found in URL query
+//! strings and in POSTed form bodies, and the percent-decoding of path
+//! segments, where `+` stays as it is.
+
+use std::str::FromStr;
+
+/// Decoded key/value pairs in their original order. Keys may repeat, as
+/// they do for checkbox groups.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Parse a query string or urlencoded body
+    pub fn parse(encoded: &str) -> Params {
+        encoded
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) => (decode(key), decode(value)),
+                None => (decode(pair), String::new()),
+            })
+            .collect()
+    }
+
+    /// The parameters in the query string of a request URL
+    pub fn from_url(url: &str) -> Params {
+        url.split_once('?')
+            .map(|(_, query)| Params::parse(query))
+            .unwrap_or_default()
+    }
+
+    /// The first value for `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The first value for `key`, or "" when it's missing
+    pub fn value(&self, key: &str) -> &str {
+        self.get(key).unwrap_or("")
+    }
+
+    /// Every value for `key`, in order
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.pairs
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The first value for `key` parsed as `T`; `None` if missing or invalid
+    pub fn get_as<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+}
+
+impl FromIterator<(String, String)> for Params {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Params {
+            pairs: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl IntoIterator for Params {
+    type Item = (String, String);
+    type IntoIter = std::vec::IntoIter<(String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.pairs.into_iter()
+    }
+}
+
+/// Percent-decode one component, with `+` as space
+pub fn decode(s: &str) -> String {
+    percent_decode(s, true)
+}
+
+/// Percent-decode a path segment, or anything else where `+` is just `+`
+pub fn decode_segment(s: &str) -> String {
+    percent_decode(s, false)
+}
+
+fn percent_decode(s: &str, plus_as_space: bool) -> String {
+    // Decode to bytes first so percent-encoded UTF-8 sequences survive
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let hex: Vec<u8> = input.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(byte) => bytes.push(byte),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend_from_slice(&hex);
+                    }
+                }
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,127 @@
+//! Byte ranges (RFC 9110 section 14) of the blobs proxied to the browser,
+//! so a video or PDF can be scrubbed and an interrupted download resumed.
+//! Only a single range is served; a request for several gets the whole
+//! blob, which the RFC allows.
+//!
+//! The range is passed on to the JMAP server; one that sends the whole blob
+//! regardless has the part cut out of it here, as it streams.
+
+use std::io::{self, Read};
+
+use crate::jmap::BlobDownload;
+
+/// The bytes a `Range: bytes=` header asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// From the first byte given to the last, or to the end
+    From(u64, Option<u64>),
+    /// The last n bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// `None` for anything but one well-formed range of bytes
+    pub fn parse(header: &str) -> Option<ByteRange> {
+        let spec = header.trim().strip_prefix("bytes=")?.trim();
+        let (first, last) = spec.split_once('-')?;
+        let number = |s: &str| {
+            let s = s.trim();
+            (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())).then(|| s.parse().ok()).flatten()
+        };
+        match (first.trim(), last.trim()) {
+            ("", last) => number(last).map(ByteRange::Suffix),
+            (first, "") => Some(ByteRange::From(number(first)?, None)),
+            (first, last) => {
+                let (first, last) = (number(first)?, number(last)?);
+                (first <= last).then_some(ByteRange::From(first, Some(last)))
+            }
+        }
+    }
+
+    /// The range as a request header, for passing on to the JMAP server
+    pub fn header(&self) -> String {
+        match self {
+            ByteRange::From(first, Some(last)) => format!("bytes={}-{}", first, last),
+            ByteRange::From(first, None) => format!("bytes={}-", first),
+            ByteRange::Suffix(n) => format!("bytes=-{}", n),
+        }
+    }
+
+    /// The first and last byte it asks for of a blob `length` bytes long,
+    /// or `None` when the blob has none of them
+    pub fn resolve(&self, length: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::From(first, _) if first >= length => None,
+            ByteRange::From(first, last) => Some((first, last.map_or(length - 1, |last| last.min(length - 1)))),
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(_) if length == 0 => None,
+            ByteRange::Suffix(n) => Some((length.saturating_sub(n), length - 1)),
+        }
+    }
+}
+
+/// The Content-Range of bytes `first` to `last` of a blob `length` long
+pub fn content_range(first: u64, last: u64, length: u64) -> String {
+    format!("bytes {}-{}/{}", first, last, length)
+}
+
+/// The Content-Range of a 416, saying how long the blob is
+pub fn unsatisfied_range(length: u64) -> String {
+    format!("bytes */{}", length)
+}
+
+/// `download` as it goes to the browser for `range`: the part the server
+/// sent, or the part asked for of a whole blob. Without a range, or with
+/// one when the blob's length isn't known, it is the whole blob.
+pub fn serve(download: BlobDownload, range: Option<ByteRange>) -> Result<BlobDownload, String> {
+    match (download.status, range, download.length) {
+        (206, ..) if download.content_range.is_none() => Err("a partial response without Content-Range".to_string()),
+        (206 | 416, ..) => Ok(download),
+        (_, Some(range), Some(length)) => {
+            let length = length as u64;
+            let Some((first, last)) = range.resolve(length) else {
+                return Ok(BlobDownload {
+                    reader: Box::new(io::empty()),
+                    length: Some(0),
+                    status: 416,
+                    content_range: Some(unsatisfied_range(length)),
+                });
+            };
+            let reader = Skip {
+                inner: download.reader,
+                skip: first,
+            };
+            Ok(BlobDownload {
+                reader: Box::new(reader.take(last - first + 1)),
+                length: Some((last - first + 1) as usize),
+                status: 206,
+                content_range: Some(content_range(first, last, length)),
+            })
+        }
+        _ => Ok(BlobDownload {
+            status: 200,
+            content_range: None,
+            ..download
+        }),
+    }
+}
+
+/// `inner` from `skip` bytes on, read past when the first bytes are asked
+/// for so the skipping happens on the thread streaming the response
+struct Skip<R> {
+    inner: R,
+    skip: u64,
+}
+
+impl<R: Read> Read for Skip<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.skip > 0 {
+            let skipped = io::copy(&mut self.inner.by_ref().take(self.skip), &mut io::sink())?;
+            if skipped < self.skip {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the blob ended before the range"));
+            }
+            self.skip = 0;
+        }
+        self.inner.read(out)
+    }
+}
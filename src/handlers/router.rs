@@ -0,0 +1,123 @@
+//! Finding the endpoint a request is for.
+//!
+//! Endpoints are declared as routes: a method and a path pattern, in
+//! which a `{name}` segment matches any one non-empty segment of the path
+//! and every other segment must match exactly. The query string plays no
+//! part in matching. Routes are tried in order, so a literal route such as
+//! `/settings/filters/new` goes before the `/settings/filters/{id}` it
+//! would otherwise be taken for.
+
+use super::params::{self, Params};
+
+pub struct Route<H> {
+    pub(super) method: &'static str,
+    pub(super) pattern: &'static str,
+    body: Body,
+    handler: H,
+}
+
+/// How large a body a route takes, each as `[server.limits]` says
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Body {
+    /// A sign-in
+    Login,
+    /// Any other form, unless the route says otherwise
+    Form,
+    /// A message with its attachments, or mail to import
+    Upload,
+    /// Read as it arrives by a handler that limits what it keeps
+    Streamed,
+}
+
+pub const fn get<H>(pattern: &'static str, handler: H) -> Route<H> {
+    Route { method: "GET", pattern, body: Body::Form, handler }
+}
+
+pub const fn post<H>(pattern: &'static str, handler: H) -> Route<H> {
+    Route { method: "POST", pattern, body: Body::Form, handler }
+}
+
+pub const fn delete<H>(pattern: &'static str, handler: H) -> Route<H> {
+    Route { method: "DELETE", pattern, body: Body::Form, handler }
+}
+
+impl<H: Copy> Route<H> {
+    /// The route takes a body of another size than a form's
+    pub const fn taking(self, body: Body) -> Route<H> {
+        Route { body, ..self }
+    }
+}
+
+/// What a matched request brings its handler
+pub struct Args<'a> {
+    /// The path and query as requested, after any base path
+    pub url: &'a str,
+    pub query: Params,
+    params: Vec<(&'static str, String)>,
+}
+
+impl Args<'_> {
+    /// The path segment the route's `{name}` matched, percent-decoded
+    /// with any `+` kept. Asking for a name the route doesn't have is a
+    /// mistake in the route table.
+    pub fn param(&self, name: &str) -> &str {
+        match self.params.iter().find(|(n, _)| *n == name) {
+            Some((_, value)) => value,
+            None => panic!("route has no {{{}}} segment", name),
+        }
+    }
+}
+
+pub enum Match<'r, 'a, H> {
+    Found(&'r H, Body, Args<'a>),
+    /// Routes take the path, but not with this method; these are the
+    /// methods they do take, for the `Allow` header
+    WrongMethod(Vec<&'static str>),
+    NotFound,
+}
+
+/// The first route for `method` and the path of `url`
+pub fn find<'r, 'a, H>(routes: &'r [Route<H>], method: &str, url: &'a str) -> Match<'r, 'a, H> {
+    let path = url.split_once('?').map_or(url, |(p, _)| p);
+    let mut allowed = Vec::new();
+    for route in routes {
+        let Some(params) = captures(route.pattern, path) else { continue };
+        if route.method == method {
+            let args = Args {
+                url,
+                query: Params::from_url(url),
+                params,
+            };
+            return Match::Found(&route.handler, route.body, args);
+        }
+        if !allowed.contains(&route.method) {
+            allowed.push(route.method);
+        }
+    }
+    if allowed.is_empty() {
+        Match::NotFound
+    } else {
+        Match::WrongMethod(allowed)
+    }
+}
+
+/// The segments `pattern`'s `{name}`s match in `path`, if it matches
+fn captures(pattern: &'static str, path: &str) -> Option<Vec<(&'static str, String)>> {
+    let mut wanted = pattern.split('/');
+    let mut given = path.split('/');
+    let mut params = Vec::new();
+    loop {
+        match (wanted.next(), given.next()) {
+            (None, None) => return Some(params),
+            (Some(want), Some(segment)) => match want.strip_prefix('{').and_then(|w| w.strip_suffix('}')) {
+                Some(name) if !segment.is_empty() => params.push((name, params::decode_segment(segment))),
+                None if want == segment => {}
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,86 @@
+use super::*;
+
+static ROUTES: &[Route<&str>] = &[
+    get("/", "root"),
+    get("/settings/filters/new", "new filter"),
+    get("/settings/filters/{id}", "filter"),
+    post("/settings/filters/{id}", "save filter"),
+    delete("/settings/filters/{id}", "delete filter"),
+    post("/mailbox/{id}/move/{target}", "move"),
+    post("/import", "import").taking(Body::Upload),
+];
+
+fn found<'a>(method: &str, url: &'a str) -> (&'static str, Body, Args<'a>) {
+    match find(ROUTES, method, url) {
+        Match::Found(handler, body, args) => (handler, body, args),
+        _ => panic!("no route for {} {}", method, url),
+    }
+}
+
+#[test]
+fn segments_are_captured_as_given() {
+    let (handler, body, args) = found("POST", "/mailbox/a%2Fb/move/m+1%20%C3%A9?keep=1");
+    assert_eq!(handler, "move");
+    assert_eq!(body, Body::Form);
+    // Decoded, but a + in a path is itself, not a space
+    assert_eq!(args.param("id"), "a/b");
+    assert_eq!(args.param("target"), "m+1 é");
+    assert_eq!(args.query.get("keep"), Some("1"));
+    assert_eq!(args.url, "/mailbox/a%2Fb/move/m+1%20%C3%A9?keep=1");
+    assert_eq!(found("POST", "/import").1, Body::Upload);
+}
+
+#[test]
+fn earlier_routes_win() {
+    assert_eq!(found("GET", "/settings/filters/new").0, "new filter");
+    assert_eq!(found("GET", "/settings/filters/other").0, "filter");
+    assert_eq!(found("GET", "/settings/filters/other").2.param("id"), "other");
+    assert_eq!(found("POST", "/settings/filters/new").0, "save filter");
+}
+
+#[test]
+fn paths_must_match_segment_for_segment() {
+    assert_eq!(found("GET", "/").0, "root");
+    assert_eq!(found("GET", "/?page=2").0, "root");
+    for url in [
+        "",
+        "/settings/filters/new/",
+        "/settings/filters/",
+        "/settings/filters",
+        "/settings/filters/a/b",
+        "//settings/filters/a",
+        "/mailbox//move/x",
+        "/SETTINGS/filters/new",
+    ] {
+        assert!(matches!(find(ROUTES, "GET", url), Match::NotFound), "{} matched", url);
+    }
+}
+
+#[test]
+fn another_method_says_which_are_allowed() {
+    match find(ROUTES, "PUT", "/settings/filters/7") {
+        Match::WrongMethod(allowed) => assert_eq!(allowed, ["GET", "POST", "DELETE"]),
+        _ => panic!("expected the wrong method"),
+    }
+    // The literal route and the pattern both take GET; it is listed once
+    match find(ROUTES, "PUT", "/settings/filters/new") {
+        Match::WrongMethod(allowed) => assert_eq!(allowed, ["GET", "POST", "DELETE"]),
+        _ => panic!("expected the wrong method"),
+    }
+    // A literal route doesn't hide the methods of the pattern after it
+    match find(ROUTES, "DELETE", "/settings/filters/new") {
+        Match::WrongMethod(_) => panic!("the {{id}} route takes DELETE"),
+        Match::Found(handler, ..) => assert_eq!(*handler, "delete filter"),
+        Match::NotFound => panic!("expected a route"),
+    }
+    match find(ROUTES, "POST", "/") {
+        Match::WrongMethod(allowed) => assert_eq!(allowed, ["GET"]),
+        _ => panic!("expected the wrong method"),
+    }
+}
+
+#[test]
+#[should_panic(expected = "route has no {name} segment")]
+fn asking_for_a_missing_segment_is_a_mistake() {
+    found("GET", "/settings/filters/7").2.param("name");
+}
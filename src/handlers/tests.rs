@@ -0,0 +1,104 @@
+//! The OpenAPI description in `openapi.json` against the routes the API
+//! actually serves, and the `mailto:` links `/compose` is given.
+
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+use super::*;
+
+fn openapi() -> Value {
+    serde_json::from_str(api::OPENAPI).expect("openapi.json is valid JSON")
+}
+
+/// What a `$ref` such as `#/components/parameters/limit` points at
+fn resolve<'a>(document: &'a Value, value: &'a Value) -> &'a Value {
+    match value["$ref"].as_str() {
+        Some(pointer) => {
+            let target = pointer.strip_prefix('#').unwrap_or_else(|| panic!("{} isn't local", pointer));
+            document.pointer(target).unwrap_or_else(|| panic!("{} points at nothing", pointer))
+        }
+        None => value,
+    }
+}
+
+fn operations(document: &Value) -> Vec<(String, &str, &Value)> {
+    let mut operations = Vec::new();
+    for (path, item) in document["paths"].as_object().unwrap() {
+        for (method, operation) in item.as_object().unwrap() {
+            operations.push((method.to_ascii_uppercase(), path.as_str(), operation));
+        }
+    }
+    operations
+}
+
+#[test]
+fn openapi_describes_every_api_route() {
+    let document = openapi();
+    let documented: BTreeSet<(String, &str)> = operations(&document)
+        .into_iter()
+        .map(|(method, path, _)| (method, path))
+        .collect();
+    let served: BTreeSet<(String, &str)> = ROUTES
+        .iter()
+        .filter(|route| route.pattern.starts_with("/api/"))
+        .map(|route| (route.method.to_string(), route.pattern))
+        .collect();
+    assert_eq!(documented, served, "openapi.json and the /api routes differ");
+}
+
+#[test]
+fn openapi_path_parameters_match_the_patterns() {
+    let document = openapi();
+    for (method, path, operation) in operations(&document) {
+        let segments: BTreeSet<&str> = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .collect();
+        let parameters: BTreeSet<&str> = operation["parameters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|parameter| resolve(&document, parameter))
+            .filter(|parameter| parameter["in"] == "path")
+            .map(|parameter| parameter["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(segments, parameters, "{} {} describes other path parameters", method, path);
+    }
+}
+
+#[test]
+fn openapi_references_resolve() {
+    fn check(document: &Value, value: &Value) {
+        match value {
+            Value::Object(object) => {
+                resolve(document, value);
+                object.values().for_each(|v| check(document, v));
+            }
+            Value::Array(array) => array.iter().for_each(|v| check(document, v)),
+            _ => {}
+        }
+    }
+    let document = openapi();
+    check(&document, &document);
+    for (method, path, operation) in operations(&document) {
+        let responses = operation["responses"].as_object();
+        assert!(responses.is_some_and(|r| !r.is_empty()), "{} {} has no responses", method, path);
+    }
+}
+
+#[test]
+fn mailto_links_fill_in_compose() {
+    let link = mailto::parse("MAILTO:ann@example.com,bo+news@example.com?cc=cy%40example.com&subject=Hi%20there&body=Line%0D%0Aone+two&x-other=1")
+        .unwrap();
+    assert_eq!(link.to, ["ann@example.com", "bo+news@example.com"]);
+    assert_eq!(link.cc, ["cy@example.com"]);
+    assert_eq!(link.subject, "Hi there");
+    assert_eq!(link.body, "Line\none+two");
+    assert_eq!(mailto::parse("https://example.com/"), None);
+
+    // As a registered handler passes it, and as a link of the app's own
+    assert_eq!(mailto::from_query(&Params::parse("to=mailto%3Aann%40example.com%3Fsubject%3DHi")).subject, "Hi");
+    let own = mailto::from_query(&Params::parse("to=ann%40example.com&subject=Hi+there"));
+    assert_eq!((own.to, own.subject), (vec!["ann@example.com".to_string()], "Hi there".to_string()));
+}
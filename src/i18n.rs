@@ -0,0 +1,146 @@
+//! Translations of the text in the templates and of the notices handlers
+//! send back, one catalog per language under locales/ built into the binary.
+//!
+//! A catalog is TOML whose tables group the messages by page, so
+//! `[login] heading = "..."` is the message `login.heading`. `{name}` in a
+//! message is filled in by [`tf`]. Anything a catalog lacks comes from
+//! English, which has every message.
+//!
+//! Pages are rendered by helpers that don't see the request, so
+//! [`negotiate`] picks the language from Accept-Language when a request
+//! comes in, [`select`] applies a user's own choice over it, and [`t`]
+//! looks messages up in whichever that left.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::log_error;
+
+/// The language used when nothing the browser asks for is available, and
+/// for messages missing from another catalog
+pub const FALLBACK: &str = "en";
+
+pub struct Catalog {
+    /// Language tag, such as "de"
+    pub code: &'static str,
+    /// The language's name in itself, for the picker
+    pub name: String,
+    messages: HashMap<String, String>,
+}
+
+fn catalogs() -> &'static [Catalog] {
+    static CATALOGS: OnceLock<Vec<Catalog>> = OnceLock::new();
+    CATALOGS.get_or_init(|| {
+        [
+            ("en", include_str!("../locales/en.toml")),
+            ("de", include_str!("../locales/de.toml")),
+        ]
+        .into_iter()
+        .map(|(code, source)| {
+            let mut messages = HashMap::new();
+            match source.parse::<toml::Table>() {
+                Ok(table) => flatten("", &table, &mut messages),
+                Err(e) => log_error!("Failed to parse locales/{}.toml: {}", code, e),
+            }
+            Catalog {
+                code,
+                name: messages.get("language.name").cloned().unwrap_or_else(|| code.to_string()),
+                messages,
+            }
+        })
+        .collect()
+    })
+}
+
+/// `table`'s strings keyed by their dotted path under `prefix`
+fn flatten(prefix: &str, table: &toml::Table, out: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::String(s) => {
+                out.insert(key, s.clone());
+            }
+            toml::Value::Table(t) => flatten(&key, t, out),
+            _ => {}
+        }
+    }
+}
+
+fn index_of(code: &str) -> Option<usize> {
+    catalogs().iter().position(|c| c.code.eq_ignore_ascii_case(code))
+}
+
+thread_local! {
+    /// Index of the catalog for this thread's request
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Every language there is a catalog for
+pub fn languages() -> &'static [Catalog] {
+    catalogs()
+}
+
+/// Whether there is a catalog for `code`
+pub fn is_known(code: &str) -> bool {
+    index_of(code).is_some()
+}
+
+/// Pick the language for the current request from its Accept-Language,
+/// taking the browser's favourite that there is a catalog for. A tag such
+/// as "de-AT" settles for "de". Otherwise `default` is used.
+pub fn negotiate(accept_language: Option<&str>, default: &str) {
+    let mut wanted: Vec<(f32, &str)> = accept_language
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!tag.is_empty() && q > 0.0).then_some((q, tag))
+        })
+        .collect();
+    // Stable, so equal weights keep the browser's order
+    wanted.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let found = wanted.iter().find_map(|(_, tag)| {
+        index_of(tag).or_else(|| index_of(tag.split('-').next().unwrap_or(tag)))
+    });
+    let index = found.or_else(|| index_of(default)).unwrap_or(0);
+    CURRENT.with(|cell| cell.set(index));
+}
+
+/// Use `code` for the rest of the current request, if there is a catalog
+/// for it
+pub fn select(code: &str) {
+    if let Some(index) = index_of(code) {
+        CURRENT.with(|cell| cell.set(index));
+    }
+}
+
+/// The language tag of the current request's language, for `<html lang>`
+pub fn current() -> &'static str {
+    catalogs()[CURRENT.with(Cell::get)].code
+}
+
+/// The message `key` in the current request's language. Messages are
+/// markup like the template text around them.
+pub fn t(key: &'static str) -> &'static str {
+    let catalogs = catalogs();
+    let current = &catalogs[CURRENT.with(Cell::get)];
+    current
+        .messages
+        .get(key)
+        .or_else(|| catalogs[0].messages.get(key))
+        .map(String::as_str)
+        .unwrap_or(key)
+}
+
+/// The message `key` with each `{name}` in it replaced by the value paired
+/// with that name. Values go in as they are, so escape them first.
+pub fn tf(key: &'static str, args: &[(&str, &str)]) -> String {
+    args.iter()
+        .fold(t(key).to_string(), |message, (name, value)| message.replace(&format!("{{{}}}", name), value))
+}
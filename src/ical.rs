@@ -0,0 +1,322 @@
+//! Meeting invitations: enough of iCalendar (RFC 5545) to show the event
+//! an iMIP message (RFC 6047) carries, and to answer it with an iTIP
+//! REPLY (RFC 5546).
+//!
+//! Only the first VEVENT is read. Times with a TZID are taken in that zone
+//! from the tz database; a zone it doesn't know, such as Outlook's
+//! "W. Europe Standard Time", falls back to the reader's, since the
+//! VTIMEZONE definitions aren't interpreted.
+
+use crate::dates;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartStat {
+    Accepted,
+    Tentative,
+    Declined,
+}
+
+impl PartStat {
+    pub fn parse(s: &str) -> Option<PartStat> {
+        match s.to_ascii_uppercase().as_str() {
+            "ACCEPTED" => Some(PartStat::Accepted),
+            "TENTATIVE" => Some(PartStat::Tentative),
+            "DECLINED" => Some(PartStat::Declined),
+            _ => None,
+        }
+    }
+
+    /// The PARTSTAT value, which the RSVP buttons also send
+    pub fn name(self) -> &'static str {
+        match self {
+            PartStat::Accepted => "ACCEPTED",
+            PartStat::Tentative => "TENTATIVE",
+            PartStat::Declined => "DECLINED",
+        }
+    }
+}
+
+/// When an event starts or ends
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventTime {
+    /// An all-day date
+    Date { year: i64, month: i64, day: i64 },
+    /// Seconds since the epoch
+    At(i64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Person {
+    /// The address from the mailto: URI
+    pub email: String,
+    pub name: Option<String>,
+    /// PARTSTAT, such as NEEDS-ACTION or ACCEPTED
+    pub partstat: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Invite {
+    /// The iTIP method, such as REQUEST or CANCEL
+    pub method: String,
+    pub uid: String,
+    pub summary: Option<String>,
+    pub location: Option<String>,
+    pub start: Option<EventTime>,
+    pub end: Option<EventTime>,
+    pub organizer: Option<Person>,
+    pub attendees: Vec<Person>,
+    /// Lines a reply has to repeat as they were, unfolded
+    echoed: Vec<String>,
+    /// The VTIMEZONE components, for a reply whose lines refer to them
+    timezones: Vec<String>,
+}
+
+/// A content line: `NAME;PARAM=value:value`
+struct Line<'a> {
+    name: String,
+    params: Vec<(String, String)>,
+    value: &'a str,
+    raw: &'a str,
+}
+
+impl Line<'_> {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Invite {
+    /// The first event in an iCalendar object, if it has one with a UID
+    pub fn parse(text: &str) -> Option<Invite> {
+        let lines = unfold(text);
+        let mut invite = Invite::default();
+        let mut in_event = false;
+        let mut in_timezone = false;
+        let mut done = false;
+        // Components nested in the event, such as VALARM
+        let mut depth = 0;
+        for raw in &lines {
+            let Some(line) = parse_line(raw) else { continue };
+            if !in_event && line.name == "BEGIN" && line.value.eq_ignore_ascii_case("VTIMEZONE") {
+                in_timezone = true;
+            }
+            if in_timezone {
+                invite.timezones.push(raw.clone());
+                in_timezone = !(line.name == "END" && line.value.eq_ignore_ascii_case("VTIMEZONE"));
+                continue;
+            }
+            match (line.name.as_str(), in_event) {
+                ("BEGIN", false) if line.value.eq_ignore_ascii_case("VEVENT") && !done => in_event = true,
+                ("METHOD", false) => invite.method = line.value.trim().to_ascii_uppercase(),
+                ("BEGIN", true) => depth += 1,
+                ("END", true) if depth > 0 => depth -= 1,
+                ("END", true) => {
+                    in_event = false;
+                    done = true;
+                }
+                (_, true) if depth == 0 => invite.take(&line),
+                _ => {}
+            }
+        }
+        (!invite.uid.is_empty()).then_some(invite)
+    }
+
+    fn take(&mut self, line: &Line) {
+        match line.name.as_str() {
+            "UID" => self.uid = line.value.to_string(),
+            "SUMMARY" => self.summary = Some(unescape(line.value)),
+            "LOCATION" => self.location = Some(unescape(line.value)),
+            "DTSTART" => self.start = event_time(line),
+            "DTEND" => self.end = event_time(line),
+            "ORGANIZER" => self.organizer = person(line),
+            "ATTENDEE" => self.attendees.extend(person(line)),
+            _ => {}
+        }
+        if matches!(line.name.as_str(), "UID" | "SEQUENCE" | "RECURRENCE-ID" | "SUMMARY" | "ORGANIZER") {
+            self.echoed.push(line.raw.to_string());
+        }
+    }
+
+    /// Whether this asks for an answer, rather than cancelling the event or
+    /// reporting someone else's answer
+    pub fn is_request(&self) -> bool {
+        self.method.is_empty() || self.method == "REQUEST"
+    }
+
+    /// The attendee with the address `email`
+    pub fn attendee(&self, email: &str) -> Option<&Person> {
+        self.attendees.iter().find(|a| a.email.eq_ignore_ascii_case(email))
+    }
+
+    /// The iTIP REPLY telling the organizer that the attendee at `email`
+    /// answers `partstat`, stamped `now` (seconds since the epoch)
+    pub fn reply(&self, email: &str, name: Option<&str>, partstat: PartStat, now: i64) -> String {
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_string(),
+            "VERSION:2.0".to_string(),
+            "PRODID:-//rust-jmap-webmail//EN".to_string(),
+            "METHOD:REPLY".to_string(),
+        ];
+        if self.echoed.iter().any(|line| parse_line(line).is_some_and(|l| l.param("TZID").is_some())) {
+            lines.extend(self.timezones.iter().cloned());
+        }
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.extend(self.echoed.iter().cloned());
+        let mut attendee = format!("ATTENDEE;PARTSTAT={}", partstat.name());
+        if let Some(name) = name.filter(|n| !n.is_empty()) {
+            attendee.push_str(&format!(";CN=\"{}\"", name.replace(['"', '\r', '\n'], "")));
+        }
+        attendee.push_str(&format!(":mailto:{}", email));
+        lines.push(attendee);
+        lines.push(format!("DTSTAMP:{}", utc_stamp(now)));
+        lines.push("END:VEVENT".to_string());
+        lines.push("END:VCALENDAR".to_string());
+        lines.iter().map(|line| fold(line)).collect()
+    }
+}
+
+/// Content lines with continuation lines (those starting with a space or
+/// tab) joined back on
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// `line` in pieces of at most 75 octets, each ending in CRLF, split only
+/// between characters
+fn fold(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + 8);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+    out
+}
+
+fn parse_line(raw: &str) -> Option<Line<'_>> {
+    // The value starts at the first colon that isn't in a quoted parameter
+    let mut quoted = false;
+    let colon = raw.char_indices().find_map(|(i, c)| {
+        match c {
+            '"' => quoted = !quoted,
+            ':' if !quoted => return Some(i),
+            _ => {}
+        }
+        None
+    })?;
+    let (head, value) = (&raw[..colon], &raw[colon + 1..]);
+    let mut parts = split_unquoted(head, ';').into_iter();
+    let name = parts.next()?.trim().to_ascii_uppercase();
+    let params = parts
+        .filter_map(|p| {
+            let (name, value) = p.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().trim_matches('"').to_string()))
+        })
+        .collect();
+    Some(Line { name, params, value, raw })
+}
+
+fn split_unquoted(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// A TEXT value with its backslash escapes undone
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+fn person(line: &Line) -> Option<Person> {
+    let address = line.value.trim();
+    let email = address
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("mailto:"))
+        .map_or(address, |_| &address[7..]);
+    if email.is_empty() {
+        return None;
+    }
+    Some(Person {
+        email: email.to_string(),
+        name: line.param("CN").filter(|n| !n.is_empty()).map(str::to_string),
+        partstat: line.param("PARTSTAT").map(str::to_ascii_uppercase),
+    })
+}
+
+/// DTSTART or DTEND: `20261020`, `20261020T150000Z`, or a local time in
+/// the line's TZID (or the reader's zone, for a floating time)
+fn event_time(line: &Line) -> Option<EventTime> {
+    let value = line.value.trim();
+    let digits = |range: std::ops::Range<usize>| value.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (digits(0..4)?, digits(4..6)?, digits(6..8)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if value.len() == 8 || line.param("VALUE").is_some_and(|v| v.eq_ignore_ascii_case("DATE")) {
+        return Some(EventTime::Date { year, month, day });
+    }
+    if value.as_bytes().get(8) != Some(&b'T') {
+        return None;
+    }
+    let seconds = digits(9..11)? * 3600 + digits(11..13)? * 60 + digits(13..15)?;
+    let days = dates::days_from_civil(year, month, day);
+    if value.ends_with(['Z', 'z']) {
+        Some(EventTime::At(days * 86400 + seconds))
+    } else {
+        Some(EventTime::At(dates::from_local(line.param("TZID"), days, seconds)))
+    }
+}
+
+/// `time` as an iCalendar UTC date-time, such as `20261014T093000Z`
+fn utc_stamp(time: i64) -> String {
+    let (year, month, day) = dates::civil_from_days(time.div_euclid(86400));
+    let seconds = time.rem_euclid(86400);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
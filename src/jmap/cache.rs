@@ -0,0 +1,437 @@
+//! Mailboxes and list rows kept between a session's requests.
+//!
+//! Each entry is remembered with the JMAP state it was fetched at. For a
+//! while after the server last confirmed it (the TTL) it is used as it is;
+//! after that, or once a change made through any of the session's clients
+//! or pushed by the server may have touched it, Mailbox/changes or
+//! Email/changes says what has to be fetched again. Only the list
+//! properties of emails are kept, never their bodies.
+//!
+//! With a [`CacheStore`] it is all written through to disk as well, and an
+//! account's entries are read back the first time a session uses it, so
+//! even the first pages after a restart only fetch what changed. While the
+//! server can't be reached the lists already shown are served from what's
+//! kept.
+//!
+//! The kept emails are indexed by their words as well (see `index`), so a
+//! search can show the ones it finds there before the server answers.
+//!
+//! A user's sessions may share a [`CacheBudget`] on the memory their
+//! emails take. An account whose new rows would go over it lets go of the
+//! ones it has, like one that reaches `MAX_EMAILS`, and keeps none while
+//! the user's other sessions hold the rest.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::filter::{Filter, Sort};
+use super::index::{self, TextIndex};
+use super::types::{ChangesResponse, Email, EmailQueryResult, Mailbox};
+use crate::log_error;
+
+/// Emails kept per account before they are all let go
+const MAX_EMAILS: usize = 5000;
+/// Email/query results kept per account, for when the server is down
+const MAX_QUERIES: usize = 100;
+
+/// Where a `MailCache` keeps its entries beyond the process, each account's
+/// under the `owner` that signed in to it
+pub trait CacheStore: Send + Sync {
+    fn load(&self, owner: &str, account_id: &str) -> Result<StoredAccount, String>;
+    fn save_mailboxes(&self, owner: &str, account_id: &str, mailboxes: &[Mailbox], state: &str) -> Result<(), String>;
+    /// Keep `emails`, and `state` as the one they are all at least as new as
+    fn save_emails(&self, owner: &str, account_id: &str, emails: &[Email], state: &str) -> Result<(), String>;
+    fn remove_emails(&self, owner: &str, account_id: &str, ids: &[String], state: &str) -> Result<(), String>;
+    fn clear_emails(&self, owner: &str, account_id: &str) -> Result<(), String>;
+    /// Keep `result` for `query`, and only the newest `keep` queries
+    fn save_query(
+        &self,
+        owner: &str,
+        account_id: &str,
+        query: &str,
+        result: &EmailQueryResult,
+        keep: usize,
+    ) -> Result<(), String>;
+}
+
+/// An account's entries as a `CacheStore` kept them
+#[derive(Default)]
+pub struct StoredAccount {
+    pub mailboxes: Option<(Vec<Mailbox>, String)>,
+    pub emails: Vec<Email>,
+    pub email_state: Option<String>,
+    pub queries: Vec<(String, EmailQueryResult)>,
+}
+
+/// Shared by every client made for a session, like its `Transport`
+#[derive(Clone)]
+pub struct MailCache {
+    ttl: Duration,
+    accounts: Arc<Mutex<HashMap<String, AccountCache>>>,
+    /// With who the entries belong to there
+    store: Option<(Arc<dyn CacheStore>, String)>,
+    budget: Option<Arc<CacheBudget>>,
+}
+
+/// Roughly how many bytes of emails the caches given it may keep between
+/// them
+pub struct CacheBudget {
+    max: usize,
+    used: AtomicUsize,
+}
+
+impl CacheBudget {
+    pub fn new(max: usize) -> Self {
+        CacheBudget {
+            max,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn fits(&self, bytes: usize) -> bool {
+        self.used.load(Ordering::Relaxed) + bytes <= self.max
+    }
+}
+
+/// What an email takes kept, near enough: its JSON, as it came
+fn footprint(email: &Email) -> usize {
+    serde_json::to_vec(email).map_or(0, |json| json.len())
+}
+
+/// The bytes of an account's emails counted against a budget, given back
+/// when its cache goes with the session
+#[derive(Default)]
+struct Counted {
+    budget: Option<Arc<CacheBudget>>,
+    bytes: usize,
+}
+
+impl Counted {
+    /// Whether `emails` can be kept as well without going over the budget
+    fn fits(&self, emails: &[&Email]) -> bool {
+        match &self.budget {
+            Some(budget) => budget.fits(emails.iter().map(|e| footprint(e)).sum()),
+            None => true,
+        }
+    }
+
+    fn add(&mut self, email: &Email) {
+        if let Some(budget) = &self.budget {
+            let bytes = footprint(email);
+            self.bytes += bytes;
+            budget.used.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&mut self, email: &Email) {
+        if let Some(budget) = &self.budget {
+            let bytes = footprint(email);
+            self.bytes -= bytes;
+            budget.used.fetch_sub(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn clear(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+        }
+        self.bytes = 0;
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[derive(Default)]
+struct AccountCache {
+    /// What `emails` take of the budget, when there is one
+    counted: Counted,
+    mailboxes: Option<(Vec<Mailbox>, String)>,
+    /// When the server last confirmed the mailboxes; `None` once
+    /// something may have changed them
+    mailboxes_checked: Option<Instant>,
+    emails: HashMap<String, Email>,
+    /// The words of `emails`
+    index: TextIndex,
+    /// Every email kept is at least as new as this
+    email_state: Option<String>,
+    emails_checked: Option<Instant>,
+    /// Counts the times emails were let go, so an Email/get that was
+    /// already running then doesn't put back what it fetched before
+    generation: u64,
+    /// The last result of each Email/query, by its arguments
+    queries: HashMap<String, EmailQueryResult>,
+}
+
+impl AccountCache {
+    fn new(budget: Option<Arc<CacheBudget>>) -> Self {
+        AccountCache {
+            counted: Counted { budget, bytes: 0 },
+            ..AccountCache::default()
+        }
+    }
+
+    /// Entries read back from disk, which are checked before they're used,
+    /// as many of the emails as the budget has room for
+    fn restore(stored: StoredAccount, budget: Option<Arc<CacheBudget>>) -> Self {
+        let mut account = AccountCache {
+            mailboxes: stored.mailboxes,
+            email_state: stored.email_state,
+            queries: stored.queries.into_iter().collect(),
+            ..AccountCache::new(budget)
+        };
+        for email in stored.emails {
+            if !account.counted.fits(&[&email]) {
+                break;
+            }
+            account.insert_email(email);
+        }
+        account
+    }
+
+    fn insert_email(&mut self, email: Email) {
+        self.remove_email(&email.id);
+        self.counted.add(&email);
+        self.index.add(&email);
+        self.emails.insert(email.id.clone(), email);
+    }
+
+    fn remove_email(&mut self, id: &str) {
+        if let Some(old) = self.emails.remove(id) {
+            self.counted.remove(&old);
+            self.index.remove(&old);
+        }
+    }
+
+    fn clear_emails(&mut self) {
+        self.counted.clear();
+        self.emails.clear();
+        self.index.clear();
+    }
+}
+
+/// The rows a list asked for, split into those kept and the ids still to
+/// fetch
+pub(super) struct Rows {
+    pub found: Vec<Email>,
+    pub missing: Vec<String>,
+    /// What the rows found are at least as new as
+    pub state: Option<String>,
+    pub generation: u64,
+}
+
+impl MailCache {
+    pub fn new(ttl: Duration) -> Self {
+        MailCache {
+            ttl,
+            accounts: Arc::default(),
+            store: None,
+            budget: None,
+        }
+    }
+
+    /// Count the emails kept against `budget`, if given
+    pub fn with_budget(self, budget: Option<Arc<CacheBudget>>) -> Self {
+        MailCache { budget, ..self }
+    }
+
+    /// Keep the entries in `store` too, as `owner`'s
+    pub fn with_store(self, store: Option<Arc<dyn CacheStore>>, owner: String) -> Self {
+        MailCache {
+            store: store.map(|store| (store, owner)),
+            ..self
+        }
+    }
+
+    /// Something may have changed on the server, so everything is checked
+    /// with it before it is used again
+    pub fn changed(&self) {
+        for account in self.accounts.lock().unwrap().values_mut() {
+            account.mailboxes_checked = None;
+            account.emails_checked = None;
+        }
+    }
+
+    fn fresh(&self, checked: Option<Instant>) -> bool {
+        checked.is_some_and(|at| at.elapsed() < self.ttl)
+    }
+
+    fn with_account<T>(&self, account_id: &str, f: impl FnOnce(&mut AccountCache) -> T) -> T {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(account_id.to_string()).or_insert_with(|| {
+            let Some((store, owner)) = &self.store else {
+                return AccountCache::new(self.budget.clone());
+            };
+            match store.load(owner, account_id) {
+                Ok(stored) => AccountCache::restore(stored, self.budget.clone()),
+                Err(e) => {
+                    log_error!("[CACHE] Failed to load the cache of account {}: {}", account_id, e);
+                    AccountCache::new(self.budget.clone())
+                }
+            }
+        });
+        f(account)
+    }
+
+    /// Write a change through to the store, if there is one. Called with
+    /// the account locked, so the store sees changes in the order made.
+    fn persist(&self, account_id: &str, write: impl FnOnce(&dyn CacheStore, &str) -> Result<(), String>) {
+        if let Some((store, owner)) = &self.store {
+            if let Err(e) = write(store.as_ref(), owner) {
+                log_error!("[CACHE] Failed to save the cache of account {}: {}", account_id, e);
+            }
+        }
+    }
+
+    /// The mailboxes kept with their state, and whether they were
+    /// confirmed recently enough to use without asking for changes
+    pub(super) fn mailboxes(&self, account_id: &str) -> Option<((Vec<Mailbox>, String), bool)> {
+        self.with_account(account_id, |account| {
+            let mailboxes = account.mailboxes.clone()?;
+            Some((mailboxes, self.fresh(account.mailboxes_checked)))
+        })
+    }
+
+    /// The mailboxes as the server has just confirmed or sent them
+    pub(super) fn store_mailboxes(&self, account_id: &str, mailboxes: &[Mailbox], state: &str) {
+        self.with_account(account_id, |account| {
+            account.mailboxes = Some((mailboxes.to_vec(), state.to_string()));
+            account.mailboxes_checked = Some(Instant::now());
+            self.persist(account_id, |store, owner| store.save_mailboxes(owner, account_id, mailboxes, state));
+        });
+    }
+
+    /// The state the kept emails are at, if any are, and whether it was
+    /// confirmed recently enough to skip asking for changes
+    pub(super) fn email_state(&self, account_id: &str) -> Option<(String, bool)> {
+        self.with_account(account_id, |account| {
+            let state = account.email_state.clone()?;
+            Some((state, self.fresh(account.emails_checked)))
+        })
+    }
+
+    /// Let go of the emails Email/changes reports as changed, keeping the
+    /// rest as of its new state
+    pub(super) fn apply_email_changes(&self, account_id: &str, changes: &ChangesResponse) {
+        self.with_account(account_id, |account| {
+            for id in changes.updated.iter().chain(&changes.destroyed) {
+                account.remove_email(id);
+            }
+            if !changes.updated.is_empty() || !changes.destroyed.is_empty() {
+                account.generation += 1;
+            }
+            account.email_state = Some(changes.new_state.clone());
+            account.emails_checked = Some(Instant::now());
+            let changed: Vec<String> = changes.updated.iter().chain(&changes.destroyed).cloned().collect();
+            self.persist(account_id, |store, owner| store.remove_emails(owner, account_id, &changed, &changes.new_state));
+        });
+    }
+
+    /// Let go of every email, when it can't be known which changed
+    pub(super) fn forget_emails(&self, account_id: &str) {
+        self.with_account(account_id, |account| {
+            account.clear_emails();
+            account.email_state = None;
+            account.emails_checked = None;
+            account.generation += 1;
+            self.persist(account_id, |store, owner| store.clear_emails(owner, account_id));
+        });
+    }
+
+    pub(super) fn generation(&self, account_id: &str) -> u64 {
+        self.with_account(account_id, |account| account.generation)
+    }
+
+    pub(super) fn rows(&self, account_id: &str, ids: &[String]) -> Rows {
+        self.with_account(account_id, |account| {
+            let (mut found, mut missing) = (Vec::new(), Vec::new());
+            for id in ids {
+                match account.emails.get(id) {
+                    Some(email) => found.push(email.clone()),
+                    None => missing.push(id.clone()),
+                }
+            }
+            Rows {
+                found,
+                missing,
+                state: account.email_state.clone(),
+                generation: account.generation,
+            }
+        })
+    }
+
+    /// Keep emails Email/get returned at `state`, unless emails were let go
+    /// since `generation` and these may be from before
+    pub(super) fn store_emails(&self, account_id: &str, emails: &[Email], state: &str, generation: u64) {
+        self.with_account(account_id, |account| {
+            if account.generation != generation {
+                return;
+            }
+            let new: Vec<&Email> = emails.iter().collect();
+            if account.emails.len() + emails.len() > MAX_EMAILS || !account.counted.fits(&new) {
+                account.clear_emails();
+                account.generation += 1;
+                self.persist(account_id, |store, owner| store.clear_emails(owner, account_id));
+            }
+            // The user's other sessions are keeping all the budget allows
+            if !account.counted.fits(&new) {
+                return;
+            }
+            if account.email_state.is_none() {
+                account.email_state = Some(state.to_string());
+                account.emails_checked = Some(Instant::now());
+            }
+            for email in emails {
+                account.insert_email(email.clone());
+            }
+            let kept_at = account.email_state.clone().unwrap_or_default();
+            self.persist(account_id, |store, owner| store.save_emails(owner, account_id, emails, &kept_at));
+        })
+    }
+
+    /// Remember what an Email/query with the arguments `query` found
+    pub(super) fn store_query(&self, account_id: &str, query: String, result: &EmailQueryResult) {
+        self.with_account(account_id, |account| {
+            if account.queries.len() >= MAX_QUERIES && !account.queries.contains_key(&query) {
+                account.queries.clear();
+            }
+            self.persist(account_id, |store, owner| {
+                store.save_query(owner, account_id, &query, result, MAX_QUERIES)
+            });
+            account.queries.insert(query, result.clone());
+        });
+    }
+
+    /// The last results of an Email/query and the emails in it, when every
+    /// one of them is kept, for showing while the server can't be reached
+    pub(super) fn offline_query(&self, account_id: &str, query: &str) -> Option<(EmailQueryResult, Vec<Email>, String)> {
+        self.with_account(account_id, |account| {
+            let result = account.queries.get(query)?;
+            let emails = result
+                .ids
+                .iter()
+                .map(|id| account.emails.get(id).cloned())
+                .collect::<Option<Vec<Email>>>()?;
+            Some((result.clone(), emails, account.email_state.clone()?))
+        })
+    }
+
+    /// How many kept emails match `filter`, and the first `limit` of them
+    /// in `sort` order; they may be behind the server
+    pub(super) fn search(&self, account_id: &str, filter: &Filter, sort: Sort, limit: usize) -> (usize, Vec<Email>) {
+        self.with_account(account_id, |account| {
+            let mut found: Vec<&Email> = match account.index.candidates(filter) {
+                Some(ids) => ids.into_iter().filter_map(|id| account.emails.get(id)).collect(),
+                None => account.emails.values().collect(),
+            };
+            found.retain(|email| index::matches(filter, email));
+            found.sort_by(|a, b| index::compare(sort, a, b));
+            (found.len(), found.into_iter().take(limit).cloned().collect())
+        })
+    }
+}
@@ -1,15 +1,127 @@
 use base64::Engine;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use zeroize::Zeroizing;
+
+use super::filter::{Filter, Position, Sort, SortProperty};
+use super::cache::MailCache;
+use super::transport::{self, HttpOptions, SocketError, Transport};
 use super::types::*;
+use super::websocket::WebSocket;
 use crate::{log_debug, log_error, log_info, log_warn};
 
+/// A blob download that has not been read yet
+pub struct BlobDownload {
+    pub reader: Box<dyn std::io::Read + Send + Sync>,
+    pub length: Option<usize>,
+    /// 206 when the server sent only the range asked for, and 416 when the
+    /// blob has none of it; otherwise the whole blob follows
+    pub status: u16,
+    /// The server's for a 206 or 416
+    pub content_range: Option<String>,
+}
+
+/// Notices when the server's session object has changed since it was
+/// fetched: responses carry a different sessionState, or the apiUrl has
+/// stopped answering. It also notices when the server stops accepting the
+/// credentials, as when a password is changed or an app password revoked.
+/// Clones share the flags, so any client made for a session can raise them.
+#[derive(Debug, Clone, Default)]
+pub struct SessionWatch {
+    state: String,
+    stale: Arc<AtomicBool>,
+    rejected: Arc<AtomicBool>,
+}
+
+impl SessionWatch {
+    fn new(state: String) -> Self {
+        SessionWatch {
+            state,
+            ..SessionWatch::default()
+        }
+    }
+
+    /// A watch that asks for the session object straight away, for a
+    /// session restored from before a restart
+    pub fn stale() -> Self {
+        let watch = SessionWatch::default();
+        watch.api_failed();
+        watch
+    }
+
+    /// Whether the session object should be fetched again
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+
+    fn saw(&self, session_state: &str) {
+        if !session_state.is_empty() && !self.state.is_empty() && session_state != self.state {
+            log_info!("[JMAP] Session state changed ({} -> {})", self.state, session_state);
+            self.stale.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn api_failed(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the server has answered 401 to the session's credentials
+    pub fn is_rejected(&self) -> bool {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Note that the server has turned the credentials down
+    pub fn credentials_rejected(&self) {
+        self.rejected.store(true, Ordering::Relaxed);
+    }
+}
+
+/// How the client proves who it is to the server
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AuthScheme {
+    /// HTTP Basic auth with the username and password
+    Basic,
+    /// `Authorization: Bearer`, with an API token given as the password
+    Bearer,
+}
+
+impl AuthScheme {
+    fn header(self, username: &str, secret: &str) -> String {
+        match self {
+            AuthScheme::Basic => {
+                let credentials = format!("{}:{}", username, secret);
+                let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+                format!("Basic {}", encoded)
+            }
+            AuthScheme::Bearer => format!("Bearer {}", secret),
+        }
+    }
+}
+
 pub struct JmapClient {
     username: String,
-    password: String,
-    api_url: String,
+    /// Wiped when the client is dropped
+    password: Zeroizing<String>,
+    auth_scheme: AuthScheme,
+    /// Replaced if the server's session object moves it
+    api_url: Mutex<String>,
+    /// Where the session object is fetched, to find a moved apiUrl again
+    well_known_url: Option<String>,
     account_id: String,
     download_url: Option<String>,
+    upload_url: Option<String>,
+    transport: Transport,
+    /// Where method calls go over WebSocket instead, if they do
+    websocket_url: Option<String>,
+    limits: Limits,
+    watch: SessionWatch,
+    /// The session's mailboxes and list rows, when it keeps them
+    cache: Option<MailCache>,
+    /// Whether to ask for S/MIME verification results (RFC 9219)
+    smime_verify: bool,
 }
 
 #[derive(Debug)]
@@ -17,6 +129,8 @@ pub enum JmapError {
     Http(String),
     Parse(String),
     Api(String),
+    /// The server turned the credentials down (401)
+    Unauthorized,
 }
 
 impl std::fmt::Display for JmapError {
@@ -25,39 +139,95 @@ impl std::fmt::Display for JmapError {
             JmapError::Http(e) => write!(f, "HTTP error: {}", e),
             JmapError::Parse(e) => write!(f, "Parse error: {}", e),
             JmapError::Api(e) => write!(f, "API error: {}", e),
+            JmapError::Unauthorized => write!(f, "Authentication failed (401 Unauthorized)"),
         }
     }
 }
 
+impl JmapError {
+    /// Whether the server turned a method call down with the JMAP error
+    /// `error_type`, such as "anchorNotFound"
+    pub fn is_method_error(&self, error_type: &str) -> bool {
+        matches!(self, JmapError::Api(e) if e.strip_suffix(error_type).is_some_and(|e| e.ends_with(" failed: ")))
+    }
+}
+
 impl JmapClient {
-    fn auth_header(username: &str, password: &str) -> String {
-        let credentials = format!("{}:{}", username, password);
-        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
-        format!("Basic {}", encoded)
+    fn auth_header(&self) -> String {
+        self.auth_scheme.header(&self.username, &self.password)
+    }
+
+    /// `e`, telling the watch if it is the server turning the credentials
+    /// down
+    fn watched(&self, e: JmapError) -> JmapError {
+        if matches!(e, JmapError::Unauthorized) {
+            log_warn!("[JMAP] The server no longer accepts the credentials for {}", self.username);
+            self.watch.credentials_rejected();
+        }
+        e
+    }
+
+    /// The error for a request the transport gave up on
+    fn http_error(&self, e: &ureq::Error) -> JmapError {
+        match e {
+            ureq::Error::Status(401, _) => self.watched(JmapError::Unauthorized),
+            e => JmapError::Http(transport::describe(e)),
+        }
     }
 
     /// Resolve a URL, following redirects manually while preserving the auth header.
     /// Returns the final URL and the response body.
     fn fetch_with_auth_following_redirects(
+        transport: &Transport,
         url: &str,
         auth: &str,
         max_redirects: u32,
     ) -> Result<(String, String), JmapError> {
-        let agent = ureq::AgentBuilder::new()
-            .redirects(0) // Don't auto-follow, we'll handle manually
-            .build();
+        let (final_url, resp) = Self::open_with_transport(transport, url, auth, &[], max_redirects)?;
+        let status = resp.status();
+
+        let body = resp
+            .into_string()
+            .map_err(|e| JmapError::Parse(format!("Failed to read response: {}", e)))?;
+
+        if body.is_empty() {
+            log_error!("[JMAP] Server returned empty response (status {})", status);
+            return Err(JmapError::Http(format!(
+                "Server returned empty response (status {})",
+                status
+            )));
+        }
 
+        log_debug!("[JMAP] Response body length: {} bytes", body.len());
+        Ok((final_url, body))
+    }
+
+    /// Like `fetch_with_auth_following_redirects`, but hands back the final
+    /// response unread so large or binary bodies can be streamed. A 416 to
+    /// a request with a Range header is handed back too.
+    fn open_with_transport(
+        transport: &Transport,
+        url: &str,
+        auth: &str,
+        headers: &[(&str, &str)],
+        max_redirects: u32,
+    ) -> Result<(String, ureq::Response), JmapError> {
         let mut current_url = url.to_string();
 
         for i in 0..max_redirects {
             log_debug!("[JMAP] Request {} to: {}", i + 1, current_url);
 
-            let response = agent
-                .get(&current_url)
-                .set("Authorization", auth)
-                .call();
-
-            match response {
+            let response = transport.send(
+                &format!("GET {}", current_url),
+                true,
+                |agent| {
+                    let request = agent.get(&current_url).set("Authorization", auth);
+                    headers.iter().fold(request, |request, (name, value)| request.set(name, value))
+                },
+                None,
+            );
+
+            match response.map_err(|e| *e) {
                 Ok(resp) => {
                     let status = resp.status();
                     log_debug!("[JMAP] Got {} response", status);
@@ -77,21 +247,7 @@ impl JmapClient {
                         }
                     }
 
-                    // Success - return the response body
-                    let body = resp
-                        .into_string()
-                        .map_err(|e| JmapError::Parse(format!("Failed to read response: {}", e)))?;
-
-                    if body.is_empty() {
-                        log_error!("[JMAP] Server returned empty response (status {})", status);
-                        return Err(JmapError::Http(format!(
-                            "Server returned empty response (status {})",
-                            status
-                        )));
-                    }
-
-                    log_debug!("[JMAP] Response body length: {} bytes", body.len());
-                    return Ok((current_url, body));
+                    return Ok((current_url, resp));
                 }
                 Err(ureq::Error::Status(code, resp)) if (300..400).contains(&code) => {
                     // Redirect returned as error - follow it with auth header preserved
@@ -106,15 +262,16 @@ impl JmapClient {
                         )));
                     }
                 }
+                Err(ureq::Error::Status(416, resp)) if headers.iter().any(|(name, _)| *name == "Range") => {
+                    return Ok((current_url, resp));
+                }
                 Err(ureq::Error::Status(code, resp)) => {
                     // HTTP error (4xx, 5xx)
                     let body = resp.into_string().unwrap_or_default();
                     log_error!("[JMAP] HTTP error {}: {}", code, body);
 
                     if code == 401 {
-                        return Err(JmapError::Http(
-                            "Authentication failed (401 Unauthorized)".to_string(),
-                        ));
+                        return Err(JmapError::Unauthorized);
                     }
 
                     return Err(JmapError::Http(format!(
@@ -165,17 +322,32 @@ impl JmapClient {
         }
     }
 
+    /// Fetch the session object and make a client for its mail account.
+    /// Each of `schemes` is tried in turn until the server accepts one; the
+    /// client keeps using that one.
     pub fn discover(
         well_known_url: &str,
         username: &str,
         password: &str,
+        schemes: &[AuthScheme],
+        options: HttpOptions,
     ) -> Result<(JmapSession, Self), JmapError> {
         log_info!("[JMAP] Discovering JMAP session from: {}", well_known_url);
-        let auth = Self::auth_header(username, password);
+        let transport = Transport::new(options);
 
         // Fetch the session, following redirects while preserving auth header
-        let (_final_url, response_text) =
-            Self::fetch_with_auth_following_redirects(well_known_url, &auth, 5)?;
+        let mut fetched = Err(JmapError::Unauthorized);
+        let mut auth_scheme = AuthScheme::Basic;
+        for &scheme in schemes {
+            auth_scheme = scheme;
+            let auth = scheme.header(username, password);
+            fetched = Self::fetch_with_auth_following_redirects(&transport, well_known_url, &auth, 5);
+            if !matches!(fetched, Err(JmapError::Unauthorized)) {
+                break;
+            }
+            log_info!("[JMAP] Server refused {:?} authentication", scheme);
+        }
+        let (_final_url, response_text) = fetched?;
 
         log_debug!("[JMAP] Session response received, parsing...");
 
@@ -208,10 +380,19 @@ impl JmapClient {
 
         let client = JmapClient {
             username: username.to_string(),
-            password: password.to_string(),
-            api_url: session.api_url.clone(),
+            password: Zeroizing::new(password.to_string()),
+            auth_scheme,
+            api_url: Mutex::new(session.api_url.clone()),
+            well_known_url: Some(well_known_url.to_string()),
             account_id,
             download_url: session.download_url.clone(),
+            upload_url: session.upload_url.clone(),
+            transport,
+            websocket_url: None,
+            limits: session.limits(),
+            watch: SessionWatch::new(session.state.clone()),
+            cache: None,
+            smime_verify: false,
         };
 
         Ok((session, client))
@@ -219,53 +400,184 @@ impl JmapClient {
 
     pub fn from_session(
         username: String,
-        password: String,
+        password: Zeroizing<String>,
         api_url: String,
         account_id: String,
         download_url: Option<String>,
+        upload_url: Option<String>,
+        transport: Transport,
     ) -> Self {
         JmapClient {
             username,
             password,
-            api_url,
+            auth_scheme: AuthScheme::Basic,
+            api_url: Mutex::new(api_url),
+            well_known_url: None,
             account_id,
             download_url,
+            upload_url,
+            transport,
+            websocket_url: None,
+            limits: Limits::default(),
+            watch: SessionWatch::default(),
+            cache: None,
+            smime_verify: false,
+        }
+    }
+
+    /// Keep requests within limits the server announced, rather than the
+    /// RFC's suggested minimums
+    pub fn with_limits(self, limits: Limits) -> Self {
+        JmapClient { limits, ..self }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Look for the apiUrl again at `well_known_url` when it stops
+    /// answering, as after the provider moves it
+    pub fn with_discovery(self, well_known_url: String) -> Self {
+        JmapClient {
+            well_known_url: Some(well_known_url),
+            ..self
         }
     }
 
+    pub fn with_auth_scheme(self, auth_scheme: AuthScheme) -> Self {
+        JmapClient { auth_scheme, ..self }
+    }
+
+    pub fn auth_scheme(&self) -> AuthScheme {
+        self.auth_scheme
+    }
+
+    /// Report session changes seen by this client to `watch`
+    pub fn with_watch(self, watch: SessionWatch) -> Self {
+        JmapClient { watch, ..self }
+    }
+
+    pub fn watch(&self) -> &SessionWatch {
+        &self.watch
+    }
+
+    /// Ask for the server's S/MIME verification results with the message
+    /// view, for an account that offers them
+    pub fn with_smime_verify(self, smime_verify: bool) -> Self {
+        JmapClient { smime_verify, ..self }
+    }
+
+    /// Make method calls over JMAP's WebSocket at `url` (RFC 8887), going
+    /// back to HTTP whenever it can't be used
+    pub fn with_websocket(self, websocket_url: Option<String>) -> Self {
+        JmapClient { websocket_url, ..self }
+    }
+
+    /// Answer repeat reads of mailboxes and list rows from `cache`, which
+    /// learns of changes made through this client
+    pub fn with_cache(self, cache: MailCache) -> Self {
+        JmapClient { cache: Some(cache), ..self }
+    }
+
+    /// The transport this client's requests share connections through
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
     pub fn account_id(&self) -> &str {
         &self.account_id
     }
 
-    pub fn api_url(&self) -> &str {
-        &self.api_url
+    pub fn api_url(&self) -> String {
+        self.api_url.lock().unwrap().clone()
     }
 
     pub fn download_url(&self) -> Option<&str> {
         self.download_url.as_deref()
     }
 
+    pub fn upload_url(&self) -> Option<&str> {
+        self.upload_url.as_deref()
+    }
+
     fn call(&self, request: JmapRequest) -> Result<JmapResponse, JmapError> {
+        let read_only = request.method_calls.iter().all(|m| transport::is_read_only(m.0));
+        let result = self.send(request);
+        // Even a failed request may have changed something before it failed
+        if let (false, Some(cache)) = (read_only, &self.cache) {
+            cache.changed();
+        }
+        result
+    }
+
+    fn send(&self, request: JmapRequest) -> Result<JmapResponse, JmapError> {
         let method_names: Vec<_> = request.method_calls.iter().map(|m| m.0).collect();
-        log_debug!("[JMAP] API call to {} - methods: {:?}", self.api_url, method_names);
+        log_debug!("[JMAP] API call to {} - methods: {:?}", self.api_url(), method_names);
 
-        let auth = Self::auth_header(&self.username, &self.password);
+        let auth = self.auth_header();
 
         let request_json = serde_json::to_string(&request)
             .map_err(|e| JmapError::Parse(format!("Failed to serialize request: {}", e)))?;
         log_debug!("[JMAP] Request body: {}", truncate_str(&request_json, 500));
 
-        let response = ureq::post(&self.api_url)
-            .set("Authorization", &auth)
-            .set("Content-Type", "application/json")
-            .send_json(&request)
-            .map_err(|e| {
+        let read_only = request.method_calls.iter().all(|m| transport::is_read_only(m.0));
+        if let Some(url) = &self.websocket_url {
+            let message = serde_json::to_value(&request)
+                .map_err(|e| JmapError::Parse(format!("Failed to serialize request: {}", e)))?;
+            match self.transport.call_over_socket(url, &auth, message) {
+                Ok(reply) => return self.socket_response(reply),
+                Err(SocketError::NotSent) => {}
+                // Reading again can't do any harm
+                Err(SocketError::Lost(e)) if read_only => {
+                    log_warn!("[JMAP] WebSocket failed ({}), repeating {:?} over HTTP", e, method_names);
+                }
+                Err(SocketError::Lost(e)) => {
+                    log_error!("[JMAP] WebSocket failed during {:?}: {}", method_names, e);
+                    return Err(JmapError::Http(format!("lost the connection to the mail server: {}", e)));
+                }
+            }
+        }
+
+        // A request that found nothing there, or was redirected, wasn't
+        // acted on, so it is sent once more if the apiUrl has moved
+        let mut rediscovered = false;
+        let response = loop {
+            let api_url = self.api_url();
+            let sent = self.transport.send(
+                &format!("{:?}", method_names),
+                read_only,
+                |agent| {
+                    agent
+                        .post(&api_url)
+                        .set("Authorization", &auth)
+                        .set("Content-Type", "application/json")
+                },
+                Some(request_json.as_bytes()),
+            );
+            let gone = match &sent {
+                Ok(response) => (300..400).contains(&response.status()),
+                Err(e) => transport::is_unreachable(e),
+            };
+            if gone {
+                // The rest of the session object may have moved with it
+                self.watch.api_failed();
+                if !rediscovered && self.rediscover(&api_url) {
+                    rediscovered = true;
+                    continue;
+                }
+            }
+            break sent.map_err(|e| {
                 log_error!("[JMAP] API call failed: {}", e);
-                JmapError::Http(e.to_string())
+                self.http_error(&e)
             })?;
+        };
 
         let status = response.status();
         log_debug!("[JMAP] API response status: {}", status);
+        if (300..400).contains(&status) {
+            log_error!("[JMAP] API call redirected ({}) to {:?}", status, response.header("location"));
+            return Err(JmapError::Http(format!("the mail server redirected the request (status {})", status)));
+        }
 
         let response_text = response
             .into_string()
@@ -278,11 +590,106 @@ impl JmapClient {
 
         let response_methods: Vec<_> = parsed.method_responses.iter().map(|m| &m.0).collect();
         log_debug!("[JMAP] Response methods: {:?}", response_methods);
+        self.watch.saw(&parsed.session_state);
+
+        Ok(parsed)
+    }
+
+    /// Fetch the session object again after `api_url` stopped answering,
+    /// and use its apiUrl from now on. Whether it has moved somewhere else.
+    fn rediscover(&self, api_url: &str) -> bool {
+        let Some(well_known_url) = &self.well_known_url else {
+            return false;
+        };
+        log_info!("[JMAP] {} stopped answering, fetching the session object again", api_url);
+        let fetched = Self::fetch_with_auth_following_redirects(&self.transport, well_known_url, &self.auth_header(), 5)
+            .and_then(|(_, body)| {
+                serde_json::from_str::<JmapSession>(&body)
+                    .map_err(|e| JmapError::Parse(format!("Failed to parse session: {}", e)))
+            });
+        match fetched {
+            Ok(session) if session.api_url != api_url => {
+                log_info!("[JMAP] API URL moved from {} to {}", api_url, session.api_url);
+                *self.api_url.lock().unwrap() = session.api_url;
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                log_error!("[JMAP] Failed to fetch the session object again: {}", self.watched(e));
+                false
+            }
+        }
+    }
 
+    /// What a request sent over the WebSocket was answered with: a
+    /// Response, or a RequestError where HTTP would have had an error
+    /// status
+    fn socket_response(&self, reply: serde_json::Value) -> Result<JmapResponse, JmapError> {
+        log_debug!("[JMAP] WebSocket reply: {}", truncate_str(&reply.to_string(), 1000));
+        if reply["@type"] == "RequestError" {
+            log_error!("[JMAP] Request refused over WebSocket: {}", reply);
+            if reply["status"] == 401 {
+                return Err(self.watched(JmapError::Unauthorized));
+            }
+            let detail = reply["detail"].as_str().or(reply["type"].as_str()).unwrap_or("unknown error");
+            return Err(JmapError::Api(format!("the mail server refused the request: {}", detail)));
+        }
+        let parsed: JmapResponse = serde_json::from_value(reply)
+            .map_err(|e| JmapError::Parse(format!("Failed to parse response: {}", e)))?;
+        self.watch.saw(&parsed.session_state);
         Ok(parsed)
     }
 
     pub fn get_mailboxes(&self) -> Result<Vec<Mailbox>, JmapError> {
+        self.get_mailboxes_with_state().map(|(mailboxes, _)| mailboxes)
+    }
+
+    /// The mailboxes along with the Mailbox state string, for a later
+    /// Mailbox/changes
+    pub fn get_mailboxes_with_state(&self) -> Result<(Vec<Mailbox>, String), JmapError> {
+        let Some(cache) = &self.cache else {
+            return self.mailbox_get(None);
+        };
+        let cached = match cache.mailboxes(&self.account_id) {
+            Some((cached, true)) => return Ok(cached),
+            // Mailbox/get again if anything changed at all, since that is
+            // usually just the counts and there are few mailboxes
+            Some((cached, false)) => match self.mailbox_changes(&cached.1) {
+                Ok(changes) if changes.is_empty() => {
+                    cache.store_mailboxes(&self.account_id, &cached.0, &changes.new_state);
+                    return Ok((cached.0, changes.new_state));
+                }
+                Ok(_) => Some(cached),
+                Err(JmapError::Http(e)) => {
+                    log_warn!("[JMAP] Server unreachable ({}), showing the cached mailboxes", e);
+                    return Ok(cached);
+                }
+                Err(e) => {
+                    log_warn!("[JMAP] Mailbox/changes since the cached {} failed: {}", cached.1, e);
+                    Some(cached)
+                }
+            },
+            None => None,
+        };
+        match self.mailbox_get(None) {
+            Ok((mailboxes, state)) => {
+                cache.store_mailboxes(&self.account_id, &mailboxes, &state);
+                Ok((mailboxes, state))
+            }
+            Err(JmapError::Http(e)) if cached.is_some() => {
+                log_warn!("[JMAP] Server unreachable ({}), showing the cached mailboxes", e);
+                Ok(cached.unwrap())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Just the given mailboxes, such as those Mailbox/changes reported
+    pub fn get_mailboxes_by_id(&self, ids: &[String]) -> Result<(Vec<Mailbox>, String), JmapError> {
+        self.mailbox_get(Some(ids))
+    }
+
+    fn mailbox_get(&self, ids: Option<&[String]>) -> Result<(Vec<Mailbox>, String), JmapError> {
         log_info!("[JMAP] Fetching mailboxes for account: {}", self.account_id);
 
         let request = JmapRequest {
@@ -291,7 +698,7 @@ impl JmapClient {
                 "Mailbox/get",
                 json!({
                     "accountId": self.account_id,
-                    "ids": null
+                    "ids": ids
                 }),
                 "0".to_string(),
             )],
@@ -305,7 +712,7 @@ impl JmapClient {
                     serde_json::from_value(method_response.1.clone())
                         .map_err(|e| JmapError::Parse(e.to_string()))?;
                 log_info!("[JMAP] Mailbox/get returned {} mailboxes", mailbox_response.list.len());
-                return Ok(mailbox_response.list);
+                return Ok((mailbox_response.list, mailbox_response.state));
             } else {
                 log_warn!("[JMAP] Unexpected method response: {}", method_response.0);
             }
@@ -316,206 +723,1556 @@ impl JmapClient {
         Err(JmapError::Api("Unexpected response".to_string()))
     }
 
-    pub fn query_emails(
-        &self,
-        mailbox_id: &str,
-        limit: u32,
-        position: u32,
-    ) -> Result<EmailQueryResult, JmapError> {
-        log_info!(
-            "[JMAP] Email/query for mailbox: {} (limit: {}, position: {})",
-            mailbox_id,
-            limit,
-            position
-        );
+    /// Which mailboxes changed since `since_state`. Fails with
+    /// cannotCalculateChanges if the server no longer knows that state.
+    pub fn mailbox_changes(&self, since_state: &str) -> Result<ChangesResponse, JmapError> {
+        self.changes("Mailbox/changes", since_state)
+    }
+
+    /// Which emails were created, updated or destroyed since `since_state`
+    pub fn email_changes(&self, since_state: &str) -> Result<ChangesResponse, JmapError> {
+        self.changes("Email/changes", since_state)
+    }
+
+    fn changes(&self, method: &'static str, since_state: &str) -> Result<ChangesResponse, JmapError> {
+        log_debug!("[JMAP] {} since {}", method, since_state);
 
         let request = JmapRequest {
             using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
             method_calls: vec![MethodCall(
-                "Email/query",
+                method,
                 json!({
                     "accountId": self.account_id,
-                    "filter": { "inMailbox": mailbox_id },
-                    "sort": [{ "property": "receivedAt", "isAscending": false }],
-                    "limit": limit,
-                    "position": position
+                    "sinceState": since_state
                 }),
                 "0".to_string(),
             )],
         };
 
         let response = self.call(request)?;
-
-        if let Some(method_response) = response.method_responses.first() {
-            if method_response.0 == "Email/query" {
-                let query_response: EmailQueryResponse =
-                    serde_json::from_value(method_response.1.clone())
-                        .map_err(|e| JmapError::Parse(e.to_string()))?;
-                log_info!(
-                    "[JMAP] Email/query returned {} email IDs (position: {}, total: {:?})",
-                    query_response.ids.len(),
-                    query_response.position,
-                    query_response.total
-                );
-                if !query_response.ids.is_empty() {
-                    log_debug!("[JMAP] Email IDs: {:?}", query_response.ids);
-                }
-                return Ok(EmailQueryResult {
-                    ids: query_response.ids,
-                    total: query_response.total,
-                    position: query_response.position,
-                });
-            } else {
-                log_warn!("[JMAP] Unexpected method response: {}", method_response.0);
-            }
-        } else {
-            log_error!("[JMAP] No method responses in Email/query response");
+        let result = method_result(&response, "0", method)?;
+        let changes: ChangesResponse =
+            serde_json::from_value(result.clone()).map_err(|e| JmapError::Parse(e.to_string()))?;
+        // Whoever asked will want what changed from the server, not the cache
+        if let (false, Some(cache)) = (changes.is_empty(), &self.cache) {
+            cache.changed();
         }
+        Ok(changes)
+    }
 
-        Err(JmapError::Api("Unexpected response".to_string()))
+    /// Create a mailbox, optionally nested under `parent_id`. Returns its id.
+    pub fn create_mailbox(&self, name: &str, parent_id: Option<&str>) -> Result<String, JmapError> {
+        log_info!("[JMAP] Mailbox/set create {:?} under {:?}", name, parent_id);
+        let result = self.mailbox_set(json!({
+            "create": { "new": { "name": name, "parentId": parent_id } }
+        }))?;
+        created_id(&result, "new")
     }
 
-    pub fn get_emails(&self, ids: &[String]) -> Result<Vec<Email>, JmapError> {
-        if ids.is_empty() {
-            log_debug!("[JMAP] Email/get called with empty ID list");
-            return Ok(vec![]);
+    pub fn rename_mailbox(&self, id: &str, name: &str) -> Result<(), JmapError> {
+        log_info!("[JMAP] Mailbox/set rename {} to {:?}", id, name);
+        let result = self.mailbox_set(json!({ "update": { id: { "name": name } } }))?;
+        match set_error(&result, "notUpdated", id) {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
+    }
 
-        log_info!("[JMAP] Email/get for {} email IDs", ids.len());
-        log_debug!("[JMAP] Requested email IDs: {:?}", ids);
+    /// Destroy a mailbox. The server refuses (mailboxHasEmail) unless it is empty.
+    pub fn destroy_mailbox(&self, id: &str) -> Result<(), JmapError> {
+        log_info!("[JMAP] Mailbox/set destroy {}", id);
+        let result = self.mailbox_set(json!({ "destroy": [id], "onDestroyRemoveEmails": false }))?;
+        match set_error(&result, "notDestroyed", id) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 
+    fn mailbox_set(&self, mut args: serde_json::Value) -> Result<serde_json::Value, JmapError> {
+        args["accountId"] = json!(self.account_id);
         let request = JmapRequest {
             using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
-            method_calls: vec![MethodCall(
-                "Email/get",
-                json!({
-                    "accountId": self.account_id,
-                    "ids": ids,
-                    "properties": [
-                        "id", "from", "to", "cc", "subject",
-                        "receivedAt", "preview", "textBody", "bodyValues", "keywords"
-                    ],
-                    "fetchTextBodyValues": true
-                }),
-                "0".to_string(),
-            )],
+            method_calls: vec![MethodCall("Mailbox/set", args, "0".to_string())],
+        };
+        let response = self.call(request)?;
+        method_result(&response, "0", "Mailbox/set").cloned()
+    }
+
+    /// Run Email/query with an arbitrary FilterCondition (or null for no
+    /// filter), newest first
+    pub fn query_emails_filtered(
+        &self,
+        filter: serde_json::Value,
+        limit: u32,
+        position: u32,
+    ) -> Result<EmailQueryResult, JmapError> {
+        self.query_emails_sorted(filter, &Sort::default(), limit, &Position::Index(position))
+    }
+
+    pub fn query_emails_sorted(
+        &self,
+        filter: serde_json::Value,
+        sort: &Sort,
+        limit: u32,
+        position: &Position,
+    ) -> Result<EmailQueryResult, JmapError> {
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![self.email_query_call(filter, sort, limit, position)],
         };
 
         let response = self.call(request)?;
+        parse_email_query(method_result(&response, "0", "Email/query")?)
+    }
 
-        if let Some(method_response) = response.method_responses.first() {
-            if method_response.0 == "Email/get" {
-                let email_response: EmailGetResponse =
-                    serde_json::from_value(method_response.1.clone())
-                        .map_err(|e| JmapError::Parse(e.to_string()))?;
+    /// A page of `filter`'s emails oldest first, in the order Email/query
+    /// found them, with only what an export names and frames each by
+    pub fn query_export_page(
+        &self,
+        filter: serde_json::Value,
+        limit: u32,
+        position: u32,
+    ) -> Result<(EmailQueryResult, Vec<Email>), JmapError> {
+        let oldest_first = Sort {
+            property: SortProperty::ReceivedAt,
+            ascending: true,
+        };
+        let limit = limit.min(self.limits.max_objects_in_get as u32);
+        let query = self.email_query_call(filter, &oldest_first, limit, &Position::Index(position));
+        let get_args = json!({
+            "accountId": self.account_id,
+            "#ids": query.result_ref("/ids"),
+            "properties": ["id", "blobId", "from", "subject", "receivedAt"]
+        });
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![query, MethodCall("Email/get", get_args, "1".to_string())],
+        };
 
-                log_info!(
-                    "[JMAP] Email/get returned {} emails (requested: {})",
-                    email_response.list.len(),
-                    ids.len()
-                );
+        let response = self.call(request)?;
+        let query_result = parse_email_query(method_result(&response, "0", "Email/query")?)?;
+        let mut emails = parse_email_get(method_result(&response, "1", "Email/get")?, query_result.ids.len())?.list;
+        emails.sort_by_key(|e| query_result.ids.iter().position(|id| *id == e.id));
+        Ok((query_result, emails))
+    }
 
-                if !email_response.not_found.is_empty() {
-                    log_error!(
-                        "[JMAP] Email/get: {} emails not found: {:?}",
-                        email_response.not_found.len(),
-                        email_response.not_found
-                    );
-                }
+    /// How many of the emails in the cache match `filter`, and the first
+    /// `limit` of them in `sort` order, found without asking the server and
+    /// so possibly behind it. `None` without a cache.
+    pub fn search_cached(&self, filter: &Filter, sort: Sort, limit: usize) -> Option<(usize, Vec<Email>)> {
+        let cache = self.cache.as_ref()?;
+        Some(cache.search(&self.account_id, filter, sort, limit))
+    }
 
-                if email_response.list.len() != ids.len() {
-                    log_warn!(
-                        "[JMAP] Email count mismatch! Requested {} but got {}",
-                        ids.len(),
-                        email_response.list.len()
-                    );
+    /// Email/query and an Email/get of the ids it finds in a single request,
+    /// the Email/get taking them by back-reference. Returns the emails in
+    /// whatever order the server sent them, with the Email state.
+    pub fn query_and_get_emails(
+        &self,
+        filter: serde_json::Value,
+        sort: &Sort,
+        limit: u32,
+        position: &Position,
+    ) -> Result<(EmailQueryResult, Vec<Email>, String), JmapError> {
+        let cache = match &self.cache {
+            Some(cache) if limit as usize <= self.limits.max_objects_in_get => cache,
+            _ => return self.query_and_fetch_emails(filter, sort, limit, position),
+        };
+        let query = json!([filter, sort.to_json(), limit, position.to_json()]).to_string();
+        match self.query_through_cache(cache, filter, sort, limit, position) {
+            Ok(found) => {
+                cache.store_query(&self.account_id, query, &found.0);
+                Ok(found)
+            }
+            Err(JmapError::Http(e)) => match cache.offline_query(&self.account_id, &query) {
+                Some(found) => {
+                    log_warn!("[JMAP] Server unreachable ({}), showing the list as last fetched", e);
+                    Ok(found)
                 }
+                None => Err(JmapError::Http(e)),
+            },
+            Err(e) => Err(e),
+        }
+    }
 
-                return Ok(email_response.list);
-            } else {
-                log_warn!("[JMAP] Unexpected method response: {}", method_response.0);
+    /// `query_and_get_emails` with the rows the cache has. Email/changes
+    /// goes along with the Email/query when they haven't been confirmed
+    /// lately, and only the rows not kept are fetched after.
+    fn query_through_cache(
+        &self,
+        cache: &MailCache,
+        filter: serde_json::Value,
+        sort: &Sort,
+        limit: u32,
+        position: &Position,
+    ) -> Result<(EmailQueryResult, Vec<Email>, String), JmapError> {
+        let Some((since, fresh)) = cache.email_state(&self.account_id) else {
+            let generation = cache.generation(&self.account_id);
+            let (query_result, emails, state) = self.query_and_fetch_emails(filter, sort, limit, position)?;
+            cache.store_emails(&self.account_id, &emails, &state, generation);
+            return Ok((query_result, emails, state));
+        };
+
+        let mut method_calls = vec![self.email_query_call(filter, sort, limit, position)];
+        if !fresh {
+            method_calls.push(MethodCall(
+                "Email/changes",
+                json!({
+                    "accountId": self.account_id,
+                    "sinceState": since
+                }),
+                "1".to_string(),
+            ));
+        }
+        let response = self.call(JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls,
+        })?;
+        let query_result = parse_email_query(method_result(&response, "0", "Email/query")?)?;
+        if !fresh {
+            let changes = method_result(&response, "1", "Email/changes").and_then(|result| {
+                serde_json::from_value::<ChangesResponse>(result.clone()).map_err(|e| JmapError::Parse(e.to_string()))
+            });
+            match changes {
+                Ok(changes) if !changes.has_more_changes => cache.apply_email_changes(&self.account_id, &changes),
+                _ => cache.forget_emails(&self.account_id),
             }
-        } else {
-            log_error!("[JMAP] No method responses in Email/get response");
         }
 
-        Err(JmapError::Api("Unexpected response".to_string()))
+        let rows = cache.rows(&self.account_id, &query_result.ids);
+        log_debug!("[JMAP] {} of {} list rows were cached", rows.found.len(), query_result.ids.len());
+        let mut emails = rows.found;
+        let mut state = rows.state;
+        // Without a state nothing was kept, and the Email/get finds it
+        if !rows.missing.is_empty() || state.is_none() {
+            let fetched = self.email_get(&rows.missing, &[])?;
+            cache.store_emails(&self.account_id, &fetched.list, &fetched.state, rows.generation);
+            emails.extend(fetched.list);
+            state = state.or(Some(fetched.state));
+        }
+        Ok((query_result, emails, state.unwrap_or_default()))
     }
 
-    pub fn get_email(&self, id: &str) -> Result<Option<Email>, JmapError> {
-        let emails = self.get_emails(&[id.to_string()])?;
-        Ok(emails.into_iter().next())
-    }
+    fn query_and_fetch_emails(
+        &self,
+        filter: serde_json::Value,
+        sort: &Sort,
+        limit: u32,
+        position: &Position,
+    ) -> Result<(EmailQueryResult, Vec<Email>, String), JmapError> {
+        // More ids than one Email/get may take have to be split up, which a
+        // back-reference can't do
+        if limit as usize > self.limits.max_objects_in_get {
+            let query_result = self.query_emails_sorted(filter, sort, limit, position)?;
+            let emails = self.email_get(&query_result.ids, &[])?;
+            return Ok((query_result, emails.list, emails.state));
+        }
 
-    /// Get raw email source (RFC 5322) via blob download
-    pub fn get_email_raw(&self, id: &str) -> Result<Option<String>, JmapError> {
-        log_info!("[JMAP] Fetching raw email via blob: {}", id);
+        let query = self.email_query_call(filter, sort, limit, position);
+        let mut get_args = self.email_get_args(&[]);
+        get_args["#ids"] = json!(query.result_ref("/ids"));
 
-        // First, get the blobId for this email
         let request = JmapRequest {
             using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
-            method_calls: vec![MethodCall(
-                "Email/get",
-                json!({
-                    "accountId": self.account_id,
-                    "ids": [id],
-                    "properties": ["id", "blobId"]
-                }),
-                "0".to_string(),
-            )],
+            method_calls: vec![
+                query,
+                MethodCall("Email/get", get_args, "1".to_string()),
+            ],
         };
 
         let response = self.call(request)?;
+        let query_result = parse_email_query(method_result(&response, "0", "Email/query")?)?;
+        let emails = parse_email_get(method_result(&response, "1", "Email/get")?, query_result.ids.len())?;
+        Ok((query_result, emails.list, emails.state))
+    }
 
-        let blob_id = if let Some(method_response) = response.method_responses.first() {
-            if method_response.0 == "Email/get" {
-                method_response.1["list"]
-                    .as_array()
-                    .and_then(|list| list.first())
-                    .and_then(|email| email["blobId"].as_str())
-                    .map(|s| s.to_string())
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+    fn email_query_call(&self, filter: serde_json::Value, sort: &Sort, limit: u32, position: &Position) -> MethodCall {
+        log_debug!("[JMAP] Email/query filter: {} sort: {:?} at: {:?}", filter, sort, position);
+        let mut args = position.to_json();
+        args["accountId"] = json!(self.account_id);
+        args["filter"] = filter;
+        args["sort"] = sort.to_json();
+        args["limit"] = json!(limit);
+        // Servers only report the total when asked; without it pagination
+        // has to guess whether more pages exist
+        args["calculateTotal"] = json!(true);
+        MethodCall("Email/query", args, "0".to_string())
+    }
 
-        let blob_id = match blob_id {
-            Some(id) => id,
-            None => {
-                log_error!("[JMAP] No blobId found for email: {}", id);
-                return Ok(None);
-            }
-        };
+    pub fn get_emails(&self, ids: &[String]) -> Result<Vec<Email>, JmapError> {
+        self.get_emails_with_properties(ids, &[])
+    }
 
-        log_debug!("[JMAP] Got blobId: {}", blob_id);
+    /// The emails along with the Email state string, for a later
+    /// Email/changes. Unlike `get_emails` this asks the server even for no
+    /// ids, since the state is still wanted.
+    pub fn get_emails_with_state(&self, ids: &[String]) -> Result<(Vec<Email>, String), JmapError> {
+        self.email_get(ids, &[]).map(|response| (response.list, response.state))
+    }
+
+    /// Email/get with the standard list/view properties plus any extras
+    fn get_emails_with_properties(
+        &self,
+        ids: &[String],
+        extra_properties: &[&str],
+    ) -> Result<Vec<Email>, JmapError> {
+        if ids.is_empty() {
+            log_debug!("[JMAP] Email/get called with empty ID list");
+            return Ok(vec![]);
+        }
+        self.email_get(ids, extra_properties).map(|response| response.list)
+    }
+
+    fn email_get(&self, ids: &[String], extra_properties: &[&str]) -> Result<EmailGetResponse, JmapError> {
+        log_info!("[JMAP] Email/get for {} email IDs", ids.len());
+        log_debug!("[JMAP] Requested email IDs: {:?}", ids);
+
+        // One call per maxObjectsInGet ids; an empty list still needs one
+        // call, for the state
+        let chunks: Vec<&[String]> = if ids.is_empty() {
+            vec![ids]
+        } else {
+            ids.chunks(self.limits.max_objects_in_get).collect()
+        };
+        let calls = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut args = self.email_get_args(extra_properties);
+                args["ids"] = json!(chunk);
+                MethodCall("Email/get", args, i.to_string())
+            })
+            .collect();
+
+        let mut using = vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"];
+        if extra_properties.contains(&"smimeStatus") {
+            using.push("urn:ietf:params:jmap:smimeverify");
+        }
+        let results = self.call_batched(using, calls)?;
+        let mut merged: Option<EmailGetResponse> = None;
+        for (result, chunk) in results.iter().zip(&chunks) {
+            let response = parse_email_get(result, chunk.len())?;
+            match &mut merged {
+                Some(merged) => {
+                    merged.list.extend(response.list);
+                    merged.not_found.extend(response.not_found);
+                    merged.state = response.state;
+                }
+                None => merged = Some(response),
+            }
+        }
+        merged.ok_or_else(|| JmapError::Api("No Email/get response".to_string()))
+    }
+
+    /// Make `calls` in as few requests as maxCallsInRequest and
+    /// maxSizeRequest allow, returning each call's result in order. The
+    /// calls must have distinct ids and not refer to each other.
+    fn call_batched(
+        &self,
+        using: Vec<&'static str>,
+        calls: Vec<MethodCall>,
+    ) -> Result<Vec<serde_json::Value>, JmapError> {
+        // Room for `using` and the rest of the request around the calls
+        let max_size = self.limits.max_size_request.saturating_sub(1024);
+
+        let mut batches: Vec<Vec<MethodCall>> = Vec::new();
+        let mut batch_size = 0;
+        for call in calls {
+            let size = serde_json::to_string(&call).map(|s| s.len() + 1).unwrap_or(0);
+            let full = batches.last().is_none_or(|batch| {
+                batch.len() >= self.limits.max_calls_in_request || batch_size + size > max_size
+            });
+            if full {
+                batches.push(Vec::new());
+                batch_size = 0;
+            }
+            batch_size += size;
+            batches.last_mut().unwrap().push(call);
+        }
+        if batches.len() > 1 {
+            log_debug!("[JMAP] Splitting {} calls over {} requests", batches.iter().map(Vec::len).sum::<usize>(), batches.len());
+        }
+
+        let mut results = Vec::new();
+        for batch in batches {
+            let names: Vec<(&'static str, String)> = batch.iter().map(|c| (c.0, c.2.clone())).collect();
+            let response = self.call(JmapRequest {
+                using: using.clone(),
+                method_calls: batch,
+            })?;
+            for (name, call_id) in names {
+                results.push(method_result(&response, &call_id, name)?.clone());
+            }
+        }
+        Ok(results)
+    }
+
+    /// Email/get arguments for the standard list/view properties plus any
+    /// extras, leaving the caller to add `ids` or a `#ids` back-reference
+    fn email_get_args(&self, extra_properties: &[&str]) -> serde_json::Value {
+        let mut properties = vec![
+            "id", "threadId", "from", "to", "cc", "subject",
+            "receivedAt", "size", "blobId", "preview", "textBody", "bodyValues", "keywords",
+            "attachments", "mailboxIds",
+        ];
+        properties.extend_from_slice(extra_properties);
+
+        json!({
+            "accountId": self.account_id,
+            "properties": properties,
+            "fetchTextBodyValues": true,
+            "fetchHTMLBodyValues": properties.contains(&"htmlBody")
+        })
+    }
+
+    /// Fetch a single email for the viewer, including its Authentication-Results
+    /// and the headers needed to reply to it or resume it as a draft
+    pub fn get_email(&self, id: &str) -> Result<Option<Email>, JmapError> {
+        Ok(self.get_emails_full(&[id.to_string()])?.into_iter().next())
+    }
+
+    /// Email/get with everything the message view needs (HTML body, headers,
+    /// body structure, and S/MIME results where the server has them)
+    pub fn get_emails_full(&self, ids: &[String]) -> Result<Vec<Email>, JmapError> {
+        let mut properties = vec![
+            "header:Authentication-Results:asText:all",
+            "htmlBody",
+            "bcc",
+            "replyTo",
+            "messageId",
+            "inReplyTo",
+            "references",
+            "bodyStructure",
+        ];
+        if self.smime_verify {
+            properties.extend(["smimeStatus", "smimeErrors", "smimeVerifiedAt"]);
+        }
+        self.get_emails_with_properties(ids, &properties)
+    }
+
+    /// Every header field of an email, for showing them all
+    pub fn get_email_headers(&self, id: &str) -> Result<Option<Vec<EmailHeader>>, JmapError> {
+        let emails = self.get_emails_with_properties(&[id.to_string()], &["headers"])?;
+        Ok(emails.into_iter().next().map(|e| e.headers.unwrap_or_default()))
+    }
+
+    /// Thread/get: the email ids of each thread
+    pub fn get_threads(&self, ids: &[String]) -> Result<Vec<Thread>, JmapError> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        log_info!("[JMAP] Thread/get for {} threads", ids.len());
+
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![MethodCall(
+                "Thread/get",
+                json!({
+                    "accountId": self.account_id,
+                    "ids": ids
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "Thread/get")?;
+        let threads: ThreadGetResponse =
+            serde_json::from_value(result.clone()).map_err(|e| JmapError::Parse(e.to_string()))?;
+        Ok(threads.list)
+    }
+
+    /// Open the raw email source (RFC 5322) for streaming, via blob download
+    pub fn download_email_raw(&self, id: &str) -> Result<Option<BlobDownload>, JmapError> {
+        log_info!("[JMAP] Fetching raw email via blob: {}", id);
+
+        // First, get the blobId for this email
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![MethodCall(
+                "Email/get",
+                json!({
+                    "accountId": self.account_id,
+                    "ids": [id],
+                    "properties": ["id", "blobId"]
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
 
-        // Now download the blob
-        let download_url = match &self.download_url {
-            Some(url) => url,
+        let blob_id = if let Some(method_response) = response.method_responses.first() {
+            if method_response.0 == "Email/get" {
+                method_response.1["list"]
+                    .as_array()
+                    .and_then(|list| list.first())
+                    .and_then(|email| email["blobId"].as_str())
+                    .map(|s| s.to_string())
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        let blob_id = match blob_id {
+            Some(id) => id,
             None => {
-                log_error!("[JMAP] No download URL available");
-                return Err(JmapError::Api("No download URL available".to_string()));
+                log_error!("[JMAP] No blobId found for email: {}", id);
+                return Ok(None);
             }
         };
 
-        // JMAP download URL is a template like:
-        // https://server/download/{accountId}/{blobId}/{name}?accept={type}
+        log_debug!("[JMAP] Got blobId: {}", blob_id);
+        self.download_blob(&blob_id, "email.eml", "message/rfc822").map(Some)
+    }
+
+    /// Upload a blob via the session uploadUrl so it can be attached to a draft
+    pub fn upload_blob(&self, data: &[u8], content_type: &str) -> Result<UploadResponse, JmapError> {
+        log_info!("[JMAP] Uploading blob ({} bytes, {})", data.len(), content_type);
+
+        let upload_url = self
+            .upload_url
+            .as_ref()
+            .ok_or_else(|| JmapError::Api("No upload URL available".to_string()))?;
+        let url = upload_url.replace("{accountId}", &percent_encode(&self.account_id));
+
+        let auth = self.auth_header();
+        // Uploading the same bytes again just makes another blob
+        let response = self
+            .transport
+            .send(
+                "Upload",
+                true,
+                |agent| agent.post(&url).set("Authorization", &auth).set("Content-Type", content_type),
+                Some(data),
+            )
+            .map_err(|e| {
+                log_error!("[JMAP] Upload failed: {}", e);
+                self.http_error(&e)
+            })?;
+
+        let uploaded: UploadResponse = response
+            .into_json()
+            .map_err(|e| JmapError::Parse(format!("Failed to parse upload response: {}", e)))?;
+        log_info!("[JMAP] Uploaded blob {} ({} bytes)", uploaded.blob_id, uploaded.size);
+        Ok(uploaded)
+    }
+
+    /// Upload a blob read from `data` as it is sent, for one too large to
+    /// hold in memory. Unlike `upload_blob` it isn't retried.
+    pub fn upload_blob_stream(&self, data: impl Read, content_type: &str) -> Result<UploadResponse, JmapError> {
+        log_info!("[JMAP] Streaming a blob upload ({})", content_type);
+
+        let upload_url = self
+            .upload_url
+            .as_ref()
+            .ok_or_else(|| JmapError::Api("No upload URL available".to_string()))?;
+        let url = upload_url.replace("{accountId}", &percent_encode(&self.account_id));
+
+        let auth = self.auth_header();
+        let response = self
+            .transport
+            .send_reader(
+                |agent| agent.post(&url).set("Authorization", &auth).set("Content-Type", content_type),
+                data,
+            )
+            .map_err(|e| {
+                log_error!("[JMAP] Upload failed: {}", e);
+                self.http_error(&e)
+            })?;
+
+        let uploaded: UploadResponse = response
+            .into_json()
+            .map_err(|e| JmapError::Parse(format!("Failed to parse upload response: {}", e)))?;
+        log_info!("[JMAP] Uploaded blob {} ({} bytes)", uploaded.blob_id, uploaded.size);
+        Ok(uploaded)
+    }
+
+    /// The largest blob the server takes (maxSizeUpload)
+    pub fn max_upload_size(&self) -> u64 {
+        self.limits.max_size_upload
+    }
+
+    /// Open a blob via the session downloadUrl for streaming to the browser
+    pub fn download_blob(
+        &self,
+        blob_id: &str,
+        name: &str,
+        content_type: &str,
+    ) -> Result<BlobDownload, JmapError> {
+        self.download_blob_range(blob_id, name, content_type, None)
+    }
+
+    /// Like `download_blob`, asking for just the bytes `range` (a Range
+    /// header's value) names. The server may send the whole blob anyway.
+    pub fn download_blob_range(
+        &self,
+        blob_id: &str,
+        name: &str,
+        content_type: &str,
+        range: Option<&str>,
+    ) -> Result<BlobDownload, JmapError> {
+        match range {
+            Some(range) => log_info!("[JMAP] Downloading {} of blob {} ({})", range, blob_id, content_type),
+            None => log_info!("[JMAP] Downloading blob {} ({})", blob_id, content_type),
+        }
+
+        let download_url = self
+            .download_url
+            .as_ref()
+            .ok_or_else(|| JmapError::Api("No download URL available".to_string()))?;
         let url = download_url
-            .replace("{accountId}", &self.account_id)
-            .replace("{blobId}", &blob_id)
-            .replace("{name}", "email.eml")
-            .replace("{type}", "message/rfc822");
+            .replace("{accountId}", &percent_encode(&self.account_id))
+            .replace("{blobId}", &percent_encode(blob_id))
+            .replace("{name}", &percent_encode(name))
+            .replace("{type}", &percent_encode(content_type));
+
+        let auth = self.auth_header();
+        let headers: Vec<(&str, &str)> = range.map(|range| ("Range", range)).into_iter().collect();
+        let (_, response) =
+            Self::open_with_transport(&self.transport, &url, &auth, &headers, 5).map_err(|e| self.watched(e))?;
+        let length = response
+            .header("content-length")
+            .and_then(|l| l.parse().ok());
+        let status = response.status();
+        let content_range = match status {
+            206 | 416 => response.header("content-range").map(str::to_string),
+            _ => None,
+        };
+
+        Ok(BlobDownload {
+            reader: Box::new(response.into_reader()),
+            length,
+            status,
+            content_range,
+        })
+    }
+
+    /// Connect to the session's eventSourceUrl for Email and Mailbox state
+    /// changes, with the server pinging every `ping` seconds. A connection
+    /// silent for three pings is treated as dead.
+    pub fn open_event_source(&self, url_template: &str, ping: u64) -> Result<Box<dyn Read + Send>, JmapError> {
+        let url = url_template
+            .replace("{types}", "Email,Mailbox")
+            .replace("{closeafter}", "no")
+            .replace("{ping}", &ping.to_string());
+        log_info!("[JMAP] Opening event source: {}", url);
+
+        let transport = Transport::for_stream(self.transport.options(), std::time::Duration::from_secs(ping * 3));
+        let auth = self.auth_header();
+        let (_, response) = Self::open_with_transport(&transport, &url, &auth, &[], 5)?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    /// Open a WebSocket of its own at `url` for the server to push
+    /// Email and Mailbox changes over (RFC 8887 section 4.3). Reads give
+    /// up after `ping` seconds, for the caller to check the connection.
+    pub fn open_push_socket(&self, url: &str, ping: u64) -> Result<WebSocket, JmapError> {
+        log_info!("[JMAP] Opening push WebSocket: {}", url);
+        let options = self.transport.options();
+        let mut socket = WebSocket::connect(url, &self.auth_header(), &options, std::time::Duration::from_secs(ping))
+            .map_err(JmapError::Http)?;
+        let enable = json!({
+            "@type": "WebSocketPushEnable",
+            "dataTypes": ["Email", "Mailbox"],
+        });
+        socket
+            .send(&enable.to_string())
+            .map_err(|e| JmapError::Http(format!("failed to enable push: {}", e)))?;
+        Ok(socket)
+    }
+
+    /// Permanently destroy emails with Email/set. Returns how many were destroyed.
+    pub fn destroy_emails(&self, ids: &[String]) -> Result<usize, JmapError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        log_info!("[JMAP] Email/set destroy for {} emails", ids.len());
+
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![MethodCall(
+                "Email/set",
+                json!({
+                    "accountId": self.account_id,
+                    "destroy": ids
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "Email/set")?;
+
+        if let Some(not_destroyed) = result["notDestroyed"].as_object() {
+            if !not_destroyed.is_empty() {
+                log_warn!("[JMAP] {} emails not destroyed: {:?}", not_destroyed.len(), not_destroyed);
+            }
+        }
+        let destroyed = result["destroyed"].as_array().map(|a| a.len()).unwrap_or(0);
+        log_info!("[JMAP] Email/set destroyed {} emails", destroyed);
+        Ok(destroyed)
+    }
+
+    /// Set (true) or clear (false) keywords such as `$seen` on emails with
+    /// Email/set patches. Returns how many were updated.
+    pub fn set_keywords(&self, ids: &[String], keywords: &[(&str, bool)]) -> Result<usize, JmapError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        log_info!("[JMAP] Email/set keywords {:?} on {} emails", keywords, ids.len());
+
+        let patch: serde_json::Map<String, serde_json::Value> = keywords
+            .iter()
+            .map(|(keyword, set)| {
+                let value = if *set { json!(true) } else { serde_json::Value::Null };
+                (format!("keywords/{}", keyword), value)
+            })
+            .collect();
+        self.update_emails(ids, patch)
+    }
+
+    /// File emails in exactly one mailbox, removing them from all others
+    pub fn move_emails(&self, ids: &[String], mailbox_id: &str) -> Result<usize, JmapError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        log_info!("[JMAP] Email/set moving {} emails to mailbox {}", ids.len(), mailbox_id);
+
+        let mut patch = serde_json::Map::new();
+        patch.insert("mailboxIds".to_string(), json!({ mailbox_id: true }));
+        self.update_emails(ids, patch)
+    }
+
+    /// Report emails as spam (`junk`) or as wrongly taken for it, setting
+    /// $junk or $notjunk and clearing the other, and in the same Email/set
+    /// filing them in `mailbox_id` alone when given
+    pub fn report_junk(&self, ids: &[String], junk: bool, mailbox_id: Option<&str>) -> Result<usize, JmapError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        log_info!("[JMAP] Email/set reporting {} emails as {}", ids.len(), if junk { "junk" } else { "not junk" });
+
+        let (set, clear) = if junk { ("$junk", "$notjunk") } else { ("$notjunk", "$junk") };
+        let mut patch = serde_json::Map::new();
+        patch.insert(format!("keywords/{}", set), json!(true));
+        patch.insert(format!("keywords/{}", clear), serde_json::Value::Null);
+        if let Some(mailbox_id) = mailbox_id {
+            patch.insert("mailboxIds".to_string(), json!({ mailbox_id: true }));
+        }
+        self.update_emails(ids, patch)
+    }
+
+    /// Add emails to a mailbox, keeping them in the ones they're already in
+    pub fn copy_emails(&self, ids: &[String], mailbox_id: &str) -> Result<usize, JmapError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        log_info!("[JMAP] Email/set adding {} emails to mailbox {}", ids.len(), mailbox_id);
+
+        let mut patch = serde_json::Map::new();
+        patch.insert(format!("mailboxIds/{}", mailbox_id), json!(true));
+        self.update_emails(ids, patch)
+    }
+
+    /// Email/import the uploaded messages `blob_ids` into `mailbox_id`, with
+    /// `keywords` set on each. Returns, in the same order, each new email's
+    /// id or why the server didn't import it.
+    pub fn import_emails(
+        &self,
+        blob_ids: &[String],
+        mailbox_id: &str,
+        keywords: &[&str],
+    ) -> Result<Vec<Result<String, JmapError>>, JmapError> {
+        if blob_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        log_info!("[JMAP] Email/import of {} messages into mailbox {}", blob_ids.len(), mailbox_id);
+
+        let keywords: serde_json::Map<String, serde_json::Value> =
+            keywords.iter().map(|keyword| (keyword.to_string(), json!(true))).collect();
+        let emails: serde_json::Map<String, serde_json::Value> = blob_ids
+            .iter()
+            .enumerate()
+            .map(|(i, blob_id)| {
+                let email = json!({ "blobId": blob_id, "mailboxIds": { mailbox_id: true }, "keywords": keywords });
+                (format!("import{}", i), email)
+            })
+            .collect();
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![MethodCall(
+                "Email/import",
+                json!({ "accountId": self.account_id, "emails": emails }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "Email/import")?;
+        Ok((0..blob_ids.len()).map(|i| created_id(result, &format!("import{}", i))).collect())
+    }
+
+    /// Apply the same Email/set patch to every id. Returns how many were updated.
+    /// Apply `patch` to each of `ids`. When the server changes none of
+    /// them, the SetError it gave the first is the error.
+    fn update_emails(
+        &self,
+        ids: &[String],
+        patch: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<usize, JmapError> {
+        let update: serde_json::Map<String, serde_json::Value> = ids
+            .iter()
+            .map(|id| (id.clone(), serde_json::Value::Object(patch.clone())))
+            .collect();
+        let result = self.email_set(update, &[])?;
+        match (changed_count(&result), ids.first()) {
+            (0, Some(first)) => set_error(&result, "notUpdated", first).map_or(Ok(0), Err),
+            (updated, _) => Ok(updated),
+        }
+    }
+
+    /// One Email/set with per-email patches and destroys, so bulk actions
+    /// cost a single round-trip. Returns how many emails were changed.
+    pub fn set_emails(
+        &self,
+        update: serde_json::Map<String, serde_json::Value>,
+        destroy: &[String],
+    ) -> Result<usize, JmapError> {
+        if update.is_empty() && destroy.is_empty() {
+            return Ok(0);
+        }
+        self.email_set(update, destroy).map(|result| changed_count(&result))
+    }
+
+    fn email_set(
+        &self,
+        update: serde_json::Map<String, serde_json::Value>,
+        destroy: &[String],
+    ) -> Result<serde_json::Value, JmapError> {
+        log_info!(
+            "[JMAP] Email/set updating {} and destroying {} emails",
+            update.len(),
+            destroy.len()
+        );
+
+        let mut args = json!({ "accountId": self.account_id });
+        if !update.is_empty() {
+            args["update"] = serde_json::Value::Object(update);
+        }
+        if !destroy.is_empty() {
+            args["destroy"] = json!(destroy);
+        }
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![MethodCall("Email/set", args, "0".to_string())],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "Email/set")?;
+
+        for failures in ["notUpdated", "notDestroyed"] {
+            if let Some(failed) = result[failures].as_object() {
+                if !failed.is_empty() {
+                    log_warn!("[JMAP] Email/set {}: {:?}", failures, failed);
+                }
+            }
+        }
+        Ok(result.clone())
+    }
+
+    pub fn get_identities(&self) -> Result<Vec<Identity>, JmapError> {
+        log_info!("[JMAP] Fetching identities for account: {}", self.account_id);
+
+        let request = JmapRequest {
+            using: vec![
+                "urn:ietf:params:jmap:core",
+                "urn:ietf:params:jmap:mail",
+                "urn:ietf:params:jmap:submission",
+            ],
+            method_calls: vec![MethodCall(
+                "Identity/get",
+                json!({
+                    "accountId": self.account_id,
+                    "ids": null
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "Identity/get")?;
+        let identities: IdentityGetResponse = serde_json::from_value(result.clone())
+            .map_err(|e| JmapError::Parse(e.to_string()))?;
+
+        log_info!("[JMAP] Identity/get returned {} identities", identities.list.len());
+        Ok(identities.list)
+    }
+
+    pub fn get_vacation_response(&self) -> Result<VacationResponse, JmapError> {
+        log_info!("[JMAP] Fetching vacation response for account: {}", self.account_id);
+
+        let request = JmapRequest {
+            using: vec![
+                "urn:ietf:params:jmap:core",
+                "urn:ietf:params:jmap:vacationresponse",
+            ],
+            method_calls: vec![MethodCall(
+                "VacationResponse/get",
+                json!({
+                    "accountId": self.account_id,
+                    "ids": ["singleton"]
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "VacationResponse/get")?;
+        let vacation: VacationResponseGetResponse = serde_json::from_value(result.clone())
+            .map_err(|e| JmapError::Parse(e.to_string()))?;
+
+        Ok(vacation.list.into_iter().next().unwrap_or_default())
+    }
+
+    /// The account's quotas and how much of each it uses
+    pub fn get_quotas(&self) -> Result<Vec<Quota>, JmapError> {
+        log_info!("[JMAP] Fetching quotas for account: {}", self.account_id);
+
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:quota"],
+            method_calls: vec![MethodCall(
+                "Quota/get",
+                json!({
+                    "accountId": self.account_id,
+                    "ids": null
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "Quota/get")?;
+        let quotas: QuotaGetResponse = serde_json::from_value(result.clone())
+            .map_err(|e| JmapError::Parse(e.to_string()))?;
+        Ok(quotas.list)
+    }
+
+    /// Replace the auto-reply settings. The HTML body is cleared so the
+    /// server sends the plain text one edited here rather than a stale copy.
+    pub fn set_vacation_response(&self, vacation: &VacationResponse) -> Result<(), JmapError> {
+        log_info!(
+            "[JMAP] VacationResponse/set enabled={} from={:?} to={:?}",
+            vacation.is_enabled,
+            vacation.from_date,
+            vacation.to_date
+        );
+
+        let request = JmapRequest {
+            using: vec![
+                "urn:ietf:params:jmap:core",
+                "urn:ietf:params:jmap:vacationresponse",
+            ],
+            method_calls: vec![MethodCall(
+                "VacationResponse/set",
+                json!({
+                    "accountId": self.account_id,
+                    "update": {
+                        "singleton": {
+                            "isEnabled": vacation.is_enabled,
+                            "fromDate": vacation.from_date,
+                            "toDate": vacation.to_date,
+                            "subject": vacation.subject,
+                            "textBody": vacation.text_body,
+                            "htmlBody": null
+                        }
+                    }
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "VacationResponse/set")?;
+        match set_error(result, "notUpdated", "singleton") {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get_sieve_scripts(&self) -> Result<Vec<SieveScript>, JmapError> {
+        log_info!("[JMAP] Fetching Sieve scripts for account: {}", self.account_id);
+
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:sieve"],
+            method_calls: vec![MethodCall(
+                "SieveScript/get",
+                json!({
+                    "accountId": self.account_id,
+                    "ids": null
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "SieveScript/get")?;
+        let scripts: SieveScriptGetResponse = serde_json::from_value(result.clone())
+            .map_err(|e| JmapError::Parse(e.to_string()))?;
+
+        log_info!("[JMAP] SieveScript/get returned {} scripts", scripts.list.len());
+        Ok(scripts.list)
+    }
+
+    /// Download a script's source
+    pub fn get_sieve_script_text(&self, script: &SieveScript) -> Result<String, JmapError> {
+        let mut download = self.download_blob(&script.blob_id, "script.siv", "application/sieve")?;
+        let mut text = String::new();
+        download
+            .reader
+            .read_to_string(&mut text)
+            .map_err(|e| JmapError::Http(e.to_string()))?;
+        Ok(text)
+    }
+
+    /// Check a script with SieveScript/validate without storing it. Returns
+    /// the server's description of the problem, or `None` if it is valid.
+    pub fn validate_sieve_script(&self, text: &str) -> Result<Option<String>, JmapError> {
+        let blob = self.upload_blob(text.as_bytes(), "application/sieve")?;
+        log_info!("[JMAP] SieveScript/validate blob {}", blob.blob_id);
+
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:sieve"],
+            method_calls: vec![MethodCall(
+                "SieveScript/validate",
+                json!({
+                    "accountId": self.account_id,
+                    "blobId": blob.blob_id
+                }),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let result = method_result(&response, "0", "SieveScript/validate")?;
+        let error = &result["error"];
+        if error.is_null() {
+            return Ok(None);
+        }
+        let description = error["description"]
+            .as_str()
+            .or_else(|| error["type"].as_str())
+            .unwrap_or("invalid script");
+        Ok(Some(description.to_string()))
+    }
+
+    /// Upload the script text and create a new script from it, or point an
+    /// existing one at it. Returns the script's id.
+    pub fn save_sieve_script(&self, id: Option<&str>, name: &str, text: &str) -> Result<String, JmapError> {
+        let blob = self.upload_blob(text.as_bytes(), "application/sieve")?;
+        let script = json!({ "name": name, "blobId": blob.blob_id });
+
+        match id {
+            Some(id) => {
+                log_info!("[JMAP] SieveScript/set update {} ({:?})", id, name);
+                let result = self.sieve_set(json!({ "update": { id: script } }))?;
+                match set_error(&result, "notUpdated", id) {
+                    Some(e) => Err(e),
+                    None => Ok(id.to_string()),
+                }
+            }
+            None => {
+                log_info!("[JMAP] SieveScript/set create {:?}", name);
+                let result = self.sieve_set(json!({ "create": { "new": script } }))?;
+                created_id(&result, "new")
+            }
+        }
+    }
+
+    /// Make `id` the active script, or turn filtering off with `None`
+    pub fn activate_sieve_script(&self, id: Option<&str>) -> Result<(), JmapError> {
+        log_info!("[JMAP] SieveScript/set activate {:?}", id);
+        let args = match id {
+            Some(id) => json!({ "onSuccessActivateScript": id }),
+            None => json!({ "onSuccessDeactivateScript": true }),
+        };
+        self.sieve_set(args).map(|_| ())
+    }
 
-        log_debug!("[JMAP] Downloading blob from: {}", url);
+    /// Destroy a script. The server refuses (scriptIsActive) while it is active.
+    pub fn destroy_sieve_script(&self, id: &str) -> Result<(), JmapError> {
+        log_info!("[JMAP] SieveScript/set destroy {}", id);
+        let result = self.sieve_set(json!({ "destroy": [id] }))?;
+        match set_error(&result, "notDestroyed", id) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn sieve_set(&self, mut args: serde_json::Value) -> Result<serde_json::Value, JmapError> {
+        args["accountId"] = json!(self.account_id);
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:sieve"],
+            method_calls: vec![MethodCall("SieveScript/set", args, "0".to_string())],
+        };
+        let response = self.call(request)?;
+        method_result(&response, "0", "SieveScript/set").cloned()
+    }
+
+    /// Create the message in Drafts and submit it with EmailSubmission/set.
+    /// On success the server moves it to Sent and clears $draft.
+    /// Returns the id of the created Email.
+    pub fn send_email(&self, identity: &Identity, email: &OutgoingEmail) -> Result<String, JmapError> {
+        log_info!(
+            "[JMAP] Sending email as {} to {} recipient(s)",
+            identity.email,
+            email.to.len() + email.cc.len() + email.bcc.len()
+        );
+
+        let mailboxes = self.get_mailboxes()?;
+        let drafts_id = drafts_mailbox_id(&mailboxes)?;
+        let sent_id = mailboxes
+            .iter()
+            .find(|m| m.role.as_deref() == Some("sent"))
+            .map(|m| m.id.clone());
+
+        let draft = draft_object(identity, email, &drafts_id);
+
+        let mut submission = json!({
+            "identityId": identity.id,
+            "emailId": "#draft"
+        });
+        if !identity.email.eq_ignore_ascii_case(&self.username) {
+            // Delegated send-as: make the envelope sender the identity rather than
+            // letting the server fall back to the login account
+            log_debug!(
+                "[JMAP] Identity {} differs from login {}, setting envelope mailFrom",
+                identity.email,
+                self.username
+            );
+            submission["envelope"] = json!({
+                "mailFrom": { "email": identity.email },
+                "rcptTo": envelope_recipients(email)
+            });
+        }
+
+        let mut on_success = json!({
+            format!("mailboxIds/{}", drafts_id): null,
+            "keywords/$draft": null
+        });
+        if let Some(sent_id) = sent_id {
+            on_success[format!("mailboxIds/{}", sent_id)] = json!(true);
+        }
+
+        let request = JmapRequest {
+            using: vec![
+                "urn:ietf:params:jmap:core",
+                "urn:ietf:params:jmap:mail",
+                "urn:ietf:params:jmap:submission",
+            ],
+            method_calls: vec![
+                MethodCall(
+                    "Email/set",
+                    draft_set_args(&self.account_id, draft, email),
+                    "0".to_string(),
+                ),
+                MethodCall(
+                    "EmailSubmission/set",
+                    json!({
+                        "accountId": self.account_id,
+                        "create": { "submission": submission },
+                        "onSuccessUpdateEmail": { "#submission": on_success }
+                    }),
+                    "1".to_string(),
+                ),
+            ],
+        };
+
+        let response = self.call(request)?;
+        let email_id = created_id(method_result(&response, "0", "Email/set")?, "draft")?;
+        created_id(
+            method_result(&response, "1", "EmailSubmission/set")?,
+            "submission",
+        )?;
+
+        log_info!("[JMAP] Email {} submitted", email_id);
+        Ok(email_id)
+    }
+
+    /// The `limit` most recent EmailSubmissions, newest first, with the
+    /// subject and recipients of the messages they sent (those still
+    /// around)
+    pub fn get_recent_submissions(&self, limit: u32) -> Result<(Vec<EmailSubmission>, Vec<Email>), JmapError> {
+        log_info!("[JMAP] Fetching the last {} email submissions", limit);
+
+        let query = MethodCall(
+            "EmailSubmission/query",
+            json!({
+                "accountId": self.account_id,
+                "sort": [{ "property": "sentAt", "isAscending": false }],
+                "limit": limit
+            }),
+            "0".to_string(),
+        );
+        let get = MethodCall(
+            "EmailSubmission/get",
+            json!({
+                "accountId": self.account_id,
+                "#ids": query.result_ref("/ids")
+            }),
+            "1".to_string(),
+        );
+        let emails = MethodCall(
+            "Email/get",
+            json!({
+                "accountId": self.account_id,
+                "#ids": get.result_ref("/list/*/emailId"),
+                "properties": ["id", "subject", "to", "cc", "bcc"]
+            }),
+            "2".to_string(),
+        );
+        let request = JmapRequest {
+            using: vec![
+                "urn:ietf:params:jmap:core",
+                "urn:ietf:params:jmap:mail",
+                "urn:ietf:params:jmap:submission",
+            ],
+            method_calls: vec![query, get, emails],
+        };
+
+        let response = self.call(request)?;
+        let submissions: EmailSubmissionGetResponse =
+            serde_json::from_value(method_result(&response, "1", "EmailSubmission/get")?.clone())
+                .map_err(|e| JmapError::Parse(e.to_string()))?;
+        let emails: EmailGetResponse = serde_json::from_value(method_result(&response, "2", "Email/get")?.clone())
+            .map_err(|e| JmapError::Parse(e.to_string()))?;
+        Ok((submissions.list, emails.list))
+    }
+
+    /// The senders and recipients of the `limit` messages received last,
+    /// in one request, so no more than one Email/get may take
+    pub fn get_recent_correspondents(&self, limit: u32) -> Result<Vec<Email>, JmapError> {
+        let limit = limit.min(self.limits.max_objects_in_get as u32);
+        log_info!("[JMAP] Fetching the correspondents of the last {} emails", limit);
+
+        let query = MethodCall(
+            "Email/query",
+            json!({
+                "accountId": self.account_id,
+                "sort": [{ "property": "receivedAt", "isAscending": false }],
+                "limit": limit
+            }),
+            "0".to_string(),
+        );
+        let get = MethodCall(
+            "Email/get",
+            json!({
+                "accountId": self.account_id,
+                "#ids": query.result_ref("/ids"),
+                "properties": ["id", "from", "to", "cc", "receivedAt"]
+            }),
+            "1".to_string(),
+        );
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![query, get],
+        };
+
+        let response = self.call(request)?;
+        let emails: EmailGetResponse = serde_json::from_value(method_result(&response, "1", "Email/get")?.clone())
+            .map_err(|e| JmapError::Parse(e.to_string()))?;
+        Ok(emails.list)
+    }
+
+    /// The events between `after` and `before` (UTCDates), recurrences
+    /// expanded into their occurrences, at most `limit` of them and no
+    /// more than one CalendarEvent/get may take
+    pub fn get_calendar_events(&self, after: &str, before: &str, limit: u32) -> Result<Vec<CalendarEvent>, JmapError> {
+        let limit = limit.min(self.limits.max_objects_in_get as u32);
+        log_info!("[JMAP] Fetching calendar events from {} to {}", after, before);
+
+        let query = MethodCall(
+            "CalendarEvent/query",
+            json!({
+                "accountId": self.account_id,
+                "filter": { "after": after, "before": before },
+                "sort": [{ "property": "start", "isAscending": true }],
+                "expandRecurrences": true,
+                "limit": limit
+            }),
+            "0".to_string(),
+        );
+        let get = MethodCall(
+            "CalendarEvent/get",
+            json!({
+                "accountId": self.account_id,
+                "#ids": query.result_ref("/ids"),
+                "properties": ["id", "title", "start", "duration", "timeZone", "showWithoutTime", "locations"]
+            }),
+            "1".to_string(),
+        );
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:calendars"],
+            method_calls: vec![query, get],
+        };
+
+        let response = self.call(request)?;
+        let events: CalendarEventGetResponse =
+            serde_json::from_value(method_result(&response, "1", "CalendarEvent/get")?.clone())
+                .map_err(|e| JmapError::Parse(e.to_string()))?;
+        Ok(events.list)
+    }
+
+    /// Store the message in Drafts without sending it, replacing
+    /// `email.draft_id` if set. Returns the new draft's id.
+    pub fn save_draft(&self, identity: &Identity, email: &OutgoingEmail) -> Result<String, JmapError> {
+        log_info!("[JMAP] Saving draft (replacing: {:?})", email.draft_id);
+
+        let drafts_id = drafts_mailbox_id(&self.get_mailboxes()?)?;
+        let draft = draft_object(identity, email, &drafts_id);
+
+        let request = JmapRequest {
+            using: vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            method_calls: vec![MethodCall(
+                "Email/set",
+                draft_set_args(&self.account_id, draft, email),
+                "0".to_string(),
+            )],
+        };
+
+        let response = self.call(request)?;
+        let email_id = created_id(method_result(&response, "0", "Email/set")?, "draft")?;
+        log_info!("[JMAP] Draft saved as {}", email_id);
+        Ok(email_id)
+    }
+}
+
+fn drafts_mailbox_id(mailboxes: &[Mailbox]) -> Result<String, JmapError> {
+    mailboxes
+        .iter()
+        .find(|m| m.role.as_deref() == Some("drafts"))
+        .map(|m| m.id.clone())
+        .ok_or_else(|| JmapError::Api("No Drafts mailbox found".to_string()))
+}
+
+/// The Email/set create object for a message filed in Drafts
+fn draft_object(identity: &Identity, email: &OutgoingEmail, drafts_id: &str) -> serde_json::Value {
+    let mut draft = json!({
+        "mailboxIds": { drafts_id: true },
+        "keywords": { "$draft": true, "$seen": true },
+        "from": [{ "name": identity.name, "email": identity.email }],
+        "to": email.to,
+        "subject": email.subject,
+        "bodyValues": { "text": { "value": email.text_body } },
+        "textBody": [{ "partId": "text", "type": "text/plain" }]
+    });
+    if let Some(html) = &email.html_body {
+        // With both, the server makes them a multipart/alternative
+        draft["bodyValues"]["html"] = json!({ "value": html });
+        draft["htmlBody"] = json!([{ "partId": "html", "type": "text/html" }]);
+    }
+    if !email.cc.is_empty() {
+        draft["cc"] = json!(email.cc);
+    }
+    if !email.bcc.is_empty() {
+        draft["bcc"] = json!(email.bcc);
+    }
+    if !email.in_reply_to.is_empty() {
+        draft["inReplyTo"] = json!(email.in_reply_to);
+    }
+    if !email.references.is_empty() {
+        draft["references"] = json!(email.references);
+    }
+    let attachments: Vec<_> = email
+        .attachments
+        .iter()
+        .map(|a| {
+            json!({
+                "blobId": a.blob_id,
+                "type": a.r#type,
+                "name": a.name,
+                "disposition": "attachment"
+            })
+        })
+        .collect();
+    if let Some(calendar) = &email.calendar {
+        // textBody can't say "alternative", so the whole structure is given
+        draft["bodyValues"]["calendar"] = json!({ "value": calendar });
+        let mut alternatives = vec![json!({ "partId": "text", "type": "text/plain" })];
+        if email.html_body.is_some() {
+            alternatives.push(json!({ "partId": "html", "type": "text/html" }));
+        }
+        alternatives.push(json!({ "partId": "calendar", "type": "text/calendar" }));
+        let alternative = json!({
+            "type": "multipart/alternative",
+            "subParts": alternatives
+        });
+        let structure = if attachments.is_empty() {
+            alternative
+        } else {
+            let mut parts = vec![alternative];
+            parts.extend(attachments);
+            json!({ "type": "multipart/mixed", "subParts": parts })
+        };
+        let object = draft.as_object_mut().unwrap();
+        object.remove("textBody");
+        object.remove("htmlBody");
+        object.insert("bodyStructure".to_string(), structure);
+    } else if !attachments.is_empty() {
+        draft["attachments"] = json!(attachments);
+    }
+    draft
+}
+
+/// Email/set arguments creating `draft`, destroying the draft it supersedes
+fn draft_set_args(account_id: &str, draft: serde_json::Value, email: &OutgoingEmail) -> serde_json::Value {
+    let mut args = json!({
+        "accountId": account_id,
+        "create": { "draft": draft }
+    });
+    if let Some(old) = &email.draft_id {
+        // Emails are immutable, so editing a draft means replacing it
+        args["destroy"] = json!([old]);
+    }
+    args
+}
 
-        let auth = Self::auth_header(&self.username, &self.password);
-        let (_, body) = Self::fetch_with_auth_following_redirects(&url, &auth, 5)?;
+/// Unique SMTP recipients across To, Cc and Bcc
+fn envelope_recipients(email: &OutgoingEmail) -> Vec<serde_json::Value> {
+    let mut seen = std::collections::HashSet::new();
+    email
+        .to
+        .iter()
+        .chain(&email.cc)
+        .chain(&email.bcc)
+        .filter_map(|a| a.email.as_deref())
+        .filter(|addr| seen.insert(addr.to_ascii_lowercase()))
+        .map(|addr| json!({ "email": addr }))
+        .collect()
+}
+
+/// Find the response for a call id, turning JMAP method errors into JmapError
+fn parse_email_query(result: &serde_json::Value) -> Result<EmailQueryResult, JmapError> {
+    let query_response: EmailQueryResponse =
+        serde_json::from_value(result.clone()).map_err(|e| JmapError::Parse(e.to_string()))?;
+    log_info!(
+        "[JMAP] Email/query returned {} email IDs (position: {}, total: {:?})",
+        query_response.ids.len(),
+        query_response.position,
+        query_response.total
+    );
+    if !query_response.ids.is_empty() {
+        log_debug!("[JMAP] Email IDs: {:?}", query_response.ids);
+    }
+    Ok(EmailQueryResult {
+        ids: query_response.ids,
+        total: query_response.total,
+        position: query_response.position,
+    })
+}
+
+fn parse_email_get(result: &serde_json::Value, requested: usize) -> Result<EmailGetResponse, JmapError> {
+    let email_response: EmailGetResponse =
+        serde_json::from_value(result.clone()).map_err(|e| JmapError::Parse(e.to_string()))?;
+
+    log_info!(
+        "[JMAP] Email/get returned {} emails (requested: {})",
+        email_response.list.len(),
+        requested
+    );
+
+    if !email_response.not_found.is_empty() {
+        log_error!(
+            "[JMAP] Email/get: {} emails not found: {:?}",
+            email_response.not_found.len(),
+            email_response.not_found
+        );
+    }
 
-        log_info!("[JMAP] Raw email downloaded, {} bytes", body.len());
-        Ok(Some(body))
+    if email_response.list.len() != requested {
+        log_warn!(
+            "[JMAP] Email count mismatch! Requested {} but got {}",
+            requested,
+            email_response.list.len()
+        );
+    }
+
+    Ok(email_response)
+}
+
+fn method_result<'a>(
+    response: &'a JmapResponse,
+    call_id: &str,
+    method: &str,
+) -> Result<&'a serde_json::Value, JmapError> {
+    let method_response = response
+        .method_responses
+        .iter()
+        .find(|r| r.2 == call_id)
+        .ok_or_else(|| {
+            log_error!("[JMAP] No {} response in reply", method);
+            JmapError::Api(format!("No {} response", method))
+        })?;
+
+    if method_response.0 == "error" {
+        let error_type = method_response.1["type"].as_str().unwrap_or("unknown");
+        log_error!("[JMAP] {} failed: {}", method, error_type);
+        return Err(JmapError::Api(format!("{} failed: {}", method, error_type)));
+    }
+    if method_response.0 != method {
+        log_warn!("[JMAP] Unexpected method response: {}", method_response.0);
+        return Err(JmapError::Api("Unexpected response".to_string()));
     }
+
+    Ok(&method_response.1)
+}
+
+/// Extract the server id for a creation id from a /set response, or the SetError
+fn created_id(result: &serde_json::Value, creation_id: &str) -> Result<String, JmapError> {
+    if let Some(id) = result["created"][creation_id]["id"].as_str() {
+        return Ok(id.to_string());
+    }
+
+    let error = &result["notCreated"][creation_id];
+    let description = error["description"]
+        .as_str()
+        .or_else(|| error["type"].as_str())
+        .unwrap_or("server did not create the object");
+    log_error!("[JMAP] Create {} failed: {}", creation_id, description);
+    Err(JmapError::Api(description.to_string()))
+}
+
+/// How many objects a /set response says were updated or destroyed
+fn changed_count(result: &serde_json::Value) -> usize {
+    let updated = result["updated"].as_object().map(|o| o.len()).unwrap_or(0);
+    let destroyed = result["destroyed"].as_array().map(|a| a.len()).unwrap_or(0);
+    updated + destroyed
+}
+
+/// The SetError reported for `id` under `notUpdated` or `notDestroyed`, if any
+fn set_error(result: &serde_json::Value, kind: &str, id: &str) -> Option<JmapError> {
+    let error = result[kind].get(id)?;
+    let description = error["description"]
+        .as_str()
+        .or_else(|| error["type"].as_str())
+        .unwrap_or("server refused the change");
+    log_error!("[JMAP] {} {}: {}", kind, id, description);
+    Some(JmapError::Api(description.to_string()))
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters, for
+/// filling URL templates
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
 }
 
 fn truncate_str(s: &str, max_len: usize) -> &str {
@@ -0,0 +1,287 @@
+//! Email/query filters and sort comparators (RFC 8621 sections 4.4.1-4.4.2).
+//!
+//! A filter is either a single FilterCondition or a FilterOperator combining
+//! other filters with AND/OR/NOT. [`Search`] turns the fields of the advanced
+//! search form into such a tree.
+
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    And,
+    Or,
+    Not,
+}
+
+impl Operator {
+    fn as_str(self) -> &'static str {
+        match self {
+            Operator::And => "AND",
+            Operator::Or => "OR",
+            Operator::Not => "NOT",
+        }
+    }
+}
+
+/// One FilterCondition property. Dates are UTCDate strings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    InMailbox(String),
+    InMailboxOtherThan(Vec<String>),
+    Text(String),
+    From(String),
+    To(String),
+    Subject(String),
+    Before(String),
+    After(String),
+    HasAttachment(bool),
+    HasKeyword(String),
+    NotKeyword(String),
+}
+
+impl Condition {
+    fn property(&self) -> (&'static str, Value) {
+        match self {
+            Condition::InMailbox(id) => ("inMailbox", json!(id)),
+            Condition::InMailboxOtherThan(ids) => ("inMailboxOtherThan", json!(ids)),
+            Condition::Text(s) => ("text", json!(s)),
+            Condition::From(s) => ("from", json!(s)),
+            Condition::To(s) => ("to", json!(s)),
+            Condition::Subject(s) => ("subject", json!(s)),
+            Condition::Before(date) => ("before", json!(date)),
+            Condition::After(date) => ("after", json!(date)),
+            Condition::HasAttachment(b) => ("hasAttachment", json!(b)),
+            Condition::HasKeyword(k) => ("hasKeyword", json!(k)),
+            Condition::NotKeyword(k) => ("notKeyword", json!(k)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Condition(Condition),
+    Operator(Operator, Vec<Filter>),
+}
+
+impl Filter {
+    /// AND the filters together. A single filter is returned as is rather
+    /// than wrapped in a one-element operator; none at all gives `None`.
+    pub fn all(mut filters: Vec<Filter>) -> Option<Filter> {
+        match filters.len() {
+            0 => None,
+            1 => filters.pop(),
+            _ => Some(Filter::Operator(Operator::And, filters)),
+        }
+    }
+
+    /// AND an already serialized filter, such as a saved search's, with
+    /// this one. A null filter matches everything, so it is just replaced.
+    pub fn narrow(&self, filter: Value) -> Value {
+        if filter.is_null() {
+            return self.to_json();
+        }
+        json!({ "operator": Operator::And.as_str(), "conditions": [filter, self.to_json()] })
+    }
+
+    /// The JSON sent as the Email/query `filter` argument
+    pub fn to_json(&self) -> Value {
+        match self {
+            Filter::Condition(condition) => {
+                let (key, value) = condition.property();
+                let mut map = Map::new();
+                map.insert(key.to_string(), value);
+                Value::Object(map)
+            }
+            Filter::Operator(op, conditions) => json!({
+                "operator": op.as_str(),
+                "conditions": conditions.iter().map(Filter::to_json).collect::<Vec<_>>()
+            }),
+        }
+    }
+}
+
+impl From<Condition> for Filter {
+    fn from(condition: Condition) -> Self {
+        Filter::Condition(condition)
+    }
+}
+
+/// The advanced search form. Empty fields are ignored; dates are the
+/// `YYYY-MM-DD` values of `<input type="date">`.
+#[derive(Debug, Clone, Default)]
+pub struct Search {
+    pub text: String,
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub after: String,
+    pub before: String,
+    pub has_attachment: bool,
+    pub mailbox: String,
+}
+
+impl Search {
+    /// The filter tree for this search, or `None` if no field is set.
+    ///
+    /// Words in the text field starting with `-` exclude messages containing
+    /// them, and comma-separated From/To addresses match any of them. Dates
+    /// that aren't `YYYY-MM-DD` are rejected rather than passed on.
+    pub fn filter(&self) -> Result<Option<Filter>, String> {
+        let mut conditions = Vec::new();
+
+        let (excluded, included): (Vec<&str>, Vec<&str>) = self
+            .text
+            .split_whitespace()
+            .partition(|word| word.len() > 1 && word.starts_with('-'));
+        if !included.is_empty() {
+            conditions.push(Condition::Text(included.join(" ")).into());
+        }
+        if !excluded.is_empty() {
+            let words = excluded
+                .iter()
+                .map(|word| Condition::Text(word[1..].to_string()).into())
+                .collect();
+            conditions.push(Filter::Operator(Operator::Not, words));
+        }
+
+        conditions.extend(any_of(&self.from, Condition::From));
+        conditions.extend(any_of(&self.to, Condition::To));
+        if !self.subject.trim().is_empty() {
+            conditions.push(Condition::Subject(self.subject.trim().to_string()).into());
+        }
+        if !self.mailbox.is_empty() {
+            conditions.push(Condition::InMailbox(self.mailbox.clone()).into());
+        }
+
+        // "after" is inclusive and "before" exclusive, both at midnight UTC,
+        // so after=D, before=D+1 selects exactly day D
+        if let Some(date) = utc_date(&self.after)? {
+            conditions.push(Condition::After(date).into());
+        }
+        if let Some(date) = utc_date(&self.before)? {
+            conditions.push(Condition::Before(date).into());
+        }
+        if self.has_attachment {
+            conditions.push(Condition::HasAttachment(true).into());
+        }
+
+        Ok(Filter::all(conditions))
+    }
+}
+
+/// OR of one condition per comma-separated value
+fn any_of(values: &str, condition: fn(String) -> Condition) -> Option<Filter> {
+    let mut filters: Vec<Filter> = values
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| condition(v.to_string()).into())
+        .collect();
+    match filters.len() {
+        0 => None,
+        1 => filters.pop(),
+        _ => Some(Filter::Operator(Operator::Or, filters)),
+    }
+}
+
+/// `YYYY-MM-DD` to a UTCDate at the start of that day
+fn utc_date(value: &str) -> Result<Option<String>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let valid = value.len() == 10
+        && value.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        });
+    if !valid {
+        return Err(format!("Invalid date: {}", value));
+    }
+    Ok(Some(format!("{}T00:00:00Z", value)))
+}
+
+/// The Email/query sort properties the list can be ordered by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortProperty {
+    ReceivedAt,
+    From,
+    Subject,
+    Size,
+}
+
+impl SortProperty {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortProperty::ReceivedAt => "receivedAt",
+            SortProperty::From => "from",
+            SortProperty::Subject => "subject",
+            SortProperty::Size => "size",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<SortProperty> {
+        [
+            SortProperty::ReceivedAt,
+            SortProperty::From,
+            SortProperty::Subject,
+            SortProperty::Size,
+        ]
+        .into_iter()
+        .find(|p| p.as_str() == s)
+    }
+}
+
+/// A single Comparator. Defaults to newest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sort {
+    pub property: SortProperty,
+    pub ascending: bool,
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Sort {
+            property: SortProperty::ReceivedAt,
+            ascending: false,
+        }
+    }
+}
+
+impl Sort {
+    /// The sort after clicking `property`'s column: the other direction if
+    /// it's already sorted by it, otherwise A-Z for text and largest or
+    /// newest first for the rest
+    pub fn toggled(self, property: SortProperty) -> Sort {
+        let ascending = if self.property == property {
+            !self.ascending
+        } else {
+            matches!(property, SortProperty::From | SortProperty::Subject)
+        };
+        Sort { property, ascending }
+    }
+
+    /// The JSON sent as the Email/query `sort` argument
+    pub fn to_json(self) -> Value {
+        json!([{ "property": self.property.as_str(), "isAscending": self.ascending }])
+    }
+}
+
+/// Where a page of Email/query results starts: at an index, or just past
+/// an email already listed (an anchor, RFC 8620 section 5.5), which still
+/// follows on from it after mail arrives ahead of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Position {
+    Index(u32),
+    After(String),
+}
+
+impl Position {
+    /// The Email/query arguments that select it
+    pub fn to_json(&self) -> Value {
+        match self {
+            Position::Index(index) => json!({ "position": index }),
+            Position::After(id) => json!({ "anchor": id, "anchorOffset": 1 }),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Searching the emails a [`MailCache`](super::MailCache) keeps, without
+//! asking the server.
+//!
+//! Subjects, senders' and recipients' names and addresses, previews and the
+//! fetched text bodies are split into lowercase words, and each word
+//! remembers the emails it is in. A search word matches every word it is
+//! the start of, so "meet" finds "meeting". The index only narrows down
+//! which emails to look at; each is then checked against the whole filter,
+//! so the other conditions (mailboxes, dates, keywords) apply as well.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+
+use super::filter::{Condition, Filter, Operator, Sort, SortProperty};
+use super::types::{Email, EmailAddress};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Default)]
+pub(super) struct TextIndex {
+    /// The ids of the emails each word is in
+    words: BTreeMap<String, HashSet<String>>,
+}
+
+impl TextIndex {
+    pub fn add(&mut self, email: &Email) {
+        for word in email_words(email) {
+            self.words.entry(word).or_default().insert(email.id.clone());
+        }
+    }
+
+    /// Forget `email`, which must be as it was added
+    pub fn remove(&mut self, email: &Email) {
+        for word in email_words(email) {
+            if let Some(ids) = self.words.get_mut(&word) {
+                ids.remove(&email.id);
+                if ids.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.words.clear();
+    }
+
+    /// The ids of the emails with a word starting with `prefix`
+    fn starting_with(&self, prefix: &str) -> HashSet<&str> {
+        self.words
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(word, _)| word.starts_with(prefix))
+            .flat_map(|(_, ids)| ids.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// The ids of the emails that have every word `filter` can't match
+    /// without, or `None` when it needs no particular word
+    pub fn candidates(&self, filter: &Filter) -> Option<HashSet<&str>> {
+        let mut required = Vec::new();
+        required_words(filter, &mut required);
+        required.into_iter().fold(None, |candidates, word| {
+            let ids = self.starting_with(&word);
+            Some(match candidates {
+                Some(candidates) => ids.intersection(&candidates).copied().collect(),
+                None => ids,
+            })
+        })
+    }
+}
+
+/// `text` as the lowercase words it is indexed and searched by
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn address_words(addresses: Option<&[EmailAddress]>) -> HashSet<String> {
+    addresses
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|address| [address.name.as_deref(), address.email.as_deref()])
+        .flatten()
+        .flat_map(words)
+        .collect()
+}
+
+/// Every word the text condition looks through
+fn email_words(email: &Email) -> HashSet<String> {
+    let mut all = address_words(email.from.as_deref());
+    all.extend(address_words(email.to.as_deref()));
+    all.extend(address_words(email.cc.as_deref()));
+    for text in [&email.subject, &email.preview].into_iter().flatten() {
+        all.extend(words(text));
+    }
+    for body in email.body_values.values() {
+        all.extend(words(&body.value));
+    }
+    all
+}
+
+/// The words of the text conditions every email matching `filter` passes
+fn required_words(filter: &Filter, required: &mut Vec<String>) {
+    match filter {
+        Filter::Condition(
+            Condition::Text(text) | Condition::From(text) | Condition::To(text) | Condition::Subject(text),
+        ) => required.extend(words(text)),
+        Filter::Operator(Operator::And, filters) => filters.iter().for_each(|f| required_words(f, required)),
+        // Either side of an OR will do, and NOT requires the words' absence
+        Filter::Condition(_) | Filter::Operator(Operator::Or | Operator::Not, _) => {}
+    }
+}
+
+/// Whether `have` has a word starting with each of `text`'s
+fn has_words(have: &HashSet<String>, text: &str) -> bool {
+    words(text).all(|word| have.iter().any(|w| w.starts_with(&word)))
+}
+
+pub(super) fn matches(filter: &Filter, email: &Email) -> bool {
+    match filter {
+        Filter::Condition(condition) => condition_matches(condition, email),
+        Filter::Operator(Operator::And, filters) => filters.iter().all(|f| matches(f, email)),
+        Filter::Operator(Operator::Or, filters) => filters.iter().any(|f| matches(f, email)),
+        Filter::Operator(Operator::Not, filters) => !filters.iter().any(|f| matches(f, email)),
+    }
+}
+
+fn condition_matches(condition: &Condition, email: &Email) -> bool {
+    let received_at = email.received_at.as_deref();
+    match condition {
+        Condition::InMailbox(id) => email.mailbox_ids.get(id) == Some(&true),
+        Condition::InMailboxOtherThan(ids) => email.mailbox_ids.iter().any(|(id, &on)| on && !ids.contains(id)),
+        Condition::Text(text) => has_words(&email_words(email), text),
+        Condition::From(text) => has_words(&address_words(email.from.as_deref()), text),
+        Condition::To(text) => has_words(&address_words(email.to.as_deref()), text),
+        Condition::Subject(text) => {
+            has_words(&words(email.subject.as_deref().unwrap_or_default()).collect(), text)
+        }
+        // UTCDates in the same form compare as strings
+        Condition::Before(date) => received_at.is_some_and(|at| at < date.as_str()),
+        Condition::After(date) => received_at.is_some_and(|at| at >= date.as_str()),
+        Condition::HasAttachment(wanted) => email.attachments.as_ref().is_some_and(|a| !a.is_empty()) == *wanted,
+        Condition::HasKeyword(keyword) => email.keywords.get(keyword) == Some(&true),
+        Condition::NotKeyword(keyword) => email.keywords.get(keyword) != Some(&true),
+    }
+}
+
+/// The order Email/query would list `a` and `b` in under `sort`, newest
+/// first among equals
+pub(super) fn compare(sort: Sort, a: &Email, b: &Email) -> Ordering {
+    let first_sender = |email: &Email| {
+        let sender = email.from.as_ref().and_then(|from| from.first());
+        sender
+            .and_then(|s| s.name.as_deref().or(s.email.as_deref()))
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+    let subject = |email: &Email| email.subject.as_deref().unwrap_or_default().to_lowercase();
+    let order = match sort.property {
+        SortProperty::ReceivedAt => a.received_at.cmp(&b.received_at),
+        SortProperty::From => first_sender(a).cmp(&first_sender(b)),
+        SortProperty::Subject => subject(a).cmp(&subject(b)),
+        SortProperty::Size => a.size.cmp(&b.size),
+    };
+    let order = if sort.ascending { order } else { order.reverse() };
+    order.then_with(|| b.received_at.cmp(&a.received_at))
+}
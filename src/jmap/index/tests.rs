@@ -0,0 +1,142 @@
+//! Searches answered from the index against what they should find.
+
+use serde_json::json;
+
+use super::*;
+use crate::jmap::Search;
+
+fn emails() -> Vec<Email> {
+    serde_json::from_value(json!([
+        {
+            "id": "E1",
+            "from": [{ "name": "Alice Example", "email": "alice@example.com" }],
+            "to": [{ "email": "bob@example.org" }],
+            "subject": "Meeting notes",
+            "receivedAt": "2024-03-02T09:00:00Z",
+            "size": 2000,
+            "mailboxIds": { "M1": true },
+            "keywords": { "$seen": true },
+            "textBody": [{ "partId": "1" }],
+            "bodyValues": { "1": { "value": "The budget for Q2 is attached." } },
+            "attachments": [{ "partId": "2", "name": "budget.pdf" }]
+        },
+        {
+            "id": "E2",
+            "from": [{ "name": "Bob", "email": "bob@example.org" }],
+            "to": [{ "name": "Alice Example", "email": "alice@example.com" }],
+            "subject": "Re: Meeting notes",
+            "receivedAt": "2024-03-03T10:00:00Z",
+            "size": 1000,
+            "mailboxIds": { "M2": true },
+            "preview": "Thanks, see you Tuesday"
+        },
+        {
+            "id": "E3",
+            "from": [{ "email": "news@shop.example" }],
+            "subject": "Spring sale",
+            "receivedAt": "2024-01-15T08:00:00Z",
+            "size": 5000,
+            "mailboxIds": { "M1": true }
+        }
+    ]))
+    .unwrap()
+}
+
+/// The ids of the emails `search` finds, as the cache would list them
+fn found(search: Search, sort: Sort) -> Vec<String> {
+    let emails = emails();
+    let mut index = TextIndex::default();
+    emails.iter().for_each(|email| index.add(email));
+    let filter = search.filter().unwrap().unwrap();
+
+    let mut found: Vec<&Email> = match index.candidates(&filter) {
+        Some(ids) => emails.iter().filter(|email| ids.contains(email.id.as_str())).collect(),
+        None => emails.iter().collect(),
+    };
+    found.retain(|email| matches(&filter, email));
+    found.sort_by(|a, b| compare(sort, a, b));
+    found.into_iter().map(|email| email.id.clone()).collect()
+}
+
+fn text(text: &str) -> Search {
+    Search {
+        text: text.to_string(),
+        ..Search::default()
+    }
+}
+
+#[test]
+fn words_match_by_their_start_in_any_field() {
+    assert_eq!(found(text("meet"), Sort::default()), ["E2", "E1"]);
+    assert_eq!(found(text("BUDGET q2"), Sort::default()), ["E1"]);
+    assert_eq!(found(text("tuesday"), Sort::default()), ["E2"]);
+    assert_eq!(found(text("shop"), Sort::default()), ["E3"]);
+    assert!(found(text("meeting sale"), Sort::default()).is_empty());
+}
+
+#[test]
+fn excluded_words_and_address_fields() {
+    assert_eq!(found(text("meeting -tuesday"), Sort::default()), ["E1"]);
+    let from = Search {
+        from: "alice, news".to_string(),
+        ..Search::default()
+    };
+    assert_eq!(found(from, Sort::default()), ["E1", "E3"]);
+    let to = Search {
+        to: "alice".to_string(),
+        ..Search::default()
+    };
+    assert_eq!(found(to, Sort::default()), ["E2"]);
+}
+
+#[test]
+fn other_conditions_are_checked_on_the_emails() {
+    let search = Search {
+        subject: "notes".to_string(),
+        mailbox: "M1".to_string(),
+        ..Search::default()
+    };
+    assert_eq!(found(search, Sort::default()), ["E1"]);
+    let dates = Search {
+        after: "2024-03-01".to_string(),
+        before: "2024-03-03".to_string(),
+        ..Search::default()
+    };
+    assert_eq!(found(dates, Sort::default()), ["E1"]);
+    let attachment = Search {
+        has_attachment: true,
+        ..Search::default()
+    };
+    assert_eq!(found(attachment, Sort::default()), ["E1"]);
+}
+
+#[test]
+fn results_follow_the_sort() {
+    let everything = || Search {
+        after: "2000-01-01".to_string(),
+        ..Search::default()
+    };
+    assert_eq!(found(everything(), Sort::default()), ["E2", "E1", "E3"]);
+    let by_size = Sort {
+        property: SortProperty::Size,
+        ascending: true,
+    };
+    assert_eq!(found(everything(), by_size), ["E2", "E1", "E3"]);
+    let by_sender = Sort {
+        property: SortProperty::From,
+        ascending: true,
+    };
+    assert_eq!(found(everything(), by_sender), ["E1", "E2", "E3"]);
+}
+
+#[test]
+fn removed_emails_leave_the_index() {
+    let emails = emails();
+    let mut index = TextIndex::default();
+    emails.iter().for_each(|email| index.add(email));
+    index.remove(&emails[0]);
+    let filter = text("budget").filter().unwrap().unwrap();
+    assert_eq!(index.candidates(&filter), Some(HashSet::new()));
+    let filter = text("meeting").filter().unwrap().unwrap();
+    assert_eq!(index.candidates(&filter), Some(HashSet::from(["E2"])));
+}
@@ -1,5 +1,16 @@
+mod cache;
 mod client;
+mod filter;
+mod index;
+mod transport;
 mod types;
+mod websocket;
 
-pub use client::JmapClient;
+pub use cache::{CacheBudget, CacheStore, MailCache};
+#[cfg(feature = "sqlite")]
+pub use cache::StoredAccount;
+pub use client::{AuthScheme, BlobDownload, JmapClient, JmapError, SessionWatch};
+pub use transport::{client_tls, CallLimit, HttpOptions, Transport};
+pub use filter::{Condition, Filter, Position, Search, Sort, SortProperty};
 pub use types::*;
+pub use websocket::WebSocket;
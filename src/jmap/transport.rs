@@ -0,0 +1,437 @@
+//! HTTP to the JMAP server: one connection pool per session, with timeouts
+//! and retries for failures that are likely to go away on their own. When
+//! the server takes JMAP over WebSocket, method calls can go over one kept
+//! open for the session instead. A user's sessions may share a
+//! [`CallLimit`] on how many requests they have waiting at once.
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde_json::{json, Value};
+use std::io::Read;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::websocket::WebSocket;
+use crate::config::JmapTlsConfig;
+use crate::log;
+use crate::{log_debug, log_error, log_info, log_warn};
+
+/// After a WebSocket fails to open, how long to stay with HTTP
+const SOCKET_RETRY: Duration = Duration::from_secs(300);
+/// A WebSocket unused for longer is opened afresh rather than risk a
+/// request on one the server has quietly dropped
+const SOCKET_IDLE: Duration = Duration::from_secs(60);
+
+/// Timeouts and retries for requests to the JMAP server
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub connect_timeout: Duration,
+    /// Longest wait for each read of a response
+    pub read_timeout: Duration,
+    /// Further attempts after a transient failure
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub retry_backoff: Duration,
+    /// What to trust the server's certificate on, if not ureq's usual CAs
+    pub tls: Option<Arc<ClientConfig>>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(60),
+            retries: 2,
+            retry_backoff: Duration::from_millis(250),
+            tls: None,
+        }
+    }
+}
+
+impl HttpOptions {
+    /// An agent with these timeouts and certificate trust, for requests
+    /// that don't go through a [`Transport`]
+    pub fn agent(&self) -> ureq::AgentBuilder {
+        let builder = ureq::AgentBuilder::new()
+            .timeout_connect(self.connect_timeout)
+            .timeout_read(self.read_timeout);
+        match &self.tls {
+            Some(tls) => builder.tls_config(Arc::clone(tls)),
+            None => builder,
+        }
+    }
+}
+
+/// The TLS setup `[jmap.tls]` asks for, or `None` to leave ureq's as it is
+pub fn client_tls(config: &JmapTlsConfig) -> Result<Option<Arc<ClientConfig>>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let config = if config.danger_accept_invalid_certs {
+        log_warn!("[JMAP] jmap.tls.danger_accept_invalid_certs is set: server certificates are not checked");
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+            .with_no_client_auth()
+    } else if let Some(path) = &config.ca_file {
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let certs = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("failed to read jmap.tls.ca_file {}: {}", path, e))?;
+        if certs.is_empty() {
+            return Err(format!("jmap.tls.ca_file {} has no certificates", path));
+        }
+        for cert in certs {
+            roots
+                .add(cert)
+                .map_err(|e| format!("invalid certificate in {}: {}", path, e))?;
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(Arc::new(config)))
+}
+
+/// Takes any certificate as the server's, still checking the handshake is
+/// signed by the key in it
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// The agent and retry policy shared by every client made for a session.
+/// Cloning it shares the connection pool, so requests reuse connections
+/// that are kept alive.
+#[derive(Clone)]
+pub struct Transport {
+    agent: ureq::Agent,
+    options: HttpOptions,
+    socket: Arc<Mutex<SocketSlot>>,
+    calls: Option<Arc<CallLimit>>,
+}
+
+/// How many requests may be waiting on the JMAP server at once, shared by
+/// every transport given it; any more wait for one of those to finish
+pub struct CallLimit {
+    max: usize,
+    busy: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl CallLimit {
+    pub fn new(max: usize) -> Self {
+        CallLimit {
+            max: max.max(1),
+            busy: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> CallPermit<'_> {
+        let mut busy = self.busy.lock().unwrap();
+        if *busy >= self.max {
+            log_debug!("[JMAP] {} requests already waiting on the server, queueing", busy);
+        }
+        while *busy >= self.max {
+            busy = self.freed.wait(busy).unwrap();
+        }
+        *busy += 1;
+        CallPermit(self)
+    }
+}
+
+/// One of a `CallLimit`'s requests, given back when dropped
+struct CallPermit<'a>(&'a CallLimit);
+
+impl Drop for CallPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// The session's WebSocket for method calls, once one has been opened
+#[derive(Default)]
+struct SocketSlot {
+    open: Option<OpenSocket>,
+    /// After one failed to open, when to try again
+    retry_at: Option<Instant>,
+    next_id: u64,
+}
+
+struct OpenSocket {
+    socket: WebSocket,
+    url: String,
+    last_used: Instant,
+}
+
+/// Why a request didn't go over the WebSocket
+pub enum SocketError {
+    /// It wasn't sent: the socket is busy with another request, or
+    /// couldn't be opened
+    NotSent,
+    /// The socket failed after it was sent, so the server may have acted
+    /// on it
+    Lost(String),
+}
+
+impl Transport {
+    pub fn new(options: HttpOptions) -> Self {
+        let read_timeout = options.read_timeout;
+        Self::with_read_timeout(options, read_timeout)
+    }
+
+    /// A transport of its own for a long-lived stream, which has nothing to
+    /// gain from sharing a pool and reconnects by itself instead of
+    /// retrying
+    pub fn for_stream(options: HttpOptions, read_timeout: Duration) -> Self {
+        Self::with_read_timeout(HttpOptions { retries: 0, ..options }, read_timeout)
+    }
+
+    fn with_read_timeout(options: HttpOptions, read_timeout: Duration) -> Self {
+        let agent = options
+            .agent()
+            .redirects(0) // Don't auto-follow, redirects are handled manually
+            .timeout_read(read_timeout)
+            .build();
+        Transport {
+            agent,
+            options,
+            socket: Arc::default(),
+            calls: None,
+        }
+    }
+
+    /// Have requests wait their turn under `calls`, if given
+    pub fn with_call_limit(self, calls: Option<Arc<CallLimit>>) -> Self {
+        Transport { calls, ..self }
+    }
+
+    fn permit(&self) -> Option<CallPermit<'_>> {
+        self.calls.as_deref().map(CallLimit::acquire)
+    }
+
+    pub fn options(&self) -> HttpOptions {
+        self.options.clone()
+    }
+
+    /// Send the request built by `request`, with `body` if given, trying
+    /// again with backoff after a transient failure. A request that may
+    /// change something on the server (`idempotent` false) is only retried
+    /// if it never reached it, since the server could otherwise have acted
+    /// on it already.
+    pub fn send(
+        &self,
+        what: &str,
+        idempotent: bool,
+        request: impl Fn(&ureq::Agent) -> ureq::Request,
+        body: Option<&[u8]>,
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        let _permit = self.permit();
+        let mut backoff = self.options.retry_backoff;
+        let mut attempt = 0;
+        // Lets the server's logs be matched up with ours
+        let request_id = log::request_id();
+        loop {
+            let mut request = request(&self.agent);
+            if let Some(id) = &request_id {
+                request = request.set("X-Request-Id", id);
+            }
+            let result = match body {
+                Some(body) => request.send_bytes(body),
+                None => request.call(),
+            };
+            match result.map_err(Box::new) {
+                Err(e) if is_transient(&e, idempotent) => {
+                    if attempt == self.options.retries {
+                        if attempt > 0 {
+                            log_error!("[JMAP] {} failed after {} attempts: {}", what, attempt + 1, e);
+                        }
+                        return Err(e);
+                    }
+                    attempt += 1;
+                    log_warn!(
+                        "[JMAP] {} failed ({}), retry {} of {} in {:?}",
+                        what,
+                        e,
+                        attempt,
+                        self.options.retries,
+                        backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send the request built by `request` with its body read from `body`
+    /// as it goes out, chunked. The body can't be read a second time, so
+    /// nothing is retried.
+    pub fn send_reader(
+        &self,
+        request: impl FnOnce(&ureq::Agent) -> ureq::Request,
+        body: impl Read,
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        let _permit = self.permit();
+        let mut request = request(&self.agent);
+        if let Some(id) = log::request_id() {
+            request = request.set("X-Request-Id", &id);
+        }
+        request.send(body).map_err(Box::new)
+    }
+
+    /// Send a JMAP request over the session's WebSocket at `url` (RFC
+    /// 8887), opening it first if need be, and give back the Response or
+    /// RequestError object that answers it. While a request is using the
+    /// socket, others go by HTTP rather than wait.
+    pub fn call_over_socket(&self, url: &str, authorization: &str, mut request: Value) -> Result<Value, SocketError> {
+        let Ok(mut slot) = self.socket.try_lock() else {
+            return Err(SocketError::NotSent);
+        };
+        let _permit = self.permit();
+        let stale = slot
+            .open
+            .as_ref()
+            .is_some_and(|open| open.url != url || open.last_used.elapsed() > SOCKET_IDLE);
+        if stale {
+            slot.open = None;
+        }
+        if slot.open.is_none() {
+            if slot.retry_at.is_some_and(|at| Instant::now() < at) {
+                return Err(SocketError::NotSent);
+            }
+            match WebSocket::connect(url, authorization, &self.options, self.options.read_timeout) {
+                Ok(socket) => {
+                    log_info!("[JMAP] Opened WebSocket {}", url);
+                    slot.retry_at = None;
+                    slot.open = Some(OpenSocket {
+                        socket,
+                        url: url.to_string(),
+                        last_used: Instant::now(),
+                    });
+                }
+                Err(e) => {
+                    log_warn!("[JMAP] WebSocket {} unavailable ({}), using HTTP for {:?}", url, e, SOCKET_RETRY);
+                    slot.retry_at = Some(Instant::now() + SOCKET_RETRY);
+                    return Err(SocketError::NotSent);
+                }
+            }
+        }
+
+        slot.next_id += 1;
+        let id = slot.next_id.to_string();
+        request["@type"] = json!("Request");
+        request["id"] = json!(id);
+        let open = slot.open.as_mut().expect("socket was just opened");
+        open.last_used = Instant::now();
+        let result = open.socket.send(&request.to_string()).and_then(|_| loop {
+            let Some(text) = open.socket.receive()? else {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no response in time"));
+            };
+            let Ok(reply) = serde_json::from_str::<Value>(&text) else {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "response isn't JSON"));
+            };
+            // Anything else, such as a state change pushed unasked, is skipped
+            if reply["requestId"] == id.as_str() {
+                return Ok(reply);
+            }
+        });
+        result.map_err(|e| {
+            slot.open = None;
+            SocketError::Lost(e.to_string())
+        })
+    }
+}
+
+fn is_transient(error: &ureq::Error, idempotent: bool) -> bool {
+    match error {
+        ureq::Error::Status(code, _) => idempotent && *code >= 500,
+        ureq::Error::Transport(transport) => match transport.kind() {
+            ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed => true,
+            // Includes timeouts and connections dropped mid-response
+            ureq::ErrorKind::Io => idempotent,
+            _ => false,
+        },
+    }
+}
+
+/// Whether nothing is answering at the URL any more, as opposed to the
+/// server failing or refusing the request
+pub fn is_unreachable(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Status(code, _) => matches!(code, 404 | 410),
+        ureq::Error::Transport(transport) => {
+            matches!(transport.kind(), ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed)
+        }
+    }
+}
+
+/// What went wrong, for the error shown in the page once any retries are
+/// used up
+pub fn describe(error: &ureq::Error) -> String {
+    match error {
+        ureq::Error::Status(code, _) if *code >= 500 => {
+            format!("the mail server is unavailable (status {})", code)
+        }
+        ureq::Error::Status(code, _) => format!("the mail server refused the request (status {})", code),
+        ureq::Error::Transport(transport) => match transport.kind() {
+            ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed => {
+                format!("could not connect to the mail server: {}", transport)
+            }
+            ureq::ErrorKind::Io => format!("lost the connection to the mail server: {}", transport),
+            _ => transport.to_string(),
+        },
+    }
+}
+
+/// Whether a JMAP method only reads, so a request made up of such methods
+/// is safe to repeat
+pub fn is_read_only(method: &str) -> bool {
+    ["/get", "/query", "/changes", "/queryChanges", "/validate"]
+        .iter()
+        .any(|suffix| method.ends_with(suffix))
+}
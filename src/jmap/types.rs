@@ -1,6 +1,12 @@
+// Wire types mirror the JMAP spec; not every deserialized field is read yet.
+#![allow(dead_code)]
+
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::bounce::Bounce;
+use crate::ical::Invite;
+
 // JMAP Session (from .well-known/jmap)
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -10,9 +16,19 @@ pub struct JmapSession {
     #[serde(default)]
     pub download_url: Option<String>,
     #[serde(default)]
+    pub upload_url: Option<String>,
+    #[serde(default)]
+    pub event_source_url: Option<String>,
+    #[serde(default)]
     pub primary_accounts: HashMap<String, String>,
     #[serde(default)]
     pub accounts: HashMap<String, JmapAccount>,
+    #[serde(default)]
+    pub capabilities: HashMap<String, serde_json::Value>,
+    /// Changes whenever anything above does; responses echo it as
+    /// sessionState
+    #[serde(default)]
+    pub state: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -23,6 +39,38 @@ pub struct JmapAccount {
     pub is_personal: bool,
     #[serde(default)]
     pub is_read_only: bool,
+    #[serde(default)]
+    pub account_capabilities: HashMap<String, serde_json::Value>,
+}
+
+/// An account the user can read mail in, for the account switcher
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailAccount {
+    pub id: String,
+    pub name: String,
+    pub is_read_only: bool,
+    /// Whether the account's Sieve scripts can be managed (RFC 9661)
+    pub has_sieve: bool,
+    /// Whether the server reports S/MIME signature checks (RFC 9219)
+    #[serde(default)]
+    pub has_smime_verify: bool,
+    /// Whether the account has calendars (JMAP Calendars)
+    #[serde(default)]
+    pub has_calendars: bool,
+    /// Whether mail can be sent from the account (RFC 8621 section 7).
+    /// Sessions kept from before it was recorded assume so.
+    #[serde(default = "assumed")]
+    pub has_submission: bool,
+    /// Whether the account has a vacation responder (RFC 8621 section 8)
+    #[serde(default = "assumed")]
+    pub has_vacation: bool,
+    /// Whether the account reports how much of its quotas it uses (RFC 9425)
+    #[serde(default)]
+    pub has_quota: bool,
+}
+
+fn assumed() -> bool {
+    true
 }
 
 impl JmapSession {
@@ -40,6 +88,98 @@ impl JmapSession {
 
         None
     }
+
+    /// Every account with the mail capability, the primary one first and
+    /// the rest (shared mailboxes, typically) by name
+    pub fn mail_accounts(&self) -> Vec<MailAccount> {
+        let primary = self.mail_account_id();
+        let mut accounts: Vec<MailAccount> = self
+            .accounts
+            .iter()
+            .filter(|(id, account)| {
+                account.account_capabilities.contains_key("urn:ietf:params:jmap:mail")
+                    || Some(id.as_str()) == primary
+            })
+            .map(|(id, account)| {
+                // An account listing no capabilities has the session's
+                let has = |capability: &str| {
+                    account.account_capabilities.contains_key(capability)
+                        || (account.account_capabilities.is_empty() && self.capabilities.contains_key(capability))
+                };
+                MailAccount {
+                    id: id.clone(),
+                    name: account.name.clone(),
+                    is_read_only: account.is_read_only,
+                    has_sieve: has("urn:ietf:params:jmap:sieve"),
+                    has_smime_verify: has("urn:ietf:params:jmap:smimeverify"),
+                    has_calendars: has("urn:ietf:params:jmap:calendars"),
+                    has_submission: has("urn:ietf:params:jmap:submission"),
+                    has_vacation: has("urn:ietf:params:jmap:vacationresponse"),
+                    has_quota: has("urn:ietf:params:jmap:quota"),
+                }
+            })
+            .collect();
+        accounts.sort_by(|a, b| {
+            (Some(a.id.as_str()) != primary, &a.name).cmp(&(Some(b.id.as_str()) != primary, &b.name))
+        });
+        accounts
+    }
+}
+
+/// The session's WebSocket URL, from its capability
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketEndpoint {
+    pub url: String,
+    /// Whether the server pushes changes over it as well
+    pub supports_push: bool,
+}
+
+/// The request limits from the server's core capability (RFC 8620 section
+/// 2). A server that leaves one out gets the minimum the RFC suggests.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_objects_in_get: usize,
+    pub max_calls_in_request: usize,
+    pub max_size_request: usize,
+    pub max_size_upload: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_objects_in_get: 500,
+            max_calls_in_request: 16,
+            max_size_request: 10_000_000,
+            max_size_upload: 50_000_000,
+        }
+    }
+}
+
+impl JmapSession {
+    /// Where the server takes JMAP over WebSocket (RFC 8887), if it does
+    pub fn websocket(&self) -> Option<WebSocketEndpoint> {
+        let capability = self.capabilities.get("urn:ietf:params:jmap:websocket")?;
+        Some(WebSocketEndpoint {
+            url: capability["url"].as_str()?.to_string(),
+            supports_push: capability["supportsPush"].as_bool().unwrap_or(false),
+        })
+    }
+
+    pub fn limits(&self) -> Limits {
+        let core = self.capabilities.get("urn:ietf:params:jmap:core");
+        let limit = |name: &str, default: usize| {
+            core.and_then(|c| c[name].as_u64())
+                .filter(|&n| n > 0)
+                .map_or(default, |n| n as usize)
+        };
+        let default = Limits::default();
+        Limits {
+            max_objects_in_get: limit("maxObjectsInGet", default.max_objects_in_get),
+            max_calls_in_request: limit("maxCallsInRequest", default.max_calls_in_request),
+            max_size_request: limit("maxSizeRequest", default.max_size_request),
+            max_size_upload: limit("maxSizeUpload", default.max_size_upload as usize) as u64,
+        }
+    }
 }
 
 // JMAP Request/Response
@@ -53,6 +193,28 @@ pub struct JmapRequest {
 #[derive(Debug, Serialize)]
 pub struct MethodCall(pub &'static str, pub serde_json::Value, pub String);
 
+impl MethodCall {
+    /// A back-reference to `path` in this call's result, for a later call
+    /// in the same request
+    pub fn result_ref(&self, path: &'static str) -> ResultReference {
+        ResultReference {
+            result_of: self.2.clone(),
+            name: self.0,
+            path,
+        }
+    }
+}
+
+/// An argument taken from an earlier call's result (RFC 8620 section 3.7),
+/// sent under the argument's name prefixed with `#`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResultReference {
+    pub result_of: String,
+    pub name: &'static str,
+    pub path: &'static str,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct JmapResponse {
@@ -65,7 +227,19 @@ pub struct JmapResponse {
 pub struct MethodResponse(pub String, pub serde_json::Value, pub String);
 
 // Mailbox types
-#[derive(Debug, Deserialize)]
+
+/// Id of the virtual "All Mail" mailbox. `~` is outside the JMAP Id
+/// character set (RFC 8620 Section 1.2), so it can never clash with a real id.
+pub const ALL_MAIL_ID: &str = "~all";
+
+/// Id of the virtual "Flagged" mailbox: every message with `$flagged` set
+pub const FLAGGED_ID: &str = "~flagged";
+
+/// Keyword of a message pinned above the others in its mailbox's list, one
+/// of this app's own rather than a registered one
+pub const PINNED_KEYWORD: &str = "$pinned";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Mailbox {
     pub id: String,
@@ -92,6 +266,29 @@ pub struct MailboxGetResponse {
     pub not_found: Vec<String>,
 }
 
+/// A /changes response (RFC 8620 Section 5.2)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangesResponse {
+    pub account_id: String,
+    pub old_state: String,
+    pub new_state: String,
+    #[serde(default)]
+    pub has_more_changes: bool,
+    #[serde(default)]
+    pub created: Vec<String>,
+    #[serde(default)]
+    pub updated: Vec<String>,
+    #[serde(default)]
+    pub destroyed: Vec<String>,
+}
+
+impl ChangesResponse {
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.updated.is_empty() && self.destroyed.is_empty()
+    }
+}
+
 // Email types
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -105,42 +302,137 @@ pub struct EmailQueryResponse {
     pub total: Option<u32>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmailQueryResult {
     pub ids: Vec<String>,
     pub total: Option<u32>,
     pub position: u32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Email {
     pub id: String,
     #[serde(default)]
+    pub thread_id: Option<String>,
+    #[serde(default)]
     pub from: Option<Vec<EmailAddress>>,
     #[serde(default)]
     pub to: Option<Vec<EmailAddress>>,
     #[serde(default)]
     pub cc: Option<Vec<EmailAddress>>,
     #[serde(default)]
+    pub bcc: Option<Vec<EmailAddress>>,
+    #[serde(default)]
+    pub reply_to: Option<Vec<EmailAddress>>,
+    #[serde(default)]
     pub subject: Option<String>,
     #[serde(default)]
     pub received_at: Option<String>,
     #[serde(default)]
+    pub size: u64,
+    /// The message as it arrived (RFC 5322)
+    #[serde(default)]
+    pub blob_id: Option<String>,
+    #[serde(default)]
     pub preview: Option<String>,
     #[serde(default)]
     pub text_body: Option<Vec<BodyPart>>,
     #[serde(default)]
+    pub html_body: Option<Vec<BodyPart>>,
+    #[serde(default)]
     pub body_values: HashMap<String, BodyValue>,
     #[serde(default)]
+    pub attachments: Option<Vec<BodyPart>>,
+    #[serde(default)]
     pub keywords: HashMap<String, bool>,
+    #[serde(default)]
+    pub mailbox_ids: HashMap<String, bool>,
+    #[serde(default, rename = "header:Authentication-Results:asText:all")]
+    pub authentication_results: Option<Vec<String>>,
+    #[serde(default)]
+    pub message_id: Option<Vec<String>>,
+    #[serde(default)]
+    pub in_reply_to: Option<Vec<String>>,
+    #[serde(default)]
+    pub references: Option<Vec<String>>,
+    #[serde(default)]
+    pub body_structure: Option<BodyPart>,
+    /// Every header field in message order, only when asked for
+    #[serde(default)]
+    pub headers: Option<Vec<EmailHeader>>,
+    /// The server's S/MIME verdict (RFC 9219), such as "signed/verified";
+    /// null when the message isn't S/MIME
+    #[serde(default)]
+    pub smime_status: Option<String>,
+    /// What went wrong when the signature didn't check out
+    #[serde(default)]
+    pub smime_errors: Option<Vec<String>>,
+    /// When the signature was last checked
+    #[serde(default)]
+    pub smime_verified_at: Option<String>,
+    /// Messages in this email's thread, filled in from Thread/get for list
+    /// rows (not a JMAP property; 0 when unknown)
+    #[serde(skip)]
+    pub thread_size: usize,
+    /// The meeting invitation in its text/calendar part, filled in from
+    /// the part's blob for the message view (not a JMAP property)
+    #[serde(skip)]
+    pub invite: Option<Invite>,
+    /// Who a delivery status notification says the message didn't reach,
+    /// filled in from its report for the message view (not a JMAP property)
+    #[serde(skip)]
+    pub bounce: Option<Bounce>,
 }
 
-#[derive(Debug, Deserialize)]
+impl Email {
+    /// Look up a part of the bodyStructure by its partId
+    pub fn find_part(&self, part_id: &str) -> Option<&BodyPart> {
+        let mut leaves = Vec::new();
+        if let Some(root) = &self.body_structure {
+            root.collect_leaves(&mut leaves);
+        }
+        leaves.into_iter().find(|p| p.part_id == part_id)
+    }
+
+    /// The part holding a meeting invitation or an answer to one
+    pub fn calendar_part(&self) -> Option<&BodyPart> {
+        let mut leaves = Vec::new();
+        if let Some(root) = &self.body_structure {
+            root.collect_leaves(&mut leaves);
+        }
+        self.attachments
+            .iter()
+            .flatten()
+            .chain(leaves)
+            .find(|p| p.blob_id.is_some() && p.r#type.as_deref().is_some_and(|t| t.eq_ignore_ascii_case("text/calendar")))
+    }
+
+    /// Leaf parts of the bodyStructure that carry a Content-ID, i.e. the
+    /// targets of cid: references in the HTML body
+    pub fn inline_parts(&self) -> Vec<&BodyPart> {
+        let mut leaves = Vec::new();
+        if let Some(root) = &self.body_structure {
+            root.collect_leaves(&mut leaves);
+        }
+        leaves.retain(|p| p.cid.is_some() && p.blob_id.is_some());
+        leaves
+    }
+}
+
+/// A header field in its raw form: the value is everything after the
+/// colon, folding included
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmailAddress {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub email: Option<String>,
 }
 
@@ -155,15 +447,107 @@ impl std::fmt::Display for EmailAddress {
     }
 }
 
-#[derive(Debug, Deserialize)]
+impl EmailAddress {
+    /// Parse a comma-separated recipient list as typed into a compose form,
+    /// accepting both `addr@example.com` and `Name <addr@example.com>`
+    pub fn parse_list(input: &str) -> Result<Vec<EmailAddress>, String> {
+        Self::split_list(input).iter().map(|entry| Self::parse_entry(entry)).collect()
+    }
+
+    /// The entries of a recipient list, split at the commas and semicolons
+    /// outside quotes and angle brackets, trimmed, and without empty ones
+    pub fn split_list(input: &str) -> Vec<String> {
+        let mut entries = Vec::new();
+        let mut current = String::new();
+        let mut in_quotes = false;
+        let mut in_angle = false;
+        for c in input.chars() {
+            match c {
+                '"' => in_quotes = !in_quotes,
+                '<' if !in_quotes => in_angle = true,
+                '>' if !in_quotes => in_angle = false,
+                ',' | ';' if !in_quotes && !in_angle => {
+                    entries.push(std::mem::take(&mut current));
+                    continue;
+                }
+                _ => {}
+            }
+            current.push(c);
+        }
+        entries.push(current);
+
+        entries
+            .iter()
+            .map(|e| e.trim())
+            .filter(|e| !e.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Parse one entry of a recipient list, as `split_list` gives them
+    pub fn parse_entry(entry: &str) -> Result<EmailAddress, String> {
+        let (name, email) = match (entry.rfind('<'), entry.rfind('>')) {
+            (Some(open), Some(close)) if open < close => {
+                let name = entry[..open].trim().trim_matches('"').trim();
+                let name = (!name.is_empty()).then(|| name.to_string());
+                (name, entry[open + 1..close].trim())
+            }
+            _ => (None, entry),
+        };
+        let valid = email
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+            && !email.contains(char::is_whitespace);
+        if !valid {
+            return Err(format!("Invalid address: {}", entry));
+        }
+        Ok(EmailAddress {
+            name,
+            email: Some(email.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BodyPart {
+    // null on multipart/* nodes of bodyStructure
+    #[serde(default, deserialize_with = "null_as_default")]
     pub part_id: String,
     #[serde(default)]
+    pub blob_id: Option<String>,
+    #[serde(default)]
     pub r#type: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub cid: Option<String>,
+    #[serde(default)]
+    pub disposition: Option<String>,
+    #[serde(default)]
+    pub sub_parts: Option<Vec<BodyPart>>,
 }
 
-#[derive(Debug, Deserialize)]
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+impl BodyPart {
+    fn collect_leaves<'a>(&'a self, out: &mut Vec<&'a BodyPart>) {
+        match &self.sub_parts {
+            Some(children) => children.iter().for_each(|c| c.collect_leaves(out)),
+            None => out.push(self),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BodyValue {
     pub value: String,
@@ -182,3 +566,240 @@ pub struct EmailGetResponse {
     #[serde(default)]
     pub not_found: Vec<String>,
 }
+
+// Thread types (RFC 8621 Section 3)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Thread {
+    pub id: String,
+    /// Sorted oldest first by the server
+    pub email_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadGetResponse {
+    pub account_id: String,
+    pub state: String,
+    pub list: Vec<Thread>,
+    #[serde(default)]
+    pub not_found: Vec<String>,
+}
+
+// Identity types (RFC 8621 Section 6)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub email: String,
+    #[serde(default)]
+    pub text_signature: Option<String>,
+    #[serde(default)]
+    pub html_signature: Option<String>,
+    #[serde(default)]
+    pub may_delete: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityGetResponse {
+    pub account_id: String,
+    pub state: String,
+    pub list: Vec<Identity>,
+}
+
+// VacationResponse (RFC 8621 Section 8). Each account has exactly one,
+// with the id "singleton".
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VacationResponse {
+    #[serde(default)]
+    pub is_enabled: bool,
+    #[serde(default)]
+    pub from_date: Option<String>,
+    #[serde(default)]
+    pub to_date: Option<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub text_body: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VacationResponseGetResponse {
+    pub account_id: String,
+    pub state: String,
+    pub list: Vec<VacationResponse>,
+}
+
+// EmailSubmission (RFC 8621 Section 7): a message handed to the server
+// to send, with how far delivery to each recipient has got.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailSubmission {
+    pub id: String,
+    pub email_id: String,
+    #[serde(default)]
+    pub envelope: Option<Envelope>,
+    #[serde(default)]
+    pub send_at: Option<String>,
+    /// "pending" while it can still be cancelled, then "final", or
+    /// "canceled"
+    #[serde(default)]
+    pub undo_status: String,
+    /// Keyed by recipient address; null when the server doesn't say
+    #[serde(default)]
+    pub delivery_status: Option<HashMap<String, DeliveryStatus>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+    pub mail_from: EnvelopeAddress,
+    #[serde(default)]
+    pub rcpt_to: Vec<EnvelopeAddress>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnvelopeAddress {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryStatus {
+    /// The last SMTP reply from the receiving server, such as "250 2.0.0 OK"
+    #[serde(default)]
+    pub smtp_reply: String,
+    /// "queued", "yes", "no" or "unknown"
+    #[serde(default)]
+    pub delivered: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailSubmissionGetResponse {
+    pub list: Vec<EmailSubmission>,
+}
+
+// CalendarEvent (JMAP Calendars, a JSCalendar event). Only what an agenda
+// shows is asked for; with recurrences expanded each occurrence comes back
+// as an event of its own.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalendarEvent {
+    pub id: String,
+    #[serde(default)]
+    pub title: String,
+    /// A LocalDateTime such as "2026-10-20T09:30:00", in `time_zone`
+    #[serde(default)]
+    pub start: String,
+    /// An ISO 8601 duration such as "PT1H30M"
+    #[serde(default)]
+    pub duration: Option<String>,
+    /// Null for a floating time, which is in the reader's zone
+    #[serde(default)]
+    pub time_zone: Option<String>,
+    /// Set for all-day events
+    #[serde(default)]
+    pub show_without_time: bool,
+    #[serde(default)]
+    pub locations: Option<HashMap<String, CalendarLocation>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CalendarLocation {
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CalendarEventGetResponse {
+    pub list: Vec<CalendarEvent>,
+}
+
+// SieveScript (RFC 9661 Section 2). The script text is stored as a blob;
+// at most one script per account is active.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SieveScript {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub blob_id: String,
+    #[serde(default)]
+    pub is_active: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SieveScriptGetResponse {
+    pub account_id: String,
+    pub state: String,
+    pub list: Vec<SieveScript>,
+}
+
+// Quota (RFC 9425 Section 4): a limit on how much an account may hold,
+// in bytes ("octets") or in objects ("count")
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub resource_type: String,
+    #[serde(default)]
+    pub used: u64,
+    pub hard_limit: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QuotaGetResponse {
+    pub list: Vec<Quota>,
+}
+
+/// A message to be created in Drafts and submitted
+#[derive(Debug, Default)]
+pub struct OutgoingEmail {
+    pub to: Vec<EmailAddress>,
+    pub cc: Vec<EmailAddress>,
+    pub bcc: Vec<EmailAddress>,
+    pub subject: String,
+    pub text_body: String,
+    /// The same message as HTML, sent as an alternative to the text, such
+    /// as to carry an HTML signature
+    pub html_body: Option<String>,
+    /// Message-IDs (without angle brackets) for the In-Reply-To header
+    pub in_reply_to: Vec<String>,
+    pub references: Vec<String>,
+    pub attachments: Vec<OutgoingAttachment>,
+    /// An iTIP message (RFC 5546) sent as a text/calendar alternative to
+    /// the text, such as the answer to an invitation
+    pub calendar: Option<String>,
+    /// Existing draft this message supersedes
+    pub draft_id: Option<String>,
+}
+
+/// Response from the session uploadUrl (RFC 8620 Section 6.1)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadResponse {
+    pub account_id: String,
+    pub blob_id: String,
+    pub r#type: String,
+    pub size: u64,
+}
+
+/// An existing blob to attach to an outgoing message, e.g. when forwarding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutgoingAttachment {
+    pub blob_id: String,
+    pub r#type: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub size: u64,
+}
@@ -0,0 +1,353 @@
+//! A WebSocket client (RFC 6455), with just what JMAP over WebSocket
+//! (RFC 8887) needs: the `jmap` subprotocol, text messages one at a time,
+//! and pings answered. It connects with the same timeouts and certificate
+//! trust as the HTTP requests.
+
+use base64::Engine;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use super::transport::HttpOptions;
+
+/// Largest message taken from the server
+const MAX_MESSAGE: u64 = 64 * 1024 * 1024;
+/// Largest handshake response
+const MAX_HEAD: usize = 16 * 1024;
+/// What the server proves it speaks WebSocket with (RFC 6455 section 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+pub struct WebSocket {
+    stream: Stream,
+    /// When a frame of any kind last came in
+    last_heard: Instant,
+}
+
+impl WebSocket {
+    /// Open a WebSocket at a `ws://` or `wss://` URL, signing in with
+    /// `authorization` as the HTTP requests do. Reads wait up to
+    /// `read_timeout`.
+    pub fn connect(url: &str, authorization: &str, options: &HttpOptions, read_timeout: Duration) -> Result<Self, String> {
+        let target = Target::parse(url)?;
+        let addrs = (target.host.as_str(), target.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("could not resolve {}: {}", target.host, e))?;
+        let mut last_error = format!("no address for {}", target.host);
+        let mut tcp = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, options.connect_timeout) {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = format!("could not connect to {}: {}", addr, e),
+            }
+        }
+        let tcp = tcp.ok_or(last_error)?;
+        let setup = tcp
+            .set_read_timeout(Some(read_timeout))
+            .and_then(|_| tcp.set_write_timeout(Some(read_timeout)))
+            .and_then(|_| tcp.set_nodelay(true));
+        setup.map_err(|e| e.to_string())?;
+
+        let stream = if target.tls {
+            let config = options.tls.clone().unwrap_or_else(default_tls);
+            let name = ServerName::try_from(target.host.clone()).map_err(|e| format!("invalid host {}: {}", target.host, e))?;
+            let connection = ClientConnection::new(config, name).map_err(|e| e.to_string())?;
+            Stream::Tls(Box::new(StreamOwned::new(connection, tcp)))
+        } else {
+            Stream::Plain(tcp)
+        };
+        let mut socket = WebSocket {
+            stream,
+            last_heard: Instant::now(),
+        };
+        socket.handshake(&target, authorization)?;
+        Ok(socket)
+    }
+
+    /// Ask for the upgrade, and check the server agreed to it and to the
+    /// `jmap` subprotocol
+    fn handshake(&mut self, target: &Target, authorization: &str) -> Result<(), String> {
+        let key = base64::engine::general_purpose::STANDARD.encode(random_bytes::<16>());
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: jmap\r\n\
+             Authorization: {}\r\n\r\n",
+            target.path, target.authority, key, authorization
+        );
+        self.stream
+            .write_all(request.as_bytes())
+            .and_then(|_| self.stream.flush())
+            .map_err(|e| format!("handshake failed: {}", e))?;
+
+        // Byte by byte, so nothing after the head is taken from the stream
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HEAD {
+                return Err("handshake response too long".to_string());
+            }
+            let mut byte = [0u8; 1];
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Err("connection closed during the handshake".to_string()),
+                Ok(_) => head.push(byte[0]),
+                Err(e) => return Err(format!("handshake failed: {}", e)),
+            }
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap_or("");
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(format!("server refused the upgrade: {}", status));
+        }
+        let header = |name: &str| {
+            head.split("\r\n")
+                .skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_string())
+        };
+        let expected = {
+            let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, ACCEPT_GUID).as_bytes());
+            base64::engine::general_purpose::STANDARD.encode(digest.as_ref())
+        };
+        if header("Sec-WebSocket-Accept").as_deref() != Some(expected.as_str()) {
+            return Err("server answered the upgrade with the wrong Sec-WebSocket-Accept".to_string());
+        }
+        if header("Sec-WebSocket-Protocol").as_deref() != Some("jmap") {
+            return Err("server didn't agree to the jmap subprotocol".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn send(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(TEXT, text.as_bytes())
+    }
+
+    pub fn ping(&mut self) -> io::Result<()> {
+        self.write_frame(PING, b"")
+    }
+
+    /// When anything, a pong included, last came from the server
+    pub fn last_heard(&self) -> Instant {
+        self.last_heard
+    }
+
+    /// The next text message, answering pings on the way. `None` when
+    /// nothing at all arrived within the read timeout.
+    pub fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut message = Vec::new();
+        loop {
+            let mut first = [0u8; 1];
+            match self.stream.read(&mut first) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(_) => {}
+                // Only between messages is a timeout just a quiet spell
+                Err(e) if is_timeout(&e) && message.is_empty() => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let (opcode, payload) = self.read_frame(first[0])?;
+            self.last_heard = Instant::now();
+            match opcode {
+                PING => self.write_frame(PONG, &payload)?,
+                PONG => {}
+                CLOSE => {
+                    // Echo the status code, as the closing handshake asks
+                    let _ = self.write_frame(CLOSE, &payload[..payload.len().min(2)]);
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "closed by the server"));
+                }
+                TEXT | BINARY | CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if message.len() as u64 > MAX_MESSAGE {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+                    }
+                    if first[0] & 0x80 != 0 {
+                        return String::from_utf8(message)
+                            .map(Some)
+                            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message isn't UTF-8"));
+                    }
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame type")),
+            }
+        }
+    }
+
+    /// The rest of a frame whose first byte is `first`: its opcode and
+    /// payload
+    fn read_frame(&mut self, first: u8) -> io::Result<(u8, Vec<u8>)> {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte)?;
+        let masked = byte[0] & 0x80 != 0;
+        let length = match byte[0] & 0x7F {
+            126 => {
+                let mut length = [0u8; 2];
+                self.stream.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0u8; 8];
+                self.stream.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if length > MAX_MESSAGE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+        }
+        // Servers shouldn't mask, but unmasking costs nothing
+        let mut mask = [0u8; 4];
+        if masked {
+            self.stream.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0u8; length as usize];
+        self.stream.read_exact(&mut payload)?;
+        if masked {
+            payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+        Ok((first & 0x0F, payload))
+    }
+
+    /// One whole frame, masked as everything from a client must be
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n if n <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        let mask = random_bytes::<4>();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+/// Where a WebSocket URL points
+struct Target {
+    tls: bool,
+    /// For DNS and the certificate, without brackets round an IPv6 address
+    host: String,
+    port: u16,
+    /// As the Host header has it
+    authority: String,
+    /// With any query
+    path: String,
+}
+
+impl Target {
+    fn parse(url: &str) -> Result<Target, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("wss://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("ws://") {
+            (false, rest)
+        } else {
+            return Err(format!("not a WebSocket URL: {}", url));
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']').ok_or_else(|| format!("invalid URL: {}", url))?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("invalid port in {}", url))?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(format!("no host in {}", url));
+        }
+        Ok(Target {
+            tls,
+            host: host.to_string(),
+            port,
+            authority: authority.to_string(),
+            path,
+        })
+    }
+}
+
+/// Certificate trust without `[jmap.tls]`: the public CAs, as ureq has
+fn default_tls() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    Arc::clone(CONFIG.get_or_init(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    }))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes).expect("system random number generator failed");
+    bytes
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
@@ -0,0 +1,74 @@
+//! A webmail client for JMAP servers, as a library.
+//!
+//! `main.rs` is only [`server::run`], the program with its command line,
+//! listener and config file. Another server can mount the webmail instead:
+//! build a [`Config`] ([`Config::load`] or [`Config::from_toml`]), the
+//! [`PrefsStore`] and [`SessionStore`] it wants, make an [`AppState`] of
+//! them, start [`spawn_tasks`], and hand each `tiny_http` request that
+//! belongs to the webmail to [`handle_request`]. Set `server.base_path` to
+//! where it is mounted. [`jmap::JmapClient`] works on its own too, and
+//! [`demo::start`] gives it, or the handlers, a JMAP server to talk to.
+
+mod access;
+mod access_log;
+mod assets;
+mod bimi;
+mod bounce;
+mod calendar;
+mod cli;
+mod compress;
+pub mod config;
+mod contacts;
+mod dates;
+pub mod demo;
+mod discovery;
+mod export;
+pub mod handlers;
+mod i18n;
+mod ical;
+pub mod jmap;
+mod links;
+pub mod log;
+#[cfg(feature = "sqlite")]
+mod mail_store;
+mod mute;
+mod oauth;
+mod pdf;
+mod pgp;
+pub mod plugins;
+pub mod prefs;
+mod push;
+mod pwa;
+mod qr;
+mod reload;
+mod relay;
+mod retention;
+mod sanitize;
+mod secret;
+pub mod server;
+pub mod session;
+mod snooze;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod templates;
+mod theme;
+mod tls;
+mod totp;
+mod uploads;
+mod user_limits;
+mod zip;
+
+use std::sync::Arc;
+
+pub use config::Config;
+pub use handlers::{handle_request, AppState};
+pub use prefs::PrefsStore;
+pub use session::SessionStore;
+
+/// Start the threads an [`AppState`] relies on: the Trash and Junk purge,
+/// snoozed messages coming back, and expired sessions being dropped
+pub fn spawn_tasks(state: &Arc<AppState>) {
+    retention::spawn(Arc::clone(state));
+    snooze::spawn(Arc::clone(state));
+    session::spawn_cleanup(Arc::clone(state));
+}
@@ -0,0 +1,97 @@
+//! Outbound links in messages.
+//!
+//! The sanitizer points every http(s) link in an HTML body at `/redirect`
+//! instead. That page takes off the query parameters that only tell the
+//! sender who clicked, and shows where the link really goes before the
+//! user follows it, sending no Referer on.
+
+use crate::templates::{base_path, url_encode};
+
+/// Click trackers of ad networks, mailing list services and the like
+#[rustfmt::skip]
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "twclid", "ttclid",
+    "li_fat_id", "igshid", "mc_cid", "mc_eid", "_hsenc", "_hsmi", "__hssc", "__hstc", "__hsfp",
+    "hsctatracking", "mkt_tok", "oly_anon_id", "oly_enc_id", "vero_id", "vero_conv", "rb_clickid",
+    "s_cid", "_openstat", "ml_subscriber", "ml_subscriber_hash", "wickedid", "ref_src",
+];
+
+/// And whole families of them, such as Google Analytics' `utm_source`
+const TRACKING_PREFIXES: &[&str] = &["utm_", "pk_", "mtm_", "piwik_", "matomo_", "stm_"];
+
+/// A link with its trackers taken off
+pub struct Cleaned {
+    pub url: String,
+    /// The names of the parameters that were, in the order they came
+    pub removed: Vec<String>,
+}
+
+/// `url` without the query parameters in [`TRACKING_PARAMS`] or starting
+/// with one of [`TRACKING_PREFIXES`], whatever their case. The rest of it,
+/// including the order and spelling of the parameters kept, is untouched.
+pub fn strip_trackers(url: &str) -> Cleaned {
+    let (without_fragment, fragment) = match url.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (url, None),
+    };
+    let Some((base, query)) = without_fragment.split_once('?') else {
+        return Cleaned {
+            url: url.to_string(),
+            removed: Vec::new(),
+        };
+    };
+    let mut removed = Vec::new();
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|pair| {
+            let name = pair.split('=').next().unwrap_or("");
+            let lower = name.to_ascii_lowercase();
+            let tracking = TRACKING_PARAMS.contains(&lower.as_str())
+                || TRACKING_PREFIXES.iter().any(|prefix| lower.starts_with(prefix));
+            if tracking {
+                removed.push(name.to_string());
+            }
+            !tracking
+        })
+        .collect();
+    let mut url = base.to_string();
+    if !kept.is_empty() {
+        url.push('?');
+        url.push_str(&kept.join("&"));
+    }
+    if let Some(fragment) = fragment {
+        url.push('#');
+        url.push_str(fragment);
+    }
+    Cleaned { url, removed }
+}
+
+/// Where a message's link to `url` goes instead: the interstitial, which
+/// takes the trackers off and says which, under the request's base path
+pub fn redirect_path(url: &str) -> String {
+    format!("{}/redirect?url={}", base_path(), url_encode(url))
+}
+
+/// The host `url` goes to, if it is an http(s) URL the interstitial may
+/// show and lead on to
+pub fn destination_host(url: &str) -> Option<&str> {
+    let (scheme, rest) = url.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    // Browsers end the authority of an http(s) URL at a backslash as well,
+    // so `https://evil.example\@good.example/` goes to evil.example. A link
+    // shaped like that is only ever meant to mislead, so it isn't followed
+    let loose = rest.split(['/', '?', '#']).next().unwrap_or("");
+    if loose.split_once('\\').is_some_and(|(_, after)| after.contains('@')) {
+        return None;
+    }
+    let authority = loose.split('\\').next().unwrap_or("");
+    // Whoever is named before an @ is only a user name, however much it
+    // looks like a host
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    (!host.is_empty()).then_some(host)
+}
+
+#[cfg(test)]
+mod tests;
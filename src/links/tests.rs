@@ -0,0 +1,24 @@
+use super::*;
+
+#[test]
+fn strips_only_trackers() {
+    let cleaned = strip_trackers("https://example.com/a?id=7&utm_source=news&UTM_Medium=mail&fbclid=x&q=a%26b#part");
+    assert_eq!(cleaned.url, "https://example.com/a?id=7&q=a%26b#part");
+    assert_eq!(cleaned.removed, ["utm_source", "UTM_Medium", "fbclid"]);
+
+    let cleaned = strip_trackers("https://example.com/?gclid=1&mc_eid=2");
+    assert_eq!(cleaned.url, "https://example.com/");
+    assert_eq!(strip_trackers("https://example.com/utm_source").url, "https://example.com/utm_source");
+    assert!(strip_trackers("https://example.com/?page=2").removed.is_empty());
+}
+
+#[test]
+fn destination_is_the_real_host() {
+    assert_eq!(destination_host("https://example.com/path"), Some("example.com"));
+    assert_eq!(destination_host("HTTP://bank.example@evil.example:8080/"), Some("evil.example:8080"));
+    assert_eq!(destination_host("https://evil.example\\@good.example/"), None);
+    assert_eq!(destination_host("https://evil.example\\x@good.example/"), None);
+    assert_eq!(destination_host("https://example.com\\path"), Some("example.com"));
+    assert_eq!(destination_host("javascript:alert(1)//x"), None);
+    assert_eq!(destination_host("https:///nohost"), None);
+}
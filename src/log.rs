@@ -1,14 +1,79 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::cell::RefCell;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::time::SystemTime;
 
+use crate::config::LogConfig;
+
 /// Format current timestamp as ISO 8601
 fn timestamp() -> String {
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap_or_default();
 
-    let secs = now.as_secs();
-    let millis = now.subsec_millis();
+    let (year, month, day, hours, minutes, seconds) = civil_time(now.as_secs());
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hours,
+        minutes,
+        seconds,
+        now.subsec_millis()
+    )
+}
+
+/// Format seconds since the Unix epoch as a JMAP UTCDate (no fractional seconds)
+pub fn utc_date(secs: u64) -> String {
+    let (year, month, day, hours, minutes, seconds) = civil_time(secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hours, minutes, seconds
+    )
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Format seconds since the Unix epoch as a Common Log Format time, such
+/// as `10/Oct/2000:13:55:36 +0000`
+pub fn clf_date(secs: u64) -> String {
+    let (year, month, day, hours, minutes, seconds) = civil_time(secs);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hours,
+        minutes,
+        seconds
+    )
+}
+
+/// Format seconds since the Unix epoch as C's asctime does, such as
+/// `Sat Mar  2 09:00:00 2024`, for mbox "From " lines
+pub fn asctime_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let (year, month, day, hours, minutes, seconds) = civil_time(secs);
+    // 1970-01-01 was a Thursday
+    let weekday = DAYS[((secs / 86400 + 4) % 7) as usize];
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {:04}",
+        weekday,
+        MONTHS[month as usize - 1],
+        day,
+        hours,
+        minutes,
+        seconds,
+        year
+    )
+}
 
+/// Split seconds since the Unix epoch into UTC (year, month, day, hour, minute, second)
+fn civil_time(secs: u64) -> (i64, i64, i64, u64, u64, u64) {
     // Convert to UTC time components
     let days_since_epoch = secs / 86400;
     let time_of_day = secs % 86400;
@@ -50,17 +115,112 @@ fn timestamp() -> String {
     }
     let day = remaining_days + 1;
 
-    format!(
-        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
-        year, month, day, hours, minutes, seconds, millis
-    )
+    (year, month, day, hours, minutes, seconds)
+}
+
+/// How serious a log line is; lines below the configured level are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(format!("unknown log level {:?}", s)),
+        }
+    }
+}
+
+/// How each log line is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `[timestamp] [LEVEL] [request id] message`, for reading
+    #[default]
+    Text,
+    /// One JSON object per line, for journald, Loki and the like
+    Json,
+}
+
+// Set once from [log] at startup; until then everything from info up is
+// written as text
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The request the current thread is handling, which its lines are
+    /// tagged with
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn init(config: &LogConfig) {
+    LEVEL.store(config.level as u8, Ordering::Relaxed);
+    JSON.store(config.format == Format::Json, Ordering::Relaxed);
+}
+
+/// Tag what this thread logs from now on with `id`, or stop tagging it
+pub fn set_request_id(id: Option<String>) {
+    REQUEST_ID.with(|current| *current.borrow_mut() = id);
+}
+
+/// The request this thread is handling, to carry over to threads it starts
+/// and to pass on to the JMAP server
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// Write one log line if `level` is enabled (used by macros)
+pub fn write(level: Level, module: &str, message: fmt::Arguments) {
+    if (level as u8) < LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    if JSON.load(Ordering::Relaxed) {
+        let line = REQUEST_ID.with(|id| {
+            json!({
+                "timestamp": timestamp(),
+                "level": level.name().to_ascii_lowercase(),
+                "module": module,
+                "request_id": *id.borrow(),
+                "message": message.to_string(),
+            })
+        });
+        eprintln!("{}", line);
+    } else {
+        REQUEST_ID.with(|id| match &*id.borrow() {
+            Some(id) => eprintln!("[{}] [{}] [{}] {}", timestamp(), level.name(), id, message),
+            None => eprintln!("[{}] [{}] {}", timestamp(), level.name(), message),
+        });
+    }
 }
 
 /// Log an info message with timestamp
 #[macro_export]
 macro_rules! log_info {
     ($($arg:tt)*) => {
-        eprintln!("[{}] [INFO] {}", $crate::log::now(), format!($($arg)*))
+        $crate::log::write($crate::log::Level::Info, module_path!(), format_args!($($arg)*))
     };
 }
 
@@ -68,7 +228,7 @@ macro_rules! log_info {
 #[macro_export]
 macro_rules! log_debug {
     ($($arg:tt)*) => {
-        eprintln!("[{}] [DEBUG] {}", $crate::log::now(), format!($($arg)*))
+        $crate::log::write($crate::log::Level::Debug, module_path!(), format_args!($($arg)*))
     };
 }
 
@@ -76,7 +236,7 @@ macro_rules! log_debug {
 #[macro_export]
 macro_rules! log_error {
     ($($arg:tt)*) => {
-        eprintln!("[{}] [ERROR] {}", $crate::log::now(), format!($($arg)*))
+        $crate::log::write($crate::log::Level::Error, module_path!(), format_args!($($arg)*))
     };
 }
 
@@ -84,11 +244,11 @@ macro_rules! log_error {
 #[macro_export]
 macro_rules! log_warn {
     ($($arg:tt)*) => {
-        eprintln!("[{}] [WARN] {}", $crate::log::now(), format!($($arg)*))
+        $crate::log::write($crate::log::Level::Warn, module_path!(), format_args!($($arg)*))
     };
 }
 
-/// Get current timestamp string (used by macros)
+/// Get current timestamp string
 pub fn now() -> String {
     timestamp()
 }
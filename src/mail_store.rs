@@ -0,0 +1,192 @@
+//! The sessions' caches of mailboxes and list rows, kept in a SQLite
+//! database at `[cache] path` so they outlive restarts. Entries are JSON,
+//! keyed by the owner (server and username) and the account.
+
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::jmap::{CacheStore, Email, EmailQueryResult, Mailbox, StoredAccount};
+use crate::sqlite::Database;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS mailboxes (
+        owner TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        state TEXT NOT NULL,
+        list TEXT NOT NULL,
+        PRIMARY KEY (owner, account_id)
+    );
+    CREATE TABLE IF NOT EXISTS email_states (
+        owner TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        state TEXT NOT NULL,
+        PRIMARY KEY (owner, account_id)
+    );
+    CREATE TABLE IF NOT EXISTS emails (
+        owner TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        id TEXT NOT NULL,
+        email TEXT NOT NULL,
+        PRIMARY KEY (owner, account_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS queries (
+        owner TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        query TEXT NOT NULL,
+        result TEXT NOT NULL,
+        PRIMARY KEY (owner, account_id, query)
+    );
+";
+
+pub struct SqliteStore {
+    db: Mutex<Database>,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it readable only by us since
+    /// it holds senders and subjects
+    pub fn open(path: &Path) -> Result<Self, String> {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+        let db = Database::open(path, Duration::from_secs(5))?;
+        db.execute_batch(SCHEMA)
+            .map_err(|e| format!("failed to set up {}: {}", path.display(), e))?;
+        Ok(SqliteStore { db: Mutex::new(db) })
+    }
+
+    /// Run `write` in one transaction, so that a crash can't leave emails
+    /// kept with a state they aren't at
+    fn transaction(&self, write: impl FnOnce(&Database) -> Result<(), String>) -> Result<(), String> {
+        let db = self.db.lock().unwrap();
+        db.execute_batch("BEGIN IMMEDIATE")?;
+        match write(&db) {
+            Ok(()) => db.execute_batch("COMMIT"),
+            Err(e) => {
+                let _ = db.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(json: &str) -> Result<T, String> {
+    serde_json::from_str(json).map_err(|e| format!("unreadable entry: {}", e))
+}
+
+fn set_email_state(db: &Database, owner: &str, account_id: &str, state: &str) -> Result<(), String> {
+    db.execute(
+        "INSERT OR REPLACE INTO email_states (owner, account_id, state) VALUES (?, ?, ?)",
+        &[owner, account_id, state],
+    )
+}
+
+impl CacheStore for SqliteStore {
+    fn load(&self, owner: &str, account_id: &str) -> Result<StoredAccount, String> {
+        let db = self.db.lock().unwrap();
+        let key = [owner, account_id];
+        let mailboxes = db
+            .query("SELECT list, state FROM mailboxes WHERE owner = ? AND account_id = ?", &key, 2)?
+            .into_iter()
+            .next()
+            .map(|row| Ok::<_, String>((parse::<Vec<Mailbox>>(&row[0])?, row[1].clone())))
+            .transpose()?;
+        let email_state = db
+            .query("SELECT state FROM email_states WHERE owner = ? AND account_id = ?", &key, 1)?
+            .into_iter()
+            .next()
+            .map(|row| row[0].clone());
+        let emails = db
+            .query("SELECT email FROM emails WHERE owner = ? AND account_id = ?", &key, 1)?
+            .iter()
+            .map(|row| parse::<Email>(&row[0]))
+            .collect::<Result<_, _>>()?;
+        let queries = db
+            .query("SELECT query, result FROM queries WHERE owner = ? AND account_id = ?", &key, 2)?
+            .into_iter()
+            .map(|row| Ok((row[0].clone(), parse::<EmailQueryResult>(&row[1])?)))
+            .collect::<Result<_, String>>()?;
+        Ok(StoredAccount {
+            mailboxes,
+            emails,
+            email_state,
+            queries,
+        })
+    }
+
+    fn save_mailboxes(&self, owner: &str, account_id: &str, mailboxes: &[Mailbox], state: &str) -> Result<(), String> {
+        let list = serde_json::to_string(mailboxes).map_err(|e| e.to_string())?;
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO mailboxes (owner, account_id, state, list) VALUES (?, ?, ?, ?)",
+            &[owner, account_id, state, &list],
+        )
+    }
+
+    fn save_emails(&self, owner: &str, account_id: &str, emails: &[Email], state: &str) -> Result<(), String> {
+        self.transaction(|db| {
+            for email in emails {
+                let json = serde_json::to_string(email).map_err(|e| e.to_string())?;
+                db.execute(
+                    "INSERT OR REPLACE INTO emails (owner, account_id, id, email) VALUES (?, ?, ?, ?)",
+                    &[owner, account_id, &email.id, &json],
+                )?;
+            }
+            set_email_state(db, owner, account_id, state)
+        })
+    }
+
+    fn remove_emails(&self, owner: &str, account_id: &str, ids: &[String], state: &str) -> Result<(), String> {
+        self.transaction(|db| {
+            for id in ids {
+                db.execute(
+                    "DELETE FROM emails WHERE owner = ? AND account_id = ? AND id = ?",
+                    &[owner, account_id, id],
+                )?;
+            }
+            set_email_state(db, owner, account_id, state)
+        })
+    }
+
+    fn clear_emails(&self, owner: &str, account_id: &str) -> Result<(), String> {
+        self.transaction(|db| {
+            let key = [owner, account_id];
+            db.execute("DELETE FROM emails WHERE owner = ? AND account_id = ?", &key)?;
+            db.execute("DELETE FROM email_states WHERE owner = ? AND account_id = ?", &key)
+        })
+    }
+
+    fn save_query(
+        &self,
+        owner: &str,
+        account_id: &str,
+        query: &str,
+        result: &EmailQueryResult,
+        keep: usize,
+    ) -> Result<(), String> {
+        let json = serde_json::to_string(result).map_err(|e| e.to_string())?;
+        self.transaction(|db| {
+            // Replacing moves the query to the end of rowid order, newest last
+            db.execute(
+                "DELETE FROM queries WHERE owner = ? AND account_id = ? AND query = ?",
+                &[owner, account_id, query],
+            )?;
+            db.execute(
+                "INSERT INTO queries (owner, account_id, query, result) VALUES (?, ?, ?, ?)",
+                &[owner, account_id, query, &json],
+            )?;
+            db.execute(
+                "DELETE FROM queries WHERE owner = ? AND account_id = ? AND rowid NOT IN \
+                 (SELECT rowid FROM queries WHERE owner = ? AND account_id = ? ORDER BY rowid DESC LIMIT ?)",
+                &[owner, account_id, owner, account_id, &keep.to_string()],
+            )
+        })
+    }
+}
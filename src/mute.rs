@@ -0,0 +1,68 @@
+//! Muting conversations.
+//!
+//! Muting a thread sets `$muted` on every message in it, and marks them
+//! read. What arrives in it afterwards is filed away as soon as it is
+//! noticed, when the Inbox is listed or a push says mail changed: muted
+//! and read too, and moved to the Archive when there is one, so it never
+//! counts as unread. Email/query finds it as an Inbox message without
+//! `$muted` of its own whose thread has it (`someInThreadHaveKeyword`).
+//! Unmuting clears the keyword from the thread again, from the message
+//! view or the list of muted threads in the settings.
+
+use std::collections::HashSet;
+
+use serde_json::json;
+
+use crate::jmap::{Email, JmapClient, JmapError, Mailbox, Position, Sort};
+use crate::log_info;
+
+pub const KEYWORD: &str = "$muted";
+/// New messages in muted threads filed away at a time
+const BATCH_SIZE: u32 = 256;
+
+pub fn is_muted(email: &Email) -> bool {
+    email.keywords.get(KEYWORD).copied().unwrap_or(false)
+}
+
+/// Mute or unmute the thread made up of `email_ids`
+pub fn set(client: &JmapClient, email_ids: &[String], muted: bool) -> Result<(), JmapError> {
+    let keywords: &[(&str, bool)] = if muted { &[(KEYWORD, true), ("$seen", true)] } else { &[(KEYWORD, false)] };
+    client.set_keywords(email_ids, keywords).map(|_| ())
+}
+
+/// File away the Inbox's messages in muted threads. Returns how many there
+/// were.
+pub fn file_new(client: &JmapClient, mailboxes: &[Mailbox]) -> Result<usize, JmapError> {
+    let Some(inbox) = mailboxes.iter().find(|m| m.role.as_deref() == Some("inbox")) else {
+        return Ok(0);
+    };
+    let filter = json!({
+        "operator": "AND",
+        "conditions": [
+            { "inMailbox": inbox.id },
+            { "someInThreadHaveKeyword": KEYWORD },
+            { "notKeyword": KEYWORD },
+        ]
+    });
+    let ids = client.query_emails_filtered(filter, BATCH_SIZE, 0)?.ids;
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    log_info!("[MUTE] Filing away {} new messages in muted threads", ids.len());
+    client.set_keywords(&ids, &[(KEYWORD, true), ("$seen", true)])?;
+    if let Some(archive) = mailboxes.iter().find(|m| m.role.as_deref() == Some("archive")) {
+        client.move_emails(&ids, &archive.id)?;
+    }
+    Ok(ids.len())
+}
+
+/// The muted threads, newest first, each as its latest message with
+/// `$muted`
+pub fn threads(client: &JmapClient) -> Result<Vec<Email>, JmapError> {
+    let filter = json!({ "hasKeyword": KEYWORD });
+    let (result, mut emails, _) = client.query_and_get_emails(filter, &Sort::default(), BATCH_SIZE, &Position::Index(0))?;
+    emails.sort_by_key(|e| result.ids.iter().position(|id| *id == e.id));
+    let mut seen = HashSet::new();
+    emails.retain(|e| e.thread_id.as_ref().is_some_and(|thread| seen.insert(thread.clone())));
+    Ok(emails)
+}
@@ -0,0 +1,215 @@
+//! Signing in through an OAuth 2.0 provider, for JMAP servers that don't
+//! take passwords.
+//!
+//! `/oauth/{provider}/start` sends the browser to the provider with a fresh
+//! state and PKCE code challenge (RFC 7636); the provider sends it back to
+//! `/oauth/callback` with a code, which is exchanged for tokens. The access
+//! token then stands in for the password as a Bearer token, and is renewed
+//! with the refresh token shortly before it expires.
+
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+use crate::config::OAuthProvider;
+use crate::handlers::AppState;
+use crate::jmap::HttpOptions;
+use crate::secret::{random_token, Secret};
+use crate::templates::url_encode;
+use crate::{log_error, log_info, log_warn};
+
+/// How long the provider's sign-in page may take before the flow is dropped
+const FLOW_TIMEOUT: Duration = Duration::from_secs(600);
+/// Renew an access token once it has less than this left
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+struct PendingFlow {
+    provider: String,
+    verifier: String,
+    started: Instant,
+}
+
+/// Sign-ins sent off to a provider and not yet back, by their state
+#[derive(Default)]
+pub struct OAuthFlows {
+    pending: Mutex<HashMap<String, PendingFlow>>,
+}
+
+impl OAuthFlows {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Begin signing in with `provider`, returning the authorization URL to
+    /// send the browser to
+    pub fn start(&self, id: &str, provider: &OAuthProvider) -> String {
+        let state = random_token();
+        let verifier = random_token();
+        let challenge = URL_SAFE_NO_PAD.encode(digest(&SHA256, verifier.as_bytes()));
+
+        let mut url = format!(
+            "{}{}response_type=code&client_id={}&redirect_uri={}&state={}&code_challenge={}&code_challenge_method=S256",
+            provider.authorize_url,
+            if provider.authorize_url.contains('?') { '&' } else { '?' },
+            url_encode(&provider.client_id),
+            url_encode(&provider.redirect_url),
+            state,
+            challenge
+        );
+        if let Some(scope) = &provider.scope {
+            url.push_str("&scope=");
+            url.push_str(&url_encode(scope));
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, flow| flow.started.elapsed() < FLOW_TIMEOUT);
+        pending.insert(
+            state,
+            PendingFlow {
+                provider: id.to_string(),
+                verifier,
+                started: Instant::now(),
+            },
+        );
+        url
+    }
+
+    /// The provider and code verifier of the sign-in `state` came from. Each
+    /// state can only be used once.
+    pub fn finish(&self, state: &str) -> Option<(String, String)> {
+        let flow = self.pending.lock().unwrap().remove(state)?;
+        (flow.started.elapsed() < FLOW_TIMEOUT).then_some((flow.provider, flow.verifier))
+    }
+}
+
+/// The provider tokens behind a session's access token
+pub struct OAuthGrant {
+    /// The `[oauth.<id>]` the session signed in with
+    pub provider: String,
+    pub refresh_token: Option<Secret>,
+    pub expires_at: Option<Instant>,
+}
+
+impl OAuthGrant {
+    fn needs_refresh(&self) -> bool {
+        self.refresh_token.is_some()
+            && self
+                .expires_at
+                .is_some_and(|at| at.saturating_duration_since(Instant::now()) < REFRESH_MARGIN)
+    }
+}
+
+/// A token endpoint response (RFC 6749 section 5.1)
+#[derive(Debug, Deserialize)]
+pub struct Tokens {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+impl Tokens {
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expires_in.map(|secs| Instant::now() + Duration::from_secs(secs))
+    }
+}
+
+/// Trade the code the provider sent back for tokens
+pub fn exchange_code(provider: &OAuthProvider, code: &str, verifier: &str, options: HttpOptions) -> Result<Tokens, String> {
+    token_request(
+        provider,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &provider.redirect_url),
+            ("code_verifier", verifier),
+        ],
+        options,
+    )
+}
+
+fn refresh(provider: &OAuthProvider, refresh_token: &str, options: HttpOptions) -> Result<Tokens, String> {
+    token_request(
+        provider,
+        &[("grant_type", "refresh_token"), ("refresh_token", refresh_token)],
+        options,
+    )
+}
+
+fn token_request(provider: &OAuthProvider, params: &[(&str, &str)], options: HttpOptions) -> Result<Tokens, String> {
+    let mut form = params.to_vec();
+    form.push(("client_id", &provider.client_id));
+    if let Some(secret) = &provider.client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    let response = options
+        .agent()
+        .build()
+        .post(&provider.token_url)
+        .set("Accept", "application/json")
+        .send_form(&form);
+    match response {
+        Ok(response) => response
+            .into_json()
+            .map_err(|e| format!("invalid token response: {}", e)),
+        Err(ureq::Error::Status(code, response)) => {
+            // An error response (section 5.2) says why
+            let body: serde_json::Value = response.into_json().unwrap_or_default();
+            let reason = body["error_description"]
+                .as_str()
+                .or(body["error"].as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("status {}", code));
+            Err(format!("the provider refused the sign-in: {}", reason))
+        }
+        Err(e) => Err(format!("could not reach the provider: {}", e)),
+    }
+}
+
+/// Renew the session's access token if it is about to expire. If that
+/// fails the session carries on with the old token, and the server will
+/// refuse it once it lapses.
+pub fn refresh_expiring_token(state: &AppState, session_id: &Uuid) {
+    let grant = state
+        .sessions
+        .get(session_id, |s| {
+            s.oauth
+                .as_ref()
+                .filter(|grant| grant.needs_refresh())
+                .map(|grant| (grant.provider.clone(), grant.refresh_token.as_ref().map(Secret::reveal)))
+        })
+        .flatten();
+    let Some((id, Some(refresh_token))) = grant else {
+        return;
+    };
+    let Some(provider) = state.config.oauth.get(&id) else {
+        log_warn!("OAuth provider {} is no longer configured", id);
+        return;
+    };
+
+    match refresh(provider, &refresh_token, state.config.jmap.http_options()) {
+        Ok(tokens) => {
+            log_info!("Refreshed OAuth access token for session {}", session_id);
+            state.sessions.update(session_id, |s| {
+                let expires_at = tokens.expires_at();
+                s.credential = Secret::new(tokens.access_token);
+                if let Some(grant) = s.oauth.as_mut() {
+                    grant.expires_at = expires_at;
+                    // Providers that rotate refresh tokens send a new one
+                    if let Some(refresh_token) = tokens.refresh_token {
+                        grant.refresh_token = Some(Secret::new(refresh_token));
+                    }
+                }
+            });
+        }
+        Err(e) => log_error!("Failed to refresh OAuth token for session {}: {}", session_id, e),
+    }
+}
@@ -0,0 +1,313 @@
+//! Minimal PDF writer for exporting a message.
+//!
+//! Uses only the PDF standard Type1 fonts with WinAnsiEncoding, so no font
+//! data has to be embedded and no external dependency is needed.
+
+use crate::config::{PageSize, PdfConfig, PdfFont};
+use crate::jmap::Email;
+use crate::templates::{format_addresses, format_size, get_email_body};
+
+const MARGIN: f32 = 56.0;
+const LINE_SPACING: f32 = 1.3;
+
+// Glyph widths (1/1000 em) for ASCII 32..=126, from the Adobe core font metrics
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, // ' '../
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, // 0..?
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778, // @..O
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556, // P.._
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556, // `..o
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584, // p..~
+];
+
+const TIMES_WIDTHS: [u16; 95] = [
+    250, 333, 408, 500, 500, 833, 778, 180, 333, 333, 500, 564, 250, 333, 250, 278, // ' '../
+    500, 500, 500, 500, 500, 500, 500, 500, 500, 500, 278, 278, 564, 564, 564, 444, // 0..?
+    921, 722, 667, 667, 722, 611, 556, 722, 722, 333, 389, 722, 611, 889, 722, 722, // @..O
+    556, 722, 667, 556, 611, 722, 722, 944, 722, 722, 611, 333, 278, 333, 469, 500, // P.._
+    333, 444, 500, 444, 500, 444, 333, 500, 500, 278, 278, 500, 278, 778, 500, 500, // `..o
+    500, 500, 333, 389, 278, 500, 500, 722, 500, 500, 444, 480, 200, 480, 541, // p..~
+];
+
+impl PdfFont {
+    fn base_font(self) -> &'static str {
+        match self {
+            PdfFont::Helvetica => "Helvetica",
+            PdfFont::TimesRoman => "Times-Roman",
+            PdfFont::Courier => "Courier",
+        }
+    }
+
+    /// Width of an encoded byte in 1/1000 em
+    fn glyph_width(self, byte: u8) -> u16 {
+        let table = match self {
+            PdfFont::Helvetica => &HELVETICA_WIDTHS,
+            PdfFont::TimesRoman => &TIMES_WIDTHS,
+            PdfFont::Courier => return 600,
+        };
+        match byte {
+            32..=126 => table[(byte - 32) as usize],
+            // Latin-1 range: approximate with the width of a lowercase letter
+            _ => table[(b'n' - 32) as usize],
+        }
+    }
+}
+
+impl PageSize {
+    /// Page dimensions in points
+    fn dimensions(self) -> (f32, f32) {
+        match self {
+            PageSize::A4 => (595.28, 841.89),
+            PageSize::Letter => (612.0, 792.0),
+            PageSize::Legal => (612.0, 1008.0),
+        }
+    }
+}
+
+/// Encode a char to WinAnsiEncoding, replacing anything unrepresentable
+fn win_ansi(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        '\u{a0}'..='\u{ff}' => c as u32 as u8,
+        '€' => 0x80,
+        '…' => 0x85,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        _ => b'?',
+    }
+}
+
+pub struct PdfWriter {
+    font: PdfFont,
+    width: f32,
+    height: f32,
+    pages: Vec<Vec<u8>>,
+    current: Vec<u8>,
+    y: f32,
+}
+
+impl PdfWriter {
+    pub fn new(font: PdfFont, page_size: PageSize) -> Self {
+        let (width, height) = page_size.dimensions();
+        PdfWriter {
+            font,
+            width,
+            height,
+            pages: Vec::new(),
+            current: Vec::new(),
+            y: height - MARGIN,
+        }
+    }
+
+    fn break_page(&mut self) {
+        let page = std::mem::take(&mut self.current);
+        self.pages.push(page);
+        self.y = self.height - MARGIN;
+    }
+
+    fn ensure_room(&mut self, needed: f32) {
+        if self.y - needed < MARGIN {
+            self.break_page();
+        }
+    }
+
+    fn text_width(&self, bytes: &[u8], size: f32) -> f32 {
+        bytes
+            .iter()
+            .map(|&b| self.font.glyph_width(b) as f32)
+            .sum::<f32>()
+            * size
+            / 1000.0
+    }
+
+    /// Split one paragraph into lines that fit the text area, breaking at
+    /// spaces where possible and mid-word otherwise
+    fn wrap(&self, paragraph: &[u8], size: f32) -> Vec<Vec<u8>> {
+        let max_width = self.width - 2.0 * MARGIN;
+        let mut lines = Vec::new();
+        let mut line: Vec<u8> = Vec::new();
+
+        for word in paragraph.split_inclusive(|&b| b == b' ') {
+            let mut candidate = line.clone();
+            candidate.extend_from_slice(word);
+            let trimmed_len = candidate.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+            if self.text_width(&candidate[..trimmed_len], size) <= max_width {
+                line = candidate;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            for &b in word {
+                line.push(b);
+                if self.text_width(&line, size) > max_width && line.len() > 1 {
+                    let overflow = line.pop().unwrap();
+                    lines.push(std::mem::replace(&mut line, vec![overflow]));
+                }
+            }
+        }
+        lines.push(line);
+        lines
+    }
+
+    /// Write text at the given size, wrapping long lines and starting new
+    /// pages as needed. Newlines start new paragraphs.
+    pub fn text(&mut self, text: &str, size: f32) {
+        let line_height = size * LINE_SPACING;
+        for paragraph in text.replace('\t', "    ").split('\n') {
+            let encoded: Vec<u8> = paragraph.trim_end_matches('\r').chars().map(win_ansi).collect();
+            for line in self.wrap(&encoded, size) {
+                self.ensure_room(line_height);
+                self.y -= line_height;
+                let mut op = format!("BT /F1 {:.1} Tf {:.2} {:.2} Td (", size, MARGIN, self.y).into_bytes();
+                for &b in &line {
+                    match b {
+                        b'(' | b')' | b'\\' => op.extend_from_slice(&[b'\\', b]),
+                        128..=255 => op.extend_from_slice(format!("\\{:03o}", b).as_bytes()),
+                        _ => op.push(b),
+                    }
+                }
+                op.extend_from_slice(b") Tj ET\n");
+                self.current.extend_from_slice(&op);
+            }
+        }
+    }
+
+    /// Vertical gap in points
+    pub fn space(&mut self, points: f32) {
+        self.y -= points;
+    }
+
+    /// Horizontal rule across the text area
+    pub fn rule(&mut self) {
+        self.ensure_room(12.0);
+        self.y -= 6.0;
+        let line = format!(
+            "0.5 w {:.2} {:.2} m {:.2} {:.2} l S\n",
+            MARGIN,
+            self.y,
+            self.width - MARGIN,
+            self.y
+        );
+        self.current.extend_from_slice(line.as_bytes());
+        self.y -= 6.0;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.break_page();
+
+        // Object numbers: 1 catalog, 2 page tree, 3 font, then a
+        // (page, contents) pair per page
+        let page_count = self.pages.len();
+        let kids: Vec<String> = (0..page_count).map(|i| format!("{} 0 R", 4 + 2 * i)).collect();
+
+        let mut out: Vec<u8> = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::new();
+        let mut object = |out: &mut Vec<u8>, body: &[u8]| {
+            offsets.push(out.len());
+            out.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+            out.extend_from_slice(body);
+            out.extend_from_slice(b"\nendobj\n");
+        };
+
+        object(&mut out, b"<< /Type /Catalog /Pages 2 0 R >>");
+        object(
+            &mut out,
+            format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), page_count).as_bytes(),
+        );
+        object(
+            &mut out,
+            format!(
+                "<< /Type /Font /Subtype /Type1 /BaseFont /{} /Encoding /WinAnsiEncoding >>",
+                self.font.base_font()
+            )
+            .as_bytes(),
+        );
+        for (i, content) in self.pages.iter().enumerate() {
+            object(
+                &mut out,
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+                    self.width,
+                    self.height,
+                    5 + 2 * i
+                )
+                .as_bytes(),
+            );
+            let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+            stream.extend_from_slice(content);
+            stream.extend_from_slice(b"\nendstream");
+            object(&mut out, &stream);
+        }
+
+        let xref_offset = out.len();
+        out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1).as_bytes());
+        for offset in &offsets {
+            out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        out.extend_from_slice(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                offsets.len() + 1,
+                xref_offset
+            )
+            .as_bytes(),
+        );
+        out
+    }
+}
+
+/// Render a message (headers, text body and attachment list) as a PDF
+pub fn email_pdf(email: &Email, config: &PdfConfig) -> Vec<u8> {
+    let size = config.font_size;
+    let mut pdf = PdfWriter::new(config.font, config.page_size);
+
+    pdf.text(email.subject.as_deref().unwrap_or("(no subject)"), size * 1.4);
+    pdf.space(size * 0.5);
+
+    let mut headers = vec![(
+        "From",
+        email.from.as_deref().map(format_addresses).unwrap_or_else(|| "(unknown)".to_string()),
+    )];
+    headers.push((
+        "To",
+        email.to.as_deref().map(format_addresses).unwrap_or_else(|| "(unknown)".to_string()),
+    ));
+    if let Some(cc) = email.cc.as_deref() {
+        headers.push(("Cc", format_addresses(cc)));
+    }
+    headers.push((
+        "Date",
+        email.received_at.clone().unwrap_or_else(|| "(unknown date)".to_string()),
+    ));
+    for (name, value) in headers {
+        pdf.text(&format!("{}: {}", name, value), size);
+    }
+
+    pdf.rule();
+    pdf.text(&get_email_body(email), size);
+
+    let attachments = email.attachments.as_deref().unwrap_or_default();
+    if !attachments.is_empty() {
+        pdf.rule();
+        pdf.text("Attachments", size * 1.2);
+        for part in attachments {
+            pdf.text(
+                &format!(
+                    "- {} ({}, {})",
+                    part.name.as_deref().unwrap_or("(unnamed)"),
+                    part.r#type.as_deref().unwrap_or("application/octet-stream"),
+                    format_size(part.size)
+                ),
+                size,
+            );
+        }
+    }
+
+    pdf.finish()
+}
@@ -0,0 +1,131 @@
+//! Recognising OpenPGP messages, so the viewer can say what they are
+//! rather than show armor as if it were the text.
+//!
+//! PGP/MIME (RFC 3156) shows in the bodyStructure as multipart/encrypted
+//! or multipart/signed; inline PGP is armor in the text body itself.
+//! Nothing is decrypted or verified here.
+
+use crate::jmap::{BodyPart, Email};
+
+/// How a PGP/MIME message is wrapped
+pub enum Mime<'a> {
+    /// multipart/encrypted, with the part holding the encrypted data
+    Encrypted { payload: Option<&'a BodyPart> },
+    /// multipart/signed, with the part holding the signature
+    Signed { signature: Option<&'a BodyPart> },
+}
+
+/// A piece of a text body that may have inline PGP in it
+pub enum Segment<'a> {
+    Text(&'a str),
+    /// A `BEGIN PGP MESSAGE` block, armor included
+    Encrypted(&'a str),
+    /// A cleartext-signed block: the text with its dash-escaping undone,
+    /// and the signature's armor
+    Signed { text: String, signature: &'a str },
+}
+
+const MESSAGE: (&str, &str) = ("-----BEGIN PGP MESSAGE-----", "-----END PGP MESSAGE-----");
+const SIGNED: &str = "-----BEGIN PGP SIGNED MESSAGE-----";
+const SIGNATURE: (&str, &str) = ("-----BEGIN PGP SIGNATURE-----", "-----END PGP SIGNATURE-----");
+
+fn is_type(part: &BodyPart, wanted: &str) -> bool {
+    part.r#type.as_deref().is_some_and(|t| t.eq_ignore_ascii_case(wanted))
+}
+
+/// The outermost PGP/MIME wrapping in `email`'s bodyStructure. An S/MIME
+/// multipart/signed doesn't count, going by its signature part's type.
+pub fn mime(email: &Email) -> Option<Mime<'_>> {
+    let mut queue: Vec<&BodyPart> = email.body_structure.iter().collect();
+    while !queue.is_empty() {
+        let part = queue.remove(0);
+        let children = part.sub_parts.as_deref().unwrap_or_default();
+        if is_type(part, "multipart/encrypted") && children.iter().any(|c| is_type(c, "application/pgp-encrypted")) {
+            let payload = children.iter().find(|c| is_type(c, "application/octet-stream") && c.blob_id.is_some());
+            return Some(Mime::Encrypted { payload });
+        }
+        if is_type(part, "multipart/signed") {
+            if let Some(signature) = children.iter().find(|c| is_type(c, "application/pgp-signature")) {
+                return Some(Mime::Signed {
+                    signature: signature.blob_id.is_some().then_some(signature),
+                });
+            }
+        }
+        queue.extend(children);
+    }
+    None
+}
+
+/// Whether `part` is the "Version: 1" control part of a multipart/encrypted,
+/// which says nothing to a reader
+pub fn is_control_part(part: &BodyPart) -> bool {
+    is_type(part, "application/pgp-encrypted")
+}
+
+/// `text` split around its inline PGP blocks; a single `Text` when there
+/// are none. A block missing its end line runs to the end of the text.
+pub fn segments(text: &str) -> Vec<Segment<'_>> {
+    let mut out = Vec::new();
+    let mut rest = text;
+    loop {
+        let next = [MESSAGE.0, SIGNED]
+            .iter()
+            .filter_map(|marker| line_start(rest, marker).map(|at| (at, *marker)))
+            .min_by_key(|(at, _)| *at);
+        let Some((at, marker)) = next else { break };
+        if at > 0 {
+            out.push(Segment::Text(&rest[..at]));
+        }
+        let block = &rest[at..];
+        if marker == MESSAGE.0 {
+            let end = block_end(block, MESSAGE.1);
+            out.push(Segment::Encrypted(block[..end].trim_end()));
+            rest = &block[end..];
+        } else {
+            let signature_at = line_start(block, SIGNATURE.0).unwrap_or(block.len());
+            let end = signature_at + block_end(&block[signature_at..], SIGNATURE.1);
+            out.push(Segment::Signed {
+                text: cleartext(&block[..signature_at]),
+                signature: block[signature_at..end].trim_end(),
+            });
+            rest = &block[end..];
+        }
+    }
+    if !rest.is_empty() {
+        out.push(Segment::Text(rest));
+    }
+    out
+}
+
+/// Where the first line of `text` that starts with `marker` begins
+fn line_start(text: &str, marker: &str) -> Option<usize> {
+    let mut at = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim_start_matches(' ').starts_with(marker) {
+            return Some(at);
+        }
+        at += line.len();
+    }
+    None
+}
+
+/// Just past the line of `block` starting with `end`, or its length
+fn block_end(block: &str, end: &str) -> usize {
+    line_start(block, end).map_or(block.len(), |at| {
+        let line = &block[at..];
+        at + line.find('\n').map_or(line.len(), |n| n + 1)
+    })
+}
+
+/// The signed text of a cleartext signature: past the armor headers (up to
+/// the first blank line), with "- " taken off dash-escaped lines
+fn cleartext(block: &str) -> String {
+    let mut lines = block.lines().skip(1);
+    for line in lines.by_ref() {
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+    let text: Vec<&str> = lines.map(|line| line.strip_prefix("- ").unwrap_or(line)).collect();
+    text.join("\n")
+}
@@ -0,0 +1,93 @@
+//! Extension points for a server embedding the webmail.
+//!
+//! A [`Plugin`] registered in `AppState::plugins` before the state is
+//! shared can change a message's body as it is shown, offer buttons of its
+//! own beside the message's (run at `POST /email/{id}/plugin/{name}/{action}`)
+//! and add links to the sidebar, say for a ticketing system or a spam
+//! reporter of the organisation's own, without changing the templates.
+//! Every hook has a default doing nothing, so a plugin implements only
+//! what it uses.
+
+use crate::jmap::{Email, JmapClient};
+
+/// One extension, called on every request's thread
+pub trait Plugin: Send + Sync {
+    /// Names the plugin in its actions' paths, so only letters, digits and
+    /// `-`, and different from every other plugin's
+    fn name(&self) -> &str;
+
+    /// The message's body as it is about to be shown, already sanitized,
+    /// to return changed or as it is. Whatever is added is shown as it is
+    /// written, so escape anything that isn't meant as markup.
+    fn render_message(&self, _email: &Email, body: String) -> String {
+        body
+    }
+
+    /// The buttons to offer on `email`
+    fn message_actions(&self, _email: &Email) -> Vec<Action> {
+        Vec::new()
+    }
+
+    /// Do what the button `action` of [`Plugin::message_actions`] stands
+    /// for, with the user's own client. `Ok` is the notice to show, `Err`
+    /// what went wrong.
+    fn run_action(&self, _action: &str, _email: &Email, _client: &JmapClient) -> Result<String, String> {
+        Err("This action is no longer offered".to_string())
+    }
+
+    /// Links for `username`'s sidebar, each opening in a tab of its own
+    fn sidebar_links(&self, _username: &str) -> Vec<Link> {
+        Vec::new()
+    }
+}
+
+/// A button on a message
+pub struct Action {
+    /// Passed back to [`Plugin::run_action`]; letters, digits and `-`
+    pub id: String,
+    pub label: String,
+}
+
+/// A link in the sidebar
+pub struct Link {
+    pub label: String,
+    pub url: String,
+}
+
+/// The plugins registered, called in the order they were
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl Plugins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, plugin: Box<dyn Plugin>) {
+        self.plugins.push(plugin);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Plugin> {
+        self.plugins.iter().find(|p| p.name() == name).map(|p| p.as_ref())
+    }
+
+    /// `body` through each plugin's [`Plugin::render_message`] in turn
+    pub fn render_message(&self, email: &Email, body: String) -> String {
+        self.plugins.iter().fold(body, |body, p| p.render_message(email, body))
+    }
+
+    /// Every plugin's buttons for `email`, with the name of the plugin
+    /// each is for
+    pub fn message_actions(&self, email: &Email) -> Vec<(&str, Action)> {
+        self.plugins
+            .iter()
+            .flat_map(|p| p.message_actions(email).into_iter().map(move |a| (p.name(), a)))
+            .collect()
+    }
+
+    pub fn sidebar_links(&self, username: &str) -> Vec<Link> {
+        self.plugins.iter().flat_map(|p| p.sidebar_links(username)).collect()
+    }
+}
@@ -0,0 +1,279 @@
+//! Per-user preferences that outlive a login session, keyed by username.
+//!
+//! Kept in memory and, when `[prefs] path` is configured, written back after
+//! every change so they survive restarts: to a JSON file holding everyone's,
+//! or with `backend = "sqlite"` to the user's row in a SQLite database.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::config::{PrefsBackend, PrefsConfig};
+#[cfg(feature = "sqlite")]
+use crate::sqlite::Database;
+#[cfg(feature = "sqlite")]
+use std::{os::unix::fs::OpenOptionsExt, sync::Mutex, time::Duration};
+
+#[cfg(feature = "sqlite")]
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS prefs (
+        username TEXT PRIMARY KEY,
+        prefs TEXT NOT NULL
+    );
+";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Prefs {
+    #[serde(default)]
+    pub saved_searches: Vec<SavedSearch>,
+    /// Language picked in the sidebar, over the browser's Accept-Language
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Time zone dates are shown in, over `i18n.timezone`
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Signatures typed in settings, by identity id, over the identities'
+    /// own. An empty one means none.
+    #[serde(default)]
+    pub signatures: HashMap<String, String>,
+    /// HTML signatures typed in settings, sanitized, by identity id, over
+    /// the identities' own. An empty one means none.
+    #[serde(default)]
+    pub html_signatures: HashMap<String, String>,
+    /// Folders whose subfolders the sidebar hides, by mailbox id
+    #[serde(default)]
+    pub collapsed_mailboxes: HashSet<String>,
+    #[serde(default)]
+    pub density: Density,
+    /// Percent of the height the list takes above the message, in place of
+    /// `DEFAULT_LIST_HEIGHT`; kept within `LIST_HEIGHTS`
+    #[serde(default)]
+    pub list_height: Option<u8>,
+    /// Where the user left off in each account, by account id, for the
+    /// page to open at again
+    #[serde(default)]
+    pub last_viewed: HashMap<String, LastViewed>,
+    /// Set up for a second factor at sign-in, when `[totp]` allows it
+    #[serde(default)]
+    pub totp: Option<TotpSecret>,
+    #[serde(default)]
+    pub replies: ReplyPrefs,
+}
+
+/// How a reply is laid out when the compose form opens on it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyPrefs {
+    #[serde(default)]
+    pub posting: Posting,
+    /// Quote the message replied to at all
+    #[serde(default = "yes")]
+    pub include_original: bool,
+    /// Put in front of each quoted line
+    #[serde(default = "default_quote_prefix")]
+    pub quote_prefix: String,
+    /// Make Reply all the message view's first reply button, and `r`'s
+    #[serde(default)]
+    pub reply_all: bool,
+}
+
+impl Default for ReplyPrefs {
+    fn default() -> Self {
+        ReplyPrefs {
+            posting: Posting::default(),
+            include_original: true,
+            quote_prefix: default_quote_prefix(),
+            reply_all: false,
+        }
+    }
+}
+
+fn yes() -> bool {
+    true
+}
+
+pub const DEFAULT_QUOTE_PREFIX: &str = "> ";
+/// The longest quote prefix kept, in characters
+pub const MAX_QUOTE_PREFIX: usize = 8;
+
+fn default_quote_prefix() -> String {
+    DEFAULT_QUOTE_PREFIX.to_string()
+}
+
+/// Whether a reply is written above the quoted message or below it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Posting {
+    #[default]
+    Top,
+    Bottom,
+}
+
+impl Posting {
+    pub fn parse(value: &str) -> Option<Posting> {
+        match value {
+            "top" => Some(Posting::Top),
+            "bottom" => Some(Posting::Bottom),
+            _ => None,
+        }
+    }
+}
+
+/// An authenticator app's secret, in base32, and the time step of the last
+/// code taken, so it can't be taken again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpSecret {
+    pub secret: String,
+    #[serde(default)]
+    pub last_step: u64,
+}
+
+/// The mailbox last listed and the message last opened
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastViewed {
+    #[serde(default)]
+    pub mailbox: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+/// How much room the list gives each message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Density {
+    #[default]
+    Comfortable,
+    Compact,
+}
+
+impl Density {
+    pub fn parse(value: &str) -> Option<Density> {
+        match value {
+            "comfortable" => Some(Density::Comfortable),
+            "compact" => Some(Density::Compact),
+            _ => None,
+        }
+    }
+}
+
+pub const DEFAULT_LIST_HEIGHT: u8 = 40;
+pub const LIST_HEIGHTS: RangeInclusive<u8> = 20..=80;
+
+/// A named Email/query filter shown in the sidebar
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedSearch {
+    pub id: String,
+    pub name: String,
+    pub filter: serde_json::Value,
+}
+
+/// Where changes are written
+enum Backend {
+    Memory,
+    File(PathBuf),
+    #[cfg(feature = "sqlite")]
+    Sqlite(Mutex<Database>),
+}
+
+pub struct PrefsStore {
+    backend: Backend,
+    users: RwLock<HashMap<String, Prefs>>,
+}
+
+impl PrefsStore {
+    /// Load the store `config` points at, starting empty if there is
+    /// nothing there yet. Without a path nothing is persisted.
+    pub fn open(config: &PrefsConfig) -> Result<Self, String> {
+        let (backend, users) = match config.path.as_deref().map(Path::new) {
+            None => (Backend::Memory, HashMap::new()),
+            Some(path) if config.backend == PrefsBackend::Sqlite => open_sqlite(path)?,
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                let users = serde_json::from_str(&contents)
+                    .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+                (Backend::File(path.to_path_buf()), users)
+            }
+            Some(path) => (Backend::File(path.to_path_buf()), HashMap::new()),
+        };
+        Ok(PrefsStore {
+            backend,
+            users: RwLock::new(users),
+        })
+    }
+
+    pub fn get(&self, username: &str) -> Prefs {
+        self.users
+            .read()
+            .unwrap()
+            .get(username)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Change one user's preferences and persist them. The change is kept
+    /// in memory even if writing it out fails.
+    pub fn update<F>(&self, username: &str, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Prefs),
+    {
+        let mut users = self.users.write().unwrap();
+        f(users.entry(username.to_string()).or_default());
+
+        match &self.backend {
+            Backend::Memory => Ok(()),
+            Backend::File(path) => {
+                let json = serde_json::to_string_pretty(&*users).map_err(|e| e.to_string())?;
+                // Write then rename, so a crash mid-write can't truncate the file
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, json)
+                    .and_then(|_| fs::rename(&tmp, path))
+                    .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(db) => {
+                let json = serde_json::to_string(&users[username]).map_err(|e| e.to_string())?;
+                db.lock()
+                    .unwrap()
+                    .execute("INSERT OR REPLACE INTO prefs (username, prefs) VALUES (?, ?)", &[username, &json])
+            }
+        }
+    }
+}
+
+/// The database at `path` and everyone's preferences in it, creating it
+/// readable only by us since it holds signatures, saved searches and
+/// two-factor secrets
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &Path) -> Result<(Backend, HashMap<String, Prefs>), String> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+    let db = Database::open(path, Duration::from_secs(5))?;
+    db.execute_batch(SCHEMA)
+        .map_err(|e| format!("failed to set up {}: {}", path.display(), e))?;
+    let users = db
+        .query("SELECT username, prefs FROM prefs", &[], 2)?
+        .into_iter()
+        .map(|row| {
+            let prefs = serde_json::from_str(&row[1])
+                .map_err(|e| format!("failed to parse the preferences of {}: {}", row[0], e))?;
+            Ok((row[0].clone(), prefs))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((Backend::Sqlite(Mutex::new(db)), users))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(path: &Path) -> Result<(Backend, HashMap<String, Prefs>), String> {
+    Err(format!(
+        "[prefs] backend is sqlite for {}, but this build has no SQLite; rebuild with --features sqlite",
+        path.display()
+    ))
+}
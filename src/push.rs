@@ -0,0 +1,304 @@
+//! Live updates pushed from the JMAP server to the browser.
+//!
+//! While a session has at least one browser tab subscribed to `/events`, a
+//! background thread holds the server's EventSource open (RFC 8620 section
+//! 7.3), or a WebSocket with push enabled (RFC 8887 section 4.3) when the
+//! server offers one, and turns each StateChange into [`Change`]s for each
+//! tab from the account it shows. The thread reconnects after errors and
+//! exits once the last tab has gone or the session has ended.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::handlers::AppState;
+use crate::jmap::WebSocket;
+use crate::mute;
+use crate::oauth;
+use crate::session::Session;
+use crate::{log_error, log_info};
+
+/// Seconds between the server's pings, which also give the thread a chance
+/// to notice tabs that have closed
+pub const PING_INTERVAL: u64 = 30;
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// What an open tab should refresh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Email,
+    Mailbox,
+    /// Nothing changed; sent to check the tab is still listening
+    Ping,
+}
+
+impl Change {
+    /// The SSE event name the page's `hx-trigger="sse:..."` listens for
+    pub fn event_name(self) -> Option<&'static str> {
+        match self {
+            Change::Email => Some("email"),
+            Change::Mailbox => Some("mailbox"),
+            Change::Ping => None,
+        }
+    }
+}
+
+/// A tab listening for changes, and the account it shows
+struct Tab {
+    account_id: String,
+    sender: Sender<Change>,
+}
+
+/// The tabs subscribed to each session. A session has an entry exactly
+/// while its listener thread is running.
+#[derive(Default)]
+pub struct PushHub {
+    subscribers: Mutex<HashMap<Uuid, Vec<Tab>>>,
+}
+
+impl PushHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send each tab of the session the changes `changes` gives for its
+    /// account, forgetting tabs that have gone. Returns `false`, and drops
+    /// the session's entry, if none remain.
+    fn publish(&self, session_id: &Uuid, changes: impl Fn(&str) -> Vec<Change>) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(tabs) = subscribers.get_mut(session_id) else {
+            return false;
+        };
+        tabs.retain(|tab| changes(&tab.account_id).into_iter().all(|change| tab.sender.send(change).is_ok()));
+        if tabs.is_empty() {
+            subscribers.remove(session_id);
+            return false;
+        }
+        true
+    }
+
+    /// Send every tab of the session a ping, like `publish`
+    fn ping(&self, session_id: &Uuid) -> bool {
+        self.publish(session_id, |_| vec![Change::Ping])
+    }
+
+    /// The accounts the session's tabs show, each once
+    fn accounts(&self, session_id: &Uuid) -> Vec<String> {
+        let subscribers = self.subscribers.lock().unwrap();
+        let mut accounts: Vec<String> = subscribers
+            .get(session_id)
+            .into_iter()
+            .flatten()
+            .map(|tab| tab.account_id.clone())
+            .collect();
+        accounts.sort();
+        accounts.dedup();
+        accounts
+    }
+
+    fn close(&self, session_id: &Uuid) {
+        self.subscribers.lock().unwrap().remove(session_id);
+    }
+}
+
+/// Subscribe a tab on `account_id` to the session's changes, starting the
+/// listener thread if it is the first. `None` when the server can't push
+/// changes.
+pub fn subscribe(state: &Arc<AppState>, session_id: Uuid, account_id: &str) -> Option<Receiver<Change>> {
+    let has_push = state.sessions.get(&session_id, Session::has_push).unwrap_or(false);
+    if !has_push {
+        return None;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let tab = Tab {
+        account_id: account_id.to_string(),
+        sender,
+    };
+    let mut subscribers = state.push.subscribers.lock().unwrap();
+    match subscribers.get_mut(&session_id) {
+        Some(tabs) => tabs.push(tab),
+        None => {
+            subscribers.insert(session_id, vec![tab]);
+            let state = Arc::clone(state);
+            thread::spawn(move || listen(&state, session_id));
+        }
+    }
+    Some(receiver)
+}
+
+fn listen(state: &AppState, session_id: Uuid) {
+    log_info!("[PUSH] Listening for changes for session {}", session_id);
+    let mut retry = RETRY_MIN;
+
+    loop {
+        // Nothing else renews the token while only this stream is in use
+        oauth::refresh_expiring_token(state, &session_id);
+        let target = state.sessions.get(&session_id, |s| {
+            let socket_url = s.websocket.as_ref().filter(|w| w.supports_push).map(|w| w.url.clone());
+            (s.client(), socket_url, s.event_source_url.clone())
+        });
+        let (client, socket_url, event_source_url) = match target {
+            Some((client, socket_url, event_source_url)) if socket_url.is_some() || event_source_url.is_some() => {
+                (client, socket_url, event_source_url)
+            }
+            _ => {
+                state.push.close(&session_id);
+                break;
+            }
+        };
+
+        if let Some(url) = socket_url {
+            match client.open_push_socket(&url, PING_INTERVAL) {
+                Ok(socket) => {
+                    retry = RETRY_MIN;
+                    if !relay_socket(state, &session_id, socket) {
+                        break;
+                    }
+                    log_info!("[PUSH] WebSocket closed for session {}, reconnecting", session_id);
+                }
+                Err(e) => log_error!("[PUSH] Failed to open WebSocket for session {}: {}", session_id, e),
+            }
+        } else if let Some(url) = event_source_url {
+            match client.open_event_source(&url, PING_INTERVAL) {
+                Ok(stream) => {
+                    retry = RETRY_MIN;
+                    if !relay(state, &session_id, stream) {
+                        break;
+                    }
+                    log_info!("[PUSH] Event stream closed for session {}, reconnecting", session_id);
+                }
+                Err(e) => log_error!("[PUSH] Failed to open event stream for session {}: {}", session_id, e),
+            }
+        }
+
+        if !state.push.ping(&session_id) {
+            break;
+        }
+        thread::sleep(retry);
+        retry = (retry * 2).min(RETRY_MAX);
+    }
+
+    log_info!("[PUSH] Stopped listening for session {}", session_id);
+}
+
+/// Forward events from one connection until it ends, returning `false` once
+/// nobody is listening any more
+fn relay(state: &AppState, session_id: &Uuid, stream: Box<dyn Read + Send>) -> bool {
+    let mut event = String::new();
+    let mut data = String::new();
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return true };
+
+        // A blank line dispatches the event built from the lines before it
+        if !line.is_empty() {
+            match line.split_once(':') {
+                Some(("event", value)) => event = value.trim_start().to_string(),
+                Some(("data", value)) => {
+                    if !data.is_empty() {
+                        data.push('\n');
+                    }
+                    data.push_str(value.trim_start());
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let forwarded = forward(state, session_id, (event == "state").then_some(data.as_str()));
+        if !forwarded {
+            return false;
+        }
+        event.clear();
+        data.clear();
+    }
+    true
+}
+
+/// Forward the pushed messages from a WebSocket until it fails, like
+/// `relay`. The server doesn't ping over a WebSocket, so when it has been
+/// quiet for a while we ping it, and give up on it if nothing comes back.
+fn relay_socket(state: &AppState, session_id: &Uuid, mut socket: WebSocket) -> bool {
+    loop {
+        let message = match socket.receive() {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                if socket.last_heard().elapsed() > Duration::from_secs(3 * PING_INTERVAL) {
+                    log_error!("[PUSH] WebSocket for session {} stopped answering", session_id);
+                    return true;
+                }
+                if socket.ping().is_err() {
+                    return true;
+                }
+                if !state.push.ping(session_id) {
+                    return false;
+                }
+                continue;
+            }
+            Err(e) => {
+                log_error!("[PUSH] WebSocket for session {} failed: {}", session_id, e);
+                return true;
+            }
+        };
+        let is_state_change = serde_json::from_str::<Value>(&message)
+            .is_ok_and(|value| value["@type"] == "StateChange");
+        if !forward(state, session_id, is_state_change.then_some(message.as_str())) {
+            return false;
+        }
+    }
+}
+
+/// Publish what a StateChange reports, or a ping for anything else.
+/// Returns `false` once nobody is listening any more.
+fn forward(state: &AppState, session_id: &Uuid, state_change: Option<&str>) -> bool {
+    let Some(data) = state_change else {
+        return state.push.ping(session_id);
+    };
+    // What the session keeps may be out of date now
+    if state.sessions.get(session_id, |s| s.cache.changed()).is_none() {
+        state.push.close(session_id);
+        return false;
+    }
+    // New mail in a muted thread goes before the tabs look
+    for account_id in state.push.accounts(session_id) {
+        if !state_changes(data, &account_id).contains(&Change::Email) {
+            continue;
+        }
+        if let Some(client) = state.sessions.get(session_id, |s| s.client_for(&account_id)).flatten() {
+            if let Err(e) = client.get_mailboxes().and_then(|mailboxes| mute::file_new(&client, &mailboxes)) {
+                log_error!("[PUSH] Filing away muted messages failed for session {}: {}", session_id, e);
+            }
+        }
+    }
+    state.push.publish(session_id, |account_id| state_changes(data, account_id))
+}
+
+/// The changes a StateChange object reports for `account_id`. Other
+/// accounts' changes still count as a ping.
+fn state_changes(data: &str, account_id: &str) -> Vec<Change> {
+    let state_change: Value = match serde_json::from_str(data) {
+        Ok(v) => v,
+        Err(e) => {
+            log_error!("[PUSH] Invalid StateChange: {}", e);
+            return vec![Change::Ping];
+        }
+    };
+    let changed = &state_change["changed"][account_id];
+    let changes: Vec<Change> = [("Email", Change::Email), ("Mailbox", Change::Mailbox)]
+        .into_iter()
+        .filter(|(type_name, _)| changed.get(type_name).is_some())
+        .map(|(_, change)| change)
+        .collect();
+    if changes.is_empty() {
+        vec![Change::Ping]
+    } else {
+        changes
+    }
+}
@@ -0,0 +1,56 @@
+//! Installing the webmail as an app: a web app manifest, and a service
+//! worker (static/sw.js) that keeps the assets and the app shell for
+//! loading without waiting on the network. Both are served at the top of
+//! the base path, since a worker only controls the pages under its own
+//! directory.
+
+use serde_json::json;
+
+use crate::assets;
+use crate::config::BrandingConfig;
+
+/// The colour of the title bar around the installed app, style.css's
+/// --button
+pub const THEME_COLOR: &str = "#333333";
+
+/// The manifest, named after the branding
+pub fn manifest(branding: &BrandingConfig, base_path: &str) -> String {
+    json!({
+        "name": branding.name,
+        "short_name": branding.name,
+        "start_url": format!("{}/", base_path),
+        "scope": format!("{}/", base_path),
+        "display": "standalone",
+        // For an installed app, where the browser offers it
+        "protocol_handlers": [{
+            "protocol": "mailto",
+            "url": format!("{}/compose?to=%s", base_path)
+        }],
+        // style.css's --bg, for the splash screen
+        "background_color": "#fafafa",
+        "theme_color": THEME_COLOR,
+        "icons": [{
+            "src": format!("{}{}", base_path, assets::url("icon.svg")),
+            "sizes": "any",
+            "type": "image/svg+xml"
+        }]
+    })
+    .to_string()
+}
+
+/// The worker's script, told where the app is and what this build's
+/// assets are. A new build changes it, which is how browsers learn to
+/// install the new worker and drop what the old one kept.
+pub fn service_worker(base_path: &str) -> String {
+    let assets: Vec<String> = assets::urls()
+        .into_iter()
+        .map(|url| format!("{}{}", base_path, url))
+        .collect();
+    format!(
+        "var BASE = {};\nvar VERSION = {};\nvar ASSETS = {};\n{}",
+        json!(base_path),
+        json!(assets::version()),
+        json!(assets),
+        include_str!("../static/sw.js")
+    )
+}
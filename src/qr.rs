@@ -0,0 +1,423 @@
+//! A QR code encoder, for the two-factor setup page's provisioning URI.
+//!
+//! Text is put in one byte-mode segment at error correction level M, in
+//! the smallest version (1 to 40) it fits, with the mask scoring lowest by
+//! the standard's penalty rules. The code is drawn as an SVG of dark
+//! squares on a light quiet zone, so it scans in either theme.
+
+/// Error correction codewords per block at level M, by version
+const ECC_PER_BLOCK: [usize; 41] = [
+    0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28, 28, 28, 28, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+];
+/// Blocks the codewords are split into at level M, by version
+const BLOCKS: [usize; 41] = [
+    0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25, 26, 28, 29, 31,
+    33, 35, 37, 38, 40, 43, 45, 47, 49,
+];
+/// Level M's two bits in the format information
+const LEVEL_M: u32 = 0;
+/// Light modules around the code, as the standard asks
+const QUIET_ZONE: usize = 4;
+
+/// The modules of a QR code, dark as `true`, row by row
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    /// Finder, timing, alignment, format and version modules, which the
+    /// data and the mask leave alone
+    reserved: Vec<bool>,
+}
+
+impl QrCode {
+    /// `text` as a QR code, or `None` if it is too long for one
+    pub fn encode(text: &str) -> Option<QrCode> {
+        let data = text.as_bytes();
+        let version = (1..=40).find(|&v| header_bits(v) + data.len() * 8 <= data_codewords(v) * 8)?;
+        let mut code = QrCode::blank(version);
+        code.draw_function_patterns(version);
+        code.draw_codewords(&interleave(version, &data_codewords_for(version, data)));
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                code.apply_mask(mask);
+                code.draw_format(mask);
+                let penalty = code.penalty();
+                code.apply_mask(mask);
+                penalty
+            })
+            .unwrap();
+        code.apply_mask(mask);
+        code.draw_format(mask);
+        Some(code)
+    }
+
+    /// The code as an SVG image, a unit per module
+    pub fn svg(&self) -> String {
+        let width = self.size + 2 * QUIET_ZONE;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.get(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+                }
+            }
+        }
+        format!(
+            r##"<svg class="qr" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {w} {w}" shape-rendering="crispEdges"><rect width="{w}" height="{w}" fill="#fff"/><path d="{path}" fill="#000"/></svg>"##,
+            w = width,
+            path = path
+        )
+    }
+
+    fn blank(version: usize) -> QrCode {
+        let size = version * 4 + 17;
+        QrCode {
+            size,
+            modules: vec![false; size * size],
+            reserved: vec![false; size * size],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// Set a function module, which the data will go around
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.reserved[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.draw_finder(x, y);
+        }
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Those corners are taken by the finders
+                let corner = (i == 0 || j == 0) && (i.max(j) == 0 || i.max(j) == last);
+                if !corner {
+                    self.draw_alignment(x, y);
+                }
+            }
+        }
+        // Reserved now, written once the mask is chosen
+        self.draw_format(0);
+        self.draw_version(version);
+    }
+
+    /// A finder pattern centred on `x`, `y`, with its light separator
+    fn draw_finder(&mut self, x: usize, y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                if (0..self.size as i32).contains(&xx) && (0..self.size as i32).contains(&yy) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function(xx as usize, yy as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, x: usize, y: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let distance = dx.abs().max(dy.abs());
+                self.set_function((x as i32 + dx) as usize, (y as i32 + dy) as usize, distance != 1);
+            }
+        }
+    }
+
+    /// The level and mask, with their BCH check bits, in both copies
+    fn draw_format(&mut self, mask: u32) {
+        let data = (LEVEL_M << 3) | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+        let size = self.size;
+
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        // Always dark
+        self.set_function(8, size - 8, true);
+    }
+
+    /// From version 7 on, the version with its check bits by two corners
+    fn draw_version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+        let mut remainder = version as u32;
+        for _ in 0..12 {
+            remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+        }
+        let bits = ((version as u32) << 12) | remainder;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Fill the free modules in the zigzag of two-module columns, from the
+    /// bottom right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size as i32 - 1;
+        while right >= 1 {
+            // The vertical timing pattern's column is skipped
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right as usize - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vertical } else { vertical };
+                    if !self.reserved[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    /// Flip the data modules `mask` picks; doing it twice undoes it
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let at = y * self.size + x;
+                if flip && !self.reserved[at] {
+                    self.modules[at] = !self.modules[at];
+                }
+            }
+        }
+    }
+
+    /// How hard the code is to scan: long runs, 2x2 blocks, stretches that
+    /// look like a finder, and an imbalance of dark and light
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        let lines = (0..size).flat_map(|i| {
+            [
+                (0..size).map(|j| self.get(j, i)).collect::<Vec<bool>>(),
+                (0..size).map(|j| self.get(i, j)).collect::<Vec<bool>>(),
+            ]
+        });
+        const FINDER: [bool; 11] = [true, false, true, true, true, false, true, false, false, false, false];
+        for line in lines {
+            let mut run = 1;
+            for j in 1..=size {
+                if j < size && line[j] == line[j - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+            }
+            for window in line.windows(FINDER.len()) {
+                if window == FINDER || window.iter().rev().eq(FINDER.iter()) {
+                    penalty += 40;
+                }
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.get(x, y);
+                if dark == self.get(x + 1, y) && dark == self.get(x, y + 1) && dark == self.get(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+        let dark = self.modules.iter().filter(|&&m| m).count();
+        let total = size * size;
+        // Each 5% away from half and half beyond the first
+        let k = (dark * 20).abs_diff(total * 10).div_ceil(total).saturating_sub(1);
+        penalty + k * 10
+    }
+}
+
+/// Bits taken by the byte mode indicator and the character count
+fn header_bits(version: usize) -> usize {
+    4 + if version <= 9 { 8 } else { 16 }
+}
+
+/// Modules left for codewords once the function patterns are drawn
+fn raw_data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codewords(version: usize) -> usize {
+    raw_data_modules(version) / 8 - ECC_PER_BLOCK[version] * BLOCKS[version]
+}
+
+/// Centres of the alignment patterns along each axis
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = (version * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    let last = version * 4 + 17 - 7;
+    let mut positions = vec![6];
+    positions.extend((0..count - 1).rev().map(|i| last - i * step));
+    positions
+}
+
+/// `data` as one byte-mode segment, terminated and padded to the version's
+/// capacity
+fn data_codewords_for(version: usize, data: &[u8]) -> Vec<u8> {
+    let capacity = data_codewords(version) * 8;
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity);
+    let mut push = |value: u32, count: usize| {
+        for i in (0..count).rev() {
+            bits.push((value >> i) & 1 != 0);
+        }
+    };
+    push(0b0100, 4);
+    push(data.len() as u32, header_bits(version) - 4);
+    for &byte in data {
+        push(byte as u32, 8);
+    }
+    let terminator = (capacity - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    bits.extend(std::iter::repeat_n(false, (8 - bits.len() % 8) % 8));
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8))
+        .collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() * 8 >= capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+/// Split the data into the version's blocks, add each one's error
+/// correction, and take the blocks a codeword at a time
+fn interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let blocks = BLOCKS[version];
+    let ecc_len = ECC_PER_BLOCK[version];
+    let raw_codewords = raw_data_modules(version) / 8;
+    let short_blocks = blocks - raw_codewords % blocks;
+    let short_len = raw_codewords / blocks;
+    let divisor = rs_divisor(ecc_len);
+
+    let mut split = Vec::with_capacity(blocks);
+    let mut k = 0;
+    for i in 0..blocks {
+        let len = short_len - ecc_len + usize::from(i >= short_blocks);
+        let mut block = data[k..k + len].to_vec();
+        k += len;
+        let ecc = rs_remainder(&block, &divisor);
+        // A placeholder, so every block lines up
+        if i < short_blocks {
+            block.push(0);
+        }
+        block.extend(ecc);
+        split.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..split[0].len() {
+        for (j, block) in split.iter().enumerate() {
+            if i != short_len - ecc_len || j >= short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+/// The Reed-Solomon generator polynomial of `degree`, highest term first
+/// and its leading 1 left out
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+/// The error correction codewords for `data`
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (r, &coefficient) in result.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(coefficient, factor);
+        }
+    }
+    result
+}
@@ -0,0 +1,170 @@
+//! Connections from browsers, taken in here and relayed to tiny_http on a
+//! loopback port of its own.
+//!
+//! tiny_http never lets go of the sockets it accepts, so this is where a
+//! client is timed out (`[server.limits]`): one that goes quiet part way
+//! through a request, or between requests, has its connection closed, and
+//! the handler reading the body gets an error rather than waiting on it.
+//! Each connection takes threads of its own, so only `max_connections` are
+//! relayed at once; the rest are turned away as they arrive.
+//! With `[server.tls]` the connection is decrypted on the way (`tls.rs`).
+//! Handlers see the relay's loopback address as the peer; [`RelayedPeers`]
+//! says who is really on the other end, and a connection to that port it
+//! doesn't know of is refused.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rustls::ServerConfig;
+
+use crate::config::LimitsConfig;
+use crate::handlers::AppState;
+use crate::tls;
+use crate::{log_debug, log_error, log_warn};
+
+/// The client behind each relayed connection, by the loopback address the
+/// relay reaches tiny_http from
+#[derive(Default)]
+pub struct RelayedPeers(Mutex<HashMap<SocketAddr, SocketAddr>>);
+
+impl RelayedPeers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The real client of a request tiny_http got from `relay`, or `None`
+    /// if it didn't come through a relay
+    pub fn client(&self, relay: SocketAddr) -> Option<SocketAddr> {
+        self.0.lock().unwrap().get(&relay).copied()
+    }
+}
+
+/// Listen on `listen_addr`, relaying each connection to the HTTP server at
+/// `backend`, through TLS with `tls`
+pub fn spawn(
+    listen_addr: &str,
+    backend: SocketAddr,
+    tls: Option<Arc<ServerConfig>>,
+    state: Arc<AppState>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(listen_addr)?;
+    let open = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log_error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            // Only this thread adds to the count, so it can't pass the limit
+            let max = state.config.server.limits.max_connections;
+            if max > 0 && open.load(Ordering::SeqCst) >= max {
+                log_warn!("Turning away {:?}: {} connections are open already", stream.peer_addr().ok(), max);
+                turn_away(stream, tls.is_some());
+                continue;
+            }
+            let slot = Slot::take(&open);
+            let tls = tls.clone();
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                let _slot = slot;
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = relay(stream, backend, tls, &state) {
+                    log_debug!("Connection from {:?} ended: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// One of the connections counted against `max_connections`, given back
+/// when its relay ends
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(open: &Arc<AtomicUsize>) -> Slot {
+        open.fetch_add(1, Ordering::SeqCst);
+        Slot(Arc::clone(open))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Close a connection there is no room for, telling a plain HTTP client
+/// to come back later. Over TLS nothing can be said before a handshake,
+/// which would take the thread the limit is saving.
+fn turn_away(mut stream: TcpStream, tls: bool) {
+    if !tls {
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+        let _ = stream.write_all(
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 5\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// The timeout for a setting in seconds, where 0 is none
+fn timeout(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Give the client this long without sending anything, or without taking
+/// any of a response, before the connection is closed
+fn set_timeouts(client: &TcpStream, limits: &LimitsConfig) -> io::Result<()> {
+    client.set_read_timeout(timeout(limits.read_timeout_seconds))?;
+    client.set_write_timeout(timeout(limits.write_timeout_seconds))
+}
+
+fn relay(client: TcpStream, backend: SocketAddr, tls: Option<Arc<ServerConfig>>, state: &AppState) -> io::Result<()> {
+    let peer = client.peer_addr()?;
+    // Before the handshake, so a client can't hold the thread by never
+    // finishing it
+    set_timeouts(&client, &state.config.server.limits)?;
+    let tls = match tls {
+        Some(config) => Some(tls::handshake(&client, config)?),
+        None => None,
+    };
+
+    let upstream = TcpStream::connect(backend)?;
+    let relay_addr = upstream.local_addr()?;
+    state.relayed_peers.0.lock().unwrap().insert(relay_addr, peer);
+    let result = match tls {
+        Some(conn) => tls::relay(client, upstream, conn),
+        None => relay_plain(client, upstream),
+    };
+    state.relayed_peers.0.lock().unwrap().remove(&relay_addr);
+    result
+}
+
+/// Pass bytes both ways until either side is done. Responses go back on a
+/// thread of their own, so neither direction waits on the other.
+fn relay_plain(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let responses = {
+        let mut upstream = upstream.try_clone()?;
+        let mut client = client.try_clone()?;
+        thread::spawn(move || {
+            let copied = io::copy(&mut upstream, &mut client);
+            // Also ends the wait for the client below
+            let _ = client.shutdown(Shutdown::Both);
+            copied
+        })
+    };
+    let result = io::copy(&mut &client, &mut &upstream).map(|_| ());
+    // Let tiny_http finish with the connection, which ends the responses;
+    // a body cut off here fails to read
+    let _ = upstream.shutdown(Shutdown::Write);
+    let _ = responses.join();
+    result
+}
@@ -0,0 +1,57 @@
+//! Re-reading the config file on SIGHUP, for the settings that can change
+//! safely while sessions are open: log level and format, the poll
+//! interval, `[server.access]` and `[server.headers]`. Anything else needs
+//! a restart.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::cli::Options;
+use crate::config::{Config, LiveSettings};
+use crate::handlers::AppState;
+use crate::log;
+use crate::{log_error, log_info};
+
+/// How often the reload thread looks for a signal
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    // Only async-signal-safe work here; the reload thread does the rest
+    HANGUP.store(true, Ordering::Relaxed);
+}
+
+/// Catch SIGHUP and reload the config the process was started with,
+/// applying `options` from the command line over it again
+pub fn spawn(state: Arc<AppState>, options: Options) {
+    // SAFETY: the handler only stores to an atomic
+    unsafe {
+        libc::signal(libc::SIGHUP, on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        if HANGUP.swap(false, Ordering::Relaxed) {
+            reload(&state, &options);
+        }
+    });
+}
+
+fn reload(state: &AppState, options: &Options) {
+    let mut config = match Config::load(&options.config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            log_error!("Not reloading {}: {}", options.config_path, e);
+            return;
+        }
+    };
+    if let Err(e) = options.apply(&mut config) {
+        log_error!("Not reloading {}: {}", options.config_path, e);
+        return;
+    }
+    log::init(&config.log);
+    *state.live.write().unwrap() = Arc::new(LiveSettings::from_config(&config));
+    log_info!("Reloaded {}", options.config_path);
+}
@@ -0,0 +1,132 @@
+//! Scheduled purge of old Trash and Junk messages.
+//!
+//! A background thread wakes up every `interval_minutes`, and for each session
+//! that has the policy enabled queries Trash/Junk for messages received before
+//! the cutoff and destroys them with Email/set.
+
+use serde_json::json;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::config::RetentionConfig;
+use crate::handlers::AppState;
+use crate::jmap::{JmapClient, JmapError};
+use crate::{log_error, log_info};
+
+const BATCH_SIZE: u32 = 256;
+// Safety valve so a server that never reports progress can't spin us forever
+const MAX_BATCHES: u32 = 100;
+const MAX_LOG_ENTRIES: usize = 50;
+
+/// One line in a session's purge log
+pub struct PurgeRecord {
+    pub at: String,
+    pub mailbox: String,
+    pub cutoff: String,
+    pub count: usize,
+}
+
+/// Start the background purge thread if the operator configured a policy
+pub fn spawn(state: Arc<AppState>) {
+    let config = &state.config.retention;
+    if !config.is_configured() {
+        return;
+    }
+
+    let interval = Duration::from_secs(config.interval_minutes.max(1) * 60);
+    log_info!(
+        "[RETENTION] Policy active (trash: {:?} days, junk: {:?} days), running every {:?}",
+        config.trash_days,
+        config.junk_days,
+        interval
+    );
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        run_all(&state);
+    });
+}
+
+fn run_all(state: &AppState) {
+    for id in state.sessions.ids() {
+        let client = state
+            .sessions
+            .get(&id, |s| s.retention_enabled.then(|| s.client()))
+            .flatten();
+        if let Some(client) = client {
+            run_for_session(state, &id, &client);
+        }
+    }
+}
+
+/// Purge one session now and append the results to its log
+pub fn run_for_session(state: &AppState, session_id: &uuid::Uuid, client: &JmapClient) {
+    match purge(client, &state.config.retention) {
+        Ok(records) => {
+            state.sessions.update(session_id, |s| {
+                s.purge_log.extend(records);
+                let excess = s.purge_log.len().saturating_sub(MAX_LOG_ENTRIES);
+                s.purge_log.drain(..excess);
+            });
+        }
+        Err(e) => log_error!("[RETENTION] Purge failed for session {}: {}", session_id, e),
+    }
+}
+
+/// Destroy Trash/Junk messages older than the configured number of days
+pub fn purge(client: &JmapClient, config: &RetentionConfig) -> Result<Vec<PurgeRecord>, JmapError> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut records = Vec::new();
+    for mailbox in client.get_mailboxes()? {
+        let days = match mailbox.role.as_deref() {
+            Some("trash") => config.trash_days,
+            Some("junk") | Some("spam") => config.junk_days,
+            _ => None,
+        };
+        let Some(days) = days else { continue };
+
+        let cutoff = crate::log::utc_date(now.saturating_sub(days as u64 * 86400));
+        let filter = json!({ "inMailbox": mailbox.id, "before": cutoff });
+        let count = destroy_matching(client, filter)?;
+
+        if count > 0 {
+            log_info!(
+                "[RETENTION] Purged {} messages older than {} from {}",
+                count,
+                cutoff,
+                mailbox.name
+            );
+        }
+        records.push(PurgeRecord {
+            at: crate::log::now(),
+            mailbox: mailbox.name,
+            cutoff,
+            count,
+        });
+    }
+
+    Ok(records)
+}
+
+/// Destroy every message matching an Email/query filter, in batches.
+/// Returns how many were destroyed.
+pub fn destroy_matching(client: &JmapClient, filter: serde_json::Value) -> Result<usize, JmapError> {
+    let mut count = 0;
+    for _ in 0..MAX_BATCHES {
+        let ids = client.query_emails_filtered(filter.clone(), BATCH_SIZE, 0)?.ids;
+        if ids.is_empty() {
+            break;
+        }
+        let destroyed = client.destroy_emails(&ids)?;
+        count += destroyed;
+        if destroyed == 0 {
+            break;
+        }
+    }
+    Ok(count)
+}
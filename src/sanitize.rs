@@ -0,0 +1,570 @@
+//! Allow-list HTML sanitizer for displaying HTML mail.
+//!
+//! The input is tokenized and re-serialized: only known-safe tags and
+//! attributes are emitted, attribute values are re-escaped, URLs are limited
+//! to safe schemes and inline CSS is filtered per declaration. Tags are
+//! balanced against what was actually emitted, so a message can never close
+//! the container it is rendered into.
+//!
+//! Remote images (img src, and CSS url() or the strings image-set() takes)
+//! are replaced with a placeholder unless the policy allows them, so
+//! opening a message can't be tracked. cid: references to inline parts
+//! are rewritten to the URLs the caller supplies in the policy, and web
+//! links can be sent through the `/redirect` interstitial (`src/links.rs`).
+//! Links always open in a new tab without an opener.
+
+use std::collections::HashMap;
+
+use crate::links;
+
+#[rustfmt::skip]
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "abbr", "address", "b", "bdi", "bdo", "big", "blockquote", "br", "caption", "center",
+    "cite", "code", "col", "colgroup", "dd", "del", "dfn", "div", "dl", "dt", "em",
+    "figcaption", "figure", "font", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i", "img", "ins",
+    "kbd", "li", "mark", "ol", "p", "pre", "q", "s", "samp", "small", "span", "strike",
+    "strong", "sub", "sup", "table", "tbody", "td", "tfoot", "th", "thead", "tr", "tt", "u",
+    "ul", "var", "wbr",
+];
+
+const VOID_TAGS: &[&str] = &["br", "col", "hr", "img", "wbr"];
+
+// Dropped together with everything inside them
+const DROP_WITH_CONTENT: &[&str] = &[
+    "script", "style", "head", "title", "iframe", "frame", "frameset", "object", "embed", "applet",
+    "noscript", "noembed", "template", "textarea", "select", "button", "svg", "math", "audio",
+    "video", "canvas",
+];
+
+#[rustfmt::skip]
+const ALLOWED_ATTRIBUTES: &[&str] = &[
+    "align", "alt", "bgcolor", "border", "cellpadding", "cellspacing", "color", "colspan",
+    "dir", "face", "height", "href", "lang", "rowspan", "size", "src", "start", "style",
+    "title", "type", "valign", "width",
+];
+
+#[rustfmt::skip]
+const ALLOWED_CSS_PROPERTIES: &[&str] = &[
+    "background", "background-color", "background-image", "border", "border-bottom",
+    "border-collapse", "border-color", "border-left", "border-radius", "border-right",
+    "border-spacing", "border-style", "border-top", "border-width", "color", "direction",
+    "display", "font", "font-family", "font-size", "font-style", "font-variant", "font-weight",
+    "height", "letter-spacing", "line-height", "list-style", "list-style-type", "margin",
+    "margin-bottom", "margin-left", "margin-right", "margin-top", "max-width", "min-width",
+    "padding", "padding-bottom", "padding-left", "padding-right", "padding-top", "table-layout",
+    "text-align", "text-decoration", "text-indent", "text-transform", "vertical-align",
+    "white-space", "width", "word-break", "word-spacing", "word-wrap",
+];
+
+/// Transparent 1x1 GIF shown in place of a blocked remote image
+const BLOCKED_IMAGE: &str =
+    "data:image/gif;base64,R0lGODlhAQABAIAAAAAAAP///yH5BAEAAAAALAAAAAABAAEAAAIBRAA7";
+
+const EMBEDDED_IMAGE_PREFIXES: &[&str] = &[
+    "data:image/png",
+    "data:image/gif",
+    "data:image/jpeg",
+    "data:image/webp",
+];
+
+#[derive(Default)]
+pub struct Policy {
+    /// Load http(s) images and CSS backgrounds instead of blocking them
+    pub remote_images: bool,
+    /// Content-ID (without angle brackets) to the URL serving that part
+    pub inline_images: HashMap<String, String>,
+    /// Point http(s) links at the interstitial on their way out
+    pub redirect_links: bool,
+}
+
+pub struct Sanitized {
+    pub html: String,
+    /// Whether any remote image was replaced by a placeholder
+    pub blocked_remote: bool,
+}
+
+/// Sanitize an HTML message body for inclusion in the email view
+pub fn sanitize(html: &str, policy: &Policy) -> Sanitized {
+    let mut sanitizer = Sanitizer {
+        policy,
+        blocked_remote: false,
+    };
+    let html = sanitizer.run(html);
+    Sanitized {
+        html,
+        blocked_remote: sanitizer.blocked_remote,
+    }
+}
+
+/// Sanitize an HTML signature typed in settings, which goes out in mail.
+/// Its remote images, such as a logo, are kept, since it's the recipients
+/// who load them.
+pub fn signature(html: &str) -> String {
+    let policy = Policy {
+        remote_images: true,
+        ..Policy::default()
+    };
+    sanitize(html, &policy).html
+}
+
+struct Sanitizer<'a> {
+    policy: &'a Policy,
+    blocked_remote: bool,
+}
+
+impl Sanitizer<'_> {
+    fn run(&mut self, html: &str) -> String {
+        let mut out = String::with_capacity(html.len());
+        let mut open: Vec<String> = Vec::new();
+        let mut rest = html;
+
+        while let Some(lt) = rest.find('<') {
+            push_text(&mut out, &rest[..lt]);
+            rest = &rest[lt..];
+
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+                continue;
+            }
+            if rest.starts_with("<!") || rest.starts_with("<?") {
+                rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+                continue;
+            }
+
+            let Some((tag, after)) = parse_tag(rest) else {
+                out.push_str("&lt;");
+                rest = &rest[1..];
+                continue;
+            };
+            rest = after;
+
+            if tag.closing {
+                close_tag(&mut out, &mut open, &tag.name);
+                continue;
+            }
+
+            if DROP_WITH_CONTENT.contains(&tag.name.as_str()) {
+                if !tag.self_closing {
+                    rest = skip_past_end_tag(rest, &tag.name);
+                }
+                continue;
+            }
+
+            if !ALLOWED_TAGS.contains(&tag.name.as_str()) {
+                // Unknown wrappers (html, body, form, center-like vendor tags...)
+                // vanish but their content stays
+                continue;
+            }
+
+            out.push('<');
+            out.push_str(&tag.name);
+            for (name, value) in &tag.attributes {
+                if let Some(value) = self.sanitize_attribute(&tag.name, name, value) {
+                    out.push_str(&format!(" {}=\"{}\"", name, escape(&value)));
+                }
+            }
+            if tag.name == "img" && !self.policy.remote_images && is_remote(tag.attribute("src")) {
+                out.push_str(" class=\"remote-blocked\"");
+            }
+            if tag.name == "a" {
+                out.push_str(" target=\"_blank\" rel=\"noopener noreferrer\"");
+            }
+            out.push('>');
+
+            if !VOID_TAGS.contains(&tag.name.as_str()) {
+                open.push(tag.name);
+            }
+        }
+        push_text(&mut out, rest);
+
+        while let Some(name) = open.pop() {
+            out.push_str(&format!("</{}>", name));
+        }
+        out
+    }
+
+    /// Resolve a cid: URL (RFC 2392) to the proxy URL for that body part
+    fn inline_image(&self, url: &str) -> Option<&str> {
+        let url = url.trim();
+        let cid = url.get(..4)?.eq_ignore_ascii_case("cid:").then(|| &url[4..])?;
+        self.policy.inline_images.get(cid).map(String::as_str)
+    }
+
+    fn sanitize_attribute(&mut self, tag: &str, name: &str, value: &str) -> Option<String> {
+        if !ALLOWED_ATTRIBUTES.contains(&name) {
+            return None;
+        }
+        match name {
+            "href" if tag == "a" => {
+                let url = safe_url(value, &["http:", "https:", "mailto:"])?;
+                // Every web link goes by the interstitial, even one it will
+                // refuse, rather than straight to wherever it points
+                let web = !url.starts_with('#') && safe_url(&url, &["http:", "https:"]).is_some();
+                if self.policy.redirect_links && web {
+                    return Some(links::redirect_path(&url));
+                }
+                Some(url)
+            }
+            "href" => None,
+            "src" if tag == "img" => {
+                if is_remote(Some(value)) {
+                    if self.policy.remote_images {
+                        return Some(value.trim().to_string());
+                    }
+                    self.blocked_remote = true;
+                    return Some(BLOCKED_IMAGE.to_string());
+                }
+                if let Some(url) = self.inline_image(value) {
+                    return Some(url.to_string());
+                }
+                safe_url(value, EMBEDDED_IMAGE_PREFIXES)
+            }
+            "src" => None,
+            "style" => {
+                let css = self.sanitize_css(value);
+                (!css.is_empty()).then_some(css)
+            }
+            _ => Some(value.to_string()),
+        }
+    }
+
+    /// Keep only allow-listed declarations whose values can't run code,
+    /// rewriting url() references according to the policy
+    fn sanitize_css(&mut self, style: &str) -> String {
+        style
+            .split(';')
+            .filter_map(|declaration| {
+                let (property, value) = declaration.split_once(':')?;
+                let property = property.trim().to_ascii_lowercase();
+                if !ALLOWED_CSS_PROPERTIES.contains(&property.as_str()) {
+                    return None;
+                }
+                let value = self.rewrite_css_urls(value.trim())?;
+                let lower = value.to_ascii_lowercase();
+                let dangerous = [
+                    "expression",
+                    "javascript:",
+                    "@import",
+                    "behavior",
+                    "\\",
+                    "<",
+                ]
+                .iter()
+                .any(|bad| lower.contains(bad));
+                (!dangerous && !value.is_empty()).then(|| format!("{}: {}", property, value))
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// Replace each url(...) with a quoted remote URL when allowed, or
+    /// `none` otherwise. A quoted string that could be a URL, as
+    /// `image-set()` and `-webkit-image-set()` take them, gets the same
+    /// treatment. Returns None if a url() or string can't be parsed.
+    fn rewrite_css_urls(&mut self, value: &str) -> Option<String> {
+        let mut out = String::new();
+        let mut rest = value;
+        loop {
+            let lower = rest.to_ascii_lowercase();
+            let Some(start) = [lower.find("url("), rest.find(['"', '\''])].into_iter().flatten().min() else {
+                break;
+            };
+            out.push_str(&rest[..start]);
+            if lower[start..].starts_with("url(") {
+                let after = &rest[start + 4..];
+                let end = after.find(')')?;
+                let url = after[..end].trim().trim_matches(|c| c == '"' || c == '\'');
+                match self.css_url(url) {
+                    Some(url) => out.push_str(&format!("url(\"{}\")", url)),
+                    None => out.push_str("none"),
+                }
+                rest = &after[end + 1..];
+            } else {
+                let quote = rest[start..].chars().next()?;
+                let after = &rest[start + 1..];
+                let end = after.find(quote)?;
+                let text = &after[..end];
+                // Font names never have these; URLs always do
+                if !text.contains([':', '/']) {
+                    out.push_str(&rest[start..start + end + 2]);
+                } else {
+                    match self.css_url(text) {
+                        Some(url) => out.push_str(&format!("\"{}\"", url)),
+                        None => out.push_str("none"),
+                    }
+                }
+                rest = &after[end + 1..];
+            }
+        }
+        out.push_str(rest);
+        Some(out)
+    }
+
+    /// The URL a stylesheet may load for `url`: an inline part's, or a
+    /// remote one when the policy allows, noting any remote one blocked
+    fn css_url(&mut self, url: &str) -> Option<String> {
+        if let Some(inline) = self.inline_image(url) {
+            return Some(inline.to_string());
+        }
+        if !is_remote(Some(url)) {
+            return None;
+        }
+        if self.policy.remote_images && !url.contains(['"', '\\', '(', ')']) {
+            return Some(url.to_string());
+        }
+        self.blocked_remote = true;
+        None
+    }
+}
+
+fn is_remote(url: Option<&str>) -> bool {
+    url.is_some_and(|u| {
+        let u = u.trim().to_ascii_lowercase();
+        u.starts_with("http://") || u.starts_with("https://") || u.starts_with("//")
+    })
+}
+
+struct Tag {
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    attributes: Vec<(String, String)>,
+}
+
+impl Tag {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parse a tag starting at `<`, returning it and the input after its `>`
+fn parse_tag(input: &str) -> Option<(Tag, &str)> {
+    let mut chars = input[1..].char_indices().peekable();
+    let closing = matches!(chars.peek(), Some((_, '/')));
+    if closing {
+        chars.next();
+    }
+    let (name_start, first) = *chars.peek()?;
+    if !first.is_ascii_alphabetic() {
+        return None;
+    }
+
+    let body = &input[1..];
+    let name_end = body[name_start..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == ':'))
+        .map_or(body.len(), |i| name_start + i);
+    let name = body[name_start..name_end].to_ascii_lowercase();
+
+    let mut attributes = Vec::new();
+    let mut self_closing = false;
+    let mut pos = name_end;
+    let bytes = body.as_bytes();
+    loop {
+        while pos < bytes.len() && (bytes[pos].is_ascii_whitespace() || bytes[pos] == b'/') {
+            self_closing = bytes[pos] == b'/';
+            pos += 1;
+        }
+        if pos >= bytes.len() {
+            // Unterminated tag: treat the rest of the input as consumed
+            return Some((
+                Tag {
+                    name,
+                    closing,
+                    self_closing,
+                    attributes,
+                },
+                "",
+            ));
+        }
+        if bytes[pos] == b'>' {
+            pos += 1;
+            break;
+        }
+        self_closing = false;
+
+        let attr_start = pos;
+        while pos < bytes.len()
+            && !bytes[pos].is_ascii_whitespace()
+            && !matches!(bytes[pos], b'=' | b'>' | b'/')
+        {
+            pos += 1;
+        }
+        let attr_name = body[attr_start..pos].to_ascii_lowercase();
+        if attr_name.is_empty() {
+            // A stray '=' or similar; skip it
+            pos += 1;
+            continue;
+        }
+
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let mut value = String::new();
+        if pos < bytes.len() && bytes[pos] == b'=' {
+            pos += 1;
+            while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if pos < bytes.len() && (bytes[pos] == b'"' || bytes[pos] == b'\'') {
+                let quote = bytes[pos];
+                let value_start = pos + 1;
+                let value_end = body[value_start..]
+                    .find(quote as char)
+                    .map_or(body.len(), |i| value_start + i);
+                value = decode_entities(&body[value_start..value_end]);
+                pos = (value_end + 1).min(body.len());
+            } else {
+                let value_start = pos;
+                while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() && bytes[pos] != b'>' {
+                    pos += 1;
+                }
+                value = decode_entities(&body[value_start..pos]);
+            }
+        }
+        attributes.push((attr_name, value));
+    }
+
+    Some((
+        Tag {
+            name,
+            closing,
+            self_closing,
+            attributes,
+        },
+        &body[pos..],
+    ))
+}
+
+/// The text of an HTML fragment, for somewhere only plain text will do:
+/// tags dropped, line breaks where blocks and `<br>` end, entities decoded
+pub fn to_text(html: &str) -> String {
+    // Whitespace in the source is just spacing; only tags break lines
+    let text = |raw: &str| decode_entities(&raw.replace(['\r', '\n', '\t'], " ")).replace('\u{a0}', " ");
+    let mut out = String::new();
+    let mut rest = html;
+    while let Some(lt) = rest.find('<') {
+        out.push_str(&text(&rest[..lt]));
+        rest = &rest[lt..];
+        let Some((tag, after)) = parse_tag(rest) else {
+            out.push('<');
+            rest = &rest[1..];
+            continue;
+        };
+        rest = if !tag.closing && DROP_WITH_CONTENT.contains(&tag.name.as_str()) {
+            skip_past_end_tag(after, &tag.name)
+        } else {
+            after
+        };
+        if tag.name == "br" || (tag.closing && matches!(tag.name.as_str(), "p" | "div" | "li" | "tr")) {
+            out.push('\n');
+        }
+    }
+    out.push_str(&text(rest));
+    out.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// Skip raw content up to and including the matching end tag
+fn skip_past_end_tag<'a>(input: &'a str, name: &str) -> &'a str {
+    let lower = input.to_ascii_lowercase();
+    let needle = format!("</{}", name);
+    match lower.find(&needle) {
+        Some(start) => {
+            let after = &input[start..];
+            after.find('>').map_or("", |end| &after[end + 1..])
+        }
+        None => "",
+    }
+}
+
+/// Close `name` if we emitted it, closing anything opened inside it first
+fn close_tag(out: &mut String, open: &mut Vec<String>, name: &str) {
+    let Some(index) = open.iter().rposition(|t| t == name) else {
+        return;
+    };
+    while open.len() > index {
+        let tag = open.pop().unwrap();
+        out.push_str(&format!("</{}>", tag));
+    }
+}
+
+/// Accept a URL only if it uses one of the given scheme prefixes (or is a
+/// fragment link), ignoring whitespace and control characters browsers strip
+fn safe_url(value: &str, allowed_prefixes: &[&str]) -> Option<String> {
+    let normalized: String = value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let allowed =
+        normalized.starts_with('#') || allowed_prefixes.iter().any(|p| normalized.starts_with(p));
+    allowed.then(|| value.trim().to_string())
+}
+
+fn push_text(out: &mut String, text: &str) {
+    // Text keeps its entities; only a bare '>' needs escaping so nothing
+    // can be mistaken for markup
+    out.push_str(&text.replace('>', "&gt;"));
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Decode character references in an attribute value
+fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let end = rest[1..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '#'))
+            .map_or(rest.len(), |i| i + 1);
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            "colon" => Some(':'),
+            "tab" => Some('\t'),
+            "newline" => Some('\n'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end..];
+                rest = rest.strip_prefix(';').unwrap_or(rest);
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests;
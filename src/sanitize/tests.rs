@@ -0,0 +1,142 @@
+use super::*;
+
+fn clean(html: &str) -> String {
+    sanitize(html, &Policy::default()).html
+}
+
+#[test]
+fn scripts_and_styles_go_with_their_content() {
+    assert_eq!(clean("a<script>alert(1)</script>b"), "ab");
+    assert_eq!(clean("a<SCRIPT type=text/javascript>x</Script >b"), "ab");
+    assert_eq!(clean("<style>p { color: red }</style><p>text</p>"), "<p>text</p>");
+    assert_eq!(clean("<iframe src=https://evil.example/></iframe>ok"), "ok");
+    // Never closed, so nothing after it can be trusted
+    assert_eq!(clean("a<script>alert(1)"), "a");
+}
+
+#[test]
+fn event_handlers_are_stripped() {
+    assert_eq!(clean(r#"<div onclick="steal()" title="t">x</div>"#), r#"<div title="t">x</div>"#);
+    assert_eq!(clean("<b onmouseover=alert(1)>x</b>"), "<b>x</b>");
+    assert!(!clean(r#"<img src="cid:missing" onerror="alert(1)">"#).contains("onerror"));
+}
+
+#[test]
+fn script_links_lose_their_href() {
+    for href in [
+        "javascript:alert(1)",
+        " JaVaScRiPt:alert(1)",
+        "jav&#x61;script:alert(1)",
+        "jav&#97;script:alert(1)",
+        "javascript&colon;alert(1)",
+        "java&#9;script:alert(1)",
+        "java&Tab;script:alert(1)",
+        "java\nscript:alert(1)",
+        "vbscript:msgbox(1)",
+        "data:text/html,<script>alert(1)</script>",
+    ] {
+        let html = clean(&format!(r#"<a href="{}">x</a>"#, href));
+        assert!(!html.contains("href"), "{} became {}", href, html);
+    }
+    assert_eq!(
+        clean(r#"<a href="https://example.com/?a=1&amp;b=2">x</a>"#),
+        r#"<a href="https://example.com/?a=1&amp;b=2" target="_blank" rel="noopener noreferrer">x</a>"#
+    );
+}
+
+#[test]
+fn tags_are_balanced_against_what_was_emitted() {
+    // A stray end tag can't close the view the body is rendered into
+    assert_eq!(clean("</div></td>text"), "text");
+    assert_eq!(clean("<b><i>x</b>y</i>"), "<b><i>x</i></b>y");
+    assert_eq!(clean("<table><tr><td>cell"), "<table><tr><td>cell</td></tr></table>");
+    assert_eq!(clean("<p>a<br>b<img src=cid:x></p>"), "<p>a<br>b<img></p>");
+    assert_eq!(clean("<html><body><form><p>kept</p></form></body></html>"), "<p>kept</p>");
+    assert_eq!(clean("1 < 2 > 0"), "1 &lt; 2 &gt; 0");
+}
+
+#[test]
+fn comments_and_cdata_vanish() {
+    assert_eq!(clean("a<!-- <script>alert(1)</script> -->b"), "ab");
+    assert_eq!(clean("a<!-- never closed <b>"), "a");
+    assert_eq!(clean("<!DOCTYPE html><p>x</p>"), "<p>x</p>");
+    assert!(!clean("<![CDATA[<script>alert(1)</script>]]>").contains("<script"));
+    assert_eq!(clean("<?xml version=\"1.0\"?>x"), "x");
+}
+
+#[test]
+fn css_is_filtered_per_declaration() {
+    assert_eq!(
+        clean(r#"<p style="color: red; position: fixed; font-weight: bold">x</p>"#),
+        r#"<p style="color: red; font-weight: bold">x</p>"#
+    );
+    for style in [
+        "width: expression(alert(1))",
+        "behavior: url(x.htc)",
+        "color: \\65 xpression(1)",
+    ] {
+        let html = clean(&format!(r#"<p style="{}">x</p>"#, style));
+        assert!(!html.contains("style"), "{} became {}", style, html);
+    }
+    assert!(!clean(r#"<p style="background: url(javascript:alert(1)) red">x</p>"#).contains("javascript"));
+
+    let blocked = sanitize(r#"<td style="background: url('https://t.example/p.gif') red">x</td>"#, &Policy::default());
+    assert_eq!(blocked.html, r#"<td style="background: none red">x</td>"#);
+    assert!(blocked.blocked_remote);
+
+    let policy = Policy {
+        remote_images: true,
+        ..Policy::default()
+    };
+    let allowed = sanitize(r#"<td style="background-image: url(https://t.example/p.gif)">x</td>"#, &policy);
+    assert_eq!(allowed.html, r#"<td style="background-image: url(&quot;https://t.example/p.gif&quot;)">x</td>"#);
+    assert!(!allowed.blocked_remote);
+}
+
+#[test]
+fn image_sets_are_treated_like_urls() {
+    for style in [
+        r#"background-image: image-set("https://t.example/a.png" 1x, "https://t.example/b.png" 2x)"#,
+        r#"background-image: -webkit-image-set('//t.example/a.png' 1x)"#,
+        r#"background: IMAGE-SET("http://t.example/a.png" type("image/png")) red"#,
+    ] {
+        let sanitized = sanitize(&format!(r#"<td style="{}">x</td>"#, style.replace('"', "&quot;")), &Policy::default());
+        assert!(!sanitized.html.contains("t.example"), "{} became {}", style, sanitized.html);
+        assert!(sanitized.blocked_remote);
+    }
+    assert_eq!(
+        clean(r#"<p style="background-image: image-set(&quot;https://t.example/a.png&quot; 1x)">x</p>"#),
+        r#"<p style="background-image: image-set(none 1x)">x</p>"#
+    );
+    // Font names stay as they are
+    assert_eq!(
+        clean(r#"<p style="font-family: 'Helvetica Neue', &quot;Segoe UI&quot;, sans-serif">x</p>"#),
+        r#"<p style="font-family: &#39;Helvetica Neue&#39;, &quot;Segoe UI&quot;, sans-serif">x</p>"#
+    );
+    // An unterminated string hides nothing
+    assert_eq!(clean(r#"<p style="background-image: image-set('https://t.example/a.png 1x)">x</p>"#), "<p>x</p>");
+
+    let policy = Policy {
+        remote_images: true,
+        ..Policy::default()
+    };
+    let allowed = sanitize(r#"<p style="background-image: image-set('https://t.example/a.png' 1x)">x</p>"#, &policy);
+    assert_eq!(
+        allowed.html,
+        r#"<p style="background-image: image-set(&quot;https://t.example/a.png&quot; 1x)">x</p>"#
+    );
+}
+
+#[test]
+fn remote_images_are_blocked_and_cid_parts_resolved() {
+    let policy = Policy {
+        inline_images: HashMap::from([("logo@x".to_string(), "/email/e1/part/2".to_string())]),
+        ..Policy::default()
+    };
+    let sanitized = sanitize(r#"<img src="https://t.example/open.gif"><img src="cid:logo@x">"#, &policy);
+    assert!(sanitized.blocked_remote);
+    assert_eq!(
+        sanitized.html,
+        format!(r#"<img src="{}" class="remote-blocked"><img src="/email/e1/part/2">"#, BLOCKED_IMAGE)
+    );
+}
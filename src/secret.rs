@@ -0,0 +1,117 @@
+//! Credentials kept in memory only in encrypted form.
+//!
+//! A session's password or token is sealed with a key generated when the
+//! process starts and never written anywhere, so a heap dump or a stray
+//! debug print of the session store shows no usable credential. The plain
+//! text exists only briefly, in a [`Zeroizing`] buffer that is wiped when
+//! the request using it is done.
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::OnceLock;
+use zeroize::{Zeroize, Zeroizing};
+
+/// A ChaCha20-Poly1305 key
+pub struct Key(LessSafeKey);
+
+impl Key {
+    fn random() -> Self {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        SystemRandom::new()
+            .fill(&mut bytes[..])
+            .expect("system random number generator failed");
+        Self::from_bytes(&bytes[..]).expect("key has the cipher's length")
+    }
+
+    /// A key given in config as 32 bytes of base64
+    pub fn from_base64(text: &str) -> Result<Self, String> {
+        let bytes = Zeroizing::new(STANDARD.decode(text.trim()).map_err(|e| format!("key is not base64: {}", e))?);
+        Self::from_bytes(&bytes).ok_or_else(|| format!("key must be 32 bytes, not {}", bytes.len()))
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        UnboundKey::new(&CHACHA20_POLY1305, bytes).ok().map(|key| Key(LessSafeKey::new(key)))
+    }
+
+    /// `plain` encrypted under a fresh nonce, which goes in front
+    pub fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system random number generator failed");
+        let mut sealed = plain.to_vec();
+        self.0
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed)
+            .expect("sealing in memory cannot fail");
+        [&nonce[..], &sealed].concat()
+    }
+
+    /// What [`seal`](Self::seal) was given, or `None` if `sealed` was made
+    /// with another key or has been altered
+    pub fn open(&self, sealed: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut buffer = Zeroizing::new(sealed.to_vec());
+        let len = self.0.open_in_place(nonce, Aad::empty(), &mut buffer).ok()?.len();
+        buffer.truncate(len);
+        Some(buffer)
+    }
+}
+
+fn process_key() -> &'static Key {
+    static KEY: OnceLock<Key> = OnceLock::new();
+    KEY.get_or_init(Key::random)
+}
+
+/// A credential sealed with the process key
+pub struct Secret {
+    sealed: Vec<u8>,
+}
+
+impl Secret {
+    /// Seal `plain`, wiping the original
+    pub fn new(mut plain: String) -> Self {
+        let sealed = process_key().seal(plain.as_bytes());
+        plain.zeroize();
+        Secret { sealed }
+    }
+
+    /// The plain text, wiped again when the returned value is dropped
+    pub fn reveal(&self) -> Zeroizing<String> {
+        let plain = process_key()
+            .open(&self.sealed)
+            .expect("secret was sealed with this process's key");
+        Zeroizing::new(String::from_utf8(plain.to_vec()).expect("secret was sealed from a String"))
+    }
+}
+
+/// 32 random bytes, URL-safe, for tokens that mustn't be guessable
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Compare a token someone sent with the real one, taking as long whether
+/// or not they share a prefix
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.sealed.zeroize();
+    }
+}
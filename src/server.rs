@@ -0,0 +1,204 @@
+//! The program `main.rs` runs: command line, config file, listener and the
+//! loop handing each request to the handlers.
+
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::{self, Config};
+use crate::handlers::{self, AppState};
+use crate::{access_log, cli, demo, jmap, log, prefs, relay, reload, session, tls};
+#[cfg(feature = "sqlite")]
+use crate::mail_store;
+use crate::{log_error, log_info, log_warn};
+
+/// The webmail as a program: read the command line and config, then
+/// listen and serve until killed
+pub fn run() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match cli::parse_args(&args) {
+        Ok(Some(options)) => options,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    log_info!("Starting rust-jmap-webmail server");
+
+    let mut config = match Config::load(&options.config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            log_error!("Error loading config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = options.apply(&mut config) {
+        log_error!("{}", e);
+        std::process::exit(2);
+    }
+    log::init(&config.log);
+    log_info!("Configuration loaded from {}", options.config_path);
+
+    if let Some(send_test_args) = &options.send_test {
+        if let Err(e) = cli::send_test(&config, send_test_args) {
+            log_error!("send-test failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(check_args) = &options.check {
+        if let Err(e) = cli::check(&config, check_args) {
+            log_error!("check failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if options.demo {
+        match demo::start() {
+            Ok(url) => {
+                log_info!("Demo mode: using built-in JMAP backend, any username/password works");
+                config.jmap.servers = vec![config::JmapServer {
+                    name: "Demo".to_string(),
+                    well_known_url: url,
+                }];
+            }
+            Err(e) => {
+                log_error!("Failed to start demo backend: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let listen_addr = config.listen_address();
+    for server in &config.jmap.servers {
+        log_info!("JMAP server {}: {}", server.name, server.well_known_url);
+    }
+
+    // On TCP, tiny_http is only reached through the relay in front, which
+    // times clients out and does any TLS
+    let tls_config = match &config.server.tls {
+        Some(tls) => match tls::server_config(tls) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                log_error!("Failed to load TLS certificate: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let server = match &config.server.listen_socket {
+        Some(path) => {
+            if tls_config.is_some() {
+                log_error!("[server.tls] can't be used with listen_socket; let the proxy in front do TLS");
+                std::process::exit(1);
+            }
+            log_info!("Binding to unix:{}", path);
+            bind_unix_socket(path, config.server.socket_mode)
+        }
+        None => tiny_http::Server::http("127.0.0.1:0").map_err(|e| e.to_string()),
+    };
+    let server = match server {
+        Ok(s) => {
+            log_info!("HTTP server started successfully");
+            s
+        }
+        Err(e) => {
+            log_error!("Failed to start server: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let prefs = match prefs::PrefsStore::open(&config.prefs) {
+        Ok(p) => p,
+        Err(e) => {
+            log_error!("Failed to load preferences: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let cache_store = match open_cache_store(config.cache.path.as_deref()) {
+        Ok(store) => store,
+        Err(e) => {
+            log_error!("Failed to open the mail cache: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let sessions = match session::SessionStore::open(&config.sessions, &config.jmap, &config.users, cache_store.clone()) {
+        Ok(s) => s,
+        Err(e) => {
+            log_error!("Failed to load sessions: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let access_log = match config.log.access.as_ref().map(access_log::AccessLog::open).transpose() {
+        Ok(a) => a,
+        Err(e) => {
+            log_error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let state = Arc::new(AppState::new(config, prefs, sessions, access_log, cache_store));
+    if let Some(backend) = server.server_addr().to_ip() {
+        let scheme = if tls_config.is_some() { "https" } else { "http" };
+        log_info!("Binding to {}://{}", scheme, listen_addr);
+        if let Err(e) = relay::spawn(&listen_addr, backend, tls_config, Arc::clone(&state)) {
+            log_error!("Failed to listen on {}: {}", listen_addr, e);
+            std::process::exit(1);
+        }
+    }
+    crate::spawn_tasks(&state);
+    reload::spawn(Arc::clone(&state), options);
+    log_info!("Server ready, waiting for requests...");
+
+    // Anything on this machine can reach the relay's loopback port, but
+    // only what came through the relay was timed out, decrypted, and has
+    // a client to say who it is for
+    let relayed = server.server_addr().to_ip().is_some();
+    for request in server.incoming_requests() {
+        let from_relay = request.remote_addr().and_then(|addr| state.relayed_peers.client(*addr)).is_some();
+        if relayed && !from_relay {
+            log_warn!("Refusing a request from {:?} that didn't come through the relay", request.remote_addr());
+            let _ = request.respond(tiny_http::Response::empty(403));
+            continue;
+        }
+        let state = Arc::clone(&state);
+        handlers::handle_request(&state, request);
+    }
+}
+
+/// The on-disk store for the sessions' mail caches, with `[cache] path` set
+fn open_cache_store(path: Option<&str>) -> Result<Option<Arc<dyn jmap::CacheStore>>, String> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    #[cfg(feature = "sqlite")]
+    {
+        let store = mail_store::SqliteStore::open(Path::new(path))?;
+        log_info!("Keeping the mail cache in {}", path);
+        Ok(Some(Arc::new(store)))
+    }
+    #[cfg(not(feature = "sqlite"))]
+    Err(format!("[cache] path is {}, but this build has no SQLite; rebuild with --features sqlite", path))
+}
+
+/// Listen on a Unix socket at `path`, replacing one left behind by an
+/// earlier run
+fn bind_unix_socket(path: &str, mode: Option<u32>) -> Result<tiny_http::Server, String> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path).map_err(|e| format!("failed to remove old socket {}: {}", path, e))?;
+    }
+    let server = tiny_http::Server::http_unix(Path::new(path)).map_err(|e| e.to_string())?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("failed to set permissions on {}: {}", path, e))?;
+    }
+    Ok(server)
+}
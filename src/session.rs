@@ -1,29 +1,222 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::contacts::AddressBook;
+use crate::config::{CookieConfig, JmapConfig, SameSite, ServerConfig, SessionConfig, UserLimitsConfig};
+use crate::handlers::AppState;
+use crate::jmap::{
+    AuthScheme, CacheStore, JmapClient, Limits, MailAccount, MailCache, SessionWatch, Transport, WebSocketEndpoint,
+};
+use crate::oauth::OAuthGrant;
+use crate::retention::PurgeRecord;
+use crate::secret::{random_token, Key, Secret};
+use crate::user_limits::{Allowance, Allowances};
+use crate::{log_error, log_info, log_warn};
+
+/// How often sessions that have expired are cleared out
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
 
 pub struct Session {
     pub username: String,
-    pub password: String,
+    /// The password, or an API token or OAuth access token when
+    /// `auth_scheme` is Bearer, sealed so it isn't kept in the clear
+    pub credential: Secret,
+    pub auth_scheme: AuthScheme,
+    /// Set when the session signed in through an OAuth provider
+    pub oauth: Option<OAuthGrant>,
+    /// The server picked at login, for discovering its session again
+    pub well_known_url: String,
     pub api_url: String,
+    /// The account shown where the URL doesn't name one, one of `accounts`
     pub account_id: String,
+    pub accounts: Vec<MailAccount>,
     pub download_url: Option<String>,
+    pub upload_url: Option<String>,
+    pub event_source_url: Option<String>,
+    /// Left out when `[jmap] websocket` is off
+    pub websocket: Option<WebSocketEndpoint>,
+    pub retention_enabled: bool,
+    pub purge_log: Vec<PurgeRecord>,
+    /// The list page last shown, so refreshing it can ask for just what
+    /// changed since
+    pub shown_list: Option<ShownList>,
+    /// Each account's Mailbox state as of its last sidebar render
+    pub mailbox_state: HashMap<String, String>,
+    /// Correspondents to suggest as recipients, by account
+    pub contacts: HashMap<String, AddressBook>,
+    /// Shared by every client made for the session, so requests reuse its
+    /// connections to the server
+    pub transport: Transport,
+    /// Mailboxes and list rows, for the session's clients to use again
+    pub cache: MailCache,
+    /// What the server accepts in one request
+    pub limits: Limits,
+    /// What the user may take of the instance, shared with their other
+    /// sessions; `transport` and `cache` already draw on it
+    pub allowance: Arc<Allowance>,
+    /// Raised when the server's session object needs fetching again
+    pub watch: SessionWatch,
+    pub created: Instant,
+    /// Sent back with every POST, to prove it came from one of our pages
+    pub csrf_token: String,
+    /// Asked for "Remember me" at login
+    pub remember: bool,
+    /// The last request the user made, as opposed to one the page made by
+    /// itself to stay up to date
+    pub last_active: Instant,
+    /// What the session is used from, for the user's list of their sessions
+    pub device: Device,
+}
+
+/// The browser or API client behind a session
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Device {
+    /// As it gave it at sign-in
+    pub user_agent: Option<String>,
+    /// Of the last request the user made, looking past trusted proxies
+    pub address: Option<IpAddr>,
+    /// Signed in through the JSON API, so it holds a token rather than a
+    /// cookie
+    pub api: bool,
+}
+
+/// A first list page as rendered: its account and URL, the ids on it in
+/// order, and the Email state they were fetched at
+pub struct ShownList {
+    pub account_id: String,
+    pub url: String,
+    pub ids: Vec<String>,
+    pub email_state: String,
+}
+
+impl Session {
+    /// A client for the account shown by default
+    pub fn client(&self) -> JmapClient {
+        self.client_of(&self.account_id)
+    }
+
+    /// A client for `account_id`, if it is one of the session's accounts
+    pub fn client_for(&self, account_id: &str) -> Option<JmapClient> {
+        self.accounts.iter().any(|a| a.id == account_id).then(|| self.client_of(account_id))
+    }
+
+    fn client_of(&self, account_id: &str) -> JmapClient {
+        JmapClient::from_session(
+            self.username.clone(),
+            self.credential.reveal(),
+            self.api_url.clone(),
+            account_id.to_string(),
+            self.download_url.clone(),
+            self.upload_url.clone(),
+            self.transport.clone(),
+        )
+        .with_auth_scheme(self.auth_scheme)
+        .with_discovery(self.well_known_url.clone())
+        .with_limits(self.limits)
+        .with_watch(self.watch.clone())
+        .with_smime_verify(self.accounts.iter().any(|a| a.id == account_id && a.has_smime_verify))
+        .with_websocket(self.websocket.as_ref().map(|w| w.url.clone()))
+        .with_cache(self.cache.clone())
+    }
+
+    /// Whether the server can push changes, over an EventSource or the
+    /// WebSocket
+    pub fn has_push(&self) -> bool {
+        self.event_source_url.is_some() || self.websocket.as_ref().is_some_and(|w| w.supports_push)
+    }
+}
+
+/// A session's cache, kept in `store` as well when there is one, within
+/// the user's allowance
+pub fn mail_cache(
+    jmap: &JmapConfig,
+    store: &Option<Arc<dyn CacheStore>>,
+    well_known_url: &str,
+    username: &str,
+    allowance: &Allowance,
+) -> MailCache {
+    // The same username on another server is someone else
+    let owner = format!("{} {}", well_known_url, username);
+    MailCache::new(Duration::from_secs(jmap.cache_seconds))
+        .with_store(store.clone(), owner)
+        .with_budget(allowance.cache.clone())
 }
 
 pub struct SessionStore {
     sessions: RwLock<HashMap<Uuid, Session>>,
+    idle_timeout: Duration,
+    max_age: Duration,
+    remember_age: Option<Duration>,
+    file: Option<SessionFile>,
+    allowances: Allowances,
 }
 
 impl SessionStore {
-    pub fn new() -> Self {
-        SessionStore {
+    /// An empty store, or with `[sessions] path` set, the sessions saved
+    /// there that haven't expired since. `users` caps what each user's
+    /// sessions take between them.
+    pub fn open(
+        config: &SessionConfig,
+        jmap: &JmapConfig,
+        users: &UserLimitsConfig,
+        cache_store: Option<Arc<dyn CacheStore>>,
+    ) -> Result<Self, String> {
+        let file = match (&config.path, &config.key) {
+            (None, _) => None,
+            (Some(_), None) => return Err("sessions.path is set but sessions.key is not".to_string()),
+            (Some(path), Some(key)) => Some(SessionFile {
+                path: PathBuf::from(path),
+                key: Key::from_base64(key).map_err(|e| format!("invalid sessions.key: {}", e))?,
+                writing: Mutex::new(()),
+            }),
+        };
+        let store = SessionStore {
             sessions: RwLock::new(HashMap::new()),
+            idle_timeout: config.idle_timeout(),
+            max_age: config.max_age(),
+            remember_age: config.remember_age(),
+            file,
+            allowances: Allowances::new(users.clone()),
+        };
+
+        if let Some(file) = &store.file {
+            let mut sessions = store.sessions.write().unwrap();
+            for (id, stored) in file.load()? {
+                let allowance = store.allowances.of(&stored.username);
+                let session = stored.restore(jmap, &cache_store, allowance);
+                if !store.is_expired(&session) {
+                    sessions.insert(id, session);
+                }
+            }
+            log_info!("Restored {} sessions from {}", sessions.len(), file.path.display());
+        }
+        Ok(store)
+    }
+
+    fn is_expired(&self, session: &Session) -> bool {
+        match self.remember_age {
+            Some(remember_age) if session.remember => session.created.elapsed() >= remember_age,
+            _ => session.last_active.elapsed() >= self.idle_timeout || session.created.elapsed() >= self.max_age,
         }
     }
 
+    /// What `username`'s sessions share of the instance, for a new one
+    pub fn allowance(&self, username: &str) -> Arc<Allowance> {
+        self.allowances.of(username)
+    }
+
     pub fn create(&self, session: Session) -> Uuid {
         let id = Uuid::now_v7();
         self.sessions.write().unwrap().insert(id, session);
+        self.save();
         id
     }
 
@@ -34,32 +227,370 @@ impl SessionStore {
         self.sessions.read().unwrap().get(id).map(f)
     }
 
+    pub fn update<F>(&self, id: &Uuid, f: F) -> bool
+    where
+        F: FnOnce(&mut Session),
+    {
+        match self.sessions.write().unwrap().get_mut(id) {
+            Some(session) => {
+                f(session);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The unexpired sessions of the user `id` is signed in as, on the same
+    /// server and `id` among them, oldest first
+    pub fn of_same_user<F, R>(&self, id: &Uuid, f: F) -> Vec<(Uuid, R)>
+    where
+        F: Fn(&Session) -> R,
+    {
+        let sessions = self.sessions.read().unwrap();
+        let Some(own) = sessions.get(id) else {
+            return Vec::new();
+        };
+        let mut found: Vec<(Uuid, R)> = sessions
+            .iter()
+            .filter(|(_, session)| same_user(own, session) && !self.is_expired(session))
+            .map(|(other, session)| (*other, f(session)))
+            .collect();
+        // The ids are v7, so they sort by when they were made
+        found.sort_by_key(|(other, _)| *other);
+        found
+    }
+
+    /// End `other` if it is another of the sessions of the user `id` is
+    /// signed in as, returning whether it was
+    pub fn revoke(&self, id: &Uuid, other: &Uuid) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let revoked = id != other
+            && match (sessions.get(id), sessions.get(other)) {
+                (Some(own), Some(session)) => same_user(own, session),
+                _ => false,
+            };
+        if revoked {
+            sessions.remove(other);
+            drop(sessions);
+            self.save();
+        }
+        revoked
+    }
+
+    /// End every session of the user `id` is signed in as but `id` itself,
+    /// returning how many there were
+    pub fn revoke_others(&self, id: &Uuid) -> usize {
+        let mut sessions = self.sessions.write().unwrap();
+        let Some(own) = sessions.get(id) else {
+            return 0;
+        };
+        let others: Vec<Uuid> = sessions
+            .iter()
+            .filter(|(other, session)| *other != id && same_user(own, session))
+            .map(|(other, _)| *other)
+            .collect();
+        for other in &others {
+            sessions.remove(other);
+        }
+        drop(sessions);
+        if !others.is_empty() {
+            self.save();
+        }
+        others.len()
+    }
+
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.sessions.read().unwrap().keys().copied().collect()
+    }
+
     pub fn remove(&self, id: &Uuid) -> Option<Session> {
-        self.sessions.write().unwrap().remove(id)
+        let removed = self.sessions.write().unwrap().remove(id);
+        if removed.is_some() {
+            self.save();
+        }
+        removed
+    }
+
+    /// Whether the session exists and hasn't expired, ending it if it has.
+    /// `active` counts the request, which came `from` there, towards the
+    /// idle timeout.
+    pub fn check(&self, id: &Uuid, active: bool, from: Option<IpAddr>) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let Some(session) = sessions.get(id) else {
+            return false;
+        };
+        if self.is_expired(session) {
+            log_info!("Session {} for {} expired", id, session.username);
+            sessions.remove(id);
+            drop(sessions);
+            self.save();
+            return false;
+        }
+        if active {
+            let session = sessions.get_mut(id).unwrap();
+            session.last_active = Instant::now();
+            if from.is_some() {
+                session.device.address = from;
+            }
+        }
+        true
+    }
+
+    /// End every session that has expired, returning how many there were.
+    /// The file is rewritten either way, which also saves changes made to
+    /// sessions in place, such as a refreshed token.
+    pub fn remove_expired(&self) -> usize {
+        let mut sessions = self.sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| !self.is_expired(session));
+        let removed = before - sessions.len();
+        drop(sessions);
+        self.save();
+        removed
+    }
+
+    /// Write every session to the file, if there is one. A failure is only
+    /// logged, since the sessions in memory carry on regardless.
+    fn save(&self) {
+        let Some(file) = &self.file else { return };
+        // Held from the snapshot to the rename, so an older snapshot can't
+        // land on top of a newer one
+        let _writing = file.writing.lock().unwrap();
+        let stored: HashMap<Uuid, StoredSession> = self
+            .sessions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, session)| (*id, StoredSession::from(session)))
+            .collect();
+        if let Err(e) = file.save(&stored) {
+            log_error!("Failed to save sessions: {}", e);
+        }
+    }
+}
+
+/// The same username on another server is someone else
+fn same_user(a: &Session, b: &Session) -> bool {
+    a.username == b.username && a.well_known_url == b.well_known_url
+}
+
+/// Sessions on disk, sealed with the configured key
+struct SessionFile {
+    path: PathBuf,
+    key: Key,
+    writing: Mutex<()>,
+}
+
+impl SessionFile {
+    /// The saved sessions. A file that can't be opened with the key, such as
+    /// one from before the key was changed, is ignored and everyone has to
+    /// log in again.
+    fn load(&self) -> Result<HashMap<Uuid, StoredSession>, String> {
+        let sealed = match fs::read(&self.path) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(format!("failed to read {}: {}", self.path.display(), e)),
+        };
+        let Some(json) = self.key.open(&sealed) else {
+            log_warn!("Ignoring {}: it was not written with the current sessions.key", self.path.display());
+            return Ok(HashMap::new());
+        };
+        serde_json::from_slice(&json).map_err(|e| format!("failed to parse {}: {}", self.path.display(), e))
+    }
+
+    fn save(&self, sessions: &HashMap<Uuid, StoredSession>) -> Result<(), String> {
+        let json = Zeroizing::new(serde_json::to_vec(sessions).map_err(|e| e.to_string())?);
+        // Write then rename, so a crash mid-write can't truncate the file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, self.key.seal(&json))
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| format!("failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+/// What's kept of a session across a restart. The rest is either cached
+/// state that is fetched again or, like the purge log, not worth keeping.
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    username: String,
+    credential: Zeroizing<String>,
+    auth_scheme: AuthScheme,
+    oauth: Option<StoredGrant>,
+    /// Missing from sessions saved when only one server could be configured
+    #[serde(default)]
+    well_known_url: Option<String>,
+    api_url: String,
+    account_id: String,
+    accounts: Vec<MailAccount>,
+    download_url: Option<String>,
+    upload_url: Option<String>,
+    event_source_url: Option<String>,
+    retention_enabled: bool,
+    /// Unix times, since an Instant doesn't outlive the process
+    created: u64,
+    #[serde(default)]
+    remember: bool,
+    #[serde(default = "random_token")]
+    csrf_token: String,
+    last_active: u64,
+    /// Missing from sessions saved before they were listed for the user
+    #[serde(default)]
+    device: Device,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredGrant {
+    provider: String,
+    refresh_token: Option<Zeroizing<String>>,
+    expires_at: Option<u64>,
+}
+
+impl From<&Session> for StoredSession {
+    fn from(s: &Session) -> Self {
+        StoredSession {
+            username: s.username.clone(),
+            credential: s.credential.reveal(),
+            auth_scheme: s.auth_scheme,
+            oauth: s.oauth.as_ref().map(|grant| StoredGrant {
+                provider: grant.provider.clone(),
+                refresh_token: grant.refresh_token.as_ref().map(Secret::reveal),
+                expires_at: grant.expires_at.map(to_unix),
+            }),
+            well_known_url: Some(s.well_known_url.clone()),
+            api_url: s.api_url.clone(),
+            account_id: s.account_id.clone(),
+            accounts: s.accounts.clone(),
+            download_url: s.download_url.clone(),
+            upload_url: s.upload_url.clone(),
+            event_source_url: s.event_source_url.clone(),
+            retention_enabled: s.retention_enabled,
+            created: to_unix(s.created),
+            remember: s.remember,
+            csrf_token: s.csrf_token.clone(),
+            last_active: to_unix(s.last_active),
+            device: s.device.clone(),
+        }
     }
+}
 
-    pub fn exists(&self, id: &Uuid) -> bool {
-        self.sessions.read().unwrap().contains_key(id)
+impl StoredSession {
+    /// The session as it was, checking again with the server on first use
+    /// in case its session object changed while we were down
+    fn restore(mut self, jmap: &JmapConfig, cache_store: &Option<Arc<dyn CacheStore>>, allowance: Arc<Allowance>) -> Session {
+        let well_known_url = self
+            .well_known_url
+            .or_else(|| jmap.default_server().map(|s| s.well_known_url.clone()))
+            .unwrap_or_default();
+        Session {
+            credential: Secret::new(std::mem::take(&mut *self.credential)),
+            oauth: self.oauth.map(|mut grant| OAuthGrant {
+                provider: grant.provider,
+                refresh_token: grant.refresh_token.as_mut().map(|token| Secret::new(std::mem::take(&mut **token))),
+                expires_at: grant.expires_at.map(from_unix),
+            }),
+            cache: mail_cache(jmap, cache_store, &well_known_url, &self.username, &allowance),
+            username: self.username,
+            auth_scheme: self.auth_scheme,
+            well_known_url,
+            api_url: self.api_url,
+            account_id: self.account_id,
+            accounts: self.accounts,
+            download_url: self.download_url,
+            upload_url: self.upload_url,
+            event_source_url: self.event_source_url,
+            // The stale watch fetches the session object, and this, again
+            websocket: None,
+            retention_enabled: self.retention_enabled,
+            purge_log: Vec::new(),
+            shown_list: None,
+            mailbox_state: HashMap::new(),
+            contacts: HashMap::new(),
+            transport: Transport::new(jmap.http_options()).with_call_limit(allowance.calls.clone()),
+            limits: Limits::default(),
+            allowance,
+            watch: SessionWatch::stale(),
+            created: from_unix(self.created),
+            remember: self.remember,
+            csrf_token: self.csrf_token,
+            last_active: from_unix(self.last_active),
+            device: self.device,
+        }
     }
 }
 
-pub fn parse_session_cookie(cookie_header: &str) -> Option<Uuid> {
+/// The time `instant` was or will be, in seconds since the epoch
+pub fn to_unix(instant: Instant) -> u64 {
+    let now = SystemTime::now();
+    let time = if instant <= Instant::now() {
+        now - instant.elapsed()
+    } else {
+        now + (instant - Instant::now())
+    };
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn from_unix(secs: u64) -> Instant {
+    let time = UNIX_EPOCH + Duration::from_secs(secs);
+    match time.duration_since(SystemTime::now()) {
+        Ok(ahead) => Instant::now() + ahead,
+        // Shortly after boot the monotonic clock can't go back that far
+        Err(behind) => Instant::now().checked_sub(behind.duration()).unwrap_or_else(Instant::now),
+    }
+}
+
+pub fn parse_session_cookie(config: &CookieConfig, cookie_header: &str) -> Option<Uuid> {
     for cookie in cookie_header.split(';') {
-        let cookie = cookie.trim();
-        if let Some(value) = cookie.strip_prefix("session=") {
+        let Some((name, value)) = cookie.trim().split_once('=') else {
+            continue;
+        };
+        if name == config.name {
             return Uuid::parse_str(value).ok();
         }
     }
     None
 }
 
-pub fn make_session_cookie(id: &Uuid) -> String {
-    format!(
-        "session={}; HttpOnly; SameSite=Strict; Path=/",
-        id
-    )
+/// The session cookie, kept by the browser for `max_age` if given and
+/// otherwise only until it closes. `https` says the browser reached us
+/// over HTTPS, which makes the cookie Secure whatever the config says.
+pub fn make_session_cookie(server: &ServerConfig, id: &Uuid, max_age: Option<Duration>, https: bool) -> String {
+    let expiry = max_age
+        .map(|age| format!("; Max-Age={}", age.as_secs()))
+        .unwrap_or_default();
+    format!("{}={}{}{}", server.cookie.name, id, cookie_attributes(server, https), expiry)
+}
+
+pub fn clear_session_cookie(server: &ServerConfig, https: bool) -> String {
+    format!("{}={}; Max-Age=0", server.cookie.name, cookie_attributes(server, https))
 }
 
-pub fn clear_session_cookie() -> String {
-    "session=; HttpOnly; SameSite=Strict; Path=/; Max-Age=0".to_string()
+pub fn cookie_attributes(server: &ServerConfig, https: bool) -> String {
+    let config = &server.cookie;
+    let same_site = match config.same_site {
+        SameSite::Strict => "Strict",
+        SameSite::Lax => "Lax",
+        SameSite::None => "None",
+    };
+    // Scoped to the mount point, so other apps on the host don't get it
+    let mut attributes = format!("; HttpOnly; SameSite={}; Path={}/", same_site, server.base_path);
+    if config.secure || https || matches!(config.same_site, SameSite::None) {
+        attributes.push_str("; Secure");
+    }
+    if let Some(domain) = &config.domain {
+        attributes.push_str("; Domain=");
+        attributes.push_str(domain);
+    }
+    attributes
+}
+
+/// Start the thread that clears out expired sessions, so ones abandoned
+/// without logging out don't pile up
+pub fn spawn_cleanup(state: Arc<AppState>) {
+    thread::spawn(move || loop {
+        thread::sleep(CLEANUP_INTERVAL);
+        let removed = state.sessions.remove_expired();
+        if removed > 0 {
+            log_info!("Removed {} expired sessions", removed);
+        }
+    });
 }
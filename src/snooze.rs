@@ -0,0 +1,147 @@
+//! Snoozing messages until later.
+//!
+//! A snoozed message is filed in the Snoozed mailbox (role `snoozed`, or
+//! one named "Snoozed", made on first use) with a `$snoozed-<time>`
+//! keyword saying when to bring it back, so the server keeps the snooze
+//! across restarts. A background thread wakes up every minute, and for
+//! each session moves the messages that are due back to the Inbox, unread.
+
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::dates;
+use crate::handlers::AppState;
+use crate::jmap::{Email, JmapClient, JmapError, Mailbox};
+use crate::{log_error, log_info};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Snoozed messages looked at per session per check
+const BATCH_SIZE: u32 = 256;
+const KEYWORD_PREFIX: &str = "$snoozed-";
+const MAILBOX_NAME: &str = "Snoozed";
+/// When "tomorrow" and "next week" start, in seconds into the day
+const MORNING: i64 = 8 * 3600;
+
+/// Start the background thread that brings snoozed messages back
+pub fn spawn(state: Arc<AppState>) {
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        for id in state.sessions.ids() {
+            let Some(client) = state.sessions.get(&id, |s| s.client()) else { continue };
+            if let Err(e) = wake_due(&client) {
+                log_error!("[SNOOZE] Check failed for session {}: {}", id, e);
+            }
+        }
+    });
+}
+
+/// When a message is snoozed until, in seconds since the epoch
+pub fn until(email: &Email) -> Option<i64> {
+    email
+        .keywords
+        .iter()
+        .filter(|(_, set)| **set)
+        .filter_map(|(keyword, _)| keyword.strip_prefix(KEYWORD_PREFIX)?.parse().ok())
+        .min()
+}
+
+/// The time a choice from the snooze menu means: `hour`, `tomorrow`
+/// morning, `week` (next Monday morning), or `custom` with `at` as a
+/// datetime-local value (`2026-10-20T09:30`). Days and times are the
+/// current request's zone's.
+pub fn resolve(choice: &str, at: Option<&str>) -> Option<i64> {
+    match choice {
+        "hour" => Some(dates::now() + 3600),
+        "tomorrow" => Some(dates::from_local(None, dates::today() + 1, MORNING)),
+        "week" => {
+            let today = dates::today();
+            let ahead = match (8 - dates::weekday_of(today)) % 7 {
+                0 => 7,
+                n => n,
+            };
+            Some(dates::from_local(None, today + ahead, MORNING))
+        }
+        "custom" => {
+            let at = at?.trim();
+            let field = |range: std::ops::Range<usize>| at.get(range)?.parse::<i64>().ok();
+            let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+            let (hour, minute) = (field(11..13)?, field(14..16)?);
+            if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+                return None;
+            }
+            let time = dates::from_local(None, dates::days_from_civil(year, month, day), hour * 3600 + minute * 60);
+            (time > dates::now()).then_some(time)
+        }
+        _ => None,
+    }
+}
+
+fn snoozed_mailbox(mailboxes: &[Mailbox]) -> Option<&Mailbox> {
+    mailboxes.iter().find(|m| m.role.as_deref() == Some("snoozed")).or_else(|| {
+        mailboxes
+            .iter()
+            .find(|m| m.parent_id.is_none() && m.name.eq_ignore_ascii_case(MAILBOX_NAME))
+    })
+}
+
+/// Snooze `email` until `time`, replacing any earlier snooze, in one
+/// Email/set
+pub fn snooze(client: &JmapClient, email: &Email, mailboxes: &[Mailbox], time: i64) -> Result<(), JmapError> {
+    let mailbox_id = match snoozed_mailbox(mailboxes) {
+        Some(mailbox) => mailbox.id.clone(),
+        None => client.create_mailbox(MAILBOX_NAME, None)?,
+    };
+    let mut patch = Map::new();
+    patch.insert("mailboxIds".to_string(), json!({ mailbox_id: true }));
+    for keyword in email.keywords.keys().filter(|k| k.starts_with(KEYWORD_PREFIX)) {
+        patch.insert(format!("keywords/{}", keyword), Value::Null);
+    }
+    patch.insert(format!("keywords/{}{}", KEYWORD_PREFIX, time), json!(true));
+
+    let mut update = Map::new();
+    update.insert(email.id.clone(), Value::Object(patch));
+    client.set_emails(update, &[])?;
+    log_info!("[SNOOZE] Snoozed {} until {}", email.id, crate::log::utc_date(time as u64));
+    Ok(())
+}
+
+/// Move the snoozed messages whose time has come back to the Inbox,
+/// unread. Returns how many were woken.
+pub fn wake_due(client: &JmapClient) -> Result<usize, JmapError> {
+    let mailboxes = client.get_mailboxes()?;
+    let (Some(snoozed), Some(inbox)) = (
+        snoozed_mailbox(&mailboxes),
+        mailboxes.iter().find(|m| m.role.as_deref() == Some("inbox")),
+    ) else {
+        return Ok(0);
+    };
+    if snoozed.total_emails == 0 {
+        return Ok(0);
+    }
+
+    let ids = client
+        .query_emails_filtered(json!({ "inMailbox": snoozed.id }), BATCH_SIZE, 0)?
+        .ids;
+    let now = dates::now();
+    let mut update = Map::new();
+    for email in client.get_emails(&ids)? {
+        // A message filed here by hand, with no time, stays until moved
+        if until(&email).is_none_or(|time| time > now) {
+            continue;
+        }
+        let mut patch = Map::new();
+        patch.insert("mailboxIds".to_string(), json!({ inbox.id.clone(): true }));
+        patch.insert("keywords/$seen".to_string(), Value::Null);
+        for keyword in email.keywords.keys().filter(|k| k.starts_with(KEYWORD_PREFIX)) {
+            patch.insert(format!("keywords/{}", keyword), Value::Null);
+        }
+        update.insert(email.id.clone(), Value::Object(patch));
+    }
+    let woken = client.set_emails(update, &[])?;
+    if woken > 0 {
+        log_info!("[SNOOZE] Brought {} messages back to {}", woken, inbox.name);
+    }
+    Ok(woken)
+}
@@ -0,0 +1,170 @@
+//! Just enough of SQLite's C API to run statements with text parameters and
+//! read text columns back, linked against the system's libsqlite3 when
+//! built with the `sqlite` feature.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+#[allow(non_camel_case_types)]
+type sqlite3 = c_void;
+#[allow(non_camel_case_types)]
+type sqlite3_stmt = c_void;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+const SQLITE_OPEN_NOMUTEX: c_int = 0x8000;
+/// Has SQLite copy a bound value before the call returns
+const SQLITE_TRANSIENT: isize = -1;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(filename: *const c_char, db: *mut *mut sqlite3, flags: c_int, vfs: *const c_char) -> c_int;
+    fn sqlite3_close_v2(db: *mut sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+    fn sqlite3_busy_timeout(db: *mut sqlite3, ms: c_int) -> c_int;
+    fn sqlite3_exec(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        callback: *const c_void,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_free(ptr: *mut c_void);
+    fn sqlite3_prepare_v2(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        bytes: c_int,
+        stmt: *mut *mut sqlite3_stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_text(stmt: *mut sqlite3_stmt, index: c_int, text: *const c_char, bytes: c_int, destructor: isize) -> c_int;
+    fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+    fn sqlite3_column_text(stmt: *mut sqlite3_stmt, column: c_int) -> *const u8;
+    fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, column: c_int) -> c_int;
+    fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+}
+
+/// An open database. Not to be used from two threads at once, so it goes
+/// behind a Mutex.
+pub struct Database {
+    db: *mut sqlite3,
+}
+
+// The connection is opened NOMUTEX and only ever used by one thread at a
+// time, which is all SQLite asks of such connections
+unsafe impl Send for Database {}
+
+impl Database {
+    /// Open the database at `path`, creating it if it isn't there
+    pub fn open(path: &Path, busy_timeout: Duration) -> Result<Database, String> {
+        let filename = CString::new(path.to_string_lossy().as_bytes()).map_err(|e| e.to_string())?;
+        let mut db = ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_NOMUTEX;
+        let code = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags, ptr::null()) };
+        // Even a failed open returns a handle, for the error message
+        let database = Database { db };
+        if code != SQLITE_OK {
+            return Err(format!("failed to open {}: {}", path.display(), database.error()));
+        }
+        unsafe { sqlite3_busy_timeout(db, busy_timeout.as_millis() as c_int) };
+        Ok(database)
+    }
+
+    fn error(&self) -> String {
+        if self.db.is_null() {
+            return "out of memory".to_string();
+        }
+        unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }.to_string_lossy().into_owned()
+    }
+
+    /// Run statements that take no parameters, such as the schema
+    pub fn execute_batch(&self, sql: &str) -> Result<(), String> {
+        let sql = CString::new(sql).map_err(|e| e.to_string())?;
+        let mut message = ptr::null_mut();
+        let code = unsafe { sqlite3_exec(self.db, sql.as_ptr(), ptr::null(), ptr::null_mut(), &mut message) };
+        if code == SQLITE_OK {
+            return Ok(());
+        }
+        if message.is_null() {
+            return Err(self.error());
+        }
+        let error = unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned();
+        unsafe { sqlite3_free(message.cast()) };
+        Err(error)
+    }
+
+    /// Run one statement, binding `params` to its `?`s in order
+    pub fn execute(&self, sql: &str, params: &[&str]) -> Result<(), String> {
+        let statement = self.prepare(sql, params)?;
+        match unsafe { sqlite3_step(statement.stmt) } {
+            SQLITE_DONE | SQLITE_ROW => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    /// The first `columns` columns of every row a query returns, as text;
+    /// NULL reads as ""
+    pub fn query(&self, sql: &str, params: &[&str], columns: usize) -> Result<Vec<Vec<String>>, String> {
+        let statement = self.prepare(sql, params)?;
+        let mut rows = Vec::new();
+        loop {
+            match unsafe { sqlite3_step(statement.stmt) } {
+                SQLITE_ROW => rows.push((0..columns as c_int).map(|i| statement.text(i)).collect()),
+                SQLITE_DONE => return Ok(rows),
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn prepare(&self, sql: &str, params: &[&str]) -> Result<Statement, String> {
+        let mut stmt = ptr::null_mut();
+        let code = unsafe {
+            sqlite3_prepare_v2(self.db, sql.as_ptr().cast(), sql.len() as c_int, &mut stmt, ptr::null_mut())
+        };
+        if code != SQLITE_OK {
+            return Err(self.error());
+        }
+        let statement = Statement { stmt };
+        for (i, param) in params.iter().enumerate() {
+            let code = unsafe {
+                sqlite3_bind_text(stmt, i as c_int + 1, param.as_ptr().cast(), param.len() as c_int, SQLITE_TRANSIENT)
+            };
+            if code != SQLITE_OK {
+                return Err(self.error());
+            }
+        }
+        Ok(statement)
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close_v2(self.db) };
+    }
+}
+
+struct Statement {
+    stmt: *mut sqlite3_stmt,
+}
+
+impl Statement {
+    fn text(&self, column: c_int) -> String {
+        let text = unsafe { sqlite3_column_text(self.stmt, column) };
+        if text.is_null() {
+            return String::new();
+        }
+        let length = unsafe { sqlite3_column_bytes(self.stmt, column) } as usize;
+        String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(text, length) }).into_owned()
+    }
+}
+
+impl Drop for Statement {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.stmt) };
+    }
+}
@@ -0,0 +1,35 @@
+//! What the askama views under `templates/` need beyond askama's own
+//! filters: the escaping the rest of the markup uses, and the message
+//! catalogs.
+
+use askama::filters::{Escaper, Safe};
+use askama::Values;
+use std::fmt;
+
+use super::html_escape;
+use crate::i18n;
+
+/// Escapes as [`html_escape`] does, so a view's output is the same whether
+/// it is a template or a `format!`
+#[derive(Clone, Copy)]
+pub struct Html;
+
+impl Escaper for Html {
+    fn write_escaped_str<W: fmt::Write>(&self, mut dest: W, string: &str) -> fmt::Result {
+        dest.write_str(&html_escape(string))
+    }
+}
+
+/// `{{ "page.key"|t }}`, the message in the request's language. Messages
+/// are markup, so it isn't escaped.
+#[askama::filter_fn]
+pub fn t(key: &'static str, _: &dyn Values) -> askama::Result<Safe<&'static str>> {
+    Ok(Safe(i18n::t(key)))
+}
+
+/// `{{ "page.key"|tf("name", value) }}`, the message with `{name}` replaced
+/// by `value`, which is escaped
+#[askama::filter_fn]
+pub fn tf(key: &'static str, _: &dyn Values, name: &str, value: &str) -> askama::Result<Safe<String>> {
+    Ok(Safe(i18n::tf(key, &[(name, &html_escape(value))])))
+}
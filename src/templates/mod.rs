@@ -1,438 +1,2991 @@
-use crate::jmap::{Email, EmailAddress, Mailbox};
+use askama::Template;
+use std::cell::RefCell;
+use std::collections::HashSet;
 
-pub fn base_page(title: &str, body: &str) -> String {
+use crate::assets;
+use crate::bounce::{self, Bounce};
+use crate::calendar::Occurrence;
+use crate::config::{BrandingConfig, RetentionConfig};
+use crate::contacts::{Chip, Problem};
+use crate::dates;
+use crate::i18n::{self, t, tf};
+use crate::ical::{EventTime, Invite, Person};
+use crate::jmap::{
+    BodyPart, Email, EmailAddress, EmailHeader, EmailSubmission, Identity, MailAccount, Mailbox, OutgoingAttachment, Search, Sort, SortProperty,
+    Quota, SieveScript, VacationResponse, ALL_MAIL_ID, FLAGGED_ID, PINNED_KEYWORD,
+};
+use crate::mute;
+use crate::pgp;
+use crate::plugins::{Link, Plugins};
+use crate::pwa;
+use crate::qr::QrCode;
+use crate::prefs::{
+    Density, Posting, Prefs, ReplyPrefs, SavedSearch, DEFAULT_LIST_HEIGHT, DEFAULT_QUOTE_PREFIX, LIST_HEIGHTS,
+    MAX_QUOTE_PREFIX,
+};
+use crate::retention::PurgeRecord;
+use crate::sanitize;
+use crate::session::{Device, Session};
+use crate::snooze;
+use crate::theme;
+
+mod filters;
+#[cfg(test)]
+mod tests;
+
+/// A whole page, titled `title` and the deployment's name or just the name
+pub fn base_page(branding: &BrandingConfig, title: Option<&str>, body: &str) -> String {
+    let base = base_path();
+    let title = match title {
+        Some(title) => format!("{} - {}", title, branding.name),
+        None => branding.name.clone(),
+    };
     format!(
         r#"<!DOCTYPE html>
-<html>
+<html lang="{lang}"{theme}>
 <head>
   <meta charset="utf-8">
   <meta name="viewport" content="width=device-width, initial-scale=1">
+  <meta name="color-scheme" content="light dark">
+  <meta name="htmx-config" content='{{"useTemplateFragments": true, "historyCacheSize": 0, "refreshOnHistoryMiss": true}}'>
   <title>{title}</title>
-  <script src="/static/htmx.min.js"></script>
-  <style>
-    * {{ box-sizing: border-box; }}
-    body {{ margin: 0; font-family: monospace; font-size: 14px; background: #fafafa; }}
-    .container {{ display: flex; height: 100vh; }}
-    .sidebar {{
-      width: 200px;
-      border-right: 1px solid #ccc;
-      background: #f5f5f5;
-      display: flex;
-      flex-direction: column;
-    }}
-    .sidebar-header {{
-      padding: 0.5rem;
-      border-bottom: 1px solid #ccc;
-      background: #f0f0f0;
-      display: flex;
-      justify-content: space-between;
-      align-items: center;
-    }}
-    .sidebar-header .username {{
-      font-size: 12px;
-      color: #333;
-      overflow: hidden;
-      text-overflow: ellipsis;
-      white-space: nowrap;
-    }}
-    .mailbox-list {{
-      flex: 1;
-      overflow-y: scroll;
-      padding: 0.5rem 0;
-    }}
-    .sidebar ul {{ list-style: none; margin: 0; padding: 0; }}
-    .sidebar li {{
-      padding: 0.5rem 1rem;
-      cursor: pointer;
-      border-bottom: 1px solid #eee;
-    }}
-    .sidebar li:hover {{ background: #e8e8e8; }}
-    .sidebar li.selected {{ background: #ddd; font-weight: bold; }}
-    .sidebar .unread {{ color: #666; font-size: 12px; }}
-    .main {{ flex: 1; display: flex; flex-direction: column; overflow: hidden; }}
-    .email-list {{
-      height: 40%;
-      overflow-y: scroll;
-      border-bottom: 1px solid #ccc;
-    }}
-    .email-list table {{ width: 100%; border-collapse: collapse; }}
-    .email-list th, .email-list td {{
-      padding: 0.5rem;
-      text-align: left;
-      border-bottom: 1px solid #eee;
-    }}
-    .email-list th {{ background: #f0f0f0; position: sticky; top: 0; }}
-    .email-list tr {{ cursor: pointer; }}
-    .email-list tr:hover {{ background: #f5f5f5; }}
-    .email-list tr.selected {{ background: #e0e8f0; }}
-    .email-list tr.unread {{ font-weight: bold; }}
-    .email-list .subject {{ max-width: 300px; overflow: hidden; text-overflow: ellipsis; white-space: nowrap; }}
-    .email-list .preview {{ color: #666; font-size: 12px; }}
-    .email-view {{
-      flex: 1;
-      overflow-y: scroll;
-      padding: 1rem;
-    }}
-    .email-view .headers {{ margin-bottom: 1rem; }}
-    .email-view .headers dt {{ font-weight: bold; float: left; width: 80px; clear: left; }}
-    .email-view .headers dd {{ margin-left: 90px; margin-bottom: 0.25rem; }}
-    .email-view .body {{
-      white-space: pre-wrap;
-      font-family: monospace;
-      background: #fff;
-      padding: 1rem;
-      border: 1px solid #ddd;
-    }}
-    .login-page {{
-      display: flex;
-      justify-content: center;
-      align-items: center;
-      height: 100vh;
-      background: #f5f5f5;
-    }}
-    .login-form {{
-      background: #fff;
-      padding: 2rem;
-      border: 1px solid #ccc;
-      width: 300px;
-    }}
-    .login-form h1 {{ margin: 0 0 1rem 0; font-size: 1.5rem; }}
-    .login-form input {{
-      display: block;
-      width: 100%;
-      padding: 0.5rem;
-      margin-bottom: 1rem;
-      border: 1px solid #ccc;
-      font-family: monospace;
-    }}
-    .login-form button {{
-      width: 100%;
-      padding: 0.5rem;
-      background: #333;
-      color: #fff;
-      border: none;
-      cursor: pointer;
-      font-family: monospace;
-    }}
-    .login-form button:hover {{ background: #555; }}
-    .error {{ color: #c00; margin-top: 1rem; }}
-    .loading {{ color: #666; font-style: italic; }}
-    .logout-btn {{
-      padding: 0.25rem 0.5rem;
-      background: none;
-      border: 1px solid #ccc;
-      cursor: pointer;
-      color: #666;
-      font-family: monospace;
-      font-size: 11px;
-    }}
-    .logout-btn:hover {{ color: #000; }}
-  </style>
+  <link rel="manifest" href="{base}/manifest.webmanifest">
+  <meta name="theme-color" content="{theme_color}">
+  <script src="{base}{htmx}"></script>
+  <script src="{base}{sse}"></script>
+  <link rel="stylesheet" href="{base}{style}">
 </head>
 <body>
 {body}
-<script>
-document.addEventListener('click', function(e) {{
-  // Handle mailbox selection
-  var mailboxItem = e.target.closest('.sidebar li');
-  if (mailboxItem) {{
-    document.querySelectorAll('.sidebar li.selected').forEach(function(el) {{
-      el.classList.remove('selected');
-    }});
-    mailboxItem.classList.add('selected');
-  }}
-  // Handle email row selection
-  var emailRow = e.target.closest('.email-list tbody tr');
-  if (emailRow && !emailRow.id) {{
-    document.querySelectorAll('.email-list tr.selected').forEach(function(el) {{
-      el.classList.remove('selected');
-    }});
-    emailRow.classList.add('selected');
-  }}
-}});
-</script>
+<script src="{base}{app}"></script>
+<script src="{base}{keys}"></script>
 </body>
 </html>"#,
-        title = html_escape(title),
+        lang = i18n::current(),
+        // Without a choice, style.css goes by prefers-color-scheme
+        theme = theme::current()
+            .map(|t| format!(r#" data-theme="{}""#, t.name()))
+            .unwrap_or_default(),
+        theme_color = pwa::THEME_COLOR,
+        style = assets::url("style.css"),
+        htmx = assets::url("htmx.min.js"),
+        sse = assets::url("sse.js"),
+        app = assets::url("app.js"),
+        keys = assets::url("keys.js"),
+        title = html_escape(&title),
         body = body
     )
 }
 
-pub fn login_page(error: Option<&str>) -> String {
+/// The login form, with a link for each OAuth provider as `(id, name)` and,
+/// if `remember` is offered, a "Remember me" checkbox
+pub fn login_page(
+    branding: &BrandingConfig,
+    error: Option<&str>,
+    servers: &[&str],
+    autodiscover: bool,
+    providers: &[(&str, &str)],
+    remember: bool,
+) -> String {
+    let base = base_path();
     let error_html = error
         .map(|e| format!(r#"<div class="error">{}</div>"#, html_escape(e)))
         .unwrap_or_default();
 
+    // Chosen by position, the first being the default. Autodiscovery comes
+    // last, and is all there is without any servers.
+    let server_html = if servers.len() + usize::from(autodiscover) > 1 {
+        let mut options: String = servers
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!(r#"<option value="{}">{}</option>"#, i, html_escape(name)))
+            .collect();
+        if autodiscover {
+            options.push_str(&format!(r#"<option value="auto">{}</option>"#, t("login.autodiscover")));
+        }
+        format!(r#"<select name="server" aria-label="{}">{}</select>"#, t("login.server"), options)
+    } else {
+        String::new()
+    };
+
+    let remember_html = if remember {
+        format!(
+            r#"<label class="remember"><input name="remember" type="checkbox" value="1"> {}</label>"#,
+            t("login.remember")
+        )
+    } else {
+        String::new()
+    };
+
+    // Plain links: the provider's page has to replace the whole window
+    let oauth_html: String = providers
+        .iter()
+        .map(|(id, name)| {
+            format!(
+                r#"<a class="oauth-login" href="{base}/oauth/{}/start">{}</a>"#,
+                url_encode(id),
+                tf("login.oauth", &[("name", &html_escape(name))])
+            )
+        })
+        .collect();
+
+    let logo_html = branding
+        .logo_url
+        .as_deref()
+        .map(|url| format!(r#"<img class="logo" src="{}" alt="">"#, html_escape(&under_base_path(url))))
+        .unwrap_or_default();
+    let message_html = branding
+        .login_message
+        .as_deref()
+        .map(|m| format!(r#"<div class="login-message">{}</div>"#, html_escape(m)))
+        .unwrap_or_default();
+
+    let body = format!(
+        r#"<div class="login-page">
+  <form class="login-form" hx-post="{base}/login" hx-target="body" hx-swap="innerHTML">
+    {logo_html}
+    <h1>{heading}</h1>
+    <input name="username" type="text" placeholder="{email}" required autofocus>
+    <input name="password" type="password" placeholder="{password}" required>
+    {server_html}
+    {remember_html}
+    <button type="submit">{submit}</button>
+    {oauth_html}
+    {error_html}
+    {message_html}
+  </form>
+</div>"#,
+        heading = tf("login.heading", &[("name", &html_escape(&branding.name))]),
+        email = t("login.email"),
+        password = t("login.password"),
+        submit = t("login.submit")
+    );
+
+    base_page(branding, Some(t("login.title")), &body)
+}
+
+/// The second step of signing in, asking for the authenticator app's code
+/// for the sign-in `token` is holding
+pub fn code_page(branding: &BrandingConfig, token: &str, error: Option<&str>) -> String {
+    let base = base_path();
+    let error_html = error
+        .map(|e| format!(r#"<div class="error">{}</div>"#, html_escape(e)))
+        .unwrap_or_default();
     let body = format!(
         r#"<div class="login-page">
-  <form class="login-form" hx-post="/login" hx-target="body" hx-swap="innerHTML">
-    <h1>Webmail Login</h1>
-    <input name="username" type="text" placeholder="Email address" required autofocus>
-    <input name="password" type="password" placeholder="Password" required>
-    <button type="submit">Login</button>
+  <form class="login-form" hx-post="{base}/login/code" hx-target="body" hx-swap="innerHTML">
+    <h1>{heading}</h1>
+    <p>{prompt}</p>
+    <input type="hidden" name="token" value="{token}">
+    <input name="code" inputmode="numeric" autocomplete="one-time-code" placeholder="{code}" required autofocus>
+    <button type="submit">{submit}</button>
     {error_html}
   </form>
-</div>"#
+</div>"#,
+        heading = t("totp.title"),
+        prompt = t("totp.prompt"),
+        token = html_escape(token),
+        code = t("totp.code"),
+        submit = t("totp.submit")
+    );
+    base_page(branding, Some(t("totp.title")), &body)
+}
+
+/// A page that moves straight on to `path` in the app, for redirects that
+/// must count as same-site
+pub fn redirect_page(path: &str) -> String {
+    let url = html_escape(&format!("{}{}", base_path(), path));
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta http-equiv="refresh" content="0; url={url}">
+  <title>{title}</title>
+</head>
+<body><a href="{url}">{label}</a></body>
+</html>"#,
+        title = t("redirect.title"),
+        label = t("redirect.continue")
+    )
+}
+
+#[derive(Template)]
+#[template(path = "link.html")]
+struct LinkPage<'a> {
+    url: &'a str,
+    host: &'a str,
+    /// The trackers taken off, comma separated
+    removed: String,
+}
+
+/// Where a link from a message goes, to follow from there, and which
+/// tracking parameters were taken off it
+pub fn link_page(branding: &BrandingConfig, url: &str, host: &str, removed: &[String]) -> String {
+    let body = render(LinkPage {
+        url,
+        host,
+        removed: removed.join(", "),
+    });
+    base_page(branding, Some(t("link.title")), &body)
+}
+
+/// A request that failed, loaded by the browser itself rather than into a
+/// pane of the app
+pub fn error_page(branding: &BrandingConfig, message: &str) -> String {
+    let base = base_path();
+    let body = format!(
+        r#"<div class="error-page">
+  <h1>{heading}</h1>
+  <div class="error">{message}</div>
+  <p><a href="{base}/">{back}</a></p>
+</div>"#,
+        heading = t("error.heading"),
+        message = html_escape(message),
+        back = t("error.back")
     );
+    base_page(branding, Some(t("error.heading")), &body)
+}
+
+/// Attributes filling an element from `path` in the app once the page has
+/// loaded
+fn load_on_start(path: Option<&str>) -> String {
+    path.map(|path| format!(r#" hx-get="{}{}" hx-trigger="load" hx-swap="innerHTML""#, base_path(), html_escape(path)))
+        .unwrap_or_default()
+}
 
-    base_page("Login", &body)
+/// What a link into the app opens as the page loads: the paths of the list
+/// and message fragments, and the mailbox listed, which the sidebar
+/// opens its folders down to
+#[derive(Default)]
+pub struct Opened {
+    pub list: Option<String>,
+    pub view: Option<String>,
+    pub mailbox: Option<String>,
 }
 
-pub fn main_page(username: &str) -> String {
+/// The app shell, on `active_account`. `csrf_token` goes out as a header
+/// with every htmx request made from inside it.
+pub fn main_page(
+    branding: &BrandingConfig,
+    session: &Session,
+    active_account: &str,
+    prefs: &Prefs,
+    opened: &Opened,
+    links: &[Link],
+) -> String {
+    let base = base_path();
+    let (username, accounts) = (&session.username, &session.accounts);
+    // Only worth a control when there's something to switch to
+    let switcher = if accounts.len() > 1 {
+        let options: String = accounts
+            .iter()
+            .map(|a| {
+                format!(
+                    r#"<option value="{id}"{selected}>{name}{read_only}</option>"#,
+                    id = html_escape(&a.id),
+                    selected = if a.id == active_account { " selected" } else { "" },
+                    name = html_escape(&a.name),
+                    read_only = if a.is_read_only { t("sidebar.read_only") } else { "" }
+                )
+            })
+            .collect();
+        format!(
+            r#"<select class="account-switcher" name="account" title="{}" hx-post="{base}/account" hx-target="body" hx-swap="innerHTML">{}</select>"#,
+            t("sidebar.switch_account"),
+            options
+        )
+    } else {
+        String::new()
+    };
+
+    // Nor is sending, which shows in the compose button and the Outbox
+    let can_send = accounts.iter().any(|a| a.id == active_account && a.has_submission);
+    let compose_button = if can_send {
+        format!(
+            r##"<button class="compose-btn" hx-get="{base}/compose" hx-target="#email-view" hx-swap="innerHTML">{}</button>"##,
+            t("sidebar.compose")
+        )
+    } else {
+        String::new()
+    };
+    let outbox_link = if can_send {
+        format!(r##"<a hx-get="{base}/outbox" hx-target="#email-view" hx-swap="innerHTML">{}</a>"##, t("sidebar.outbox"))
+    } else {
+        String::new()
+    };
+
+    // Calendars are per account, so the link follows the switcher
+    let calendar_link = if accounts.iter().any(|a| a.id == active_account && a.has_calendars) {
+        format!(
+            r##"<a hx-get="{base}/calendar" hx-target="#email-view" hx-swap="innerHTML">{}</a>"##,
+            t("sidebar.calendar")
+        )
+    } else {
+        String::new()
+    };
+
+    // From plugins, for whatever else the deployment offers
+    let plugin_links: String = links
+        .iter()
+        .map(|link| {
+            format!(
+                r#"<a href="{url}" target="_blank" rel="noopener noreferrer">{label}</a>"#,
+                url = html_escape(&under_base_path(&link.url)),
+                label = html_escape(&link.label)
+            )
+        })
+        .collect();
+
     let body = format!(
-        r#"<div class="container">
+        r##"<div class="container{compact}" data-pane="{pane}"{list_height} hx-ext="sse" sse-connect="{base}/events" hx-headers='{{"X-CSRF-Token": "{csrf_token}"}}'>
   <div class="sidebar">
     <div class="sidebar-header">
       <span class="username">{username}</span>
-      <button class="logout-btn" hx-post="/logout" hx-target="body" hx-swap="innerHTML">Logout</button>
+      <button class="theme-toggle" hx-post="{base}/theme" hx-swap="none" title="{theme}">&#9680;</button>
+      <button class="logout-btn" hx-post="{base}/logout" hx-target="body" hx-swap="innerHTML">{logout}</button>
+      {switcher}
     </div>
-    <div class="mailbox-list" hx-get="/mailboxes" hx-trigger="load">
-      <div class="loading">Loading mailboxes...</div>
+    {compose_button}
+    <span hidden hx-get="{base}/mailboxes/unread" hx-trigger="sse:mailbox" hx-swap="none"></span>
+    <span id="unread-title" hidden hx-get="{base}/unread-count" hx-trigger="load, sse:mailbox, mailboxesChanged from:body" hx-swap="none"></span>
+    <span id="mailbox-poll" hidden></span>
+    <div class="mailbox-list" id="mailbox-list" hx-get="{mailboxes_url}" hx-trigger="load">
+      <div class="loading">{loading}</div>
+    </div>
+    <div class="saved-searches" id="saved-searches" hx-get="{base}/saved-searches" hx-trigger="load"></div>
+    <div class="sidebar-footer">
+      <a hx-get="{base}/search" hx-target="#email-view" hx-swap="innerHTML">{search}</a>
+      {outbox_link}
+      {calendar_link}
+      <a hx-get="{base}/settings" hx-target="#email-view" hx-swap="innerHTML">{settings}</a>
+      {plugin_links}
+      {language_picker}
     </div>
   </div>
   <div class="main">
-    <div class="email-list" id="email-list">
-      <div style="padding: 1rem; color: #666;">Select a mailbox</div>
+    <div class="pane-bar">
+      <button class="pane-back" data-pane="mailboxes">&lsaquo; {back_to_folders}</button>
+      <button class="pane-back" data-pane="list">&lsaquo; {back_to_list}</button>
+    </div>
+    <div class="email-list" id="email-list"{list_load}>
+      <div style="padding: 1rem; color: var(--muted);">{select_mailbox}</div>
     </div>
-    <div class="email-view" id="email-view">
-      <div style="color: #666;">Select an email to view</div>
+    <div class="email-view" id="email-view"{view_load}>
+      <div style="color: var(--muted);">{select_email}</div>
     </div>
   </div>
-</div>"#,
-        username = html_escape(username)
+  <div class="toasts" id="toasts"></div>
+</div>"##,
+        username = html_escape(username),
+        csrf_token = html_escape(&session.csrf_token),
+        compact = if prefs.density == Density::Compact { " compact" } else { "" },
+        list_height = match prefs.list_height {
+            Some(height) => format!(r#" style="--list-height: {}%""#, height),
+            None => String::new(),
+        },
+        // The pane a narrow screen starts on, the one opened last
+        pane = match opened {
+            Opened { view: Some(_), .. } => "view",
+            Opened { list: Some(_), .. } => "list",
+            _ => "mailboxes",
+        },
+        switcher = switcher,
+        compose_button = compose_button,
+        outbox_link = outbox_link,
+        calendar_link = calendar_link,
+        plugin_links = plugin_links,
+        language_picker = language_picker(prefs.language.as_deref()),
+        mailboxes_url = match &opened.mailbox {
+            Some(id) => html_escape(&format!("{base}/mailboxes?selected={}", url_encode(id))),
+            None => format!("{base}/mailboxes"),
+        },
+        list_load = load_on_start(opened.list.as_deref()),
+        view_load = load_on_start(opened.view.as_deref()),
+        theme = t("sidebar.theme"),
+        logout = t("sidebar.logout"),
+        loading = t("sidebar.loading"),
+        search = t("sidebar.search"),
+        settings = t("sidebar.settings"),
+        back_to_folders = t("sidebar.back_to_folders"),
+        back_to_list = t("sidebar.back_to_list"),
+        select_mailbox = t("sidebar.select_mailbox"),
+        select_email = t("sidebar.select_email")
     );
 
-    base_page("Webmail", &body)
+    base_page(branding, None, &body)
 }
 
-pub fn mailbox_list(mailboxes: &[Mailbox]) -> String {
-    let mut sorted: Vec<_> = mailboxes.iter().collect();
-    sorted.sort_by(|a, b| {
-        let role_order = |m: &Mailbox| match m.role.as_deref() {
-            Some("inbox") => 0,
-            Some("drafts") => 1,
-            Some("sent") => 2,
-            Some("trash") => 3,
-            Some("junk") | Some("spam") => 4,
-            Some("archive") => 5,
-            _ => 10,
-        };
-        role_order(a).cmp(&role_order(b)).then(a.name.cmp(&b.name))
-    });
-
-    let items: String = sorted
+/// The sidebar's choice of language, `language` being the user's own if
+/// they made one. Left out when there is only one.
+fn language_picker(language: Option<&str>) -> String {
+    let base = base_path();
+    let languages = i18n::languages();
+    if languages.len() < 2 {
+        return String::new();
+    }
+    let options: String = languages
         .iter()
-        .map(|m| {
-            let unread = if m.unread_emails > 0 {
-                format!(r#" <span class="unread">({})</span>"#, m.unread_emails)
-            } else {
-                String::new()
-            };
+        .map(|l| {
             format!(
-                "<li hx-get=\"/mailbox/{id}/emails\" hx-target=\"#email-list\" hx-swap=\"innerHTML\">{name}{unread}</li>",
-                id = html_escape(&m.id),
-                name = html_escape(&m.name),
-                unread = unread
+                r#"<option value="{code}"{selected}>{name}</option>"#,
+                code = l.code,
+                selected = if language == Some(l.code) { " selected" } else { "" },
+                name = html_escape(&l.name)
             )
         })
         .collect();
-
-    format!("<ul>{}</ul>", items)
+    format!(
+        r#"<select class="language-picker" name="language" aria-label="{label}" hx-post="{base}/language" hx-target="body" hx-swap="innerHTML"><option value="">{browser}</option>{options}</select>"#,
+        label = t("sidebar.language"),
+        browser = t("sidebar.browser_language"),
+        options = options
+    )
 }
 
-fn email_rows(emails: &[Email], mailbox_id: &str, next_offset: Option<u32>) -> String {
-    let rows: String = emails
-        .iter()
-        .map(|e| {
-            let from = e
-                .from
-                .as_ref()
-                .and_then(|f| f.first())
-                .map(|a| format_address_short(a))
-                .unwrap_or_else(|| "(unknown)".to_string());
+/// Mailboxes in sidebar order, depth first, with their nesting depth.
+/// Top-level mailboxes put special-use roles first; siblings then follow
+/// sortOrder and name (RFC 8621 Section 2). A mailbox whose parent is
+/// missing is shown at the top level.
+fn mailbox_tree(mailboxes: &[Mailbox]) -> Vec<(usize, &Mailbox)> {
+    let role_order = |m: &Mailbox| match m.role.as_deref() {
+        Some("inbox") => 0,
+        Some("drafts") => 1,
+        Some("sent") => 2,
+        Some("trash") => 3,
+        Some("junk") | Some("spam") => 4,
+        Some("archive") => 5,
+        _ => 10,
+    };
+    let known = |id: &str| mailboxes.iter().any(|m| m.id == id);
+    let children = |parent: Option<&str>| {
+        let mut list: Vec<&Mailbox> = mailboxes
+            .iter()
+            .filter(|m| match parent {
+                Some(parent) => m.parent_id.as_deref() == Some(parent),
+                None => m.parent_id.as_deref().is_none_or(|p| !known(p)),
+            })
+            .collect();
+        list.sort_by(|a, b| {
+            let top = |m: &Mailbox| if parent.is_none() { role_order(m) } else { 0 };
+            top(a)
+                .cmp(&top(b))
+                .then(a.sort_order.cmp(&b.sort_order))
+                .then(a.name.cmp(&b.name))
+        });
+        list
+    };
 
-            let subject = e
-                .subject
-                .as_deref()
-                .unwrap_or("(no subject)")
-                .to_string();
+    let mut out = Vec::with_capacity(mailboxes.len());
+    // Explicit stack; `out` doubles as the visited set so a parentId cycle
+    // can't loop forever
+    let mut stack: Vec<(usize, &Mailbox)> = children(None).into_iter().rev().map(|m| (0, m)).collect();
+    while let Some((depth, mailbox)) = stack.pop() {
+        if out.iter().any(|(_, m): &(usize, &Mailbox)| m.id == mailbox.id) {
+            continue;
+        }
+        out.push((depth, mailbox));
+        stack.extend(children(Some(&mailbox.id)).into_iter().rev().map(|m| (depth + 1, m)));
+    }
+    // Members of a cycle are never reached from the top level; list them flat
+    for mailbox in mailboxes {
+        if !out.iter().any(|(_, m)| m.id == mailbox.id) {
+            out.push((0, mailbox));
+        }
+    }
+    out
+}
 
-            let date = e
-                .received_at
-                .as_deref()
-                .map(format_date)
-                .unwrap_or_default();
+/// `<option>`s for a "Move to…" select, leaving out the mailboxes for which
+/// `exclude` is true (where the message is already filed)
+fn mailbox_options(mailboxes: &[Mailbox], exclude: impl Fn(&str) -> bool) -> String {
+    mailbox_tree(mailboxes)
+        .into_iter()
+        .filter(|(_, m)| !exclude(&m.id))
+        .map(|(depth, m)| {
+            format!(
+                r#"<option value="{id}">{indent}{name}</option>"#,
+                id = html_escape(&m.id),
+                indent = "&nbsp;&nbsp;".repeat(depth),
+                name = html_escape(&m.name)
+            )
+        })
+        .collect()
+}
 
-            let preview = e.preview.as_deref().unwrap_or("");
+/// The sidebar's folders. Those in `collapsed` that have subfolders show
+/// them only when expanded again, or when one of them is `selected`; the
+/// hidden ones are still rendered, for their unread badges' swaps.
+pub fn mailbox_list(mailboxes: &[Mailbox], collapsed: &HashSet<String>, selected: Option<&str>) -> String {
+    let base = base_path();
+    // The folders above the selected one, which must stay open to show it
+    let mut open_path = HashSet::new();
+    let mut parent = selected.and_then(|id| mailboxes.iter().find(|m| m.id == id)?.parent_id.as_deref());
+    while let Some(id) = parent.filter(|id| open_path.insert(*id)) {
+        parent = mailboxes.iter().find(|m| m.id == id).and_then(|m| m.parent_id.as_deref());
+    }
 
-            let unread_class = if e.keywords.get("$seen").copied().unwrap_or(false) {
-                ""
+    let tree = mailbox_tree(mailboxes);
+    // Depth of the collapsed folder the current one is inside, if any
+    let mut hidden_below: Option<usize> = None;
+    let items: String = tree
+        .iter()
+        .enumerate()
+        .map(|(i, &(depth, m))| {
+            if hidden_below.is_some_and(|below| depth <= below) {
+                hidden_below = None;
+            }
+            let hidden = if hidden_below.is_some() { " hidden" } else { "" };
+            let has_children = tree.get(i + 1).is_some_and(|&(next, _)| next > depth);
+            let toggle = if has_children {
+                let is_collapsed = collapsed.contains(&m.id) && !open_path.contains(m.id.as_str());
+                if is_collapsed && hidden_below.is_none() {
+                    hidden_below = Some(depth);
+                }
+                let (action, glyph, title) = if is_collapsed {
+                    ("expand", "&#9656;", t("sidebar.expand"))
+                } else {
+                    ("collapse", "&#9662;", t("sidebar.collapse"))
+                };
+                format!(
+                    r##"<span class="toggle" title="{title}" hx-post="{base}/mailboxes/{id}/{action}" hx-trigger="click consume" hx-target="#mailbox-list" hx-swap="innerHTML" hx-push-url="false">{glyph}</span> "##,
+                    title = title,
+                    id = html_escape(&url_encode(&m.id)),
+                    action = action,
+                    glyph = glyph
+                )
             } else {
-                " class=\"unread\""
+                String::new()
+            };
+            let indent = if depth > 0 {
+                format!(" style=\"padding-left: {}rem\"", 1 + depth)
+            } else {
+                String::new()
             };
-
             format!(
-                "<tr{unread_class} hx-get=\"/email/{id}\" hx-target=\"#email-view\" hx-swap=\"innerHTML\">
-  <td style=\"white-space: nowrap\">{date}</td>
-  <td>{from}</td>
-  <td><span class=\"subject\">{subject}</span><br><span class=\"preview\">{preview}</span></td>
-</tr>",
-                id = html_escape(&e.id),
-                from = html_escape(&from),
-                subject = html_escape(&subject),
-                preview = html_escape(&truncate(preview, 80)),
-                date = html_escape(&date),
-                unread_class = unread_class
+                "<li{indent}{hidden} hx-get=\"{base}/mailbox/{id}/emails\" hx-target=\"#email-list\" hx-swap=\"innerHTML\" hx-push-url=\"{base}/mailbox/{id}\">{toggle}{name} {unread}</li>",
+                indent = indent,
+                hidden = hidden,
+                id = html_escape(&m.id),
+                toggle = toggle,
+                name = html_escape(&m.name),
+                unread = unread_count(m, false)
             )
         })
         .collect();
 
-    let load_more = if let Some(offset) = next_offset {
-        format!(
-            "<tr id=\"loadmore\">\n\
-  <td colspan=\"3\" style=\"text-align: center; padding: 1rem;\">\n\
-    <button hx-get=\"/mailbox/{mailbox_id}/emails?offset={offset}\" hx-target=\"#loadmore\" hx-swap=\"outerHTML\" style=\"padding: 0.5rem 1rem; cursor: pointer; font-family: monospace; background: #f0f0f0; border: 1px solid #ccc;\">Load More</button>\n\
-  </td>\n\
-</tr>",
-            mailbox_id = html_escape(mailbox_id),
-            offset = offset
-        )
+    let flagged = format!(
+        "<li class=\"virtual\" hx-get=\"{base}/mailbox/{id}/emails\" hx-target=\"#email-list\" hx-swap=\"innerHTML\" hx-push-url=\"{base}/mailbox/{id}\">{name}</li>",
+        id = FLAGGED_ID,
+        name = t("sidebar.flagged")
+    );
+    let all_mail = format!(
+        "<li class=\"virtual\" hx-get=\"{base}/mailbox/{id}/emails\" hx-target=\"#email-list\" hx-swap=\"innerHTML\" hx-push-url=\"{base}/mailbox/{id}\">{name}</li>",
+        id = ALL_MAIL_ID,
+        name = t("sidebar.all_mail")
+    );
+
+    format!("<ul>{}{}{}</ul>", items, flagged, all_mail)
+}
+
+/// The "(n)" unread badge next to a mailbox name. Always rendered, even when
+/// empty, so out-of-band swaps have an element to replace.
+fn unread_count(mailbox: &Mailbox, oob: bool) -> String {
+    let count = if mailbox.unread_emails > 0 {
+        format!("({})", mailbox.unread_emails)
     } else {
         String::new()
     };
-
-    format!("{}{}", rows, load_more)
+    format!(
+        r#"<span class="unread" id="unread-{id}"{oob}>{count}</span>"#,
+        id = html_escape(&mailbox.id),
+        oob = if oob { r#" hx-swap-oob="true""# } else { "" },
+        count = count
+    )
 }
 
-pub fn email_list(emails: &[Email], mailbox_id: &str, next_offset: Option<u32>) -> String {
-    if emails.is_empty() {
-        return r#"<div style="padding: 1rem; color: #666;">No emails in this mailbox</div>"#
-            .to_string();
-    }
+/// Out-of-band replacement of the sidebar's poller, which asks for changes
+/// since `mailbox_state` every `interval` seconds
+pub fn mailbox_poller(interval: u64, mailbox_state: &str) -> String {
+    let base = base_path();
+    format!(
+        r#"<span id="mailbox-poll" hidden hx-swap-oob="true" hx-get="{base}/mailboxes/poll?since={state}" hx-trigger="every {interval}s" hx-swap="none"></span>"#,
+        state = html_escape(&url_encode(mailbox_state)),
+        interval = interval
+    )
+}
 
-    let rows = email_rows(emails, mailbox_id, next_offset);
+/// Out-of-band refresh of every mailbox's unread badge, leaving the rest of
+/// the sidebar (including the selected mailbox) untouched
+pub fn unread_counts_oob(mailboxes: &[Mailbox]) -> String {
+    mailboxes.iter().map(|m| unread_count(m, true)).collect()
+}
 
+/// Star that toggles `$flagged`. The list row and the email view each have
+/// one (`place` is "row" or "view"); a toggle response swaps both.
+fn flag_star(email_id: &str, flagged: bool, place: &str, oob: bool) -> String {
+    let base = base_path();
+    let (action, glyph, title) = if flagged {
+        ("unflag", "&#9733;", t("email.unflag"))
+    } else {
+        ("flag", "&#9734;", t("email.flag"))
+    };
     format!(
-        r#"<table>
-<thead><tr><th>Date</th><th>From</th><th>Subject</th></tr></thead>
-<tbody>{}</tbody>
-</table>"#,
-        rows
+        r#"<span class="star" id="star-{place}-{id}"{oob} title="{title}" hx-post="{base}/email/{id}/{action}" hx-trigger="click consume" hx-swap="none">{glyph}</span>"#,
+        place = place,
+        id = html_escape(email_id),
+        oob = if oob { r#" hx-swap-oob="true""# } else { "" },
+        title = title,
+        action = action,
+        glyph = glyph
     )
 }
 
-pub fn email_list_rows(emails: &[Email], mailbox_id: &str, next_offset: Option<u32>) -> String {
-    email_rows(emails, mailbox_id, next_offset)
+/// Out-of-band updates for both stars of a message after a flag toggle
+pub fn flag_stars_oob(email_id: &str, flagged: bool) -> String {
+    flag_star(email_id, flagged, "row", true) + &flag_star(email_id, flagged, "view", true)
 }
 
-pub fn email_view(email: &Email) -> String {
-    let from = email
+fn email_row(e: &Email, mailboxes: &[Mailbox], selected: bool, oob: bool) -> String {
+    let base = base_path();
+    let from = e
         .from
         .as_ref()
-        .map(|addrs| format_addresses(addrs))
-        .unwrap_or_else(|| "(unknown)".to_string());
-
-    let to = email
-        .to
-        .as_ref()
-        .map(|addrs| format_addresses(addrs))
-        .unwrap_or_else(|| "(unknown)".to_string());
-
-    let cc = email.cc.as_ref().map(|addrs| format_addresses(addrs));
+        .and_then(|f| f.first())
+        .map(format_address_short)
+        .unwrap_or_else(|| t("common.unknown").to_string());
 
-    let subject = email
+    let subject = e
         .subject
         .as_deref()
-        .unwrap_or("(no subject)");
+        .unwrap_or(t("common.no_subject"))
+        .to_string();
 
-    let date = email
+    // How long ago at a glance, the exact time on hover
+    let (date, full_date) = e
         .received_at
         .as_deref()
-        .unwrap_or("(unknown date)");
+        .map(|d| (dates::relative(d), dates::full(d)))
+        .unwrap_or_default();
 
-    let body = get_email_body(email);
+    let preview = e.preview.as_deref().unwrap_or("");
 
-    let cc_html = cc
-        .map(|c| format!("<dt>Cc:</dt><dd>{}</dd>", html_escape(&c)))
-        .unwrap_or_default();
+    let mut classes = Vec::new();
+    if !e.keywords.get("$seen").copied().unwrap_or(false) {
+        classes.push("unread");
+    }
+    if selected {
+        classes.push("selected");
+    }
+    let class_attr = if classes.is_empty() {
+        String::new()
+    } else {
+        format!(" class=\"{}\"", classes.join(" "))
+    };
+
+    let flagged = e.keywords.get("$flagged").copied().unwrap_or(false);
+
+    // Only shown in lists spanning several mailboxes, but always rendered so
+    // rows swapped in out-of-band line up either way
+    let source = mailbox_tree(mailboxes)
+        .into_iter()
+        .filter(|(_, m)| e.mailbox_ids.get(&m.id).copied().unwrap_or(false))
+        .map(|(_, m)| m.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // Messages in a longer conversation open the whole thread
+    let (href, thread_badge) = match e.thread_id.as_deref() {
+        Some(thread_id) if e.thread_size > 1 => (
+            format!("{base}/thread/{}?focus={}", url_encode(thread_id), url_encode(&e.id)),
+            format!(r#" <span class="thread-count">{}</span>"#, e.thread_size),
+        ),
+        _ => (format!("{base}/email/{}", url_encode(&e.id)), String::new()),
+    };
 
     format!(
-        r#"<div style="margin-bottom: 0.5rem;">
-  <a href="/email/{id}/raw" target="_blank" style="font-size: 12px; color: #666; text-decoration: none; border: 1px solid #ccc; padding: 2px 8px; background: #f5f5f5;">View Raw</a>
-</div>
-<dl class="headers">
-  <dt>From:</dt><dd>{from}</dd>
-  <dt>To:</dt><dd>{to}</dd>
-  {cc_html}
-  <dt>Subject:</dt><dd>{subject}</dd>
-  <dt>Date:</dt><dd>{date}</dd>
-</dl>
-<hr>
-<pre class="body">{body}</pre>"#,
-        id = html_escape(&email.id),
+        "<tr id=\"row-{id}\"{class_attr}{oob} hx-get=\"{href}\" hx-target=\"#email-view\" hx-swap=\"innerHTML\" hx-push-url=\"true\">
+  <td class=\"select\"><input type=\"checkbox\" name=\"email\" value=\"{id}\"></td>
+  <td class=\"star\">{star}</td>
+  <td style=\"white-space: nowrap\" title=\"{full_date}\">{date}</td>
+  <td>{from}</td>
+  <td><span class=\"subject\">{subject}</span>{thread_badge}<br><span class=\"preview\">{preview}</span></td>
+  <td class=\"source\">{source}</td>
+  <td class=\"size\">{size}</td>
+  <td class=\"move\"><select name=\"mailbox\" hx-post=\"{base}/email/{id}/move\" hx-trigger=\"change\" hx-swap=\"none\"><option value=\"\">{move_to}</option>{options}</select></td>
+</tr>",
+        move_to = t("common.move_to"),
+        id = html_escape(&e.id),
+        href = html_escape(&href),
+        thread_badge = thread_badge,
         from = html_escape(&from),
-        to = html_escape(&to),
-        cc_html = cc_html,
-        subject = html_escape(subject),
-        date = html_escape(date),
-        body = html_escape(&body)
+        subject = html_escape(&subject),
+        preview = html_escape(&truncate(preview, 80)),
+        date = html_escape(&date),
+        full_date = html_escape(&full_date),
+        source = html_escape(&source),
+        size = format_size(e.size),
+        class_attr = class_attr,
+        star = flag_star(&e.id, flagged, "row", false),
+        options = mailbox_options(mailboxes, |id| e.mailbox_ids.get(id).copied().unwrap_or(false)),
+        oob = if oob { " hx-swap-oob=\"true\"" } else { "" }
     )
 }
 
-pub fn error_fragment(message: &str) -> String {
-    format!(r#"<div class="error">{}</div>"#, html_escape(message))
+/// Out-of-band replacements for rows changed by a bulk action
+pub fn email_rows_oob(emails: &[Email], mailboxes: &[Mailbox]) -> String {
+    emails
+        .iter()
+        .map(|e| email_row(e, mailboxes, false, true))
+        .collect()
 }
 
-// Helper functions
-
-fn html_escape(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
-        .replace('\'', "&#39;")
+/// Out-of-band replacement for the list row of the message that is open in
+/// the viewer, which is therefore the selected row
+pub fn email_row_oob(e: &Email, mailboxes: &[Mailbox]) -> String {
+    email_row(e, mailboxes, true, true)
 }
 
-fn format_address_short(addr: &EmailAddress) -> String {
-    addr.name
-        .as_ref()
-        .or(addr.email.as_ref())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "(unknown)".to_string())
+/// Rows plus, when there are more, a row fetching `next_page` in its place
+/// as soon as it scrolls into view. That is `intersect` rather than htmx's
+/// `revealed`, which only notices the window scrolling, not the list. Its
+/// Load More button is for when the fetch failed.
+fn email_rows(emails: &[Email], mailboxes: &[Mailbox], next_page: Option<&str>) -> String {
+    let rows: String = emails
+        .iter()
+        .map(|e| email_row(e, mailboxes, false, false))
+        .collect();
+
+    let load_more = if let Some(url) = next_page {
+        let base = base_path();
+        format!(
+            "<tr id=\"loadmore\" hx-get=\"{base}{url}\" hx-trigger=\"intersect once\" hx-swap=\"outerHTML\">\n\
+  <td colspan=\"8\" style=\"text-align: center; padding: 1rem;\">\n\
+    <button hx-get=\"{base}{url}\" hx-target=\"#loadmore\" hx-swap=\"outerHTML\" style=\"padding: 0.5rem 1rem; cursor: pointer; font-family: monospace; background: var(--header); border: 1px solid var(--border);\">{label}</button>\n\
+  </td>\n\
+</tr>",
+            url = html_escape(url),
+            label = t("list.load_more")
+        )
+    } else {
+        String::new()
+    };
+
+    format!("{}{}", rows, load_more)
 }
 
-fn format_addresses(addrs: &[EmailAddress]) -> String {
-    addrs
-        .iter()
-        .map(|a| a.to_string())
-        .collect::<Vec<_>>()
-        .join(", ")
+/// How a list was queried, for the controls that query it again differently
+pub struct ListView<'a> {
+    pub mailbox_id: &'a str,
+    pub sort: Sort,
+    pub unread_only: bool,
+    /// Path of the first page with the given sort and unread filter
+    pub requery: &'a dyn Fn(Sort, bool) -> String,
+    /// Path of the next page, if there is one
+    pub next_page: Option<String>,
+    /// Messages pinned above the rest
+    pub pinned: &'a [Email],
 }
 
-fn format_date(iso_date: &str) -> String {
-    // Simple date formatting - just extract date and time parts
-    if let Some(t_pos) = iso_date.find('T') {
-        let date = &iso_date[..t_pos];
-        let time = iso_date
-            .get(t_pos + 1..t_pos + 6)
-            .unwrap_or("");
-        format!("{} {}", date, time)
-    } else {
-        iso_date.to_string()
+pub fn email_list(emails: &[Email], mailboxes: &[Mailbox], view: &ListView) -> String {
+    // Pushed or polled changes refresh the list, except while rows are
+    // checked for a bulk action, which a refresh would clear. `refresh=1`
+    // lets the server answer with just the rows that changed, or nothing.
+    let base = base_path();
+    let live_refresh = format!(
+        r##"<span hidden hx-get="{base}{url}&amp;refresh=1" hx-trigger="sse:email, mailboxesChanged from:body" hx-target="#email-list" hx-swap="innerHTML" class="live-refresh"></span>"##,
+        url = html_escape(&(view.requery)(view.sort, view.unread_only))
+    );
+    let unread_toggle = format!(
+        r##"<div class="list-options"><label><input type="checkbox" hx-get="{base}{url}" hx-target="#email-list" hx-swap="innerHTML"{checked}> {label}</label></div>"##,
+        label = t("list.unread_only"),
+        url = html_escape(&(view.requery)(view.sort, !view.unread_only)),
+        checked = if view.unread_only { " checked" } else { "" }
+    );
+
+    if emails.is_empty() && view.pinned.is_empty() {
+        let message = if view.unread_only {
+            t("list.empty_unread")
+        } else {
+            t("list.empty")
+        };
+        return format!(
+            r#"{}{}<div style="padding: 1rem; color: var(--muted);">{}</div>"#,
+            live_refresh, unread_toggle, message
+        );
     }
+
+    let rows = email_rows(emails, mailboxes, view.next_page.as_deref());
+    let pinned = if view.pinned.is_empty() {
+        String::new()
+    } else {
+        format!(
+            r#"<tbody class="pinned"><tr class="pinned-heading"><td colspan="8">{}</td></tr>{}</tbody>"#,
+            t("list.pinned"),
+            email_rows(view.pinned, mailboxes, None)
+        )
+    };
+    let cross_mailbox = !mailboxes.iter().any(|m| m.id == view.mailbox_id);
+    let sort_url = |sort| (view.requery)(sort, view.unread_only);
+    let header = |label, property| sort_header(label, property, view.sort, &sort_url);
+
+    format!(
+        r#"{live_refresh}{unread_toggle}{bulk_bar}<table{class}>
+<thead><tr><th><input type="checkbox" class="select-all" title="{select_all}"></th><th></th>{date}{from}{subject}<th class="source">{mailbox}</th>{size}<th></th></tr></thead>
+{pinned}<tbody>{rows}</tbody>
+</table>"#,
+        live_refresh = live_refresh,
+        unread_toggle = unread_toggle,
+        class = if cross_mailbox { r#" class="cross-mailbox""# } else { "" },
+        bulk_bar = bulk_bar(mailboxes, view.mailbox_id),
+        select_all = t("list.select_all"),
+        mailbox = t("list.mailbox"),
+        date = header(t("list.date"), SortProperty::ReceivedAt),
+        from = header(t("list.from"), SortProperty::From),
+        subject = header(t("list.subject"), SortProperty::Subject),
+        size = header(t("list.size"), SortProperty::Size),
+        pinned = pinned,
+        rows = rows
+    )
 }
 
-fn truncate(s: &str, max_len: usize) -> String {
-    let char_count = s.chars().count();
-    if char_count <= max_len {
-        s.to_string()
+/// A column header that re-sorts the list, with an arrow on the active one
+fn sort_header(
+    label: &str,
+    property: SortProperty,
+    sort: Sort,
+    sort_url: &dyn Fn(Sort) -> String,
+) -> String {
+    let arrow = match (sort.property == property, sort.ascending) {
+        (true, true) => " &#9650;",
+        (true, false) => " &#9660;",
+        (false, _) => "",
+    };
+    let base = base_path();
+    format!(
+        r##"<th class="sortable" hx-get="{base}{url}" hx-target="#email-list" hx-swap="innerHTML">{label}{arrow}</th>"##,
+        url = html_escape(&sort_url(sort.toggled(property))),
+        label = label,
+        arrow = arrow
+    )
+}
+
+/// Actions applied to every checked row in one request
+fn bulk_bar(mailboxes: &[Mailbox], mailbox_id: &str) -> String {
+    let base = base_path();
+    let role = |role: &str| mailboxes.iter().find(|m| m.role.as_deref() == Some(role));
+    let current_role = mailboxes
+        .iter()
+        .find(|m| m.id == mailbox_id)
+        .and_then(|m| m.role.as_deref());
+
+    // From Trash (or without one) Delete is permanent, so ask first. The
+    // button needs its own hx-post for hx-confirm to apply to it.
+    let delete_confirm = if current_role == Some("trash") || role("trash").is_none() {
+        format!(r#" hx-post="{base}/emails/bulk" hx-confirm="{}""#, t("list.confirm_delete"))
     } else {
-        let truncated: String = s.chars().take(max_len).collect();
-        format!("{}...", truncated)
-    }
+        String::new()
+    };
+    let archive = match role("archive") {
+        Some(archive) if archive.id != mailbox_id => {
+            format!(r#"<button name="action" value="archive">{}</button>"#, t("common.archive"))
+        }
+        _ => String::new(),
+    };
+
+    let empty = match current_role {
+        Some(role @ ("trash" | "junk" | "spam")) => {
+            let name = mailboxes
+                .iter()
+                .find(|m| m.id == mailbox_id)
+                .map(|m| html_escape(&m.name))
+                .unwrap_or_else(|| role.to_string());
+            format!(
+                r##"<button type="button" hx-post="{base}/mailbox/{id}/empty" hx-target="#email-list" hx-swap="innerHTML" hx-confirm="{confirm}">{label}</button>"##,
+                id = html_escape(mailbox_id),
+                confirm = tf("list.confirm_empty", &[("name", &name)]),
+                label = tf("list.empty_mailbox", &[("name", &name)])
+            )
+        }
+        _ => String::new(),
+    };
+
+    format!(
+        r##"<form class="bulk-bar" hx-post="{base}/emails/bulk" hx-include="#email-list input[name=email]:checked" hx-target="#bulk-status" hx-swap="innerHTML">
+  <button name="action" value="read">{mark_read}</button>
+  <button name="action" value="unread">{mark_unread}</button>
+  {archive}
+  <button name="action" value="delete"{delete_confirm}>{delete}</button>
+  <select name="mailbox"><option value="">{move_to}</option>{options}</select>
+  <button name="action" value="move">{move_label}</button>
+  {empty}
+  <span id="bulk-status"></span>
+</form>"##,
+        mark_read = t("common.mark_read"),
+        mark_unread = t("common.mark_unread"),
+        delete = t("common.delete"),
+        move_to = t("common.move_to"),
+        move_label = t("common.move"),
+        empty = empty,
+        archive = archive,
+        delete_confirm = delete_confirm,
+        options = mailbox_options(mailboxes, |id| id == mailbox_id)
+    )
+}
+
+pub fn email_list_rows(emails: &[Email], mailboxes: &[Mailbox], next_page: Option<&str>) -> String {
+    email_rows(emails, mailboxes, next_page)
+}
+
+/// The advanced search panel, pre-filled from `search`. Results replace the
+/// email list.
+pub fn search_form(search: &Search, mailboxes: &[Mailbox]) -> String {
+    let base = base_path();
+    let text = |label: &str, name: &str, kind: &str, value: &str| {
+        format!(
+            r#"<tr><td><label for="search-{name}">{label}</label></td><td><input type="{kind}" id="search-{name}" name="{name}" value="{value}"></td></tr>"#,
+            label = label,
+            name = name,
+            kind = kind,
+            value = html_escape(value)
+        )
+    };
+    let options: String = mailbox_tree(mailboxes)
+        .into_iter()
+        .map(|(depth, m)| {
+            format!(
+                r#"<option value="{id}"{selected}>{indent}{name}</option>"#,
+                id = html_escape(&m.id),
+                selected = if m.id == search.mailbox { " selected" } else { "" },
+                indent = "&nbsp;&nbsp;".repeat(depth),
+                name = html_escape(&m.name)
+            )
+        })
+        .collect();
+
+    format!(
+        r##"<div class="settings search">
+<h2>{heading}</h2>
+<form hx-get="{base}/search/results" hx-target="#email-list" hx-swap="innerHTML">
+<table>
+{text}{from}{to}{subject}{after}{before}
+<tr><td><label for="search-mailbox">{in_label}</label></td><td><select id="search-mailbox" name="mailbox"><option value="">{any_mailbox}</option>{options}</select></td></tr>
+<tr><td></td><td><label><input type="checkbox" name="has_attachment" value="true"{attachment}> {has_attachment}</label></td></tr>
+</table>
+<button type="submit">{submit}</button>
+<p class="hint">{hint}</p>
+</form>
+</div>"##,
+        heading = t("search.heading"),
+        in_label = t("search.in"),
+        any_mailbox = t("search.any_mailbox"),
+        has_attachment = t("search.has_attachment"),
+        submit = t("search.submit"),
+        hint = t("search.hint"),
+        text = text(t("search.text"), "text", "text", &search.text),
+        from = text(t("search.from"), "from", "text", &search.from),
+        to = text(t("search.to"), "to", "text", &search.to),
+        subject = text(t("search.subject"), "subject", "text", &search.subject),
+        after = text(t("search.after"), "after", "date", &search.after),
+        before = text(t("search.before"), "before", "date", &search.before),
+        options = options,
+        attachment = if search.has_attachment { " checked" } else { "" }
+    )
+}
+
+/// Result count above the search results, with a way back to the form
+pub fn search_summary(total: Option<u32>, query: &str) -> String {
+    let base = base_path();
+    let count = match total {
+        Some(1) => t("search.one_match").to_string(),
+        Some(n) => tf("search.matches", &[("count", &n.to_string())]),
+        None => t("search.results").to_string(),
+    };
+    format!(
+        r##"<div class="search-summary">{count} &middot; <a hx-get="{base}/search?{query}" hx-target="#email-view" hx-swap="innerHTML">{edit}</a>
+<form hx-post="{base}/saved-searches" hx-swap="outerHTML"><input type="hidden" name="query" value="{query}"><input name="name" placeholder="{name}" required> <button type="submit">{save}</button></form></div>"##,
+        count = count,
+        edit = t("search.edit"),
+        name = t("common.name"),
+        save = t("search.save"),
+        query = html_escape(query)
+    )
+}
+
+/// The summary above search results found among the messages the cache
+/// keeps. Loads the server's results from the path `results_url` in their
+/// place.
+pub fn local_search_summary(count: usize, results_url: &str) -> String {
+    let base = base_path();
+    format!(
+        r##"<div class="search-summary" hx-get="{base}{url}" hx-trigger="load" hx-target="#email-list" hx-swap="innerHTML">{count} &middot; {searching}</div>"##,
+        url = html_escape(results_url),
+        count = local_matches(count),
+        searching = t("search.searching_server")
+    )
+}
+
+/// The same when the server's search failed, leaving the cache's results
+pub fn local_search_failed(count: usize, error: &str) -> String {
+    format!(
+        r#"<div class="search-summary">{count} &middot; {failed}</div>"#,
+        count = local_matches(count),
+        failed = tf("search.server_failed", &[("error", &html_escape(error))])
+    )
+}
+
+fn local_matches(count: usize) -> String {
+    match count {
+        1 => t("search.one_local_match").to_string(),
+        n => tf("search.local_matches", &[("count", &n.to_string())]),
+    }
+}
+
+/// The sidebar section under the mailboxes; empty when nothing is saved
+pub fn saved_search_list(searches: &[SavedSearch]) -> String {
+    let base = base_path();
+    if searches.is_empty() {
+        return String::new();
+    }
+    let items: String = searches
+        .iter()
+        .map(|search| {
+            format!(
+                r##"<li hx-get="{base}/saved-search/{id}/emails" hx-target="#email-list" hx-swap="innerHTML">{name}<button class="remove" title="{remove}" hx-post="{base}/saved-search/{id}/delete" hx-target="#saved-searches" hx-swap="innerHTML" hx-trigger="click consume" hx-confirm="{confirm}">&times;</button></li>"##,
+                id = html_escape(&url_encode(&search.id)),
+                name = html_escape(&search.name),
+                remove = t("search.remove"),
+                confirm = tf("search.confirm_remove", &[("name", &html_escape(&search.name))])
+            )
+        })
+        .collect();
+    format!("<h3>{}</h3><ul>{}</ul>", t("search.saved"), items)
+}
+
+/// Out-of-band refresh of the saved searches after one was added
+pub fn saved_searches_oob(searches: &[SavedSearch]) -> String {
+    format!(
+        r#"<div id="saved-searches" class="saved-searches" hx-swap-oob="innerHTML">{}</div>"#,
+        saved_search_list(searches)
+    )
+}
+
+struct ThreadMessage<'a> {
+    id: &'a str,
+    open: bool,
+    from: String,
+    date: String,
+    preview: String,
+    /// The message as `email_view` shows it
+    view: String,
+}
+
+#[derive(Template)]
+#[template(path = "thread.html")]
+struct ThreadView<'a> {
+    thread_id: &'a str,
+    subject: &'a str,
+    count: String,
+    messages: Vec<ThreadMessage<'a>>,
+}
+
+/// A conversation, oldest first, each message collapsible. `messages` pairs
+/// each email with its BIMI-verified sender domain. The focused message and
+/// the newest one start expanded.
+pub fn thread_view(
+    messages: &[(Email, Option<String>)],
+    focus: &str,
+    mailboxes: &[Mailbox],
+    options: &ViewOptions,
+) -> String {
+    let subject = messages
+        .first()
+        .and_then(|(e, _)| e.subject.as_deref())
+        .unwrap_or(t("common.no_subject"));
+
+    let items = messages
+        .iter()
+        .enumerate()
+        .map(|(i, (email, verified_domain))| ThreadMessage {
+            id: &email.id,
+            open: email.id == focus || i + 1 == messages.len(),
+            from: email
+                .from
+                .as_ref()
+                .and_then(|f| f.first())
+                .map(format_address_short)
+                .unwrap_or_else(|| t("common.unknown").to_string()),
+            date: email.received_at.as_deref().map(dates::short).unwrap_or_default(),
+            preview: truncate(email.preview.as_deref().unwrap_or(""), 80),
+            view: email_view(email, mailboxes, verified_domain.as_deref(), options),
+        })
+        .collect();
+
+    render(ThreadView {
+        thread_id: messages.first().and_then(|(e, _)| e.thread_id.as_deref()).unwrap_or(""),
+        subject,
+        count: messages.len().to_string(),
+        messages: items,
+    })
+}
+
+/// Which of a conversation's messages match the words in its filter box,
+/// for app.js to show only those with the words marked, and how many they
+/// are. With no words, every message shows again.
+pub fn thread_matches(query: &str, matches: &[String], total: usize) -> String {
+    let query = query.trim();
+    let count = if query.is_empty() {
+        String::new()
+    } else {
+        tf("thread.matches", &[("count", &matches.len().to_string()), ("total", &total.to_string())])
+    };
+    format!(
+        r#"<span class="thread-matches" data-ids="{ids}" data-query="{query}">{count}</span>"#,
+        ids = html_escape(&matches.join(" ")),
+        query = html_escape(query),
+    )
+}
+
+/// Asks before destroying a message (one already in Trash, or when the
+/// account has no Trash mailbox)
+pub fn delete_confirmation(email_id: &str) -> String {
+    let base = base_path();
+    format!(
+        r##"<div class="confirm">
+  <p>{question}</p>
+  <button hx-post="{base}/email/{id}/delete?confirm=yes" hx-target="#email-view" hx-swap="innerHTML">{forever}</button>
+  <button hx-get="{base}/email/{id}" hx-target="#email-view" hx-swap="innerHTML">{cancel}</button>
+</div>"##,
+        id = html_escape(email_id),
+        question = t("delete.question"),
+        forever = t("delete.forever"),
+        cancel = t("common.cancel")
+    )
+}
+
+/// Out-of-band removal of a message's list row once it has left the mailbox
+pub fn email_row_remove_oob(email_id: &str) -> String {
+    format!(r#"<tr id="row-{}" hx-swap-oob="delete"></tr>"#, html_escape(email_id))
+}
+
+/// "Mark unread" / "Mark read" button in the email view. It replaces itself
+/// with the opposite action once the request completes.
+pub fn seen_toggle(email_id: &str, seen: bool) -> String {
+    let base = base_path();
+    let (action, label) = if seen {
+        ("unseen", t("common.mark_unread"))
+    } else {
+        ("seen", t("common.mark_read"))
+    };
+    format!(
+        r#"<a id="seen-toggle-{id}" hx-post="{base}/email/{id}/{action}" hx-target="this" hx-swap="outerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{label}</a>"#,
+        id = html_escape(email_id),
+        action = action,
+        label = label
+    )
+}
+
+/// "Pin" / "Unpin" in the email view, replacing itself with the opposite
+/// once done
+pub fn pin_toggle(email_id: &str, pinned: bool) -> String {
+    let base = base_path();
+    let (action, label) = if pinned {
+        ("unpin", t("email.unpin"))
+    } else {
+        ("pin", t("email.pin"))
+    };
+    format!(
+        r#"<a hx-post="{base}/email/{id}/{action}" hx-target="this" hx-swap="outerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{label}</a>"#,
+        id = html_escape(&url_encode(email_id)),
+        action = action,
+        label = label
+    )
+}
+
+/// "Mute" / "Unmute" for the conversation a message is in, replacing
+/// itself with the opposite once done
+pub fn mute_toggle(thread_id: &str, muted: bool) -> String {
+    let base = base_path();
+    let (action, label) = if muted {
+        ("unmute", t("email.unmute"))
+    } else {
+        ("mute", t("email.mute"))
+    };
+    format!(
+        r#"<a class="mute-toggle" hx-post="{base}/thread/{id}/{action}" hx-target="this" hx-swap="outerHTML" title="{title}" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{label}</a>"#,
+        id = html_escape(&url_encode(thread_id)),
+        action = action,
+        title = if muted { t("email.muted_hint") } else { t("email.mute_hint") },
+        label = label
+    )
+}
+
+/// How the email view should render a message body
+#[derive(Default)]
+pub struct ViewOptions<'a> {
+    /// Show the text body even when an HTML body exists
+    pub prefer_text: bool,
+    /// Load remote images in HTML bodies instead of blocking them
+    pub remote_images: bool,
+    /// Leave out replying and forwarding, for an account that can't send
+    pub no_sending: bool,
+    /// Put Reply all before Reply, as the default way to answer
+    pub reply_all_first: bool,
+    /// To change the body and add buttons
+    pub plugins: Option<&'a Plugins>,
+}
+
+pub fn email_view(
+    email: &Email,
+    mailboxes: &[Mailbox],
+    verified_domain: Option<&str>,
+    options: &ViewOptions,
+) -> String {
+    let base = base_path();
+    let from = email
+        .from
+        .as_ref()
+        .map(|addrs| format_addresses(addrs))
+        .unwrap_or_else(|| t("common.unknown").to_string());
+
+    let to = email
+        .to
+        .as_ref()
+        .map(|addrs| format_addresses(addrs))
+        .unwrap_or_else(|| t("common.unknown").to_string());
+
+    let cc = email.cc.as_ref().map(|addrs| format_addresses(addrs));
+
+    let subject = email
+        .subject
+        .as_deref()
+        .unwrap_or(t("common.no_subject"));
+
+    let date = email
+        .received_at
+        .as_deref()
+        .map(dates::full)
+        .unwrap_or_else(|| t("email.unknown_date").to_string());
+
+    let html_body = get_email_html_body(email);
+    let pgp_mime = pgp::mime(email);
+    // Found in the text body as it's rendered
+    let (mut inline_encrypted, mut inline_signed) = (false, false);
+    let body_html = match &html_body {
+        // There's nothing readable to show, only the notice
+        _ if matches!(pgp_mime, Some(pgp::Mime::Encrypted { .. })) => String::new(),
+        Some(html) if !options.prefer_text => {
+            let inline_images = email
+                .inline_parts()
+                .into_iter()
+                .filter_map(|part| {
+                    let cid = part.cid.as_deref()?.trim().trim_start_matches('<').trim_end_matches('>');
+                    let url = format!("{base}/email/{}/part/{}", url_encode(&email.id), url_encode(&part.part_id));
+                    Some((cid.to_string(), url))
+                })
+                .collect();
+            let policy = sanitize::Policy {
+                remote_images: options.remote_images,
+                inline_images,
+                redirect_links: true,
+            };
+            let sanitized = sanitize::sanitize(html, &policy);
+            let remote_notice = if sanitized.blocked_remote {
+                format!(
+                    r##"<div class="remote-notice">{blocked} <a hx-get="{base}/email/{id}?images=remote" hx-target="#email-view" hx-swap="innerHTML">{load}</a></div>"##,
+                    id = html_escape(&email.id),
+                    blocked = t("email.remote_blocked"),
+                    load = t("email.load_remote")
+                )
+            } else {
+                String::new()
+            };
+            format!(r#"{}<div class="body html">{}</div>"#, remote_notice, sanitized.html)
+        }
+        _ => {
+            let text = get_email_body(email);
+            let segments = pgp::segments(&text);
+            inline_encrypted = segments.iter().any(|s| matches!(s, pgp::Segment::Encrypted(_)));
+            inline_signed = segments.iter().any(|s| matches!(s, pgp::Segment::Signed { .. }));
+            plain_body(&segments)
+        }
+    };
+    let body_html = match options.plugins {
+        Some(plugins) => plugins.render_message(email, body_html),
+        None => body_html,
+    };
+    let pgp_notice = pgp_notice(email, pgp_mime.as_ref(), inline_encrypted, inline_signed);
+    let view_toggle = match (&html_body, options.prefer_text) {
+        (None, _) => String::new(),
+        (Some(_), true) => format!(
+            r##"<a hx-get="{base}/email/{id}" hx-target="#email-view" hx-swap="innerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{}</a>"##,
+            t("email.html"),
+            id = html_escape(&email.id)
+        ),
+        (Some(_), false) => format!(
+            r##"<a hx-get="{base}/email/{id}?view=text" hx-target="#email-view" hx-swap="innerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{}</a>"##,
+            t("email.plain_text"),
+            id = html_escape(&email.id)
+        ),
+    };
+
+    let cc_html = cc
+        .map(|c| format!("<dt>{}</dt><dd>{}</dd>", t("email.cc"), html_escape(&c)))
+        .unwrap_or_default();
+
+    let attachments = email
+        .attachments
+        .iter()
+        .flatten()
+        .filter(|part| !pgp::is_control_part(part))
+        // A bounce's report is shown as what it says instead
+        .filter(|part| email.bounce.is_none() || !bounce::is_report_part(part))
+        .filter_map(|part| {
+            let blob_id = part.blob_id.as_deref()?;
+            let url = format!(
+                "{base}/blob/{}?email={}",
+                html_escape(&url_encode(blob_id)),
+                html_escape(&url_encode(&email.id))
+            );
+            let name = html_escape(part.name.as_deref().unwrap_or(t("common.unnamed")));
+            let content_type = part.r#type.as_deref().unwrap_or("application/octet-stream");
+            Some(format!(
+                r#"<li><a href="{url}" download>{name}</a> <span class="meta">{content_type}, {size}</span>{preview}</li>"#,
+                preview = attachment_preview(part, &url, &name),
+                content_type = html_escape(content_type),
+                size = format_size(part.size)
+            ))
+        })
+        .collect::<Vec<_>>();
+    let attachments_html = if attachments.is_empty() {
+        String::new()
+    } else {
+        // One archive saves a click per file once there are several
+        let download_all = if attachments.len() > 1 {
+            format!(
+                r#" <a href="{base}/email/{id}/attachments.zip" download>{}</a>"#,
+                t("email.download_all"),
+                id = html_escape(&url_encode(&email.id))
+            )
+        } else {
+            String::new()
+        };
+        format!(
+            r#"<div class="attachments"><h4>{}{}</h4><ul>{}</ul></div>"#,
+            t("email.attachments"),
+            download_all,
+            attachments.concat()
+        )
+    };
+
+    // Only offered when there is an Archive mailbox the message isn't already in
+    let archive_button = mailboxes
+        .iter()
+        .find(|m| m.role.as_deref() == Some("archive"))
+        .filter(|m| !email.mailbox_ids.get(&m.id).copied().unwrap_or(false))
+        .map(|_| {
+            format!(
+                r##"<a hx-post="{base}/email/{id}/archive" hx-target="#email-view" hx-swap="innerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{}</a>"##,
+                t("common.archive"),
+                id = html_escape(&email.id)
+            )
+        })
+        .unwrap_or_default();
+
+    // Spam for now may be "not spam" once it's in the Junk mailbox
+    let in_junk = mailboxes
+        .iter()
+        .any(|m| m.role.as_deref() == Some("junk") && email.mailbox_ids.get(&m.id).copied().unwrap_or(false));
+    let junk_button = format!(
+        r##"<a hx-post="{base}/email/{id}/{action}" hx-target="#email-view" hx-swap="innerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{}</a>"##,
+        if in_junk { t("email.not_spam") } else { t("email.spam") },
+        id = html_escape(&email.id),
+        action = if in_junk { "not-spam" } else { "spam" }
+    );
+
+    let plugin_buttons: String = options
+        .plugins
+        .map(|plugins| plugins.message_actions(email))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(plugin, action)| {
+            format!(
+                r##"<a hx-post="{base}/email/{id}/plugin/{plugin}/{action}" hx-target="#email-view" hx-swap="innerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{label}</a>"##,
+                id = html_escape(&email.id),
+                plugin = html_escape(&url_encode(plugin)),
+                action = html_escape(&url_encode(&action.id)),
+                label = html_escape(&action.label)
+            )
+        })
+        .collect();
+
+    let reply_buttons = if options.no_sending {
+        String::new()
+    } else {
+        let button = |action: &str, label: &str, class: &str| {
+            format!(
+                r##"<a{class} hx-get="{base}/email/{id}/{action}" hx-target="#email-view" hx-swap="innerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{label}</a>"##,
+                id = html_escape(&email.id),
+            )
+        };
+        // The first is the one the `r` shortcut clicks (keys.js)
+        let (reply, reply_all) = (t("email.reply"), t("email.reply_all"));
+        let replies = if options.reply_all_first {
+            [button("reply-all", reply_all, r#" class="default-reply""#), button("reply", reply, "")]
+        } else {
+            [button("reply", reply, r#" class="default-reply""#), button("reply-all", reply_all, "")]
+        };
+        format!("{}\n  {}\n  {}", replies[0], replies[1], button("forward", t("email.forward"), ""))
+    };
+
+    let avatar = verified_domain
+        .map(|d| {
+            format!(
+                r#"<img class="avatar" src="{base}/bimi/{domain}" alt="" title="{title}"> "#,
+                domain = html_escape(d),
+                title = tf("email.verified", &[("domain", &html_escape(d))])
+            )
+        })
+        .unwrap_or_default();
+
+    format!(
+        r##"<div style="margin-bottom: 0.5rem;">
+  <a href="{base}/email/{id}/raw" target="_blank" style="font-size: 12px; color: var(--muted); text-decoration: none; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{raw}</a>
+  <a href="{base}/email/{id}/eml" download style="font-size: 12px; color: var(--muted); text-decoration: none; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{eml}</a>
+  <a href="{base}/email/{id}/pdf" target="_blank" style="font-size: 12px; color: var(--muted); text-decoration: none; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{pdf}</a>
+  {reply_buttons}
+  {seen_toggle}
+  {pin_toggle}
+  {mute_toggle}
+  {archive_button}
+  {junk_button}
+  {plugin_buttons}
+  <details class="snooze">
+    <summary>{snooze}</summary>
+    <form hx-post="{base}/email/{id}/snooze" hx-target="#email-view" hx-swap="innerHTML">
+      <button name="until" value="hour">{snooze_hour}</button>
+      <button name="until" value="tomorrow">{snooze_tomorrow}</button>
+      <button name="until" value="week">{snooze_week}</button>
+      <input type="datetime-local" name="at" aria-label="{snooze_custom}">
+      <button name="until" value="custom">{snooze_custom}</button>
+    </form>
+  </details>
+  <a hx-post="{base}/email/{id}/delete" hx-target="#email-view" hx-swap="innerHTML" style="font-size: 12px; color: var(--muted); cursor: pointer; border: 1px solid var(--border); padding: 2px 8px; background: var(--panel);">{delete}</a>
+  {view_toggle}
+</div>
+<form class="move-form" hx-post="{base}/email/{id}/move" hx-target="#email-view" hx-swap="innerHTML">
+  <select name="mailbox">{mailbox_options}</select>
+  <button name="mode" value="move">{move_label}</button>
+  <button name="mode" value="copy">{copy}</button>
+</form>
+<dl class="headers">
+  <dt>{from_label}</dt><dd>{avatar}{from}</dd>
+  <dt>{to_label}</dt><dd>{to}</dd>
+  {cc_html}
+  <dt>{subject_label}</dt><dd>{star} {subject}</dd>
+  <dt>{date_label}</dt><dd>{date}</dd>
+  {snoozed_html}
+  {smime_html}
+</dl>
+<details class="all-headers">
+  <summary hx-get="{base}/email/{id}/headers" hx-target="next pre" hx-trigger="click once">{all_headers}</summary>
+  <pre></pre>
+</details>
+{bounce_html}{invite_html}
+{attachments_html}
+<hr>
+{pgp_notice}
+{body_html}"##,
+        id = html_escape(&email.id),
+        avatar = avatar,
+        from = html_escape(&from),
+        to = html_escape(&to),
+        cc_html = cc_html,
+        subject = html_escape(subject),
+        star = flag_star(&email.id, email.keywords.get("$flagged").copied().unwrap_or(false), "view", false),
+        date = html_escape(&date),
+        smime_html = smime_status(email),
+        snoozed_html = snooze::until(email)
+            .map(|time| format!("<dt>{}</dt><dd>{}</dd>", t("email.snoozed"), html_escape(&dates::full_at(time))))
+            .unwrap_or_default(),
+        bounce_html = email.bounce.as_ref().map(|b| bounce_notice(b, options.no_sending)).unwrap_or_default(),
+        invite_html = email.invite.as_ref().map(|i| calendar_invite(i, &email.id, None)).unwrap_or_default(),
+        attachments_html = attachments_html,
+        mailbox_options = mailbox_options(mailboxes, |id| email.mailbox_ids.get(id).copied().unwrap_or(false)),
+        archive_button = archive_button,
+        junk_button = junk_button,
+        plugin_buttons = plugin_buttons,
+        snooze = t("email.snooze"),
+        snooze_hour = t("email.snooze_hour"),
+        snooze_tomorrow = t("email.snooze_tomorrow"),
+        snooze_week = t("email.snooze_week"),
+        snooze_custom = t("email.snooze_custom"),
+        seen_toggle = seen_toggle(&email.id, email.keywords.get("$seen").copied().unwrap_or(false)),
+        pin_toggle = pin_toggle(&email.id, email.keywords.get(PINNED_KEYWORD).copied().unwrap_or(false)),
+        mute_toggle = email.thread_id.as_deref().map(|id| mute_toggle(id, mute::is_muted(email))).unwrap_or_default(),
+        view_toggle = view_toggle,
+        pgp_notice = pgp_notice,
+        body_html = body_html,
+        raw = t("email.raw"),
+        eml = t("email.eml"),
+        all_headers = t("email.all_headers"),
+        pdf = t("email.pdf"),
+        reply_buttons = reply_buttons,
+        delete = t("common.delete"),
+        move_label = t("common.move"),
+        copy = t("email.copy"),
+        from_label = t("email.from"),
+        to_label = t("email.to"),
+        subject_label = t("email.subject"),
+        date_label = t("email.date")
+    )
+}
+
+/// Who a bounce says the message didn't reach and why, with the sent
+/// message to correct and send again while it is still there
+fn bounce_notice(bounce: &Bounce, no_sending: bool) -> String {
+    let base = base_path();
+    let failures: String = bounce
+        .failures
+        .iter()
+        .map(|f| {
+            let recipient = html_escape(&f.recipient);
+            let reason = html_escape(&f.reason);
+            format!("<li>{}</li>", tf("bounce.failed_to", &[("recipient", &recipient), ("reason", &reason)]))
+        })
+        .collect();
+    let resend = match &bounce.original_id {
+        Some(id) if !no_sending => format!(
+            r##"<p><button hx-get="{base}/email/{}/resend" hx-target="#email-view" hx-swap="innerHTML">{}</button></p>"##,
+            html_escape(&url_encode(id)),
+            t("bounce.resend")
+        ),
+        _ => String::new(),
+    };
+    format!(r#"<div class="bounce"><h4>{}</h4><ul>{}</ul>{}</div>"#, t("bounce.heading"), failures, resend)
+}
+
+/// The event a meeting invitation is for, with buttons to answer it when
+/// it asks for an answer. `sent_to` is the organizer just answered.
+pub fn calendar_invite(invite: &Invite, email_id: &str, sent_to: Option<&str>) -> String {
+    let base = base_path();
+    let heading = match invite.method.as_str() {
+        "CANCEL" => t("calendar.cancelled"),
+        "REPLY" => t("calendar.reply"),
+        _ => t("calendar.invitation"),
+    };
+    let mut rows = String::new();
+    let mut row = |label: &str, value: String| {
+        rows.push_str(&format!("<dt>{}</dt><dd>{}</dd>", label, value));
+    };
+    if let Some(when) = event_when(invite) {
+        row(t("calendar.when"), html_escape(&when));
+    }
+    if let Some(location) = invite.location.as_deref().filter(|l| !l.is_empty()) {
+        row(t("calendar.where"), html_escape(location));
+    }
+    if let Some(organizer) = &invite.organizer {
+        row(t("calendar.organizer"), html_escape(&person_name(organizer)));
+    }
+    if !invite.attendees.is_empty() {
+        let attendees: String = invite
+            .attendees
+            .iter()
+            .map(|a| {
+                let answer = match a.partstat.as_deref() {
+                    Some("ACCEPTED") => t("calendar.accepted"),
+                    Some("TENTATIVE") => t("calendar.tentatively_accepted"),
+                    Some("DECLINED") => t("calendar.declined"),
+                    _ => t("calendar.no_answer"),
+                };
+                format!(r#"<li>{} <span class="meta">({})</span></li>"#, html_escape(&person_name(a)), answer)
+            })
+            .collect();
+        row(t("calendar.attendees"), format!("<ul>{}</ul>", attendees));
+    }
+
+    let notice = match (sent_to, invite.method.as_str()) {
+        (Some(organizer), _) => format!(
+            r#"<p class="notice">{}</p>"#,
+            tf("calendar.sent", &[("organizer", &html_escape(organizer))])
+        ),
+        (None, "CANCEL") => format!("<p>{}</p>", t("calendar.cancelled_notice")),
+        (None, _) => String::new(),
+    };
+    let rsvp = if invite.is_request() && invite.organizer.is_some() {
+        format!(
+            r#"<form class="rsvp" hx-post="{base}/email/{id}/rsvp" hx-target="closest .invite" hx-swap="outerHTML">
+  <button name="partstat" value="ACCEPTED">{accept}</button>
+  <button name="partstat" value="TENTATIVE">{tentative}</button>
+  <button name="partstat" value="DECLINED">{decline}</button>
+</form>"#,
+            id = html_escape(email_id),
+            accept = t("calendar.accept"),
+            tentative = t("calendar.tentative"),
+            decline = t("calendar.decline")
+        )
+    } else {
+        String::new()
+    };
+
+    format!(
+        r#"<div class="invite">
+<h4>{heading}: {summary}</h4>
+<dl>{rows}</dl>
+{notice}{rsvp}
+</div>"#,
+        summary = html_escape(invite.summary.as_deref().unwrap_or(t("common.no_subject"))),
+    )
+}
+
+/// When an event is, in the reader's zone, or its dates if it lasts all day
+fn event_when(invite: &Invite) -> Option<String> {
+    let when = match (invite.start.as_ref()?, invite.end.as_ref()) {
+        (EventTime::At(start), Some(EventTime::At(end))) if end > start => {
+            format!("{} – {}", dates::full_at(*start), dates::full_at(*end))
+        }
+        (EventTime::At(start), _) => dates::full_at(*start),
+        (EventTime::Date { year, month, day }, end) => {
+            let first = dates::day(*year, *month, *day);
+            // DTEND is the day after the last one
+            let last = match end {
+                Some(EventTime::Date { year: y, month: m, day: d }) => {
+                    let (y, m, d) = dates::civil_from_days(dates::days_from_civil(*y, *m, *d) - 1);
+                    Some(dates::day(y, m, d)).filter(|last| *last != first)
+                }
+                _ => None,
+            };
+            match last {
+                Some(last) => format!("{} – {} ({})", first, last, t("calendar.all_day")),
+                None => format!("{} ({})", first, t("calendar.all_day")),
+            }
+        }
+    };
+    Some(when)
+}
+
+/// The agenda: the events from today to [`crate::calendar::DAYS`] days
+/// ahead, under the day they're on. Ones that began earlier and are still
+/// going are listed under today.
+pub fn calendar_agenda(occurrences: &[Occurrence]) -> String {
+    let today = dates::today();
+    let mut days: Vec<(i64, String)> = Vec::new();
+    for occurrence in occurrences {
+        let day = occurrence.day().max(today);
+        let when = match (&occurrence.start, &occurrence.end) {
+            (EventTime::At(start), Some(EventTime::At(end))) if dates::day_of(end - 1) == dates::day_of(*start) => {
+                format!("{} – {}", dates::clock(*start), dates::clock(*end))
+            }
+            (EventTime::At(start), _) if dates::day_of(*start) == day => dates::clock(*start),
+            (EventTime::At(_), _) => t("calendar.continued").to_string(),
+            (EventTime::Date { .. }, _) => t("calendar.all_day").to_string(),
+        };
+        let location = occurrence
+            .location
+            .as_deref()
+            .map(|location| format!(r#" <span class="location">{}</span>"#, html_escape(location)))
+            .unwrap_or_default();
+        let row = format!(
+            r#"<li><span class="when">{when}</span> <span class="title">{title}</span>{location}</li>"#,
+            when = html_escape(&when),
+            title = html_escape(if occurrence.title.is_empty() { t("calendar.untitled") } else { &occurrence.title }),
+            location = location
+        );
+        match days.last_mut() {
+            Some((last, rows)) if *last == day => rows.push_str(&row),
+            _ => days.push((day, row)),
+        }
+    }
+
+    let agenda: String = days
+        .iter()
+        .map(|(day, rows)| {
+            let (year, month, date) = dates::civil_from_days(*day);
+            format!(
+                r#"<h3>{heading}</h3>
+<ul>{rows}</ul>"#,
+                heading = html_escape(&dates::day(year, month, date)),
+                rows = rows
+            )
+        })
+        .collect();
+    let agenda = if agenda.is_empty() {
+        format!("<p>{}</p>", t("calendar.nothing"))
+    } else {
+        agenda
+    };
+
+    format!(
+        r##"<div class="settings agenda">
+<h2>{heading}</h2>
+{agenda}
+</div>"##,
+        heading = t("calendar.week"),
+        agenda = agenda
+    )
+}
+
+fn person_name(person: &Person) -> String {
+    match &person.name {
+        Some(name) => format!("{} <{}>", name, person.email),
+        None => person.email.clone(),
+    }
+}
+
+/// Header fields as they were in the message, one per line (folded ones
+/// staying folded), for telling how it was delivered
+pub fn all_headers(headers: &[EmailHeader]) -> String {
+    if headers.is_empty() {
+        return html_escape(t("email.no_headers"));
+    }
+    headers
+        .iter()
+        .map(|h| format!("{}:{}\n", html_escape(&h.name), html_escape(h.value.trim_end_matches(['\r', '\n']))))
+        .collect()
+}
+
+/// Raster image types a browser may be shown straight from a blob
+pub const INLINE_IMAGE_TYPES: &[&str] = &["image/png", "image/gif", "image/jpeg", "image/webp"];
+
+/// A thumbnail for an image attachment, which app.js expands on click, or
+/// the PDF viewer for a PDF. `url` and `name` come escaped. Images already
+/// placed in the HTML body by their cid aren't shown twice.
+fn attachment_preview(part: &BodyPart, url: &str, name: &str) -> String {
+    let content_type = part.r#type.as_deref().unwrap_or("").to_ascii_lowercase();
+    let placed = part.cid.is_some() && part.disposition.as_deref() == Some("inline");
+    if INLINE_IMAGE_TYPES.contains(&content_type.as_str()) && !placed {
+        format!(
+            r#"<img class="attachment-thumb" src="{url}&amp;preview=1" alt="{name}" title="{expand}" loading="lazy">"#,
+            expand = t("email.expand_image")
+        )
+    } else if content_type == "application/pdf" {
+        format!(
+            r#"<object class="attachment-pdf" data="{url}&amp;preview=1" type="application/pdf"><a href="{url}" download>{name}</a></object>"#
+        )
+    } else {
+        String::new()
+    }
+}
+
+/// Recently sent messages with where delivery to each recipient has got,
+/// so a bounce or a deferral doesn't go unnoticed. `emails` are the sent
+/// messages still around, for their subjects.
+pub fn outbox(submissions: &[EmailSubmission], emails: &[Email]) -> String {
+    let base = base_path();
+    let rows: String = submissions
+        .iter()
+        .map(|submission| {
+            let email = emails.iter().find(|e| e.id == submission.email_id);
+            let subject = match email {
+                Some(email) => format!(
+                    r##"<a hx-get="{base}/email/{}" hx-target="#email-view" hx-swap="innerHTML">{}</a>"##,
+                    html_escape(&url_encode(&email.id)),
+                    html_escape(email.subject.as_deref().unwrap_or(t("common.no_subject")))
+                ),
+                None => format!(r#"<span class="meta">{}</span>"#, t("outbox.deleted")),
+            };
+            let sent = submission.send_at.as_deref().map(dates::full).unwrap_or_default();
+            let status = match submission.undo_status.as_str() {
+                "pending" => t("outbox.pending"),
+                "canceled" => t("outbox.canceled"),
+                _ => t("outbox.final"),
+            };
+            // The envelope says who it actually went to; failing that, the
+            // headers, or whoever there is a delivery report for
+            let recipients: Vec<String> = match (&submission.envelope, email) {
+                (Some(envelope), _) => envelope.rcpt_to.iter().map(|a| a.email.clone()).collect(),
+                (None, Some(e)) => [&e.to, &e.cc, &e.bcc]
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .filter_map(|a| a.email.clone())
+                    .collect(),
+                (None, None) => submission.delivery_status.iter().flatten().map(|(to, _)| to.clone()).collect(),
+            };
+            let deliveries: String = recipients
+                .iter()
+                .map(|address| {
+                    let delivery = submission.delivery_status.as_ref().and_then(|statuses| {
+                        statuses
+                            .iter()
+                            .find(|(to, _)| to.eq_ignore_ascii_case(address))
+                            .map(|(_, status)| status)
+                    });
+                    let (class, label) = match delivery.map(|d| d.delivered.as_str()) {
+                        Some("yes") => ("delivered", t("outbox.delivered")),
+                        Some("queued") => ("queued", t("outbox.queued")),
+                        Some("no") => ("failed", t("outbox.failed")),
+                        Some(_) => ("unknown", t("outbox.handed_on")),
+                        None => ("unknown", t("outbox.no_status")),
+                    };
+                    // The server's reply only says something when it didn't go
+                    let reply = delivery
+                        .filter(|d| matches!(class, "queued" | "failed") && !d.smtp_reply.is_empty())
+                        .map(|d| format!(r#"<br><span class="meta">{}</span>"#, html_escape(&d.smtp_reply)))
+                        .unwrap_or_default();
+                    format!(
+                        r#"<li class="{}">{} &ndash; {}{}</li>"#,
+                        class,
+                        html_escape(address),
+                        label,
+                        reply
+                    )
+                })
+                .collect();
+            // Once a recipient refused it, the message can go again corrected
+            let bounced = submission.delivery_status.iter().flatten().any(|(_, d)| d.delivered == "no");
+            let resend = match email {
+                Some(email) if bounced => format!(
+                    r##"<button hx-get="{base}/email/{}/resend" hx-target="#email-view" hx-swap="innerHTML">{}</button>"##,
+                    html_escape(&url_encode(&email.id)),
+                    t("bounce.resend")
+                ),
+                _ => String::new(),
+            };
+            format!(
+                r#"<tr><td>{}</td><td>{}</td><td>{}</td><td><ul class="deliveries">{}</ul>{}</td></tr>"#,
+                html_escape(&sent),
+                subject,
+                status,
+                deliveries,
+                resend
+            )
+        })
+        .collect();
+    let table = if rows.is_empty() {
+        format!("<p>{}</p>", t("outbox.empty"))
+    } else {
+        format!(
+            "<table><thead><tr><th>{}</th><th>{}</th><th>{}</th><th>{}</th></tr></thead><tbody>{}</tbody></table>",
+            t("outbox.sent"),
+            t("outbox.subject"),
+            t("outbox.status"),
+            t("outbox.recipients"),
+            rows
+        )
+    };
+
+    format!(
+        r##"<div class="settings outbox">
+<h2>{heading}</h2>
+<p>{intro} <button hx-get="{base}/outbox" hx-target="#email-view" hx-swap="innerHTML">{refresh}</button></p>
+{table}
+</div>"##,
+        heading = t("outbox.heading"),
+        intro = t("outbox.intro"),
+        refresh = t("outbox.refresh"),
+        table = table
+    )
+}
+
+#[derive(Template)]
+#[template(path = "settings/retention.html")]
+struct RetentionSettings<'a> {
+    configured: bool,
+    /// What happens to Trash and Junk, and how often, already markup
+    trash: String,
+    junk: String,
+    schedule: String,
+    enabled: bool,
+    /// Newest first
+    log: Vec<PurgeLine<'a>>,
+}
+
+struct PurgeLine<'a> {
+    at: String,
+    mailbox: &'a str,
+    count: usize,
+    cutoff: String,
+}
+
+pub fn retention_settings(policy: &RetentionConfig, enabled: bool, log: &[PurgeRecord]) -> String {
+    let days = |d: Option<u32>| {
+        d.map(|d| tf("retention.older_than", &[("days", &d.to_string())]))
+            .unwrap_or_else(|| t("retention.kept").to_string())
+    };
+    let status = if enabled { t("retention.enabled") } else { t("retention.disabled") };
+    render(RetentionSettings {
+        configured: policy.is_configured(),
+        trash: tf("retention.trash", &[("age", &days(policy.trash_days))]),
+        junk: tf("retention.junk", &[("age", &days(policy.junk_days))]),
+        schedule: tf(
+            "retention.schedule",
+            &[("interval", &policy.interval_minutes.to_string()), ("status", status)],
+        ),
+        enabled,
+        log: log
+            .iter()
+            .rev()
+            .map(|r| PurgeLine {
+                at: dates::full(&r.at),
+                mailbox: &r.mailbox,
+                count: r.count,
+                cutoff: dates::full(&r.cutoff),
+            })
+            .collect(),
+    })
+}
+
+/// Result of the last folder change, shown above the management form
+pub enum Outcome<'a> {
+    Notice(&'a str),
+    Error(&'a str),
+}
+
+fn outcome_fragment(outcome: Option<Outcome>) -> String {
+    match outcome {
+        Some(Outcome::Notice(text)) => notice_fragment(text),
+        Some(Outcome::Error(text)) => error_fragment(text),
+        None => String::new(),
+    }
+}
+
+/// Folder management: create (optionally nested), rename, delete when empty
+#[derive(Template)]
+#[template(path = "settings/mailboxes.html")]
+struct MailboxSettings<'a> {
+    message: String,
+    rows: Vec<(usize, &'a Mailbox)>,
+    parents: String,
+}
+
+pub fn mailbox_settings(mailboxes: &[Mailbox], outcome: Option<Outcome>) -> String {
+    render(MailboxSettings {
+        message: outcome_fragment(outcome),
+        rows: mailbox_tree(mailboxes),
+        parents: mailbox_options(mailboxes, |_| false),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "settings/vacation.html")]
+struct VacationSettings<'a> {
+    message: String,
+    enabled: bool,
+    from_date: &'a str,
+    to_date: &'a str,
+    subject: &'a str,
+    text_body: &'a str,
+}
+
+/// Auto-reply settings. The date range is optional on either end; dates are
+/// edited as UTC since that is what VacationResponse stores.
+pub fn vacation_settings(vacation: &VacationResponse, outcome: Option<Outcome>) -> String {
+    // UTCDate "2024-06-01T09:00:00Z" -> datetime-local "2024-06-01T09:00"
+    fn local(date: &Option<String>) -> &str {
+        date.as_deref().map(|d| d.get(..16).unwrap_or(d)).unwrap_or("")
+    }
+    render(VacationSettings {
+        message: outcome_fragment(outcome),
+        enabled: vacation.is_enabled,
+        from_date: local(&vacation.from_date),
+        to_date: local(&vacation.to_date),
+        subject: vacation.subject.as_deref().unwrap_or(""),
+        text_body: vacation.text_body.as_deref().unwrap_or(""),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "settings/timezone.html")]
+struct TimezoneSettings<'a> {
+    message: String,
+    current: &'a str,
+    default: &'a str,
+}
+
+/// The user's time zone for dates, `current` being their own choice if
+/// they made one and `default` the server's
+pub fn timezone_settings(current: Option<&str>, default: &str, outcome: Option<Outcome>) -> String {
+    render(TimezoneSettings {
+        message: outcome_fragment(outcome),
+        current: current.unwrap_or(""),
+        default,
+    })
+}
+
+struct TotpSetup<'a> {
+    secret: &'a str,
+    /// In fours, for typing in
+    grouped: String,
+    /// The provisioning URI as an SVG
+    qr: String,
+}
+
+#[derive(Template)]
+#[template(path = "settings/two_factor.html")]
+struct TotpSettings<'a> {
+    message: String,
+    /// Without one, the user has it turned on
+    setup: Option<TotpSetup<'a>>,
+}
+
+/// Turning two-factor authentication off, or on with `setup`, the secret
+/// offered and its otpauth:// URI
+pub fn totp_settings(setup: Option<(&str, &str)>, outcome: Option<Outcome>) -> String {
+    render(TotpSettings {
+        message: outcome_fragment(outcome),
+        setup: setup.map(|(secret, uri)| TotpSetup {
+            secret,
+            grouped: secret
+                .as_bytes()
+                .chunks(4)
+                .map(|group| String::from_utf8_lossy(group))
+                .collect::<Vec<_>>()
+                .join(" "),
+            qr: QrCode::encode(uri).map(|code| code.svg()).unwrap_or_default(),
+        }),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "settings/index.html")]
+struct SettingsIndex {
+    vacation: bool,
+    signatures: bool,
+    filters: bool,
+    two_factor: bool,
+    usage: Vec<String>,
+}
+
+/// Links to the settings pages the account's server has what's needed
+/// for, and the two-factor one if it is offered, and how much of its
+/// quotas the account uses
+pub fn settings_index(account: &MailAccount, quotas: &[Quota], two_factor: bool) -> String {
+    let usage = quotas
+        .iter()
+        .map(|quota| {
+            let amount = |n: u64| match quota.resource_type.as_str() {
+                "octets" => format_size(n),
+                _ => n.to_string(),
+            };
+            let used = tf(
+                "settings.quota_used",
+                &[("used", &amount(quota.used)), ("limit", &amount(quota.hard_limit))],
+            );
+            match quota.name.as_str() {
+                "" => used,
+                name => format!("{}: {}", name, used),
+            }
+        })
+        .collect();
+    render(SettingsIndex {
+        vacation: account.has_vacation,
+        signatures: account.has_submission,
+        filters: account.has_sieve,
+        two_factor,
+        usage,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "settings/display.html")]
+struct DisplaySettings {
+    compact: bool,
+    list_height: u8,
+    min: u8,
+    max: u8,
+    hint: String,
+}
+
+/// The list's density and how much of the height it takes
+pub fn display_settings(prefs: &Prefs) -> String {
+    let (min, max) = (*LIST_HEIGHTS.start(), *LIST_HEIGHTS.end());
+    render(DisplaySettings {
+        compact: prefs.density == Density::Compact,
+        list_height: prefs.list_height.unwrap_or(DEFAULT_LIST_HEIGHT),
+        min,
+        max,
+        hint: tf("display.list_height_hint", &[("min", &min.to_string()), ("max", &max.to_string())]),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "settings/replies.html")]
+struct ReplySettings<'a> {
+    message: String,
+    prefs: &'a ReplyPrefs,
+    bottom: bool,
+    default_prefix: &'static str,
+    max_prefix: usize,
+}
+
+/// How replies are started: quoting, where the reply goes and which reply
+/// button comes first
+pub fn reply_settings(prefs: &ReplyPrefs, outcome: Option<Outcome>) -> String {
+    render(ReplySettings {
+        message: outcome_fragment(outcome),
+        prefs,
+        bottom: prefs.posting == Posting::Bottom,
+        default_prefix: DEFAULT_QUOTE_PREFIX,
+        max_prefix: MAX_QUOTE_PREFIX,
+    })
+}
+
+struct MutedRow<'a> {
+    thread_id: &'a str,
+    subject: &'a str,
+    from: String,
+    date: String,
+}
+
+#[derive(Template)]
+#[template(path = "settings/muted.html")]
+struct MutedSettings<'a> {
+    rows: Vec<MutedRow<'a>>,
+}
+
+/// The muted threads, each by its latest muted message, with a button to
+/// unmute it
+pub fn muted_settings(emails: &[Email]) -> String {
+    let rows = emails
+        .iter()
+        .filter_map(|email| {
+            Some(MutedRow {
+                thread_id: email.thread_id.as_deref()?,
+                subject: email.subject.as_deref().unwrap_or(t("common.no_subject")),
+                from: email
+                    .from
+                    .as_ref()
+                    .and_then(|f| f.first())
+                    .map(format_address_short)
+                    .unwrap_or_else(|| t("common.unknown").to_string()),
+                date: email.received_at.as_deref().map(dates::full).unwrap_or_default(),
+            })
+        })
+        .collect();
+    render(MutedSettings { rows })
+}
+
+/// One of the user's sessions, as listed for them to sign out
+pub struct SignedInSession {
+    pub id: String,
+    /// The session the list is being shown to
+    pub current: bool,
+    pub device: Device,
+    /// Seconds since the epoch
+    pub created: u64,
+    pub last_active: u64,
+}
+
+struct SessionRow<'a> {
+    id: &'a str,
+    current: bool,
+    device: String,
+    address: String,
+    created: String,
+    last_active: String,
+}
+
+#[derive(Template)]
+#[template(path = "settings/sessions.html")]
+struct SessionSettings<'a> {
+    message: String,
+    rows: Vec<SessionRow<'a>>,
+}
+
+/// Where the user is signed in, oldest first, each with a button to sign
+/// it out but the one looking
+pub fn session_settings(sessions: &[SignedInSession], outcome: Option<Outcome>) -> String {
+    let rows = sessions
+        .iter()
+        .map(|session| SessionRow {
+            id: &session.id,
+            current: session.current,
+            device: match &session.device {
+                Device { api: true, .. } => t("sessions.api_token").to_string(),
+                Device { user_agent: Some(agent), .. } => agent.clone(),
+                Device { user_agent: None, .. } => t("sessions.unknown_device").to_string(),
+            },
+            address: session
+                .device
+                .address
+                .map(|address| address.to_string())
+                .unwrap_or_else(|| t("sessions.unknown_address").to_string()),
+            created: dates::full_at(session.created as i64),
+            last_active: dates::full_at(session.last_active as i64),
+        })
+        .collect();
+    render(SessionSettings {
+        message: outcome_fragment(outcome),
+        rows,
+    })
+}
+
+struct SignatureField<'a> {
+    identity: &'a Identity,
+    signature: String,
+    html_signature: String,
+    /// Whether it was typed here rather than taken from the server
+    custom: bool,
+}
+
+#[derive(Template)]
+#[template(path = "settings/signatures.html")]
+struct SignatureSettings<'a> {
+    message: String,
+    fields: Vec<SignatureField<'a>>,
+}
+
+/// A text and an HTML signature for each identity, those typed here if
+/// there are any and the server's otherwise
+pub fn signature_settings(identities: &[Identity], prefs: &Prefs, outcome: Option<Outcome>) -> String {
+    let fields = identities
+        .iter()
+        .map(|identity| SignatureField {
+            identity,
+            signature: prefs
+                .signatures
+                .get(&identity.id)
+                .cloned()
+                .unwrap_or_else(|| identity_signature(identity)),
+            html_signature: prefs
+                .html_signatures
+                .get(&identity.id)
+                .cloned()
+                .unwrap_or_else(|| identity.html_signature.clone().unwrap_or_default()),
+            custom: prefs.signatures.contains_key(&identity.id) || prefs.html_signatures.contains_key(&identity.id),
+        })
+        .collect();
+    render(SignatureSettings {
+        message: outcome_fragment(outcome),
+        fields,
+    })
+}
+
+struct ScriptLine<'a> {
+    id: &'a str,
+    name: &'a str,
+    active: bool,
+}
+
+#[derive(Template)]
+#[template(path = "settings/filters.html")]
+struct SieveScriptList<'a> {
+    message: String,
+    scripts: Vec<ScriptLine<'a>>,
+    any_active: bool,
+}
+
+/// The account's Sieve scripts, with the active one marked. Only one can be
+/// active at a time, and the server won't delete it while it is.
+pub fn sieve_script_list(scripts: &[SieveScript], outcome: Option<Outcome>) -> String {
+    render(SieveScriptList {
+        message: outcome_fragment(outcome),
+        scripts: scripts
+            .iter()
+            .map(|script| ScriptLine {
+                id: &script.id,
+                name: script.name.as_deref().unwrap_or(t("common.unnamed")),
+                active: script.is_active,
+            })
+            .collect(),
+        any_active: scripts.iter().any(|s| s.is_active),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "settings/filter_editor.html")]
+struct SieveScriptEditor<'a> {
+    message: String,
+    id: Option<&'a str>,
+    name: &'a str,
+    text: &'a str,
+}
+
+/// Edit a Sieve script's name and source. `id` is `None` for a new script.
+pub fn sieve_script_editor(id: Option<&str>, name: &str, text: &str, outcome: Option<Outcome>) -> String {
+    render(SieveScriptEditor {
+        message: outcome_fragment(outcome),
+        id,
+        name,
+        text,
+    })
+}
+
+/// Out-of-band replacement of the whole sidebar mailbox list, after folders
+/// were added, renamed or removed
+pub fn mailbox_list_oob(mailboxes: &[Mailbox], collapsed: &HashSet<String>) -> String {
+    format!(
+        r#"<div id="mailbox-list" hx-swap-oob="innerHTML">{}</div>"#,
+        mailbox_list(mailboxes, collapsed, None)
+    )
+}
+
+/// Field values for the compose form, kept as typed so a failed send can
+/// re-render the form without losing anything
+#[derive(Default)]
+pub struct ComposeForm {
+    pub identity_id: String,
+    pub to: String,
+    pub cc: String,
+    pub bcc: String,
+    pub subject: String,
+    pub body: String,
+    /// Space-separated Message-IDs carried through hidden fields when replying
+    pub in_reply_to: String,
+    pub references: String,
+    pub attachments: Vec<OutgoingAttachment>,
+    /// Id of the saved draft being edited, replaced on the next save or send
+    pub draft_id: String,
+}
+
+impl ComposeForm {
+    /// A blank message from the default (first) identity, with its signature
+    pub fn new(identities: &[Identity]) -> Self {
+        let identity = identities.first();
+        ComposeForm {
+            identity_id: identity.map(|i| i.id.clone()).unwrap_or_default(),
+            body: identity.map(signature_block).unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// Re-open a saved draft for editing
+    pub fn from_draft(email: &Email, identities: &[Identity]) -> Self {
+        let from = email
+            .from
+            .as_ref()
+            .and_then(|f| f.first())
+            .and_then(|a| a.email.as_deref());
+        let identity_id = identities
+            .iter()
+            .find(|i| from.is_some_and(|f| i.email.eq_ignore_ascii_case(f)))
+            .or(identities.first())
+            .map(|i| i.id.clone())
+            .unwrap_or_default();
+        let addresses = |list: &Option<Vec<EmailAddress>>| {
+            list.as_deref().map(editable_addresses).unwrap_or_default()
+        };
+
+        ComposeForm {
+            identity_id,
+            to: addresses(&email.to),
+            cc: addresses(&email.cc),
+            bcc: addresses(&email.bcc),
+            subject: email.subject.clone().unwrap_or_default(),
+            body: get_email_body(email),
+            in_reply_to: email.in_reply_to.clone().unwrap_or_default().join(" "),
+            references: email.references.clone().unwrap_or_default().join(" "),
+            attachments: outgoing_attachments(email),
+            draft_id: email.id.clone(),
+        }
+    }
+
+    /// A sent message as it went, to correct and send as a new one after
+    /// it bounced
+    pub fn resend(email: &Email, identities: &[Identity]) -> Self {
+        ComposeForm {
+            draft_id: String::new(),
+            ..Self::from_draft(email, identities)
+        }
+    }
+
+    /// Pre-fill a reply: recipients from Reply-To/From (plus the other To/Cc
+    /// addresses for reply-all), "Re:" subject, threading headers, and the
+    /// body quoted above or below the signature as `prefs` say
+    pub fn reply(email: &Email, identities: &[Identity], reply_all: bool, prefs: &ReplyPrefs) -> Self {
+        let is_mine = |a: &EmailAddress| {
+            a.email.as_deref().is_some_and(|addr| {
+                identities.iter().any(|i| i.email.eq_ignore_ascii_case(addr))
+            })
+        };
+        let addresses = |list: &Option<Vec<EmailAddress>>| list.clone().unwrap_or_default();
+        let original_to = addresses(&email.to);
+        let original_cc = addresses(&email.cc);
+
+        // Reply from whichever identity the message was addressed to
+        let identity = original_to
+            .iter()
+            .chain(&original_cc)
+            .find_map(|a| {
+                let addr = a.email.as_deref()?;
+                identities.iter().find(|i| i.email.eq_ignore_ascii_case(addr))
+            })
+            .or(identities.first());
+        let signature = identity.map(signature_block).unwrap_or_default();
+        let identity_id = identity.map(|i| i.id.clone()).unwrap_or_default();
+
+        let from = addresses(&email.from);
+        let to = if from.iter().any(is_mine) {
+            // Replying to our own sent message goes back to its recipients
+            original_to.clone()
+        } else {
+            email.reply_to.clone().filter(|r| !r.is_empty()).unwrap_or(from)
+        };
+        let mut cc = Vec::new();
+        if reply_all {
+            let same = |a: &EmailAddress, b: &EmailAddress| match (&a.email, &b.email) {
+                (Some(x), Some(y)) => x.eq_ignore_ascii_case(y),
+                _ => false,
+            };
+            for addr in original_to.iter().chain(&original_cc) {
+                if !is_mine(addr) && !to.iter().chain(&cc).any(|a| same(a, addr)) {
+                    cc.push(addr.clone());
+                }
+            }
+        }
+
+        let subject = email.subject.as_deref().unwrap_or("");
+        let subject = if has_prefix(subject, &["re:", t("compose.reply_prefix")]) {
+            subject.to_string()
+        } else {
+            format!("{} {}", t("compose.reply_prefix"), subject)
+        };
+
+        let sender = email
+            .from
+            .as_deref()
+            .map(format_addresses)
+            .unwrap_or_else(|| t("common.unknown").to_string());
+        let date = email.received_at.as_deref().map(dates::full).unwrap_or_default();
+
+        let message_id = email.message_id.clone().unwrap_or_default();
+        let mut references = email.references.clone().unwrap_or_default();
+        references.extend(message_id.iter().cloned());
+
+        let quoted = format!(
+            "{}\n{}",
+            tf("compose.attribution", &[("date", &date), ("sender", &sender)]),
+            quote_body(email, &prefs.quote_prefix)
+        );
+        let body = match prefs.posting {
+            _ if !prefs.include_original => signature,
+            Posting::Top => format!("{}\n\n{}", signature, quoted),
+            // The signature's blank lines leave room to write in below
+            Posting::Bottom => format!("{}{}", quoted, signature),
+        };
+
+        ComposeForm {
+            identity_id,
+            to: editable_addresses(&to),
+            cc: editable_addresses(&cc),
+            subject,
+            body,
+            in_reply_to: message_id.join(" "),
+            references: references.join(" "),
+            ..Default::default()
+        }
+    }
+
+    /// Pre-fill a forward: "Fwd:" subject, the original headers and quoted
+    /// body, and the original attachments re-used by blob id
+    pub fn forward(email: &Email, identities: &[Identity]) -> Self {
+        let subject = email.subject.as_deref().unwrap_or("");
+        let subject = if has_prefix(subject, &["fwd:", t("compose.forward_prefix")]) {
+            subject.to_string()
+        } else {
+            format!("{} {}", t("compose.forward_prefix"), subject)
+        };
+
+        let addresses = |list: &Option<Vec<EmailAddress>>| {
+            list.as_deref().map(format_addresses).unwrap_or_default()
+        };
+        let mut header = format!(
+            "---------- {} ----------\n{} {}\n{} {}\n{} {}\n{} {}\n",
+            t("compose.forwarded"),
+            t("email.from"),
+            addresses(&email.from),
+            t("email.date"),
+            email.received_at.as_deref().map(dates::full).unwrap_or_default(),
+            t("email.subject"),
+            email.subject.as_deref().unwrap_or(""),
+            t("email.to"),
+            addresses(&email.to)
+        );
+        if email.cc.as_ref().is_some_and(|cc| !cc.is_empty()) {
+            header.push_str(&format!("{} {}\n", t("email.cc"), addresses(&email.cc)));
+        }
+
+        let identity = identities.first();
+        ComposeForm {
+            identity_id: identity.map(|i| i.id.clone()).unwrap_or_default(),
+            subject,
+            body: format!(
+                "{}\n\n{}\n{}",
+                identity.map(signature_block).unwrap_or_default(),
+                header,
+                quote_body(email, DEFAULT_QUOTE_PREFIX)
+            ),
+            attachments: outgoing_attachments(email),
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether `subject` already starts with one of `prefixes`, such as "Re:"
+/// or the reader's own word for it
+fn has_prefix(subject: &str, prefixes: &[&str]) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| subject.get(..prefix.len()).is_some_and(|p| p.eq_ignore_ascii_case(prefix)))
+}
+
+/// An identity's signature as text: its textSignature, or the text of its
+/// htmlSignature if that's all it has. A "-- " separator already in it is
+/// taken off, since the compose form adds its own.
+pub fn identity_signature(identity: &Identity) -> String {
+    let signature = match identity.text_signature.as_deref().map(str::trim_end) {
+        Some(signature) if !signature.is_empty() => signature.to_string(),
+        _ => identity.html_signature.as_deref().map(sanitize::to_text).unwrap_or_default(),
+    };
+    match signature.split_once('\n') {
+        Some((separator, rest)) if separator.trim_end() == "--" => rest.to_string(),
+        _ => signature,
+    }
+}
+
+/// Two blank lines for the message, then the identity's signature behind
+/// the conventional "-- " separator. Just the blank lines if it has none.
+fn signature_block(identity: &Identity) -> String {
+    match identity_signature(identity) {
+        signature if !signature.is_empty() => format!("\n\n-- \n{}", signature),
+        _ => "\n\n".to_string(),
+    }
+}
+
+/// The message typed as `text` in HTML, with the identity's HTML signature
+/// in place of its text one. `None`, for the text to go alone, when the
+/// identity has no HTML signature or its signature was taken out.
+pub fn html_body(text: &str, identity: &Identity) -> Option<String> {
+    let html_signature = identity.html_signature.as_deref().filter(|html| !html.trim().is_empty())?;
+    let signature = identity_signature(identity);
+    if signature.is_empty() {
+        return None;
+    }
+    let block = format!("-- \n{}", signature);
+    let at = text.rfind(&block)?;
+    let paragraph = |text: &str| html_escape(text).replace('\n', "<br>\n");
+    Some(format!(
+        "<div>{}</div>\n<div class=\"signature\">-- <br>\n{}</div>\n<div>{}</div>",
+        paragraph(&text[..at]),
+        sanitize::signature(html_signature),
+        paragraph(&text[at + block.len()..])
+    ))
+}
+
+/// The message's attachments as references to their existing blobs
+fn outgoing_attachments(email: &Email) -> Vec<OutgoingAttachment> {
+    email
+        .attachments
+        .iter()
+        .flatten()
+        .filter_map(|part| {
+            Some(OutgoingAttachment {
+                blob_id: part.blob_id.clone()?,
+                r#type: part
+                    .r#type
+                    .clone()
+                    .unwrap_or_else(|| "application/octet-stream".to_string()),
+                name: part.name.clone(),
+                size: part.size,
+            })
+        })
+        .collect()
+}
+
+/// The message's text body with every line behind `prefix`, such as "> ",
+/// less its trailing spaces on empty lines
+fn quote_body(email: &Email, prefix: &str) -> String {
+    get_email_body(email)
+        .lines()
+        .map(|line| if line.is_empty() { format!("{}\n", prefix.trim_end()) } else { format!("{}{}\n", prefix, line) })
+        .collect()
+}
+
+
+/// The recipient suggestions for a field; empty when there are none, which
+/// hides the list
+pub fn contact_suggestions(addresses: &[EmailAddress]) -> String {
+    addresses
+        .iter()
+        .map(|address| {
+            format!(
+                r#"<button type="button" data-address="{value}">{label}</button>"#,
+                value = html_escape(&editable_addresses(std::slice::from_ref(address))),
+                label = html_escape(&address.to_string())
+            )
+        })
+        .collect()
+}
+
+/// The chips for the entries of a recipient field, each with a button
+/// taking it out and, for a typo, one putting in the fix. The buttons carry
+/// the whole field as it would be after, for `app.js` to put in place.
+pub fn recipient_chips(chips: &[Chip]) -> String {
+    let field_with = |i: usize, replacement: Option<&str>| {
+        let entries = chips
+            .iter()
+            .enumerate()
+            .filter_map(|(j, chip)| if j == i { replacement } else { Some(chip.entry.as_str()) });
+        html_escape(&entries.collect::<Vec<_>>().join(", "))
+    };
+    chips
+        .iter()
+        .enumerate()
+        .map(|(i, chip)| {
+            let (class, title) = match &chip.problem {
+                None => ("", String::new()),
+                Some(Problem::Invalid) => (" invalid", t("compose.chip_invalid").to_string()),
+                Some(Problem::Duplicate) => (" duplicate", t("compose.chip_duplicate").to_string()),
+                Some(Problem::Typo(_)) => (" typo", t("compose.chip_typo").to_string()),
+            };
+            let fix = match &chip.problem {
+                Some(Problem::Typo(fixed)) => format!(
+                    r#"<button type="button" class="chip-fix" data-value="{value}">{label}</button>"#,
+                    value = field_with(i, Some(fixed)),
+                    label = html_escape(&tf("compose.chip_fix", &[("address", fixed)]))
+                ),
+                _ => String::new(),
+            };
+            format!(
+                r#"<span class="chip{class}" title="{title}">{entry}<button type="button" class="chip-remove" data-value="{value}" title="{remove}">&times;</button>{fix}</span>"#,
+                title = html_escape(&title),
+                entry = html_escape(&chip.entry),
+                value = field_with(i, None),
+                remove = t("compose.chip_remove"),
+            )
+        })
+        .collect()
+}
+
+struct IdentityOption<'a> {
+    id: &'a str,
+    /// What the form swaps in when this identity is picked
+    signature: String,
+    selected: bool,
+    label: String,
+}
+
+struct AttachmentLine<'a> {
+    /// The attachment as JSON, for the form to post back
+    value: String,
+    name: &'a str,
+    size: String,
+}
+
+impl<'a> AttachmentLine<'a> {
+    fn new(attachment: &'a OutgoingAttachment) -> Self {
+        AttachmentLine {
+            value: serde_json::to_string(attachment).unwrap_or_default(),
+            name: attachment.name.as_deref().unwrap_or(t("common.unnamed")),
+            size: format_size(attachment.size),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "compose_attachment.html")]
+struct ComposeAttachment<'a> {
+    attachment: AttachmentLine<'a>,
+}
+
+/// An attachment just uploaded, for the compose form's list
+pub fn compose_attachment(attachment: &OutgoingAttachment) -> String {
+    render(ComposeAttachment {
+        attachment: AttachmentLine::new(attachment),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "compose.html")]
+struct ComposePage<'a> {
+    form: &'a ComposeForm,
+    options: Vec<IdentityOption<'a>>,
+    /// The chosen identity's
+    signature: String,
+    attachments: Vec<AttachmentLine<'a>>,
+    error: Option<&'a str>,
+}
+
+pub fn compose_page(identities: &[Identity], form: &ComposeForm, error: Option<&str>) -> String {
+    let options = identities
+        .iter()
+        .map(|i| IdentityOption {
+            id: &i.id,
+            signature: signature_block(i),
+            selected: i.id == form.identity_id,
+            label: if i.name.is_empty() {
+                i.email.clone()
+            } else {
+                format!("{} <{}>", i.name, i.email)
+            },
+        })
+        .collect();
+    let attachments = form.attachments.iter().map(AttachmentLine::new).collect();
+
+    render(ComposePage {
+        form,
+        options,
+        signature: identities
+            .iter()
+            .find(|i| i.id == form.identity_id)
+            .or(identities.first())
+            .map(signature_block)
+            .unwrap_or_default(),
+        attachments,
+        error,
+    })
+}
+
+/// A view written as an askama template under `templates/`. Rendering
+/// only writes to a String, with filters that can't fail, so it can't
+/// either.
+fn render(view: impl Template) -> String {
+    view.render().expect("views render into a String")
+}
+
+pub fn notice_fragment(message: &str) -> String {
+    format!(r#"<div class="notice">{}</div>"#, html_escape(message))
+}
+
+pub fn error_fragment(message: &str) -> String {
+    format!(r#"<div class="error">{}</div>"#, html_escape(message))
+}
+
+/// An error shown over the page until dismissed or timed out (app.js),
+/// added out of band to the page's `#toasts`
+pub fn error_toast_oob(message: &str) -> String {
+    format!(
+        r#"<div id="toasts" hx-swap-oob="beforeend"><div class="toast error" role="alert">{}</div></div>"#,
+        html_escape(message)
+    )
+}
+
+// Helper functions
+
+thread_local! {
+    /// `server.base_path` for this thread's request
+    static BASE_PATH: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Write links back to the app under `base_path`, where a reverse proxy
+/// mounts it, for the rest of the current request
+pub fn mount(base_path: &str) {
+    BASE_PATH.with(|current| {
+        if *current.borrow() != base_path {
+            *current.borrow_mut() = base_path.to_string();
+        }
+    });
+}
+
+/// What every link back to the app starts with: "" when it is mounted at
+/// `/`. The config only takes URL-safe characters here, so it needs no
+/// escaping.
+pub fn base_path() -> String {
+    BASE_PATH.with(|current| current.borrow().clone())
+}
+
+/// `url` from the config or a plugin, with a root-relative one put under
+/// the base path like the app's own links
+fn under_base_path(url: &str) -> String {
+    if url.starts_with('/') && !url.starts_with("//") {
+        format!("{}{}", base_path(), url)
+    } else {
+        url.to_string()
+    }
+}
+
+/// `html` loading the stylesheet at `path` in the app last in its head, so
+/// its rules win over the built-in ones
+pub fn with_stylesheet(html: &str, path: &str) -> String {
+    html.replacen(
+        "</head>",
+        &format!(r#"  <link rel="stylesheet" href="{}{}">
+</head>"#, base_path(), html_escape(path)),
+        1,
+    )
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters
+pub fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn format_address_short(addr: &EmailAddress) -> String {
+    addr.name
+        .as_ref()
+        .or(addr.email.as_ref())
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| t("common.unknown").to_string())
+}
+
+pub fn format_addresses(addrs: &[EmailAddress]) -> String {
+    addrs
+        .iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Address list in a form EmailAddress::parse_list reads back unchanged,
+/// quoting display names that contain separators
+fn editable_addresses(addrs: &[EmailAddress]) -> String {
+    addrs
+        .iter()
+        .map(|a| match (&a.name, &a.email) {
+            (Some(name), Some(email)) if name.contains([',', ';', '"', '<', '>']) => {
+                format!("\"{}\" <{}>", name.replace('"', ""), email)
+            }
+            _ => a.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Human-readable byte count, e.g. "1.5 MB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn truncate(s: &str, max_len: usize) -> String {
+    let char_count = s.chars().count();
+    if char_count <= max_len {
+        s.to_string()
+    } else {
+        let truncated: String = s.chars().take(max_len).collect();
+        format!("{}...", truncated)
+    }
+}
+
+/// The HTML body parts joined together, if the message has any that are
+/// actually HTML (servers may list text/plain parts in htmlBody)
+/// The header row for the server's S/MIME verdict (RFC 9219), empty when
+/// the message isn't S/MIME or the server doesn't check
+fn smime_status(email: &Email) -> String {
+    let Some(status) = email.smime_status.as_deref() else {
+        return String::new();
+    };
+    let (class, label) = if status.ends_with("/verified") {
+        ("verified", t("smime.verified"))
+    } else if status.ends_with("/failed") {
+        ("failed", t("smime.failed"))
+    } else if status.starts_with("signed") || status.starts_with("encrypted+signed") {
+        ("signed", t("smime.signed"))
+    } else {
+        ("unknown", t("smime.unknown"))
+    };
+    let mut details = Vec::new();
+    if status.starts_with("encrypted") {
+        details.push(t("smime.encrypted").to_string());
+    }
+    // A signature only verifies if its certificate is the From address's
+    let signer = email.from.iter().flatten().find_map(|a| a.email.as_deref());
+    if let (Some(signer), "verified") = (signer, class) {
+        details.push(tf("smime.signer", &[("signer", &html_escape(signer))]));
+    }
+    if let Some(at) = email.smime_verified_at.as_deref() {
+        details.push(tf("smime.checked", &[("when", &html_escape(&dates::full(at)))]));
+    }
+    let errors: String = email
+        .smime_errors
+        .iter()
+        .flatten()
+        .map(|e| format!("<li>{}</li>", html_escape(e)))
+        .collect();
+    format!(
+        r#"<dt>{}</dt><dd><span class="smime {}">{}</span> {}{}</dd>"#,
+        t("smime.label"),
+        class,
+        label,
+        details.join(", "),
+        if errors.is_empty() { String::new() } else { format!(r#"<ul class="smime-errors">{}</ul>"#, errors) }
+    )
+}
+
+/// A text body as `<pre>` blocks, with inline PGP armor tucked away in
+/// collapsed sections rather than shown as text
+fn plain_body(segments: &[pgp::Segment]) -> String {
+    let armor = |label: &str, armor: &str| {
+        format!(
+            r#"<details class="pgp-block"><summary>{}</summary><pre>{}</pre></details>"#,
+            label,
+            html_escape(armor)
+        )
+    };
+    segments
+        .iter()
+        .map(|segment| match segment {
+            pgp::Segment::Text(text) if segments.len() > 1 && text.trim().is_empty() => String::new(),
+            pgp::Segment::Text(text) => format!(r#"<pre class="body">{}</pre>"#, html_escape(text)),
+            pgp::Segment::Encrypted(block) => armor(t("pgp.encrypted_block"), block),
+            pgp::Segment::Signed { text, signature } => format!(
+                r#"<pre class="body">{}</pre>{}"#,
+                html_escape(text),
+                armor(t("pgp.signature"), signature)
+            ),
+        })
+        .collect()
+}
+
+/// The banner saying a message is OpenPGP encrypted or signed, since it
+/// can't be decrypted or checked here
+fn pgp_notice(email: &Email, mime: Option<&pgp::Mime>, inline_encrypted: bool, inline_signed: bool) -> String {
+    let base = base_path();
+    // The part to take to OpenPGP software
+    let download = |part: Option<&BodyPart>, label: &str| {
+        part.and_then(|part| part.blob_id.as_deref())
+            .map(|blob_id| {
+                format!(
+                    r#" <a href="{base}/blob/{}?email={}" download>{}</a>"#,
+                    html_escape(&url_encode(blob_id)),
+                    html_escape(&url_encode(&email.id)),
+                    label
+                )
+            })
+            .unwrap_or_default()
+    };
+    let message = match mime {
+        Some(pgp::Mime::Encrypted { payload }) => {
+            format!("{}{}", t("pgp.encrypted"), download(*payload, t("pgp.download")))
+        }
+        Some(pgp::Mime::Signed { signature }) => {
+            format!("{}{}", t("pgp.signed"), download(*signature, t("pgp.download_signature")))
+        }
+        None if inline_encrypted => t("pgp.inline_encrypted").to_string(),
+        None if inline_signed => t("pgp.signed").to_string(),
+        None => return String::new(),
+    };
+    format!(r#"<div class="pgp-notice">{}</div>"#, message)
+}
+
+fn get_email_html_body(email: &Email) -> Option<String> {
+    let html: String = email
+        .html_body
+        .iter()
+        .flatten()
+        .filter(|part| part.r#type.as_deref() == Some("text/html"))
+        .filter_map(|part| email.body_values.get(&part.part_id))
+        .map(|value| value.value.as_str())
+        .collect();
+    (!html.is_empty()).then_some(html)
 }
 
-fn get_email_body(email: &Email) -> String {
+pub fn get_email_body(email: &Email) -> String {
     // Try to get body from bodyValues using textBody parts
     if let Some(text_body) = &email.text_body {
         for part in text_body {
@@ -446,6 +2999,6 @@ fn get_email_body(email: &Email) -> String {
     email
         .preview
         .as_deref()
-        .unwrap_or("(no body)")
+        .unwrap_or(t("email.no_body"))
         .to_string()
 }
@@ -0,0 +1,403 @@
+//! Snapshots of the views rendered by askama templates, kept under
+//! `src/templates/snapshots/`. A change to a view's output fails here; when
+//! it is meant, run the tests with `UPDATE_SNAPSHOTS=1` to write the new
+//! output and review it in the diff.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use super::*;
+use crate::plugins::{Action, Plugin};
+
+fn assert_snapshot(name: &str, html: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src/templates/snapshots")
+        .join(format!("{}.html", name));
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&path, html).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|_| panic!("no snapshot at {}; run with UPDATE_SNAPSHOTS=1", path.display()));
+    assert_eq!(html, expected, "{} no longer renders as its snapshot", name);
+}
+
+fn from_json<T: DeserializeOwned>(value: Value) -> T {
+    serde_json::from_value(value).unwrap()
+}
+
+/// Dates in the snapshots are in UTC, whatever the machine's zone
+fn utc() {
+    assert!(dates::select("UTC"));
+}
+
+fn mailboxes() -> Vec<Mailbox> {
+    from_json(json!([
+        { "id": "M1", "name": "Inbox", "role": "inbox", "totalEmails": 3, "unreadEmails": 1 },
+        { "id": "M2", "name": "Projects", "totalEmails": 0 },
+        { "id": "M3", "name": "2026", "parentId": "M2", "totalEmails": 0 },
+        { "id": "M4", "name": "Receipts & \"bills\"", "totalEmails": 2 },
+    ]))
+}
+
+fn identities() -> Vec<Identity> {
+    from_json(json!([
+        { "id": "I1", "name": "Ann Example", "email": "ann@example.com", "textSignature": "-- \nAnn <ann@example.com>" },
+        { "id": "I2", "name": "", "email": "lists@example.com", "htmlSignature": "<p>Cheers,<br>Ann &amp; co</p>" },
+    ]))
+}
+
+#[test]
+fn mailbox_settings_page() {
+    assert_snapshot("mailbox_settings", &mailbox_settings(&mailboxes(), None));
+    assert_snapshot(
+        "mailbox_settings_notice",
+        &mailbox_settings(&[], Some(Outcome::Notice("Created <Projects>"))),
+    );
+}
+
+#[test]
+fn vacation_settings_page() {
+    let vacation: VacationResponse = from_json(json!({
+        "id": "singleton",
+        "isEnabled": true,
+        "fromDate": "2026-06-01T09:00:00Z",
+        "subject": "Away \"until\" June",
+        "textBody": "Back soon.\n<Ann>",
+    }));
+    assert_snapshot("vacation_settings", &vacation_settings(&vacation, Some(Outcome::Error("Failed: <denied>"))));
+    let off: VacationResponse = from_json(json!({ "id": "singleton" }));
+    assert_snapshot("vacation_settings_off", &vacation_settings(&off, None));
+}
+
+#[test]
+fn timezone_settings_page() {
+    assert_snapshot("timezone_settings", &timezone_settings(Some("Europe/Berlin"), "UTC", None));
+    assert_snapshot(
+        "timezone_settings_default",
+        &timezone_settings(None, "America/New_York", Some(Outcome::Notice("Dates are now shown in UTC"))),
+    );
+}
+
+#[test]
+fn settings_index_page() {
+    let account: MailAccount = from_json(json!({
+        "id": "A1",
+        "name": "ann@example.com",
+        "is_read_only": false,
+        "has_sieve": true,
+        "has_quota": true
+    }));
+    let quotas: Vec<Quota> = from_json(json!([
+        { "id": "Q1", "name": "Mail", "resourceType": "octets", "used": 1_572_864, "hardLimit": 1_073_741_824 },
+        { "id": "Q2", "resourceType": "count", "used": 120, "hardLimit": 10_000 }
+    ]));
+    assert_snapshot("settings_index", &settings_index(&account, &quotas, true));
+    let no_sieve = MailAccount {
+        has_sieve: false,
+        ..account.clone()
+    };
+    assert_snapshot("settings_index_no_filters", &settings_index(&no_sieve, &[], false));
+    // A server without submission or a vacation responder
+    let bare = MailAccount {
+        has_submission: false,
+        has_vacation: false,
+        ..no_sieve
+    };
+    assert_snapshot("settings_index_unsupported", &settings_index(&bare, &[], false));
+}
+
+#[test]
+fn display_settings_page() {
+    assert_snapshot("display_settings", &display_settings(&Prefs::default()));
+    let prefs = Prefs {
+        density: Density::Compact,
+        list_height: Some(65),
+        ..Prefs::default()
+    };
+    assert_snapshot("display_settings_compact", &display_settings(&prefs));
+}
+
+#[test]
+fn reply_settings_page() {
+    assert_snapshot("reply_settings", &reply_settings(&ReplyPrefs::default(), None));
+    let prefs = ReplyPrefs {
+        posting: Posting::Bottom,
+        include_original: false,
+        quote_prefix: "| ".to_string(),
+        reply_all: true,
+    };
+    assert_snapshot("reply_settings_changed", &reply_settings(&prefs, Some(Outcome::Notice("Saved"))));
+}
+
+#[test]
+fn replies_follow_the_quoting_preferences() {
+    utc();
+    let email: Email = from_json(json!({
+        "id": "E1",
+        "from": [{ "name": "Bo", "email": "bo@example.com" }],
+        "to": [{ "email": "ann@example.com" }],
+        "subject": "Plans",
+        "receivedAt": "2020-03-03T10:00:00Z",
+        "textBody": [{ "partId": "1", "type": "text/plain" }],
+        "bodyValues": { "1": { "value": "One\n\nTwo" } },
+    }));
+    let reply = |prefs: &ReplyPrefs| ComposeForm::reply(&email, &identities(), false, prefs).body;
+    let attribution = "On Tue, Mar 3, 2020 10:00 UTC, Bo <bo@example.com> wrote:";
+    let signature = "\n\n-- \nAnn <ann@example.com>";
+
+    let top = reply(&ReplyPrefs::default());
+    assert_eq!(top, format!("{}\n\n{}\n> One\n>\n> Two\n", signature, attribution));
+    let bottom = reply(&ReplyPrefs { posting: Posting::Bottom, quote_prefix: "| ".to_string(), ..ReplyPrefs::default() });
+    assert_eq!(bottom, format!("{}\n| One\n|\n| Two\n{}", attribution, signature));
+    assert_eq!(reply(&ReplyPrefs { include_original: false, ..ReplyPrefs::default() }), signature);
+}
+
+#[test]
+fn signature_settings_page() {
+    let prefs = Prefs {
+        signatures: HashMap::from([("I2".to_string(), "Custom & \"mine\"".to_string())]),
+        html_signatures: HashMap::from([("I1".to_string(), "<b>Ann</b>".to_string())]),
+        ..Prefs::default()
+    };
+    assert_snapshot("signature_settings", &signature_settings(&identities(), &prefs, None));
+    assert_snapshot(
+        "signature_settings_none",
+        &signature_settings(&[], &Prefs::default(), Some(Outcome::Error("Failed to load identities"))),
+    );
+}
+
+#[test]
+fn html_signatures_replace_the_text_one() {
+    let mut identity = identities().remove(1);
+    let text = "Hi <all>\n\n-- \nCheers,\nAnn & co\n> quoted";
+    assert_eq!(
+        html_body(text, &identity).unwrap(),
+        "<div>Hi &lt;all&gt;<br>\n<br>\n</div>\n<div class=\"signature\">-- <br>\n<p>Cheers,<br>Ann &amp; co</p></div>\n<div><br>\n&gt; quoted</div>"
+    );
+    // Not when the signature was taken out, or there's no HTML one
+    assert_eq!(html_body("Hi", &identity), None);
+    identity.html_signature = None;
+    assert_eq!(html_body(text, &identity), None);
+}
+
+#[test]
+fn retention_settings_page() {
+    utc();
+    let policy: RetentionConfig = from_json(json!({ "trash_days": 30, "interval_minutes": 60 }));
+    let log = [
+        PurgeRecord {
+            at: "2026-03-01T04:00:00Z".to_string(),
+            mailbox: "Trash <old>".to_string(),
+            cutoff: "2026-01-30T04:00:00Z".to_string(),
+            count: 12,
+        },
+        PurgeRecord {
+            at: "2026-03-02T04:00:00Z".to_string(),
+            mailbox: "Trash".to_string(),
+            cutoff: "2026-01-31T04:00:00Z".to_string(),
+            count: 1,
+        },
+    ];
+    assert_snapshot("retention_settings", &retention_settings(&policy, true, &log));
+    assert_snapshot("retention_settings_disabled", &retention_settings(&policy, false, &[]));
+    let unset: RetentionConfig = from_json(json!({}));
+    assert_snapshot("retention_settings_unset", &retention_settings(&unset, false, &[]));
+}
+
+#[test]
+fn muted_settings_page() {
+    utc();
+    let emails: Vec<Email> = from_json(json!([
+        {
+            "id": "E2",
+            "threadId": "T1",
+            "from": [{ "name": "Bo", "email": "bo@example.com" }],
+            "subject": "Lunch <Friday>",
+            "receivedAt": "2020-03-03T10:00:00Z",
+        },
+        { "id": "E5", "threadId": "T4", "receivedAt": "2020-02-01T08:30:00Z" },
+    ]));
+    assert_snapshot("muted_settings", &muted_settings(&emails));
+    assert_snapshot("muted_settings_empty", &muted_settings(&[]));
+}
+
+#[test]
+fn session_settings_page() {
+    utc();
+    let sessions = [
+        SignedInSession {
+            id: "019a0000-0000-7000-8000-000000000001".to_string(),
+            current: false,
+            device: Device {
+                user_agent: Some("Mozilla/5.0 <Firefox>".to_string()),
+                address: Some("203.0.113.9".parse().unwrap()),
+                api: false,
+            },
+            created: 1772337600,
+            last_active: 1772424000,
+        },
+        SignedInSession {
+            id: "019a0000-0000-7000-8000-000000000002".to_string(),
+            current: true,
+            device: Device::default(),
+            created: 1772510400,
+            last_active: 1772510460,
+        },
+        SignedInSession {
+            id: "019a0000-0000-7000-8000-000000000003".to_string(),
+            current: false,
+            device: Device {
+                user_agent: Some("curl/8.0".to_string()),
+                address: None,
+                api: true,
+            },
+            created: 1772510400,
+            last_active: 1772510400,
+        },
+    ];
+    assert_snapshot("session_settings", &session_settings(&sessions, Some(Outcome::Notice("Signed out"))));
+    assert_snapshot("session_settings_alone", &session_settings(&sessions[1..2], None));
+}
+
+#[test]
+fn totp_settings_page() {
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    let uri = "otpauth://totp/Webmail:ann%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Webmail";
+    assert_snapshot("totp_settings_setup", &totp_settings(Some((secret, uri)), None));
+    assert_snapshot(
+        "totp_settings_enabled",
+        &totp_settings(None, Some(Outcome::Notice("Two-factor authentication is on."))),
+    );
+}
+
+#[test]
+fn sieve_pages() {
+    let scripts: Vec<SieveScript> = from_json(json!([
+        { "id": "S1", "name": "Sort \"lists\"", "blobId": "B1", "isActive": true },
+        { "id": "S2", "blobId": "B2", "isActive": false },
+    ]));
+    assert_snapshot("sieve_script_list", &sieve_script_list(&scripts, Some(Outcome::Notice("Saved"))));
+    assert_snapshot("sieve_script_list_empty", &sieve_script_list(&[], None));
+    let script = "require \"fileinto\";\nif header :contains \"list-id\" \"<dev>\" { fileinto \"Lists\"; }";
+    assert_snapshot("sieve_script_editor", &sieve_script_editor(Some("S1"), "Sort & file", script, None));
+    assert_snapshot(
+        "sieve_script_editor_new",
+        &sieve_script_editor(None, "", "", Some(Outcome::Error("line 1: syntax error"))),
+    );
+}
+
+#[test]
+fn compose() {
+    utc();
+    let identities = identities();
+    assert_snapshot("compose_new", &compose_page(&identities, &ComposeForm::new(&identities), None));
+    let form = ComposeForm {
+        identity_id: "I2".to_string(),
+        to: "\"Bo <B>\" <bo@example.com>, cy@example.com".to_string(),
+        cc: "dee@example.com".to_string(),
+        bcc: String::new(),
+        subject: "Re: <plans> & \"dates\"".to_string(),
+        body: "\n\nSee below.\n\n> quoted <b>".to_string(),
+        in_reply_to: "<a1@example.com>".to_string(),
+        references: "<a0@example.com> <a1@example.com>".to_string(),
+        attachments: vec![
+            OutgoingAttachment {
+                blob_id: "B9".to_string(),
+                r#type: "application/pdf".to_string(),
+                name: Some("plan \"v2\".pdf".to_string()),
+                size: 20480,
+            },
+            OutgoingAttachment {
+                blob_id: "B10".to_string(),
+                r#type: "application/octet-stream".to_string(),
+                name: None,
+                size: 12,
+            },
+        ],
+        draft_id: "D1".to_string(),
+    };
+    assert_snapshot("compose_reply", &compose_page(&identities, &form, Some("Failed to send: <quota>")));
+    assert_snapshot("compose_no_identities", &compose_page(&[], &ComposeForm::default(), None));
+}
+
+#[test]
+fn link_pages() {
+    // Only the page's own part, as the rest is `base_page`'s
+    let page = |url, removed: &[&str]| render(LinkPage { url, host: "shop.example", removed: removed.join(", ") });
+    assert_snapshot("link_page", &page("https://shop.example/sale?id=7&q=<b>", &["utm_source", "fbclid"]));
+    assert_snapshot("link_page_clean", &page("https://shop.example/", &[]));
+}
+
+#[test]
+fn thread() {
+    utc();
+    let email = |id: &str, from: &str, received: &str, text: &str| -> Email {
+        from_json(json!({
+            "id": id,
+            "threadId": "T1",
+            "mailboxIds": { "M1": true },
+            "keywords": { "$seen": true },
+            "from": [{ "name": from, "email": "sender@example.com" }],
+            "to": [{ "email": "ann@example.com" }],
+            "subject": "Plans <for> \"spring\"",
+            "receivedAt": received,
+            "preview": text,
+            "textBody": [{ "partId": "1", "type": "text/plain" }],
+            "bodyValues": { "1": { "value": text } },
+        }))
+    };
+    let messages = vec![
+        (email("E1", "Bo & Co", "2020-03-03T10:00:00Z", "First <draft> of the plan"), None),
+        (email("E2", "Cy", "2020-03-04T11:30:00Z", "Looks good"), Some("example.com".to_string())),
+        (email("E3", "Bo & Co", "2020-03-05T08:15:00Z", "Final"), None),
+    ];
+    let options = ViewOptions::default();
+    assert_snapshot("thread", &thread_view(&messages, "E1", &mailboxes(), &options));
+    assert_snapshot("thread_single", &thread_view(&messages[2..], "", &mailboxes(), &options));
+
+    let mut bounced = email("E4", "Mail Delivery System", "2020-03-05T08:16:00Z", "Undeliverable");
+    bounced.bounce = Some(crate::bounce::Bounce {
+        failures: vec![crate::bounce::Failure {
+            recipient: "ann@example.org".to_string(),
+            reason: "550 5.1.1 <ann@example.org>: user unknown".to_string(),
+        }],
+        message_id: Some("plan-1@example.com".to_string()),
+        original_id: Some("E3".to_string()),
+    });
+    assert_snapshot("thread_bounce", &thread_view(&[(bounced, None)], "", &mailboxes(), &options));
+
+    struct Tickets;
+    impl Plugin for Tickets {
+        fn name(&self) -> &str {
+            "tickets"
+        }
+        fn render_message(&self, email: &Email, body: String) -> String {
+            format!(r#"<p class="ticket">Ticket for {}</p>{}"#, html_escape(&email.id), body)
+        }
+        fn message_actions(&self, _: &Email) -> Vec<Action> {
+            vec![Action { id: "open".to_string(), label: "Open a <ticket>".to_string() }]
+        }
+    }
+    let mut plugins = Plugins::new();
+    plugins.register(Box::new(Tickets));
+    let options = ViewOptions { plugins: Some(&plugins), ..ViewOptions::default() };
+    assert_snapshot("thread_plugin", &thread_view(&messages[2..], "", &mailboxes(), &options));
+}
+#[test]
+fn thread_matches_name_the_messages_to_show() {
+    let matches = ["E1".to_string(), "E3".to_string()];
+    assert_eq!(
+        thread_matches(" lunch <b> ", &matches, 3),
+        r#"<span class="thread-matches" data-ids="E1 E3" data-query="lunch &lt;b&gt;">2 of 3 match</span>"#
+    );
+    assert_eq!(
+        thread_matches("", &matches, 2),
+        r#"<span class="thread-matches" data-ids="E1 E3" data-query=""></span>"#
+    );
+}
+
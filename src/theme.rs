@@ -0,0 +1,75 @@
+//! The light or dark theme a browser has picked with the sidebar toggle,
+//! kept in a cookie. Without one, style.css follows the system's
+//! `prefers-color-scheme`.
+//!
+//! Pages are rendered by helpers that don't see the request, so [`note`]
+//! reads the cookie when a request comes in and [`current`] picks it up
+//! when `base_page` renders.
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::config::ServerConfig;
+use crate::session::cookie_attributes;
+
+/// Name of the cookie holding the choice
+const COOKIE: &str = "theme";
+
+/// How long a browser keeps the choice
+const MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub fn parse(s: &str) -> Option<Theme> {
+        match s {
+            "light" => Some(Theme::Light),
+            "dark" => Some(Theme::Dark),
+            _ => None,
+        }
+    }
+
+    /// The value of `data-theme` on `<html>` that style.css looks for
+    pub fn name(self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+}
+
+thread_local! {
+    /// The theme chosen by the browser making this thread's request
+    static CHOSEN: Cell<Option<Theme>> = const { Cell::new(None) };
+}
+
+/// Note the theme the current request's Cookie header asks for
+pub fn note(cookie_header: Option<&str>) {
+    let theme = cookie_header.unwrap_or("").split(';').find_map(|cookie| {
+        let (name, value) = cookie.trim().split_once('=')?;
+        (name == COOKIE).then(|| Theme::parse(value)).flatten()
+    });
+    CHOSEN.with(|cell| cell.set(theme));
+}
+
+/// The theme for pages rendered for the current request, if one was chosen
+pub fn current() -> Option<Theme> {
+    CHOSEN.with(Cell::get)
+}
+
+/// The cookie that keeps `theme` for this browser, which also applies it to
+/// whatever else the current request renders
+pub fn cookie(server: &ServerConfig, theme: Theme, https: bool) -> String {
+    CHOSEN.with(|cell| cell.set(Some(theme)));
+    format!(
+        "{}={}{}; Max-Age={}",
+        COOKIE,
+        theme.name(),
+        cookie_attributes(server, https),
+        MAX_AGE.as_secs()
+    )
+}
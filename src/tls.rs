@@ -0,0 +1,159 @@
+//! HTTPS without a reverse proxy in front.
+//!
+//! tiny_http only speaks plain HTTP here (its own TLS support wants an
+//! older rustls than the one ureq already brings in), so with
+//! `[server.tls]` every connection `relay.rs` takes in is decrypted here on
+//! its way to tiny_http.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TlsConfig;
+
+/// How long a client gets to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate and key from `[server.tls]`, ready to serve
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read certificate {}: {}", tls.cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .map_err(|e| format!("failed to read private key {}: {}", tls.key, e))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// A client connection's TLS state and the socket its records go out on
+struct Tls {
+    conn: ServerConnection,
+    socket: TcpStream,
+}
+
+impl Tls {
+    /// Send whatever records the connection has queued
+    fn flush(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+/// Take the client through the TLS handshake, then give it back the read
+/// timeout the caller set for the rest of the connection
+pub fn handshake(mut client: &TcpStream, config: Arc<ServerConfig>) -> io::Result<ServerConnection> {
+    let mut conn = ServerConnection::new(config).map_err(io::Error::other)?;
+    let read_timeout = client.read_timeout()?;
+    client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut client)?;
+    }
+    client.set_read_timeout(read_timeout)?;
+    Ok(conn)
+}
+
+/// Relay the requests `conn` decrypts to `upstream`, and its responses back
+/// encrypted, until either side is done
+pub fn relay(mut client: TcpStream, upstream: TcpStream, conn: ServerConnection) -> io::Result<()> {
+    let tls = Arc::new(Mutex::new(Tls {
+        conn,
+        socket: client.try_clone()?,
+    }));
+    // Responses go back on a thread of their own, so neither direction
+    // waits on the other; the lock is only held while records are handled
+    let responses = {
+        let tls = Arc::clone(&tls);
+        let upstream = upstream.try_clone()?;
+        thread::spawn(move || relay_responses(upstream, &tls))
+    };
+    let mut upstream = upstream;
+    let result = relay_requests(&mut client, &mut upstream, &tls);
+    // Let tiny_http finish with the connection, which ends relay_responses
+    let _ = upstream.shutdown(Shutdown::Write);
+    let _ = responses.join();
+    result
+}
+
+/// Decrypt what the client sends and pass it on to tiny_http, until the
+/// client closes its side
+fn relay_requests(client: &mut TcpStream, upstream: &mut TcpStream, tls: &Mutex<Tls>) -> io::Result<()> {
+    let mut records = [0u8; 16 * 1024];
+    // The handshake may have read the first request along with it
+    let mut n = 0;
+    loop {
+        let mut plain = Vec::new();
+        let closed = {
+            let mut tls = tls.lock().unwrap();
+            let mut records = &records[..n];
+            while !records.is_empty() {
+                tls.conn.read_tls(&mut records)?;
+                let processed = tls.conn.process_new_packets();
+                // Any alert has to reach the client before we give up
+                tls.flush()?;
+                processed.map_err(io::Error::other)?;
+            }
+            take_plaintext(&mut tls.conn, &mut plain)?
+        };
+        upstream.write_all(&plain)?;
+        if closed {
+            return Ok(());
+        }
+        n = client.read(&mut records)?;
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Move the decrypted bytes waiting in `conn` to `plain`, saying whether
+/// the client has since closed the connection
+fn take_plaintext(conn: &mut ServerConnection, plain: &mut Vec<u8>) -> io::Result<bool> {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        match conn.reader().read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) => plain.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Encrypt what tiny_http answers and send it to the client, until
+/// tiny_http closes the connection
+fn relay_responses(upstream: TcpStream, tls: &Mutex<Tls>) -> io::Result<()> {
+    let result = encrypt_responses(upstream, tls);
+    let mut tls = tls.lock().unwrap();
+    tls.conn.send_close_notify();
+    let _ = tls.flush();
+    // Also ends relay_requests' wait for the client
+    let _ = tls.socket.shutdown(Shutdown::Both);
+    result
+}
+
+fn encrypt_responses(mut upstream: TcpStream, tls: &Mutex<Tls>) -> io::Result<()> {
+    let mut plain = [0u8; 16 * 1024];
+    loop {
+        let n = upstream.read(&mut plain)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let mut tls = tls.lock().unwrap();
+        tls.conn.writer().write_all(&plain[..n])?;
+        tls.flush()?;
+    }
+}
@@ -0,0 +1,232 @@
+//! Time-based one-time passwords (RFC 6238), asked for after the JMAP
+//! server has accepted the password when `[totp] enabled` is set.
+//!
+//! A user sets it up from `/settings/two-factor`, scanning the secret into
+//! an authenticator app; it is kept in their preferences (`Prefs.totp`)
+//! with the last time step used, so a code can't be entered twice. A
+//! sign-in whose user has a secret waits in [`PendingLogins`] until the
+//! code is given, and only then becomes a session. Wrong codes are also
+//! counted by user, whether from the form or the API, and too many of
+//! them shut the user out of giving more for a while.
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::log_warn;
+use crate::secret::{random_token, tokens_match};
+use crate::session::Session;
+use crate::templates::url_encode;
+
+/// Seconds each code lasts
+const PERIOD: u64 = 30;
+const DIGITS: usize = 6;
+/// 160 bits, as RFC 4226 recommends for HMAC-SHA1
+const SECRET_BYTES: usize = 20;
+/// How long the code form may be left before the sign-in is dropped
+const PENDING_TIMEOUT: Duration = Duration::from_secs(300);
+/// Wrong codes taken for one sign-in before it has to start again
+const MAX_ATTEMPTS: u32 = 5;
+/// Wrong codes taken for one user, by any means, before no more are
+/// checked until [`LOCKOUT`] after the last
+const MAX_USER_FAILURES: u32 = 10;
+const LOCKOUT: Duration = Duration::from_secs(900);
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A fresh secret, in the base32 authenticator apps take
+pub fn new_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    base32_encode(&bytes)
+}
+
+/// The otpauth:// URI a QR code carries for an authenticator app to add
+/// the account from
+pub fn provisioning_uri(issuer: &str, username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        url_encode(issuer),
+        url_encode(username),
+        secret,
+        url_encode(issuer),
+        DIGITS,
+        PERIOD
+    )
+}
+
+/// The time step `code` is right for, if later than `last_step`: the
+/// current one or one either side, for clocks a little apart
+pub fn verify(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    verify_at(secret, code, last_step, now)
+}
+
+/// [`verify`] at `now`, in seconds since the epoch
+fn verify_at(secret: &str, code: &str, last_step: u64, now: u64) -> Option<u64> {
+    let key = base32_decode(secret)?;
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let now = now / PERIOD;
+    (now.saturating_sub(1)..=now + 1)
+        .filter(|&step| step > last_step)
+        .find(|&step| {
+            let expected = format!("{:0width$}", code_at(&key, step), width = DIGITS);
+            tokens_match(&code, &expected)
+        })
+}
+
+/// The code for time step `step`, by RFC 4226's dynamic truncation
+fn code_at(key: &[u8], step: u64) -> u32 {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key), &step.to_be_bytes());
+    let mac = tag.as_ref();
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS as u32)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut block = [0u8; 5];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let bits = block.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        // Unpadded, as otpauth URIs have it
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            text.push(BASE32[((bits >> (35 - i * 5)) & 31) as usize] as char);
+        }
+    }
+    text
+}
+
+/// Either case, ignoring spaces and padding, as secrets get typed in
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes().filter(|&c| c != b' ' && c != b'=') {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes).filter(|bytes| !bytes.is_empty())
+}
+
+/// A sign-in the JMAP server accepted, waiting for its code
+struct PendingLogin {
+    session: Session,
+    remember_age: Option<Duration>,
+    started: Instant,
+    attempts: u32,
+}
+
+/// How a code given for a pending sign-in went
+pub enum Attempt {
+    /// Right, so the sign-in goes ahead, remembered for that long if asked
+    Passed(Box<Session>, Option<Duration>),
+    /// Wrong, with tries left
+    Failed,
+    /// Unknown, expired or out of tries, so it starts again from the
+    /// password
+    Gone,
+}
+
+/// Wrong codes lately given for a user
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Sign-ins held back until their code is given, by a token the code form
+/// sends back
+#[derive(Default)]
+pub struct PendingLogins {
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    /// By username
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl PendingLogins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold `session` back, returning the token that finishes it
+    pub fn start(&self, session: Session, remember_age: Option<Duration>) -> String {
+        let token = random_token();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| login.started.elapsed() < PENDING_TIMEOUT);
+        pending.insert(
+            token.clone(),
+            PendingLogin {
+                session,
+                remember_age,
+                started: Instant::now(),
+                attempts: 0,
+            },
+        );
+        token
+    }
+
+    /// Give `token`'s sign-in a code, which `check` says is right or not
+    pub fn attempt(&self, token: &str, check: impl FnOnce(&Session) -> bool) -> Attempt {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(login) = pending.get_mut(token) else {
+            return Attempt::Gone;
+        };
+        if login.started.elapsed() >= PENDING_TIMEOUT {
+            pending.remove(token);
+            return Attempt::Gone;
+        }
+        if check(&login.session) {
+            let login = pending.remove(token).unwrap();
+            return Attempt::Passed(Box::new(login.session), login.remember_age);
+        }
+        login.attempts += 1;
+        if login.attempts >= MAX_ATTEMPTS {
+            pending.remove(token);
+            return Attempt::Gone;
+        }
+        Attempt::Failed
+    }
+
+    /// Whether `check` takes `username`'s code, which isn't asked once they
+    /// have given too many wrong ones lately
+    pub fn check_limited(&self, username: &str, check: impl FnOnce() -> bool) -> bool {
+        {
+            let mut failures = self.failures.lock().unwrap();
+            failures.retain(|_, failed| failed.last.elapsed() < LOCKOUT);
+            if failures.get(username).is_some_and(|failed| failed.count >= MAX_USER_FAILURES) {
+                log_warn!("Not checking a two-factor code for user {}, who gave too many wrong ones", username);
+                return false;
+            }
+        }
+        // Not under the lock, so other users' codes aren't held up behind
+        // the preferences this reads
+        let passed = check();
+        let mut failures = self.failures.lock().unwrap();
+        if passed {
+            failures.remove(username);
+            return true;
+        }
+        let failed = failures.entry(username.to_string()).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        failed.count += 1;
+        failed.last = Instant::now();
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,51 @@
+use super::*;
+
+/// RFC 6238's SHA-1 secret, "12345678901234567890"
+const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+#[test]
+fn codes_match_rfc_6238() {
+    let key = base32_decode(RFC_SECRET).unwrap();
+    assert_eq!(key, b"12345678901234567890");
+    // The RFC's eight-digit codes, cut to six
+    for (time, code) in [(59, 287082), (1111111109, 81804), (1234567890, 5924), (2000000000, 279037)] {
+        assert_eq!(code_at(&key, time / PERIOD), code, "at {}", time);
+    }
+}
+
+#[test]
+fn codes_are_checked_within_a_step_and_only_once() {
+    let now = 1111111109;
+    let step = now / PERIOD;
+    assert_eq!(verify_at(RFC_SECRET, "081804", 0, now), Some(step));
+    assert_eq!(verify_at(RFC_SECRET, " 081 804 ", 0, now + PERIOD), Some(step));
+    assert_eq!(verify_at(RFC_SECRET, "081804", 0, now + 3 * PERIOD), None);
+    assert_eq!(verify_at(RFC_SECRET, "081804", step, now), None);
+    assert_eq!(verify_at(RFC_SECRET, "81804", 0, now), None);
+    assert_eq!(verify_at("not base32!", "081804", 0, now), None);
+}
+
+#[test]
+fn wrong_codes_shut_a_user_out() {
+    let logins = PendingLogins::new();
+    for _ in 0..MAX_USER_FAILURES - 1 {
+        assert!(!logins.check_limited("alice", || false));
+    }
+    // A right code forgives the wrong ones before it
+    assert!(logins.check_limited("alice", || true));
+    for _ in 0..MAX_USER_FAILURES {
+        assert!(!logins.check_limited("alice", || false));
+    }
+    assert!(!logins.check_limited("alice", || true));
+    assert!(logins.check_limited("bob", || true));
+}
+
+#[test]
+fn base32_round_trips() {
+    for bytes in [&b"f"[..], b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+        assert_eq!(base32_decode(&base32_encode(bytes)).unwrap(), bytes);
+    }
+    assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    assert_eq!(base32_decode("mzxw 6ytb oi======").unwrap(), b"foobar");
+    assert_eq!(new_secret().len(), 32);
+}
@@ -0,0 +1,175 @@
+//! Attachments of [`CHUNK_BYTES`] or more, sent from the compose form in
+//! chunks so that a dropped connection costs the chunk it was on and not
+//! the whole file: app.js asks how far the upload got and carries on
+//! from there.
+//!
+//! The chunks are put back together in a file of the upload's own under
+//! the system's temporary directory, readable only by us, which goes to
+//! the JMAP server as one blob once the last chunk is in. Uploads nobody
+//! has added to for [`IDLE_TIMEOUT`] are thrown away, file and all.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::secret::random_token;
+
+/// The size of each chunk, and so of the smallest file sent in chunks
+pub const CHUNK_BYTES: u64 = 8 * 1024 * 1024;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+/// Uploads one session may have going at once, each holding disk space
+const MAX_PER_SESSION: usize = 4;
+
+pub struct ChunkedUploads {
+    dir: PathBuf,
+    uploads: Mutex<HashMap<String, Upload>>,
+}
+
+/// A file as far as it has arrived
+pub struct Upload {
+    session_id: Uuid,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    received: u64,
+    touched: Instant,
+    part: Part,
+}
+
+/// The file the chunks are written to, removed with it
+struct Part {
+    file: File,
+    path: PathBuf,
+}
+
+impl Drop for Part {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub enum Added {
+    /// The bytes received so far, to send the next chunk from
+    Partial(u64),
+    /// The last chunk, the whole file ready to read
+    Complete(Upload),
+}
+
+#[derive(Debug)]
+pub enum ChunkError {
+    /// No upload by that token for this session; it finished, or sat idle
+    /// too long
+    Gone,
+    /// The chunk doesn't start where the upload got to, which it says
+    Offset(u64),
+    /// The chunk runs past the end of the file, or past [`CHUNK_BYTES`]
+    TooLong,
+    Io(io::Error),
+}
+
+impl ChunkedUploads {
+    pub fn new() -> Self {
+        ChunkedUploads {
+            dir: std::env::temp_dir(),
+            uploads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Make room for a file of `size` bytes, returning the token its
+    /// chunks go to; `None` when the session has as many going as it may
+    pub fn start(&self, session_id: &Uuid, name: &str, content_type: &str, size: u64) -> io::Result<Option<String>> {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|_, upload| upload.touched.elapsed() < IDLE_TIMEOUT);
+        if uploads.values().filter(|u| u.session_id == *session_id).count() >= MAX_PER_SESSION {
+            return Ok(None);
+        }
+        let token = random_token();
+        let path = self.dir.join(format!("jmap-webmail-upload-{}", token));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        uploads.insert(
+            token.clone(),
+            Upload {
+                session_id: *session_id,
+                name: name.to_string(),
+                content_type: content_type.to_string(),
+                size,
+                received: 0,
+                touched: Instant::now(),
+                part: Part { file, path },
+            },
+        );
+        Ok(Some(token))
+    }
+
+    /// How many bytes of `token`'s file have arrived
+    pub fn received(&self, session_id: &Uuid, token: &str) -> Option<u64> {
+        let uploads = self.uploads.lock().unwrap();
+        uploads
+            .get(token)
+            .filter(|u| u.session_id == *session_id && u.touched.elapsed() < IDLE_TIMEOUT)
+            .map(|u| u.received)
+    }
+
+    /// Write `chunk`, sent as starting `offset` bytes into the file. A
+    /// chunk sent again after its answer was lost starts before where the
+    /// upload got to, and is turned down with where that is.
+    pub fn add(&self, session_id: &Uuid, token: &str, offset: u64, chunk: &[u8]) -> Result<Added, ChunkError> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = match uploads.get_mut(token) {
+            Some(upload) if upload.session_id == *session_id && upload.touched.elapsed() < IDLE_TIMEOUT => upload,
+            _ => return Err(ChunkError::Gone),
+        };
+        if offset != upload.received {
+            return Err(ChunkError::Offset(upload.received));
+        }
+        let len = chunk.len() as u64;
+        if len > CHUNK_BYTES || offset + len > upload.size {
+            return Err(ChunkError::TooLong);
+        }
+        // A write that failed partway leaves bytes past `received`, which
+        // the chunk sent again writes over
+        upload.part.file.seek(SeekFrom::Start(offset)).map_err(ChunkError::Io)?;
+        upload.part.file.write_all(chunk).map_err(ChunkError::Io)?;
+        upload.received += len;
+        upload.touched = Instant::now();
+        if upload.received < upload.size {
+            return Ok(Added::Partial(upload.received));
+        }
+        Ok(Added::Complete(uploads.remove(token).unwrap()))
+    }
+
+    /// Keep a completed upload after all, when it couldn't go on to the
+    /// JMAP server, for its last chunk to be sent again: an empty one at
+    /// its end
+    pub fn put_back(&self, token: &str, mut upload: Upload) {
+        upload.touched = Instant::now();
+        self.uploads.lock().unwrap().insert(token.to_string(), upload);
+    }
+}
+
+impl Default for ChunkedUploads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Upload {
+    /// The whole file, from the start
+    pub fn reader(&mut self) -> io::Result<impl Read + '_> {
+        self.part.file.seek(SeekFrom::Start(0))?;
+        Ok((&mut self.part.file).take(self.size))
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,42 @@
+use super::*;
+
+#[test]
+fn chunks_come_back_together_whatever_is_sent_twice() {
+    let uploads = ChunkedUploads::new();
+    let session = Uuid::now_v7();
+    let token = uploads.start(&session, "notes.txt", "text/plain", 11).unwrap().unwrap();
+
+    assert!(matches!(uploads.add(&session, &token, 0, b"hello "), Ok(Added::Partial(6))));
+    // The answer to that chunk was lost, so it comes again
+    assert!(matches!(uploads.add(&session, &token, 0, b"hello "), Err(ChunkError::Offset(6))));
+    assert_eq!(uploads.received(&session, &token), Some(6));
+    assert!(matches!(uploads.add(&session, &token, 6, b"world!"), Err(ChunkError::TooLong)));
+
+    let Ok(Added::Complete(mut upload)) = uploads.add(&session, &token, 6, b"world") else {
+        panic!("the last chunk completes the upload");
+    };
+    let mut contents = String::new();
+    upload.reader().unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!((upload.name.as_str(), contents.as_str()), ("notes.txt", "hello world"));
+    assert_eq!(uploads.received(&session, &token), None);
+
+    let path = upload.part.path.clone();
+    drop(upload);
+    assert!(!path.exists());
+}
+
+#[test]
+fn uploads_belong_to_the_session_that_started_them() {
+    let uploads = ChunkedUploads::new();
+    let session = Uuid::now_v7();
+    let token = uploads.start(&session, "a.bin", "application/octet-stream", 4).unwrap().unwrap();
+
+    let other = Uuid::now_v7();
+    assert_eq!(uploads.received(&other, &token), None);
+    assert!(matches!(uploads.add(&other, &token, 0, b"abcd"), Err(ChunkError::Gone)));
+
+    for _ in 1..MAX_PER_SESSION {
+        uploads.start(&session, "b.bin", "application/octet-stream", 4).unwrap().unwrap();
+    }
+    assert!(uploads.start(&session, "c.bin", "application/octet-stream", 4).unwrap().is_none());
+}
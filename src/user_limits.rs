@@ -0,0 +1,63 @@
+//! Caps on what each user may take of the instance (`[users]`), so that
+//! one heavy user can't slow it down for the others.
+//!
+//! Every session of a username shares one [`Allowance`]: their requests
+//! to the JMAP server queue for the same few slots (`jmap::CallLimit`, on
+//! each session's `Transport`), the emails their caches keep count against
+//! the same memory (`jmap::CacheBudget`), and uploads are held to the same
+//! size. An allowance goes once the user's last session does.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::config::UserLimitsConfig;
+use crate::jmap::{CacheBudget, CallLimit};
+
+pub struct Allowances {
+    config: UserLimitsConfig,
+    users: Mutex<HashMap<String, Weak<Allowance>>>,
+}
+
+/// What one user's sessions share
+#[derive(Default)]
+pub struct Allowance {
+    pub calls: Option<Arc<CallLimit>>,
+    pub cache: Option<Arc<CacheBudget>>,
+    upload_bytes: Option<u64>,
+}
+
+impl Allowances {
+    pub fn new(config: UserLimitsConfig) -> Self {
+        Allowances {
+            config,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The allowance `username`'s sessions share, made with the first
+    pub fn of(&self, username: &str) -> Arc<Allowance> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(allowance) = users.get(username).and_then(Weak::upgrade) {
+            return allowance;
+        }
+        users.retain(|_, allowance| allowance.strong_count() > 0);
+        let limits = self.config.of(username);
+        let allowance = Arc::new(Allowance {
+            calls: limits.upstream_calls.map(|max| Arc::new(CallLimit::new(max))),
+            cache: limits.cache_mb.map(|mb| Arc::new(CacheBudget::new((mb * 1024 * 1024) as usize))),
+            upload_bytes: limits.upload_mb.map(|mb| mb * 1024 * 1024),
+        });
+        users.insert(username.to_string(), Arc::downgrade(&allowance));
+        allowance
+    }
+}
+
+impl Allowance {
+    /// `limit`, or the user's cap on uploads where that is lower
+    pub fn upload_limit(&self, limit: u64) -> u64 {
+        self.upload_bytes.map_or(limit, |cap| cap.min(limit))
+    }
+}
+
+#[cfg(test)]
+mod tests;
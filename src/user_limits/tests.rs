@@ -0,0 +1,44 @@
+use super::*;
+
+fn config(toml: &str) -> UserLimitsConfig {
+    toml::from_str(toml).unwrap()
+}
+
+#[test]
+fn overrides_replace_the_caps_they_set() {
+    let config = config(
+        r#"
+        cache_mb = 64
+        upstream_calls = 4
+
+        [overrides."alice@example.com"]
+        upstream_calls = 16
+        upload_mb = 10
+
+        [overrides."bob@example.com"]
+        cache_mb = 0
+        "#,
+    );
+    let everyone = config.of("carol@example.com");
+    assert_eq!((everyone.cache_mb, everyone.upstream_calls, everyone.upload_mb), (Some(64), Some(4), None));
+    let alice = config.of("alice@example.com");
+    assert_eq!((alice.cache_mb, alice.upstream_calls, alice.upload_mb), (Some(64), Some(16), Some(10)));
+    let bob = config.of("bob@example.com");
+    assert_eq!((bob.cache_mb, bob.upstream_calls), (None, Some(4)));
+}
+
+#[test]
+fn a_users_sessions_share_one_allowance() {
+    let allowances = Allowances::new(config("upload_mb = 1"));
+    let first = allowances.of("alice@example.com");
+    let second = allowances.of("alice@example.com");
+    assert!(Arc::ptr_eq(&first, &second));
+    assert!(!Arc::ptr_eq(&first, &allowances.of("bob@example.com")));
+    assert_eq!(first.upload_limit(25 * 1024 * 1024), 1024 * 1024);
+    assert_eq!(first.upload_limit(1000), 1000);
+
+    let old = Arc::downgrade(&first);
+    drop((first, second));
+    assert!(old.upgrade().is_none());
+    assert_eq!(allowances.of("alice@example.com").upload_limit(u64::MAX), 1024 * 1024);
+}
@@ -0,0 +1,286 @@
+//! Minimal streaming ZIP writer for downloading a message's attachments
+//! in one go, or a whole mailbox as .eml files.
+//!
+//! Entries are stored rather than deflated, since attachments are mostly
+//! compressed already, and each is read from its source only when the
+//! archive gets to it; the list of entries itself may be produced as the
+//! archive goes, too. Sizes and CRCs aren't known until then, so they
+//! follow each entry's data in a data descriptor. There is no ZIP64, so
+//! an archive must stay under 4 GiB and 65535 entries.
+//!
+//! Once the archive has started going out there is no error page to show,
+//! so an entry that can't be opened is left out and listed in a
+//! MISSING.txt at the end instead.
+
+use flate2::Crc;
+use std::collections::HashSet;
+use std::io::{self, Cursor, Read};
+
+use crate::dates::civil_from_days;
+
+/// Opens an entry's contents when the archive reaches it
+pub type Opener = Box<dyn FnOnce() -> io::Result<Box<dyn Read + Send>> + Send>;
+
+pub struct Entry {
+    pub name: String,
+    pub open: Opener,
+}
+
+/// What the central directory needs to know about an entry already written
+struct Written {
+    name: Vec<u8>,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// A ZIP archive of `entries`, produced as it is read
+pub struct ZipStream {
+    entries: Box<dyn Iterator<Item = Entry> + Send>,
+    /// Entries started so far, for naming those without a usable name
+    count: usize,
+    /// Bytes ready to go out ahead of anything else
+    pending: Vec<u8>,
+    pending_at: usize,
+    current: Option<(Box<dyn Read + Send>, Crc)>,
+    current_name: Vec<u8>,
+    current_offset: u32,
+    written: Vec<Written>,
+    /// Names in use, in lower case
+    seen: HashSet<String>,
+    /// Entries that couldn't be opened, and why
+    missing: Vec<String>,
+    /// Bytes of the archive produced so far
+    offset: u64,
+    /// DOS time and date every entry is stamped with
+    modified: (u16, u16),
+    finished: bool,
+}
+
+impl ZipStream {
+    /// An archive of `entries`, each stamped with `modified` (seconds since
+    /// the epoch, UTC). Names are made safe and unique, so none can reach
+    /// outside the folder it is extracted to.
+    pub fn new(entries: impl Iterator<Item = Entry> + Send + 'static, modified: i64) -> ZipStream {
+        ZipStream {
+            entries: Box::new(entries),
+            count: 0,
+            pending: Vec::new(),
+            pending_at: 0,
+            current: None,
+            current_name: Vec::new(),
+            current_offset: 0,
+            written: Vec::new(),
+            seen: HashSet::new(),
+            missing: Vec::new(),
+            offset: 0,
+            modified: dos_time(modified),
+            finished: false,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        self.offset += bytes.len() as u64;
+    }
+
+    fn offset32(&self) -> io::Result<u32> {
+        u32::try_from(self.offset).map_err(|_| io::Error::other("archive is too large for ZIP without ZIP64"))
+    }
+
+    fn start_entry(&mut self, entry: Entry) -> io::Result<()> {
+        self.count += 1;
+        let entry = Entry {
+            name: unique_name(&safe_name(&entry.name, self.count), &mut self.seen),
+            open: entry.open,
+        };
+        let reader = match (entry.open)() {
+            Ok(reader) => reader,
+            Err(e) => {
+                self.missing.push(format!("{}: {}", entry.name, e));
+                return Ok(());
+            }
+        };
+        let name = entry.name.into_bytes();
+        let offset = self.offset32()?;
+        let (time, date) = self.modified;
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        header.extend_from_slice(&FLAGS.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // stored
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        // CRC and sizes come in the data descriptor
+        header.extend_from_slice(&[0; 12]);
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        header.extend_from_slice(&name);
+        self.push(&header);
+        self.current = Some((reader, Crc::new()));
+        self.current_name = name;
+        self.current_offset = offset;
+        Ok(())
+    }
+
+    fn finish_entry(&mut self, crc: Crc) -> io::Result<()> {
+        let size = crc.amount();
+        self.offset32()?;
+        let mut descriptor = Vec::with_capacity(16);
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&crc.sum().to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+        self.push(&descriptor);
+        self.written.push(Written {
+            name: std::mem::take(&mut self.current_name),
+            crc: crc.sum(),
+            size,
+            offset: self.current_offset,
+        });
+        Ok(())
+    }
+
+    fn finish_archive(&mut self) -> io::Result<()> {
+        let start = self.offset32()?;
+        let (time, date) = self.modified;
+        let mut directory = Vec::new();
+        for entry in &self.written {
+            directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            directory.extend_from_slice(&0x031eu16.to_le_bytes()); // made by: Unix, 3.0
+            directory.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            directory.extend_from_slice(&FLAGS.to_le_bytes());
+            directory.extend_from_slice(&0u16.to_le_bytes()); // stored
+            directory.extend_from_slice(&time.to_le_bytes());
+            directory.extend_from_slice(&date.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            // Extra field, comment, disk number, internal attributes
+            directory.extend_from_slice(&[0; 8]);
+            // rw-r--r-- regular file
+            directory.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(&entry.name);
+        }
+        let count = u16::try_from(self.written.len())
+            .map_err(|_| io::Error::other("archive has too many entries for ZIP without ZIP64"))?;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // disk numbers
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&start.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        self.push(&directory);
+        self.push(&end);
+        self.finished = true;
+        Ok(())
+    }
+}
+
+/// Sizes in a data descriptor (bit 3), names in UTF-8 (bit 11)
+const FLAGS: u16 = (1 << 3) | (1 << 11);
+
+impl Read for ZipStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pending_at < self.pending.len() {
+                let n = out.len().min(self.pending.len() - self.pending_at);
+                out[..n].copy_from_slice(&self.pending[self.pending_at..self.pending_at + n]);
+                self.pending_at += n;
+                if self.pending_at == self.pending.len() {
+                    self.pending.clear();
+                    self.pending_at = 0;
+                }
+                return Ok(n);
+            }
+            if let Some((reader, crc)) = self.current.as_mut() {
+                let n = reader.read(out)?;
+                if n > 0 {
+                    crc.update(&out[..n]);
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                let (_, crc) = self.current.take().unwrap();
+                self.finish_entry(crc)?;
+                continue;
+            }
+            match self.entries.next() {
+                Some(entry) => self.start_entry(entry)?,
+                None if !self.missing.is_empty() => {
+                    let mut note = "These could not be downloaded:\n\n".to_string();
+                    for line in self.missing.drain(..) {
+                        note.push_str(&line);
+                        note.push('\n');
+                    }
+                    let name = "MISSING.txt".to_string();
+                    let open: Opener = Box::new(move || Ok(Box::new(Cursor::new(note.into_bytes()))));
+                    self.start_entry(Entry { name, open })?;
+                }
+                None if !self.finished => self.finish_archive()?,
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Longest name an entry is given, in bytes, as most file systems allow
+const MAX_NAME: usize = 255;
+
+/// `name` as a plain file name: no folders, no control characters, not
+/// empty, and no longer than [`MAX_NAME`], keeping a short extension
+fn safe_name(name: &str, index: usize) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | ':') { '_' } else { c })
+        .collect();
+    let name = name.trim().trim_start_matches('.');
+    if name.is_empty() {
+        return format!("attachment-{}", index);
+    }
+    if name.len() <= MAX_NAME {
+        return name.to_string();
+    }
+    let ext = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+    let mut end = MAX_NAME - ext.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], ext)
+}
+
+/// `name`, or "name (2).ext" and so on if an earlier entry already has it
+fn unique_name(name: &str, seen: &mut HashSet<String>) -> String {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut candidate = name.to_string();
+    let mut n = 2;
+    while !seen.insert(candidate.to_lowercase()) {
+        candidate = format!("{} ({}){}", stem, n, ext);
+        n += 1;
+    }
+    candidate
+}
+
+/// Seconds since the epoch as a DOS (time, date), clamped to the years DOS
+/// dates can hold
+fn dos_time(time: i64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days(time.div_euclid(86400));
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = year.min(2107);
+    let seconds = time.rem_euclid(86400);
+    let (hour, minute, second) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    let time = ((hour << 11) | (minute << 5) | (second / 2)) as u16;
+    let date = (((year - 1980) << 9) | (month << 5) | day) as u16;
+    (time, date)
+}
@@ -0,0 +1,411 @@
+/*
+ * Page behaviour that htmx attributes don't cover. Kept out of the HTML so
+ * the Content-Security-Policy can refuse inline scripts.
+ */
+// The service worker sits beside the manifest, at the top of the app
+var manifest = document.querySelector('link[rel=manifest]');
+if (manifest && 'serviceWorker' in navigator) {
+  navigator.serviceWorker.register(manifest.href.replace(/manifest\.webmanifest$/, 'sw.js'));
+}
+var selectedRowId = null;
+document.addEventListener('click', function(e) {
+  // Handle mailbox selection
+  var mailboxItem = e.target.closest('.sidebar li');
+  if (mailboxItem) {
+    document.querySelectorAll('.sidebar li.selected').forEach(function(el) {
+      el.classList.remove('selected');
+    });
+    mailboxItem.classList.add('selected');
+  }
+  // Handle email row selection
+  var emailRow = e.target.closest('.email-list tbody tr[id^="row-"]');
+  if (emailRow) {
+    document.querySelectorAll('.email-list tr.selected').forEach(function(el) {
+      el.classList.remove('selected');
+    });
+    emailRow.classList.add('selected');
+    selectedRowId = emailRow.id;
+  }
+});
+// A failed request answers with the error to show in its place
+// (handlers/error.rs); htmx leaves error statuses unswapped otherwise
+document.addEventListener('htmx:beforeSwap', function(e) {
+  var type = e.detail.xhr.getResponseHeader('Content-Type') || '';
+  if (e.detail.xhr.status >= 400 && type.indexOf('text/html') === 0) {
+    e.detail.shouldSwap = true;
+    e.detail.isError = false;
+  }
+});
+// A live refresh re-renders the list; keep the open message highlighted.
+// On a page opened at a mailbox's or message's address, nothing has been
+// clicked yet, so the address says what to highlight.
+document.addEventListener('htmx:afterSwap', function(e) {
+  if (e.detail.target.id === 'mailbox-list' && !document.querySelector('.sidebar li.selected')) {
+    document.querySelectorAll('.sidebar li[hx-push-url]').forEach(function(li) {
+      if (li.getAttribute('hx-push-url') === location.pathname) {
+        li.classList.add('selected');
+      }
+    });
+  }
+  if (!selectedRowId) {
+    var opened = location.pathname.match(/\/email\/([^\/]+)$/) || location.search.match(/[?&]focus=([^&]+)/);
+    selectedRowId = opened && 'row-' + decodeURIComponent(opened[1]);
+  }
+  var row = selectedRowId && e.detail.target.id === 'email-list' && document.getElementById(selectedRowId);
+  if (row) {
+    row.classList.add('selected');
+  }
+});
+// The unread count in front of the tab's title, so new mail shows from
+// other tabs; the count is fetched as the page loads and on mailbox changes
+var baseTitle = document.title;
+document.addEventListener('htmx:afterRequest', function(e) {
+  if (e.detail.elt.id !== 'unread-title' || !e.detail.successful) {
+    return;
+  }
+  var unread = JSON.parse(e.detail.xhr.responseText).unread;
+  document.title = unread > 0 ? '(' + unread + ') ' + baseTitle : baseTitle;
+});
+// On a narrow screen one pane shows at a time (style.css): whatever was
+// opened last, unless it only loaded with the page or refreshed itself
+document.addEventListener('htmx:afterSwap', function(e) {
+  var container = document.querySelector('.container[data-pane]');
+  var opener = e.detail.elt;
+  if (!container || opener === e.detail.target || opener.classList.contains('live-refresh')) {
+    return;
+  }
+  if (e.detail.target.id === 'email-list') {
+    container.dataset.pane = 'list';
+  } else if (e.detail.target.id === 'email-view') {
+    container.dataset.pane = 'view';
+  }
+});
+document.addEventListener('click', function(e) {
+  var back = e.target.closest('.pane-back');
+  if (back) {
+    back.closest('.container').dataset.pane = back.dataset.pane;
+  }
+});
+// Skip a live refresh of the list while rows are checked for a bulk
+// action, since re-rendering would clear them
+document.addEventListener('htmx:beforeRequest', function(e) {
+  if (e.detail.elt.classList.contains('live-refresh') && document.querySelector('#email-list input[name=email]:checked')) {
+    e.preventDefault();
+  }
+});
+// Clicks on a row's checkbox or Move menu mustn't also open the message
+document.addEventListener('click', function(e) {
+  if (e.target.matches('.email-list td.select input, .email-list td.move select')) {
+    e.stopPropagation();
+  }
+}, true);
+document.addEventListener('change', function(e) {
+  var el = e.target;
+  if (el.matches('.email-list input.select-all')) {
+    document.querySelectorAll('#email-list input[name=email]').forEach(function(c) {
+      c.checked = el.checked;
+    });
+  }
+  // On a From change, replace the old identity's signature in the body
+  // with the new one's, if the old one is still there unedited
+  if (el.matches('select[name=identity][data-signature]')) {
+    var body = el.form.elements.body, old = el.dataset.signature, next = el.selectedOptions[0].dataset.signature;
+    if (old && old.trim() && body.value.indexOf(old) >= 0) {
+      body.value = body.value.replace(old, next);
+    }
+    el.dataset.signature = next;
+  }
+});
+// The theme toggle flips the page at once and sends the choice along to
+// be kept in a cookie. With none made yet the page follows the system.
+document.addEventListener('htmx:configRequest', function(e) {
+  if (e.detail.elt.matches('.theme-toggle')) {
+    var root = document.documentElement;
+    var dark = root.dataset.theme ? root.dataset.theme === 'dark' : matchMedia('(prefers-color-scheme: dark)').matches;
+    root.dataset.theme = dark ? 'light' : 'dark';
+    e.detail.parameters.theme = root.dataset.theme;
+  }
+});
+// An attachment's thumbnail grows to full width on click, and back
+document.addEventListener('click', function(e) {
+  if (e.target.matches('.attachment-thumb')) {
+    e.target.classList.toggle('expanded');
+  }
+});
+// Fill the time zone setting with the one this browser is in
+document.addEventListener('click', function(e) {
+  if (e.target.matches('.detect-timezone')) {
+    e.target.form.elements.timezone.value = Intl.DateTimeFormat().resolvedOptions().timeZone || '';
+  }
+});
+// Have the browser open mailto: links in a new message here, at the top
+// of the app beside the manifest
+document.addEventListener('click', function(e) {
+  if (e.target.matches('.register-mailto') && manifest && navigator.registerProtocolHandler) {
+    navigator.registerProtocolHandler('mailto', manifest.href.replace(/manifest\.webmanifest$/, 'compose?to=%s'));
+  }
+});
+// Recipient suggestions: picking one replaces the address being typed,
+// the last after a comma or semicolon; Escape or a click elsewhere closes
+// the list, and the down arrow moves into it
+document.addEventListener('click', function(e) {
+  var pick = e.target.closest('.suggestions button');
+  document.querySelectorAll('.suggestions').forEach(function(list) {
+    list.innerHTML = '';
+  });
+  if (pick) {
+    var field = pick.parentNode.previousElementSibling;
+    var cut = Math.max(field.value.lastIndexOf(','), field.value.lastIndexOf(';'));
+    field.value = (cut >= 0 ? field.value.slice(0, cut + 1) + ' ' : '') + pick.dataset.address + ', ';
+    field.focus();
+  }
+});
+document.addEventListener('keydown', function(e) {
+  var field = e.target.matches('.compose input') ? e.target : null;
+  var list = field ? field.nextElementSibling : e.target.closest('.suggestions');
+  if (!list || !list.matches('.suggestions') || !list.firstElementChild) {
+    return;
+  }
+  var next = null;
+  if (e.key === 'Escape') {
+    list.innerHTML = '';
+    next = list.previousElementSibling;
+  } else if (e.key === 'ArrowDown') {
+    next = field ? list.firstElementChild : e.target.nextElementSibling;
+  } else if (e.key === 'ArrowUp' && !field) {
+    next = e.target.previousElementSibling || list.previousElementSibling;
+  }
+  if (next) {
+    next.focus();
+    e.preventDefault();
+  }
+});
+// A recipient chip's buttons carry the field as it would be without the
+// chip, or with its typo fixed; putting that in checks the chips again
+document.addEventListener('click', function(e) {
+  var button = e.target.closest('.recipient-chips button[data-value]');
+  if (!button) {
+    return;
+  }
+  var field = button.closest('.recipient-chips').previousElementSibling.previousElementSibling;
+  field.value = button.dataset.value;
+  field.dispatchEvent(new Event('change', { bubbles: true }));
+});
+// Searching a conversation: the server says which of its messages match,
+// and only those stay shown, opened, with the words marked
+document.addEventListener('htmx:afterSwap', function(e) {
+  if (!e.detail.elt.matches('.thread-filter input')) {
+    return;
+  }
+  var thread = e.detail.elt.closest('.thread');
+  var matches = thread.querySelector('.thread-matches');
+  var ids = matches.dataset.ids.split(' ');
+  var words = matches.dataset.query.split(/\s+/).filter(Boolean).map(function(word) {
+    return word.replace(/[.*+?^${}()|[\]\\]/g, '\\$&');
+  });
+  thread.querySelectorAll('mark.thread-match').forEach(function(mark) {
+    var parent = mark.parentNode;
+    parent.replaceChild(document.createTextNode(mark.textContent), mark);
+    parent.normalize();
+  });
+  thread.querySelectorAll('details[id^="thread-"]').forEach(function(message) {
+    message.hidden = ids.indexOf(message.id.slice('thread-'.length)) < 0;
+    if (words.length && !message.hidden) {
+      message.open = true;
+      markWords(message.querySelector('.thread-message'), new RegExp(words.join('|'), 'gi'));
+    }
+  });
+});
+function markWords(root, pattern) {
+  var walker = document.createTreeWalker(root, NodeFilter.SHOW_TEXT);
+  var nodes = [];
+  while (walker.nextNode()) {
+    nodes.push(walker.currentNode);
+  }
+  nodes.forEach(function(node) {
+    var text = node.nodeValue, last = 0, found, pieces = document.createDocumentFragment();
+    pattern.lastIndex = 0;
+    while ((found = pattern.exec(text))) {
+      var mark = document.createElement('mark');
+      mark.className = 'thread-match';
+      mark.textContent = found[0];
+      pieces.append(text.slice(last, found.index), mark);
+      last = found.index + found[0].length;
+    }
+    if (last > 0) {
+      pieces.append(text.slice(last));
+      node.parentNode.replaceChild(pieces, node);
+    }
+  });
+}
+// Marking read, flagging and archiving show on the row at once, before
+// the server answers; if it turns the change down, its answer puts the row
+// back as it is and says why in a toast
+document.addEventListener('htmx:beforeRequest', function(e) {
+  var action = e.detail.requestConfig.path.match(/\/email\/([^\/?]+)\/(seen|unseen|flag|unflag|archive)$/);
+  if (!action) {
+    return;
+  }
+  var id = decodeURIComponent(action[1]);
+  var row = document.getElementById('row-' + id);
+  if (action[2] === 'flag' || action[2] === 'unflag') {
+    ['star-row-', 'star-view-'].forEach(function(place) {
+      var star = document.getElementById(place + id);
+      if (star) {
+        star.textContent = action[2] === 'flag' ? '\u2605' : '\u2606';
+      }
+    });
+  } else if (row && action[2] === 'archive') {
+    row.hidden = true;
+  } else if (row) {
+    row.classList.toggle('unread', action[2] === 'unseen');
+  }
+});
+document.addEventListener('htmx:oobAfterSwap', function(e) {
+  if (e.detail.target.id === 'toasts') {
+    var toast = e.detail.target.lastElementChild;
+    setTimeout(function() {
+      toast.remove();
+    }, 8000);
+  }
+});
+document.addEventListener('click', function(e) {
+  var toast = e.target.closest('.toast');
+  if (toast) {
+    toast.remove();
+  }
+});
+// Files picked in the compose form upload as soon as they are picked, each
+// landing in the attachment list; the bar beside the picker shows how far
+// the upload has got, and the picker is emptied so they aren't sent twice
+document.addEventListener('htmx:xhr:progress', function(e) {
+  var bar = e.target.nextElementSibling;
+  if (!e.target.matches('.compose input[type=file]') || !e.detail.lengthComputable) {
+    return;
+  }
+  bar.hidden = false;
+  bar.value = e.detail.loaded * 100 / e.detail.total;
+});
+document.addEventListener('htmx:afterRequest', function(e) {
+  var picker = e.detail.elt;
+  if (!picker.matches('.compose input[type=file]')) {
+    return;
+  }
+  picker.value = '';
+  picker.nextElementSibling.hidden = true;
+});
+// A pick with a file of a chunk's size or more goes up in chunks instead
+// (src/uploads.rs), one file after another; when the connection drops or
+// the server fails to pass a finished file on, the upload waits, asks how
+// far it got, and carries on from there
+document.addEventListener('htmx:confirm', function(e) {
+  var picker = e.detail.elt;
+  if (!picker.matches('.compose input[type=file]')) {
+    return;
+  }
+  var files = Array.prototype.slice.call(picker.files);
+  var chunk = Number(picker.dataset.chunk);
+  if (!files.some(function(file) { return file.size >= chunk; })) {
+    return;
+  }
+  e.preventDefault();
+  picker.value = '';
+  var list = document.getElementById('compose-attachments');
+  var bar = picker.nextElementSibling;
+  var total = files.reduce(function(sum, file) { return sum + file.size; }, 0) || 1;
+  var done = 0;
+  bar.hidden = false;
+  var next = function() {
+    var file = files.shift();
+    if (!file) {
+      bar.hidden = true;
+      return;
+    }
+    uploadInChunks(picker, file, function(sent) {
+      bar.value = (done + sent) * 100 / total;
+    }).then(function(outcome) {
+      list.insertAdjacentHTML('beforeend', outcome.html);
+      done += file.size;
+      if (outcome.ok) {
+        next();
+      } else {
+        bar.hidden = true;
+      }
+    });
+  };
+  next();
+});
+var MAX_UPLOAD_FAILURES = 8;
+// Send `file` a chunk at a time, settling with whether it made it and the
+// HTML for the attachment list: its line, or why not
+function uploadInChunks(picker, file, onProgress) {
+  var headers = JSON.parse(picker.closest('[hx-headers]').getAttribute('hx-headers'));
+  headers['HX-Request'] = 'true';
+  var send = function(url, method, body) {
+    return fetch(url, { method: method, headers: headers, body: body, credentials: 'same-origin' });
+  };
+  var answer = function(response) {
+    return response.text().then(function(html) {
+      return { ok: response.ok, html: html };
+    });
+  };
+  var failed = function() {
+    var error = document.createElement('div');
+    error.className = 'error';
+    error.textContent = picker.dataset.failed;
+    return { ok: false, html: error.outerHTML };
+  };
+  var form = new URLSearchParams({ name: file.name, type: file.type, size: file.size });
+  return send(picker.dataset.uploads, 'POST', form).then(function(response) {
+    if (!response.ok) {
+      return answer(response);
+    }
+    return response.json().then(function(started) {
+      var url = picker.dataset.uploads + '/' + encodeURIComponent(started.id);
+      var failures = 0;
+      // Each step settles with the offset to send from next, or the outcome
+      var step = function(offset) {
+        onProgress(offset);
+        return send(url + '?offset=' + offset, 'POST', file.slice(offset, offset + started.chunk)).then(function(response) {
+          if (response.status === 202 || response.status === 409) {
+            failures = 0;
+            return response.json().then(function(progress) {
+              return progress.received;
+            });
+          }
+          if (response.status >= 500) {
+            throw response;
+          }
+          return answer(response);
+        }).catch(function(failure) {
+          failures += 1;
+          if (failures > MAX_UPLOAD_FAILURES) {
+            return failure instanceof Response ? answer(failure) : failed();
+          }
+          return new Promise(function(resolve) {
+            setTimeout(resolve, 1000 * Math.min(30, Math.pow(2, failures - 1)));
+          }).then(function() {
+            return send(url, 'GET');
+          }).then(function(response) {
+            // Offline, the service worker answers 503
+            if (response.status >= 500) {
+              return offset;
+            }
+            if (!response.ok) {
+              return answer(response);
+            }
+            return response.json().then(function(progress) {
+              return progress.received;
+            });
+          }, function() {
+            return offset;
+          });
+        });
+      };
+      var run = function(next) {
+        return typeof next === 'number' ? step(next).then(run) : next;
+      };
+      return run(0);
+    });
+  }).catch(failed);
+}
@@ -0,0 +1,148 @@
+/*
+ * Keyboard shortcuts. Each one stands in for a click on a control the page
+ * already has, so it goes through the same htmx request:
+ *
+ *   j / k   select the next / previous message in the list
+ *   Enter   open the selected message
+ *   r       reply to the open message, or to all when that's the first
+ *           reply button (the replies setting)
+ *   a       archive the open message, or else the selected one
+ *   #       delete the open message, or else the selected one
+ *   /       open the search form
+ *
+ * Keys typed into a form field, or with Ctrl, Alt or Meta held, are left
+ * alone.
+ */
+(function() {
+  function rows() {
+    return Array.prototype.slice.call(document.querySelectorAll('#email-list tbody tr[id^="row-"]'));
+  }
+
+  function selectedRow() {
+    return document.querySelector('#email-list tr.selected[id^="row-"]');
+  }
+
+  // Mark a row the way a click does, without opening it
+  function select(row) {
+    document.querySelectorAll('.email-list tr.selected').forEach(function(el) {
+      el.classList.remove('selected');
+    });
+    row.classList.add('selected');
+    selectedRowId = row.id;
+    row.scrollIntoView({block: 'nearest'});
+  }
+
+  function move(step) {
+    var list = rows();
+    if (!list.length) {
+      return;
+    }
+    var at = list.indexOf(selectedRow());
+    var next = at < 0 ? 0 : Math.min(Math.max(at + step, 0), list.length - 1);
+    select(list[next]);
+  }
+
+  // The id of the message a row stands for
+  function rowEmail(row) {
+    return row.id.slice('row-'.length);
+  }
+
+  // The open message's control that sends `attr` to /email/{id}/`action`:
+  // the selected row's message if it's open, or with no row selected the
+  // last one open (a thread shows several)
+  function control(attr, action) {
+    var row = selectedRow();
+    var found = Array.prototype.filter.call(
+      document.querySelectorAll('#email-view [' + attr + '$="/' + action + '"]'),
+      function(el) {
+        return !row || el.getAttribute(attr).endsWith('/email/' + rowEmail(row) + '/' + action);
+      });
+    return found[found.length - 1];
+  }
+
+  // Archive or delete through the open message's button, or else with the
+  // same request for the selected row, then select the row that takes the
+  // removed one's place
+  function act(action) {
+    var button = control('hx-post', action);
+    var row = selectedRow();
+    if (!button && !row) {
+      return;
+    }
+    var after = row && (row.nextElementSibling || row.previousElementSibling);
+    if (button) {
+      button.click();
+    } else {
+      // The row's Move menu posts to /email/{id}/move, under any base path
+      var url = row.querySelector('[hx-post$="/move"]').getAttribute('hx-post').replace(/\/move$/, '/' + action);
+      htmx.ajax('POST', url, {source: row, target: '#email-view', swap: 'innerHTML'});
+    }
+    if (after && after.id.indexOf('row-') === 0) {
+      select(after);
+    }
+  }
+
+  var focusSearch = false;
+  document.addEventListener('htmx:afterSwap', function(e) {
+    if (focusSearch && e.detail.target.id === 'email-view') {
+      focusSearch = false;
+      var field = e.detail.target.querySelector('form input:not([type=hidden])');
+      if (field) {
+        field.focus();
+      }
+    }
+  });
+
+  document.addEventListener('keydown', function(e) {
+    if (e.ctrlKey || e.altKey || e.metaKey || e.defaultPrevented) {
+      return;
+    }
+    var el = e.target;
+    if (el.isContentEditable || (el.closest && el.closest('input, textarea, select, button, summary'))) {
+      return;
+    }
+    var row;
+    switch (e.key) {
+      case 'j':
+        move(1);
+        break;
+      case 'k':
+        move(-1);
+        break;
+      case 'Enter':
+        row = selectedRow();
+        if (!row) {
+          return;
+        }
+        row.click();
+        break;
+      case 'r':
+        var reply = control('hx-get', 'reply-all');
+        if (!reply || !reply.classList.contains('default-reply')) {
+          reply = control('hx-get', 'reply');
+        }
+        if (!reply) {
+          return;
+        }
+        reply.click();
+        break;
+      case 'a':
+        act('archive');
+        break;
+      case '#':
+        act('delete');
+        break;
+      case '/':
+        var search = document.querySelector('.sidebar-footer [hx-get$="/search"]');
+        if (!search) {
+          return;
+        }
+        focusSearch = true;
+        search.click();
+        break;
+      default:
+        return;
+    }
+    e.preventDefault();
+  });
+})();
@@ -0,0 +1,57 @@
+/*
+ * Server-Sent Events extension for htmx 1.9.
+ *
+ * A small subset of the official `sse` extension: an element with
+ * hx-ext="sse" and sse-connect="<url>" opens an EventSource, and any element
+ * inside it can react to a named event with hx-trigger="sse:<name>". The
+ * source is closed when its element leaves the page. The browser reconnects
+ * on its own after a dropped connection; a 204 or an error response from the
+ * server ends the stream for good.
+ */
+(function () {
+  var api;
+
+  htmx.defineExtension("sse", {
+    init: function (apiRef) {
+      api = apiRef;
+    },
+
+    onEvent: function (name, evt) {
+      var elt = evt.target || evt.detail.elt;
+      switch (name) {
+        case "htmx:beforeCleanupElement":
+          var internalData = api.getInternalData(elt);
+          if (internalData.sseEventSource) {
+            internalData.sseEventSource.close();
+          }
+          return;
+
+        case "htmx:afterProcessNode":
+          connect(elt);
+          return;
+      }
+    },
+  });
+
+  function connect(elt) {
+    var url = api.getAttributeValue(elt, "sse-connect");
+    if (!url) {
+      return;
+    }
+    var internalData = api.getInternalData(elt);
+    if (internalData.sseEventSource) {
+      return;
+    }
+
+    // htmx binds hx-trigger="sse:<name>" to the nearest ancestor holding an
+    // sseEventSource, so storing it here is all the wiring triggers need
+    var source = new EventSource(url);
+    source.onerror = function (err) {
+      api.triggerErrorEvent(elt, "htmx:sseError", { error: err, source: source });
+    };
+    source.onopen = function () {
+      api.triggerEvent(elt, "htmx:sseOpen", { source: source });
+    };
+    internalData.sseEventSource = source;
+  }
+})();
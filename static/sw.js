@@ -0,0 +1,99 @@
+/*
+ * The service worker. BASE, VERSION and ASSETS are put in front of it by
+ * src/pwa.rs.
+ *
+ * The built-in scripts, the stylesheet and the icon are cached when the
+ * worker installs and come from the cache from then on; their URLs change
+ * with their contents, so they never go stale. The app shell (the main
+ * page) is answered from the cache as well, while a fresh copy is fetched
+ * for next time. Other pages and htmx fragments go to the network first.
+ * Fragments aren't kept, since they hold mail; without the network a page
+ * falls back to the shell, and a fragment gets a 503.
+ */
+var ASSET_CACHE = 'assets-' + VERSION;
+// A shell from another build links to assets this one doesn't serve
+var SHELL_CACHE = 'shell-' + VERSION;
+var SHELL = BASE + '/';
+
+self.addEventListener('install', function(e) {
+  e.waitUntil(caches.open(ASSET_CACHE).then(function(cache) {
+    return cache.addAll(ASSETS);
+  }).then(function() {
+    return self.skipWaiting();
+  }));
+});
+
+// Drop what older builds kept
+self.addEventListener('activate', function(e) {
+  e.waitUntil(caches.keys().then(function(names) {
+    return Promise.all(names.filter(function(name) {
+      return name !== ASSET_CACHE && name !== SHELL_CACHE;
+    }).map(function(name) {
+      return caches.delete(name);
+    }));
+  }).then(function() {
+    return self.clients.claim();
+  }));
+});
+
+// The shell is fetched again after anything that changes what it shows
+// (signing in or out, the account, theme or language), so the next load
+// doesn't show the old one
+var SHELL_CHANGES = ['/login', '/login/code', '/logout', '/account', '/theme', '/language'];
+
+self.addEventListener('fetch', function(e) {
+  var request = e.request;
+  var url = new URL(request.url);
+  if (url.origin !== location.origin) {
+    return;
+  }
+  if (request.method !== 'GET') {
+    if (SHELL_CHANGES.indexOf(url.pathname.slice(BASE.length).replace(/^\/account\/[^\/]+/, '')) >= 0) {
+      e.waitUntil(caches.delete(SHELL_CACHE));
+    }
+    return;
+  }
+  if (ASSETS.indexOf(url.pathname) >= 0) {
+    e.respondWith(caches.match(request).then(function(cached) {
+      return cached || fetch(request);
+    }));
+  } else if (request.mode === 'navigate' && url.pathname === SHELL && !url.search) {
+    e.respondWith(caches.open(SHELL_CACHE).then(function(cache) {
+      return cache.match(SHELL).then(function(cached) {
+        var fresh = fetchShell(cache, request);
+        if (cached) {
+          e.waitUntil(fresh.catch(function() {}));
+          return cached;
+        }
+        return fresh;
+      });
+    }));
+  } else if (request.mode === 'navigate' || request.headers.get('HX-Request')) {
+    e.respondWith(fetch(request).catch(function() {
+      return offline(request);
+    }));
+  }
+  // Downloads and the event stream go straight to the network
+});
+
+// The shell from the server, kept for next time unless it sent us to the
+// login page instead, when the kept one is dropped
+function fetchShell(cache, request) {
+  return fetch(request).then(function(response) {
+    if (response.ok && !response.redirected) {
+      cache.put(SHELL, response.clone());
+    } else {
+      cache.delete(SHELL);
+    }
+    return response;
+  });
+}
+
+function offline(request) {
+  if (request.mode === 'navigate') {
+    return caches.match(SHELL).then(function(cached) {
+      return cached || Response.error();
+    });
+  }
+  return new Response('', { status: 503, statusText: 'Offline' });
+}